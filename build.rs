@@ -0,0 +1,81 @@
+//! Embeds where and when the binary was built.
+//!
+//! `SYMBOLSERVER_GIT_COMMIT` wins over asking git, so that builds from a
+//! source tarball or a docker context without `.git` can still pass it in.
+//! `SOURCE_DATE_EPOCH` overrides the build time for reproducible builds.
+//! With the `grpc` feature it also generates the messages of the gRPC
+//! service from `proto/symbolserver.proto`.
+
+#[cfg(feature = "grpc")]
+extern crate protobuf_codegen_pure;
+
+use std::env;
+#[cfg(feature = "grpc")]
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn run(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(cmd).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let rv = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    if rv.is_empty() { None } else { Some(rv) }
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SYMBOLSERVER_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(head_ref) = run("git", &["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed=.git/{}", head_ref);
+        }
+    }
+
+    let commit = env::var("SYMBOLSERVER_GIT_COMMIT").ok()
+        .or_else(|| run("git", &["rev-parse", "HEAD"]));
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=SYMBOLSERVER_GIT_COMMIT={}", commit);
+    }
+
+    let timestamp = env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+        });
+    println!("cargo:rustc-env=SYMBOLSERVER_BUILD_TIMESTAMP={}", timestamp);
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = run(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=SYMBOLSERVER_RUSTC_VERSION={}", version);
+    }
+
+    #[cfg(feature = "grpc")]
+    generate_protos();
+}
+
+/// Writes the messages of the gRPC service to `OUT_DIR/symbolserver.rs`.
+///
+/// The generated file starts with inner attributes, which `include!`
+/// cannot take, so they are dropped and set on the including module.
+#[cfg(feature = "grpc")]
+fn generate_protos() {
+    println!("cargo:rerun-if-changed=proto/symbolserver.proto");
+    let out_dir = env::var("OUT_DIR").unwrap();
+    protobuf_codegen_pure::run(protobuf_codegen_pure::Args {
+        out_dir: &out_dir,
+        input: &["proto/symbolserver.proto"],
+        includes: &["proto"],
+        customize: Default::default(),
+    }).expect("could not generate the gRPC messages");
+
+    let path = Path::new(&out_dir).join("symbolserver.rs");
+    let code = fs::read_to_string(&path).unwrap();
+    let code: Vec<_> = code.lines()
+        .filter(|line| !line.starts_with("#![") && !line.starts_with("//!"))
+        .collect();
+    fs::write(&path, code.join("\n")).unwrap();
+}
@@ -0,0 +1,30 @@
+//! Feeds arbitrary bytes to the memdb reader.
+//!
+//! Anything but an error for a broken file is a bug: the reader must not
+//! panic or read outside of the buffer no matter what the offsets and
+//! counts in the file say.
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate libsymbolserver;
+
+use libsymbolserver::memdb::read::MemDb;
+
+fuzz_target!(|data: &[u8]| {
+    let memdb = match MemDb::from_slice(data) {
+        Ok(memdb) => memdb,
+        Err(_) => return,
+    };
+    let _ = memdb.validate();
+    let _ = memdb.conversion_info();
+    let _ = memdb.find_uuid_fuzzy("/usr/lib/libKoreanConverter.dylib:arm64");
+    if let Ok(Some(uuid)) = memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b") {
+        memdb.lookup_by_uuid(uuid, 0x5b14);
+        memdb.nearby_symbols(uuid, 0x5b14);
+        memdb.is_shared_object(uuid);
+        if let Ok(iter) = memdb.iter_symbols(uuid) {
+            for sym in iter {
+                let _ = sym;
+            }
+        }
+    }
+});
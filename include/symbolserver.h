@@ -0,0 +1,76 @@
+/* Generated with cbindgen from src/ffi.rs; do not edit by hand. */
+
+#ifndef SYMBOLSERVER_H
+#define SYMBOLSERVER_H
+
+#include <stdint.h>
+#include <stdlib.h>
+#include <stdbool.h>
+
+/*
+ * An opened stash.
+ */
+typedef struct SymbolServerStash SymbolServerStash;
+
+/*
+ * A resolved symbol.
+ */
+typedef struct {
+  /*
+   * The uuid of the object as raw bytes.
+   */
+  uint8_t object_uuid[16];
+  /*
+   * The name of the object containing the symbol.
+   */
+  char *object_name;
+  /*
+   * The name of the symbol.
+   */
+  char *symbol;
+  /*
+   * The address of the symbol.
+   */
+  uint64_t addr;
+} SymbolServerSymbol;
+
+/*
+ * Frees an error message.
+ */
+void symbolserver_err_free(char *err);
+
+/*
+ * Looks up an address in an object of an SDK.
+ *
+ * `object` is either the uuid or the name of the object; `cpu_name` is
+ * only used for lookups by name and may be null for lookups by uuid.
+ * Returns null if the symbol was not found or an error occurred, in
+ * which case `err_out` is set.  The result has to be freed with
+ * `symbolserver_symbol_free`.
+ */
+SymbolServerSymbol *symbolserver_lookup(const SymbolServerStash *stash,
+                                        const char *sdk_id,
+                                        const char *cpu_name,
+                                        const char *object,
+                                        uint64_t addr,
+                                        char **err_out);
+
+/*
+ * Closes a stash opened with `symbolserver_stash_open`.
+ */
+void symbolserver_stash_free(SymbolServerStash *stash);
+
+/*
+ * Opens the stash configured in the given config file.
+ *
+ * If `config_path` is null the default config is loaded.  Returns null
+ * on error.
+ */
+SymbolServerStash *symbolserver_stash_open(const char *config_path, char **err_out);
+
+/*
+ * Frees a symbol returned by `symbolserver_lookup`.
+ */
+void symbolserver_symbol_free(SymbolServerSymbol *sym);
+
+#endif /* SYMBOLSERVER_H */
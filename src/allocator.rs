@@ -0,0 +1,48 @@
+//! Provides information about the memory allocator in use.
+//!
+//! The binary can be built with the `jemalloc` or `mimalloc` feature to
+//! replace the system allocator.  Detailed statistics are currently only
+//! available for jemalloc.
+
+/// Statistics reported by the memory allocator
+#[derive(Serialize, Debug, Clone)]
+pub struct AllocatorStats {
+    /// The name of the allocator
+    pub name: &'static str,
+    /// Bytes allocated by the application
+    pub allocated: Option<u64>,
+    /// Bytes in active pages allocated by the application
+    pub active: Option<u64>,
+    /// Bytes dedicated to allocator metadata
+    pub metadata: Option<u64>,
+    /// Bytes in physically resident pages mapped by the allocator
+    pub resident: Option<u64>,
+}
+
+/// Returns the statistics of the allocator in use.
+#[cfg(feature = "jemalloc")]
+pub fn get_allocator_stats() -> AllocatorStats {
+    use jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches the statistics until the epoch is advanced
+    epoch().ok();
+    AllocatorStats {
+        name: "jemalloc",
+        allocated: stats::allocated().ok().map(|x| x as u64),
+        active: stats::active().ok().map(|x| x as u64),
+        metadata: stats::metadata().ok().map(|x| x as u64),
+        resident: stats::resident().ok().map(|x| x as u64),
+    }
+}
+
+/// Returns the statistics of the allocator in use.
+#[cfg(not(feature = "jemalloc"))]
+pub fn get_allocator_stats() -> AllocatorStats {
+    AllocatorStats {
+        name: if cfg!(feature = "mimalloc") { "mimalloc" } else { "system" },
+        allocated: None,
+        active: None,
+        metadata: None,
+        resident: None,
+    }
+}
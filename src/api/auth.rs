@@ -0,0 +1,588 @@
+//! Authentication of lookup clients.
+//!
+//! `server.auth` picks how clients prove who they are: with one of the
+//! static `server.api_keys`, with a JSON web token or with a TLS client
+//! certificate.  Every mode ends up with an `Identity` that names the
+//! client in logs, says which SDKs it may look symbols up in and whether it
+//! may use the admin endpoints.
+//!
+//! Tokens and client certificates need the `crypto` feature, builds
+//! without it only support API keys.
+use std::borrow::Cow;
+#[cfg(feature = "crypto")]
+use std::collections::HashMap;
+#[cfg(feature = "crypto")]
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "crypto")]
+use std::thread;
+#[cfg(feature = "crypto")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "crypto")]
+use chrono::Utc;
+use hyper::header::{Authorization, Bearer};
+use hyper::server::Request;
+#[cfg(feature = "crypto")]
+use openssl::bn::BigNum;
+#[cfg(feature = "crypto")]
+use openssl::hash::MessageDigest;
+#[cfg(feature = "crypto")]
+use openssl::memcmp;
+#[cfg(feature = "crypto")]
+use openssl::pkey::PKey;
+#[cfg(feature = "crypto")]
+use openssl::rsa::Rsa;
+#[cfg(feature = "crypto")]
+use openssl::sign::{Signer, Verifier};
+#[cfg(feature = "crypto")]
+use rustc_serialize::base64::FromBase64;
+#[cfg(feature = "crypto")]
+use serde_json::{self, Value};
+
+use super::super::config::{ApiKey, AuthMode, Config};
+#[cfg(feature = "crypto")]
+use super::super::s3::new_hyper_client;
+use super::super::utils::IgnorePatterns;
+use super::super::Result;
+#[cfg(feature = "crypto")]
+use super::super::ResultExt;
+#[cfg(not(feature = "crypto"))]
+use super::super::ErrorKind;
+use super::connections::CountingStream;
+use super::spans;
+use super::tls::TlsStream;
+use super::types::ApiError;
+
+/// How long fetched JWKS keys are used before they are fetched again.
+#[cfg(feature = "crypto")]
+const JWKS_MAX_AGE: u64 = 3600;
+
+/// How often at most the JWKS is fetched for tokens with an unknown key.
+#[cfg(feature = "crypto")]
+const JWKS_MIN_REFRESH: u64 = 60;
+
+/// How long fetching the JWKS may take, in seconds.
+#[cfg(feature = "crypto")]
+const JWKS_FETCH_TIMEOUT: u64 = 10;
+
+/// Clock skew tolerated for `exp` and `nbf` of tokens, in seconds.
+#[cfg(feature = "crypto")]
+const JWT_LEEWAY: i64 = 60;
+
+/// A client that authenticated.
+pub struct Identity<'a> {
+    name: Cow<'a, str>,
+    api_key: Option<&'a ApiKey>,
+    /// `None` if the client may use every SDK
+    sdks: Option<IgnorePatterns>,
+    is_admin: bool,
+}
+
+impl<'a> Identity<'a> {
+    /// The name of the client for logs
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks if the client may look symbols up in an SDK.
+    pub fn may_access(&self, sdk_id: &str) -> bool {
+        self.api_key.map_or(true, |x| x.may_access(sdk_id)) &&
+            self.sdks.as_ref().map_or(true, |x| x.is_match(sdk_id))
+    }
+
+    /// Checks if the client may use the admin endpoints.
+    pub fn is_admin(&self) -> bool {
+        self.is_admin
+    }
+}
+
+/// Checks the credentials of requests.
+pub struct Authenticator {
+    mode: AuthMode,
+    api_keys: Vec<ApiKey>,
+    admin_clients: Vec<String>,
+    jwt: Option<JwtVerifier>,
+}
+
+impl Authenticator {
+    /// Sets up the authentication the config asks for.
+    pub fn from_config(config: &Config) -> Result<Authenticator> {
+        let mode = config.get_server_auth_mode()?;
+        check_client_certs(mode)?;
+        Ok(Authenticator {
+            mode,
+            api_keys: config.get_server_api_keys()?.to_vec(),
+            admin_clients: config.get_server_tls_admin_clients().to_vec(),
+            jwt: if mode == AuthMode::Jwt {
+                Some(JwtVerifier::from_config(config)?)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Returns who sent a request.
+    ///
+    /// Returns `None` if no API keys are configured and lookups are open to
+    /// everybody.  Fails with `unauthorized` if the request does not carry
+    /// valid credentials.  Call it before the body is read so that nobody
+    /// gets the server to read and parse a body without credentials.
+    pub fn authenticate<'a>(&'a self, req: &Request) -> Result<Option<Identity<'a>>> {
+        if self.mode != AuthMode::ClientCert {
+            let auth = req.headers.get::<Authorization<Bearer>>();
+            return self.authenticate_token(auth.map(|x| x.token.as_str()));
+        }
+        let name = req.ssl::<CountingStream<TlsStream>>()
+            .and_then(|x| x.get_ref().client_common_name())
+            .ok_or(ApiError::Unauthorized)?;
+        let is_admin = self.admin_clients.contains(&name);
+        spans::annotate("client", name.clone());
+        Ok(Some(Identity { name: Cow::Owned(name), api_key: None, sdks: None, is_admin }))
+    }
+
+    /// Returns who sent a bearer token.
+    ///
+    /// For calls that carry the token outside of HTTP headers, like the
+    /// metadata of gRPC calls.  Works like `authenticate` otherwise, except
+    /// that client certificates cannot be checked, so with `client_cert`
+    /// every call fails with `unauthorized`.
+    pub fn authenticate_token<'a>(&'a self, token: Option<&str>)
+        -> Result<Option<Identity<'a>>>
+    {
+        let identity = match self.mode {
+            AuthMode::ApiKeys => {
+                if self.api_keys.is_empty() {
+                    return Ok(None);
+                }
+                let api_key = token
+                    .and_then(|token| self.api_keys.iter().find(|x| x.matches(token)))
+                    .ok_or(ApiError::Unauthorized)?;
+                return Ok(Some(api_key_identity(api_key)));
+            }
+            AuthMode::Jwt => {
+                let token = token.ok_or(ApiError::Unauthorized)?;
+                self.jwt.as_ref().unwrap().verify(token)?
+            }
+            AuthMode::ClientCert => {
+                return Err(ApiError::Unauthorized.into());
+            }
+        };
+        spans::annotate("client", identity.name().to_string());
+        Ok(Some(identity))
+    }
+
+    /// Returns who sent a request to an admin endpoint.
+    ///
+    /// Unlike lookups the admin endpoints are never open: requests without
+    /// valid credentials fail with `unauthorized`, also if no API keys are
+    /// configured, and clients that are no admins with `admin_forbidden`.
+    pub fn authenticate_admin<'a>(&'a self, req: &Request) -> Result<Identity<'a>> {
+        match self.authenticate(req)? {
+            Some(identity) => {
+                if identity.is_admin() {
+                    Ok(identity)
+                } else {
+                    Err(ApiError::AdminForbidden.into())
+                }
+            }
+            None => Err(ApiError::Unauthorized.into()),
+        }
+    }
+}
+
+/// Returns the identity of a client that sent an API key.
+fn api_key_identity(api_key: &ApiKey) -> Identity {
+    let name = api_key.name();
+    spans::annotate("api_key", name.to_string());
+    Identity {
+        name,
+        api_key: Some(api_key),
+        sdks: None,
+        is_admin: api_key.is_admin(),
+    }
+}
+
+#[cfg(feature = "crypto")]
+fn check_client_certs(_mode: AuthMode) -> Result<()> {
+    Ok(())
+}
+
+/// Refuses client certificates as this build cannot serve TLS.
+#[cfg(not(feature = "crypto"))]
+fn check_client_certs(mode: AuthMode) -> Result<()> {
+    if mode == AuthMode::ClientCert {
+        return Err(ErrorKind::BadConfigKey(
+            "server.auth", "Client certificates need a build with the crypto feature").into());
+    }
+    Ok(())
+}
+
+/// The claims of a token that are checked.
+#[cfg(feature = "crypto")]
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    exp: Option<i64>,
+    nbf: Option<i64>,
+    iss: Option<String>,
+    /// A string or a list of strings
+    aud: Option<Value>,
+    /// Glob patterns of the SDKs the client may use, like for API keys
+    sdks: Option<Vec<String>>,
+    /// Whether the client may use the admin endpoints
+    admin: Option<bool>,
+}
+
+#[cfg(feature = "crypto")]
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[cfg(feature = "crypto")]
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[cfg(feature = "crypto")]
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[cfg(feature = "crypto")]
+#[derive(Default)]
+struct JwksCache {
+    keys: HashMap<Option<String>, PKey>,
+    fetched: Option<Instant>,
+}
+
+/// The keys published at the JWKS URL.
+///
+/// A background thread fetches them every `JWKS_MAX_AGE` seconds so that
+/// requests only wait for a fetch if a token names a key that is not known
+/// yet.
+#[cfg(feature = "crypto")]
+struct Jwks {
+    url: String,
+    cache: RwLock<JwksCache>,
+}
+
+/// Checks JSON web tokens.
+///
+/// Tokens signed with HS256 are checked with the shared secret and tokens
+/// signed with RS256 with the key of the JWKS their `kid` names.
+#[cfg(feature = "crypto")]
+struct JwtVerifier {
+    secret: Option<Vec<u8>>,
+    jwks: Option<Arc<Jwks>>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    require_exp: bool,
+}
+
+#[cfg(feature = "crypto")]
+fn decode_part<T: ::serde::Deserialize>(part: &str) -> Option<T> {
+    serde_json::from_slice(&part.from_base64().ok()?).ok()
+}
+
+#[cfg(feature = "crypto")]
+impl JwtVerifier {
+    fn from_config(config: &Config) -> Result<JwtVerifier> {
+        let jwks = config.get_server_jwks_url().map(|url| Arc::new(Jwks::new(url)));
+        if let Some(ref jwks) = jwks {
+            spawn_jwks_refresh_thread(jwks);
+        }
+        Ok(JwtVerifier {
+            secret: config.get_server_jwt_secret().map(|x| x.into_bytes()),
+            jwks,
+            issuer: config.get_server_jwt_issuer(),
+            audience: config.get_server_jwt_audience(),
+            require_exp: config.get_server_jwt_require_exp()?,
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<Identity<'static>> {
+        let parts: Vec<_> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(ApiError::Unauthorized.into());
+        }
+        let header: JwtHeader = decode_part(parts[0]).ok_or(ApiError::Unauthorized)?;
+        let signature = parts[2].from_base64().map_err(|_| ApiError::Unauthorized)?;
+        let signed = &token[..token.rfind('.').unwrap()];
+        let valid = match (header.alg.as_str(), &self.secret, &self.jwks) {
+            ("HS256", &Some(ref secret), _) => {
+                let key = PKey::hmac(secret)?;
+                let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+                signer.update(signed.as_bytes())?;
+                let expected = signer.sign_to_vec()?;
+                expected.len() == signature.len() && memcmp::eq(&expected, &signature)
+            }
+            ("RS256", _, &Some(ref jwks)) => {
+                jwks.verify(&header.kid, signed.as_bytes(), &signature)?
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(ApiError::Unauthorized.into());
+        }
+
+        let claims: Claims = decode_part(parts[1]).ok_or(ApiError::Unauthorized)?;
+        let now = Utc::now().timestamp();
+        if claims.exp.map_or(self.require_exp, |exp| exp + JWT_LEEWAY < now) ||
+            claims.nbf.map_or(false, |nbf| nbf - JWT_LEEWAY > now)
+        {
+            return Err(ApiError::Unauthorized.into());
+        }
+        if let Some(ref issuer) = self.issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return Err(ApiError::Unauthorized.into());
+            }
+        }
+        if let Some(ref audience) = self.audience {
+            let matches = match claims.aud {
+                Some(Value::String(ref aud)) => aud == audience,
+                Some(Value::Array(ref auds)) => {
+                    auds.iter().any(|x| x.as_str() == Some(audience.as_str()))
+                }
+                _ => false,
+            };
+            if !matches {
+                return Err(ApiError::Unauthorized.into());
+            }
+        }
+        Ok(Identity {
+            name: Cow::Owned(claims.sub.unwrap_or_else(|| "jwt".into())),
+            api_key: None,
+            sdks: match claims.sdks {
+                Some(sdks) => Some(IgnorePatterns::new(&sdks)
+                    .map_err(|_| ApiError::Unauthorized)?),
+                None => None,
+            },
+            is_admin: claims.admin.unwrap_or(false),
+        })
+    }
+}
+
+/// Builds without the `crypto` feature cannot check tokens, so there is
+/// never a value of this.
+#[cfg(not(feature = "crypto"))]
+enum JwtVerifier {}
+
+#[cfg(not(feature = "crypto"))]
+impl JwtVerifier {
+    fn from_config(_config: &Config) -> Result<JwtVerifier> {
+        Err(ErrorKind::BadConfigKey(
+            "server.auth", "JWT needs a build with the crypto feature").into())
+    }
+
+    fn verify(&self, _token: &str) -> Result<Identity<'static>> {
+        match *self {}
+    }
+}
+
+/// Fetches the keys now and every `JWKS_MAX_AGE` seconds in a new thread.
+///
+/// The thread ends once the authenticator is gone.
+#[cfg(feature = "crypto")]
+fn spawn_jwks_refresh_thread(jwks: &Arc<Jwks>) {
+    let jwks = Arc::downgrade(jwks);
+    thread::spawn(move || {
+        while let Some(jwks) = jwks.upgrade() {
+            jwks.refresh();
+            drop(jwks);
+            thread::sleep(Duration::from_secs(JWKS_MAX_AGE));
+        }
+    });
+}
+
+#[cfg(feature = "crypto")]
+impl Jwks {
+    fn new(url: String) -> Jwks {
+        Jwks {
+            url,
+            cache: RwLock::new(JwksCache::default()),
+        }
+    }
+
+    /// Checks a signature made with a key of the JWKS.
+    ///
+    /// Fetches the JWKS right away if it does not know the key and was not
+    /// fetched within the last `JWKS_MIN_REFRESH` seconds.
+    fn verify(&self, kid: &Option<String>, data: &[u8], signature: &[u8]) -> Result<bool> {
+        let unknown = {
+            let cache = self.cache.read().unwrap();
+            !cache.keys.contains_key(kid) &&
+                cache.fetched.map_or(true, |x| {
+                    x.elapsed() > Duration::from_secs(JWKS_MIN_REFRESH)
+                })
+        };
+        if unknown {
+            self.refresh();
+        }
+
+        let cache = self.cache.read().unwrap();
+        let key = match cache.keys.get(kid) {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+        let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+        verifier.update(data)?;
+        Ok(verifier.verify(signature)?)
+    }
+
+    /// Fetches the keys again.
+    ///
+    /// Keeps the old keys if that fails, but does not ask again before
+    /// `JWKS_MIN_REFRESH` seconds passed.
+    fn refresh(&self) {
+        let fetched = self.fetch();
+        let mut cache = self.cache.write().unwrap();
+        match fetched {
+            Ok(keys) => { cache.keys = keys; }
+            Err(err) => { warn!("Could not fetch JWKS: {}", err); }
+        }
+        cache.fetched = Some(Instant::now());
+    }
+
+    fn fetch(&self) -> Result<HashMap<Option<String>, PKey>> {
+        let mut client = new_hyper_client()?;
+        client.set_read_timeout(Some(Duration::from_secs(JWKS_FETCH_TIMEOUT)));
+        client.set_write_timeout(Some(Duration::from_secs(JWKS_FETCH_TIMEOUT)));
+        let resp = client.get(&self.url).send()
+            .chain_err(|| format!("Could not reach {}", self.url))?;
+        let jwks: JwkSet = serde_json::from_reader(resp).chain_err(|| "Invalid JWKS")?;
+        let mut rv = HashMap::new();
+        for jwk in jwks.keys {
+            if jwk.kty != "RSA" {
+                continue;
+            }
+            if let (Some(n), Some(e)) = (jwk.n, jwk.e) {
+                let n = BigNum::from_slice(&n.from_base64().chain_err(|| "Invalid JWK")?)?;
+                let e = BigNum::from_slice(&e.from_base64().chain_err(|| "Invalid JWK")?)?;
+                rv.insert(jwk.kid, PKey::from_rsa(Rsa::from_public_components(n, e)?)?);
+            }
+        }
+        Ok(rv)
+    }
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn test_verify_hs256_token() {
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+    let mut verifier = JwtVerifier {
+        secret: Some(b"secret".to_vec()),
+        jwks: None,
+        issuer: Some("auth".into()),
+        audience: None,
+        require_exp: true,
+    };
+    let sign = |claims: &str, secret: &[u8]| {
+        let signed = format!("{}.{}", br#"{"alg":"HS256","typ":"JWT"}"#.to_base64(URL_SAFE),
+                             claims.as_bytes().to_base64(URL_SAFE));
+        let key = PKey::hmac(secret).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        format!("{}.{}", signed, signer.sign_to_vec().unwrap().to_base64(URL_SAFE))
+    };
+
+    let exp = Utc::now().timestamp() + 3600;
+
+    let identity = verifier.verify(&sign(&format!(
+        r#"{{"sub":"crash-processor","iss":"auth","exp":{},"sdks":["iOS_*"]}}"#, exp),
+        b"secret")).unwrap();
+    assert_eq!(identity.name(), "crash-processor");
+    assert!(identity.may_access("iOS_10.2.0_14C92"));
+    assert!(!identity.may_access("tvOS_10.1.0_14U593"));
+    assert!(!identity.is_admin());
+    assert!(verifier.verify(&sign(&format!(r#"{{"iss":"auth","exp":{},"admin":true}}"#, exp),
+                                  b"secret")).unwrap().is_admin());
+
+    let valid = format!(r#"{{"iss":"auth","exp":{}}}"#, exp);
+    assert!(verifier.verify(&sign(&valid, b"guessed")).is_err());
+    assert!(verifier.verify(&sign(&format!(r#"{{"iss":"other","exp":{}}}"#, exp), b"secret"))
+            .is_err());
+    assert!(verifier.verify(&sign(r#"{"iss":"auth","exp":1000}"#, b"secret")).is_err());
+
+    // tokens that never expire only pass if that is switched off
+    assert!(verifier.verify(&sign(r#"{"iss":"auth"}"#, b"secret")).is_err());
+    verifier.require_exp = false;
+    assert!(verifier.verify(&sign(r#"{"iss":"auth"}"#, b"secret")).is_ok());
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn test_verify_rs256_token() {
+    use std::mem;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use hyper::server::{Server, Response};
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+    let rsa = Rsa::generate(2048).unwrap();
+    let jwks_body = format!(
+        r#"{{"keys":[{{"kty":"RSA","kid":"current","n":"{}","e":"{}"}}]}}"#,
+        rsa.n().unwrap().to_vec().to_base64(URL_SAFE),
+        rsa.e().unwrap().to_vec().to_base64(URL_SAFE));
+    let key = PKey::from_rsa(rsa).unwrap();
+    let other_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let listening = {
+        let fetches = fetches.clone();
+        Server::http("127.0.0.1:0").unwrap().handle(move |_: Request, resp: Response| {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            resp.send(jwks_body.as_bytes()).unwrap();
+        }).unwrap()
+    };
+    let verifier = JwtVerifier {
+        secret: None,
+        jwks: Some(Arc::new(Jwks::new(format!("http://{}/jwks.json", listening.socket)))),
+        issuer: None,
+        audience: None,
+        require_exp: true,
+    };
+    mem::forget(listening);
+
+    let sign = |kid: &str, key: &PKey| {
+        let header = format!(r#"{{"alg":"RS256","typ":"JWT","kid":"{}"}}"#, kid);
+        let claims = format!(r#"{{"sub":"crash-processor","exp":{}}}"#,
+                             Utc::now().timestamp() + 3600);
+        let signed = format!("{}.{}", header.as_bytes().to_base64(URL_SAFE),
+                             claims.as_bytes().to_base64(URL_SAFE));
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        format!("{}.{}", signed, signer.sign_to_vec().unwrap().to_base64(URL_SAFE))
+    };
+
+    assert_eq!(verifier.verify(&sign("current", &key)).unwrap().name(), "crash-processor");
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    assert!(verifier.verify(&sign("current", &other_key)).is_err());
+
+    // unknown keys only make the server ask again once a minute
+    assert!(verifier.verify(&sign("rotated", &key)).is_err());
+    assert!(verifier.verify(&sign("rotated", &key)).is_err());
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_unnamed_api_key_is_not_logged() {
+    let mut config = Config::default();
+    config.add_server_api_key("very-secret", &["*"]).unwrap();
+    let api_key = &config.get_server_api_keys().unwrap()[0];
+
+    spans::start_request();
+    let identity = api_key_identity(api_key);
+    let trace = spans::finish_request().unwrap();
+    assert!(identity.name().starts_with("key-"));
+    assert!(!identity.name().contains("very-secret"));
+    assert_eq!(trace.details().len(), 1);
+    assert_eq!(trace.details()[0], ("api_key", identity.name().to_string()));
+    assert!(!trace.details()[0].1.contains("very-secret"));
+}
@@ -0,0 +1,96 @@
+//! Counting the requests of keep-alive connections.
+//!
+//! hyper does not tell handlers which connection a request came in on.
+//! The listener wraps every stream it accepts with a counter of its own,
+//! which handlers reach through `Request::ssl` like the TLS stream.
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{NetworkListener, NetworkStream};
+
+/// Wraps the streams a listener accepts in a `CountingStream`.
+#[derive(Clone)]
+pub struct CountingListener<L>(pub L);
+
+/// A connection that counts the requests that came in on it.
+///
+/// hyper reads requests from a clone of the stream, so the clones share
+/// the counter.
+#[derive(Clone)]
+pub struct CountingStream<S> {
+    stream: S,
+    requests: Arc<AtomicUsize>,
+}
+
+impl<S> CountingStream<S> {
+    /// Counts a request and returns how many came in on the connection.
+    pub fn count_request(&self) -> usize {
+        self.requests.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The stream of the connection.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<L: NetworkListener> NetworkListener for CountingListener<L> {
+    type Stream = CountingStream<L::Stream>;
+
+    fn accept(&mut self) -> hyper::Result<CountingStream<L::Stream>> {
+        Ok(CountingStream {
+            stream: self.0.accept()?,
+            requests: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.0.set_write_timeout(dur)
+    }
+}
+
+impl<S: Read> Read for CountingStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for CountingStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: NetworkStream> NetworkStream for CountingStream<S> {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.stream.close(how)
+    }
+}
@@ -0,0 +1,470 @@
+//! Serving the lookup API over gRPC.
+//!
+//! With `server.grpc_port` set the server also answers the `SymbolServer`
+//! service of `proto/symbolserver.proto` on that port.  The calls share
+//! their lookups with the HTTP endpoints of the same names, clients pass
+//! their API key or token as `authorization: Bearer ...` metadata.  Only
+//! the root stash is served, tenants are HTTP only.
+use std::cmp;
+use std::str;
+use std::sync::Arc;
+
+use futures::{stream, Future, Stream};
+use futures::future::Either;
+use grpcio::{pb_de, pb_ser, DuplexSink, Environment, Marshaller, Method, MethodType,
+             RequestStream, RpcContext, RpcStatus, RpcStatusCode, Server, ServerBuilder,
+             Service, ServiceBuilder, UnarySink, WriteFlags};
+use hyper::status::StatusCode;
+use protobuf::{ProtobufEnum, RepeatedField};
+use serde_json;
+
+use super::super::{Error, ErrorKind, Result, ResultExt};
+use super::super::memdb::stash::FuzzyMatchOptions;
+use super::super::sdk::DeviceFamily;
+use super::super::utils::{parse_uuid, Addr, DebugId};
+use super::handlers::{self, BatchFrame, Confidence, MatchedSdk, OwnedSymbol, Symbol};
+use super::server::ServerContext;
+use super::types::{ApiError, get_error_code, get_error_status};
+
+#[allow(dead_code, missing_docs, non_camel_case_types, non_snake_case,
+        non_upper_case_globals, trivial_casts, unused_imports, unused_results,
+        unknown_lints, renamed_and_removed_lints, clippy::all)]
+mod proto {
+    include!(concat!(env!("OUT_DIR"), "/symbolserver.rs"));
+}
+
+/// How many frames a `LookupBatch` call may send at most.
+const MAX_BATCH_FRAMES: usize = 100_000;
+
+const LOOKUP: Method<proto::LookupRequest, proto::LookupResponse> = Method {
+    ty: MethodType::Unary,
+    name: "/symbolserver.v1.SymbolServer/Lookup",
+    req_mar: Marshaller { ser: pb_ser, de: pb_de },
+    resp_mar: Marshaller { ser: pb_ser, de: pb_de },
+};
+
+const LOOKUP_BATCH: Method<proto::BatchFrame, proto::BatchFrameResult> = Method {
+    ty: MethodType::Duplex,
+    name: "/symbolserver.v1.SymbolServer/LookupBatch",
+    req_mar: Marshaller { ser: pb_ser, de: pb_de },
+    resp_mar: Marshaller { ser: pb_ser, de: pb_de },
+};
+
+const LIST_SDKS: Method<proto::ListSdksRequest, proto::ListSdksResponse> = Method {
+    ty: MethodType::Unary,
+    name: "/symbolserver.v1.SymbolServer/ListSdks",
+    req_mar: Marshaller { ser: pb_ser, de: pb_de },
+    resp_mar: Marshaller { ser: pb_ser, de: pb_de },
+};
+
+const RESOLVE_SDK: Method<proto::ResolveSdkRequest, proto::ResolveSdkResponse> = Method {
+    ty: MethodType::Unary,
+    name: "/symbolserver.v1.SymbolServer/ResolveSdk",
+    req_mar: Marshaller { ser: pb_ser, de: pb_de },
+    resp_mar: Marshaller { ser: pb_ser, de: pb_de },
+};
+
+const HEALTH: Method<proto::HealthRequest, proto::HealthResponse> = Method {
+    ty: MethodType::Unary,
+    name: "/symbolserver.v1.SymbolServer/Health",
+    req_mar: Marshaller { ser: pb_ser, de: pb_de },
+    resp_mar: Marshaller { ser: pb_ser, de: pb_de },
+};
+
+/// The running gRPC server, which stops when dropped.
+pub struct GrpcServer {
+    server: Server,
+}
+
+impl GrpcServer {
+    /// Starts serving gRPC if `server.grpc_port` is set.
+    ///
+    /// Calls are answered by `threads` threads, like the listener threads
+    /// of the HTTP server.
+    pub fn start(ctx: &Arc<ServerContext>, threads: usize) -> Result<Option<GrpcServer>> {
+        let port = match ctx.config.get_server_grpc_port()? {
+            Some(port) => port,
+            None => { return Ok(None); }
+        };
+        let (host, _) = ctx.config.get_server_socket_addr()?;
+        let env = Arc::new(Environment::new(threads));
+        let mut server = ServerBuilder::new(env)
+            .register_service(symbol_server(ctx.clone()))
+            .bind(host, port)
+            .build()
+            .chain_err(|| "Could not start the gRPC server")?;
+        server.start();
+        let rv = GrpcServer { server };
+        for addr in rv.addrs() {
+            info!("Serving gRPC on {}", addr);
+        }
+        Ok(Some(rv))
+    }
+
+    /// The addresses the server listens on.
+    pub fn addrs(&self) -> Vec<String> {
+        self.server.bind_addrs().iter()
+            .map(|&(ref host, port)| format!("{}:{}", host, port))
+            .collect()
+    }
+}
+
+/// Builds the `SymbolServer` service.
+fn symbol_server(ctx: Arc<ServerContext>) -> Service {
+    let lookup_ctx = ctx.clone();
+    let batch_ctx = ctx.clone();
+    let list_ctx = ctx.clone();
+    let resolve_ctx = ctx.clone();
+    ServiceBuilder::new()
+        .add_unary_handler(&LOOKUP, move |rpc, req, sink| {
+            let rv = lookup(&lookup_ctx, &rpc, req);
+            reply(&rpc, sink, rv);
+        })
+        .add_duplex_streaming_handler(&LOOKUP_BATCH, move |rpc, frames, sink| {
+            lookup_batch(&batch_ctx, &rpc, frames, sink);
+        })
+        .add_unary_handler(&LIST_SDKS, move |rpc, req, sink| {
+            let rv = list_sdks(&list_ctx, &rpc, &req);
+            reply(&rpc, sink, rv);
+        })
+        .add_unary_handler(&RESOLVE_SDK, move |rpc, req, sink| {
+            let rv = resolve_sdk(&resolve_ctx, &rpc, &req);
+            reply(&rpc, sink, rv);
+        })
+        .add_unary_handler(&HEALTH, move |rpc, _req, sink| {
+            let rv = health(&ctx);
+            reply(&rpc, sink, rv);
+        })
+        .build()
+}
+
+/// Answers a unary call with its result or error.
+fn reply<T>(rpc: &RpcContext, sink: UnarySink<T>, rv: Result<T>) {
+    let f = match rv {
+        Ok(rv) => sink.success(rv),
+        Err(err) => sink.fail(to_status(&err)),
+    };
+    rpc.spawn(f.map_err(|err| debug!("Could not answer gRPC call: {:?}", err)));
+}
+
+fn lookup(ctx: &ServerContext, rpc: &RpcContext, mut req: proto::LookupRequest)
+    -> Result<proto::LookupResponse>
+{
+    let token = bearer_token(rpc);
+    let identity = ctx.auth.authenticate_token(token.as_ref().map(|x| x.as_str()))?;
+    let mut match_opts = ctx.stash.fuzzy_match_options();
+    apply_match_options(&mut match_opts, req.get_match_options());
+    let symbols = req.take_symbols().into_vec().iter()
+        .map(to_symbol)
+        .collect::<Result<Vec<_>>>()?;
+    let (symbols, matched) = handlers::lookup_symbols(
+        ctx, identity.as_ref(), req.get_sdk_id(), req.get_cpu_name(), symbols, &match_opts)?;
+
+    let mut rv = proto::LookupResponse::new();
+    for sym in symbols {
+        let mut result = proto::LookupResult::new();
+        if let Some(sym) = sym {
+            result.set_symbol(to_resolved_symbol(sym));
+        }
+        rv.mut_symbols().push(result);
+    }
+    rv.set_matched_sdks(to_matched_sdks(&matched));
+    Ok(rv)
+}
+
+/// Looks up the frames of a `LookupBatch` call once all of them are in.
+///
+/// The credentials are checked before the frames are read.  As the
+/// identity borrows from the server context they are checked again once
+/// the frames are in.
+fn lookup_batch(ctx: &Arc<ServerContext>, rpc: &RpcContext,
+                frames: RequestStream<proto::BatchFrame>,
+                sink: DuplexSink<proto::BatchFrameResult>)
+{
+    let token = bearer_token(rpc);
+    if let Err(err) = ctx.auth.authenticate_token(token.as_ref().map(|x| x.as_str())) {
+        rpc.spawn(sink.fail(to_status(&err))
+            .map_err(|err| debug!("Could not answer gRPC call: {:?}", err)));
+        return;
+    }
+    let ctx = ctx.clone();
+    let f = frames.take(MAX_BATCH_FRAMES as u64 + 1).collect()
+        .and_then(move |frames| {
+            match lookup_frames(&ctx, token.as_ref().map(|x| x.as_str()), &frames) {
+                Ok(results) => {
+                    let results = results.into_iter().map(|x| (x, WriteFlags::default()));
+                    Either::A(sink.send_all(stream::iter_ok(results)).map(|_| ()))
+                }
+                Err(err) => Either::B(sink.fail(to_status(&err))),
+            }
+        })
+        .map_err(|err| debug!("Could not answer gRPC call: {:?}", err));
+    rpc.spawn(f);
+}
+
+fn lookup_frames(ctx: &ServerContext, token: Option<&str>, frames: &[proto::BatchFrame])
+    -> Result<Vec<proto::BatchFrameResult>>
+{
+    let identity = ctx.auth.authenticate_token(token)?;
+    if frames.len() > MAX_BATCH_FRAMES {
+        return Err(ApiError::PayloadTooLarge.into());
+    }
+    let mut match_opts = ctx.stash.fuzzy_match_options();
+    if let Some(frame) = frames.first() {
+        apply_match_options(&mut match_opts, frame.get_match_options());
+    }
+    let frames = frames.iter().map(to_batch_frame).collect::<Result<Vec<_>>>()?;
+    let (rv, _) = handlers::lookup_frames(ctx, identity.as_ref(), "", &frames, &match_opts)?;
+
+    let matched_sdks = rv.matched_sdks;
+    Ok(rv.frames.into_iter().map(|(id, frame)| {
+        let mut result = proto::BatchFrameResult::new();
+        result.set_id(id);
+        if let Some(matched) = matched_sdks.get(&frame.sdk_id) {
+            result.set_matched_sdks(to_matched_sdks(matched));
+        }
+        result.set_sdk_id(frame.sdk_id);
+        if let Some(sym) = frame.symbol {
+            result.set_symbol(to_resolved_symbol(sym));
+        }
+        if let Some(error) = frame.error {
+            result.set_error(error.to_string());
+        }
+        result
+    }).collect())
+}
+
+fn list_sdks(ctx: &ServerContext, rpc: &RpcContext, req: &proto::ListSdksRequest)
+    -> Result<proto::ListSdksResponse>
+{
+    let token = bearer_token(rpc);
+    let identity = ctx.auth.authenticate_token(token.as_ref().map(|x| x.as_str()))?;
+    let max = ctx.config.get_server_max_page_size()?;
+    let limit = match req.get_limit() {
+        0 => max,
+        limit => cmp::min(limit as usize, max),
+    };
+    let sdks = handlers::list_sdks(ctx, identity.as_ref(), req.get_conversions(),
+                                   req.get_offset() as usize, limit)?;
+
+    let mut rv = proto::ListSdksResponse::new();
+    for conversion in sdks.conversions.unwrap_or_default() {
+        let mut item = proto::SdkConversion::new();
+        item.set_sdk_id(conversion.sdk_id().to_string());
+        item.set_format_version(conversion.format_version().unwrap_or(0));
+        item.set_device_family(to_device_family(conversion.device_family()));
+        item.set_product_type(conversion.product_type().unwrap_or("").to_string());
+        if let Some(info) = conversion.conversion() {
+            item.set_conversion(serde_json::to_string(info)
+                .chain_err(|| "Failed to serialize response for client")?);
+        }
+        rv.mut_conversions().push(item);
+    }
+    rv.set_sdks(RepeatedField::from_vec(sdks.sdks));
+    rv.set_total(sdks.total as u32);
+    if let Some(next_offset) = sdks.next_offset {
+        let mut value = proto::UInt32Value::new();
+        value.set_value(next_offset as u32);
+        rv.set_next_offset(value);
+    }
+    Ok(rv)
+}
+
+fn resolve_sdk(ctx: &ServerContext, rpc: &RpcContext, req: &proto::ResolveSdkRequest)
+    -> Result<proto::ResolveSdkResponse>
+{
+    let token = bearer_token(rpc);
+    let identity = ctx.auth.authenticate_token(token.as_ref().map(|x| x.as_str()))?;
+    let mut match_opts = ctx.stash.fuzzy_match_options();
+    apply_match_options(&mut match_opts, req.get_match_options());
+    let resolved = handlers::resolve_sdk(ctx, identity.as_ref(), req.get_os_version(),
+                                         &match_opts)?;
+
+    let mut rv = proto::ResolveSdkResponse::new();
+    rv.set_sdk_id(resolved.sdk_id);
+    rv.set_matched_sdks(to_matched_sdks(&resolved.matched_sdks));
+    Ok(rv)
+}
+
+fn health(ctx: &ServerContext) -> Result<proto::HealthResponse> {
+    let health = ctx.get_healthcheck_result()?;
+    let mut rv = proto::HealthResponse::new();
+    rv.set_is_offline(health.is_offline);
+    rv.set_is_healthy(health.is_healthy);
+    rv.set_is_degraded(health.is_degraded);
+    rv.set_sync_lag(health.sync_lag);
+    rv.set_revision(health.revision);
+    rv.set_last_sync(health.last_sync.unwrap_or(0));
+    rv.set_last_sync_error(health.last_sync_error.unwrap_or_default());
+    rv.set_sync_warning(health.sync_warning.unwrap_or_default());
+    rv.set_sync_failures(health.sync_failures);
+    rv.set_sync_restarts(health.sync_restarts);
+    Ok(rv)
+}
+
+/// Returns the token of the `authorization: Bearer ...` metadata of a call.
+fn bearer_token(rpc: &RpcContext) -> Option<String> {
+    rpc.request_headers().iter()
+        .find(|&(key, _)| key.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| str::from_utf8(value).ok())
+        .and_then(|value| if value.starts_with("Bearer ") {
+            Some(value[7..].trim().to_string())
+        } else {
+            None
+        })
+}
+
+/// Maps an error to the gRPC status closest to its HTTP status.
+///
+/// The details start with the error code of the HTTP API.
+fn to_status(err: &Error) -> RpcStatus {
+    let code = match get_error_status(err) {
+        StatusCode::BadRequest => RpcStatusCode::InvalidArgument,
+        StatusCode::Unauthorized => RpcStatusCode::Unauthenticated,
+        StatusCode::Forbidden => RpcStatusCode::PermissionDenied,
+        StatusCode::NotFound | StatusCode::Gone => RpcStatusCode::NotFound,
+        StatusCode::Conflict => RpcStatusCode::FailedPrecondition,
+        StatusCode::PayloadTooLarge => RpcStatusCode::ResourceExhausted,
+        StatusCode::ServiceUnavailable | StatusCode::InsufficientStorage => {
+            RpcStatusCode::Unavailable
+        }
+        _ => {
+            error!("Internal Server Error: {}", err);
+            RpcStatusCode::Internal
+        }
+    };
+    let details = match *err.kind() {
+        ErrorKind::ApiError(_) => get_error_code(err).to_string(),
+        _ => format!("{}: {}", get_error_code(err), err),
+    };
+    RpcStatus::new(code, Some(details))
+}
+
+/// Applies the match options of a call over the configured ones.
+fn apply_match_options(match_opts: &mut FuzzyMatchOptions, opts: &proto::MatchOptions) {
+    if opts.get_max_results() > 0 {
+        match_opts.max_results = opts.get_max_results() as usize;
+    }
+    if opts.has_max_score() {
+        match_opts.max_score = Some(opts.get_max_score().get_value());
+    }
+    match_opts.device_family = DeviceFamily::from_u32(opts.get_device_family().value() as u32);
+    match_opts.product_type = non_empty(opts.get_product_type());
+}
+
+fn to_symbol(sym: &proto::Symbol) -> Result<Symbol> {
+    let object_uuid = match sym.get_object_uuid() {
+        "" => None,
+        value => Some(parse_uuid(value).ok_or(ApiError::BadRequest)?),
+    };
+    let debug_id = match sym.get_debug_id() {
+        "" => None,
+        value => Some(DebugId::parse(value).ok_or(ApiError::BadRequest)?),
+    };
+    let module_offset = non_zero(sym.get_module_offset());
+    Ok(Symbol {
+        object_uuid,
+        debug_id,
+        object_name: non_empty(sym.get_object_name()),
+        // an address of zero is a real address unless the offset is sent
+        addr: if module_offset.is_some() && sym.get_addr() == 0 {
+            None
+        } else {
+            Some(Addr(sym.get_addr()))
+        },
+        module_offset,
+        image_addr: non_zero(sym.get_image_addr()),
+        image_vmaddr: non_zero(sym.get_image_vmaddr()),
+        slide: non_zero(sym.get_slide()),
+    })
+}
+
+fn to_batch_frame(frame: &proto::BatchFrame) -> Result<BatchFrame> {
+    let symq = to_symbol(frame.get_symbol())?;
+    Ok(BatchFrame {
+        id: non_empty(frame.get_id()),
+        sdk_id: frame.get_sdk_id().to_string(),
+        cpu_name: non_empty(frame.get_cpu_name()),
+        object_uuid: symq.object_uuid,
+        debug_id: symq.debug_id,
+        object_name: symq.object_name,
+        addr: symq.addr,
+        module_offset: symq.module_offset,
+        image_addr: symq.image_addr,
+        image_vmaddr: symq.image_vmaddr,
+        slide: symq.slide,
+    })
+}
+
+fn to_resolved_symbol(sym: OwnedSymbol) -> proto::ResolvedSymbol {
+    let mut rv = proto::ResolvedSymbol::new();
+    rv.set_object_uuid(sym.object_uuid.to_string());
+    rv.set_object_name(sym.object_name);
+    rv.set_symbol(sym.symbol);
+    rv.set_addr(sym.addr.0);
+    rv.set_confidence(match sym.confidence {
+        Confidence::Exact => proto::Confidence::EXACT,
+        Confidence::Approximate => proto::Confidence::APPROXIMATE,
+        Confidence::Guessed => proto::Confidence::GUESSED,
+    });
+    rv.set_recovered(sym.recovered.unwrap_or(false));
+    if let Some(source) = sym.source {
+        rv.set_source(source.to_string());
+    }
+    rv
+}
+
+fn to_matched_sdks(matched: &[MatchedSdk]) -> RepeatedField<proto::MatchedSdk> {
+    matched.iter().map(|x| {
+        let mut rv = proto::MatchedSdk::new();
+        rv.set_sdk_id(x.sdk_id.clone());
+        rv.set_score(x.score);
+        rv
+    }).collect()
+}
+
+fn to_device_family(family: Option<DeviceFamily>) -> proto::DeviceFamily {
+    family.and_then(|x| proto::DeviceFamily::from_i32(x.to_u32() as i32))
+        .unwrap_or(proto::DeviceFamily::DEVICE_FAMILY_UNKNOWN)
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() { None } else { Some(value.to_string()) }
+}
+
+fn non_zero(value: u64) -> Option<Addr> {
+    if value == 0 { None } else { Some(Addr(value)) }
+}
+
+#[test]
+fn test_symbol_fields() {
+    let mut sym = proto::Symbol::new();
+    sym.set_debug_id("FE6D76D48C3A3A9A9F63F4A475501F1B0".into());
+    sym.set_module_offset(0x1a2f8);
+    let symq = to_symbol(&sym).unwrap();
+    assert_eq!(symq.debug_id, DebugId::parse("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b"));
+    assert!(symq.addr.is_none());
+    assert_eq!(symq.module_offset.map(|x| x.0), Some(0x1a2f8));
+    assert!(symq.image_addr.is_none());
+
+    let mut sym = proto::Symbol::new();
+    sym.set_object_name("/usr/lib/libobjc.A.dylib".into());
+    let symq = to_symbol(&sym).unwrap();
+    assert_eq!(symq.addr.map(|x| x.0), Some(0));
+    assert!(symq.module_offset.is_none());
+
+    let mut sym = proto::Symbol::new();
+    sym.set_object_uuid("not a uuid".into());
+    assert!(to_symbol(&sym).is_err());
+}
+
+#[test]
+fn test_error_status() {
+    let status = to_status(&ApiError::SdkNotFound.into());
+    assert_eq!(status.status, RpcStatusCode::NotFound);
+    assert_eq!(status.details, Some("sdk_not_found".to_string()));
+    let status = to_status(&ApiError::Unauthorized.into());
+    assert_eq!(status.status, RpcStatusCode::Unauthenticated);
+    let status = to_status(&ErrorKind::SdkUpdating("iOS_10.2.0_14C92".into()).into());
+    assert_eq!(status.status, RpcStatusCode::Unavailable);
+}
@@ -1,34 +1,125 @@
 //! The handlers for the API endpoints.
+use std::cmp;
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::result::Result as StdResult;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use hyper::server::Request;
 use hyper::status::StatusCode;
 use hyper::method::Method;
+use hyper::uri::RequestUri;
+use hyper::header::{ByteRangeSpec, ContentType, HttpDate, IfModifiedSince, IfNoneMatch,
+                    IfRange, Range};
+use time;
+use serde::{Serialize, Serializer, ser};
+use serde::ser::SerializeSeq;
 use uuid::Uuid;
 
-use super::super::Result;
-use super::super::constants::VERSION;
-use super::super::utils::Addr;
-use super::super::sdk::SdkInfo;
+use super::super::{Result, Error, ErrorKind};
+use super::super::constants::{get_build_timestamp, get_features, GIT_COMMIT, RUSTC_VERSION,
+                               VERSION};
+use super::super::allocator::{AllocatorStats, get_allocator_stats};
+use super::super::utils::{Addr, DebugId, deserialize_loose_uuid, IgnorePatterns, get_resident_memory, get_thread_count};
+use super::super::sdk::{DeviceFamily, SdkInfo};
+use super::super::dsym::SymbolSource;
 use super::super::memdb::read::{MemDb, Symbol as MemDbSymbol};
-use super::super::memdb::stash::MemDbStash;
-use super::server::{ServerContext, load_request_data};
-use super::types::{ApiResponse, ApiError};
+use super::super::memdb::audit::AuditEvent;
+use super::super::memdb::usage::SdkUsage;
+use super::super::memdb::types::{MEMDB_VERSION, MIN_MEMDB_VERSION};
+use super::super::memdb::stash::{AuditStats, FuzzyMatchOptions, GcStats, MemDbCacheStats,
+                                 MemDbStash, QuarantinedSdk, SdkConversion, SdkMatch};
+use super::auth::Identity;
+use super::server::{ServerContext, get_query_param, parse_request_data, read_request_body};
+use super::types::{ApiResponse, ApiError, ResponseFormat, get_error_code};
+use super::sdk_metrics::{LookupOutcome, SdkLookupStats};
+use super::spans;
 
 #[derive(Deserialize)]
 struct SymbolLookupRequest {
     sdk_id: String,
     cpu_name: String,
     symbols: Vec<Symbol>,
+    device_family: Option<DeviceFamily>,
+    product_type: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Symbol {
-    object_uuid: Option<Uuid>,
-    object_name: Option<String>,
-    symbol: Option<String>,
+/// A symbol to look up.
+///
+/// `addr` is relative to the image unless `image_addr` (where the image was
+/// loaded) or `image_vmaddr` and `slide` are given, in which case it is the
+/// absolute address.  Breakpad based clients can instead name the image by
+/// its `debug_id` and send the `module_offset`, which is always relative.
+#[derive(Deserialize)]
+pub struct Symbol {
+    #[serde(default, deserialize_with="deserialize_loose_uuid")]
+    pub object_uuid: Option<Uuid>,
+    pub debug_id: Option<DebugId>,
+    pub object_name: Option<String>,
+    pub addr: Option<Addr>,
+    pub module_offset: Option<Addr>,
+    pub image_addr: Option<Addr>,
+    pub image_vmaddr: Option<Addr>,
+    pub slide: Option<Addr>,
+}
+
+#[derive(Deserialize)]
+struct BatchLookupRequest {
+    cpu_name: String,
+    frames: Vec<BatchFrame>,
+    device_family: Option<DeviceFamily>,
+    product_type: Option<String>,
+}
+
+/// A frame of a batch lookup, which names its own SDK.
+#[derive(Deserialize)]
+pub struct BatchFrame {
+    /// Identifies the frame in the response (its index if not given)
+    pub id: Option<String>,
+    pub sdk_id: String,
+    /// Overrides the `cpu_name` of the request
+    pub cpu_name: Option<String>,
+    #[serde(default, deserialize_with="deserialize_loose_uuid")]
+    pub object_uuid: Option<Uuid>,
+    pub debug_id: Option<DebugId>,
+    pub object_name: Option<String>,
+    pub addr: Option<Addr>,
+    pub module_offset: Option<Addr>,
+    pub image_addr: Option<Addr>,
+    pub image_vmaddr: Option<Addr>,
+    pub slide: Option<Addr>,
+}
+
+/// How sure we are that a resolved symbol is correct.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confidence {
+    /// Found in the requested SDK.
+    #[serde(rename = "exact")]
+    Exact,
+    /// Found in an alias or a fuzzy match of the requested SDK.
+    #[serde(rename = "approximate")]
+    Approximate,
+    /// The address lies past the last known symbol of the object.
+    #[serde(rename = "guessed")]
+    Guessed,
+}
+
+/// A symbol that borrows its strings from the memdb it was found in.
+///
+/// `recovered` is only sent for symbols recovered from Objective-C or
+/// Swift metadata and `source` only for lookups with `?debug=1`.
+#[derive(Serialize)]
+struct ResolvedSymbol<'a> {
+    object_uuid: Uuid,
+    object_name: &'a str,
+    symbol: &'a str,
     addr: Addr,
+    confidence: Confidence,
+    #[serde(skip_serializing_if="Option::is_none")]
+    recovered: Option<bool>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    source: Option<SymbolSource>,
 }
 
 macro_rules! assert_method {
@@ -40,35 +131,292 @@ macro_rules! assert_method {
     }
 }
 
-impl<'a> From<MemDbSymbol<'a>> for Symbol {
-    fn from(sym: MemDbSymbol<'a>) -> Symbol {
-        Symbol {
-            object_uuid: Some(sym.object_uuid()),
-            object_name: Some(sym.object_name().to_string()),
-            symbol: Some(sym.symbol().to_string()),
-            addr: Addr(sym.addr()),
+impl<'a> From<ResolvedSymbol<'a>> for OwnedSymbol {
+    fn from(sym: ResolvedSymbol<'a>) -> OwnedSymbol {
+        OwnedSymbol {
+            object_uuid: sym.object_uuid,
+            object_name: sym.object_name.to_string(),
+            symbol: sym.symbol.to_string(),
+            addr: sym.addr,
+            confidence: sym.confidence,
+            recovered: sym.recovered,
+            source: sym.source,
+        }
+    }
+}
+
+impl<'b> ResolvedSymbol<'b> {
+    fn new<'a>(sym: &'b MemDbSymbol<'a>, image_base: u64, confidence: Confidence)
+        -> ResolvedSymbol<'b>
+    {
+        ResolvedSymbol {
+            object_uuid: sym.object_uuid(),
+            object_name: sym.object_name(),
+            symbol: sym.symbol(),
+            addr: Addr(sym.addr().wrapping_add(image_base)),
+            confidence,
+            recovered: if sym.is_recovered() { Some(true) } else { None },
+            source: None,
+        }
+    }
+}
+
+impl Symbol {
+    /// Turns the Breakpad style `debug_id` and `module_offset` into the
+    /// `object_uuid` and relative `addr` the lookups work with.
+    fn normalize(&mut self) -> Result<()> {
+        if let Some(DebugId(uuid)) = self.debug_id.take() {
+            if self.object_uuid.map_or(false, |x| x != uuid) {
+                return Err(ApiError::BadRequest.into());
+            }
+            self.object_uuid = Some(uuid);
+        }
+        if let Some(offset) = self.module_offset.take() {
+            if self.addr.is_some() || self.image_addr.is_some() ||
+               self.image_vmaddr.is_some() || self.slide.is_some() {
+                return Err(ApiError::BadRequest.into());
+            }
+            self.addr = Some(offset);
         }
+        if self.addr.is_none() {
+            return Err(ApiError::BadRequest.into());
+        }
+        self.relative_addr().map(|_| ())
+    }
+
+    /// Returns the address as sent by the client.
+    fn addr(&self) -> u64 {
+        self.addr.map_or(0, |x| x.0)
     }
+
+    /// Returns the address the image was loaded at if `addr` is absolute.
+    fn image_base(&self) -> Result<Option<u64>> {
+        match (self.image_addr, self.image_vmaddr, self.slide) {
+            (Some(Addr(image_addr)), _, _) => Ok(Some(image_addr)),
+            (None, Some(Addr(vmaddr)), slide) => {
+                Ok(Some(vmaddr.wrapping_add(slide.map_or(0, |x| x.0))))
+            }
+            (None, None, Some(_)) => Err(ApiError::BadRequest.into()),
+            (None, None, None) => Ok(None),
+        }
+    }
+
+    /// Returns the address relative to the image, which is what memdbs
+    /// are keyed by.
+    fn relative_addr(&self) -> Result<u64> {
+        match self.image_base()? {
+            Some(base) => self.addr().checked_sub(base).ok_or_else(|| {
+                ErrorKind::AddressBeforeImage(self.addr(), base).into()
+            }),
+            None => Ok(self.addr()),
+        }
+    }
+}
+
+/// Looks up symbols while the response is being serialized.
+///
+/// This way the symbol strings are written straight from the memdb into
+/// the response body without being copied into owned strings first.
+struct SymbolLookup<'a> {
+    ctx: &'a ServerContext,
+    cpu_name: &'a str,
+    sdks: &'a [SdkMatch],
+    is_aliased: bool,
+    symbols: &'a [Symbol],
+    cache: RefCell<LocalMemDbCache<'a>>,
+    error: RefCell<Option<Error>>,
+    outcome: RefCell<LookupOutcome>,
+    /// Collects the near misses while serializing if set
+    near_misses: Option<RefCell<Vec<NearMiss>>>,
+    /// Adds the source of every symbol to the response
+    with_sources: bool,
+}
+
+#[derive(Serialize)]
+struct SymbolResponse<'a> {
+    symbols: &'a SymbolLookup<'a>,
+    matched_sdks: Vec<MatchedSdk>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    near_misses: Option<NearMisses<'a>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    debug: Option<LookupDebug<'a>>,
+}
+
+/// The symbols around an address that did not resolve to a symbol of
+/// its own.
+///
+/// `index` is the position of the symbol in the request and `distance` is
+/// in bytes from the requested address.
+#[derive(Serialize)]
+struct NearMiss {
+    index: usize,
+    preceding: Option<NearbySymbol>,
+    following: Option<NearbySymbol>,
 }
 
 #[derive(Serialize)]
-struct SymbolResponse {
-    symbols: Vec<Option<Symbol>>,
+struct NearbySymbol {
+    symbol: String,
+    addr: Addr,
+    distance: u64,
+}
+
+/// The near misses of a lookup with `?near_misses=1`.
+///
+/// Serialized after the symbols which is when they are collected.
+struct NearMisses<'a> {
+    lookup: &'a SymbolLookup<'a>,
+}
+
+/// The timings of a lookup with `?debug=1`.
+///
+/// Serialized after the symbols so that it covers their lookup.
+struct LookupDebug<'a> {
+    lookup: &'a SymbolLookup<'a>,
+    started: Instant,
+}
+
+#[derive(Serialize)]
+struct LookupDebugInfo {
+    sdk_match: &'static str,
+    total_ms: f64,
+    phases: BTreeMap<&'static str, f64>,
+    memdbs: Vec<MemDbOpenInfo>,
+}
+
+/// How the memdb of an SDK was obtained for a request.
+#[derive(Serialize, Clone)]
+struct MemDbOpenInfo {
+    sdk_id: String,
+    source: &'static str,
+    duration_ms: f64,
+}
+
+#[derive(Serialize)]
+pub struct MatchedSdk {
+    pub sdk_id: String,
+    pub score: u32,
+}
+
+#[derive(Serialize)]
+pub struct BatchLookupResponse {
+    pub frames: BTreeMap<String, BatchFrameResult>,
+    pub matched_sdks: BTreeMap<String, Vec<MatchedSdk>>,
+}
+
+/// The result of a single frame of a batch lookup.
+///
+/// `error` is set if the SDK of the frame cannot be used, in which case
+/// `symbol` is `null`.
+#[derive(Serialize)]
+pub struct BatchFrameResult {
+    pub sdk_id: String,
+    pub symbol: Option<OwnedSymbol>,
+    pub error: Option<&'static str>,
+}
+
+/// A resolved symbol with its strings copied out of the memdb.
+#[derive(Serialize)]
+pub struct OwnedSymbol {
+    pub object_uuid: Uuid,
+    pub object_name: String,
+    pub symbol: String,
+    pub addr: Addr,
+    pub confidence: Confidence,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub recovered: Option<bool>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub source: Option<SymbolSource>,
+}
+
+#[derive(Serialize)]
+pub struct SdksResponse {
+    pub sdks: Vec<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub conversions: Option<Vec<SdkConversion>>,
+    pub total: usize,
+    pub next_offset: Option<usize>,
 }
 
 #[derive(Serialize)]
-struct SdksResponse {
-    sdks: Vec<String>,
+pub struct ResolveSdkResponse {
+    pub sdk_id: String,
+    pub matched_sdks: Vec<MatchedSdk>,
 }
 
 #[derive(Serialize)]
 struct VersionResponse {
-    version: String,
+    version: &'static str,
+    git_commit: Option<&'static str>,
+    build_timestamp: Option<i64>,
+    rustc_version: Option<&'static str>,
+    features: Vec<&'static str>,
+    memdb_versions: MemDbVersions,
+    config_fingerprint: String,
+}
+
+#[derive(Serialize)]
+struct MemDbVersions {
+    supported: Vec<u32>,
+    written: u32,
+}
+
+#[derive(Serialize)]
+struct MetricsResponse {
+    requests: usize,
+    requests_in_flight: usize,
+    open_memdbs: usize,
+    max_open_memdbs: Option<usize>,
+    quarantined_memdbs: Vec<QuarantinedSdk>,
+    garbage_collection: GcStats,
+    sample_audits: AuditStats,
+    memdb_cache: MemDbCacheStats,
+    allocator: AllocatorStats,
+    sdks: Vec<SdkLookupStats>,
+}
+
+#[derive(Deserialize)]
+struct IgnoreOverrideRequest {
+    patterns: IgnorePatterns,
+}
+
+#[derive(Serialize)]
+struct IgnoreResponse {
+    config: IgnorePatterns,
+    #[serde(rename="override")]
+    ignore_override: Option<IgnorePatterns>,
+}
+
+#[derive(Serialize)]
+struct UsageResponse<'a> {
+    sdks: BTreeMap<&'a str, &'a SdkUsage>,
+    total: usize,
+    next_offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct AuditResponse {
+    events: Vec<AuditEvent>,
+    next_offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct MemoryResponse {
+    resident_memory: Option<u64>,
+    mapped_memdbs: usize,
+    mapped_memdb_size: u64,
+    local_sdks: usize,
+    usage_stats_sdks: usize,
+    threads: Option<usize>,
+    listener_threads: usize,
+    worker_threads: usize,
+    io_threads: usize,
 }
 
 struct LocalMemDbCache<'a> {
     stash: &'a MemDbStash,
     cache: HashMap<SdkInfo, Arc<MemDb<'static>>>,
+    opened: Vec<MemDbOpenInfo>,
 }
 
 impl<'a> LocalMemDbCache<'a> {
@@ -76,6 +424,7 @@ impl<'a> LocalMemDbCache<'a> {
         LocalMemDbCache {
             stash: stash,
             cache: HashMap::new(),
+            opened: vec![],
         }
     }
 
@@ -83,10 +432,212 @@ impl<'a> LocalMemDbCache<'a> {
         if let Some(memdb) = self.cache.get(&info) {
             return Ok(memdb.clone());
         }
-        let rv = self.stash.get_memdb(info)?;
+        let started = Instant::now();
+        let was_open = self.stash.is_memdb_open(info);
+        let rv = {
+            let _span = spans::span("memdb_open");
+            self.stash.get_memdb(info)?
+        };
+        self.opened.push(MemDbOpenInfo {
+            sdk_id: info.sdk_id(),
+            source: if was_open { "cache_hit" } else { "fresh_mmap" },
+            duration_ms: spans::as_millis(started.elapsed()),
+        });
         self.cache.insert(info.clone(), rv.clone());
         Ok(rv)
     }
+
+    /// The memdbs that were looked up so far and where they came from
+    pub fn opened(&self) -> &[MemDbOpenInfo] {
+        &self.opened
+    }
+}
+
+impl<'a> SymbolLookup<'a> {
+    /// Returns the memdb to look up a symbol in, which is the memdb of the
+    /// shared object for images of thin SDKs.
+    fn get_object_memdb(&self, memdb: Arc<MemDb<'static>>, symq: &Symbol)
+        -> Result<Arc<MemDb<'static>>>
+    {
+        if !memdb.is_thin() {
+            return Ok(memdb);
+        }
+        let uuid = if let Some(ref uuid) = symq.object_uuid {
+            Some(*uuid)
+        } else if let Some(ref name) = symq.object_name {
+            memdb.find_uuid(name, self.cpu_name)?.cloned()
+        } else {
+            None
+        };
+        match uuid {
+            Some(uuid) => self.ctx.stash.get_object_memdb(memdb, &uuid),
+            None => Ok(memdb),
+        }
+    }
+
+    /// Records the symbols around an address that was not resolved or only
+    /// guessed if near misses were requested.
+    fn record_near_miss(&self, index: usize, symq: &Symbol) -> Result<()> {
+        let near_misses = match self.near_misses {
+            Some(ref near_misses) => near_misses,
+            None => { return Ok(()); }
+        };
+        if symq.object_uuid.is_none() && symq.object_name.is_none() {
+            return Ok(());
+        }
+        let addr = symq.relative_addr()?;
+        let image_base = symq.image_base()?.unwrap_or(0);
+        let mut cache = self.cache.borrow_mut();
+        for sdk in self.sdks {
+            let memdb = self.get_object_memdb(cache.get_memdb(sdk.info())?, symq)?;
+            let uuid = if let Some(ref uuid) = symq.object_uuid {
+                *uuid
+            } else if let Some(uuid) = symq.object_name.as_ref()
+                .map_or(Ok(None), |name| memdb.find_uuid(name, self.cpu_name))? {
+                *uuid
+            } else {
+                continue;
+            };
+            if !memdb.has_uuid(&uuid) {
+                continue;
+            }
+            let (preceding, following) = memdb.nearby_symbols(&uuid, addr);
+            near_misses.borrow_mut().push(NearMiss {
+                index,
+                preceding: preceding.map(|sym| NearbySymbol {
+                    symbol: sym.symbol().to_string(),
+                    addr: Addr(sym.addr().wrapping_add(image_base)),
+                    distance: addr - sym.addr(),
+                }),
+                following: following.map(|sym| NearbySymbol {
+                    symbol: sym.symbol().to_string(),
+                    addr: Addr(sym.addr().wrapping_add(image_base)),
+                    distance: sym.addr() - addr,
+                }),
+            });
+            break;
+        }
+        Ok(())
+    }
+
+    fn resolve<R, F>(&self, symq: &Symbol, f: F) -> Result<R>
+        where F: for<'b> FnOnce(Option<ResolvedSymbol<'b>>) -> R
+    {
+        let mut outcome = self.outcome.borrow_mut();
+        outcome.symbols += 1;
+        if symq.object_uuid.is_none() && symq.object_name.is_none() {
+            outcome.unresolved += 1;
+            return Ok(f(None));
+        }
+
+        let addr = symq.relative_addr()?;
+        let image_base = symq.image_base()?.unwrap_or(0);
+        let mut cache = self.cache.borrow_mut();
+        let mut knows_object = false;
+        for sdk in self.sdks {
+            let sdk_info = sdk.info();
+            let memdb = self.get_object_memdb(cache.get_memdb(sdk_info)?, symq)?;
+            let sym = {
+                let _span = spans::span("search");
+                if let Some(ref uuid) = symq.object_uuid {
+                    memdb.lookup_by_uuid(uuid, addr)
+                } else if let Some(ref name) = symq.object_name {
+                    memdb.lookup_by_object_name(name, self.cpu_name, addr)
+                } else {
+                    None
+                }
+            };
+            if let Some(sym) = sym {
+                self.ctx.stash.record_lookup(sdk_info, &sym.object_uuid(), sym.object_name());
+                let confidence = if sym.is_last_in_object() && addr > sym.addr() {
+                    Confidence::Guessed
+                } else if self.is_aliased || sdk.score() > 0 {
+                    Confidence::Approximate
+                } else {
+                    Confidence::Exact
+                };
+                let mut rv = ResolvedSymbol::new(&sym, image_base, confidence);
+                if self.with_sources {
+                    rv.source = sym.source();
+                }
+                return Ok(f(Some(rv)));
+            }
+            if !knows_object {
+                knows_object = if let Some(ref uuid) = symq.object_uuid {
+                    memdb.has_uuid(uuid)
+                } else if let Some(ref name) = symq.object_name {
+                    memdb.find_uuid(name, self.cpu_name)?.is_some()
+                } else {
+                    false
+                };
+            }
+        }
+        outcome.unresolved += 1;
+        if !knows_object {
+            outcome.unknown_objects += 1;
+        }
+        Ok(f(None))
+    }
+}
+
+impl<'a> Serialize for NearMisses<'a> {
+    fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match self.lookup.near_misses {
+            Some(ref near_misses) => near_misses.borrow().serialize(serializer),
+            None => serializer.serialize_unit(),
+        }
+    }
+}
+
+impl<'a> Serialize for LookupDebug<'a> {
+    fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let sdk_match = if self.lookup.is_aliased {
+            "alias"
+        } else if self.lookup.sdks.first().map_or(false, |x| x.score() > 0) {
+            "fuzzy_fallback"
+        } else {
+            "exact"
+        };
+        LookupDebugInfo {
+            sdk_match,
+            total_ms: spans::as_millis(self.started.elapsed()),
+            phases: spans::current_phases().into_iter()
+                .map(|(name, duration)| (name, spans::as_millis(duration)))
+                .collect(),
+            memdbs: self.lookup.cache.borrow().opened().to_vec(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'a> Serialize for SymbolLookup<'a> {
+    fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let mut seq = serializer.serialize_seq(Some(self.symbols.len()))?;
+        for (idx, symq) in self.symbols.iter().enumerate() {
+            let rv = self.resolve(symq, |sym| {
+                let is_miss = sym.as_ref().map_or(true, |x| x.confidence == Confidence::Guessed);
+                (is_miss, seq.serialize_element(&sym))
+            }).and_then(|(is_miss, rv)| {
+                if is_miss {
+                    self.record_near_miss(idx, symq)?;
+                }
+                Ok(rv)
+            });
+            match rv {
+                Ok(rv) => rv?,
+                Err(err) => {
+                    *self.error.borrow_mut() = Some(err);
+                    return Err(ser::Error::custom("symbol lookup failed"));
+                }
+            }
+        }
+        seq.end()
+    }
 }
 
 /// Implements the health check.
@@ -102,60 +653,879 @@ pub fn healthcheck_handler(ctx: &ServerContext, req: Request) -> Result<ApiRespo
     ApiResponse::new(rv, status)
 }
 
+/// Reports whether the server should receive traffic.
+pub fn readiness_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    let rv = ctx.get_readiness_result()?;
+    let status = if rv.is_ready {
+        StatusCode::Ok
+    } else {
+        StatusCode::ServiceUnavailable
+    };
+    ApiResponse::new(rv, status)
+}
+
+/// Makes the server report itself as not ready ahead of a shutdown.
+///
+/// Also accepts `GET` as that is all an `httpGet` preStop hook sends.
+pub fn drain_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    if req.method != Method::Get {
+        assert_method!(req, Method::Post);
+    }
+    ctx.auth.authenticate_admin(&req)?;
+    ctx.start_draining();
+    ApiResponse::new(ctx.get_readiness_result()?, StatusCode::Ok)
+}
+
 /// Implements the system symbol lookup.
+///
+/// With `?debug=1` the response also says how the SDK was matched, where
+/// its memdbs came from and how long each step of the request took.
 pub fn lookup_symbol_handler(ctx: &ServerContext, mut req: Request) -> Result<ApiResponse>
 {
     assert_method!(req, Method::Post);
-    let data: SymbolLookupRequest = load_request_data(&mut req)?;
-    let sdk_infos = ctx.stash.fuzzy_match_sdk_id(&data.sdk_id)?;
-    if sdk_infos.is_empty() {
-        return Err(ApiError::SdkNotFound.into());
+    let mut match_opts = ctx.stash.fuzzy_match_options();
+    if let Some(max_results) = get_query_param(&req, "max_results")? {
+        match_opts.max_results = max_results;
+    }
+    if let Some(max_score) = get_query_param(&req, "max_score")? {
+        match_opts.max_score = Some(max_score);
+    }
+    let debug = get_query_param::<u32>(&req, "debug")?.map_or(false, |x| x != 0);
+    let near_misses = get_query_param::<u32>(&req, "near_misses")?.map_or(false, |x| x != 0);
+    let format = ResponseFormat::from_request(&req);
+    let identity = ctx.auth.authenticate(&req)?;
+    let body = read_request_body(&mut req)?;
+    let mut data: SymbolLookupRequest = parse_request_data(&body)?;
+    ctx.record_lookup(&req, &body);
+    for symq in &mut data.symbols {
+        symq.normalize()?;
     }
+    match_opts.device_family = data.device_family;
+    match_opts.product_type = data.product_type.clone();
+    spans::annotate("sdk_id", data.sdk_id.clone());
+    spans::annotate("cpu_name", data.cpu_name.clone());
+    spans::annotate("symbols", data.symbols.len().to_string());
+    let started = Instant::now();
+    let (matches, is_aliased) = match match_sdks(ctx, &data.sdk_id, &match_opts, identity.as_ref()) {
+        Ok(rv) => rv,
+        Err(err) => {
+            ctx.sdk_metrics.record(&data.sdk_id, &match_failure_outcome(&err),
+                                   started.elapsed());
+            return Err(err);
+        }
+    };
 
-    let mut lc = LocalMemDbCache::new(&ctx.stash);
+    let lookup = SymbolLookup {
+        ctx,
+        cpu_name: &data.cpu_name,
+        sdks: &matches,
+        is_aliased,
+        symbols: &data.symbols,
+        cache: RefCell::new(LocalMemDbCache::new(&ctx.stash)),
+        error: RefCell::new(None),
+        outcome: RefCell::new(Default::default()),
+        near_misses: if near_misses { Some(RefCell::new(vec![])) } else { None },
+        with_sources: debug,
+    };
+    let rv = ApiResponse::new_with_format(SymbolResponse {
+        symbols: &lookup,
+        matched_sdks: matched_sdks(&matches),
+        near_misses: if near_misses { Some(NearMisses { lookup: &lookup }) } else { None },
+        debug: if debug {
+            Some(LookupDebug { lookup: &lookup, started })
+        } else {
+            None
+        },
+    }, StatusCode::Ok, format);
+    ctx.sdk_metrics.record(&data.sdk_id, &lookup.outcome.borrow(), started.elapsed());
 
-    let mut rv = vec![];
-    for symq in data.symbols {
-        let mut rvsym = None;
-        if let Some(ref uuid) = symq.object_uuid {
-            for sdk_info in sdk_infos.iter() {
-                if let Some(sym) = lc.get_memdb(sdk_info)?.lookup_by_uuid(
-                   uuid, symq.addr.into()) {
-                    rvsym = Some(sym.into());
-                    break;
+    // errors from the lookup itself take precedence over the generic
+    // serialization error they caused.
+    if let Some(err) = lookup.error.borrow_mut().take() {
+        return Err(err);
+    }
+
+    // let clients and caches know when the result might change
+    let mut etags = vec![];
+    for sdk in matches.iter() {
+        if let Some(etag) = ctx.stash.get_sdk_etag(sdk.info())? {
+            etags.push(etag);
+        }
+    }
+    let rv = with_staleness(ctx, rv?, matches.iter());
+    sign_response(ctx, rv
+       .with_header("X-Symbolserver-Revision", ctx.stash.get_revision()?.to_string())
+       .with_header("X-Symbolserver-Sdk-Etag", etags.join(", "))
+       .with_header("Vary", "Accept".to_string()))
+}
+
+/// Implements symbol lookups of frames from different SDKs.
+///
+/// Frames are grouped by SDK and CPU so that every memdb is only looked
+/// at once.  The results are keyed by the `id` of the frames and a frame
+/// whose SDK cannot be used carries the error code instead of failing the
+/// whole request.  `max_results` and `max_score` work like for `/lookup`.
+pub fn batch_lookup_handler(ctx: &ServerContext, mut req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Post);
+    let mut match_opts = ctx.stash.fuzzy_match_options();
+    if let Some(max_results) = get_query_param(&req, "max_results")? {
+        match_opts.max_results = max_results;
+    }
+    if let Some(max_score) = get_query_param(&req, "max_score")? {
+        match_opts.max_score = Some(max_score);
+    }
+    let format = ResponseFormat::from_request(&req);
+    let identity = ctx.auth.authenticate(&req)?;
+    let body = read_request_body(&mut req)?;
+    let data: BatchLookupRequest = parse_request_data(&body)?;
+    ctx.record_lookup(&req, &body);
+    match_opts.device_family = data.device_family;
+    match_opts.product_type = data.product_type.clone();
+    spans::annotate("frames", data.frames.len().to_string());
+    let (rv, is_stale) = lookup_frames(ctx, identity.as_ref(), &data.cpu_name, &data.frames,
+                                       &match_opts)?;
+
+    let mut rv = ApiResponse::new_with_format(rv, StatusCode::Ok, format)?;
+    if is_stale {
+        rv = rv.with_header("X-Symbol-Staleness", "updating".to_string());
+    }
+    sign_response(ctx, rv
+       .with_header("X-Symbolserver-Revision", ctx.stash.get_revision()?.to_string())
+       .with_header("Vary", "Accept".to_string()))
+}
+
+/// Looks up the frames of a batch lookup.
+///
+/// Also returns whether one of the matched SDKs is being updated.  Shared
+/// by `/lookup/batch` and the `LookupBatch` call of the gRPC service.
+pub fn lookup_frames(ctx: &ServerContext, identity: Option<&Identity>, cpu_name: &str,
+                     frames: &[BatchFrame], match_opts: &FuzzyMatchOptions)
+    -> Result<(BatchLookupResponse, bool)>
+{
+    let mut groups: BTreeMap<(&str, &str), Vec<(String, Symbol)>> = BTreeMap::new();
+    for (idx, frame) in frames.iter().enumerate() {
+        let cpu_name = frame.cpu_name.as_ref().map_or(cpu_name, |x| x.as_str());
+        let mut symq = Symbol {
+            object_uuid: frame.object_uuid,
+            debug_id: frame.debug_id,
+            object_name: frame.object_name.clone(),
+            addr: frame.addr,
+            module_offset: frame.module_offset,
+            image_addr: frame.image_addr,
+            image_vmaddr: frame.image_vmaddr,
+            slide: frame.slide,
+        };
+        symq.normalize()?;
+        groups.entry((&frame.sdk_id, cpu_name)).or_insert_with(Vec::new).push((
+            frame.id.clone().unwrap_or_else(|| idx.to_string()),
+            symq,
+        ));
+    }
+
+    let mut rv = BatchLookupResponse {
+        frames: BTreeMap::new(),
+        matched_sdks: BTreeMap::new(),
+    };
+    let mut matched = HashMap::new();
+    let mut outcomes: HashMap<&str, (LookupOutcome, Duration)> = HashMap::new();
+    for &(sdk_id, _) in groups.keys() {
+        if matched.contains_key(sdk_id) {
+            continue;
+        }
+        let started = Instant::now();
+        let result = match match_sdks(ctx, sdk_id, match_opts, identity) {
+            Ok(rv) => Ok(rv),
+            Err(err) => match get_error_code(&err) {
+                "internal_server_error" => { return Err(err); }
+                code => {
+                    outcomes.insert(sdk_id, (match_failure_outcome(&err), started.elapsed()));
+                    Err(code)
                 }
+            },
+        };
+        matched.insert(sdk_id, result);
+    }
+
+    for ((sdk_id, cpu_name), symbols) in groups {
+        let started = Instant::now();
+        let (matches, is_aliased) = match matched[sdk_id] {
+            Ok((ref matches, is_aliased)) => (matches, is_aliased),
+            Err(code) => {
+                for (id, _) in symbols {
+                    rv.frames.insert(id, BatchFrameResult {
+                        sdk_id: sdk_id.to_string(),
+                        symbol: None,
+                        error: Some(code),
+                    });
+                }
+                continue;
             }
-        } else if let Some(ref name) = symq.object_name {
-            for sdk_info in sdk_infos.iter() {
-                if let Some(sym) = lc.get_memdb(sdk_info)?.lookup_by_object_name(
-                   name, &data.cpu_name, symq.addr.into()) {
-                    rvsym = Some(sym.into());
-                    break;
+        };
+        let lookup = SymbolLookup {
+            ctx,
+            cpu_name,
+            sdks: matches,
+            is_aliased,
+            symbols: &[],
+            cache: RefCell::new(LocalMemDbCache::new(&ctx.stash)),
+            error: RefCell::new(None),
+            outcome: RefCell::new(Default::default()),
+            near_misses: None,
+            with_sources: false,
+        };
+        for (id, symq) in symbols {
+            let symbol = lookup.resolve(&symq, |sym| sym.map(OwnedSymbol::from))?;
+            rv.frames.insert(id, BatchFrameResult {
+                sdk_id: sdk_id.to_string(),
+                symbol,
+                error: None,
+            });
+        }
+        // frames of one SDK with different CPUs count as one request
+        let group_outcome = lookup.outcome.into_inner();
+        let entry = outcomes.entry(sdk_id).or_insert_with(Default::default);
+        entry.0.symbols += group_outcome.symbols;
+        entry.0.unresolved += group_outcome.unresolved;
+        entry.0.unknown_objects += group_outcome.unknown_objects;
+        entry.1 += started.elapsed();
+    }
+    for (sdk_id, (outcome, duration)) in outcomes {
+        ctx.sdk_metrics.record(sdk_id, &outcome, duration);
+    }
+    let is_stale = matched.values().filter_map(|x| x.as_ref().ok())
+        .any(|&(ref matches, _)| matches.iter().any(|x| ctx.stash.is_updating(x.info())));
+    for (sdk_id, result) in matched {
+        if let Ok((matches, _)) = result {
+            rv.matched_sdks.insert(sdk_id.to_string(), matched_sdks(&matches));
+        }
+    }
+    Ok((rv, is_stale))
+}
+
+/// Looks up symbols in the SDKs an SDK id matches.
+///
+/// Unlike `/lookup`, which writes the symbols from the memdbs straight
+/// into the response, this copies them out for the `Lookup` call of the
+/// gRPC service.  Unresolved symbols are `None`.
+pub fn lookup_symbols(ctx: &ServerContext, identity: Option<&Identity>, sdk_id: &str,
+                      cpu_name: &str, mut symbols: Vec<Symbol>, match_opts: &FuzzyMatchOptions)
+    -> Result<(Vec<Option<OwnedSymbol>>, Vec<MatchedSdk>)>
+{
+    for symq in &mut symbols {
+        symq.normalize()?;
+    }
+    let started = Instant::now();
+    let (matches, is_aliased) = match match_sdks(ctx, sdk_id, match_opts, identity) {
+        Ok(rv) => rv,
+        Err(err) => {
+            ctx.sdk_metrics.record(sdk_id, &match_failure_outcome(&err), started.elapsed());
+            return Err(err);
+        }
+    };
+    let lookup = SymbolLookup {
+        ctx,
+        cpu_name,
+        sdks: &matches,
+        is_aliased,
+        symbols: &[],
+        cache: RefCell::new(LocalMemDbCache::new(&ctx.stash)),
+        error: RefCell::new(None),
+        outcome: RefCell::new(Default::default()),
+        near_misses: None,
+        with_sources: false,
+    };
+    let mut rv = vec![];
+    for symq in &symbols {
+        rv.push(lookup.resolve(symq, |sym| sym.map(OwnedSymbol::from))?);
+    }
+    ctx.sdk_metrics.record(sdk_id, &lookup.outcome.borrow(), started.elapsed());
+    Ok((rv, matched_sdks(&matches)))
+}
+
+/// Describes the SDKs a lookup used for the response.
+fn matched_sdks(matches: &[SdkMatch]) -> Vec<MatchedSdk> {
+    matches.iter().map(|x| MatchedSdk {
+        sdk_id: x.info().sdk_id(),
+        score: x.score(),
+    }).collect()
+}
+
+/// Marks a response that used SDKs the running sync replaces with a newer
+/// version, as the result might change once the download is done.
+fn with_staleness<'a, I>(ctx: &ServerContext, rv: ApiResponse, mut sdks: I) -> ApiResponse
+    where I: Iterator<Item=&'a SdkMatch>
+{
+    if sdks.any(|x| ctx.stash.is_updating(x.info())) {
+        rv.with_header("X-Symbol-Staleness", "updating".to_string())
+    } else {
+        rv
+    }
+}
+
+/// Adds the signature of the body if responses are signed.
+fn sign_response(ctx: &ServerContext, rv: ApiResponse) -> Result<ApiResponse> {
+    let signer = match ctx.signer {
+        Some(ref signer) => signer,
+        None => { return Ok(rv); }
+    };
+    let signature = match rv.data() {
+        Some(data) => {
+            let _span = spans::span("sign");
+            Some(signer.sign(data)?)
+        }
+        None => None,
+    };
+    Ok(match signature {
+        Some(signature) => rv.with_header("X-Symbolserver-Signature", signature)
+            .with_header("X-Symbolserver-Key-Id", signer.key_id().to_string()),
+        None => rv,
+    })
+}
+
+/// The lookup metrics of an SDK that could not be matched.
+fn match_failure_outcome(err: &Error) -> LookupOutcome {
+    LookupOutcome {
+        sdk_not_found: get_error_code(err) == "sdk_not_found",
+        ..Default::default()
+    }
+}
+
+/// Finds the SDKs a lookup for an SDK id uses.
+///
+/// Also returns whether the SDK id is an alias.  Fails if the SDK was
+/// withdrawn, the client may not use it or nothing matches.
+fn match_sdks(ctx: &ServerContext, sdk_id: &str, match_opts: &FuzzyMatchOptions,
+              identity: Option<&Identity>)
+    -> Result<(Vec<SdkMatch>, bool)>
+{
+    let _span = spans::span("stash_lookup");
+    check_access(identity, sdk_id)?;
+    // never fall back to a similar SDK for one that was recalled
+    if let Some(info) = SdkInfo::from_filename(sdk_id) {
+        let info = ctx.stash.resolve_sdk_alias(&info);
+        if let Some(withdrawn) = ctx.stash.get_withdrawn(&info)? {
+            return Err(ErrorKind::SdkWithdrawn(
+                info.sdk_id(), withdrawn.reason().to_string()).into());
+        }
+    }
+
+    let mut matches = ctx.stash.fuzzy_match_sdk_id_with_scores(sdk_id, match_opts)?;
+    if let Some(identity) = identity {
+        // similar SDKs the client may not use are no match either
+        matches.retain(|x| identity.may_access(&x.info().sdk_id()));
+    }
+    if matches.is_empty() {
+        // without `sync.serve_stale` SDKs that are being updated do not match
+        if let Some(info) = SdkInfo::from_filename(sdk_id) {
+            if ctx.stash.is_updating(&ctx.stash.resolve_sdk_alias(&info)) {
+                return Err(ErrorKind::SdkUpdating(info.sdk_id()).into());
+            }
+        }
+        return Err(ApiError::SdkNotFound.into());
+    }
+    let is_aliased = SdkInfo::from_filename(sdk_id).map_or(false, |info| {
+        ctx.stash.resolve_sdk_alias(&info) != info
+    });
+    Ok((matches, is_aliased))
+}
+
+/// Fails with `sdk_forbidden` if the client may not use an SDK.
+fn check_access(identity: Option<&Identity>, sdk_id: &str) -> Result<()> {
+    match identity {
+        Some(identity) if !identity.may_access(sdk_id) => Err(ApiError::SdkForbidden.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Sends the memdb of an SDK in the stash.
+///
+/// `/download/<sdk_id>.memdb` is the memdb itself and
+/// `/download/<sdk_id>.memdbz` the compressed memdb, which only exists if
+/// the stash keeps memdbs compressed.  A single byte range can be
+/// requested and `If-Modified-Since` and `If-Range` are honored.
+pub fn download_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    let filename = match req.uri {
+        RequestUri::AbsolutePath(ref path) => {
+            path.splitn(2, '?').next().unwrap_or("")
+                .trim_start_matches("/download/").to_string()
+        }
+        _ => { return Err(ApiError::BadRequest.into()); }
+    };
+    let compressed = if filename.ends_with(".memdbz") {
+        true
+    } else if filename.ends_with(".memdb") {
+        false
+    } else {
+        return Err(ApiError::NotFound.into());
+    };
+    let identity = ctx.auth.authenticate(&req)?;
+    let info = SdkInfo::from_filename(&filename).ok_or(ApiError::SdkNotFound)?;
+    spans::annotate("sdk_id", info.sdk_id());
+    check_access(identity.as_ref(), &info.sdk_id())?;
+    let memdb_file = {
+        let _span = spans::span("memdb_open");
+        ctx.stash.open_memdb_file(&info, compressed)?.ok_or(ApiError::NotFound)?
+    };
+
+    let modified = memdb_file.modified().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64).unwrap_or(0);
+    let last_modified = HttpDate(time::at_utc(time::Timespec::new(modified, 0)));
+    let is_unchanged_since = |date: &HttpDate| date.0.to_timespec().sec >= modified;
+    let size = memdb_file.size();
+    let mut headers = vec![
+        ("Accept-Ranges", "bytes".to_string()),
+        ("Last-Modified", last_modified.to_string()),
+    ];
+    if let Some(etag) = memdb_file.etag() {
+        headers.push(("ETag", format!("\"{}\"", etag)));
+    }
+
+    if let Some(&IfModifiedSince(ref date)) = req.headers.get() {
+        if is_unchanged_since(date) {
+            return Ok(with_headers(ApiResponse::empty(StatusCode::NotModified), headers));
+        }
+    }
+
+    // a range only applies to the version of the file the client has
+    let range_applies = match req.headers.get() {
+        Some(&IfRange::EntityTag(ref tag)) => memdb_file.etag() == Some(tag.tag()),
+        Some(&IfRange::Date(ref date)) => is_unchanged_since(date),
+        None => true,
+    };
+    let range = match req.headers.get() {
+        Some(&Range::Bytes(ref specs)) if range_applies && specs.len() == 1 => {
+            match byte_range(&specs[0], size) {
+                Some(range) => Some(range),
+                None => {
+                    let rv = ApiResponse::empty(StatusCode::RangeNotSatisfiable)
+                        .with_header("Content-Range", format!("bytes */{}", size));
+                    return Ok(with_headers(rv, headers));
                 }
             }
         }
-        rv.push(rvsym);
+        _ => None,
+    };
+
+    let content_type = if compressed {
+        ContentType("application/x-xz".parse().unwrap())
+    } else {
+        ContentType("application/octet-stream".parse().unwrap())
+    };
+    let (start, len, status) = match range {
+        Some((start, end)) => {
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end, size)));
+            (start, end - start + 1, StatusCode::PartialContent)
+        }
+        None => (0, size, StatusCode::Ok),
+    };
+    let rv = match memdb_file.data() {
+        Some(data) => {
+            let data = data[start as usize..(start + len) as usize].to_vec();
+            ApiResponse::from_data(data, status, content_type)
+        }
+        None => {
+            let file = memdb_file.file().unwrap().try_clone()?;
+            ApiResponse::from_file(file, start, len, status, content_type)
+        }
+    };
+    Ok(with_headers(rv, headers))
+}
+
+/// Resolves a byte range against a file of the given size.
+///
+/// Returns the first and last byte or `None` if the range cannot be
+/// satisfied.
+fn byte_range(spec: &ByteRangeSpec, size: u64) -> Option<(u64, u64)> {
+    match *spec {
+        ByteRangeSpec::FromTo(start, end) if start < size && start <= end => {
+            Some((start, end.min(size - 1)))
+        }
+        ByteRangeSpec::AllFrom(start) if start < size => Some((start, size - 1)),
+        ByteRangeSpec::Last(len) if len > 0 && size > 0 => {
+            Some((size - len.min(size), size - 1))
+        }
+        _ => None,
     }
+}
 
-    ApiResponse::new(SymbolResponse {
-        symbols: rv,
-    }, StatusCode::Ok)
+/// How many audit events a request may skip at most
+const MAX_AUDIT_OFFSET: usize = 100_000;
+
+/// The part of a listing a request asks for.
+struct Page {
+    offset: usize,
+    limit: usize,
+}
+
+impl Page {
+    /// Reads the `offset` and `limit` query parameters.
+    ///
+    /// The limit defaults to `default` or `server.max_page_size` and is
+    /// capped at the latter.
+    fn from_request(ctx: &ServerContext, req: &Request, default: Option<usize>)
+        -> Result<Page>
+    {
+        let max = ctx.config.get_server_max_page_size()?;
+        let limit = get_query_param(req, "limit")?.or(default).unwrap_or(max);
+        if limit == 0 {
+            return Err(ApiError::BadRequest.into());
+        }
+        Ok(Page {
+            offset: get_query_param(req, "offset")?.unwrap_or(0),
+            limit: cmp::min(limit, max),
+        })
+    }
+
+    /// The offset of the next page if `count` of `total` entries were sent.
+    fn next_offset(&self, count: usize, total: usize) -> Option<usize> {
+        if self.offset.saturating_add(count) < total {
+            Some(self.offset + count)
+        } else {
+            None
+        }
+    }
+}
+
+fn with_headers(mut rv: ApiResponse, headers: Vec<(&'static str, String)>) -> ApiResponse {
+    for (name, value) in headers {
+        rv = rv.with_header(name, value);
+    }
+    rv
+}
+
+/// Describes the stash for servers following this one.
+///
+/// The ETag is the revision of the stash, so a follower that sends it as
+/// `If-None-Match` gets `304 Not Modified` until the stash changes.  Only
+/// the SDKs the client may use are listed.
+pub fn mirror_state_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    let identity = ctx.auth.authenticate(&req)?;
+    let mut state = ctx.stash.mirror_state()?;
+    if let Some(ref identity) = identity {
+        state.retain(|sdk_id| identity.may_access(sdk_id));
+    }
+    let etag = state.revision().to_string();
+    if let Some(&IfNoneMatch::Items(ref tags)) = req.headers.get() {
+        if tags.iter().any(|tag| tag.tag() == etag) {
+            return Ok(ApiResponse::empty(StatusCode::NotModified)
+                      .with_header("ETag", format!("\"{}\"", etag)));
+        }
+    }
+    Ok(ApiResponse::new(state, StatusCode::Ok)?
+       .with_header("ETag", format!("\"{}\"", etag)))
+}
+
+/// Hands the object manifests and shared objects of thin SDKs to servers
+/// following this one.
+///
+/// `/mirror/objects/<sdk_id>` returns the object manifest of an SDK and
+/// `/mirror/objects/<sdk_id>/<uuid>` the memdb of one of its objects.
+pub fn mirror_objects_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    let path = match req.uri {
+        RequestUri::AbsolutePath(ref path) => {
+            path.splitn(2, '?').next().unwrap_or("")
+                .trim_start_matches("/mirror/objects/").to_string()
+        }
+        _ => { return Err(ApiError::BadRequest.into()); }
+    };
+    let (sdk_id, uuid) = match path.find('/') {
+        Some(idx) => (&path[..idx], Some(&path[idx + 1..])),
+        None => (&path[..], None),
+    };
+    let identity = ctx.auth.authenticate(&req)?;
+    let info = SdkInfo::from_filename(sdk_id).ok_or(ApiError::SdkNotFound)?;
+    spans::annotate("sdk_id", info.sdk_id());
+    check_access(identity.as_ref(), &info.sdk_id())?;
+    match uuid {
+        None => {
+            let manifest = ctx.stash.get_object_manifest(&info)?.ok_or(ApiError::NotFound)?;
+            Ok(ApiResponse::from_data(manifest.to_vec()?, StatusCode::Ok, ContentType::json()))
+        }
+        Some(uuid) => {
+            let uuid: Uuid = uuid.parse().map_err(|_| ApiError::NotFound)?;
+            let data = ctx.stash.read_shared_object(&info, &uuid)?.ok_or(ApiError::NotFound)?;
+            Ok(ApiResponse::from_data(data, StatusCode::Ok,
+                                      ContentType("application/octet-stream".parse().unwrap())))
+        }
+    }
 }
 
-/// Lists all found SDKs.
+/// Lists all found SDKs the client may use.
+///
+/// With `?conversions=1` the response also says how the memdb of every SDK
+/// was converted.
 pub fn list_sdks_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
 {
     assert_method!(req, Method::Get);
-    ApiResponse::new(SdksResponse {
-        sdks: ctx.stash.list_sdks()?.into_iter().map(|x| x.sdk_id()).collect(),
-    }, StatusCode::Ok)
+    let conversions = get_query_param::<u32>(&req, "conversions")?.map_or(false, |x| x != 0);
+    let mut page = Page::from_request(ctx, &req, None)?;
+    if ctx.config.get_server_unpaged_sdk_list()? &&
+        get_query_param::<usize>(&req, "limit")?.is_none()
+    {
+        page.limit = ::std::usize::MAX;
+    }
+    let identity = ctx.auth.authenticate(&req)?;
+    ApiResponse::new(list_sdks(ctx, identity.as_ref(), conversions, page.offset, page.limit)?,
+                     StatusCode::Ok)
+}
+
+/// Lists `limit` of the SDKs a client may use starting at `offset`.
+///
+/// Shared by `/sdks` and the `ListSdks` call of the gRPC service.
+pub fn list_sdks(ctx: &ServerContext, identity: Option<&Identity>, conversions: bool,
+                 offset: usize, limit: usize)
+    -> Result<SdksResponse>
+{
+    let page = Page { offset, limit };
+    let mut sdks = ctx.stash.list_sdks()?;
+    if let Some(identity) = identity {
+        sdks.retain(|x| identity.may_access(&x.sdk_id()));
+    }
+    let total = sdks.len();
+    let sdks: Vec<_> = sdks.into_iter().skip(page.offset).take(page.limit).collect();
+    Ok(SdksResponse {
+        conversions: if conversions {
+            Some(ctx.stash.get_sdk_conversions(&sdks)?)
+        } else {
+            None
+        },
+        next_offset: page.next_offset(sdks.len(), total),
+        sdks: sdks.into_iter().map(|x| x.sdk_id()).collect(),
+        total,
+    })
+}
+
+/// Finds the SDKs a lookup would use for an OS version from a crash report.
+///
+/// Takes the `os_version` (for instance `iPhone OS 16.3.1 (20D67)`) and
+/// the `device_family`, `product_type`, `max_results` and `max_score` of
+/// lookups as query parameters.
+pub fn resolve_sdk_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    let os_version: String = get_query_param(&req, "os_version")?
+        .ok_or(ApiError::BadRequest)?;
+    let mut match_opts = ctx.stash.fuzzy_match_options();
+    if let Some(max_results) = get_query_param(&req, "max_results")? {
+        match_opts.max_results = max_results;
+    }
+    if let Some(max_score) = get_query_param(&req, "max_score")? {
+        match_opts.max_score = Some(max_score);
+    }
+    match_opts.device_family = get_query_param(&req, "device_family")?;
+    match_opts.product_type = get_query_param(&req, "product_type")?;
+    let identity = ctx.auth.authenticate(&req)?;
+    ApiResponse::new(resolve_sdk(ctx, identity.as_ref(), &os_version, &match_opts)?,
+                     StatusCode::Ok)
+}
+
+/// Finds the SDKs a lookup would use for an OS version.
+///
+/// Shared by `/sdks/resolve` and the `ResolveSdk` call of the gRPC service.
+pub fn resolve_sdk(ctx: &ServerContext, identity: Option<&Identity>, os_version: &str,
+                   match_opts: &FuzzyMatchOptions)
+    -> Result<ResolveSdkResponse>
+{
+    let info = SdkInfo::from_os_version(os_version)
+        .ok_or_else(|| Error::from(ErrorKind::InvalidOsVersion(os_version.to_string())))?;
+    let (matches, _) = match_sdks(ctx, &info.sdk_id(), match_opts, identity)?;
+    Ok(ResolveSdkResponse {
+        sdk_id: info.sdk_id(),
+        matched_sdks: matched_sdks(&matches),
+    })
+}
+
+/// Sends the public key lookup responses are signed with.
+pub fn signing_key_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    match ctx.signer {
+        Some(ref signer) => ApiResponse::new(signer.key_info(), StatusCode::Ok),
+        None => Err(ApiError::NotFound.into()),
+    }
 }
 
 /// Server version info.
-pub fn version_handler(_ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+///
+/// Describes the build and the config so that servers of a fleet can be
+/// compared.
+pub fn version_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
 {
     assert_method!(req, Method::Get);
     ApiResponse::new(VersionResponse {
-        version: VERSION.to_string(),
+        version: VERSION,
+        git_commit: GIT_COMMIT,
+        build_timestamp: get_build_timestamp(),
+        rustc_version: RUSTC_VERSION,
+        features: get_features(),
+        memdb_versions: MemDbVersions {
+            supported: (MIN_MEMDB_VERSION..=MEMDB_VERSION).collect(),
+            written: MEMDB_VERSION,
+        },
+        config_fingerprint: ctx.config.fingerprint(),
+    }, StatusCode::Ok)
+}
+
+/// Reports internal metrics of the server.
+pub fn metrics_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    let (requests, requests_in_flight) = ctx.request_counts();
+    ApiResponse::new(MetricsResponse {
+        requests,
+        requests_in_flight,
+        open_memdbs: ctx.stash.open_memdb_count(),
+        max_open_memdbs: ctx.stash.max_open_memdbs(),
+        quarantined_memdbs: ctx.stash.list_quarantined()?,
+        garbage_collection: ctx.stash.gc_stats(),
+        sample_audits: ctx.stash.audit_stats(),
+        memdb_cache: ctx.stash.memdb_cache_stats(),
+        allocator: get_allocator_stats(),
+        sdks: ctx.sdk_metrics.top(ctx.config.get_server_sdk_metrics_top()?),
+    }, StatusCode::Ok)
+}
+
+/// Reports the lookup counters of SDKs and objects.
+///
+/// Accepts `limit` and `offset` query parameters like `/sdks`, the page
+/// is over the SDKs in the order of their ids.
+pub fn usage_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    ctx.auth.authenticate_admin(&req)?;
+    let page = Page::from_request(ctx, &req, None)?;
+    let usage = ctx.stash.get_usage_stats();
+    let total = usage.sdk_count();
+    let sdks = usage.page(page.offset, page.limit);
+    ApiResponse::new(UsageResponse {
+        next_offset: page.next_offset(sdks.len(), total),
+        sdks,
+        total,
+    }, StatusCode::Ok)
+}
+
+/// Returns the most recent changes to the stash.
+///
+/// Accepts `sdk_id`, `limit` (defaults to 100) and `offset` query parameters.
+pub fn audit_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    ctx.auth.authenticate_admin(&req)?;
+    let sdk_id: Option<String> = get_query_param(&req, "sdk_id")?;
+    let mut page = Page::from_request(ctx, &req, Some(100))?;
+    page.offset = cmp::min(page.offset, MAX_AUDIT_OFFSET);
+    // one more than asked for tells if there is a next page
+    let end = page.offset.saturating_add(page.limit);
+    let mut events = ctx.stash.audit_log().query(
+        sdk_id.as_ref().map(|x| x.as_str()), end.saturating_add(1))?;
+    let more = events.len() > end;
+    let events: Vec<_> = events.drain(..).skip(page.offset).take(page.limit).collect();
+    ApiResponse::new(AuditResponse {
+        next_offset: if more { Some(page.offset + events.len()) } else { None },
+        events,
+    }, StatusCode::Ok)
+}
+
+/// Shows and overrides the ignore patterns of the stash.
+///
+/// `PUT` overrides the patterns from the config with the `patterns` in the
+/// body and `DELETE` goes back to the config.  All methods answer with the
+/// patterns from the config and the override.
+pub fn ignore_handler(ctx: &ServerContext, mut req: Request) -> Result<ApiResponse>
+{
+    match req.method {
+        Method::Put => {
+            ctx.auth.authenticate_admin(&req)?;
+            let body = read_request_body(&mut req)?;
+            let data: IgnoreOverrideRequest = parse_request_data(&body)?;
+            ctx.stash.set_ignore_override(Some(data.patterns))?;
+        }
+        Method::Delete => {
+            ctx.auth.authenticate_admin(&req)?;
+            ctx.stash.set_ignore_override(None)?;
+        }
+        _ => {
+            assert_method!(req, Method::Get);
+            ctx.auth.authenticate_admin(&req)?;
+        }
+    }
+    ApiResponse::new(IgnoreResponse {
+        config: ctx.stash.config_ignore_patterns(),
+        ignore_override: ctx.stash.ignore_override(),
+    }, StatusCode::Ok)
+}
+
+#[derive(Serialize)]
+struct EvictResponse {
+    sdk_id: String,
+    evicted: bool,
+}
+
+/// Closes or reloads the memdb of a single SDK.
+///
+/// `POST /admin/sdks/{sdk_id}/evict` closes the memdb so the next lookup
+/// maps it again and `/reload` also verifies the file first.  With
+/// `?redownload=1` a reload makes the next sync download the memdb again,
+/// which keeps being served until then.
+pub fn admin_sdk_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Post);
+    let identity = ctx.auth.authenticate_admin(&req)?;
+    let (sdk_id, action) = match req.uri {
+        RequestUri::AbsolutePath(ref path) => {
+            let path = path.splitn(2, '?').next().unwrap_or("")
+                .trim_start_matches("/admin/sdks/");
+            match path.find('/') {
+                Some(idx) => (path[..idx].to_string(), path[idx + 1..].to_string()),
+                None => { return Err(ApiError::NotFound.into()); }
+            }
+        }
+        _ => { return Err(ApiError::BadRequest.into()); }
+    };
+    let info = SdkInfo::from_filename(&sdk_id).ok_or(ApiError::SdkNotFound)?;
+    spans::annotate("sdk_id", info.sdk_id());
+    match &action[..] {
+        "evict" => {
+            ApiResponse::new(EvictResponse {
+                sdk_id: info.sdk_id(),
+                evicted: ctx.stash.evict_memdb(&info)?,
+            }, StatusCode::Ok)
+        }
+        "reload" => {
+            let redownload = get_query_param::<u32>(&req, "redownload")?
+                .map_or(false, |x| x != 0);
+            let actor = format!("admin ({})", identity.name());
+            ApiResponse::new(ctx.stash.reload_memdb(&info, redownload, &actor)?,
+                             StatusCode::Ok)
+        }
+        _ => Err(ApiError::NotFound.into()),
+    }
+}
+
+/// Reports the memory usage of the server.
+pub fn memory_handler(ctx: &ServerContext, req: Request) -> Result<ApiResponse>
+{
+    assert_method!(req, Method::Get);
+    ctx.auth.authenticate_admin(&req)?;
+    ApiResponse::new(MemoryResponse {
+        resident_memory: get_resident_memory(),
+        mapped_memdbs: ctx.stash.open_memdb_count(),
+        mapped_memdb_size: ctx.stash.open_memdb_size(),
+        local_sdks: ctx.stash.sdk_count()?,
+        usage_stats_sdks: ctx.stash.get_usage_stats().sdk_count(),
+        threads: get_thread_count(),
+        listener_threads: ctx.listener_threads(),
+        worker_threads: ctx.config.get_server_worker_threads()?,
+        io_threads: ctx.config.get_server_io_threads()?,
     }, StatusCode::Ok)
 }
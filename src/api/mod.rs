@@ -1,4 +1,14 @@
 //! Implements the API layer for the server.
 pub mod server;
 pub mod types;
+pub mod msgpack;
 pub mod handlers;
+pub mod spans;
+pub mod sdk_metrics;
+pub mod signing;
+pub mod replay;
+pub mod auth;
+pub mod tls;
+pub mod connections;
+#[cfg(feature = "grpc")]
+pub mod grpc;
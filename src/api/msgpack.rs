@@ -0,0 +1,578 @@
+//! Encodes responses as MessagePack.
+//!
+//! This writes the same structure the JSON responses have: structs become
+//! maps keyed by their field names, enum variants are encoded like
+//! `serde_json` encodes them and `Option::None` is nil.  Sequences and maps
+//! of unknown length get a 32 bit header that is filled in at the end.
+use std::fmt;
+use std::error;
+use std::result::Result as StdResult;
+
+use serde::ser::{self, Serialize};
+
+/// Raised if a value cannot be encoded.
+#[derive(Debug)]
+pub struct EncodeError(String);
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "msgpack encoding failed: {}", self.0)
+    }
+}
+
+impl error::Error for EncodeError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+impl ser::Error for EncodeError {
+    fn custom<T: fmt::Display>(msg: T) -> EncodeError {
+        EncodeError(msg.to_string())
+    }
+}
+
+type Result<T> = StdResult<T, EncodeError>;
+
+/// Encodes a value as MessagePack.
+pub fn to_vec<T: ?Sized + Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut rv = vec![];
+    value.serialize(&mut Serializer { out: &mut rv })?;
+    Ok(rv)
+}
+
+struct Serializer<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+/// A sequence or map that is being written.
+///
+/// `header` is where the 32 bit length has to be patched in if the length
+/// was not known up front.
+pub struct Compound<'a, 'b: 'a> {
+    ser: &'a mut Serializer<'b>,
+    header: Option<usize>,
+    expected: usize,
+    count: usize,
+    is_map: bool,
+}
+
+fn write_be(out: &mut Vec<u8>, value: u64, bytes: usize) {
+    for idx in (0..bytes).rev() {
+        out.push((value >> (idx * 8)) as u8);
+    }
+}
+
+impl<'b> Serializer<'b> {
+    fn write_uint(&mut self, v: u64) {
+        if v < 0x80 {
+            self.out.push(v as u8);
+        } else if v <= u64::from(u8::max_value()) {
+            self.out.push(0xcc);
+            write_be(self.out, v, 1);
+        } else if v <= u64::from(u16::max_value()) {
+            self.out.push(0xcd);
+            write_be(self.out, v, 2);
+        } else if v <= u64::from(u32::max_value()) {
+            self.out.push(0xce);
+            write_be(self.out, v, 4);
+        } else {
+            self.out.push(0xcf);
+            write_be(self.out, v, 8);
+        }
+    }
+
+    fn write_int(&mut self, v: i64) {
+        if v >= 0 {
+            self.write_uint(v as u64);
+        } else if v >= -32 {
+            self.out.push(v as u8);
+        } else if v >= i64::from(i8::min_value()) {
+            self.out.push(0xd0);
+            write_be(self.out, v as u64, 1);
+        } else if v >= i64::from(i16::min_value()) {
+            self.out.push(0xd1);
+            write_be(self.out, v as u64, 2);
+        } else if v >= i64::from(i32::min_value()) {
+            self.out.push(0xd2);
+            write_be(self.out, v as u64, 4);
+        } else {
+            self.out.push(0xd3);
+            write_be(self.out, v as u64, 8);
+        }
+    }
+
+    /// Writes a length with the fix format `(tag, max)` if it fits and
+    /// the 8, 16 or 32 bit tags otherwise (0 if there is no 8 bit form).
+    fn write_len(&mut self, len: usize, fix: Option<(u8, usize)>, tags: [u8; 3]) -> Result<()> {
+        if let Some((tag, _)) = fix.filter(|&(_, max)| len <= max) {
+            self.out.push(tag | len as u8);
+        } else if len <= 0xff && tags[0] != 0 {
+            self.out.push(tags[0]);
+            write_be(self.out, len as u64, 1);
+        } else if len <= 0xffff {
+            self.out.push(tags[1]);
+            write_be(self.out, len as u64, 2);
+        } else if len <= 0xffff_ffff {
+            self.out.push(tags[2]);
+            write_be(self.out, len as u64, 4);
+        } else {
+            return Err(EncodeError(format!("{} items do not fit", len)));
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, v: &str) -> Result<()> {
+        self.write_len(v.len(), Some((0xa0, 31)), [0xd9, 0xda, 0xdb])?;
+        self.out.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn begin<'a>(&'a mut self, len: Option<usize>, is_map: bool) -> Result<Compound<'a, 'b>> {
+        let header = match len {
+            Some(len) => {
+                if is_map {
+                    self.write_len(len, Some((0x80, 15)), [0, 0xde, 0xdf])?;
+                } else {
+                    self.write_len(len, Some((0x90, 15)), [0, 0xdc, 0xdd])?;
+                }
+                None
+            }
+            None => {
+                self.out.push(if is_map { 0xdf } else { 0xdd });
+                self.out.extend_from_slice(&[0; 4]);
+                Some(self.out.len() - 4)
+            }
+        };
+        Ok(Compound {
+            ser: self,
+            header,
+            expected: len.unwrap_or(0),
+            count: 0,
+            is_map,
+        })
+    }
+
+    /// Starts the single entry map serde_json uses for enum variants.
+    fn begin_variant(&mut self, variant: &str) -> Result<()> {
+        self.out.push(0x81);
+        self.write_str(variant)
+    }
+}
+
+impl<'a, 'b> Compound<'a, 'b> {
+    fn element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.count += 1;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn finish(self) -> Result<()> {
+        let count = if self.is_map { self.count / 2 } else { self.count };
+        match self.header {
+            Some(pos) => {
+                if count > 0xffff_ffff {
+                    return Err(EncodeError(format!("{} items do not fit", count)));
+                }
+                let mut len = vec![];
+                write_be(&mut len, count as u64, 4);
+                self.ser.out[pos..pos + 4].copy_from_slice(&len);
+            }
+            None if count != self.expected => {
+                return Err(EncodeError(format!(
+                    "expected {} items but got {}", self.expected, count)));
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = EncodeError;
+    type SerializeSeq = Compound<'a, 'b>;
+    type SerializeTuple = Compound<'a, 'b>;
+    type SerializeTupleStruct = Compound<'a, 'b>;
+    type SerializeTupleVariant = Compound<'a, 'b>;
+    type SerializeMap = Compound<'a, 'b>;
+    type SerializeStruct = Compound<'a, 'b>;
+    type SerializeStructVariant = Compound<'a, 'b>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.out.push(if v { 0xc3 } else { 0xc2 });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> { self.write_int(i64::from(v)); Ok(()) }
+    fn serialize_i16(self, v: i16) -> Result<()> { self.write_int(i64::from(v)); Ok(()) }
+    fn serialize_i32(self, v: i32) -> Result<()> { self.write_int(i64::from(v)); Ok(()) }
+    fn serialize_i64(self, v: i64) -> Result<()> { self.write_int(v); Ok(()) }
+    fn serialize_u8(self, v: u8) -> Result<()> { self.write_uint(u64::from(v)); Ok(()) }
+    fn serialize_u16(self, v: u16) -> Result<()> { self.write_uint(u64::from(v)); Ok(()) }
+    fn serialize_u32(self, v: u32) -> Result<()> { self.write_uint(u64::from(v)); Ok(()) }
+    fn serialize_u64(self, v: u64) -> Result<()> { self.write_uint(v); Ok(()) }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.out.push(0xca);
+        write_be(self.out, u64::from(v.to_bits()), 4);
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.out.push(0xcb);
+        write_be(self.out, v.to_bits(), 8);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        let mut buf = [0; 4];
+        self.write_str(v.encode_utf8(&mut buf))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.write_str(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.write_len(v.len(), None, [0xc4, 0xc5, 0xc6])
+            .map(|_| self.out.extend_from_slice(v))
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.out.push(0xc0);
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _idx: usize, variant: &'static str)
+        -> Result<()>
+    {
+        self.write_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T)
+        -> Result<()>
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, _idx: usize,
+                                                        variant: &'static str, value: &T)
+        -> Result<()>
+    {
+        self.begin_variant(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a, 'b>> {
+        self.begin(len, false)
+    }
+
+    fn serialize_seq_fixed_size(self, len: usize) -> Result<Compound<'a, 'b>> {
+        self.begin(Some(len), false)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a, 'b>> {
+        self.begin(Some(len), false)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize)
+        -> Result<Compound<'a, 'b>>
+    {
+        self.begin(Some(len), false)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _idx: usize, variant: &'static str,
+                               len: usize) -> Result<Compound<'a, 'b>>
+    {
+        self.begin_variant(variant)?;
+        self.begin(Some(len), false)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a, 'b>> {
+        self.begin(len, true)
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a, 'b>> {
+        self.begin(Some(len), true)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _idx: usize, variant: &'static str,
+                                len: usize) -> Result<Compound<'a, 'b>>
+    {
+        self.begin_variant(variant)?;
+        self.begin(Some(len), true)
+    }
+}
+
+impl<'a, 'b> ser::SerializeSeq for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, 'b> ser::SerializeTuple for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleStruct for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleVariant for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, 'b> ser::SerializeMap for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, 'b> ser::SerializeStruct for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+        -> Result<()>
+    {
+        self.element(key)?;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a, 'b> ser::SerializeStructVariant for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+        -> Result<()>
+    {
+        self.element(key)?;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+#[test]
+fn test_encode_msgpack() {
+    use serde::ser::SerializeSeq;
+
+    #[derive(Serialize)]
+    struct Frame {
+        symbol: Option<&'static str>,
+        addr: u64,
+        offset: i32,
+        #[serde(skip_serializing_if="Option::is_none")]
+        recovered: Option<bool>,
+    }
+
+    let frame = Frame { symbol: Some("main"), addr: 0x1_0000_0000, offset: -40, recovered: None };
+    assert_eq!(to_vec(&frame).unwrap(), b"\
+        \x83\xa6symbol\xa4main\
+        \xa4addr\xcf\x00\x00\x00\x01\x00\x00\x00\x00\
+        \xa6offset\xd0\xd8".to_vec());
+    assert_eq!(to_vec(&(0..3).collect::<Vec<u8>>()).unwrap(), b"\x93\x00\x01\x02".to_vec());
+    assert_eq!(to_vec(&"x".repeat(40)).unwrap()[..2], [0xd9, 40]);
+
+    // sequences of unknown length get a patched 32 bit header
+    struct Unsized;
+    impl Serialize for Unsized {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(None)?;
+            seq.serialize_element(&true)?;
+            seq.serialize_element(&())?;
+            seq.end()
+        }
+    }
+    assert_eq!(to_vec(&Unsized).unwrap(), b"\xdd\x00\x00\x00\x02\xc3\xc0".to_vec());
+}
+
+#[test]
+fn test_msgpack_round_trip() {
+    use std::collections::BTreeMap;
+    use std::f64;
+
+    /// What the decoder below reads back
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Nil,
+        Bool(bool),
+        Int(i64),
+        F32(f32),
+        F64(f64),
+        Str(String),
+        Bin(Vec<u8>),
+        Array(Vec<Value>),
+        Map(Vec<(Value, Value)>),
+    }
+
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> &'a [u8] {
+        let (head, tail) = buf.split_at(len);
+        *buf = tail;
+        head
+    }
+
+    fn read_be(buf: &mut &[u8], len: usize) -> u64 {
+        take(buf, len).iter().fold(0, |acc, &x| (acc << 8) | u64::from(x))
+    }
+
+    fn decode(buf: &mut &[u8]) -> Value {
+        let tag = take(buf, 1)[0];
+        let (kind, len) = match tag {
+            0x00..=0x7f => { return Value::Int(i64::from(tag)); }
+            0xe0..=0xff => { return Value::Int(i64::from(tag as i8)); }
+            0xc0 => { return Value::Nil; }
+            0xc2 | 0xc3 => { return Value::Bool(tag == 0xc3); }
+            0xca => { return Value::F32(f32::from_bits(read_be(buf, 4) as u32)); }
+            0xcb => { return Value::F64(f64::from_bits(read_be(buf, 8))); }
+            0xcc..=0xcf => { return Value::Int(read_be(buf, 1 << (tag - 0xcc)) as i64); }
+            0xd0..=0xd3 => {
+                let bytes = 1usize << (tag - 0xd0);
+                let shift = 64 - 8 * bytes;
+                return Value::Int(((read_be(buf, bytes) << shift) as i64) >> shift);
+            }
+            0x80..=0x8f => ('m', usize::from(tag & 0x0f)),
+            0x90..=0x9f => ('a', usize::from(tag & 0x0f)),
+            0xa0..=0xbf => ('s', usize::from(tag & 0x1f)),
+            0xc4..=0xc6 => ('b', read_be(buf, 1 << (tag - 0xc4)) as usize),
+            0xd9..=0xdb => ('s', read_be(buf, 1 << (tag - 0xd9)) as usize),
+            0xdc | 0xdd => ('a', read_be(buf, if tag == 0xdc { 2 } else { 4 }) as usize),
+            0xde | 0xdf => ('m', read_be(buf, if tag == 0xde { 2 } else { 4 }) as usize),
+            _ => panic!("unexpected tag {:#x}", tag),
+        };
+        match kind {
+            'm' => Value::Map((0..len).map(|_| (decode(buf), decode(buf))).collect()),
+            'a' => Value::Array((0..len).map(|_| decode(buf)).collect()),
+            's' => Value::Str(String::from_utf8(take(buf, len).to_vec()).unwrap()),
+            _ => Value::Bin(take(buf, len).to_vec()),
+        }
+    }
+
+    fn round_trip<T: Serialize>(value: &T) -> Value {
+        let data = to_vec(value).unwrap();
+        let mut buf = &data[..];
+        let rv = decode(&mut buf);
+        assert!(buf.is_empty());
+        rv
+    }
+
+    fn s(value: &str) -> Value {
+        Value::Str(value.to_string())
+    }
+
+    // maps are not limited to string keys
+    let mut map = BTreeMap::new();
+    map.insert(-1000i32, "a");
+    map.insert(7, "b");
+    map.insert(70_000, "c");
+    assert_eq!(round_trip(&map), Value::Map(vec![
+        (Value::Int(-1000), s("a")),
+        (Value::Int(7), s("b")),
+        (Value::Int(70_000), s("c")),
+    ]));
+
+    #[derive(Serialize)]
+    enum Shape {
+        Point,
+        Circle(f32),
+        Rect(u8, u16),
+        Named { name: &'static str, inner: Box<Shape> },
+    }
+    let shapes = vec![
+        Shape::Point,
+        Shape::Named { name: "n", inner: Box::new(Shape::Circle(1.5)) },
+        Shape::Rect(1, 300),
+    ];
+    assert_eq!(round_trip(&shapes), Value::Array(vec![
+        s("Point"),
+        Value::Map(vec![(s("Named"), Value::Map(vec![
+            (s("name"), s("n")),
+            (s("inner"), Value::Map(vec![(s("Circle"), Value::F32(1.5))])),
+        ]))]),
+        Value::Map(vec![(s("Rect"), Value::Array(vec![Value::Int(1), Value::Int(300)]))]),
+    ]));
+
+    let floats = (0.1f32, -2.5e300f64, f64::INFINITY, Some(-0.0f64), None::<f32>);
+    assert_eq!(round_trip(&floats), Value::Array(vec![
+        Value::F32(0.1), Value::F64(-2.5e300), Value::F64(f64::INFINITY), Value::F64(-0.0),
+        Value::Nil,
+    ]));
+    assert_eq!(round_trip(&(i64::min_value(), -33i8, -32i8, true)), Value::Array(vec![
+        Value::Int(i64::min_value()), Value::Int(-33), Value::Int(-32), Value::Bool(true),
+    ]));
+
+    struct Blob(Vec<u8>);
+    impl Serialize for Blob {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+    let data: Vec<u8> = (0..300).map(|x| x as u8).collect();
+    assert_eq!(to_vec(&Blob(data.clone())).unwrap()[..3], [0xc5, 0x01, 0x2c]);
+    assert_eq!(round_trip(&Blob(data.clone())), Value::Bin(data));
+    assert_eq!(round_trip(&Blob(vec![])), Value::Bin(vec![]));
+}
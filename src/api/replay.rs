@@ -0,0 +1,349 @@
+//! Recording lookups and replaying them against another server.
+//!
+//! With `server.record_path` set the server appends a sample of the
+//! requests to `/lookup` and `/lookup/batch` to that file, one JSON object
+//! per line.  Only the body and the path are kept, without headers (and so
+//! API keys) or the address of the client.  The names and ids of objects
+//! outside of the system directories, which are the apps of the clients,
+//! are replaced by salted hashes, and their image addresses and the ids of
+//! batch frames are dropped.  `replay` sends the recorded requests to
+//! another server with their original spacing.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::client::Client;
+use hyper::header::{Authorization, Bearer};
+use md5;
+use serde_json::{self, Value};
+use uuid::Uuid;
+
+use super::super::config::Config;
+use super::super::utils::random_bytes;
+use super::super::{Result, ResultExt};
+use super::spans::as_millis;
+
+/// Object names starting with these are kept when recording.
+const SYSTEM_PREFIXES: &[&str] = &["/System/", "/usr/", "/Library/", "/Developer/"];
+
+/// A request as it is stored in the replay file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordedRequest {
+    /// Milliseconds since the recording started
+    pub offset_ms: u64,
+    /// The tenant whose stash the request went to
+    pub tenant: Option<String>,
+    /// The path and query string
+    pub path: String,
+    pub body: Value,
+}
+
+/// The replay file and how much was written to it
+struct RecordFile {
+    file: fs::File,
+    size: u64,
+}
+
+/// Appends a sample of the lookups to the replay file.
+pub struct Recorder {
+    file: Mutex<RecordFile>,
+    sample_rate: f64,
+    max_size: u64,
+    salt: [u8; 16],
+    seen: AtomicUsize,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Opens the replay file of the config if one is set.
+    pub fn from_config(config: &Config) -> Result<Option<Recorder>> {
+        let path = match config.get_server_record_path() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)
+            .chain_err(|| format!("Could not open replay file {}", path.display()))?;
+        let size = file.metadata()?.len();
+        let sample_rate = config.get_server_record_sample_rate()?;
+        let mut salt = [0u8; 16];
+        random_bytes(&mut salt)?;
+        info!("Recording {:.1}% of the lookups to {}", sample_rate * 100.0, path.display());
+        Ok(Some(Recorder {
+            file: Mutex::new(RecordFile { file, size }),
+            sample_rate,
+            max_size: config.get_server_record_max_size()?,
+            salt,
+            seen: AtomicUsize::new(0),
+            started: Instant::now(),
+        }))
+    }
+
+    /// Records a lookup if it falls into the sample.
+    ///
+    /// Requests whose body is not JSON are skipped, and so are failures to
+    /// write, which should not fail the lookup.  Once the file reached its
+    /// maximum size nothing is recorded anymore.
+    pub fn record(&self, tenant: Option<&str>, path: &str, body: &[u8]) {
+        // spreads the sample evenly instead of drawing random numbers
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        if ((n + 1.0) * self.sample_rate).floor() <= (n * self.sample_rate).floor() {
+            return;
+        }
+        let mut body: Value = match serde_json::from_slice(body) {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        anonymize(&mut body, &self.salt);
+        let mut line = match serde_json::to_vec(&RecordedRequest {
+            offset_ms: as_millis(self.started.elapsed()) as u64,
+            tenant: tenant.map(|x| x.to_string()),
+            path: path.to_string(),
+            body,
+        }) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if file.size + line.len() as u64 > self.max_size {
+            if file.size <= self.max_size {
+                warn!("Replay file is full, not recording lookups anymore");
+                // makes sure the warning is only logged once
+                file.size = self.max_size + 1;
+            }
+            return;
+        }
+        if let Err(err) = file.file.write_all(&line) {
+            warn!("Could not record lookup: {}", err);
+            return;
+        }
+        file.size += line.len() as u64;
+    }
+}
+
+fn is_system_path(name: &str) -> bool {
+    SYSTEM_PREFIXES.iter().any(|x| name.starts_with(x))
+}
+
+/// Hashes an object UUID with a salt into another UUID.
+fn hash_uuid(value: &str, salt: &[u8]) -> Value {
+    let mut ctx = md5::Context::new();
+    ctx.consume(salt);
+    ctx.consume(value.as_bytes());
+    let uuid = Uuid::from_bytes(&ctx.compute().0).unwrap();
+    Value::String(uuid.hyphenated().to_string())
+}
+
+/// Removes what identifies the clients from a lookup body.
+///
+/// Only objects with names in the system directories are kept as they
+/// are, all others could be apps of the clients.
+fn anonymize(body: &mut Value, salt: &[u8]) {
+    let obj = match body.as_object_mut() {
+        Some(obj) => obj,
+        None => return,
+    };
+    let key = if obj.contains_key("symbols") { "symbols" } else { "frames" };
+    let symbols = match obj.get_mut(key).and_then(|x| x.as_array_mut()) {
+        Some(symbols) => symbols,
+        None => return,
+    };
+    for symbol in symbols.iter_mut().filter_map(|x| x.as_object_mut()) {
+        symbol.remove("id");
+        let hashed = match symbol.get("object_name").and_then(|x| x.as_str()) {
+            Some(name) if is_system_path(name) => continue,
+            Some(name) => {
+                let mut ctx = md5::Context::new();
+                ctx.consume(salt);
+                ctx.consume(name.as_bytes());
+                Some(format!("/anonymized/{:x}", ctx.compute()))
+            }
+            None => None,
+        };
+        if let Some(hashed) = hashed {
+            symbol.insert("object_name".into(), Value::String(hashed));
+        }
+        for key in &["object_uuid", "debug_id"] {
+            let hashed = match symbol.get(*key).and_then(|x| x.as_str()) {
+                Some(value) => hash_uuid(value, salt),
+                None => continue,
+            };
+            symbol.insert(key.to_string(), hashed);
+        }
+        symbol.remove("image_addr");
+    }
+}
+
+/// Influences how recorded lookups are replayed.
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// The server to send the lookups to
+    pub url: String,
+    /// Sent as bearer token with every request
+    pub api_key: Option<String>,
+    /// How much faster than recorded to send the requests.  With 0 they
+    /// are sent as fast as the workers manage.
+    pub speed: f64,
+    /// How many requests are in flight at most
+    pub concurrency: usize,
+}
+
+/// What the server answered to replayed lookups.
+#[derive(Serialize, Debug, Default)]
+pub struct ReplayReport {
+    pub sent: usize,
+    /// Requests that did not get an answer at all
+    pub failed: usize,
+    /// How often the server answered with each status code
+    pub statuses: BTreeMap<u16, usize>,
+    pub avg_duration_ms: f64,
+    pub max_duration_ms: f64,
+}
+
+/// Sends the lookups of a replay file to another server.
+pub fn replay<P: AsRef<Path>>(path: P, options: &ReplayOptions) -> Result<ReplayReport> {
+    let f = fs::File::open(path.as_ref())
+        .chain_err(|| format!("Could not open replay file {}", path.as_ref().display()))?;
+    let (tx, rx) = mpsc::sync_channel::<RecordedRequest>(options.concurrency);
+    let rx = Arc::new(Mutex::new(rx));
+    let report = Arc::new(Mutex::new(ReplayReport::default()));
+    let url = options.url.trim_end_matches('/').to_string();
+
+    let workers: Vec<_> = (0..options.concurrency.max(1)).map(|_| {
+        let rx = rx.clone();
+        let report = report.clone();
+        let url = url.clone();
+        let api_key = options.api_key.clone();
+        thread::spawn(move || {
+            let client = Client::new();
+            // the lock is only held while waiting for the next request
+            let next = || rx.lock().unwrap().recv().ok();
+            while let Some(req) = next() {
+                let path = match req.tenant {
+                    Some(ref tenant) => format!("/tenants/{}{}", tenant, req.path),
+                    None => req.path.clone(),
+                };
+                let body = serde_json::to_vec(&req.body).unwrap_or_default();
+                let mut builder = client.post(&format!("{}{}", url, path)).body(&body[..]);
+                if let Some(ref key) = api_key {
+                    builder = builder.header(Authorization(Bearer { token: key.clone() }));
+                }
+                let started = Instant::now();
+                let status = builder.send().ok().map(|mut resp| {
+                    // read the body so that the timing covers the whole lookup
+                    let mut sink = vec![];
+                    resp.read_to_end(&mut sink).ok();
+                    resp.status.to_u16()
+                });
+                let duration = as_millis(started.elapsed());
+                let mut report = report.lock().unwrap();
+                report.sent += 1;
+                match status {
+                    Some(status) => { *report.statuses.entry(status).or_insert(0) += 1; }
+                    None => { report.failed += 1; }
+                }
+                report.avg_duration_ms += duration;
+                if duration > report.max_duration_ms {
+                    report.max_duration_ms = duration;
+                }
+            }
+        })
+    }).collect();
+
+    let started = Instant::now();
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let req: RecordedRequest = serde_json::from_str(&line)
+            .chain_err(|| "Invalid replay file")?;
+        if options.speed > 0.0 {
+            let due = Duration::from_millis((req.offset_ms as f64 / options.speed) as u64);
+            let elapsed = started.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+        if tx.send(req).is_err() {
+            break;
+        }
+    }
+    drop(tx);
+    for worker in workers {
+        worker.join().ok();
+    }
+
+    let mut report = Arc::try_unwrap(report).ok().unwrap().into_inner().unwrap();
+    if report.sent > 0 {
+        report.avg_duration_ms /= report.sent as f64;
+    }
+    Ok(report)
+}
+
+#[test]
+fn test_anonymize() {
+    let mut body: Value = serde_json::from_str(r#"{"cpu_name": "arm64", "frames": [
+        {"id": "a", "sdk_id": "iOS_10.2.0", "object_name": "/usr/lib/libobjc.A.dylib"},
+        {"id": "b", "sdk_id": "iOS_10.2.0",
+         "object_name": "/var/containers/Bundle/Application/Foo.app/Foo",
+         "object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", "image_addr": "0x1000"},
+        {"id": "c", "sdk_id": "iOS_10.2.0",
+         "debug_id": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b-0"}]}"#).unwrap();
+    anonymize(&mut body, b"salt");
+    let frames = body.get("frames").unwrap().as_array().unwrap();
+    assert!(frames.iter().all(|x| x.get("id").is_none()));
+    assert_eq!(frames[0].get("object_name").unwrap().as_str(),
+               Some("/usr/lib/libobjc.A.dylib"));
+    assert!(frames[1].get("object_name").unwrap().as_str().unwrap()
+            .starts_with("/anonymized/"));
+    assert!(frames[1].get("image_addr").is_none());
+    let uuid = frames[1].get("object_uuid").unwrap().as_str().unwrap();
+    assert!(uuid != "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b");
+    assert!(uuid.parse::<Uuid>().is_ok());
+    assert!(frames[2].get("debug_id").unwrap().as_str().unwrap() !=
+            "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b-0");
+
+    // the same object hashes the same way with the same salt only
+    let mut other: Value = serde_json::from_str(r#"{"symbols": [
+        {"object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b"}]}"#).unwrap();
+    let mut salted = other.clone();
+    anonymize(&mut salted, b"salt");
+    assert_eq!(salted.get("symbols").unwrap()[0].get("object_uuid").unwrap().as_str(),
+               Some(uuid));
+    anonymize(&mut other, b"other");
+    assert!(other.get("symbols").unwrap()[0].get("object_uuid").unwrap().as_str() !=
+            Some(uuid));
+}
+
+#[test]
+fn test_record_max_size() {
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let path = dir.path().join("replay.jsonl");
+    let recorder = Recorder {
+        file: Mutex::new(RecordFile { file: fs::File::create(&path).unwrap(), size: 0 }),
+        sample_rate: 1.0,
+        max_size: 200,
+        salt: [0; 16],
+        seen: AtomicUsize::new(0),
+        started: Instant::now(),
+    };
+    for _ in 0..10 {
+        recorder.record(None, "/lookup", br#"{"sdk_id": "iOS_10.2.0", "symbols": []}"#);
+    }
+    // only whole lines are written
+    let contents = fs::read_to_string(&path).unwrap();
+    assert!(!contents.is_empty() && contents.len() <= 200);
+    assert!(contents.ends_with('\n'));
+    assert!(contents.lines().count() < 10);
+    for line in contents.lines() {
+        serde_json::from_str::<RecordedRequest>(line).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! Lookup metrics broken down by the requested SDK.
+//!
+//! For every SDK id clients look symbols up in the server counts requests,
+//! latencies and how many of the looked up objects none of the matched
+//! memdbs knows.  A high unknown object rate usually means that clients
+//! already run an SDK (say a freshly released iOS) that was not synced yet.
+//! Only a bounded number of SDK ids is tracked; when the limit is reached
+//! the one that was requested longest ago is forgotten.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::spans::as_millis;
+
+/// The number of SDK ids that are tracked at most.
+const MAX_TRACKED_SDKS: usize = 1000;
+
+/// What happened to the lookups for one SDK in a request.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct LookupOutcome {
+    /// No SDK in the stash matched the requested one
+    pub sdk_not_found: bool,
+    pub symbols: u64,
+    /// Symbols that were not found
+    pub unresolved: u64,
+    /// Symbols whose object is not in any of the matched memdbs
+    pub unknown_objects: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: u64,
+    sdk_not_found: u64,
+    symbols: u64,
+    unresolved: u64,
+    unknown_objects: u64,
+    total_duration: Duration,
+    max_duration: Duration,
+}
+
+/// The metrics of one requested SDK id.
+#[derive(Serialize, Debug, Clone)]
+pub struct SdkLookupStats {
+    sdk_id: String,
+    requests: u64,
+    sdk_not_found: u64,
+    symbols: u64,
+    unresolved_symbols: u64,
+    unknown_objects: u64,
+    unknown_object_rate: f64,
+    avg_duration_ms: f64,
+    max_duration_ms: f64,
+}
+
+/// Tracks lookup metrics per requested SDK id.
+pub struct SdkMetrics {
+    sdks: Mutex<HashMap<String, (Counters, Instant)>>,
+    capacity: usize,
+}
+
+impl SdkLookupStats {
+    /// The SDK id as requested by clients
+    pub fn sdk_id(&self) -> &str {
+        &self.sdk_id
+    }
+
+    /// The number of requests that looked symbols up in the SDK
+    pub fn requests(&self) -> u64 {
+        self.requests
+    }
+
+    /// The number of requests for which no SDK matched
+    pub fn sdk_not_found(&self) -> u64 {
+        self.sdk_not_found
+    }
+
+    /// The number of looked up symbols whose object was unknown
+    pub fn unknown_objects(&self) -> u64 {
+        self.unknown_objects
+    }
+
+    /// The share of looked up symbols whose object was unknown
+    pub fn unknown_object_rate(&self) -> f64 {
+        self.unknown_object_rate
+    }
+}
+
+impl Default for SdkMetrics {
+    fn default() -> SdkMetrics {
+        SdkMetrics::new(MAX_TRACKED_SDKS)
+    }
+}
+
+impl SdkMetrics {
+    /// Creates metrics that track up to `capacity` SDK ids.
+    pub fn new(capacity: usize) -> SdkMetrics {
+        SdkMetrics {
+            sdks: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    /// Records the lookups for an SDK in one request.
+    pub fn record(&self, sdk_id: &str, outcome: &LookupOutcome, duration: Duration) {
+        let mut sdks = self.sdks.lock().unwrap();
+        if !sdks.contains_key(sdk_id) && sdks.len() >= self.capacity {
+            let oldest = sdks.iter()
+                .min_by_key(|&(_, &(_, last_seen))| last_seen)
+                .map(|(sdk_id, _)| sdk_id.clone());
+            if let Some(oldest) = oldest {
+                sdks.remove(&oldest);
+            }
+        }
+        let entry = sdks.entry(sdk_id.to_string())
+            .or_insert_with(|| (Counters::default(), Instant::now()));
+        entry.1 = Instant::now();
+        let counters = &mut entry.0;
+        counters.requests += 1;
+        if outcome.sdk_not_found {
+            counters.sdk_not_found += 1;
+        }
+        counters.symbols += outcome.symbols;
+        counters.unresolved += outcome.unresolved;
+        counters.unknown_objects += outcome.unknown_objects;
+        counters.total_duration += duration;
+        if duration > counters.max_duration {
+            counters.max_duration = duration;
+        }
+    }
+
+    /// Returns the `count` most requested SDK ids, most requested first.
+    pub fn top(&self, count: usize) -> Vec<SdkLookupStats> {
+        let sdks = self.sdks.lock().unwrap();
+        let mut rv: Vec<_> = sdks.iter().map(|(sdk_id, &(ref counters, _))| {
+            SdkLookupStats {
+                sdk_id: sdk_id.clone(),
+                requests: counters.requests,
+                sdk_not_found: counters.sdk_not_found,
+                symbols: counters.symbols,
+                unresolved_symbols: counters.unresolved,
+                unknown_objects: counters.unknown_objects,
+                unknown_object_rate: if counters.symbols > 0 {
+                    counters.unknown_objects as f64 / counters.symbols as f64
+                } else {
+                    0.0
+                },
+                avg_duration_ms: as_millis(counters.total_duration) / counters.requests as f64,
+                max_duration_ms: as_millis(counters.max_duration),
+            }
+        }).collect();
+        rv.sort_by(|a, b| b.requests.cmp(&a.requests).then_with(|| a.sdk_id.cmp(&b.sdk_id)));
+        rv.truncate(count);
+        rv
+    }
+
+    /// The number of SDK ids that are currently tracked.
+    pub fn tracked_sdks(&self) -> usize {
+        self.sdks.lock().unwrap().len()
+    }
+}
+
+#[test]
+fn test_sdk_metrics() {
+    let metrics = SdkMetrics::new(2);
+    let found = LookupOutcome { symbols: 4, unknown_objects: 1, unresolved: 1,
+                                ..Default::default() };
+    let missing = LookupOutcome { sdk_not_found: true, ..Default::default() };
+    metrics.record("iOS_10.3_14E277", &found, Duration::from_millis(10));
+    metrics.record("iOS_10.3_14E277", &found, Duration::from_millis(30));
+    metrics.record("iOS_11.0_15A372", &missing, Duration::from_millis(1));
+
+    let top = metrics.top(1);
+    assert_eq!(top.len(), 1);
+    assert_eq!(top[0].sdk_id(), "iOS_10.3_14E277");
+    assert_eq!(top[0].requests(), 2);
+    assert!((top[0].unknown_object_rate() - 0.25).abs() < 1e-9);
+    assert!((top[0].max_duration_ms - 30.0).abs() < 1e-9);
+
+    // the least recently requested SDK makes room
+    metrics.record("iOS_11.0_15A372", &missing, Duration::from_millis(1));
+    metrics.record("iOS_11.1_15B93", &missing, Duration::from_millis(1));
+    assert_eq!(metrics.tracked_sdks(), 2);
+    let ids: Vec<_> = metrics.top(10).iter().map(|x| x.sdk_id().to_string()).collect();
+    assert_eq!(ids, vec!["iOS_11.0_15A372", "iOS_11.1_15B93"]);
+}
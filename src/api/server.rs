@@ -1,23 +1,49 @@
 //! Implements the API server.
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 use std::io::Read;
 use std::os::unix::io::{FromRawFd, RawFd};
+use std::str::FromStr;
+use std::time::{Duration as StdDuration, Instant};
+use std::iter;
+use std::mem;
+use std::path::PathBuf;
 
 use libc;
+use chrono::{DateTime, Duration, Utc};
 use hyper::server::{Server, Request, Response};
+use indicatif::HumanBytes;
 use hyper::header::ContentLength;
 use hyper::method::Method;
-use hyper::net::HttpListener;
+use hyper::net::{HttpListener, HttpStream, HttpsListener};
 use hyper::uri::RequestUri;
 use serde::Deserialize;
 use serde_json;
+use url::form_urlencoded;
 
-use super::super::config::Config;
-use super::super::memdb::stash::{MemDbStash, SyncStatus};
+use super::super::config::{AuthMode, Config};
+use super::super::constants::get_build_description;
+use super::super::memdb::stash::{MemDbStash, SyncOptions, SyncStatus};
+use super::super::report::{default_reporter, Reporter, SdkStatus};
+use super::super::sdk::SdkInfo;
 use super::super::Result;
-use super::super::utils::{HumanDuration, run_isolated, get_systemd_fd};
+#[cfg(not(feature = "grpc"))]
+use super::super::ErrorKind;
+use super::super::statsd::StatsdClient;
+use super::super::utils::{HumanDuration, run_isolated, get_systemd_fd, get_resident_memory,
+                          parallel_map, install_reload_handler, take_reload_request};
+use super::auth::Authenticator;
+use super::connections::{CountingListener, CountingStream};
+#[cfg(feature = "grpc")]
+use super::grpc::GrpcServer;
 use super::handlers;
+use super::replay::Recorder;
+use super::sdk_metrics::SdkMetrics;
+use super::signing::ResponseSigner;
+use super::spans;
+use super::tls::{TlsServer, TlsStream};
 use super::types::{ApiResponse, ApiError};
 
 /// Result from a healthcheck.
@@ -25,7 +51,72 @@ use super::types::{ApiResponse, ApiError};
 pub struct HealthCheckResponse {
     pub is_offline: bool,
     pub is_healthy: bool,
+    pub is_degraded: bool,
     pub sync_lag: u32,
+    pub revision: u64,
+    pub last_sync: Option<i64>,
+    pub last_sync_error: Option<String>,
+    pub sync_warning: Option<String>,
+    pub sync_failures: u64,
+    pub sync_restarts: u64,
+}
+
+/// Result from a readiness check.
+#[derive(Serialize, Clone)]
+pub struct ReadinessResponse {
+    pub is_ready: bool,
+    pub is_draining: bool,
+    pub sync_lag: u32,
+}
+
+/// How long a shutdown waits for requests that are still running.
+const SHUTDOWN_REQUEST_TIMEOUT: u64 = 10;
+
+/// Bookkeeping for the supervised background sync loop.
+#[derive(Default)]
+struct SyncSupervisor {
+    /// Bumped whenever the loop is restarted; older loops exit on seeing it.
+    generation: usize,
+    started: Option<DateTime<Utc>>,
+    /// When the running loop last made progress: started or finished a
+    /// sync or moved on to the next SDK.
+    heartbeat: Option<DateTime<Utc>>,
+    /// Set while the running loop is in the middle of a sync.
+    in_flight: bool,
+    /// Set while the thread of the running loop is alive.
+    loop_running: bool,
+    /// Set once a stale heartbeat was reported, until the next one.
+    stall_reported: bool,
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    /// Why the last sync could not reach S3, if it could not.
+    last_warning: Option<String>,
+    failures: u64,
+    restarts: u64,
+}
+
+impl SyncSupervisor {
+    /// Checks if the heartbeat is older than `limit`.
+    fn is_stalled(&self, now: DateTime<Utc>, limit: Duration) -> bool {
+        match self.heartbeat {
+            Some(ts) => now - ts > limit,
+            None => false,
+        }
+    }
+
+    /// Checks if the loop has to be restarted.
+    ///
+    /// Only a loop whose thread exited is restarted.  A loop that merely
+    /// stopped making progress may still finish its sync and a second one
+    /// would race it for the stash.
+    fn needs_restart(&self) -> bool {
+        self.started.is_some() && !self.loop_running && !self.in_flight
+    }
+
+    fn touch(&mut self, now: DateTime<Utc>) {
+        self.heartbeat = Some(now);
+        self.stall_reported = false;
+    }
 }
 
 /// Shared access to the state of the server.
@@ -34,13 +125,69 @@ pub struct ServerContext {
     pub stash: MemDbStash,
     enable_sync: bool,
     cached_memdb_status: RwLock<Option<SyncStatus>>,
+    listener_threads: AtomicUsize,
+    sync_supervisor: Mutex<SyncSupervisor>,
+    statsd: Option<StatsdClient>,
+    pub sdk_metrics: SdkMetrics,
+    pub signer: Option<ResponseSigner>,
+    pub auth: Authenticator,
+    draining: AtomicBool,
+    /// Set once the server was asked to shut down.
+    shutting_down: AtomicBool,
+    /// Set once the sync lag was below `server.ready_max_lag`.
+    caught_up: AtomicBool,
+    requests_in_flight: AtomicUsize,
+    requests_total: AtomicUsize,
+    /// The name of the tenant if this is not the root stash
+    tenant: Option<String>,
+    recorder: Option<Arc<Recorder>>,
+    /// The stashes served under `/tenants/{name}/`
+    tenants: BTreeMap<String, Arc<ServerContext>>,
 }
 
+/// The signature of the endpoint handlers.
+type Handler = fn(&ServerContext, Request) -> Result<ApiResponse>;
+
 /// The API server itself.
 pub struct ApiServer {
     ctx: Arc<ServerContext>,
 }
 
+/// Asks a running API server to shut down gracefully.
+///
+/// The server does not handle signals itself, whoever runs it decides
+/// what triggers a shutdown.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    ctx: Arc<ServerContext>,
+}
+
+impl ShutdownHandle {
+    /// Makes `ApiServer::run` return after the server drained.
+    pub fn shutdown(&self) {
+        self.ctx.shutting_down.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Counts a request as in flight until it is dropped, also if its
+/// handler panicked.
+struct InFlightGuard<'a> {
+    counter: &'a AtomicUsize,
+}
+
+impl<'a> InFlightGuard<'a> {
+    fn new(counter: &'a AtomicUsize) -> InFlightGuard<'a> {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard { counter }
+    }
+}
+
+impl<'a> Drop for InFlightGuard<'a> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 /// Controls how the server binds to sockets.
 pub enum BindOptions<'a> {
     /// Bind according to the config file.
@@ -52,6 +199,97 @@ pub enum BindOptions<'a> {
 }
 
 impl ServerContext {
+    fn new(config: &Config, enable_sync: bool) -> Result<ServerContext> {
+        let recorder = Recorder::from_config(config)?.map(Arc::new);
+        ServerContext::with_recorder(config, enable_sync, None, recorder)
+    }
+
+    /// Creates the context of the root stash or of a tenant, which shares
+    /// the recorder of the root.
+    fn with_recorder(config: &Config, enable_sync: bool, tenant: Option<&str>,
+                     recorder: Option<Arc<Recorder>>) -> Result<ServerContext> {
+        let stash = MemDbStash::new(config)?;
+        if enable_sync && stash.is_read_only() {
+            info!("The stash in {} is read-only. Background sync is disabled.",
+                  config.get_symbol_dir()?.display());
+        }
+        match config.get_server_auth_mode()? {
+            AuthMode::ApiKeys => {
+                let api_keys = config.get_server_api_keys()?.len();
+                if api_keys > 0 {
+                    info!("Lookups in {} are restricted to {} API keys",
+                          config.get_symbol_dir()?.display(), api_keys);
+                }
+            }
+            AuthMode::Jwt => {
+                info!("Lookups in {} require a JSON web token",
+                      config.get_symbol_dir()?.display());
+            }
+            AuthMode::ClientCert => {
+                info!("Lookups in {} require a client certificate",
+                      config.get_symbol_dir()?.display());
+            }
+        }
+        let signer = ResponseSigner::from_config(config)?;
+        if let Some(ref signer) = signer {
+            info!("Signing lookup responses with key {}", signer.key_id());
+        }
+        let mut tenants = BTreeMap::new();
+        for name in config.get_tenant_names() {
+            let tenant_config = config.get_tenant_config(name)?;
+            tenants.insert(name.to_string(), Arc::new(ServerContext::with_recorder(
+                &tenant_config, enable_sync, Some(name), recorder.clone())?));
+        }
+        Ok(ServerContext {
+            config: config.clone(),
+            enable_sync: enable_sync && !stash.is_read_only(),
+            stash,
+            cached_memdb_status: RwLock::new(None),
+            listener_threads: AtomicUsize::new(0),
+            sync_supervisor: Mutex::new(Default::default()),
+            statsd: StatsdClient::from_config(config)?,
+            sdk_metrics: SdkMetrics::default(),
+            signer,
+            auth: Authenticator::from_config(config)?,
+            draining: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+            caught_up: AtomicBool::new(false),
+            requests_in_flight: AtomicUsize::new(0),
+            requests_total: AtomicUsize::new(0),
+            tenant: tenant.map(|x| x.to_string()),
+            recorder,
+            tenants,
+        })
+    }
+
+    /// Returns the context of a tenant.
+    pub fn get_tenant(&self, name: &str) -> Option<&Arc<ServerContext>> {
+        self.tenants.get(name)
+    }
+
+    /// Records a lookup for replaying if the server records lookups.
+    ///
+    /// Only call this once the request is authenticated.
+    pub fn record_lookup(&self, req: &Request, body: &[u8]) {
+        if let (Some(ref recorder), &RequestUri::AbsolutePath(ref path)) =
+            (self.recorder.as_ref(), &req.uri)
+        {
+            recorder.record(self.tenant.as_ref().map(|x| x.as_str()), path, body);
+        }
+    }
+
+    /// Returns the number of threads handling requests.
+    pub fn listener_threads(&self) -> usize {
+        self.listener_threads.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many requests the server received since it started and
+    /// how many of them are still running.
+    pub fn request_counts(&self) -> (usize, usize) {
+        (self.requests_total.load(Ordering::Relaxed),
+         self.requests_in_flight.load(Ordering::SeqCst))
+    }
+
     pub fn check_health(&self) -> Result<()> {
         let sync_status = self.stash.get_sync_status()?;
         *self.cached_memdb_status.write().unwrap() = Some(sync_status);
@@ -60,58 +298,245 @@ impl ServerContext {
 
     pub fn get_healthcheck_result(&self) -> Result<HealthCheckResponse> {
         if self.enable_sync {
+            let is_degraded = self.is_sync_stale()?;
+            let sup = self.sync_supervisor.lock().unwrap();
             let cache_value = self.cached_memdb_status.read().unwrap();
-            if let Some(ref state) = *cache_value {
-                Ok(HealthCheckResponse {
-                    is_offline: state.is_offline(),
-                    is_healthy: state.is_healthy(),
-                    sync_lag: state.lag()
-                })
-            } else {
-                Ok(HealthCheckResponse {
-                    is_offline: true,
-                    is_healthy: false,
-                    sync_lag: 0,
-                })
-            }
+            let (is_offline, is_healthy, sync_lag, warning) = match *cache_value {
+                Some(ref state) => (state.is_offline(), state.is_healthy(), state.lag(),
+                                    state.warning().map(|x| x.to_string())),
+                None => (true, false, 0, None),
+            };
+            Ok(HealthCheckResponse {
+                is_offline,
+                is_healthy: is_healthy && !is_degraded,
+                is_degraded,
+                sync_lag,
+                revision: self.stash.get_revision()?,
+                last_sync: sup.last_success.map(|x| x.timestamp()),
+                last_sync_error: sup.last_error.clone(),
+                sync_warning: sup.last_warning.clone().or(warning),
+                sync_failures: sup.failures,
+                sync_restarts: sup.restarts,
+            })
         } else {
             Ok(HealthCheckResponse {
                 is_offline: true,
                 is_healthy: true,
+                is_degraded: false,
                 sync_lag: 0,
+                revision: self.stash.get_revision()?,
+                last_sync: None,
+                last_sync_error: None,
+                sync_warning: None,
+                sync_failures: 0,
+                sync_restarts: 0,
             })
         }
     }
+
+    /// Returns `true` if the server was asked to stop taking traffic.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Makes the server report itself as not ready so that load balancers
+    /// stop routing requests to it.
+    pub fn start_draining(&self) {
+        if !self.draining.swap(true, Ordering::SeqCst) {
+            info!("Draining, readiness check reports not ready from now on");
+        }
+        for tenant in self.tenants.values() {
+            tenant.draining.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Checks if the server should receive traffic.
+    ///
+    /// A draining server is never ready.  With `server.ready_max_lag` set
+    /// the server is not ready until the sync lag dropped to that value
+    /// once; it does not become unready again when new SDKs show up.  If
+    /// S3 is offline the lag cannot be known and the server is ready with
+    /// what it has.  The main stash is only ready if all tenants are.
+    pub fn get_readiness_result(&self) -> Result<ReadinessResponse> {
+        let max_lag = if self.enable_sync {
+            self.config.get_server_ready_max_lag()?
+        } else {
+            None
+        };
+        if max_lag.is_some() && !self.caught_up.load(Ordering::SeqCst) {
+            // no need to wait for the next healthcheck while catching up
+            self.check_health()?;
+        }
+        let (is_offline, sync_lag) = match *self.cached_memdb_status.read().unwrap() {
+            Some(ref state) => (state.is_offline(), state.lag()),
+            None => (false, 0),
+        };
+        let caught_up = match max_lag {
+            Some(max_lag) => is_offline || sync_lag <= max_lag,
+            None => true,
+        };
+        if caught_up && !self.caught_up.swap(true, Ordering::SeqCst) && max_lag.is_some() {
+            info!("Sync lag is {}, reporting ready", sync_lag);
+        }
+        let is_draining = self.is_draining();
+        let mut is_ready = self.caught_up.load(Ordering::SeqCst) && !is_draining;
+        for tenant in self.tenants.values() {
+            is_ready = tenant.get_readiness_result()?.is_ready && is_ready;
+        }
+        Ok(ReadinessResponse {
+            is_ready,
+            is_draining,
+            sync_lag,
+        })
+    }
+
+    /// Checks if the last successful sync is older than the configured
+    /// staleness.  Before the first sync the start of the loop counts.
+    fn is_sync_stale(&self) -> Result<bool> {
+        let max_staleness = self.config.get_server_sync_max_staleness()?;
+        let sup = self.sync_supervisor.lock().unwrap();
+        Ok(match sup.last_success.or(sup.started) {
+            Some(ts) => Utc::now() - ts > max_staleness,
+            None => false,
+        })
+    }
+
+    /// Records the outcome of a sync run by the loop of the given generation.
+    ///
+    /// A sync that could not reach S3 only records the warning; it neither
+    /// counts as a failure nor as a success.  Returns `false` if the loop
+    /// was replaced and should shut down.
+    fn record_sync(&self, generation: usize, failure: Option<String>,
+                   warning: Option<String>) -> bool {
+        let mut sup = self.sync_supervisor.lock().unwrap();
+        if sup.generation != generation {
+            return false;
+        }
+        let now = Utc::now();
+        sup.touch(now);
+        sup.in_flight = false;
+        if let Some(warning) = warning {
+            warn!("sync skipped: {}", warning);
+            sup.last_warning = Some(warning);
+            return true;
+        }
+        sup.last_warning = None;
+        match failure {
+            Some(err) => {
+                sup.failures += 1;
+                sup.last_error = Some(err);
+            }
+            None => {
+                sup.last_success = Some(now);
+                sup.last_error = None;
+            }
+        }
+        true
+    }
+
+    /// Marks the start of a sync.
+    ///
+    /// Returns `false` if the loop was replaced and should shut down.
+    fn begin_sync(&self, generation: usize) -> bool {
+        let mut sup = self.sync_supervisor.lock().unwrap();
+        if sup.generation != generation {
+            return false;
+        }
+        sup.touch(Utc::now());
+        sup.in_flight = true;
+        true
+    }
+
+    /// Records that the sync of the loop of the given generation made
+    /// progress.
+    fn touch_sync(&self, generation: usize) {
+        let mut sup = self.sync_supervisor.lock().unwrap();
+        if sup.generation == generation {
+            sup.touch(Utc::now());
+        }
+    }
+
+    /// Records that the thread of the loop of the given generation exited.
+    fn end_sync_loop(&self, generation: usize) {
+        let mut sup = self.sync_supervisor.lock().unwrap();
+        if sup.generation == generation {
+            // a sync cannot outlive the thread that runs it
+            sup.loop_running = false;
+            sup.in_flight = false;
+        }
+    }
 }
 
 impl ApiServer {
     /// Create a new server.
     pub fn new(config: &Config, enable_sync: bool) -> Result<ApiServer> {
         Ok(ApiServer {
-            ctx: Arc::new(ServerContext {
-                config: config.clone(),
-                stash: MemDbStash::new(config)?,
-                enable_sync: enable_sync,
-                cached_memdb_status: RwLock::new(None),
-            }),
+            ctx: Arc::new(ServerContext::new(config, enable_sync)?),
         })
     }
 
     /// Spawns a background thread that runs the sync process.
     pub fn spawn_sync_thread(&self) -> Result<()> {
         let interval = self.ctx.config.get_server_sync_interval()?;
-        let std_interval = interval.to_std().unwrap();
-        info!("Checking for symbols from S3 in background every {}",
-              HumanDuration(interval));
-        info!("Source Bucket: {}", self.ctx.config.get_aws_bucket_url()?);
+        if let Some(primary) = self.ctx.config.get_sync_primary()? {
+            info!("Following the stash of {} in background every {}", primary,
+                  HumanDuration(interval));
+        } else {
+            info!("Checking for symbols from S3 in background every {}",
+                  HumanDuration(interval));
+            info!("Source Bucket: {}", self.ctx.config.get_aws_bucket_url()?);
+        }
         info!("Local SDKs: {}", self.ctx.stash.sdk_count()?);
 
+        {
+            let mut sup = self.ctx.sync_supervisor.lock().unwrap();
+            sup.started = Some(Utc::now());
+            sup.heartbeat = sup.started;
+            sup.loop_running = true;
+        }
+        spawn_sync_loop(self.ctx.clone(), 0, interval);
+        Ok(())
+    }
+
+    /// Spawns a background thread that watches the sync loop.
+    ///
+    /// A loop that makes no progress for longer than the sync interval plus
+    /// `sync.max_staleness` is reported as stalled.  The loop is restarted
+    /// once its thread exited.
+    pub fn spawn_sync_supervisor_thread(&self) -> Result<()> {
+        let interval = self.ctx.config.get_server_sync_interval()?;
+        let max_staleness = self.ctx.config.get_server_sync_max_staleness()?;
+        let check_interval = ::std::cmp::min(interval, max_staleness).to_std().unwrap();
+        info!("Reporting sync as stalled after {} without progress",
+              HumanDuration(max_staleness));
+
         let ctx = self.ctx.clone();
         thread::spawn(move || {
             loop {
-                let ctx = ctx.clone();
-                run_isolated(move || ctx.stash.sync(Default::default()));
-                thread::sleep(std_interval);
+                thread::sleep(check_interval);
+                let generation = {
+                    let mut sup = ctx.sync_supervisor.lock().unwrap();
+                    if !sup.needs_restart() {
+                        if sup.is_stalled(Utc::now(), interval + max_staleness) &&
+                           !sup.stall_reported {
+                            error!("background sync made no progress for {}",
+                                   HumanDuration(interval + max_staleness));
+                            sup.stall_reported = true;
+                            sup.failures += 1;
+                            sup.last_error = Some("sync stalled".into());
+                        }
+                        continue;
+                    }
+                    sup.generation += 1;
+                    sup.restarts += 1;
+                    sup.failures += 1;
+                    sup.last_error = Some("sync loop died".into());
+                    sup.touch(Utc::now());
+                    sup.loop_running = true;
+                    sup.generation
+                };
+                error!("background sync loop died, restarting it");
+                spawn_sync_loop(ctx.clone(), generation, interval);
             }
         });
 
@@ -139,20 +564,323 @@ impl ApiServer {
         Ok(())
     }
 
-    /// Runs the server in a loop.
-    pub fn run(&self, threads: usize, opts: BindOptions) -> Result<()> {
-        let debug_addr;
+    /// Spawns a background thread that reloads the config file on `SIGHUP`.
+    ///
+    /// Only the ignore patterns of the stash and its tenants are applied,
+    /// everything else needs a restart.
+    pub fn spawn_reload_thread(&self, config_file: PathBuf) -> Result<()> {
+        install_reload_handler()?;
+
+        let ctx = self.ctx.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(StdDuration::from_millis(100));
+                if !take_reload_request() {
+                    continue;
+                }
+                let ctx = ctx.clone();
+                let config_file = config_file.clone();
+                run_isolated(move || {
+                    let config = Config::load_file(&config_file)?;
+                    ctx.stash.set_config_ignore_patterns(config.get_ignore_patterns()?.clone());
+                    for (name, tenant) in &ctx.tenants {
+                        match config.get_tenant_config(name) {
+                            Ok(tenant_config) => tenant.stash.set_config_ignore_patterns(
+                                tenant_config.get_ignore_patterns()?.clone()),
+                            Err(err) => warn!("Could not reload tenant {}: {}", name, err),
+                        }
+                    }
+                    info!("Reloaded ignore patterns from {}", config_file.display());
+                    Ok(())
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Spawns a background thread that persists the usage counters.
+    pub fn spawn_usage_thread(&self) -> Result<()> {
+        let interval = self.ctx.config.get_stash_usage_flush_interval()?;
+        let std_interval = interval.to_std().unwrap();
+        info!("Saving usage stats every {}", HumanDuration(interval));
+
+        let ctx = self.ctx.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(std_interval);
+                let ctx = ctx.clone();
+                run_isolated(move || ctx.stash.save_usage_stats());
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Spawns a background thread that picks up changes other processes
+    /// make to the stash.
+    pub fn spawn_state_check_thread(&self) -> Result<()> {
+        let interval = self.ctx.config.get_stash_state_check_interval()?;
+        let std_interval = interval.to_std().unwrap();
+        info!("Checking for external stash changes every {}", HumanDuration(interval));
+
+        let ctx = self.ctx.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(std_interval);
+                let ctx = ctx.clone();
+                run_isolated(move || ctx.stash.reload_if_changed().map(|_| ()));
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Spawns a background thread that removes leftover temporary files.
+    pub fn spawn_gc_thread(&self) -> Result<()> {
+        let interval = self.ctx.config.get_stash_gc_interval()?;
+        let std_interval = interval.to_std().unwrap();
+        info!("Removing leftover temporary files every {}", HumanDuration(interval));
+
+        let ctx = self.ctx.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(std_interval);
+                let ctx = ctx.clone();
+                run_isolated(move || ctx.stash.collect_garbage().map(|_| ()));
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Spawns a background thread that audits random samples of the stash
+    /// for bit-rot and drift from S3 if configured.
+    pub fn spawn_audit_thread(&self) -> Result<()> {
+        let interval = match self.ctx.config.get_stash_audit_interval()? {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        let sample = self.ctx.config.get_stash_audit_sample()?;
+        let std_interval = interval.to_std().unwrap();
+        info!("Auditing {} random SDKs every {}",
+              sample, HumanDuration(interval));
+
+        let ctx = self.ctx.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(std_interval);
+                let ctx = ctx.clone();
+                run_isolated(move || {
+                    let stats = ctx.stash.audit_sample(sample, "audit")?;
+                    info!("Audit of {} SDKs found {} broken and {} drifted",
+                          stats.checked(), stats.failures(), stats.drifted());
+                    Ok(())
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Spawns a background thread that keeps the listing of the bucket
+    /// fresh so that healthchecks do not list it themselves.
+    pub fn spawn_listing_refresh_thread(&self) -> Result<()> {
+        let ttl = self.ctx.stash.listing_ttl();
+        let std_interval = (ttl / 2).to_std().unwrap();
+        info!("Refreshing the bucket listing every {}", HumanDuration(ttl / 2));
+
+        let ctx = self.ctx.clone();
+        thread::spawn(move || {
+            loop {
+                let ctx = ctx.clone();
+                run_isolated(move || {
+                    match ctx.stash.refresh_remote_listing() {
+                        // reported by the sync status
+                        Err(ref err) if err.is_s3_offline() => Ok(()),
+                        rv => rv,
+                    }
+                });
+                thread::sleep(std_interval);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Spawns a background thread that sends the size of the stash and
+    /// the metrics of the most requested SDKs to statsd.
+    pub fn spawn_statsd_thread(&self) -> Result<()> {
+        let interval = self.ctx.config.get_statsd_interval()?;
+        let std_interval = interval.to_std().unwrap();
+        info!("Sending metrics to statsd at {} every {}",
+              self.ctx.config.get_statsd_addr().unwrap_or_default(), HumanDuration(interval));
+
+        let ctx = self.ctx.clone();
+        thread::spawn(move || {
+            loop {
+                let ctx = ctx.clone();
+                run_isolated(move || {
+                    if let Some(ref statsd) = ctx.statsd {
+                        statsd.gauge("stash.sdks", ctx.stash.sdk_count()? as u64, &[]);
+                        statsd.gauge("stash.revision", ctx.stash.get_revision()?, &[]);
+                        statsd.gauge("stash.disk_usage", ctx.stash.disk_usage(), &[]);
+                        statsd.gauge("stash.open_memdbs", ctx.stash.open_memdb_count() as u64,
+                                     &[]);
+                        statsd.gauge("stash.quarantined",
+                                     ctx.stash.list_quarantined()?.len() as u64, &[]);
+                        let audits = ctx.stash.audit_stats();
+                        statsd.gauge("stash.audit_failures", audits.failures(), &[]);
+                        statsd.gauge("stash.audit_drift", audits.drifted(), &[]);
+                        if let Some(resident_memory) = get_resident_memory() {
+                            statsd.gauge("resident_memory", resident_memory, &[]);
+                        }
+                        // lookups may name any SDK id, only the ones the
+                        // stash has become tags
+                        let known: HashSet<_> = ctx.stash.list_sdks()?.iter()
+                            .map(|x| x.sdk_id()).collect();
+                        let top = ctx.config.get_server_sdk_metrics_top()?;
+                        for stats in ctx.sdk_metrics.top(top).into_iter()
+                            .filter(|x| known.contains(x.sdk_id()))
+                        {
+                            let tags = [("sdk_id", stats.sdk_id())];
+                            statsd.gauge("sdk.requests", stats.requests(), &tags);
+                            statsd.gauge("sdk.sdk_not_found", stats.sdk_not_found(), &tags);
+                            statsd.gauge("sdk.unknown_objects", stats.unknown_objects(), &tags);
+                        }
+                    }
+                    Ok(())
+                });
+                thread::sleep(std_interval);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Returns a handle that shuts the running server down.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle { ctx: self.ctx.clone() }
+    }
+
+    /// Waits until a shutdown is requested and shuts the server down
+    /// gracefully.
+    ///
+    /// The server drains, keeps serving for `server.shutdown_grace_period`
+    /// so that load balancers notice, waits for running requests and saves
+    /// the usage stats.
+    fn wait_for_shutdown(&self, grace_period: Duration) {
+        let ctx = &self.ctx;
+        while !ctx.shutting_down.load(Ordering::SeqCst) {
+            thread::sleep(StdDuration::from_millis(100));
+        }
+        info!("Shutting down in {}", HumanDuration(grace_period));
+        ctx.start_draining();
+        thread::sleep(grace_period.to_std().unwrap());
+
+        let deadline = Instant::now() + StdDuration::from_secs(SHUTDOWN_REQUEST_TIMEOUT);
+        while ctx.requests_in_flight.load(Ordering::SeqCst) > 0 &&
+              Instant::now() < deadline {
+            thread::sleep(StdDuration::from_millis(50));
+        }
+        let in_flight = ctx.requests_in_flight.load(Ordering::SeqCst);
+        if in_flight > 0 {
+            warn!("Abandoning {} running requests", in_flight);
+        }
+        for ctx in iter::once(ctx).chain(ctx.tenants.values()) {
+            if let Err(err) = ctx.stash.save_usage_stats() {
+                warn!("Could not save usage stats: {}", err);
+            }
+        }
+        info!("Shut down");
+    }
+
+    /// Opens the most frequently used SDKs ahead of time on the IO threads.
+    ///
+    /// Returns the number of memdbs that were opened.
+    fn warm_up(&self, count: usize) -> Result<usize> {
+        let candidates = self.ctx.stash.warm_up_candidates(count)?;
+        let ctx = self.ctx.clone();
+        let results = parallel_map(candidates, self.ctx.config.get_server_io_threads()?,
+                                   move |info| ctx.stash.get_memdb(&info).map(|_| ()));
+        let mut opened = 0;
+        for result in results {
+            result?;
+            opened += 1;
+        }
+        Ok(opened)
+    }
+
+    /// Checks the stash and starts the background threads.
+    fn start(&self) -> Result<()> {
+        match self.ctx.stash.check_consistency("server") {
+            Ok(report) => {
+                info!("Checked {} SDKs against the symbol directory", report.checked());
+                for info in report.missing() {
+                    warn!("Dropped {} from sync state, memdb is missing", info);
+                }
+                for filename in report.unknown() {
+                    warn!("Found {} which is not in the sync state", filename);
+                }
+            }
+            Err(err) => {
+                warn!("Could not check stash consistency: {}", err);
+            }
+        }
+
+        match self.ctx.stash.collect_garbage() {
+            Ok(stats) => {
+                if stats.removed_files() > 0 {
+                    info!("Removed {} leftover temporary files ({})", stats.removed_files(),
+                          HumanBytes(stats.removed_bytes()));
+                }
+            }
+            Err(err) => {
+                warn!("Could not remove leftover temporary files: {}", err);
+            }
+        }
+
+        let warmup_sdks = self.ctx.config.get_stash_warmup_sdks()?;
+        if warmup_sdks > 0 {
+            let opened = self.warm_up(warmup_sdks)?;
+            info!("Warmed up {} frequently used SDKs", opened);
+        }
+        self.spawn_usage_thread()?;
+        self.spawn_state_check_thread()?;
+        self.spawn_gc_thread()?;
+        self.spawn_audit_thread()?;
+        if self.ctx.statsd.is_some() {
+            self.spawn_statsd_thread()?;
+        }
 
         if self.ctx.enable_sync {
             self.spawn_sync_thread()?;
+            self.spawn_sync_supervisor_thread()?;
+            self.spawn_listing_refresh_thread()?;
             self.spawn_healthcheck_thread()?;
         } else {
             info!("Background sync is disabled. Health check forced to healthy.");
         }
+        Ok(())
+    }
+
+    /// Runs the server until it is shut down with a `ShutdownHandle`.
+    pub fn run(&self, threads: usize, opts: BindOptions) -> Result<()> {
+        let debug_addr;
+        let tls = TlsServer::from_config(&self.ctx.config)?;
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let grace_period = self.ctx.config.get_server_shutdown_grace_period()?;
+
+        info!("Starting sentry-symbolserver {}", get_build_description());
+        self.start()?;
+        for (name, ctx) in &self.ctx.tenants {
+            info!("Starting tenant {}", name);
+            ApiServer { ctx: ctx.clone() }.start()?;
+        }
 
         let listener = match opts {
             BindOptions::BindToAddr(addr) => {
-                debug_addr = format!("http://{}/", addr);
+                debug_addr = format!("{}://{}/", scheme, addr);
                 HttpListener::new(addr)?
             }
             BindOptions::BindToFd(fd) => {
@@ -170,42 +898,261 @@ impl ApiServer {
                 } else {
                     let addr = self.ctx.config.get_server_socket_addr()?;
                     let (host, port) = addr;
-                    debug_addr = format!("http://{}:{}/", host, port);
+                    debug_addr = format!("{}://{}:{}/", scheme, host, port);
                     HttpListener::new((host.as_str(), port))?
                 }
             }
         };
         info!("Listening on {}", debug_addr);
         info!("Spawning {} listener threads", threads);
+        self.ctx.listener_threads.store(threads, Ordering::Relaxed);
+        #[cfg(feature = "grpc")]
+        let _grpc = GrpcServer::start(&self.ctx, threads)?;
+        #[cfg(not(feature = "grpc"))]
+        refuse_grpc_port(&self.ctx.config)?;
 
-        let ctx = self.ctx.clone();
-        Server::new(listener)
-            .handle_threads(move |req: Request, resp: Response|
-        {
+        let slow_request_threshold = self.ctx.config.get_server_slow_request_threshold()?
+            .to_std().unwrap();
+        let keep_alive = self.ctx.config.get_server_keep_alive_timeout()?
+            .map(|x| x.to_std().unwrap());
+        let max_requests = self.ctx.config.get_server_max_requests_per_connection()?;
+        let root = self.ctx.clone();
+        let handle = move |mut req: Request, resp: Response| {
+            let started = Instant::now();
+            let in_flight = InFlightGuard::new(&root.requests_in_flight);
+            root.requests_total.fetch_add(1, Ordering::Relaxed);
+            spans::start_request();
+            let request_line = format!("{} {}", req.method, req.uri);
             let is_head = req.method == Method::Head;
-            let handler = match req.uri {
-                RequestUri::AbsolutePath(ref path) => {
-                    match path.as_str() {
-                        "/health" => handlers::healthcheck_handler,
-                        "/lookup" => handlers::lookup_symbol_handler,
-                        "/sdks" => handlers::list_sdks_handler,
-                        "/version" => handlers::version_handler,
-                        _ => not_found,
+            let has_body = req.headers.get::<ContentLength>().map_or(false, |x| x.0 > 0);
+            let connection_requests = count_connection_request(&req);
+            let (ctx, endpoint, handler) = route(&root, &mut req);
+            let (mut rv, failed) = match handler(&*ctx, req) {
+                Ok(result) => (result, false),
+                Err(err) => (ApiResponse::from_error(err).unwrap(), true),
+            };
+            // handlers check credentials before they read the body, what is
+            // left of it would be taken for the next request
+            if failed && has_body {
+                rv = rv.with_header("Connection", "close".to_string());
+            } else if max_requests.map_or(false, |max| connection_requests >= max) {
+                rv = rv.with_header("Connection", "close".to_string());
+            }
+            let status = rv.status();
+            {
+                let _span = spans::span("write");
+                // clients that hang up mid-download are no error of ours
+                if let Err(err) = rv.write_to_response(is_head, resp) {
+                    debug!("could not send response to {}: {}", request_line, err);
+                }
+            }
+            drop(in_flight);
+            if let Some(ref statsd) = ctx.statsd {
+                let status = status.to_u16().to_string();
+                statsd.count("requests", 1, &[("endpoint", endpoint), ("status", &status)]);
+                statsd.timing("request.duration", started.elapsed(), &[("endpoint", endpoint)]);
+            }
+            if let Some(trace) = spans::finish_request() {
+                if trace.elapsed() >= slow_request_threshold {
+                    let mut details = String::new();
+                    for &(key, ref value) in trace.details() {
+                        details.push_str(&format!(" {}={}", key, value));
                     }
+                    warn!("slow request: {}{} answered {} in {:.1}ms ({})",
+                          request_line, details, status,
+                          spans::as_millis(trace.elapsed()), trace);
                 }
-                _ => bad_request,
-            };
-            match handler(&*ctx.clone(), req) {
-                Ok(result) => result,
-                Err(err) => ApiResponse::from_error(err).unwrap(),
-            }.write_to_response(is_head, resp).unwrap();
-        }, threads)?;
+            }
+        };
+        let listening = match tls {
+            Some(tls) => {
+                let listener = HttpsListener::with_listener(listener, tls);
+                let mut server = Server::new(CountingListener(listener));
+                server.keep_alive(keep_alive);
+                server.handle_threads(handle, threads)?
+            }
+            None => {
+                let mut server = Server::new(CountingListener(listener));
+                server.keep_alive(keep_alive);
+                server.handle_threads(handle, threads)?
+            }
+        };
+        self.wait_for_shutdown(grace_period);
+        // hyper cannot stop its listener threads and dropping the guard
+        // would join them forever, they end with the process.
+        mem::forget(listening);
         Ok(())
     }
 }
 
+/// Refuses `server.grpc_port` as this build cannot serve gRPC.
+#[cfg(not(feature = "grpc"))]
+fn refuse_grpc_port(config: &Config) -> Result<()> {
+    if config.get_server_grpc_port()?.is_some() {
+        return Err(ErrorKind::BadConfigKey(
+            "server.grpc_port", "gRPC needs a build with the grpc feature").into());
+    }
+    Ok(())
+}
+
+/// Counts a request towards the connection it came in on and returns
+/// how many requests came in on that connection so far.
+fn count_connection_request(req: &Request) -> u64 {
+    req.ssl::<CountingStream<HttpStream>>().map(|x| x.count_request())
+        .or_else(|| req.ssl::<CountingStream<TlsStream>>().map(|x| x.count_request()))
+        .unwrap_or(0) as u64
+}
+
+/// Finds the stash a request is for and the handler of its endpoint.
+///
+/// Requests to `/tenants/{name}/...` go to the tenant with the prefix
+/// removed from their path.
+fn route(root: &Arc<ServerContext>, req: &mut Request)
+    -> (Arc<ServerContext>, &'static str, Handler)
+{
+    let mut path = match req.uri {
+        RequestUri::AbsolutePath(ref path) => path.clone(),
+        _ => { return (root.clone(), "bad_request", bad_request); }
+    };
+    let mut ctx = root.clone();
+    if path.starts_with("/tenants/") {
+        let (name, rest) = {
+            let rest = &path["/tenants/".len()..];
+            let idx = rest.find(|c| c == '/' || c == '?').unwrap_or_else(|| rest.len());
+            (rest[..idx].to_string(), format!("/{}", rest[idx..].trim_start_matches('/')))
+        };
+        ctx = match root.get_tenant(&name) {
+            Some(tenant) => tenant.clone(),
+            None => { return (root.clone(), "not_found", not_found); }
+        };
+        spans::annotate("tenant", name);
+        req.uri = RequestUri::AbsolutePath(rest.clone());
+        path = rest;
+    }
+    let (endpoint, handler): (&'static str, Handler) =
+        match path.splitn(2, '?').next().unwrap_or("") {
+            "/health" => ("/health", handlers::healthcheck_handler),
+            "/ready" => ("/ready", handlers::readiness_handler),
+            "/lookup" => ("/lookup", handlers::lookup_symbol_handler),
+            "/lookup/batch" => ("/lookup/batch", handlers::batch_lookup_handler),
+            "/sdks" => ("/sdks", handlers::list_sdks_handler),
+            "/sdks/resolve" => ("/sdks/resolve", handlers::resolve_sdk_handler),
+            "/mirror/state" => ("/mirror/state", handlers::mirror_state_handler),
+            "/version" => ("/version", handlers::version_handler),
+            "/signing-key" => ("/signing-key", handlers::signing_key_handler),
+            "/metrics" => ("/metrics", handlers::metrics_handler),
+            "/admin/usage" => ("/admin/usage", handlers::usage_handler),
+            "/admin/memory" => ("/admin/memory", handlers::memory_handler),
+            "/admin/audit" => ("/admin/audit", handlers::audit_handler),
+            "/admin/drain" => ("/admin/drain", handlers::drain_handler),
+            "/admin/ignore" => ("/admin/ignore", handlers::ignore_handler),
+            path if path.starts_with("/download/") => {
+                ("/download", handlers::download_handler)
+            }
+            path if path.starts_with("/mirror/objects/") => {
+                ("/mirror/objects", handlers::mirror_objects_handler)
+            }
+            path if path.starts_with("/admin/sdks/") => {
+                ("/admin/sdks", handlers::admin_sdk_handler)
+            }
+            _ => ("not_found", not_found),
+        };
+    (ctx, endpoint, handler)
+}
+
+/// Forwards the progress of a background sync to the log and keeps the
+/// heartbeat of its loop fresh while it works through the SDKs.
+struct HeartbeatReporter {
+    ctx: Arc<ServerContext>,
+    generation: usize,
+    inner: Arc<Reporter>,
+}
+
+impl Reporter for HeartbeatReporter {
+    fn sdk_status(&self, status: SdkStatus, info: &SdkInfo) {
+        self.ctx.touch_sync(self.generation);
+        self.inner.sdk_status(status, info);
+    }
+
+    fn step(&self, step: usize, steps: usize, msg: &str) {
+        self.inner.step(step, steps, msg);
+    }
+
+    fn detail(&self, msg: &str) {
+        self.inner.detail(msg);
+    }
+}
+
+/// Tells the supervisor when the thread of a sync loop exits, also if it
+/// panicked.
+struct SyncLoopGuard {
+    ctx: Arc<ServerContext>,
+    generation: usize,
+}
+
+impl Drop for SyncLoopGuard {
+    fn drop(&mut self) {
+        self.ctx.end_sync_loop(self.generation);
+    }
+}
+
+/// Runs the sync loop of the given generation in a new thread.
+fn spawn_sync_loop(ctx: Arc<ServerContext>, generation: usize, interval: Duration) {
+    let std_interval = interval.to_std().unwrap();
+    thread::spawn(move || {
+        let _guard = SyncLoopGuard { ctx: ctx.clone(), generation };
+        while ctx.begin_sync(generation) {
+            let started = Instant::now();
+            let sync_ctx = ctx.clone();
+            let mut warning = None;
+            let failure = {
+                let warning = &mut warning;
+                run_isolated(move || {
+                    let options = SyncOptions {
+                        reporter: Arc::new(HeartbeatReporter {
+                            ctx: sync_ctx.clone(),
+                            generation,
+                            inner: default_reporter(),
+                        }),
+                        ..Default::default()
+                    };
+                    match sync_ctx.stash.sync(options) {
+                        Err(ref err) if err.is_s3_offline() => {
+                            *warning = Some(err.to_string());
+                            Ok(())
+                        }
+                        rv => rv,
+                    }
+                })
+            };
+            if let Some(ref statsd) = ctx.statsd {
+                let result = if warning.is_some() {
+                    "offline"
+                } else if failure.is_some() {
+                    "failure"
+                } else {
+                    "success"
+                };
+                statsd.count("sync.runs", 1, &[("result", result)]);
+                statsd.timing("sync.duration", started.elapsed(), &[("result", result)]);
+            }
+            if !ctx.record_sync(generation, failure, warning) {
+                break;
+            }
+            thread::sleep(std_interval);
+        }
+        info!("sync loop {} shut down", generation);
+    });
+}
+
 /// Helper for the handlers to safely load request data.
 pub fn load_request_data<D: Deserialize>(req: &mut Request) -> Result<D> {
+    let body = read_request_body(req)?;
+    parse_request_data(&body)
+}
+
+/// Reads the body of a request, which is limited to 10MB.
+pub fn read_request_body(req: &mut Request) -> Result<Vec<u8>> {
     if let Some(&ContentLength(length)) = req.headers.get() {
         if length > 1024 * 1024 * 10 {
             return Err(ApiError::PayloadTooLarge.into());
@@ -216,13 +1163,39 @@ pub fn load_request_data<D: Deserialize>(req: &mut Request) -> Result<D> {
 
     let mut body: Vec<u8> = vec![];
     req.read_to_end(&mut body)?;
+    Ok(body)
+}
 
-    Ok(match serde_json::from_slice(&body[..]) {
+/// Parses a request body read with `read_request_body`.
+pub fn parse_request_data<D: Deserialize>(body: &[u8]) -> Result<D> {
+    let _span = spans::span("parse");
+    Ok(match serde_json::from_slice(body) {
         Ok(data) => data,
         Err(err) => { return Err(ApiError::BadJson(Box::new(err)).into()); }
     })
 }
 
+/// Parses a query string parameter of the request.
+///
+/// Returns `None` if the parameter is missing and fails with a bad
+/// request if it cannot be parsed.
+pub fn get_query_param<T: FromStr>(req: &Request, key: &str) -> Result<Option<T>> {
+    let query = match req.uri {
+        RequestUri::AbsolutePath(ref path) => match path.splitn(2, '?').nth(1) {
+            Some(query) => query,
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    match form_urlencoded::parse(query.as_bytes()).find(|&(ref k, _)| k == key) {
+        Some((_, value)) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(ApiError::BadRequest.into()),
+        },
+        None => Ok(None),
+    }
+}
+
 fn bad_request(_: &ServerContext, _: Request) -> Result<ApiResponse>
 {
     Err(ApiError::BadRequest.into())
@@ -232,3 +1205,30 @@ fn not_found(_: &ServerContext, _: Request) -> Result<ApiResponse>
 {
     Err(ApiError::NotFound.into())
 }
+
+#[test]
+fn test_sync_supervisor_restarts_only_dead_loops() {
+    let now = Utc::now();
+    let limit = Duration::seconds(60);
+    let mut sup = SyncSupervisor {
+        started: Some(now - Duration::seconds(600)),
+        heartbeat: Some(now - Duration::seconds(300)),
+        loop_running: true,
+        in_flight: true,
+        ..Default::default()
+    };
+
+    // a hanging sync is reported but left alone
+    assert!(sup.is_stalled(now, limit));
+    assert!(!sup.needs_restart());
+
+    // so is a live loop between two syncs
+    sup.in_flight = false;
+    assert!(!sup.needs_restart());
+
+    sup.loop_running = false;
+    assert!(sup.needs_restart());
+
+    sup.touch(now);
+    assert!(!sup.is_stalled(now, limit));
+}
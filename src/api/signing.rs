@@ -0,0 +1,161 @@
+//! Signatures over lookup responses.
+//!
+//! With `server.signing_key` set the body of every successful lookup is
+//! signed with SHA-256 and that key (RSA or EC, PEM encoded).  Systems
+//! further downstream can check with the public key from `/signing-key`
+//! that symbols came from this server and were not changed by proxies or
+//! caches on the way.
+//!
+//! Signing needs the `crypto` feature.  Builds without it refuse
+//! `server.signing_key`.
+#[cfg(feature = "crypto")]
+use std::fs;
+#[cfg(feature = "crypto")]
+use std::io::Read;
+use std::path::Path;
+
+#[cfg(feature = "crypto")]
+use openssl::hash::MessageDigest;
+#[cfg(feature = "crypto")]
+use openssl::pkey::PKey;
+#[cfg(feature = "crypto")]
+use openssl::sha::sha256;
+#[cfg(feature = "crypto")]
+use openssl::sign::Signer;
+#[cfg(feature = "crypto")]
+use rustc_serialize::base64::{ToBase64, STANDARD};
+#[cfg(feature = "crypto")]
+use rustc_serialize::hex::ToHex;
+
+use super::super::config::Config;
+use super::super::{ErrorKind, Result};
+#[cfg(feature = "crypto")]
+use super::super::ResultExt;
+
+/// Signs response bodies with the configured key.
+#[cfg(feature = "crypto")]
+pub struct ResponseSigner {
+    key: PKey,
+    key_id: String,
+    algorithm: &'static str,
+    public_key: String,
+}
+
+/// Builds without the `crypto` feature cannot load a signing key, so
+/// there is never a value of this.
+#[cfg(not(feature = "crypto"))]
+pub enum ResponseSigner {}
+
+/// The public half of the signing key as served to clients.
+#[derive(Serialize)]
+pub struct SigningKeyInfo<'a> {
+    key_id: &'a str,
+    algorithm: &'static str,
+    public_key: &'a str,
+}
+
+impl ResponseSigner {
+    /// Loads the signing key of the config if one is set.
+    pub fn from_config(config: &Config) -> Result<Option<ResponseSigner>> {
+        match config.get_server_signing_key() {
+            Some(path) => Ok(Some(ResponseSigner::load(&path)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl ResponseSigner {
+    /// Refuses to load a key as this build cannot sign.
+    pub fn load<P: AsRef<Path>>(_path: P) -> Result<ResponseSigner> {
+        Err(ErrorKind::BadConfigKey(
+            "server.signing_key", "Signing needs a build with the crypto feature").into())
+    }
+
+    /// A short fingerprint of the public key
+    pub fn key_id(&self) -> &str {
+        match *self {}
+    }
+
+    /// Describes the public key for clients.
+    pub fn key_info(&self) -> SigningKeyInfo {
+        match *self {}
+    }
+
+    /// Signs data and returns the base64 encoded signature.
+    pub fn sign(&self, _data: &[u8]) -> Result<String> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl ResponseSigner {
+    /// Loads a PEM encoded RSA or EC private key.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ResponseSigner> {
+        let mut pem = vec![];
+        fs::File::open(path.as_ref())
+            .and_then(|mut f| f.read_to_end(&mut pem))
+            .chain_err(|| format!("Could not read signing key {}", path.as_ref().display()))?;
+        let key = PKey::private_key_from_pem(&pem)
+            .chain_err(|| "Signing key is not a PEM encoded private key")?;
+        let algorithm = if key.rsa().is_ok() {
+            "rsa-sha256"
+        } else if key.ec_key().is_ok() {
+            "ecdsa-sha256"
+        } else {
+            return Err(ErrorKind::BadConfigKey(
+                "server.signing_key", "Signing key has to be an RSA or EC key").into());
+        };
+        let key_id = sha256(&key.public_key_to_der()?)[..8].to_hex();
+        let public_key = String::from_utf8(key.public_key_to_pem()?)?;
+        Ok(ResponseSigner { key, key_id, algorithm, public_key })
+    }
+
+    /// A short fingerprint of the public key
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Describes the public key for clients.
+    pub fn key_info(&self) -> SigningKeyInfo {
+        SigningKeyInfo {
+            key_id: &self.key_id,
+            algorithm: self.algorithm,
+            public_key: &self.public_key,
+        }
+    }
+
+    /// Signs data and returns the base64 encoded signature.
+    pub fn sign(&self, data: &[u8]) -> Result<String> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(data)?;
+        Ok(signer.sign_to_vec()?.to_base64(STANDARD))
+    }
+}
+
+#[test]
+#[cfg(feature = "crypto")]
+fn test_sign() {
+    use std::io::Write;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid;
+    use openssl::sign::Verifier;
+    use rustc_serialize::base64::FromBase64;
+    use tempdir::TempDir;
+
+    let group = EcGroup::from_curve_name(nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let path = dir.path().join("signing.pem");
+    fs::File::create(&path).unwrap().write_all(&key.private_key_to_pem().unwrap()).unwrap();
+
+    let signer = ResponseSigner::load(&path).unwrap();
+    assert_eq!(signer.key_info().algorithm, "ecdsa-sha256");
+    assert_eq!(signer.key_id().len(), 16);
+    let signature = signer.sign(b"{\"symbols\":[]}").unwrap().from_base64().unwrap();
+
+    let public_key = PKey::public_key_from_pem(signer.key_info().public_key.as_bytes()).unwrap();
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+    verifier.update(b"{\"symbols\":[]}").unwrap();
+    assert!(verifier.verify(&signature).unwrap());
+}
@@ -0,0 +1,177 @@
+//! Per-request timing spans.
+//!
+//! Every request handled by a listener thread gets a trace.  Code that is
+//! interesting for slow requests wraps itself in a span and the trace adds
+//! up how long was spent in each span, not counting the spans nested in
+//! it.  Since a listener thread handles one request at a time the trace is
+//! kept in a thread local and spans do nothing outside of requests.
+use std::cell::RefCell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The spans of a single request.
+pub struct RequestTrace {
+    started: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    details: Vec<(&'static str, String)>,
+    stack: Vec<OpenSpan>,
+}
+
+/// A span that has not ended yet.
+struct OpenSpan {
+    name: &'static str,
+    started: Instant,
+    nested: Duration,
+}
+
+/// Ends the span when dropped.
+pub struct Span {
+    active: bool,
+}
+
+thread_local! {
+    static CURRENT_TRACE: RefCell<Option<RequestTrace>> = RefCell::new(None);
+}
+
+/// Starts the trace of a request on this thread.
+pub fn start_request() {
+    CURRENT_TRACE.with(|trace| {
+        *trace.borrow_mut() = Some(RequestTrace {
+            started: Instant::now(),
+            phases: vec![],
+            details: vec![],
+            stack: vec![],
+        });
+    });
+}
+
+/// Ends the trace of the request on this thread and returns it.
+pub fn finish_request() -> Option<RequestTrace> {
+    CURRENT_TRACE.with(|trace| trace.borrow_mut().take())
+}
+
+/// Returns the time spent in the spans of the request on this thread
+/// that have ended so far.
+pub fn current_phases() -> Vec<(&'static str, Duration)> {
+    CURRENT_TRACE.with(|trace| match *trace.borrow() {
+        Some(ref trace) => trace.phases.clone(),
+        None => vec![],
+    })
+}
+
+/// Starts a span that ends when the returned value is dropped.
+pub fn span(name: &'static str) -> Span {
+    let active = CURRENT_TRACE.with(|trace| {
+        if let Some(ref mut trace) = *trace.borrow_mut() {
+            trace.stack.push(OpenSpan {
+                name,
+                started: Instant::now(),
+                nested: Duration::from_secs(0),
+            });
+            true
+        } else {
+            false
+        }
+    });
+    Span { active }
+}
+
+/// Records a detail of the request (such as the SDK it was for) that is
+/// logged with it.
+pub fn annotate(key: &'static str, value: String) {
+    CURRENT_TRACE.with(|trace| {
+        if let Some(ref mut trace) = *trace.borrow_mut() {
+            trace.details.push((key, value));
+        }
+    });
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+        CURRENT_TRACE.with(|trace| {
+            if let Some(ref mut trace) = *trace.borrow_mut() {
+                trace.end_span();
+            }
+        });
+    }
+}
+
+impl RequestTrace {
+    fn end_span(&mut self) {
+        let span = match self.stack.pop() {
+            Some(span) => span,
+            None => return,
+        };
+        let elapsed = span.started.elapsed();
+        if let Some(parent) = self.stack.last_mut() {
+            parent.nested += elapsed;
+        }
+        let own = elapsed.checked_sub(span.nested).unwrap_or_default();
+        if let Some(phase) = self.phases.iter_mut().find(|x| x.0 == span.name) {
+            phase.1 += own;
+            return;
+        }
+        self.phases.push((span.name, own));
+    }
+
+    /// How long the request took so far
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The time spent in each span, in the order the spans first ended
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    /// The recorded details of the request
+    pub fn details(&self) -> &[(&'static str, String)] {
+        &self.details
+    }
+}
+
+impl fmt::Display for RequestTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, &(name, duration)) in self.phases.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {:.1}ms", name, as_millis(duration))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns a duration in milliseconds.
+pub fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+#[test]
+fn test_nested_spans() {
+    use std::thread;
+
+    start_request();
+    {
+        let _outer = span("serialize");
+        thread::sleep(Duration::from_millis(20));
+        for _ in 0..2 {
+            let _inner = span("search");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+    annotate("sdk_id", "iOS_10.2.0_14C92".into());
+    let trace = finish_request().unwrap();
+    let phases: Vec<_> = trace.phases().iter().map(|x| x.0).collect();
+    assert_eq!(phases, vec!["search", "serialize"]);
+    assert!(trace.phases()[0].1 >= Duration::from_millis(40));
+    assert!(trace.phases()[1].1 < Duration::from_millis(40));
+    assert_eq!(trace.details().len(), 1);
+
+    // outside of requests spans are ignored
+    drop(span("search"));
+    assert!(finish_request().is_none());
+}
@@ -0,0 +1,224 @@
+//! Serving the API over TLS.
+//!
+//! With `server.tls.cert` and `server.tls.key` the server terminates TLS
+//! itself.  If `server.tls.client_ca` is set it also asks clients for a
+//! certificate and checks it against those CAs, which is required for the
+//! `client_cert` authentication.
+//!
+//! TLS needs the `crypto` feature.  Builds without it refuse `server.tls`
+//! and only serve plain HTTP.
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+#[cfg(feature = "crypto")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+#[cfg(feature = "crypto")]
+use openssl::nid;
+#[cfg(feature = "crypto")]
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslStream,
+                   SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_PEER};
+#[cfg(feature = "crypto")]
+use openssl::x509::X509_FILETYPE_PEM;
+
+#[cfg(feature = "crypto")]
+use super::super::config::AuthMode;
+use super::super::config::Config;
+use super::super::{ErrorKind, Result};
+#[cfg(feature = "crypto")]
+use super::super::ResultExt;
+
+/// How long the TLS handshake of a client may take, in seconds.
+#[cfg(feature = "crypto")]
+const TLS_HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Wraps accepted connections in TLS.
+#[cfg(feature = "crypto")]
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: Arc<SslAcceptor>,
+}
+
+/// A TLS connection of a client.
+#[cfg(feature = "crypto")]
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<SslStream<HttpStream>>>);
+
+/// Builds without the `crypto` feature never serve TLS, so there is
+/// never a value of this.
+#[cfg(not(feature = "crypto"))]
+#[derive(Clone)]
+pub enum TlsServer {}
+
+/// Builds without the `crypto` feature never serve TLS, so there is
+/// never a value of this.
+#[cfg(not(feature = "crypto"))]
+#[derive(Clone)]
+pub enum TlsStream {}
+
+#[cfg(not(feature = "crypto"))]
+impl TlsServer {
+    /// Refuses a TLS config as this build cannot serve TLS.
+    pub fn from_config(config: &Config) -> Result<Option<TlsServer>> {
+        if config.get_server_tls_cert().is_some() || config.get_server_tls_key().is_some() {
+            return Err(ErrorKind::BadConfigKey(
+                "server.tls", "TLS needs a build with the crypto feature").into());
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl SslServer for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, _stream: HttpStream) -> hyper::Result<TlsStream> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl TlsStream {
+    /// The common name of the verified certificate of the client.
+    pub fn client_common_name(&self) -> Option<String> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl Read for TlsStream {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl Write for TlsStream {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        match *self {}
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        match *self {}
+    }
+
+    fn set_read_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+        match *self {}
+    }
+
+    fn set_write_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+        match *self {}
+    }
+
+    fn close(&mut self, _how: Shutdown) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl TlsServer {
+    /// Sets up TLS as configured, if the server serves TLS at all.
+    pub fn from_config(config: &Config) -> Result<Option<TlsServer>> {
+        let (cert, key) = match (config.get_server_tls_cert(), config.get_server_tls_key()) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(ErrorKind::BadConfigKey(
+                    "server.tls", "TLS needs both a cert and a key").into());
+            }
+        };
+        let mut builder = SslAcceptorBuilder::mozilla_intermediate_raw(SslMethod::tls())?;
+        builder.set_certificate_chain_file(&cert)
+            .chain_err(|| format!("Could not load TLS certificate {}", cert.display()))?;
+        builder.set_private_key_file(&key, X509_FILETYPE_PEM)
+            .chain_err(|| format!("Could not load TLS key {}", key.display()))?;
+        builder.check_private_key().chain_err(|| "TLS key does not match the certificate")?;
+        if let Some(ca) = config.get_server_tls_client_ca() {
+            builder.set_ca_file(&ca)
+                .chain_err(|| format!("Could not load client CAs {}", ca.display()))?;
+            if config.get_server_auth_mode()? == AuthMode::ClientCert {
+                builder.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT);
+            } else {
+                builder.set_verify(SSL_VERIFY_PEER);
+            }
+        }
+        Ok(Some(TlsServer { acceptor: Arc::new(builder.build()) }))
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl SslServer for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        // the handshake runs on a listener thread, a client that stalls it
+        // must not block that thread forever.  hyper sets the timeouts of
+        // requests once it got the stream.
+        let timeout = Some(Duration::from_secs(TLS_HANDSHAKE_TIMEOUT));
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        match self.acceptor.accept(stream) {
+            Ok(stream) => Ok(TlsStream(Arc::new(Mutex::new(stream)))),
+            Err(err) => Err(hyper::Error::Io(io::Error::new(io::ErrorKind::Other,
+                                                            err.to_string()))),
+        }
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl TlsStream {
+    /// The common name of the verified certificate of the client, if it
+    /// sent one.
+    pub fn client_common_name(&self) -> Option<String> {
+        let stream = self.0.lock().unwrap();
+        let cert = stream.ssl().peer_certificate()?;
+        let entry = cert.subject_name().entries_by_nid(nid::COMMONNAME).next()?;
+        entry.data().as_utf8().ok().map(|x| x.to_string())
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.lock().unwrap().get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.lock().unwrap().get_mut().close(how)
+    }
+}
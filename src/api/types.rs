@@ -1,19 +1,41 @@
+use std::fs;
 use std::fmt;
 use std::error;
+use std::io::{self, Read, Seek, SeekFrom};
 
-use hyper::server::Response;
+use hyper::server::{Request, Response};
 use hyper::status::StatusCode;
-use hyper::header::{Server, ContentLength, ContentType};
-use serde_json;
+use hyper::header::{Accept, Server, ContentLength, ContentType};
+use serde_json::{self, Value};
 use serde::Serialize;
 
 use super::super::{Result, Error, ResultExt, ErrorKind};
 use super::super::constants::VERSION;
+use super::super::utils::Addr;
+use super::msgpack;
+use super::spans;
 
 /// Represents API responses.
 pub struct ApiResponse {
-    body: Vec<u8>,
+    body: ResponseBody,
     status: StatusCode,
+    content_type: ContentType,
+    headers: Vec<(&'static str, String)>,
+}
+
+/// The encodings a response body can be sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    MsgPack,
+}
+
+/// The body of an API response.
+enum ResponseBody {
+    Data(Vec<u8>),
+    /// `len` bytes of a file starting at `offset`
+    File { file: fs::File, offset: u64, len: u64 },
+    Empty,
 }
 
 /// Represents API Errors.
@@ -25,28 +47,261 @@ pub enum ApiError {
     PayloadTooLarge,
     BadJson(Box<serde_json::Error>),
     SdkNotFound,
+    Unauthorized,
+    SdkForbidden,
+    AdminForbidden,
     InternalServerError(Box<Error>),
 }
 
+/// The body of an error response.
+///
+/// `code` is stable and meant for clients to branch on, `message` is for
+/// humans and `details` carries error specific data (or `null`).  `type`
+/// is the old name of `code` and only kept for older clients.
 #[derive(Serialize)]
 struct ApiErrorDescription {
+    code: &'static str,
     #[serde(rename="type")]
-    pub ty: String,
-    pub message: String,
+    ty: &'static str,
+    message: String,
+    details: Value,
+}
+
+impl ApiErrorDescription {
+    fn new(code: &'static str, message: String, details: Value) -> ApiErrorDescription {
+        ApiErrorDescription {
+            code,
+            ty: code,
+            message,
+            details,
+        }
+    }
+}
+
+/// Maps an error that did not originate in the API layer to a response.
+fn describe_error(err: &Error) -> (ApiErrorDescription, StatusCode) {
+    let (code, details, status) = match *err.kind() {
+        ErrorKind::UnknownSdk => {
+            ("unknown_sdk", Value::Null, StatusCode::NotFound)
+        }
+        ErrorKind::UnknownArchitecture(ref arch) => {
+            ("unknown_architecture", json!({"arch": arch}), StatusCode::BadRequest)
+        }
+        ErrorKind::MissingArchitecture(ref arch) => {
+            ("missing_architecture", json!({"arch": arch}), StatusCode::NotFound)
+        }
+        ErrorKind::UnsupportedMemDbVersion => {
+            ("unsupported_memdb_version", Value::Null, StatusCode::InternalServerError)
+        }
+        ErrorKind::BadMemDb => {
+            ("bad_memdb", Value::Null, StatusCode::InternalServerError)
+        }
+        ErrorKind::ChecksumMismatch(ref expected, ref actual) => {
+            ("checksum_mismatch", json!({"expected": expected, "actual": actual}),
+             StatusCode::InternalServerError)
+        }
+        ErrorKind::SdkWithdrawn(ref sdk_id, ref reason) => {
+            ("sdk_withdrawn", json!({"sdk_id": sdk_id, "reason": reason}), StatusCode::Gone)
+        }
+        ErrorKind::InvalidOsVersion(ref os_version) => {
+            ("invalid_os_version", json!({"os_version": os_version}), StatusCode::BadRequest)
+        }
+        ErrorKind::AddressBeforeImage(addr, image_addr) => {
+            ("address_before_image", json!({"addr": Addr(addr), "image_addr": Addr(image_addr)}),
+             StatusCode::BadRequest)
+        }
+        ErrorKind::TruncatedMemDb(expected, actual) => {
+            ("truncated_memdb", json!({"expected": expected, "actual": actual}),
+             StatusCode::InternalServerError)
+        }
+        ErrorKind::SdkUpdating(ref sdk_id) => {
+            ("sdk_updating", json!({"sdk_id": sdk_id}), StatusCode::ServiceUnavailable)
+        }
+        ErrorKind::TooManyOpenMemDbs(limit) => {
+            ("too_many_open_memdbs", json!({"limit": limit}), StatusCode::ServiceUnavailable)
+        }
+        ErrorKind::ObjectFetchPending(ref uuid) => {
+            ("object_fetch_pending", json!({"uuid": uuid}), StatusCode::ServiceUnavailable)
+        }
+        ErrorKind::InsufficientSpace(ref path, needed, available) => {
+            ("insufficient_space", json!({"path": path, "needed": needed, "available": available}),
+             StatusCode::InsufficientStorage)
+        }
+        ErrorKind::ConfigError(_) |
+        ErrorKind::MissingConfigKey(_) => {
+            ("config_error", Value::Null, StatusCode::InternalServerError)
+        }
+        ErrorKind::BadConfigKey(key, _) => {
+            ("config_error", json!({"key": key}), StatusCode::InternalServerError)
+        }
+        ErrorKind::BadEnvVar(var, _) => {
+            ("config_error", json!({"env_var": var}), StatusCode::InternalServerError)
+        }
+        ErrorKind::S3Unavailable(_) |
+        ErrorKind::S3ClockSkew |
+        ErrorKind::S3IncompleteListing(_) => {
+            ("s3_unavailable", Value::Null, StatusCode::ServiceUnavailable)
+        }
+        ErrorKind::PrimaryUnavailable(_) => {
+            ("primary_unavailable", Value::Null, StatusCode::ServiceUnavailable)
+        }
+        ErrorKind::QuotaExceeded(_) => {
+            ("quota_exceeded", Value::Null, StatusCode::ServiceUnavailable)
+        }
+        ErrorKind::DownloadInterrupted(ref filename, received) => {
+            ("download_interrupted", json!({"filename": filename, "received": received}),
+             StatusCode::ServiceUnavailable)
+        }
+        ErrorKind::StashLocked(_) => {
+            ("stash_locked", Value::Null, StatusCode::Conflict)
+        }
+        ErrorKind::ReadOnlyStash => {
+            ("read_only_stash", Value::Null, StatusCode::Conflict)
+        }
+        _ => {
+            ("internal_server_error", Value::Null, StatusCode::InternalServerError)
+        }
+    };
+    let message = if status == StatusCode::InternalServerError {
+        format!("The server failed with an internal error: {}", err)
+    } else {
+        err.to_string()
+    };
+    (ApiErrorDescription::new(code, message, details), status)
+}
+
+/// Returns the stable error code clients see for an error.
+pub fn get_error_code(err: &Error) -> &'static str {
+    match *err.kind() {
+        ErrorKind::ApiError(ref api_error) => api_error.describe().code,
+        _ => describe_error(err).0.code,
+    }
+}
+
+/// Returns the HTTP status an error is answered with.
+pub fn get_error_status(err: &Error) -> StatusCode {
+    match *err.kind() {
+        ErrorKind::ApiError(ref api_error) => api_error.get_status(),
+        _ => describe_error(err).1,
+    }
+}
+
+impl ResponseFormat {
+    /// Picks the format the `Accept` header of a request prefers.
+    ///
+    /// MessagePack is only sent if the client asks for it at least as
+    /// strongly as for JSON, everything else gets JSON.
+    pub fn from_request(req: &Request) -> ResponseFormat {
+        let accept = match req.headers.get::<Accept>() {
+            Some(accept) => accept,
+            None => return ResponseFormat::Json,
+        };
+        let mut msgpack_quality = None;
+        let mut json_quality = None;
+        for item in accept.iter() {
+            let quality = Some(item.quality);
+            match &format!("{}/{}", item.item.0, item.item.1)[..] {
+                "application/msgpack" | "application/x-msgpack" => {
+                    msgpack_quality = msgpack_quality.max(quality);
+                }
+                "application/json" | "application/*" | "*/*" => {
+                    json_quality = json_quality.max(quality);
+                }
+                _ => {}
+            }
+        }
+        match msgpack_quality {
+            Some(q) if q.0 > 0 && Some(q) >= json_quality => ResponseFormat::MsgPack,
+            _ => ResponseFormat::Json,
+        }
+    }
 }
 
 impl ApiResponse {
     /// Creates a new API response.
     pub fn new<S: Serialize>(data: S, status: StatusCode) -> Result<ApiResponse> {
-        let mut body = serde_json::to_vec(&data)
-            .chain_err(|| "Failed to serialize response for client")?;
-        body.push(b'\n');
+        ApiResponse::new_with_format(data, status, ResponseFormat::Json)
+    }
+
+    /// Creates a new API response in the given format.
+    pub fn new_with_format<S: Serialize>(data: S, status: StatusCode, format: ResponseFormat)
+        -> Result<ApiResponse>
+    {
+        let _span = spans::span("serialize");
+        let (body, content_type) = match format {
+            ResponseFormat::Json => {
+                let mut body = serde_json::to_vec(&data)
+                    .chain_err(|| "Failed to serialize response for client")?;
+                body.push(b'\n');
+                (body, ContentType::json())
+            }
+            ResponseFormat::MsgPack => {
+                (msgpack::to_vec(&data)
+                    .chain_err(|| "Failed to serialize response for client")?,
+                 ContentType("application/msgpack".parse().unwrap()))
+            }
+        };
         Ok(ApiResponse {
-            body: body,
+            body: ResponseBody::Data(body),
             status: status,
+            content_type,
+            headers: vec![],
         })
     }
 
+    /// Creates a response that sends data as it is.
+    pub fn from_data(data: Vec<u8>, status: StatusCode, content_type: ContentType)
+        -> ApiResponse
+    {
+        ApiResponse {
+            body: ResponseBody::Data(data),
+            status,
+            content_type,
+            headers: vec![],
+        }
+    }
+
+    /// Creates a response that sends `len` bytes of a file starting at
+    /// `offset`.
+    pub fn from_file(file: fs::File, offset: u64, len: u64, status: StatusCode,
+                     content_type: ContentType) -> ApiResponse {
+        ApiResponse {
+            body: ResponseBody::File { file, offset, len },
+            status,
+            content_type,
+            headers: vec![],
+        }
+    }
+
+    /// Creates a response without a body.
+    pub fn empty(status: StatusCode) -> ApiResponse {
+        ApiResponse {
+            body: ResponseBody::Empty,
+            status,
+            content_type: ContentType::json(),
+            headers: vec![],
+        }
+    }
+
+    /// The body of the response if it is held in memory.
+    pub fn data(&self) -> Option<&[u8]> {
+        match self.body {
+            ResponseBody::Data(ref body) => Some(body),
+            _ => None,
+        }
+    }
+
+    /// The HTTP status of the response.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Adds an extra header to the response.
+    pub fn with_header(mut self, name: &'static str, value: String) -> ApiResponse {
+        self.headers.push((name, value));
+        self
+    }
+
     /// Creates an API response from a given error.
     pub fn from_error(err: Error) -> Result<ApiResponse> {
         if_chain! {
@@ -57,26 +312,49 @@ impl ApiResponse {
             }
         }
 
-        error!("Internal Server Error: {}", &err);
-        if let Some(backtrace) = err.backtrace() {
-            debug!("  Traceback: {:?}", backtrace);
+        let (description, status) = describe_error(&err);
+        if status == StatusCode::InternalServerError {
+            error!("Internal Server Error: {}", &err);
+            if let Some(backtrace) = err.backtrace() {
+                debug!("  Traceback: {:?}", backtrace);
+            }
         }
 
-        ApiResponse::new(ApiErrorDescription {
-            ty: "internal_server_error".into(),
-            message: format!("The server failed with an internal error: {}",
-                &err),
-        }, StatusCode::InternalServerError)
+        ApiResponse::new(description, status)
     }
 
     /// Writes the API response into a hyper response.
     pub fn write_to_response(&self, is_head: bool, mut resp: Response) -> Result<()> {
         *resp.status_mut() = self.status;
         resp.headers_mut().set(Server(format!("sentry-symbolserver/{}", VERSION)));
-        resp.headers_mut().set(ContentLength(self.body.len() as u64));
-        resp.headers_mut().set(ContentType::json());
-        if !is_head {
-            resp.send(&self.body[..])?;
+        for &(name, ref value) in &self.headers {
+            resp.headers_mut().set_raw(name, vec![value.clone().into_bytes()]);
+        }
+        match self.body {
+            ResponseBody::Data(ref body) => {
+                resp.headers_mut().set(ContentLength(body.len() as u64));
+                resp.headers_mut().set(self.content_type.clone());
+                if !is_head {
+                    resp.send(&body[..])?;
+                }
+            }
+            ResponseBody::File { ref file, offset, len } => {
+                resp.headers_mut().set(ContentLength(len));
+                resp.headers_mut().set(self.content_type.clone());
+                if !is_head {
+                    let mut file = file.try_clone()?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    let mut stream = resp.start()?;
+                    io::copy(&mut file.take(len), &mut stream)?;
+                    stream.end()?;
+                }
+            }
+            ResponseBody::Empty => {
+                resp.headers_mut().set(ContentLength(0));
+                if !is_head {
+                    resp.send(b"")?;
+                }
+            }
         }
         Ok(())
     }
@@ -92,56 +370,52 @@ impl ApiError {
             ApiError::PayloadTooLarge => StatusCode::PayloadTooLarge,
             ApiError::BadJson(_) => StatusCode::BadRequest,
             ApiError::SdkNotFound => StatusCode::NotFound,
-            ApiError::InternalServerError(_) => StatusCode::InternalServerError,
+            ApiError::Unauthorized => StatusCode::Unauthorized,
+            ApiError::SdkForbidden => StatusCode::Forbidden,
+            ApiError::AdminForbidden => StatusCode::Forbidden,
+            ApiError::InternalServerError(ref err) => describe_error(err).1,
         }
     }
 
     fn describe(&self) -> ApiErrorDescription {
         match *self {
-            ApiError::NotFound => {
-                ApiErrorDescription {
-                    ty: "not_found".into(),
-                    message: "The requested resource was not found".into(),
-                }
-            }
-            ApiError::BadRequest => {
-                ApiErrorDescription {
-                    ty: "bad_request".into(),
-                    message: "The client sent a bad request".into(),
-                }
-            }
-            ApiError::MethodNotAllowed => {
-                ApiErrorDescription {
-                    ty: "method_not_allowed".into(),
-                    message: "This HTTP method is not supported here".into(),
-                }
-            }
-            ApiError::PayloadTooLarge => {
-                ApiErrorDescription {
-                    ty: "payload_too_large".into(),
-                    message: "The request payload is too large".into(),
-                }
-            }
-            ApiError::BadJson(ref json_err) => {
-                ApiErrorDescription {
-                    ty: "bad_json".into(),
-                    message: format!("The client sent bad json: {}", json_err),
-                }
-            }
-            ApiError::SdkNotFound => {
-                ApiErrorDescription {
-                    ty: "sdk_not_found".into(),
-                    message: "The requested SDK was not found".into(),
-                }
-            }
-            ApiError::InternalServerError(ref err) => {
-                ApiErrorDescription {
-                    ty: "internal_server_error".into(),
-                    message: format!(
-                        "The server failed with an internal error: {}",
-                        err),
-                }
-            }
+            ApiError::NotFound => ApiErrorDescription::new(
+                "not_found",
+                "The requested resource was not found".into(),
+                Value::Null),
+            ApiError::BadRequest => ApiErrorDescription::new(
+                "bad_request",
+                "The client sent a bad request".into(),
+                Value::Null),
+            ApiError::MethodNotAllowed => ApiErrorDescription::new(
+                "method_not_allowed",
+                "This HTTP method is not supported here".into(),
+                Value::Null),
+            ApiError::PayloadTooLarge => ApiErrorDescription::new(
+                "payload_too_large",
+                "The request payload is too large".into(),
+                Value::Null),
+            ApiError::BadJson(ref json_err) => ApiErrorDescription::new(
+                "bad_json",
+                format!("The client sent bad json: {}", json_err),
+                json!({"line": json_err.line(), "column": json_err.column()})),
+            ApiError::SdkNotFound => ApiErrorDescription::new(
+                "sdk_not_found",
+                "The requested SDK was not found".into(),
+                Value::Null),
+            ApiError::Unauthorized => ApiErrorDescription::new(
+                "unauthorized",
+                "The request needs a valid API key".into(),
+                Value::Null),
+            ApiError::SdkForbidden => ApiErrorDescription::new(
+                "sdk_forbidden",
+                "The API key may not look symbols up in the requested SDK".into(),
+                Value::Null),
+            ApiError::AdminForbidden => ApiErrorDescription::new(
+                "admin_forbidden",
+                "The API key may not use the admin endpoints".into(),
+                Value::Null),
+            ApiError::InternalServerError(ref err) => describe_error(err).0,
         }
     }
 
@@ -162,3 +436,78 @@ impl fmt::Display for ApiError {
         write!(f, "API error")
     }
 }
+
+#[test]
+fn test_error_codes_and_statuses() {
+    use std::io;
+
+    let cases: Vec<(Error, &str, StatusCode)> = vec![
+        (ErrorKind::UnknownSdk.into(), "unknown_sdk", StatusCode::NotFound),
+        (ErrorKind::UnknownArchitecture("x".into()).into(),
+         "unknown_architecture", StatusCode::BadRequest),
+        (ErrorKind::MissingArchitecture("arm64".into()).into(),
+         "missing_architecture", StatusCode::NotFound),
+        (ErrorKind::UnsupportedMemDbVersion.into(),
+         "unsupported_memdb_version", StatusCode::InternalServerError),
+        (ErrorKind::BadMemDb.into(), "bad_memdb", StatusCode::InternalServerError),
+        (ErrorKind::ChecksumMismatch("a".into(), "b".into()).into(),
+         "checksum_mismatch", StatusCode::InternalServerError),
+        (ErrorKind::SdkWithdrawn("iOS_10.2.0_14C92".into(), "broken".into()).into(),
+         "sdk_withdrawn", StatusCode::Gone),
+        (ErrorKind::InvalidOsVersion("x".into()).into(),
+         "invalid_os_version", StatusCode::BadRequest),
+        (ErrorKind::AddressBeforeImage(1, 2).into(),
+         "address_before_image", StatusCode::BadRequest),
+        (ErrorKind::TruncatedMemDb(2, 1).into(),
+         "truncated_memdb", StatusCode::InternalServerError),
+        (ErrorKind::SdkUpdating("iOS_10.2.0_14C92".into()).into(),
+         "sdk_updating", StatusCode::ServiceUnavailable),
+        (ErrorKind::TooManyOpenMemDbs(1).into(),
+         "too_many_open_memdbs", StatusCode::ServiceUnavailable),
+        (ErrorKind::ObjectFetchPending("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".into()).into(),
+         "object_fetch_pending", StatusCode::ServiceUnavailable),
+        (ErrorKind::InsufficientSpace("/tmp".into(), 2, 1).into(),
+         "insufficient_space", StatusCode::InsufficientStorage),
+        (ErrorKind::MissingConfigKey("symbol_dir").into(),
+         "config_error", StatusCode::InternalServerError),
+        (ErrorKind::BadConfigKey("symbol_dir", "bad").into(),
+         "config_error", StatusCode::InternalServerError),
+        (ErrorKind::BadEnvVar("SYMBOLSERVER_SYMBOL_DIR", "bad").into(),
+         "config_error", StatusCode::InternalServerError),
+        (ErrorKind::S3Unavailable("down".into()).into(),
+         "s3_unavailable", StatusCode::ServiceUnavailable),
+        (ErrorKind::S3ClockSkew.into(), "s3_unavailable", StatusCode::ServiceUnavailable),
+        (ErrorKind::S3IncompleteListing("short".into()).into(),
+         "s3_unavailable", StatusCode::ServiceUnavailable),
+        (ErrorKind::PrimaryUnavailable("down".into()).into(),
+         "primary_unavailable", StatusCode::ServiceUnavailable),
+        (ErrorKind::QuotaExceeded("slow down".into()).into(),
+         "quota_exceeded", StatusCode::ServiceUnavailable),
+        (ErrorKind::DownloadInterrupted("x.memdbz".into(), 1).into(),
+         "download_interrupted", StatusCode::ServiceUnavailable),
+        (ErrorKind::StashLocked("/tmp".into()).into(), "stash_locked", StatusCode::Conflict),
+        (ErrorKind::ReadOnlyStash.into(), "read_only_stash", StatusCode::Conflict),
+        (io::Error::new(io::ErrorKind::Other, "oops").into(),
+         "internal_server_error", StatusCode::InternalServerError),
+        (ApiError::NotFound.into(), "not_found", StatusCode::NotFound),
+        (ApiError::BadRequest.into(), "bad_request", StatusCode::BadRequest),
+        (ApiError::MethodNotAllowed.into(), "method_not_allowed", StatusCode::MethodNotAllowed),
+        (ApiError::PayloadTooLarge.into(), "payload_too_large", StatusCode::PayloadTooLarge),
+        (ApiError::BadJson(Box::new(serde_json::from_str::<Value>("{").unwrap_err())).into(),
+         "bad_json", StatusCode::BadRequest),
+        (ApiError::SdkNotFound.into(), "sdk_not_found", StatusCode::NotFound),
+        (ApiError::Unauthorized.into(), "unauthorized", StatusCode::Unauthorized),
+        (ApiError::SdkForbidden.into(), "sdk_forbidden", StatusCode::Forbidden),
+        (ApiError::AdminForbidden.into(), "admin_forbidden", StatusCode::Forbidden),
+        (ApiError::InternalServerError(Box::new(ErrorKind::UnknownSdk.into())).into(),
+         "unknown_sdk", StatusCode::NotFound),
+    ];
+
+    for (err, code, status) in cases {
+        assert_eq!(get_error_code(&err), code);
+        let resp = ApiResponse::from_error(err).unwrap();
+        assert_eq!(resp.status(), status, "status of {}", code);
+        let body: Value = serde_json::from_slice(resp.data().unwrap()).unwrap();
+        assert_eq!(body["code"], Value::String(code.into()));
+    }
+}
@@ -1,30 +1,129 @@
 //! This exposes the command line interface that the binary uses
+use std::borrow::Cow;
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
 use std::env;
 use std::process;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
-use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
 use clap::{App, Arg, SubCommand, ArgMatches, AppSettings};
 use chrono;
+use hyper::client::Client as HyperClient;
+use hyper::header::{Authorization, Bearer, Headers};
+use hyper::status::StatusCode;
 use log;
 use mime::Mime;
 use multipart::client::lazy::Multipart;
 use openssl_probe::init_ssl_cert_env_vars;
-use tempdir::TempDir;
-use console::style;
-use indicatif::HumanDuration;
+use serde_json;
+use uuid::Uuid;
+use console::{style, Term};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar};
 
-use super::{Result, ResultExt, Error};
-use super::sdk::{Sdk, SdkInfo, DumpOptions};
+use super::{Result, ResultExt, Error, ErrorKind};
+use super::sdk::{DeviceFamily, Sdk, SdkInfo, DumpOptions};
+use super::dsym::SymbolSource;
+use super::sandbox::Sandbox;
+use super::download::{parse_sdk_url, DownloadedSdk};
+use super::memdb::write::convert_sandboxed_object;
 use super::config::Config;
-use super::constants::VERSION;
-use super::memdb::stash::{MemDbStash, SyncOptions};
+use super::constants::{get_build_description, VERSION};
+use super::memdb::usage::UsageStats;
+use super::memdb::stash::{create_temp_dir, MemDbStash, RemoteSdk, SdkDifferenceKind,
+                          SyncOptions, SyncStatus, VerifyOptions};
+use super::report::{Reporter, SdkStatus};
+use super::api::replay::{replay, ReplayOptions};
 use super::api::server::{ApiServer, BindOptions};
-use super::utils::ProgressReader;
-use super::s3::new_hyper_client;
+use super::utils::{install_shutdown_handler, shutdown_requested, uninstall_shutdown_handler,
+                   parse_duration, ProgressReader};
+use super::s3::{new_hyper_client, S3Server};
+use super::manifest::{publish_bucket_manifest, publish_dir_manifest};
+use super::reconvert::{reconvert, ReconvertOptions};
+
+/// Reports the status of every SDK as a porcelain line on stdout and
+/// nothing else.
+struct PorcelainReporter;
+
+impl Reporter for PorcelainReporter {
+    fn sdk_status(&self, status: SdkStatus, info: &SdkInfo) {
+        println!("{}", porcelain_line(&["sdk", porcelain_status(status), &info.sdk_id()]));
+    }
+
+    fn step(&self, _step: usize, _steps: usize, _msg: &str) {}
+
+    fn detail(&self, _msg: &str) {}
+}
+
+/// The porcelain name of an SDK status, which must not change even if the
+/// human readable one does.
+fn porcelain_status(status: SdkStatus) -> &'static str {
+    match status {
+        SdkStatus::Updating => "updating",
+        SdkStatus::Unchanged => "unchanged",
+        SdkStatus::Ignored => "ignored",
+        SdkStatus::Deleting => "deleting",
+    }
+}
+
+/// Joins the fields of a line of `--porcelain` output.
+///
+/// Tabs and line breaks in a field are replaced with spaces and empty
+/// fields are written as `-` so that every line splits into the same
+/// number of fields.
+fn porcelain_line(fields: &[&str]) -> String {
+    fields.iter().map(|field| {
+        if field.is_empty() {
+            "-".to_string()
+        } else {
+            field.replace(|c| c == '\t' || c == '\n' || c == '\r', " ")
+        }
+    }).collect::<Vec<_>>().join("\t")
+}
+
+/// Reports progress to the terminal.
+struct ConsoleReporter {
+    term: Term,
+}
+
+impl ConsoleReporter {
+    fn new() -> ConsoleReporter {
+        ConsoleReporter { term: Term::stdout() }
+    }
+
+    /// Reports to stderr, for when the result goes to stdout.
+    fn stderr() -> ConsoleReporter {
+        ConsoleReporter { term: Term::stderr() }
+    }
+}
+
+impl Reporter for ConsoleReporter {
+    fn sdk_status(&self, status: SdkStatus, info: &SdkInfo) {
+        let line = match status {
+            SdkStatus::Updating => format!("{} {}", style(status).green(), info),
+            SdkStatus::Unchanged => format!("{} {}", style(status).cyan(), info),
+            SdkStatus::Ignored => format!("{} {} by config", style(status).yellow(), info),
+            SdkStatus::Deleting => format!("{} {}", style(status).red(), info),
+        };
+        self.term.write_line(&line).ok();
+    }
+
+    fn step(&self, step: usize, steps: usize, msg: &str) {
+        self.term.write_line(&format!("{} {}", style(format!("[{}/{}]", step, steps)).dim(),
+                                      msg)).ok();
+    }
+
+    fn detail(&self, msg: &str) {
+        self.term.write_line(&format!("      {}", msg)).ok();
+    }
+
+    fn progress_bar(&self, len: u64) -> ProgressBar {
+        ProgressBar::new(len)
+    }
+}
 
 struct SimpleLogger<W: ?Sized> {
     f: Mutex<Box<W>>,
@@ -56,7 +155,7 @@ fn setup_openssl() {
     init_ssl_cert_env_vars();
 }
 
-fn setup_logging(config: &Config) -> Result<()> {
+fn setup_logging(config: &Config, to_stderr: bool) -> Result<()> {
     let filter = config.get_log_level_filter()?;
     if filter >= log::LogLevel::Debug {
         env::set_var("RUST_BACKTRACE", "1");
@@ -64,6 +163,7 @@ fn setup_logging(config: &Config) -> Result<()> {
 
     let f : Box<io::Write + Send> = match config.get_log_filename()? {
         Some(path) => Box::new(fs::File::open(path)?),
+        None if to_stderr => Box::new(io::stderr()),
         None => Box::new(io::stdout()),
     };
     log::set_logger(|max_log_level| {
@@ -81,10 +181,10 @@ pub fn main() {
         Ok(()) => {},
         Err(err) => {
             use std::error::Error;
-            println!("error: {}", err);
+            eprintln!("error: {}", err);
             let mut cause = err.cause();
             while let Some(the_cause) = cause {
-                println!("  caused by: {}", the_cause);
+                eprintln!("  caused by: {}", the_cause);
                 cause = the_cause.cause();
             }
             if let Some(backtrace) = err.backtrace() {
@@ -96,7 +196,11 @@ pub fn main() {
 }
 
 fn config_from_matches(matches: &ArgMatches) -> Result<Config> {
-    let mut cfg = if let Some(config_path) = matches.value_of("config") {
+    let mut cfg = if let Some(value) = matches.value_of("config_json") {
+        Config::load_str(value)?
+    } else if matches.value_of("config") == Some("-") {
+        Config::load_reader(io::stdin())?
+    } else if let Some(config_path) = matches.value_of("config") {
         Config::load_file(config_path)?
     } else {
         Config::load_default()?
@@ -116,15 +220,35 @@ fn config_from_matches(matches: &ArgMatches) -> Result<Config> {
         cfg.set_aws_bucket_url(value);
     }
 
+    if let Some(value) = matches.value_of("primary") {
+        cfg.set_sync_primary(value);
+    }
+
     if let Some(value) = matches.value_of("aws_region") {
         let region = value.parse()
             .map_err(|_| Error::from("Invalid AWS region"))?;
         cfg.set_aws_region(region);
     }
 
+    if let Some(name) = matches.value_of("tenant") {
+        cfg = cfg.get_tenant_config(name)?;
+    }
+
     Ok(cfg)
 }
 
+/// Returns the config file the server reloads on SIGHUP, if there is one.
+///
+/// A config read from stdin or passed inline cannot be read again.
+fn get_config_file(matches: &ArgMatches) -> Option<PathBuf> {
+    match matches.value_of("config") {
+        Some("-") => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None if matches.is_present("config_json") => None,
+        None => Config::get_default_path(),
+    }
+}
+
 fn get_default_sdks() -> Result<Vec<PathBuf>> {
     let mut rv = vec![];
     let path = dirs::home_dir().ok_or_else(|| {
@@ -147,9 +271,24 @@ fn get_default_sdks() -> Result<Vec<PathBuf>> {
     Ok(rv)
 }
 
+/// Checks for `--version --verbose`.
+///
+/// clap prints the version as soon as it sees `--version`, so the flags
+/// are looked for before it parses the arguments.
+fn wants_verbose_version() -> bool {
+    let args: Vec<_> = env::args().skip(1).collect();
+    args.iter().any(|x| x == "--version" || x == "-V") &&
+        args.iter().any(|x| x == "--verbose")
+}
+
 fn execute() -> Result<()> {
     setup_openssl();
 
+    if wants_verbose_version() {
+        println!("sentry-symbolserver {}", get_build_description());
+        return Ok(());
+    }
+
     let app = App::new("sentry-symbolserver")
         .version(VERSION)
         .about("This tool implements an Apple SDK processor and server.")
@@ -158,7 +297,12 @@ fn execute() -> Result<()> {
         .arg(Arg::with_name("config")
              .long("config")
              .value_name("FILE")
-             .help("The path to the config file"))
+             .help("The path to the config file (- reads it from stdin)"))
+        .arg(Arg::with_name("config_json")
+             .long("config-json")
+             .value_name("JSON")
+             .conflicts_with("config")
+             .help("The config as inline JSON instead of a file"))
         .arg(Arg::with_name("log_level")
              .short("l")
              .long("log-level")
@@ -177,9 +321,205 @@ fn execute() -> Result<()> {
              .long("aws-region")
              .value_name("REGION")
              .help("Sets the AWS region the bucket is located in"))
+        .arg(Arg::with_name("primary")
+             .long("primary")
+             .value_name("URL")
+             .help("Follows the stash of the server at this URL instead of syncing from S3"))
+        .arg(Arg::with_name("tenant")
+             .long("tenant")
+             .value_name("NAME")
+             .help("Works on the stash of a tenant instead of the main stash"))
+        .arg(Arg::with_name("porcelain")
+             .long("porcelain")
+             .help("Prints tab separated lines that stay the same across versions \
+                    instead of the human readable output (sync, sync-status and \
+                    list-sdks)"))
         .subcommand(
             SubCommand::with_name("sync")
-                .about("Updates symbols from S3"))
+                .about("Updates symbols from S3")
+                .arg(Arg::with_name("max_duration")
+                     .long("max-duration")
+                     .value_name("DURATION")
+                     .help("Stops before downloads that would not finish within \
+                            this time (eg: 30m, 2h).  Run sync again to continue.")))
+        .subcommand(
+            SubCommand::with_name("publish-manifest")
+                .about("Writes the manifest of the SDKs in the bucket")
+                .after_help("The manifest is uploaded next to the SDKs unless a folder \
+                             is given, in which case the compressed memdbs in that \
+                             folder are listed and the manifest is written into it.")
+                .arg(Arg::with_name("path")
+                     .index(1)
+                     .value_name("PATH")
+                     .help("A local folder to write the manifest for instead"))
+                .arg(Arg::with_name("key")
+                     .long("key")
+                     .value_name("KEY")
+                     .help("The name of the manifest (defaults to sync.manifest \
+                            or index.json)")))
+        .subcommand(
+            SubCommand::with_name("reconvert")
+                .about("Converts the SDKs in the bucket that older converters wrote again")
+                .after_help("Memdbs that do not record their converter count as outdated. \
+                             Several workers can run at the same time, they claim SDKs \
+                             in the manifest before converting them.  The manifest is \
+                             published again after every converted SDK.")
+                .arg(Arg::with_name("min_converter_version")
+                     .long("min-converter-version")
+                     .value_name("VERSION")
+                     .required(true)
+                     .help("Converts memdbs written by converters older than this"))
+                .arg(Arg::with_name("inputs")
+                     .long("inputs")
+                     .value_name("PATH")
+                     .required(true)
+                     .help("The folder with the archived support folders \
+                            (like `iOS DeviceSupport/10.2 (14C92)`)"))
+                .arg(Arg::with_name("manifest")
+                     .long("manifest")
+                     .value_name("KEY")
+                     .help("The manifest to coordinate through (defaults to \
+                            sync.manifest or index.json)"))
+                .arg(Arg::with_name("worker")
+                     .long("worker")
+                     .value_name("NAME")
+                     .help("The name of this worker in claims (defaults to the \
+                            host name and process ID)"))
+                .arg(Arg::with_name("claim_timeout")
+                     .long("claim-timeout")
+                     .value_name("DURATION")
+                     .help("How long until claims of other workers expire (defaults to 2h)"))
+                .arg(Arg::with_name("symbol_sources")
+                     .long("symbol-sources")
+                     .value_name("SOURCES")
+                     .help("The sources of symbols like for convert-sdk"))
+                .arg(Arg::with_name("profile")
+                     .long("profile")
+                     .value_name("PATH")
+                     .help("Usage counters to lay out the memdbs by like for convert-sdk"))
+                .arg(Arg::with_name("dry_run")
+                     .long("dry-run")
+                     .help("Only lists the outdated SDKs")))
+        .subcommand(
+            SubCommand::with_name("sync-status")
+                .about("Prints the sync status as JSON")
+                .after_help("Exits with 0 if the stash is healthy, 2 if it is unhealthy \
+                             and 1 if the status could not be determined."))
+        .subcommand(
+            SubCommand::with_name("list-sdks")
+                .about("Prints how the memdbs of the local SDKs were converted as JSON")
+                .after_help("Lists the converter version, memdb format, SDK fingerprint \
+                             and conversion options of every SDK so that memdbs from a \
+                             faulty converter can be found and converted again."))
+        .subcommand(
+            SubCommand::with_name("export-usage")
+                .about("Exports the usage counters of the stash or a server")
+                .after_help("The export can be imported into a new server with \
+                             import-usage so that it warms up what production uses.")
+                .arg(Arg::with_name("from")
+                     .long("from")
+                     .value_name("URL")
+                     .help("Exports the counters of the server at this URL instead"))
+                .arg(Arg::with_name("token")
+                     .long("token")
+                     .value_name("KEY")
+                     .help("The admin API key or token to send to the server"))
+                .arg(Arg::with_name("top")
+                     .long("top")
+                     .value_name("COUNT")
+                     .help("Only exports the COUNT most used SDKs"))
+                .arg(Arg::with_name("output_path")
+                     .short("o")
+                     .long("output")
+                     .value_name("FILE")
+                     .help("Where to write the export (defaults to stdout)")))
+        .subcommand(
+            SubCommand::with_name("import-usage")
+                .about("Adds exported usage counters to the stash")
+                .after_help("Import before starting the server, a running server \
+                             overwrites the counters with its own.")
+                .arg(Arg::with_name("path")
+                     .index(1)
+                     .value_name("FILE")
+                     .required(true)
+                     .help("The export to import")))
+        .subcommand(
+            SubCommand::with_name("compare")
+                .about("Lists the SDKs that differ between the stash and upstream")
+                .after_help("SDKs ignored by the config are left out."))
+        .subcommand(
+            SubCommand::with_name("healthcheck")
+                .about("Checks whether the local server is healthy")
+                .after_help("Exits with 0 if the server answers its health endpoint \
+                             with 200 and with 1 otherwise, which makes it usable as \
+                             HEALTHCHECK of a container without curl.")
+                .arg(Arg::with_name("url")
+                     .long("url")
+                     .value_name("URL")
+                     .help("The health endpoint to check (defaults to /health on \
+                            the configured address)"))
+                .arg(Arg::with_name("timeout")
+                     .long("timeout")
+                     .value_name("SECS")
+                     .help("How long to wait for the server (defaults to 5)"))
+                .arg(Arg::with_name("no_http")
+                     .long("no-http")
+                     .conflicts_with("url")
+                     .help("Reads the sync status of the stash directly instead")))
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Sends recorded lookups to a server")
+                .after_help("Replays a file written by a server with server.record_path \
+                             set, keeping the spacing of the requests, and reports the \
+                             status codes and latencies of the answers.")
+                .arg(Arg::with_name("path")
+                     .index(1)
+                     .value_name("PATH")
+                     .required(true)
+                     .help("The replay file"))
+                .arg(Arg::with_name("url")
+                     .long("url")
+                     .value_name("URL")
+                     .help("The server to send the lookups to (defaults to the \
+                            configured address)"))
+                .arg(Arg::with_name("speed")
+                     .long("speed")
+                     .value_name("FACTOR")
+                     .help("How much faster than recorded to send the lookups, 0 sends \
+                            them as fast as possible (defaults to 1)"))
+                .arg(Arg::with_name("concurrency")
+                     .long("concurrency")
+                     .short("j")
+                     .value_name("COUNT")
+                     .help("How many lookups are in flight at most (defaults to 8)"))
+                .arg(Arg::with_name("api_key")
+                     .long("api-key")
+                     .value_name("KEY")
+                     .help("The API key to send with the lookups")))
+        .subcommand(
+            SubCommand::with_name("top")
+                .about("Shows live traffic and sync state of a running server")
+                .after_help("Polls /metrics and /health and redraws request rates, the \
+                             most requested SDKs, the memdb cache hit rate and the sync \
+                             state in place until interrupted.")
+                .arg(Arg::with_name("url")
+                     .long("url")
+                     .value_name("URL")
+                     .help("The server to watch (defaults to the configured address)"))
+                .arg(Arg::with_name("interval")
+                     .long("interval")
+                     .short("n")
+                     .value_name("SECS")
+                     .help("How often to refresh (defaults to 2)")))
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Verifies the checksums and structure of the local memdbs")
+                .arg(Arg::with_name("jobs")
+                     .long("jobs")
+                     .short("j")
+                     .value_name("COUNT")
+                     .help("The number of memdbs to verify in parallel \
+                            (defaults to server.worker_threads)")))
         .subcommand(
             SubCommand::with_name("run")
                 .about("Runs the symbol server")
@@ -195,10 +535,23 @@ fn execute() -> Result<()> {
                      .value_name("FD")
                      .help("Bind to a specific file descriptor"))
                 .arg(Arg::with_name("threads")
-                     .long("threads")
+                     .long("listener-threads")
+                     .alias("threads")
                      .short("t")
                      .value_name("COUNT")
-                     .help("Overrides the default listener thread count")))
+                     .help("Overrides the listener thread count"))
+                .arg(Arg::with_name("worker_threads")
+                     .long("worker-threads")
+                     .value_name("COUNT")
+                     .help("Overrides the worker thread count"))
+                .arg(Arg::with_name("io_threads")
+                     .long("io-threads")
+                     .value_name("COUNT")
+                     .help("Overrides the IO thread count"))
+                .arg(Arg::with_name("ready_max_lag")
+                     .long("ready-max-lag")
+                     .value_name("COUNT")
+                     .help("Reports not ready until at most COUNT SDKs are out of sync")))
         .subcommand(
             SubCommand::with_name("convert-sdk")
                 .about("Converts an SDK into a memdb file")
@@ -209,19 +562,100 @@ fn execute() -> Result<()> {
                      .index(1)
                      .value_name("PATH")
                      .multiple(true)
-                     .help("Path to the support folder"))
+                     .help("Path to the support folder, or the URL of a zip of it"))
                 .arg(Arg::with_name("compress")
                      .short("c")
                      .long("compress")
                      .help("Write compressed files instead."))
+                .arg(Arg::with_name("thin")
+                     .long("thin")
+                     .conflicts_with("share_to")
+                     .help("Write the symbols of each object to the shared object \
+                            store in the output folder instead of the memdb"))
+                .arg(Arg::with_name("no_recover_symbols")
+                     .long("no-recover-symbols")
+                     .help("Only use the symbol tables, do not add symbols \
+                            recovered from Objective-C and Swift metadata \
+                            or function starts"))
+                .arg(Arg::with_name("symbol_sources")
+                     .long("symbol-sources")
+                     .value_name("SOURCES")
+                     .conflicts_with("no_recover_symbols")
+                     .help("Comma separated sources of symbols in order of priority \
+                            (symtab, objc, swift, function_starts).  The first \
+                            source that names an address wins."))
+                .arg(Arg::with_name("profile")
+                     .long("profile")
+                     .value_name("PATH")
+                     .help("Usage counters from export-usage.  The objects that \
+                            are looked up most are laid out first."))
+                .arg(Arg::with_name("device_family")
+                     .long("device-family")
+                     .value_name("FAMILY")
+                     .possible_values(&["iphone", "ipad", "ipod", "appletv", "watch"])
+                     .help("Record the device family of the SDK (derived from \
+                            the folder name if it contains a device model)"))
+                .arg(Arg::with_name("product_type")
+                     .long("product-type")
+                     .value_name("TYPE")
+                     .help("Record the product type of the device the SDK was \
+                            copied from, for instance iPhone12,1 (read from the \
+                            Info.plist of the support folder if it has one)"))
                 .arg(Arg::with_name("share_to")
                      .hidden(true)
                      .long("share-to")
                      .value_name("URL"))
+                .arg(Arg::with_name("sandbox")
+                     .long("sandbox")
+                     .help("Parse every object in a child process of its own so \
+                            that malformed objects cannot crash the conversion"))
+                .arg(Arg::with_name("seccomp")
+                     .long("seccomp")
+                     .help("Like --sandbox and restrict the child processes \
+                            with seccomp (Linux only)"))
+                .arg(Arg::with_name("sandbox_timeout")
+                     .long("sandbox-timeout")
+                     .value_name("SECS")
+                     .help("How long a child process may take for one object \
+                            (defaults to 300)"))
+                .arg(Arg::with_name("max_size_mb")
+                     .long("max-size-mb")
+                     .value_name("MB")
+                     .conflicts_with("thin")
+                     .help("Trim the memdb until it is not larger than this \
+                            (uncompressed)"))
+                .arg(Arg::with_name("cold_objects")
+                     .long("cold-objects")
+                     .value_name("PATTERNS")
+                     .requires("max_size_mb")
+                     .help("Comma separated glob patterns of objects that may be \
+                            dropped to fit --max-size-mb, in the order they are \
+                            dropped in"))
                 .arg(Arg::with_name("output_path")
                      .short("o")
                      .long("output")
-                     .help("Where the result should be stored")))
+                     .value_name("PATH")
+                     .help("Where the result should be stored (- writes the \
+                            memdb to stdout).  Defaults to convert.output_dir \
+                            or the current folder."))
+                .arg(Arg::with_name("filename_template")
+                     .long("filename-template")
+                     .value_name("TEMPLATE")
+                     .help("Name the memdb files like {name}_{version}_{build}.{ext} \
+                            (placeholders: name, version, build, sdk_id, ext)")))
+        .subcommand(
+            SubCommand::with_name("convert-object")
+                .setting(AppSettings::Hidden)
+                .about("Converts the object on stdin for convert-sdk --sandbox")
+                .arg(Arg::with_name("filename")
+                     .long("filename")
+                     .value_name("NAME")
+                     .required(true))
+                .arg(Arg::with_name("symbol_sources")
+                     .long("symbol-sources")
+                     .value_name("SOURCES"))
+                .arg(Arg::with_name("seccomp")
+                     .long("seccomp")))
         .subcommand(
             SubCommand::with_name("dump-object")
                 .about("Dumps an object from a memdb in the stash")
@@ -246,7 +680,12 @@ fn execute() -> Result<()> {
     let matches = app.get_matches();
 
     let cfg = config_from_matches(&matches)?;
-    setup_logging(&cfg)?;
+    let config_file = get_config_file(&matches);
+    let porcelain = matches.is_present("porcelain");
+    // a memdb written to stdout must not be mixed with log output
+    let to_stdout = matches.subcommand_matches("convert-sdk")
+        .and_then(|x| x.value_of("output_path")) == Some("-");
+    setup_logging(&cfg, to_stdout)?;
 
     if let Some(matches) = matches.subcommand_matches("convert-sdk") {
         let paths = if matches.is_present("default_location") {
@@ -256,67 +695,242 @@ fn execute() -> Result<()> {
         } else {
             return Err(Error::from("No paths provided"));
         };
+        let scratch_dir = cfg.get_stash_scratch_dir();
+        if let Some(ref scratch_dir) = scratch_dir {
+            fs::create_dir_all(scratch_dir)?;
+        }
         let tempdir;
         let (share_to, compress, output_path) = match matches.value_of("share_to") {
             Some(value) => {
-                tempdir = TempDir::new("symbolserver")?;
-                (Some(value), true, tempdir.path())
+                tempdir = create_temp_dir(&cfg)?;
+                (Some(value), true, Cow::Borrowed(tempdir.path()))
             }
             None => {
-                (None, matches.is_present("compress"),
-                 Path::new(matches.value_of("output_path").unwrap_or(".")))
+                (None, matches.is_present("compress"), get_output_path(matches, &cfg))
             }
         };
-        convert_sdk_action(paths, output_path, compress, share_to)?;
+        let filename_template = get_filename_template(matches, &cfg)?;
+        let device_family = match matches.value_of("device_family") {
+            Some(value) => Some(value.parse()?),
+            None => None,
+        };
+        let options = DumpOptions {
+            compress,
+            reporter: Arc::new(if to_stdout {
+                ConsoleReporter::stderr()
+            } else {
+                ConsoleReporter::new()
+            }),
+            scratch_dir: scratch_dir.as_ref().map(|x| x.as_ref().to_path_buf()),
+            symbol_sources: get_symbol_sources(matches)?,
+            hot_objects: get_hot_objects(matches)?,
+            sandbox: get_sandbox(matches)?,
+            max_size: match matches.value_of("max_size_mb") {
+                Some(value) => Some(value.parse::<u64>()
+                    .chain_err(|| "Invalid maximum size")? * 1024 * 1024),
+                None => None,
+            },
+            cold_objects: matches.value_of("cold_objects")
+                .map(|x| x.split(',').map(|x| x.trim().to_string()).collect())
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        let output = ConvertOutput {
+            path: &output_path,
+            filename_template: filename_template.as_ref().map(|x| x.as_str()),
+        };
+        convert_sdk_action(paths, &output, share_to, device_family,
+                           matches.value_of("product_type"), options,
+                           matches.is_present("thin"))?;
+    } else if let Some(matches) = matches.subcommand_matches("convert-object") {
+        convert_sandboxed_object(matches.value_of("filename").unwrap(),
+                                 &get_symbol_sources(matches)?,
+                                 matches.is_present("seccomp"))?;
     } else if let Some(matches) = matches.subcommand_matches("dump-object") {
         dump_object_action(&cfg, matches.value_of("sdk_id").unwrap(),
                            matches.value_of("name_or_uuid").unwrap())?;
     } else if let Some(matches) = matches.subcommand_matches("sdk-fuzzy-match") {
         sdk_fuzzy_match_action(&cfg, matches.value_of("sdk_id").unwrap())?;
     } else if let Some(matches) = matches.subcommand_matches("run") {
-        run_action(&cfg, matches)?;
-    } else if let Some(_matches) = matches.subcommand_matches("sync") {
-        sync_action(&cfg)?;
+        run_action(&cfg, matches, config_file)?;
+    } else if let Some(matches) = matches.subcommand_matches("sync") {
+        sync_action(&cfg, matches, porcelain)?;
+    } else if let Some(matches) = matches.subcommand_matches("publish-manifest") {
+        publish_manifest_action(&cfg, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("reconvert") {
+        reconvert_action(&cfg, matches)?;
+    } else if let Some(_matches) = matches.subcommand_matches("sync-status") {
+        sync_status_action(&cfg, porcelain)?;
+    } else if let Some(_matches) = matches.subcommand_matches("list-sdks") {
+        list_sdks_action(&cfg, porcelain)?;
+    } else if let Some(matches) = matches.subcommand_matches("export-usage") {
+        export_usage_action(&cfg, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("import-usage") {
+        import_usage_action(&cfg, matches.value_of("path").unwrap())?;
+    } else if let Some(_matches) = matches.subcommand_matches("compare") {
+        compare_action(&cfg)?;
+    } else if let Some(matches) = matches.subcommand_matches("healthcheck") {
+        healthcheck_action(&cfg, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("replay") {
+        replay_action(&cfg, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("top") {
+        top_action(&cfg, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("verify") {
+        verify_action(&cfg, matches)?;
     }
 
     Ok(())
 }
 
-fn convert_sdk_action(paths: Vec<PathBuf>, output_path: &Path, compress: bool,
-                      share_to: Option<&str>)
+fn get_symbol_sources(matches: &ArgMatches) -> Result<Vec<SymbolSource>> {
+    Ok(if let Some(value) = matches.value_of("symbol_sources") {
+        value.split(',').map(|x| x.trim().parse()).collect::<Result<Vec<_>>>()?
+    } else if matches.is_present("no_recover_symbols") {
+        vec![SymbolSource::SymbolTable]
+    } else {
+        SymbolSource::all()
+    })
+}
+
+fn get_output_path<'a>(matches: &'a ArgMatches, cfg: &'a Config) -> Cow<'a, Path> {
+    match matches.value_of("output_path") {
+        Some(value) => Cow::Borrowed(Path::new(value)),
+        None => cfg.get_convert_output_dir().unwrap_or_else(|| Cow::Borrowed(Path::new("."))),
+    }
+}
+
+fn get_filename_template(matches: &ArgMatches, cfg: &Config) -> Result<Option<String>> {
+    match matches.value_of("filename_template") {
+        Some(value) => {
+            if SdkInfo::new("iOS", 10, 2, 0, None).format_filename(value, false).is_none() {
+                return Err(Error::from("Unknown placeholder in filename template"));
+            }
+            Ok(Some(value.to_string()))
+        }
+        None => cfg.get_convert_filename_template(),
+    }
+}
+
+fn get_hot_objects(matches: &ArgMatches) -> Result<Vec<Uuid>> {
+    Ok(match matches.value_of("profile") {
+        Some(path) => {
+            let stats: UsageStats = serde_json::from_reader(
+                io::BufReader::new(fs::File::open(path)?))
+                .chain_err(|| "Invalid usage stats")?;
+            stats.most_used_objects().into_iter().map(|(_, uuid)| *uuid).collect()
+        }
+        None => vec![],
+    })
+}
+
+fn get_sandbox(matches: &ArgMatches) -> Result<Option<Sandbox>> {
+    if !matches.is_present("sandbox") && !matches.is_present("seccomp") {
+        return Ok(None);
+    }
+    let mut sandbox = Sandbox::new(env::current_exe()?);
+    sandbox.seccomp = matches.is_present("seccomp");
+    if let Some(value) = matches.value_of("sandbox_timeout") {
+        sandbox.timeout = Duration::from_secs(value.parse()
+            .chain_err(|| "Invalid sandbox timeout")?);
+    }
+    Ok(Some(sandbox))
+}
+
+/// Where `convert-sdk` writes memdbs and how it names them
+struct ConvertOutput<'a> {
+    path: &'a Path,
+    filename_template: Option<&'a str>,
+}
+
+fn convert_sdk_action(paths: Vec<PathBuf>, output: &ConvertOutput, share_to: Option<&str>,
+                      device_family: Option<DeviceFamily>, product_type: Option<&str>,
+                      options: DumpOptions, thin: bool)
     -> Result<()>
 {
-    let dst_base = env::current_dir().unwrap().join(output_path);
+    let to_stdout = output.path == Path::new("-");
+    if to_stdout && (thin || paths.len() > 1) {
+        return Err(Error::from("Only a single SDK that is not thin can be written to stdout"));
+    }
+    let term = if to_stdout { Term::stderr() } else { Term::stdout() };
+    let dst_base = env::current_dir().unwrap().join(output.path);
+    let object_store = if thin {
+        Some(dst_base.join("objects"))
+    } else {
+        None
+    };
 
     for (idx, path) in paths.iter().enumerate() {
         if idx > 0 {
-            println!("");
+            term.write_line("")?;
         }
-        let sdk = Sdk::new(&path)?;
-        let mut dst = dst_base.join(sdk.info().memdb_filename());
-        if compress {
-            dst.set_extension("memdbz");
+        let download = match path.to_str().and_then(parse_sdk_url) {
+            Some(url) => {
+                let scratch_dir = options.scratch_dir.clone().unwrap_or_else(env::temp_dir);
+                Some(DownloadedSdk::fetch(&url, &scratch_dir, &*options.reporter)?)
+            }
+            None => None,
+        };
+        let mut sdk = Sdk::new(download.as_ref().map_or(path.as_path(), |x| x.path()))?;
+        if product_type.is_some() {
+            sdk.set_product_type(product_type);
+            if device_family.is_none() {
+                sdk.set_device_family(product_type.and_then(DeviceFamily::from_product_type));
+            }
         }
-
-        println!("Processing {} SDK ({} {})",
-                 style(sdk.info().name()).green(),
-                 style(sdk.info().version()).cyan(),
-                 style(sdk.info().build().unwrap_or("UNKNOWN")).cyan());
+        if device_family.is_some() {
+            sdk.set_device_family(device_family);
+        }
+        term.write_line(&format!("Processing {} SDK ({} {})",
+                                 style(sdk.info().name()).green(),
+                                 style(sdk.info().version()).cyan(),
+                                 style(sdk.info().build().unwrap_or("UNKNOWN")).cyan()))?;
         let started = Instant::now();
 
-        // make sure we close the file at the end, in case we want to
-        // re-open it for compressing.
-        let f = fs::File::create(&dst)?;
-        let options = DumpOptions {
-            compress: compress,
-            ..Default::default()
+        let dst = if to_stdout {
+            // there is no file to resume into when writing to stdout
+            let stdout = io::stdout();
+            sdk.dump_memdb_to_stream(stdout.lock(), options.clone())?;
+            None
+        } else {
+            let dst = match output.filename_template {
+                Some(template) => {
+                    let filename = sdk.info().format_filename(template, options.compress)
+                        .ok_or_else(|| Error::from("Unknown placeholder in filename template"))?;
+                    dst_base.join(filename)
+                }
+                None => {
+                    let mut dst = dst_base.join(sdk.info().memdb_filename());
+                    if options.compress {
+                        dst.set_extension("memdbz");
+                    }
+                    dst
+                }
+            };
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            // make sure we close the file at the end, in case we want to
+            // re-open it for compressing.
+            let f = fs::File::create(&dst)?;
+            let mut journal = dst.clone().into_os_string();
+            journal.push(".journal");
+            let options = DumpOptions {
+                journal: Some(journal.into()),
+                object_store: object_store.clone(),
+                ..options.clone()
+            };
+            sdk.dump_memdb(f, options)?;
+            Some(dst)
         };
-        sdk.dump_memdb(f, options)?;
-        println!("Dumped in {}", HumanDuration(started.elapsed()));
+        term.write_line(&format!("Dumped in {}", HumanDuration(started.elapsed())))?;
 
-        if let Some(url) = share_to {
+        if let (Some(url), Some(dst)) = (share_to, dst) {
             share_sdk(&dst, url, sdk.info())?;
         }
+        if let Some(download) = download {
+            download.remove()?;
+        }
     }
 
     Ok(())
@@ -339,10 +953,11 @@ fn dump_object_action(config: &Config, sdk_id: &str, name_or_uuid: &str) -> Resu
     let info = SdkInfo::from_filename(sdk_id).ok_or_else(||
         Error::from("Invalid SDK ID"))?;
     let memdb = stash.get_memdb(&info)?;
-    let uuid = memdb.find_uuid_fuzzy(name_or_uuid)?.ok_or_else(||
+    let uuid = *memdb.find_uuid_fuzzy(name_or_uuid)?.ok_or_else(||
         Error::from("Object not found in SDK"))?;
+    let memdb = stash.get_object_memdb(memdb, &uuid)?;
 
-    for item_rv in memdb.iter_symbols(uuid)? {
+    for item_rv in memdb.iter_symbols(&uuid)? {
         let item = item_rv?;
         println!("{:>014x} {}", item.addr(), item.symbol());
     }
@@ -351,30 +966,636 @@ fn dump_object_action(config: &Config, sdk_id: &str, name_or_uuid: &str) -> Resu
 
 fn sdk_fuzzy_match_action(config: &Config, sdk_id: &str) -> Result<()> {
     let stash = MemDbStash::new(config)?;
-    let sdk_infos = stash.fuzzy_match_sdk_id(sdk_id)?;
-    for info in sdk_infos {
-        println!("> {}", info.sdk_id());
+    let matches = stash.fuzzy_match_sdk_id_with_scores(sdk_id, &stash.fuzzy_match_options())?;
+    for m in matches {
+        println!("> {} (score: {})", m.info().sdk_id(), m.score());
     }
     Ok(())
 }
 
-fn sync_action(config: &Config) -> Result<()> {
+/// Who is running the command, as recorded in the audit log.
+fn cli_actor() -> String {
+    match env::var("USER") {
+        Ok(user) => format!("cli ({})", user),
+        Err(_) => "cli".into(),
+    }
+}
+
+fn sync_action(config: &Config, matches: &ArgMatches, porcelain: bool) -> Result<()> {
     let stash = MemDbStash::new(config)?;
-    stash.sync(SyncOptions {
-        user_facing: true,
-        ..Default::default()
-    })?;
+    let started = Instant::now();
+    let deadline = match matches.value_of("max_duration") {
+        Some(value) => Some(chrono::Utc::now() + parse_duration(value)
+            .ok_or_else(|| Error::from("invalid value for max duration"))?),
+        None => None,
+    };
+
+    // the first Ctrl-C lets the current SDK finish, the second one kills
+    let cancel = Arc::new(AtomicBool::new(false));
+    install_shutdown_handler()?;
+    {
+        let cancel = cancel.clone();
+        thread::spawn(move || {
+            while !shutdown_requested() {
+                thread::sleep(Duration::from_millis(100));
+            }
+            let msg = style("Finishing the current SDK, press Ctrl-C again to abort").yellow();
+            if porcelain {
+                eprintln!("{}", msg);
+            } else {
+                println!("{}", msg);
+            }
+            cancel.store(true, Ordering::SeqCst);
+            uninstall_shutdown_handler();
+        });
+    }
+
+    let rv = stash.sync(SyncOptions {
+        reporter: if porcelain {
+            Arc::new(PorcelainReporter)
+        } else {
+            Arc::new(ConsoleReporter::new())
+        },
+        actor: cli_actor(),
+        cancel,
+        deadline,
+    });
+    if porcelain {
+        let elapsed = started.elapsed().as_secs().to_string();
+        let (outcome, remaining) = match rv {
+            Err(Error(ErrorKind::SyncCancelled(_, remaining), _)) => {
+                (if shutdown_requested() { "cancelled" } else { "deadline" }, remaining)
+            }
+            Err(err) => return Err(err),
+            Ok(()) => ("done", 0),
+        };
+        println!("{}", porcelain_line(&["result", outcome, &remaining.to_string(), &elapsed]));
+        if outcome == "cancelled" {
+            process::exit(130);
+        }
+        return Ok(());
+    }
+    if let Err(Error(ErrorKind::SyncCancelled(done, remaining), _)) = rv {
+        if shutdown_requested() {
+            println!("Sync cancelled after {} in {}", plural(done, "SDK"),
+                     HumanDuration(started.elapsed()));
+            println!("{} left to download, run sync again to resume",
+                     plural(remaining, "SDK"));
+            process::exit(130);
+        }
+        println!("Sync window over after {} in {}", plural(done, "SDK"),
+                 HumanDuration(started.elapsed()));
+        println!("{} left to download, run sync again to continue", plural(remaining, "SDK"));
+        return Ok(());
+    }
+    rv?;
+    println!("Sync done in {}", HumanDuration(started.elapsed()));
     Ok(())
 }
 
-fn run_action(config: &Config, matches: &ArgMatches) -> Result<()> {
-    let api_server = ApiServer::new(config, !matches.is_present("disable_sync"))?;
+fn plural(count: usize, noun: &str) -> String {
+    format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" })
+}
 
-    let threads: usize = if let Some(threads) = matches.value_of("threads") {
-        threads.parse().chain_err(|| "invalid value for threads")?
+fn publish_manifest_action(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let key = match matches.value_of("key") {
+        Some(key) => key.to_string(),
+        None => config.get_sync_manifest().map(|x| x.into_owned())
+            .unwrap_or_else(|| "index.json".to_string()),
+    };
+    let manifest = match matches.value_of("path") {
+        Some(path) => publish_dir_manifest(path, &key)?,
+        None => publish_bucket_manifest(&S3Server::from_config(config)?, &key)?,
+    };
+    println!("Published {} with {} SDKs (revision {})", key, manifest.sdk_count(),
+             manifest.revision().unwrap_or(0));
+    Ok(())
+}
+
+fn reconvert_action(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let opts = ReconvertOptions {
+        min_converter_version: matches.value_of("min_converter_version").unwrap().to_string(),
+        inputs: PathBuf::from(matches.value_of("inputs").unwrap()),
+        manifest_key: match matches.value_of("manifest") {
+            Some(key) => key.to_string(),
+            None => config.get_sync_manifest().map(|x| x.into_owned())
+                .unwrap_or_else(|| "index.json".to_string()),
+        },
+        worker: match matches.value_of("worker") {
+            Some(worker) => worker.to_string(),
+            None => format!("{}-{}", env::var("HOSTNAME").unwrap_or_else(|_| "worker".into()),
+                            process::id()),
+        },
+        claim_timeout: parse_duration(matches.value_of("claim_timeout").unwrap_or("2h"))
+            .ok_or_else(|| Error::from("invalid value for claim timeout"))?,
+        dry_run: matches.is_present("dry_run"),
+        dump: DumpOptions {
+            reporter: Arc::new(ConsoleReporter::new()),
+            scratch_dir: config.get_stash_scratch_dir().map(|x| x.into_owned()),
+            symbol_sources: get_symbol_sources(matches)?,
+            hot_objects: get_hot_objects(matches)?,
+            ..Default::default()
+        },
+    };
+    let report = reconvert(&S3Server::from_config(config)?, &opts)?;
+    for info in report.outdated.iter() {
+        let status = if report.converted.contains(info) {
+            style("converted").green()
+        } else if opts.dry_run {
+            style("outdated").yellow()
+        } else {
+            style("skipped").red()
+        };
+        println!("{} {}", status, info);
+    }
+    for &(ref info, ref reason) in report.skipped.iter() {
+        println!("  {}: {}", info, reason);
+    }
+    println!("Converted {} of {} outdated", report.converted.len(),
+             plural(report.outdated.len(), "SDK"));
+    Ok(())
+}
+
+fn sync_status_action(config: &Config, porcelain: bool) -> Result<()> {
+    let stash = MemDbStash::new(config)?;
+    let status = stash.get_sync_status()?;
+    if porcelain {
+        println!("{}", porcelain_line(&["revision", &status.revision().to_string()]));
+        println!("{}", porcelain_line(&["missing", &status.missing().to_string()]));
+        println!("{}", porcelain_line(&["different", &status.different().to_string()]));
+        println!("{}", porcelain_line(&["lag", &status.lag().to_string()]));
+        println!("{}", porcelain_line(&["offline", &status.is_offline().to_string()]));
+        println!("{}", porcelain_line(&["healthy", &status.is_healthy().to_string()]));
+        println!("{}", porcelain_line(&["warning", status.warning().unwrap_or("")]));
+        for sdk in stash.list_quarantined()? {
+            println!("{}", porcelain_line(&["quarantined", sdk.sdk_id(),
+                                            &sdk.timestamp().to_string(), sdk.reason()]));
+        }
     } else {
-        config.get_server_threads()?
+        print_sync_status_json(&stash, &status)?;
+    }
+    if !status.is_healthy() {
+        process::exit(2);
+    }
+    Ok(())
+}
+
+fn print_sync_status_json(stash: &MemDbStash, status: &SyncStatus) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&json!({
+        "revision": status.revision(),
+        "missing": status.missing(),
+        "different": status.different(),
+        "lag": status.lag(),
+        "offline": status.is_offline(),
+        "warning": status.warning(),
+        "healthy": status.is_healthy(),
+        "quarantined": stash.list_quarantined()?,
+    })).chain_err(|| "Could not serialize the sync status")?);
+    Ok(())
+}
+
+fn list_sdks_action(config: &Config, porcelain: bool) -> Result<()> {
+    let stash = MemDbStash::new(config)?;
+    let mut sdks = stash.list_sdk_conversions()?;
+    if !porcelain {
+        println!("{}", serde_json::to_string_pretty(&sdks)
+            .chain_err(|| "Could not serialize the SDKs")?);
+        return Ok(());
+    }
+    sdks.sort_by(|a, b| a.sdk_id().cmp(b.sdk_id()));
+    for sdk in sdks {
+        let device_family = sdk.device_family().map(|x| x.to_string()).unwrap_or_default();
+        println!("{}", porcelain_line(&[
+            "sdk",
+            sdk.sdk_id(),
+            &sdk.format_version().map(|x| x.to_string()).unwrap_or_default(),
+            &device_family,
+            sdk.product_type().unwrap_or(""),
+            sdk.conversion().map_or("", |x| x.converter_version.as_str()),
+            sdk.conversion().map_or("", |x| x.sdk_fingerprint.as_str()),
+        ]));
+    }
+    Ok(())
+}
+
+/// The paging of a response of `/admin/usage`
+#[derive(Deserialize)]
+struct UsagePage {
+    next_offset: Option<usize>,
+}
+
+fn export_usage_action(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let mut stats: UsageStats = match matches.value_of("from") {
+        Some(url) => {
+            let url = format!("{}/admin/usage", url.trim_end_matches('/'));
+            let mut headers = Headers::new();
+            if let Some(token) = matches.value_of("token") {
+                headers.set(Authorization(Bearer { token: token.to_string() }));
+            }
+            // the server sends the counters of a page of SDKs at a time
+            let client = HyperClient::new();
+            let mut stats = UsageStats::default();
+            let mut offset = Some(0);
+            while let Some(current) = offset {
+                let page_url = format!("{}?offset={}", url, current);
+                let mut resp = client.get(&page_url).headers(headers.clone()).send()
+                    .chain_err(|| format!("Could not reach {}", url))?;
+                if resp.status != StatusCode::Ok {
+                    return Err(Error::from(format!("{} answered {}", url, resp.status)));
+                }
+                let mut body = vec![];
+                resp.read_to_end(&mut body)?;
+                let page: UsagePage = serde_json::from_slice(&body)
+                    .chain_err(|| "Invalid usage stats")?;
+                stats.merge(&serde_json::from_slice(&body).chain_err(|| "Invalid usage stats")?);
+                offset = page.next_offset;
+            }
+            stats
+        }
+        None => MemDbStash::new(config)?.get_usage_stats(),
+    };
+    if let Some(top) = matches.value_of("top") {
+        stats = stats.hot_set(top.parse().chain_err(|| "invalid value for top")?);
+    }
+    let data = serde_json::to_vec_pretty(&stats).chain_err(|| "Could not serialize usage stats")?;
+    match matches.value_of("output_path") {
+        Some(path) => fs::File::create(path)?.write_all(&data)?,
+        None => io::stdout().write_all(&data)?,
+    }
+    Ok(())
+}
+
+fn import_usage_action(config: &Config, path: &str) -> Result<()> {
+    let stats: UsageStats = serde_json::from_reader(io::BufReader::new(fs::File::open(path)?))
+        .chain_err(|| "Invalid usage stats")?;
+    let stash = MemDbStash::new(config)?;
+    stash.import_usage_stats(&stats);
+    stash.save_usage_stats()?;
+    println!("Imported the usage of {}", plural(stats.sdk_count(), "SDK"));
+    let fetched = stash.prefetch_hot_objects()?;
+    if fetched > 0 {
+        println!("Prefetched {}", plural(fetched, "shared object"));
+    }
+    Ok(())
+}
+
+fn compare_action(config: &Config) -> Result<()> {
+    fn describe(sdk: Option<&RemoteSdk>) -> String {
+        match sdk {
+            Some(sdk) => format!("{} {}", HumanBytes(sdk.size()), sdk.etag()),
+            None => "-".into(),
+        }
+    }
+
+    let stash = MemDbStash::new(config)?;
+    let differences = stash.compare_with_upstream()?;
+    if differences.is_empty() {
+        println!("The stash matches upstream");
+        return Ok(());
+    }
+
+    println!("STATUS       {:<32} {:<48} REMOTE", "SDK", "LOCAL");
+    for diff in differences.iter() {
+        let status = format!("{:<12}", diff.kind().to_string());
+        println!("{} {:<32} {:<48} {}", match diff.kind() {
+            SdkDifferenceKind::LocalOnly => style(status).yellow(),
+            SdkDifferenceKind::RemoteOnly => style(status).red(),
+            SdkDifferenceKind::Different => style(status).cyan(),
+        }, diff.info().sdk_id(), describe(diff.local()), describe(diff.remote()));
+    }
+    let count = |kind| differences.iter().filter(|x| x.kind() == kind).count();
+    println!("{} local only, {} remote only, {} different",
+             count(SdkDifferenceKind::LocalOnly), count(SdkDifferenceKind::RemoteOnly),
+             count(SdkDifferenceKind::Different));
+    Ok(())
+}
+
+fn healthcheck_action(config: &Config, matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("no_http") {
+        let status = MemDbStash::new(config)?.get_sync_status()?;
+        if !status.is_healthy() {
+            println!("unhealthy: {} SDKs out of sync{}", status.lag(),
+                     if status.is_offline() { " (offline)" } else { "" });
+            process::exit(1);
+        }
+        println!("healthy");
+        return Ok(());
+    }
+
+    let url = match matches.value_of("url") {
+        Some(url) => url.to_string(),
+        None => format!("{}/health", get_local_server_url(config)?),
+    };
+    let timeout = match matches.value_of("timeout") {
+        Some(value) => value.parse().chain_err(|| "invalid value for timeout")?,
+        None => 5,
+    };
+    let mut client = HyperClient::new();
+    client.set_read_timeout(Some(Duration::from_secs(timeout)));
+    client.set_write_timeout(Some(Duration::from_secs(timeout)));
+    let resp = client.get(&url).send().chain_err(|| format!("Could not reach {}", url))?;
+    if resp.status != StatusCode::Ok {
+        println!("unhealthy: {} answered {}", url, resp.status);
+        process::exit(1);
+    }
+    println!("healthy");
+    Ok(())
+}
+
+/// Returns the base URL of the server the config describes on this host.
+fn get_local_server_url(config: &Config) -> Result<String> {
+    let (host, port) = config.get_server_socket_addr()?;
+    let host = match host.as_str() {
+        "0.0.0.0" | "::" | "" => "127.0.0.1".to_string(),
+        host if host.contains(':') => format!("[{}]", host),
+        host => host.to_string(),
+    };
+    Ok(format!("http://{}:{}", host, port))
+}
+
+/// The parts of `/metrics` that `top` shows.
+#[derive(Deserialize, Default)]
+struct TopMetrics {
+    #[serde(default)]
+    requests: u64,
+    #[serde(default)]
+    requests_in_flight: u64,
+    #[serde(default)]
+    open_memdbs: u64,
+    #[serde(default)]
+    memdb_cache: TopCacheStats,
+    #[serde(default)]
+    sdks: Vec<TopSdkStats>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+struct TopCacheStats {
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Deserialize)]
+struct TopSdkStats {
+    sdk_id: String,
+    requests: u64,
+    symbols: u64,
+    unknown_object_rate: f64,
+    avg_duration_ms: f64,
+}
+
+/// The parts of `/health` that `top` shows.
+#[derive(Deserialize)]
+struct TopHealth {
+    is_healthy: bool,
+    is_offline: bool,
+    is_degraded: bool,
+    sync_lag: u32,
+    last_sync: Option<i64>,
+    last_sync_error: Option<String>,
+    sync_warning: Option<String>,
+    sync_failures: u64,
+}
+
+struct TopSnapshot {
+    taken: Instant,
+    metrics: TopMetrics,
+    health: TopHealth,
+}
+
+impl TopSnapshot {
+    fn fetch(client: &HyperClient, url: &str) -> Result<TopSnapshot> {
+        let metrics_url = format!("{}/metrics", url);
+        let resp = client.get(&metrics_url).send()
+            .chain_err(|| format!("Could not reach {}", metrics_url))?;
+        if resp.status != StatusCode::Ok {
+            return Err(Error::from(format!("{} answered {}", metrics_url, resp.status)));
+        }
+        let metrics = serde_json::from_reader(resp).chain_err(|| "Invalid metrics")?;
+        // an unhealthy server answers 503 but still describes its state
+        let health_url = format!("{}/health", url);
+        let resp = client.get(&health_url).send()
+            .chain_err(|| format!("Could not reach {}", health_url))?;
+        let health = serde_json::from_reader(resp).chain_err(|| "Invalid health check")?;
+        Ok(TopSnapshot {
+            taken: Instant::now(),
+            metrics,
+            health,
+        })
+    }
+
+    /// Renders the dashboard, with rates relative to the previous snapshot.
+    fn render(&self, prev: Option<&TopSnapshot>, url: &str, max_lines: usize) -> Vec<String> {
+        let secs = prev.map(|prev| {
+            let elapsed = self.taken.duration_since(prev.taken);
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
+        }).unwrap_or(0.0);
+        let rate = |now: u64, before: Option<u64>| -> String {
+            match before {
+                Some(before) if secs > 0.0 => {
+                    format!("{:.1}/s", now.saturating_sub(before) as f64 / secs)
+                }
+                _ => "-".into(),
+            }
+        };
+
+        let mut rv = vec![];
+        rv.push(format!("{} {}", style("sentry-symbolserver at").bold(), url));
+        let metrics = &self.metrics;
+        rv.push(format!("Requests  {} ({} in flight, {} total)",
+                        style(rate(metrics.requests, prev.map(|x| x.metrics.requests))).cyan(),
+                        metrics.requests_in_flight, metrics.requests));
+
+        let cache = match prev {
+            Some(prev) => TopCacheStats {
+                hits: metrics.memdb_cache.hits.saturating_sub(prev.metrics.memdb_cache.hits),
+                misses: metrics.memdb_cache.misses
+                    .saturating_sub(prev.metrics.memdb_cache.misses),
+            },
+            None => metrics.memdb_cache,
+        };
+        let lookups = cache.hits + cache.misses;
+        rv.push(format!("Memdbs    {} open, cache hit rate {}",
+                        metrics.open_memdbs,
+                        if lookups > 0 {
+                            format!("{:.1}%", cache.hits as f64 * 100.0 / lookups as f64)
+                        } else {
+                            "-".into()
+                        }));
+
+        let health = &self.health;
+        let state = if health.is_offline {
+            style("offline").red()
+        } else if !health.is_healthy {
+            style(if health.is_degraded { "degraded" } else { "unhealthy" }).red()
+        } else {
+            style("healthy").green()
+        };
+        rv.push(format!("Sync      {}, {} behind, last sync {}, {}",
+                        state, plural(health.sync_lag as usize, "SDK"),
+                        match health.last_sync {
+                            Some(ts) => format!("{} ago", HumanDuration(Duration::from_secs(
+                                (chrono::Utc::now().timestamp() - ts).max(0) as u64))),
+                            None => "never".into(),
+                        },
+                        plural(health.sync_failures as usize, "failure")));
+        if let Some(ref err) = health.last_sync_error {
+            rv.push(format!("          {}", style(err).red()));
+        }
+        if let Some(ref warning) = health.sync_warning {
+            rv.push(format!("          {}", style(warning).yellow()));
+        }
+
+        rv.push("".into());
+        rv.push(format!("{}", style(format!("{:<32} {:>10} {:>12} {:>8} {:>8}",
+                                             "SDK", "REQUESTS", "SYMBOLS", "UNKNOWN",
+                                             "AVG MS")).bold()));
+        let before = |sdk_id: &str, f: fn(&TopSdkStats) -> u64| {
+            prev.and_then(|prev| prev.metrics.sdks.iter().find(|x| x.sdk_id == sdk_id)).map(f)
+        };
+        let mut sdks: Vec<_> = metrics.sdks.iter().map(|sdk| {
+            let requests = sdk.requests.saturating_sub(
+                before(&sdk.sdk_id, |x| x.requests).unwrap_or(0));
+            (requests, sdk)
+        }).collect();
+        sdks.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.sdk_id.cmp(&b.1.sdk_id)));
+        let rows = max_lines.saturating_sub(rv.len());
+        for &(_, sdk) in sdks.iter().take(rows) {
+            rv.push(format!("{:<32} {:>10} {:>12} {:>7.1}% {:>8.1}",
+                            sdk.sdk_id,
+                            rate(sdk.requests, before(&sdk.sdk_id, |x| x.requests)),
+                            rate(sdk.symbols, before(&sdk.sdk_id, |x| x.symbols)),
+                            sdk.unknown_object_rate * 100.0,
+                            sdk.avg_duration_ms));
+        }
+        rv
+    }
+}
+
+fn top_action(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let url = match matches.value_of("url") {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => get_local_server_url(config)?,
     };
+    let interval = match matches.value_of("interval") {
+        Some(value) => value.parse().chain_err(|| "invalid value for interval")?,
+        None => 2,
+    };
+    let mut client = HyperClient::new();
+    client.set_read_timeout(Some(Duration::from_secs(5)));
+    client.set_write_timeout(Some(Duration::from_secs(5)));
+
+    let term = Term::stdout();
+    let mut prev: Option<TopSnapshot> = None;
+    let mut drawn = 0;
+    install_shutdown_handler()?;
+    while !shutdown_requested() {
+        let snapshot = TopSnapshot::fetch(&client, &url)?;
+        let max_lines = term.size_checked()
+            .map_or(usize::max_value(), |x| (x.0 as usize).saturating_sub(1));
+        let lines = snapshot.render(prev.as_ref(), &url, max_lines);
+        if term.is_term() {
+            term.clear_last_lines(drawn)?;
+        } else if prev.is_some() {
+            term.write_line("")?;
+        }
+        for line in &lines {
+            term.write_line(line)?;
+        }
+        drawn = lines.len();
+        prev = Some(snapshot);
+
+        let started = Instant::now();
+        while !shutdown_requested() && started.elapsed() < Duration::from_secs(interval) {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    uninstall_shutdown_handler();
+    Ok(())
+}
+
+fn replay_action(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let mut options = ReplayOptions {
+        url: match matches.value_of("url") {
+            Some(url) => url.to_string(),
+            None => get_local_server_url(config)?,
+        },
+        api_key: matches.value_of("api_key").map(|x| x.to_string()),
+        speed: 1.0,
+        concurrency: 8,
+    };
+    if let Some(speed) = matches.value_of("speed") {
+        options.speed = speed.parse().chain_err(|| "invalid value for speed")?;
+    }
+    if let Some(concurrency) = matches.value_of("concurrency") {
+        options.concurrency = concurrency.parse().chain_err(|| "invalid value for concurrency")?;
+    }
+    let report = replay(matches.value_of("path").unwrap(), &options)?;
+    println!("{}", serde_json::to_string_pretty(&report)
+        .chain_err(|| "Could not serialize the replay report")?);
+    Ok(())
+}
+
+fn verify_action(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let stash = MemDbStash::new(config)?;
+    let mut options = VerifyOptions {
+        reporter: Arc::new(ConsoleReporter::new()),
+        jobs: config.get_server_worker_threads()?,
+    };
+    if let Some(jobs) = matches.value_of("jobs") {
+        options.jobs = jobs.parse().chain_err(|| "invalid value for jobs")?;
+    }
+
+    let started = Instant::now();
+    let failures = stash.verify(&options)?;
+    for failure in failures.iter() {
+        println!("{} {}: {}", style("Quarantined").red(), failure.info(), failure.reason());
+        stash.quarantine(failure.info(), failure.reason(), &cli_actor())?;
+    }
+    println!("Verified {} SDKs in {}", stash.sdk_count()?, HumanDuration(started.elapsed()));
+
+    if !failures.is_empty() {
+        return Err(Error::from(format!("{} SDKs failed verification", failures.len())));
+    }
+    Ok(())
+}
+
+fn run_action(config: &Config, matches: &ArgMatches, config_file: Option<PathBuf>)
+    -> Result<()>
+{
+    let mut config = config.clone();
+    if let Some(threads) = matches.value_of("threads") {
+        config.set_server_listener_threads(
+            threads.parse().chain_err(|| "invalid value for listener threads")?);
+    }
+    if let Some(threads) = matches.value_of("worker_threads") {
+        config.set_server_worker_threads(
+            threads.parse().chain_err(|| "invalid value for worker threads")?);
+    }
+    if let Some(threads) = matches.value_of("io_threads") {
+        config.set_server_io_threads(
+            threads.parse().chain_err(|| "invalid value for IO threads")?);
+    }
+    if let Some(lag) = matches.value_of("ready_max_lag") {
+        config.set_server_ready_max_lag(
+            lag.parse().chain_err(|| "invalid value for ready max lag")?);
+    }
+    let api_server = ApiServer::new(&config, !matches.is_present("disable_sync"))?;
+    let threads = config.get_server_listener_threads()?;
+    if let Some(config_file) = config_file {
+        api_server.spawn_reload_thread(config_file)?;
+    }
+
+    // SIGTERM and SIGINT drain the server and make `run` return
+    install_shutdown_handler()?;
+    {
+        let shutdown = api_server.shutdown_handle();
+        thread::spawn(move || {
+            while !shutdown_requested() {
+                thread::sleep(Duration::from_millis(100));
+            }
+            shutdown.shutdown();
+        });
+    }
 
     api_server.run(threads, if let Some(addr) = matches.value_of("bind") {
         BindOptions::BindToAddr(addr)
@@ -383,6 +1604,15 @@ fn run_action(config: &Config, matches: &ArgMatches) -> Result<()> {
     } else {
         BindOptions::UseConfig
     })?;
+    uninstall_shutdown_handler();
 
     Ok(())
 }
+
+#[test]
+fn test_porcelain_line() {
+    assert_eq!(porcelain_line(&["sdk", "updating", "iOS_10.2.0_14C92"]),
+               "sdk\tupdating\tiOS_10.2.0_14C92");
+    assert_eq!(porcelain_line(&["warning", ""]), "warning\t-");
+    assert_eq!(porcelain_line(&["warning", "a\tb\nc"]), "warning\ta b c");
+}
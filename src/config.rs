@@ -3,7 +3,15 @@ use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::borrow::Cow;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
+use std::collections::{BTreeMap, HashMap};
+
+#[cfg(feature = "crypto")]
+use openssl::sha::sha256;
+#[cfg(feature = "crypto")]
+use rustc_serialize::hex::ToHex;
+#[cfg(not(feature = "crypto"))]
+use md5;
 
 use num_cpus;
 use serde_yaml;
@@ -13,9 +21,25 @@ use chrono::Duration;
 use log::LogLevelFilter;
 
 use super::{Result, ResultExt, ErrorKind};
+use super::sdk::SdkInfo;
 use super::utils::{is_docker, IgnorePatterns};
 
 
+/// Hashes data for names and fingerprints.
+///
+/// This is SHA-256 but builds without the `crypto` feature have no
+/// OpenSSL and fall back to MD5.  Nothing here needs to be secure, the
+/// hash only keeps secrets out of logs.
+#[cfg(feature = "crypto")]
+fn hex_digest(data: &[u8]) -> String {
+    sha256(data).to_hex()
+}
+
+#[cfg(not(feature = "crypto"))]
+fn hex_digest(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
 #[derive(Deserialize, Debug, Default, Clone)]
 struct AwsConfig {
     endpoint: Option<String>,
@@ -30,7 +54,73 @@ struct ServerConfig {
     host: Option<String>,
     port: Option<u16>,
     healthcheck_interval: Option<i64>,
+    /// The old name of `listener_threads`
     threads: Option<usize>,
+    listener_threads: Option<usize>,
+    worker_threads: Option<usize>,
+    io_threads: Option<usize>,
+    slow_request_ms: Option<i64>,
+    sdk_metrics_top: Option<usize>,
+    max_page_size: Option<usize>,
+    unpaged_sdk_list: Option<bool>,
+    shutdown_grace_period: Option<i64>,
+    ready_max_lag: Option<u32>,
+    keep_alive_timeout: Option<i64>,
+    max_requests_per_connection: Option<u64>,
+    grpc_port: Option<u16>,
+    #[serde(default)]
+    api_keys: Vec<ApiKey>,
+    signing_key: Option<PathBuf>,
+    record_path: Option<PathBuf>,
+    record_sample_rate: Option<f64>,
+    record_max_mb: Option<u64>,
+    auth: Option<String>,
+    #[serde(default)]
+    jwt: JwtConfig,
+    #[serde(default)]
+    tls: TlsConfig,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+struct JwtConfig {
+    secret: Option<String>,
+    jwks_url: Option<String>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    require_exp: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+struct TlsConfig {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    #[serde(default)]
+    admin_clients: Vec<String>,
+}
+
+/// How clients of the lookup endpoints authenticate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// With one of `server.api_keys`, or not at all if there are none
+    ApiKeys,
+    /// With a JSON web token signed with `server.jwt.secret` or a key of
+    /// `server.jwt.jwks_url`
+    Jwt,
+    /// With a TLS client certificate signed by `server.tls.client_ca`
+    ClientCert,
+}
+
+/// An API key, the SDKs it may look symbols up in and whether it may use
+/// the admin endpoints.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    key: String,
+    name: Option<String>,
+    #[serde(default)]
+    sdks: IgnorePatterns,
+    #[serde(default)]
+    admin: bool,
 }
 
 #[derive(Deserialize, Debug, Default, Clone)]
@@ -39,11 +129,98 @@ struct LogConfig {
     file: Option<PathBuf>,
 }
 
+#[derive(Deserialize, Debug, Default, Clone)]
+struct StashConfig {
+    max_open_memdbs: Option<usize>,
+    usage_flush_interval: Option<i64>,
+    warmup_sdks: Option<usize>,
+    fuzzy_max_results: Option<usize>,
+    fuzzy_max_score: Option<u32>,
+    #[serde(default)]
+    sdk_aliases: BTreeMap<String, String>,
+    read_only: Option<bool>,
+    state_check_interval: Option<i64>,
+    compress_memdbs: Option<bool>,
+    decompressed_cache_mb: Option<u64>,
+    gc_interval: Option<i64>,
+    gc_max_age: Option<i64>,
+    audit_interval: Option<i64>,
+    audit_sample: Option<usize>,
+    scratch_dir: Option<PathBuf>,
+    encryption_key: Option<PathBuf>,
+}
+
 #[derive(Deserialize, Debug, Default, Clone)]
 struct SyncConfig {
     #[serde(default)]
     ignore: IgnorePatterns,
     interval: Option<i64>,
+    max_staleness: Option<i64>,
+    keep_reports: Option<usize>,
+    listing_ttl: Option<i64>,
+    manifest: Option<String>,
+    primary: Option<String>,
+    primary_token: Option<String>,
+    trusted_keys: Option<Vec<PathBuf>>,
+    require_signatures: Option<bool>,
+    lazy_objects: Option<bool>,
+    serve_stale: Option<bool>,
+    object_fetch_budget_ms: Option<i64>,
+    prefetch_objects: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+struct StatsdConfig {
+    addr: Option<String>,
+    prefix: Option<String>,
+    tags: Option<Vec<String>>,
+    interval: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+struct ConvertConfig {
+    output_dir: Option<PathBuf>,
+    filename_template: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+struct TenantConfig {
+    bucket_url: Option<String>,
+    region: Option<String>,
+    symbol_dir: Option<PathBuf>,
+    #[serde(default)]
+    ignore: IgnorePatterns,
+}
+
+impl ApiKey {
+    /// Checks if the key matches a key sent by a client.
+    ///
+    /// Takes the same time no matter where the keys differ.
+    pub fn matches(&self, key: &str) -> bool {
+        self.key.len() == key.len() &&
+            self.key.bytes().zip(key.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
+    /// The name of the key for logs
+    ///
+    /// Keys without a name are called `key-` and the start of the hash of
+    /// the key so that the secret itself never ends up in logs.
+    pub fn name(&self) -> Cow<str> {
+        match self.name {
+            Some(ref name) => Cow::Borrowed(name),
+            None => Cow::Owned(format!("key-{}", &hex_digest(self.key.as_bytes())[..8])),
+        }
+    }
+
+    /// Checks if the key may look symbols up in an SDK.
+    pub fn may_access(&self, sdk_id: &str) -> bool {
+        self.sdks.is_match(sdk_id)
+    }
+
+    /// Checks if the key may use the admin endpoints.
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
 }
 
 /// Central config object that exposes the information from
@@ -58,31 +235,68 @@ pub struct Config {
     log: LogConfig,
     symbol_dir: Option<PathBuf>,
     #[serde(default)]
+    stash: StashConfig,
+    #[serde(default)]
     sync: SyncConfig,
+    #[serde(default)]
+    statsd: StatsdConfig,
+    #[serde(default)]
+    convert: ConvertConfig,
+    #[serde(default)]
+    tenants: BTreeMap<String, TenantConfig>,
 }
 
 impl Config {
     /// Loads a config from a given file
     pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Config> {
         let f = fs::File::open(path)?;
-        serde_yaml::from_reader(BufReader::new(f)).map_err(|err| {
+        Config::load_reader(BufReader::new(f))
+    }
+
+    /// Loads a config in YAML or JSON from a reader like stdin
+    pub fn load_reader<R: Read>(reader: R) -> Result<Config> {
+        serde_yaml::from_reader(reader).map_err(|err| {
+            ErrorKind::ConfigError(err).into()
+        })
+    }
+
+    /// Loads a config in YAML or JSON from a string
+    pub fn load_str(value: &str) -> Result<Config> {
+        serde_yaml::from_str(value).map_err(|err| {
             ErrorKind::ConfigError(err).into()
         })
     }
 
+    /// Returns the default location of the config file if it exists
+    pub fn get_default_path() -> Option<PathBuf> {
+        let mut home = dirs::home_dir()?;
+        home.push(".sentry-symbolserver.yml");
+        if fs::metadata(&home).is_ok() {
+            Some(home)
+        } else {
+            None
+        }
+    }
+
     /// Loads a config from the default location
     pub fn load_default() -> Result<Config> {
-        let mut home = match dirs::home_dir() {
-            Some(home) => home,
-            None => { return Ok(Default::default()) },
-        };
-        home.push(".sentry-symbolserver.yml");
+        match Config::get_default_path() {
+            Some(path) => Config::load_file(&path),
+            None => Ok(Default::default()),
+        }
+    }
 
-        Ok(if let Ok(_) = fs::metadata(&home) {
-            Config::load_file(&home)?
-        } else {
-            Default::default()
-        })
+    /// Returns a short fingerprint of the effective config.
+    ///
+    /// This covers the config as loaded and overridden as well as the
+    /// `SYMBOLSERVER_*` environment variables, so servers that were set up
+    /// the same way have the same fingerprint.
+    pub fn fingerprint(&self) -> String {
+        let mut vars: Vec<_> = env::vars()
+            .filter(|&(ref key, _)| key.starts_with("SYMBOLSERVER_"))
+            .collect();
+        vars.sort();
+        hex_digest(format!("{:?}\n{:?}", self, vars).as_bytes())[..16].to_string()
     }
 
     /// Return the AWS access key
@@ -100,6 +314,17 @@ impl Config {
         self.aws.endpoint.as_ref().map(|x| &**x)
     }
 
+    /// Overrides the AWS endpoint.
+    pub fn set_aws_endpoint(&mut self, value: &str) {
+        self.aws.endpoint = Some(value.to_string());
+    }
+
+    /// Overrides the AWS credentials.
+    pub fn set_aws_credentials(&mut self, access_key: &str, secret_key: &str) {
+        self.aws.access_key = Some(access_key.to_string());
+        self.aws.secret_key = Some(secret_key.to_string());
+    }
+
     /// Return the AWS S3 bucket URL
     pub fn get_aws_bucket_url<'a>(&'a self) -> Result<Url> {
         let url = if let Some(ref value) = self.aws.bucket_url {
@@ -165,6 +390,286 @@ impl Config {
         self.symbol_dir = Some(value.as_ref().to_path_buf());
     }
 
+    /// Return the maximum number of memdbs the stash keeps open at once
+    ///
+    /// `None` means that no limit is enforced.
+    pub fn get_stash_max_open_memdbs(&self) -> Result<Option<usize>> {
+        let max = if let Some(max) = self.stash.max_open_memdbs {
+            max
+        } else if let Ok(maxstr) = env::var("SYMBOLSERVER_MAX_OPEN_MEMDBS") {
+            maxstr.parse().chain_err(|| "Invalid value for max open memdbs")?
+        } else {
+            return Ok(None);
+        };
+        if max == 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.max_open_memdbs", "Limit has to be at least one").into());
+        }
+        Ok(Some(max))
+    }
+
+    /// Return the interval in which usage counters are written to disk
+    pub fn get_stash_usage_flush_interval(&self) -> Result<Duration> {
+        let interval = if let Some(interval) = self.stash.usage_flush_interval {
+            interval
+        } else if let Ok(intervalstr) = env::var("SYMBOLSERVER_USAGE_FLUSH_INTERVAL") {
+            intervalstr.parse().chain_err(|| "Invalid value for usage flush interval")?
+        } else {
+            return Ok(Duration::minutes(1));
+        };
+        if interval <= 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.usage_flush_interval", "Usage flush interval has to be positive").into());
+        }
+        Ok(Duration::seconds(interval))
+    }
+
+    /// Return the number of most used SDKs that are opened on startup
+    pub fn get_stash_warmup_sdks(&self) -> Result<usize> {
+        if let Some(count) = self.stash.warmup_sdks {
+            Ok(count)
+        } else if let Ok(countstr) = env::var("SYMBOLSERVER_WARMUP_SDKS") {
+            Ok(countstr.parse().chain_err(|| "Invalid value for warmup SDKs")?)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Return how many SDKs a fuzzy match returns at most
+    pub fn get_stash_fuzzy_max_results(&self) -> Result<usize> {
+        let max = if let Some(max) = self.stash.fuzzy_max_results {
+            max
+        } else if let Ok(maxstr) = env::var("SYMBOLSERVER_FUZZY_MAX_RESULTS") {
+            maxstr.parse().chain_err(|| "Invalid value for fuzzy max results")?
+        } else {
+            return Ok(10);
+        };
+        if max == 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.fuzzy_max_results", "Limit has to be at least one").into());
+        }
+        Ok(max)
+    }
+
+    /// Return the worst score a fuzzy match may have
+    ///
+    /// `None` means that every SDK of the same minor version matches.
+    pub fn get_stash_fuzzy_max_score(&self) -> Result<Option<u32>> {
+        if let Some(max) = self.stash.fuzzy_max_score {
+            Ok(Some(max))
+        } else if let Ok(maxstr) = env::var("SYMBOLSERVER_FUZZY_MAX_SCORE") {
+            Ok(Some(maxstr.parse().chain_err(|| "Invalid value for fuzzy max score")?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Return whether the stash must not be modified
+    ///
+    /// A read-only stash never syncs and never writes to the symbol
+    /// directory.  This is for servers that share a directory which is
+    /// populated by another process.
+    pub fn get_stash_read_only(&self) -> Result<bool> {
+        if let Some(read_only) = self.stash.read_only {
+            Ok(read_only)
+        } else if let Ok(read_onlystr) = env::var("SYMBOLSERVER_READ_ONLY") {
+            Ok(read_onlystr.parse().chain_err(|| "Invalid value for read only")?)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Return whether memdbs are kept compressed in the symbol directory
+    pub fn get_stash_compress_memdbs(&self) -> Result<bool> {
+        if let Some(compress) = self.stash.compress_memdbs {
+            Ok(compress)
+        } else if let Ok(compressstr) = env::var("SYMBOLSERVER_COMPRESS_MEMDBS") {
+            Ok(compressstr.parse().chain_err(|| "Invalid value for compress memdbs")?)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Overrides whether memdbs are kept compressed.
+    pub fn set_stash_compress_memdbs(&mut self, value: bool) {
+        self.stash.compress_memdbs = Some(value);
+    }
+
+    /// Return the path of the key memdbs are encrypted with
+    pub fn get_stash_encryption_key(&self) -> Option<Cow<Path>> {
+        if let Some(ref path) = self.stash.encryption_key {
+            Some(Cow::Borrowed(path.as_path()))
+        } else if let Ok(path) = env::var("SYMBOLSERVER_ENCRYPTION_KEY") {
+            Some(Cow::Owned(PathBuf::from(path)))
+        } else {
+            None
+        }
+    }
+
+    /// Overrides the key memdbs are encrypted with.
+    pub fn set_stash_encryption_key<P: AsRef<Path>>(&mut self, value: P) {
+        self.stash.encryption_key = Some(value.as_ref().to_path_buf());
+    }
+
+    /// Return how many bytes of decompressed memdbs are kept around
+    pub fn get_stash_decompressed_cache_size(&self) -> Result<u64> {
+        let mb = if let Some(mb) = self.stash.decompressed_cache_mb {
+            mb
+        } else if let Ok(mbstr) = env::var("SYMBOLSERVER_DECOMPRESSED_CACHE_MB") {
+            mbstr.parse().chain_err(|| "Invalid value for decompressed cache size")?
+        } else {
+            4096
+        };
+        if mb == 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.decompressed_cache_mb", "Cache size has to be positive").into());
+        }
+        Ok(mb * 1024 * 1024)
+    }
+
+    /// Return the interval in which the stash checks for external changes
+    pub fn get_stash_state_check_interval(&self) -> Result<Duration> {
+        let interval = if let Some(interval) = self.stash.state_check_interval {
+            interval
+        } else if let Ok(intervalstr) = env::var("SYMBOLSERVER_STATE_CHECK_INTERVAL") {
+            intervalstr.parse().chain_err(|| "Invalid value for state check interval")?
+        } else {
+            return Ok(Duration::seconds(10));
+        };
+        if interval <= 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.state_check_interval", "State check interval has to be positive").into());
+        }
+        Ok(Duration::seconds(interval))
+    }
+
+    /// Return the interval in which leftover temporary files are removed
+    pub fn get_stash_gc_interval(&self) -> Result<Duration> {
+        let interval = if let Some(interval) = self.stash.gc_interval {
+            interval
+        } else if let Ok(intervalstr) = env::var("SYMBOLSERVER_GC_INTERVAL") {
+            intervalstr.parse().chain_err(|| "Invalid value for GC interval")?
+        } else {
+            return Ok(Duration::seconds(3600));
+        };
+        if interval <= 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.gc_interval", "GC interval has to be positive").into());
+        }
+        Ok(Duration::seconds(interval))
+    }
+
+    /// Return how old a temporary file has to be before it is removed
+    pub fn get_stash_gc_max_age(&self) -> Result<Duration> {
+        let age = if let Some(age) = self.stash.gc_max_age {
+            age
+        } else if let Ok(agestr) = env::var("SYMBOLSERVER_GC_MAX_AGE") {
+            agestr.parse().chain_err(|| "Invalid value for GC max age")?
+        } else {
+            return Ok(Duration::seconds(3600));
+        };
+        if age < 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.gc_max_age", "GC max age cannot be negative").into());
+        }
+        Ok(Duration::seconds(age))
+    }
+
+    /// Overrides how old (in seconds) a temporary file has to be before it
+    /// is removed.
+    pub fn set_stash_gc_max_age(&mut self, value: i64) {
+        self.stash.gc_max_age = Some(value);
+    }
+
+    /// Return how often a random sample of the stash is audited, if at all
+    pub fn get_stash_audit_interval(&self) -> Result<Option<Duration>> {
+        let interval = if let Some(interval) = self.stash.audit_interval {
+            interval
+        } else if let Ok(intervalstr) = env::var("SYMBOLSERVER_AUDIT_INTERVAL") {
+            intervalstr.parse().chain_err(|| "Invalid value for audit interval")?
+        } else {
+            return Ok(None);
+        };
+        if interval < 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.audit_interval",
+                "Audit interval cannot be negative").into());
+        }
+        Ok(if interval == 0 { None } else { Some(Duration::seconds(interval)) })
+    }
+
+    /// Overrides the audit interval in seconds (0 disables it).
+    pub fn set_stash_audit_interval(&mut self, value: i64) {
+        self.stash.audit_interval = Some(value);
+    }
+
+    /// Return how many SDKs an audit samples
+    pub fn get_stash_audit_sample(&self) -> Result<usize> {
+        let sample = if let Some(sample) = self.stash.audit_sample {
+            sample
+        } else if let Ok(samplestr) = env::var("SYMBOLSERVER_AUDIT_SAMPLE") {
+            samplestr.parse().chain_err(|| "Invalid value for audit sample")?
+        } else {
+            return Ok(10);
+        };
+        if sample == 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "stash.audit_sample",
+                "Audit sample has to be positive").into());
+        }
+        Ok(sample)
+    }
+
+    /// Overrides how many SDKs an audit samples.
+    pub fn set_stash_audit_sample(&mut self, value: usize) {
+        self.stash.audit_sample = Some(value);
+    }
+
+    /// Return the folder temporary files are written to before they are
+    /// moved into place, if one is configured
+    pub fn get_stash_scratch_dir(&self) -> Option<Cow<Path>> {
+        if let Some(ref path) = self.stash.scratch_dir {
+            Some(Cow::Borrowed(path.as_path()))
+        } else if let Ok(dir) = env::var("SYMBOLSERVER_SCRATCH_DIR") {
+            Some(Cow::Owned(PathBuf::from(dir)))
+        } else {
+            None
+        }
+    }
+
+    /// Overrides the scratch folder.
+    pub fn set_stash_scratch_dir<P: AsRef<Path>>(&mut self, value: P) {
+        self.stash.scratch_dir = Some(value.as_ref().to_path_buf());
+    }
+
+    /// Overrides whether the stash is read-only.
+    pub fn set_stash_read_only(&mut self, value: bool) {
+        self.stash.read_only = Some(value);
+    }
+
+    /// Return the SDK aliases
+    ///
+    /// Requests for the SDK on the left are served as if the SDK on the
+    /// right was requested.
+    pub fn get_stash_sdk_aliases(&self) -> Result<HashMap<SdkInfo, SdkInfo>> {
+        let mut rv = HashMap::new();
+        for (from, to) in self.stash.sdk_aliases.iter() {
+            match (SdkInfo::from_filename(from), SdkInfo::from_filename(to)) {
+                (Some(from), Some(to)) => { rv.insert(from, to); }
+                _ => {
+                    return Err(ErrorKind::BadConfigKey(
+                        "stash.sdk_aliases", "Aliases have to map SDK ids to SDK ids").into());
+                }
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Adds an SDK alias.
+    pub fn add_stash_sdk_alias(&mut self, from: &str, to: &str) {
+        self.stash.sdk_aliases.insert(from.to_string(), to.to_string());
+    }
+
     fn get_server_host(&self) -> Result<String> {
         if let Some(ref host) = self.server.host {
             Ok(host.clone())
@@ -209,6 +714,422 @@ impl Config {
         Ok(Duration::seconds(ttl))
     }
 
+    /// Return how long a request may take before it is logged as slow
+    ///
+    /// With 0 every request is logged.
+    pub fn get_server_slow_request_threshold(&self) -> Result<Duration> {
+        let ms = if let Some(ms) = self.server.slow_request_ms {
+            ms
+        } else if let Ok(msstr) = env::var("SYMBOLSERVER_SLOW_REQUEST_MS") {
+            msstr.parse().chain_err(|| "Invalid value for slow request threshold")?
+        } else {
+            return Ok(Duration::seconds(1));
+        };
+        if ms < 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "server.slow_request_ms", "Slow request threshold cannot be negative").into());
+        }
+        Ok(Duration::milliseconds(ms))
+    }
+
+    /// Return how many SDKs `/metrics` reports lookup metrics for
+    ///
+    /// The most requested SDKs are reported.  With 0 none are.
+    pub fn get_server_sdk_metrics_top(&self) -> Result<usize> {
+        if let Some(count) = self.server.sdk_metrics_top {
+            Ok(count)
+        } else if let Ok(countstr) = env::var("SYMBOLSERVER_SDK_METRICS_TOP") {
+            Ok(countstr.parse().chain_err(|| "Invalid value for SDK metrics count")?)
+        } else {
+            Ok(20)
+        }
+    }
+
+    /// Return how many entries listing endpoints return at most per request
+    pub fn get_server_max_page_size(&self) -> Result<usize> {
+        let max = if let Some(max) = self.server.max_page_size {
+            max
+        } else if let Ok(maxstr) = env::var("SYMBOLSERVER_MAX_PAGE_SIZE") {
+            maxstr.parse().chain_err(|| "Invalid value for max page size")?
+        } else {
+            return Ok(1000);
+        };
+        if max == 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "server.max_page_size", "Limit has to be at least one").into());
+        }
+        Ok(max)
+    }
+
+    /// Override the maximum page size of listing endpoints
+    pub fn set_server_max_page_size(&mut self, value: usize) {
+        self.server.max_page_size = Some(value);
+    }
+
+    /// Return if `/sdks` lists all SDKs when no `limit` is given, like
+    /// older versions did
+    pub fn get_server_unpaged_sdk_list(&self) -> Result<bool> {
+        if let Some(unpaged) = self.server.unpaged_sdk_list {
+            Ok(unpaged)
+        } else if let Ok(unpagedstr) = env::var("SYMBOLSERVER_UNPAGED_SDK_LIST") {
+            Ok(unpagedstr.parse().chain_err(|| "Invalid value for unpaged SDK list")?)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Overrides whether `/sdks` lists all SDKs when no `limit` is given
+    pub fn set_server_unpaged_sdk_list(&mut self, value: bool) {
+        self.server.unpaged_sdk_list = Some(value);
+    }
+
+    /// Return how long the server keeps serving after it was asked to
+    /// shut down
+    pub fn get_server_shutdown_grace_period(&self) -> Result<Duration> {
+        let secs = if let Some(secs) = self.server.shutdown_grace_period {
+            secs
+        } else if let Ok(secsstr) = env::var("SYMBOLSERVER_SHUTDOWN_GRACE_PERIOD") {
+            secsstr.parse().chain_err(|| "Invalid value for shutdown grace period")?
+        } else {
+            return Ok(Duration::seconds(0));
+        };
+        if secs < 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "server.shutdown_grace_period",
+                "Shutdown grace period cannot be negative").into());
+        }
+        Ok(Duration::seconds(secs))
+    }
+
+    /// Return the sync lag below which the server reports itself as ready
+    ///
+    /// `None` means that the server is ready right away.
+    pub fn get_server_ready_max_lag(&self) -> Result<Option<u32>> {
+        if let Some(lag) = self.server.ready_max_lag {
+            Ok(Some(lag))
+        } else if let Ok(lagstr) = env::var("SYMBOLSERVER_READY_MAX_LAG") {
+            Ok(Some(lagstr.parse().chain_err(|| "Invalid value for ready max lag")?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Overrides the sync lag below which the server reports itself as ready.
+    pub fn set_server_ready_max_lag(&mut self, value: u32) {
+        self.server.ready_max_lag = Some(value);
+    }
+
+    /// Return how long idle connections are kept open for further requests
+    ///
+    /// `None` means that every connection is closed after one request.
+    pub fn get_server_keep_alive_timeout(&self) -> Result<Option<Duration>> {
+        let secs = if let Some(secs) = self.server.keep_alive_timeout {
+            secs
+        } else if let Ok(secsstr) = env::var("SYMBOLSERVER_KEEP_ALIVE_TIMEOUT") {
+            secsstr.parse().chain_err(|| "Invalid value for keep-alive timeout")?
+        } else {
+            return Ok(Some(Duration::seconds(5)));
+        };
+        if secs < 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "server.keep_alive_timeout",
+                "Keep-alive timeout cannot be negative").into());
+        }
+        Ok(if secs == 0 { None } else { Some(Duration::seconds(secs)) })
+    }
+
+    /// Return after how many requests a connection is closed
+    ///
+    /// `None` means that connections are kept open for any number of
+    /// requests.
+    pub fn get_server_max_requests_per_connection(&self) -> Result<Option<u64>> {
+        let max = if let Some(max) = self.server.max_requests_per_connection {
+            max
+        } else if let Ok(maxstr) = env::var("SYMBOLSERVER_MAX_REQUESTS_PER_CONNECTION") {
+            maxstr.parse().chain_err(|| "Invalid value for max requests per connection")?
+        } else {
+            return Ok(None);
+        };
+        Ok(if max == 0 { None } else { Some(max) })
+    }
+
+    /// Overrides after how many requests a connection is closed.
+    pub fn set_server_max_requests_per_connection(&mut self, value: u64) {
+        self.server.max_requests_per_connection = Some(value);
+    }
+
+    /// Return the port the gRPC service is served on, if any
+    ///
+    /// It listens on the same host as the HTTP API.
+    pub fn get_server_grpc_port(&self) -> Result<Option<u16>> {
+        if let Some(port) = self.server.grpc_port {
+            Ok(Some(port))
+        } else if let Ok(portstr) = env::var("SYMBOLSERVER_GRPC_PORT") {
+            Ok(Some(portstr.parse().chain_err(|| "Invalid value for gRPC port")?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Overrides the port the gRPC service is served on.
+    pub fn set_server_grpc_port(&mut self, value: u16) {
+        self.server.grpc_port = Some(value);
+    }
+
+    /// Return the API keys lookups are restricted to
+    ///
+    /// If there are none everybody may look symbols up in every SDK.
+    pub fn get_server_api_keys(&self) -> Result<&[ApiKey]> {
+        for (idx, api_key) in self.server.api_keys.iter().enumerate() {
+            if api_key.key.is_empty() {
+                return Err(ErrorKind::BadConfigKey(
+                    "server.api_keys", "API keys cannot be empty").into());
+            }
+            if self.server.api_keys[..idx].iter().any(|x| x.key == api_key.key) {
+                return Err(ErrorKind::BadConfigKey(
+                    "server.api_keys", "API keys have to be unique").into());
+            }
+        }
+        Ok(&self.server.api_keys)
+    }
+
+    /// Adds an API key that may look symbols up in the SDKs matching the
+    /// patterns.
+    pub fn add_server_api_key(&mut self, key: &str, sdks: &[&str]) -> Result<()> {
+        self.server.api_keys.push(ApiKey {
+            key: key.to_string(),
+            name: None,
+            sdks: IgnorePatterns::new(sdks)?,
+            admin: false,
+        });
+        Ok(())
+    }
+
+    /// Adds an API key that may use the admin endpoints and every SDK.
+    pub fn add_server_admin_key(&mut self, key: &str) -> Result<()> {
+        self.server.api_keys.push(ApiKey {
+            key: key.to_string(),
+            name: None,
+            sdks: IgnorePatterns::new(&["*"])?,
+            admin: true,
+        });
+        Ok(())
+    }
+
+    /// Return how clients of the lookup endpoints authenticate
+    pub fn get_server_auth_mode(&self) -> Result<AuthMode> {
+        let mode = self.server.auth.clone().or_else(|| env::var("SYMBOLSERVER_AUTH").ok());
+        let mode = match mode.as_ref().map(|x| x.as_str()) {
+            None | Some("api_keys") => AuthMode::ApiKeys,
+            Some("jwt") => AuthMode::Jwt,
+            Some("client_cert") => AuthMode::ClientCert,
+            Some(_) => {
+                return Err(ErrorKind::BadConfigKey(
+                    "server.auth", "Has to be api_keys, jwt or client_cert").into());
+            }
+        };
+        if mode == AuthMode::Jwt && self.get_server_jwt_secret().is_none() &&
+            self.get_server_jwks_url().is_none()
+        {
+            return Err(ErrorKind::BadConfigKey(
+                "server.jwt", "JWT authentication needs a secret or a jwks_url").into());
+        }
+        if mode == AuthMode::ClientCert && self.get_server_tls_client_ca().is_none() {
+            return Err(ErrorKind::BadConfigKey(
+                "server.tls.client_ca", "Client certificates need a CA to check them").into());
+        }
+        if mode == AuthMode::ClientCert && self.get_server_tls_cert().is_none() {
+            return Err(ErrorKind::BadConfigKey(
+                "server.tls.cert", "Client certificates need the server to serve TLS").into());
+        }
+        Ok(mode)
+    }
+
+    /// Overrides how clients authenticate.
+    pub fn set_server_auth_mode(&mut self, value: AuthMode) {
+        self.server.auth = Some(match value {
+            AuthMode::ApiKeys => "api_keys",
+            AuthMode::Jwt => "jwt",
+            AuthMode::ClientCert => "client_cert",
+        }.to_string());
+    }
+
+    /// Return the shared secret HS256 signed tokens are checked with
+    pub fn get_server_jwt_secret(&self) -> Option<String> {
+        self.server.jwt.secret.clone().or_else(|| env::var("SYMBOLSERVER_JWT_SECRET").ok())
+    }
+
+    /// Overrides the shared secret of tokens.
+    pub fn set_server_jwt_secret(&mut self, value: &str) {
+        self.server.jwt.secret = Some(value.to_string());
+    }
+
+    /// Return where the keys RS256 signed tokens are checked with are
+    /// published
+    pub fn get_server_jwks_url(&self) -> Option<String> {
+        self.server.jwt.jwks_url.clone().or_else(|| env::var("SYMBOLSERVER_JWKS_URL").ok())
+    }
+
+    /// Overrides where the keys of tokens are published.
+    pub fn set_server_jwks_url(&mut self, value: &str) {
+        self.server.jwt.jwks_url = Some(value.to_string());
+    }
+
+    /// Return the issuer tokens have to name, if any
+    pub fn get_server_jwt_issuer(&self) -> Option<String> {
+        self.server.jwt.issuer.clone().or_else(|| env::var("SYMBOLSERVER_JWT_ISSUER").ok())
+    }
+
+    /// Return the audience tokens have to be for, if any
+    pub fn get_server_jwt_audience(&self) -> Option<String> {
+        self.server.jwt.audience.clone()
+            .or_else(|| env::var("SYMBOLSERVER_JWT_AUDIENCE").ok())
+    }
+
+    /// Return whether tokens without an `exp` claim are rejected
+    pub fn get_server_jwt_require_exp(&self) -> Result<bool> {
+        if let Some(require) = self.server.jwt.require_exp {
+            Ok(require)
+        } else if let Ok(requirestr) = env::var("SYMBOLSERVER_JWT_REQUIRE_EXP") {
+            Ok(requirestr.parse().chain_err(|| "Invalid value for JWT require exp")?)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Overrides whether tokens need an `exp` claim.
+    pub fn set_server_jwt_require_exp(&mut self, value: bool) {
+        self.server.jwt.require_exp = Some(value);
+    }
+
+    /// Return the certificate chain the server presents, if it serves TLS
+    pub fn get_server_tls_cert(&self) -> Option<Cow<Path>> {
+        if let Some(ref path) = self.server.tls.cert {
+            Some(Cow::Borrowed(path.as_path()))
+        } else if let Ok(path) = env::var("SYMBOLSERVER_TLS_CERT") {
+            Some(Cow::Owned(PathBuf::from(path)))
+        } else {
+            None
+        }
+    }
+
+    /// Return the private key of the TLS certificate
+    pub fn get_server_tls_key(&self) -> Option<Cow<Path>> {
+        if let Some(ref path) = self.server.tls.key {
+            Some(Cow::Borrowed(path.as_path()))
+        } else if let Ok(path) = env::var("SYMBOLSERVER_TLS_KEY") {
+            Some(Cow::Owned(PathBuf::from(path)))
+        } else {
+            None
+        }
+    }
+
+    /// Return the CAs client certificates are checked against, if any
+    pub fn get_server_tls_client_ca(&self) -> Option<Cow<Path>> {
+        if let Some(ref path) = self.server.tls.client_ca {
+            Some(Cow::Borrowed(path.as_path()))
+        } else if let Ok(path) = env::var("SYMBOLSERVER_TLS_CLIENT_CA") {
+            Some(Cow::Owned(PathBuf::from(path)))
+        } else {
+            None
+        }
+    }
+
+    /// Return the common names of the client certificates that may use
+    /// the admin endpoints
+    pub fn get_server_tls_admin_clients(&self) -> &[String] {
+        &self.server.tls.admin_clients
+    }
+
+    /// Lets the client certificate with a common name use the admin
+    /// endpoints.
+    pub fn add_server_tls_admin_client(&mut self, common_name: &str) {
+        self.server.tls.admin_clients.push(common_name.to_string());
+    }
+
+    /// Makes the server serve TLS with a certificate chain and key (PEM),
+    /// checking client certificates against `client_ca` if given.
+    pub fn set_server_tls<P: AsRef<Path>>(&mut self, cert: P, key: P, client_ca: Option<P>) {
+        self.server.tls = TlsConfig {
+            cert: Some(cert.as_ref().to_path_buf()),
+            key: Some(key.as_ref().to_path_buf()),
+            client_ca: client_ca.map(|x| x.as_ref().to_path_buf()),
+            admin_clients: self.server.tls.admin_clients.clone(),
+        };
+    }
+
+    /// Return the private key lookup responses are signed with, if one
+    /// is configured
+    pub fn get_server_signing_key(&self) -> Option<Cow<Path>> {
+        if let Some(ref path) = self.server.signing_key {
+            Some(Cow::Borrowed(path.as_path()))
+        } else if let Ok(path) = env::var("SYMBOLSERVER_SIGNING_KEY") {
+            Some(Cow::Owned(PathBuf::from(path)))
+        } else {
+            None
+        }
+    }
+
+    /// Overrides the key lookup responses are signed with.
+    pub fn set_server_signing_key<P: AsRef<Path>>(&mut self, value: P) {
+        self.server.signing_key = Some(value.as_ref().to_path_buf());
+    }
+
+    /// Return the file lookups are recorded to for replaying, if any
+    pub fn get_server_record_path(&self) -> Option<Cow<Path>> {
+        if let Some(ref path) = self.server.record_path {
+            Some(Cow::Borrowed(path.as_path()))
+        } else if let Ok(path) = env::var("SYMBOLSERVER_RECORD_PATH") {
+            Some(Cow::Owned(PathBuf::from(path)))
+        } else {
+            None
+        }
+    }
+
+    /// Overrides the file lookups are recorded to.
+    pub fn set_server_record_path<P: AsRef<Path>>(&mut self, value: P) {
+        self.server.record_path = Some(value.as_ref().to_path_buf());
+    }
+
+    /// Return the share of lookups that are recorded
+    pub fn get_server_record_sample_rate(&self) -> Result<f64> {
+        let rate = if let Some(rate) = self.server.record_sample_rate {
+            rate
+        } else if let Ok(ratestr) = env::var("SYMBOLSERVER_RECORD_SAMPLE_RATE") {
+            ratestr.parse().chain_err(|| "Invalid value for record sample rate")?
+        } else {
+            return Ok(0.01);
+        };
+        if !(rate > 0.0 && rate <= 1.0) {
+            return Err(ErrorKind::BadConfigKey(
+                "server.record_sample_rate", "Rate has to be above 0 and at most 1").into());
+        }
+        Ok(rate)
+    }
+
+    /// Overrides the share of lookups that are recorded.
+    pub fn set_server_record_sample_rate(&mut self, value: f64) {
+        self.server.record_sample_rate = Some(value);
+    }
+
+    /// Return how large the file lookups are recorded to may grow in bytes
+    pub fn get_server_record_max_size(&self) -> Result<u64> {
+        let mb = if let Some(mb) = self.server.record_max_mb {
+            mb
+        } else if let Ok(mbstr) = env::var("SYMBOLSERVER_RECORD_MAX_MB") {
+            mbstr.parse().chain_err(|| "Invalid value for record max size")?
+        } else {
+            1024
+        };
+        Ok(mb * 1024 * 1024)
+    }
+
+    /// Overrides how many megabytes the file lookups are recorded to may
+    /// grow to.
+    pub fn set_server_record_max_mb(&mut self, value: u64) {
+        self.server.record_max_mb = Some(value);
+    }
+
     /// Return the server sync interval
     pub fn get_server_sync_interval(&self) -> Result<Duration> {
         let interval = if let Some(interval) = self.sync.interval {
@@ -225,17 +1146,369 @@ impl Config {
         Ok(Duration::seconds(interval))
     }
 
-    /// Return the number of threads to listen on
-    pub fn get_server_threads(&self) -> Result<usize> {
-        if let Some(threads) = self.server.threads {
-            Ok(threads)
-        } else if let Ok(threadstr) = env::var("SYMBOLSERVER_THREADS") {
-            Ok(threadstr.parse().chain_err(|| "Invalid value for thread count")?)
+    /// Return how long the server may go without a successful sync
+    /// before it reports itself as degraded
+    pub fn get_server_sync_max_staleness(&self) -> Result<Duration> {
+        let staleness = if let Some(staleness) = self.sync.max_staleness {
+            staleness
+        } else if let Ok(stalenessstr) = env::var("SYMBOLSERVER_SYNC_MAX_STALENESS") {
+            stalenessstr.parse().chain_err(|| "Invalid value for sync staleness")?
+        } else {
+            return Ok(Duration::minutes(30));
+        };
+        if staleness <= 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "sync.max_staleness", "Sync staleness has to be positive").into());
+        }
+        Ok(Duration::seconds(staleness))
+    }
+
+    /// Return how many sync reports are kept in the symbol directory
+    pub fn get_sync_keep_reports(&self) -> Result<usize> {
+        if let Some(keep) = self.sync.keep_reports {
+            Ok(keep)
+        } else if let Ok(keepstr) = env::var("SYMBOLSERVER_SYNC_KEEP_REPORTS") {
+            Ok(keepstr.parse().chain_err(|| "Invalid value for sync keep reports")?)
+        } else {
+            Ok(20)
+        }
+    }
+
+    /// Return how long a listing of the bucket is reused for the sync status
+    pub fn get_sync_listing_ttl(&self) -> Result<Duration> {
+        let ttl = if let Some(ttl) = self.sync.listing_ttl {
+            ttl
+        } else if let Ok(ttlstr) = env::var("SYMBOLSERVER_SYNC_LISTING_TTL") {
+            ttlstr.parse().chain_err(|| "Invalid value for sync listing ttl")?
+        } else {
+            return Ok(Duration::seconds(60));
+        };
+        if ttl <= 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "sync.listing_ttl", "Listing TTL has to be positive").into());
+        }
+        Ok(Duration::seconds(ttl))
+    }
+
+    /// Return the key of the upstream manifest relative to the bucket URL
+    pub fn get_sync_manifest(&self) -> Option<Cow<str>> {
+        if let Some(ref key) = self.sync.manifest {
+            Some(Cow::Borrowed(key.as_str()))
+        } else if let Ok(key) = env::var("SYMBOLSERVER_SYNC_MANIFEST") {
+            Some(Cow::Owned(key))
+        } else {
+            None
+        }
+    }
+
+    /// Overrides the key of the upstream manifest.
+    pub fn set_sync_manifest(&mut self, value: &str) {
+        self.sync.manifest = Some(value.to_string());
+    }
+
+    /// Return the URL of the server whose stash is followed instead of
+    /// syncing from S3
+    pub fn get_sync_primary(&self) -> Result<Option<Url>> {
+        let url = if let Some(ref value) = self.sync.primary {
+            Url::parse(value)?
+        } else if let Ok(value) = env::var("SYMBOLSERVER_SYNC_PRIMARY") {
+            Url::parse(&value)?
+        } else {
+            return Ok(None);
+        };
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(ErrorKind::BadConfigKey(
+                "sync.primary", "The primary has to be an http or https URL").into());
+        }
+        Ok(Some(url))
+    }
+
+    /// Overrides the server that is followed.
+    pub fn set_sync_primary(&mut self, value: &str) {
+        self.sync.primary = Some(value.to_string());
+    }
+
+    /// Return the API key or token sent to the primary, if it requires one
+    pub fn get_sync_primary_token(&self) -> Option<String> {
+        self.sync.primary_token.clone()
+            .or_else(|| env::var("SYMBOLSERVER_SYNC_PRIMARY_TOKEN").ok())
+    }
+
+    /// Overrides the token sent to the primary.
+    pub fn set_sync_primary_token(&mut self, value: &str) {
+        self.sync.primary_token = Some(value.to_string());
+    }
+
+    /// Return the public keys signatures of upstream SDKs are checked
+    /// against
+    pub fn get_sync_trusted_keys(&self) -> Vec<PathBuf> {
+        if let Some(ref keys) = self.sync.trusted_keys {
+            keys.clone()
+        } else if let Ok(keys) = env::var("SYMBOLSERVER_TRUSTED_KEYS") {
+            keys.split(',').map(|x| x.trim()).filter(|x| !x.is_empty())
+                .map(PathBuf::from).collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Adds a public key signatures of upstream SDKs are checked against.
+    pub fn add_sync_trusted_key<P: AsRef<Path>>(&mut self, value: P) {
+        self.sync.trusted_keys.get_or_insert_with(Vec::new).push(value.as_ref().to_path_buf());
+    }
+
+    /// Return whether SDKs without a valid signature are refused
+    pub fn get_sync_require_signatures(&self) -> Result<bool> {
+        let required = if let Some(required) = self.sync.require_signatures {
+            required
+        } else if let Ok(requiredstr) = env::var("SYMBOLSERVER_REQUIRE_SIGNATURES") {
+            requiredstr.parse().chain_err(|| "Invalid value for require signatures")?
+        } else {
+            false
+        };
+        if required && self.get_sync_trusted_keys().is_empty() {
+            return Err(ErrorKind::BadConfigKey(
+                "sync.require_signatures", "Signatures need sync.trusted_keys").into());
+        }
+        Ok(required)
+    }
+
+    /// Overrides whether SDKs without a valid signature are refused.
+    pub fn set_sync_require_signatures(&mut self, value: bool) {
+        self.sync.require_signatures = Some(value);
+    }
+
+    /// Return whether shared objects are only fetched when they are looked up
+    pub fn get_sync_lazy_objects(&self) -> Result<bool> {
+        if let Some(lazy) = self.sync.lazy_objects {
+            Ok(lazy)
+        } else if let Ok(lazystr) = env::var("SYMBOLSERVER_LAZY_OBJECTS") {
+            Ok(lazystr.parse().chain_err(|| "Invalid value for lazy objects")?)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Overrides whether shared objects are only fetched when they are
+    /// looked up.
+    pub fn set_sync_lazy_objects(&mut self, value: bool) {
+        self.sync.lazy_objects = Some(value);
+    }
+
+    /// Return whether SDKs are still looked up while a newer version of
+    /// them downloads
+    pub fn get_sync_serve_stale(&self) -> Result<bool> {
+        if let Some(serve_stale) = self.sync.serve_stale {
+            Ok(serve_stale)
+        } else if let Ok(stalestr) = env::var("SYMBOLSERVER_SERVE_STALE") {
+            Ok(stalestr.parse().chain_err(|| "Invalid value for serve stale")?)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Overrides whether SDKs are still looked up while a newer version of
+    /// them downloads.
+    pub fn set_sync_serve_stale(&mut self, value: bool) {
+        self.sync.serve_stale = Some(value);
+    }
+
+    /// Return how long a lookup waits for a shared object to be fetched
+    ///
+    /// With 0 the lookup does not wait and the object is fetched in the
+    /// background.
+    pub fn get_sync_object_fetch_budget(&self) -> Result<Duration> {
+        let ms = if let Some(ms) = self.sync.object_fetch_budget_ms {
+            ms
+        } else if let Ok(msstr) = env::var("SYMBOLSERVER_OBJECT_FETCH_BUDGET_MS") {
+            msstr.parse().chain_err(|| "Invalid value for object fetch budget")?
+        } else {
+            return Ok(Duration::milliseconds(500));
+        };
+        if ms < 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "sync.object_fetch_budget_ms", "Object fetch budget cannot be negative").into());
+        }
+        Ok(Duration::milliseconds(ms))
+    }
+
+    /// Overrides how long a lookup waits for a shared object in milliseconds.
+    pub fn set_sync_object_fetch_budget_ms(&mut self, value: i64) {
+        self.sync.object_fetch_budget_ms = Some(value);
+    }
+
+    /// Return how many of the most used shared objects a sync prefetches
+    pub fn get_sync_prefetch_objects(&self) -> Result<usize> {
+        if let Some(count) = self.sync.prefetch_objects {
+            Ok(count)
+        } else if let Ok(countstr) = env::var("SYMBOLSERVER_PREFETCH_OBJECTS") {
+            Ok(countstr.parse().chain_err(|| "Invalid value for prefetch objects")?)
+        } else {
+            Ok(100)
+        }
+    }
+
+    /// Return the number of threads that accept and handle requests
+    ///
+    /// Lookups mostly wait for pages of the mapped memdbs to be read from
+    /// disk, so by default there are twice as many as there are CPUs.
+    /// `server.threads` is the old name of this setting.
+    pub fn get_server_listener_threads(&self) -> Result<usize> {
+        let threads = if let Some(threads) = self.server.listener_threads.or(self.server.threads) {
+            threads
+        } else if let Ok(threadstr) = env::var("SYMBOLSERVER_LISTENER_THREADS")
+            .or_else(|_| env::var("SYMBOLSERVER_THREADS")) {
+            threadstr.parse().chain_err(|| "Invalid value for listener thread count")?
+        } else {
+            return Ok(num_cpus::get() * 2);
+        };
+        if threads == 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "server.listener_threads", "Thread count has to be positive").into());
+        }
+        Ok(threads)
+    }
+
+    /// Overrides the number of listener threads.
+    pub fn set_server_listener_threads(&mut self, value: usize) {
+        self.server.listener_threads = Some(value);
+    }
+
+    /// Return the number of threads for CPU bound work such as verifying
+    /// memdbs (one per CPU by default)
+    pub fn get_server_worker_threads(&self) -> Result<usize> {
+        let threads = if let Some(threads) = self.server.worker_threads {
+            threads
+        } else if let Ok(threadstr) = env::var("SYMBOLSERVER_WORKER_THREADS") {
+            threadstr.parse().chain_err(|| "Invalid value for worker thread count")?
+        } else {
+            return Ok(num_cpus::get());
+        };
+        if threads == 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "server.worker_threads", "Thread count has to be positive").into());
+        }
+        Ok(threads)
+    }
+
+    /// Overrides the number of worker threads.
+    pub fn set_server_worker_threads(&mut self, value: usize) {
+        self.server.worker_threads = Some(value);
+    }
+
+    /// Return the number of threads for work that blocks on disk such as
+    /// opening memdbs (twice the number of CPUs by default)
+    pub fn get_server_io_threads(&self) -> Result<usize> {
+        let threads = if let Some(threads) = self.server.io_threads {
+            threads
+        } else if let Ok(threadstr) = env::var("SYMBOLSERVER_IO_THREADS") {
+            threadstr.parse().chain_err(|| "Invalid value for IO thread count")?
+        } else {
+            return Ok(num_cpus::get() * 2);
+        };
+        if threads == 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "server.io_threads", "Thread count has to be positive").into());
+        }
+        Ok(threads)
+    }
+
+    /// Overrides the number of IO threads.
+    pub fn set_server_io_threads(&mut self, value: usize) {
+        self.server.io_threads = Some(value);
+    }
+
+    /// Return the address (`host:port`) of the statsd server metrics are
+    /// sent to if there is one
+    pub fn get_statsd_addr(&self) -> Option<Cow<str>> {
+        if let Some(ref addr) = self.statsd.addr {
+            Some(Cow::Borrowed(addr.as_str()))
+        } else if let Ok(addr) = env::var("SYMBOLSERVER_STATSD_ADDR") {
+            Some(Cow::Owned(addr))
+        } else {
+            None
+        }
+    }
+
+    /// Return the prefix of all statsd metrics
+    pub fn get_statsd_prefix(&self) -> Cow<str> {
+        if let Some(ref prefix) = self.statsd.prefix {
+            Cow::Borrowed(prefix.as_str())
+        } else if let Ok(prefix) = env::var("SYMBOLSERVER_STATSD_PREFIX") {
+            Cow::Owned(prefix)
+        } else {
+            Cow::Borrowed("symbolserver")
+        }
+    }
+
+    /// Return the tags (`key:value`) sent with every statsd metric
+    pub fn get_statsd_tags(&self) -> Vec<String> {
+        if let Some(ref tags) = self.statsd.tags {
+            tags.clone()
+        } else if let Ok(tags) = env::var("SYMBOLSERVER_STATSD_TAGS") {
+            tags.split(',').map(|x| x.trim()).filter(|x| !x.is_empty())
+                .map(|x| x.to_string()).collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Return the interval in which the stash gauges are sent to statsd
+    pub fn get_statsd_interval(&self) -> Result<Duration> {
+        let interval = if let Some(interval) = self.statsd.interval {
+            interval
+        } else if let Ok(intervalstr) = env::var("SYMBOLSERVER_STATSD_INTERVAL") {
+            intervalstr.parse().chain_err(|| "Invalid value for statsd interval")?
+        } else {
+            return Ok(Duration::seconds(10));
+        };
+        if interval <= 0 {
+            return Err(ErrorKind::BadConfigKey(
+                "statsd.interval", "Statsd interval has to be positive").into());
+        }
+        Ok(Duration::seconds(interval))
+    }
+
+    /// Overrides the address of the statsd server.
+    pub fn set_statsd_addr(&mut self, value: &str) {
+        self.statsd.addr = Some(value.to_string());
+    }
+
+    /// Return the folder `convert-sdk` writes to unless `--output` is
+    /// given, if one is configured
+    pub fn get_convert_output_dir(&self) -> Option<Cow<Path>> {
+        if let Some(ref path) = self.convert.output_dir {
+            Some(Cow::Borrowed(path.as_path()))
+        } else if let Ok(dir) = env::var("SYMBOLSERVER_CONVERT_OUTPUT_DIR") {
+            Some(Cow::Owned(PathBuf::from(dir)))
         } else {
-            Ok(num_cpus::get() * 5 / 4)
+            None
         }
     }
 
+    /// Return the template `convert-sdk` names memdb files by, if one is
+    /// configured (see `SdkInfo::format_filename`)
+    pub fn get_convert_filename_template(&self) -> Result<Option<String>> {
+        let template = if let Some(ref template) = self.convert.filename_template {
+            template.clone()
+        } else if let Ok(template) = env::var("SYMBOLSERVER_CONVERT_FILENAME_TEMPLATE") {
+            template
+        } else {
+            return Ok(None);
+        };
+        let example = SdkInfo::new("iOS", 10, 2, 0, Some("14C92"));
+        if example.format_filename(&template, false).is_none() {
+            return Err(ErrorKind::BadConfigKey(
+                "convert.filename_template", "Unknown placeholder in filename template").into());
+        }
+        Ok(Some(template))
+    }
+
+    /// Overrides the template `convert-sdk` names memdb files by.
+    pub fn set_convert_filename_template(&mut self, value: &str) {
+        self.convert.filename_template = Some(value.to_string());
+    }
+
     /// Return the log level filter
     pub fn get_log_level_filter(&self) -> Result<LogLevelFilter> {
         let level_opt = self.log.level
@@ -271,4 +1544,68 @@ impl Config {
     pub fn get_ignore_patterns(&self) -> Result<&IgnorePatterns> {
         Ok(&self.sync.ignore)
     }
+
+    /// Return the names of the tenants served next to the main stash
+    pub fn get_tenant_names(&self) -> Vec<&str> {
+        self.tenants.keys().map(|x| x.as_str()).collect()
+    }
+
+    /// Return the config of a tenant
+    ///
+    /// A tenant is configured like the main stash except for its bucket,
+    /// region, symbol directory and ignore patterns.  If the main stash
+    /// follows a primary the tenant follows the tenant of the same name
+    /// on the primary.
+    pub fn get_tenant_config(&self, name: &str) -> Result<Config> {
+        let tenant = match self.tenants.get(name) {
+            Some(tenant) => tenant,
+            None => { return Err(ErrorKind::UnknownTenant(name.to_string()).into()); }
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() ||
+                                                     c == '-' || c == '_') {
+            return Err(ErrorKind::BadConfigKey(
+                "tenants", "Tenant names may only contain letters, digits, - and _").into());
+        }
+        let symbol_dir = match tenant.symbol_dir {
+            Some(ref path) => path.clone(),
+            None => { return Err(ErrorKind::MissingConfigKey("tenants.symbol_dir").into()); }
+        };
+        if let Ok(main_dir) = self.get_symbol_dir() {
+            if *main_dir == *symbol_dir {
+                return Err(ErrorKind::BadConfigKey(
+                    "tenants.symbol_dir", "Tenants need a symbol directory of their own").into());
+            }
+        }
+
+        let mut rv = self.clone();
+        rv.tenants.clear();
+        if let Some(ref bucket_url) = tenant.bucket_url {
+            rv.aws.bucket_url = Some(bucket_url.clone());
+        }
+        if let Some(ref region) = tenant.region {
+            rv.aws.region = Some(region.clone());
+        }
+        // the scratch folder is cleaned up per stash and cannot be shared
+        rv.stash.scratch_dir = Some(symbol_dir.join("scratch"));
+        rv.symbol_dir = Some(symbol_dir);
+        rv.sync.ignore = tenant.ignore.clone();
+        if let Some(mut primary) = self.get_sync_primary()? {
+            let path = format!("{}/tenants/{}/", primary.path().trim_end_matches('/'), name);
+            primary.set_path(&path);
+            rv.sync.primary = Some(primary.to_string());
+        }
+        let mut tags = self.get_statsd_tags();
+        tags.push(format!("tenant:{}", name));
+        rv.statsd.tags = Some(tags);
+        Ok(rv)
+    }
+
+    /// Adds a tenant with its own bucket and symbol directory.
+    pub fn add_tenant<P: AsRef<Path>>(&mut self, name: &str, bucket_url: &str, symbol_dir: P) {
+        self.tenants.insert(name.to_string(), TenantConfig {
+            bucket_url: Some(bucket_url.to_string()),
+            symbol_dir: Some(symbol_dir.as_ref().to_path_buf()),
+            ..Default::default()
+        });
+    }
 }
@@ -1,4 +1,47 @@
 //! Provides some useful constants
 
+use chrono::{TimeZone, Utc};
+
 /// The version of the library
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// The git commit the library was built from
+///
+/// The build script asks git unless `SYMBOLSERVER_GIT_COMMIT` is set.
+pub const GIT_COMMIT: Option<&'static str> = option_env!("SYMBOLSERVER_GIT_COMMIT");
+
+/// When the library was built as seconds since the epoch
+const BUILD_TIMESTAMP: Option<&'static str> = option_env!("SYMBOLSERVER_BUILD_TIMESTAMP");
+
+/// The version of the compiler the library was built with
+pub const RUSTC_VERSION: Option<&'static str> = option_env!("SYMBOLSERVER_RUSTC_VERSION");
+
+/// Returns when the library was built as unix timestamp.
+pub fn get_build_timestamp() -> Option<i64> {
+    BUILD_TIMESTAMP?.parse().ok()
+}
+
+/// Describes the build in one line for `--version --verbose` and logs.
+pub fn get_build_description() -> String {
+    format!("{} (commit {}, built {}, {})",
+            VERSION,
+            GIT_COMMIT.unwrap_or("unknown"),
+            get_build_timestamp().map_or_else(|| "at an unknown time".to_string(),
+                                              |x| Utc.timestamp(x, 0).to_rfc3339()),
+            RUSTC_VERSION.unwrap_or("unknown rustc"))
+}
+
+/// Returns the cargo features the library was built with.
+pub fn get_features() -> Vec<&'static str> {
+    let mut rv = vec![];
+    if cfg!(feature = "server") { rv.push("server"); }
+    if cfg!(feature = "cli") { rv.push("cli"); }
+    if cfg!(feature = "jemalloc") { rv.push("jemalloc"); }
+    if cfg!(feature = "mimalloc") { rv.push("mimalloc"); }
+    if cfg!(feature = "ffi") { rv.push("ffi"); }
+    if cfg!(feature = "python") { rv.push("python"); }
+    if cfg!(feature = "crypto") { rv.push("crypto"); }
+    if cfg!(feature = "grpc") { rv.push("grpc"); }
+    if cfg!(feature = "testing") { rv.push("testing"); }
+    rv
+}
@@ -0,0 +1,186 @@
+//! Downloading SDKs for conversion.
+//!
+//! `convert-sdk` takes URLs of zipped support folders next to local paths,
+//! so that build machines do not have to stage the inputs first.  The zip
+//! is downloaded into the scratch folder and converted from there like a
+//! local one.  An interrupted download is kept and the next attempt asks
+//! the server for the rest only, if the file did not change meanwhile.
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use hyper::header::{ByteRangeSpec, ContentLength, ETag, Headers, IfRange, LastModified, Range};
+use hyper::status::StatusCode;
+use indicatif::ProgressStyle;
+use md5;
+use url::Url;
+use url::percent_encoding::percent_decode;
+
+use super::{ErrorKind, Result, ResultExt};
+use super::report::Reporter;
+use super::s3::new_hyper_client;
+use super::sdk::SdkInfo;
+use super::utils::copy_with_progress;
+
+/// Parses an input of `convert-sdk` as URL if it is one.
+pub fn parse_sdk_url(value: &str) -> Option<Url> {
+    Url::parse(value).ok().and_then(|url| match url.scheme() {
+        "http" | "https" => Some(url),
+        _ => None,
+    })
+}
+
+/// The folder below the scratch folder a URL is downloaded into.
+///
+/// It only depends on the URL so that another attempt finds the part that
+/// was downloaded already.
+pub fn get_download_dir(scratch_dir: &Path, url: &Url) -> PathBuf {
+    scratch_dir.join("downloads").join(format!("{:x}", md5::compute(url.as_str())))
+}
+
+/// A zipped support folder that was downloaded for conversion.
+pub struct DownloadedSdk {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl DownloadedSdk {
+    /// Downloads a zipped support folder into the scratch folder.
+    ///
+    /// The last two segments of the URL have to name the SDK like the path
+    /// of a local zip does (`.../iOS%20DeviceSupport/10.2%20(14C92).zip`).
+    pub fn fetch(url: &Url, scratch_dir: &Path, reporter: &Reporter) -> Result<DownloadedSdk> {
+        let segments: Vec<String> = url.path_segments()
+            .map(|segments| segments.map(|x| {
+                percent_decode(x.as_bytes()).decode_utf8_lossy().into_owned()
+            }).collect())
+            .unwrap_or_default();
+        if segments.len() < 2 {
+            return Err(format!("{} does not name an SDK", url).into());
+        }
+        // the segments end up in a path
+        if segments.iter().any(|x| x.is_empty() || x == "." || x.contains("..") ||
+                                    x.contains('/') || x.contains('\0')) {
+            return Err(format!("{} has path segments that are not allowed", url).into());
+        }
+        let name = Path::new(&segments[segments.len() - 2]).join(&segments[segments.len() - 1]);
+        if SdkInfo::from_path(&name).is_none() {
+            return Err(format!("{} does not name an SDK", url).into());
+        }
+
+        let dir = get_download_dir(scratch_dir, url);
+        let path = dir.join(&name);
+        if path.is_file() {
+            reporter.detail(&format!("Using the earlier download of {}", url));
+        } else {
+            fs::create_dir_all(path.parent().unwrap())?;
+            download(url, &path, reporter)?;
+        }
+        Ok(DownloadedSdk { dir, path })
+    }
+
+    /// The path of the downloaded zip
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes the download once the SDK is converted.
+    pub fn remove(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
+
+/// Reads what the partial download was validated with when it started.
+fn read_validator(path: &Path) -> Option<IfRange> {
+    let mut contents = String::new();
+    File::open(path).ok()?.read_to_string(&mut contents).ok()?;
+    let mut parts = contents.trim().splitn(2, ' ');
+    match (parts.next()?, parts.next()?) {
+        ("etag", value) => value.parse().ok().map(IfRange::EntityTag),
+        ("date", value) => value.parse().ok().map(IfRange::Date),
+        _ => None,
+    }
+}
+
+/// Returns what identifies the version of a file the server sends.
+///
+/// Weak ETags cannot be used to resume downloads.
+fn get_validator(headers: &Headers) -> Option<String> {
+    match headers.get::<ETag>() {
+        Some(&ETag(ref tag)) if !tag.weak => Some(format!("etag {}", tag)),
+        _ => headers.get::<LastModified>().map(|x| format!("date {}", x.0)),
+    }
+}
+
+/// Downloads a URL to a path, resuming from `<path>.part`.
+///
+/// The ETag or modification time of the file is kept in
+/// `<path>.part.validator` and sent along when resuming, so servers send
+/// the whole file again if it changed.  Without either downloads are not
+/// resumed.
+fn download(url: &Url, path: &Path, reporter: &Reporter) -> Result<()> {
+    let mut part = path.to_path_buf().into_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let mut validator_path = part.clone().into_os_string();
+    validator_path.push(".validator");
+    let validator_path = PathBuf::from(validator_path);
+    let mut offset = fs::metadata(&part).map(|x| x.len()).unwrap_or(0);
+
+    let mut headers = Headers::new();
+    match read_validator(&validator_path) {
+        Some(validator) if offset > 0 => {
+            headers.set(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+            headers.set(validator);
+        }
+        _ => offset = 0,
+    }
+    let mut resp = new_hyper_client()?.get(url.as_str()).headers(headers).send()
+        .chain_err(|| format!("Could not download {}", url))?;
+    // servers that do not understand ranges and servers whose file changed
+    // send everything again
+    let (mut file, offset) = match resp.status {
+        StatusCode::PartialContent if offset > 0 => {
+            reporter.detail(&format!("Resuming the download of {} after {} bytes",
+                                     url, offset));
+            (OpenOptions::new().append(true).open(&part)?, offset)
+        }
+        StatusCode::Ok => {
+            reporter.detail(&format!("Downloading {}", url));
+            let file = File::create(&part)?;
+            match get_validator(&resp.headers) {
+                Some(validator) => File::create(&validator_path)?
+                    .write_all(validator.as_bytes())?,
+                None => if validator_path.is_file() {
+                    fs::remove_file(&validator_path)?;
+                },
+            }
+            (file, 0)
+        }
+        status => {
+            return Err(format!("Could not download {}: {}", url, status).into());
+        }
+    };
+
+    let expected = resp.headers.get::<ContentLength>().map(|x| x.0);
+    let pb = reporter.progress_bar(offset + expected.unwrap_or(0));
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{wide_bar} {bytes}/{total_bytes}"));
+    pb.set_position(offset);
+    let result = copy_with_progress(&pb, &mut resp, &mut file);
+    pb.finish_and_clear();
+    drop(file);
+    match result {
+        Ok(received) if expected.map_or(true, |x| x == received) => {}
+        _ => {
+            let received = fs::metadata(&part).map(|x| x.len()).unwrap_or(0);
+            return Err(ErrorKind::DownloadInterrupted(url.to_string(), received).into());
+        }
+    }
+    fs::rename(&part, path)?;
+    if validator_path.is_file() {
+        fs::remove_file(&validator_path)?;
+    }
+    Ok(())
+}
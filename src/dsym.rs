@@ -4,17 +4,22 @@
 //! limited set of debug symbols from it.  This is exclusively used for
 //! system symbols from iOS SDKs and similar where actual DWARF info is
 //! not contained, just symbol tabs.
+use std::fmt;
 use std::io::Cursor;
 use std::path::Path;
 use std::borrow::Cow;
+use std::str::FromStr;
 
 use memmap;
 use uuid::Uuid;
 use mach_object::{OFile, Symbol, Section, SymbolIter, SymbolReader, DyLib,
-    LoadCommand, MachCommand, get_arch_name_from_types, get_arch_from_flag,
+    LinkEditData, LoadCommand, MachCommand, get_arch_name_from_types, get_arch_from_flag,
     SEG_TEXT, SECT_TEXT, cpu_type_t, cpu_subtype_t};
 
 use super::{Result, Error, ErrorKind};
+use super::image::{MachImage, Segment};
+use super::objc::ObjcImage;
+use super::swift::SwiftImage;
 
 
 enum Backing<'a> {
@@ -45,6 +50,24 @@ pub struct Symbols<'a> {
     cursor: Cursor<&'a [u8]>,
 }
 
+/// Where the name of a symbol came from
+///
+/// DWARF and the export trie are deliberately not sources: the binaries of
+/// SDKs carry no DWARF, and the exports of an image are also in its symbol
+/// table.  Memdbs only have room for three recovered sources next to the
+/// symbol table, so a new one would need a new format version.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize, Hash)]
+pub enum SymbolSource {
+    #[serde(rename = "symtab")]
+    SymbolTable,
+    #[serde(rename = "objc")]
+    Objc,
+    #[serde(rename = "swift")]
+    Swift,
+    #[serde(rename = "function_starts")]
+    FunctionStarts,
+}
+
 /// Represents a variant in an object
 pub struct Variant {
     cputype: cpu_type_t,
@@ -55,6 +78,64 @@ pub struct Variant {
     vmsize: u64,
 }
 
+impl SymbolSource {
+    /// All sources in the default order of priority.
+    pub fn all() -> Vec<SymbolSource> {
+        vec![
+            SymbolSource::SymbolTable,
+            SymbolSource::Objc,
+            SymbolSource::Swift,
+            SymbolSource::FunctionStarts,
+        ]
+    }
+
+    /// Converts the source into the value stored in memdb index items.
+    pub fn to_u32(self) -> u32 {
+        match self {
+            SymbolSource::SymbolTable => 0,
+            SymbolSource::Objc => 1,
+            SymbolSource::Swift => 2,
+            SymbolSource::FunctionStarts => 3,
+        }
+    }
+
+    /// Converts a value stored in memdb index items back into a source.
+    pub fn from_u32(value: u32) -> Option<SymbolSource> {
+        match value {
+            0 => Some(SymbolSource::SymbolTable),
+            1 => Some(SymbolSource::Objc),
+            2 => Some(SymbolSource::Swift),
+            3 => Some(SymbolSource::FunctionStarts),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SymbolSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            SymbolSource::SymbolTable => "symtab",
+            SymbolSource::Objc => "objc",
+            SymbolSource::Swift => "swift",
+            SymbolSource::FunctionStarts => "function_starts",
+        })
+    }
+}
+
+impl FromStr for SymbolSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SymbolSource> {
+        match s {
+            "symtab" => Ok(SymbolSource::SymbolTable),
+            "objc" => Ok(SymbolSource::Objc),
+            "swift" => Ok(SymbolSource::Swift),
+            "function_starts" => Ok(SymbolSource::FunctionStarts),
+            _ => Err(Error::from(format!("Unknown symbol source '{}'", s))),
+        }
+    }
+}
+
 impl<'a> Symbols<'a> {
     /// Returns the architecture for these symbols
     pub fn arch(&self) -> &str {
@@ -233,6 +314,103 @@ impl<'a> Object<'a> {
 
         return Err(ErrorKind::MissingArchitecture(arch.to_string()).into());
     }
+
+    /// Returns symbols recovered from the Objective-C and Swift metadata
+    /// of an architecture by absolute address with their source.
+    ///
+    /// See the `objc` and `swift` modules for what is recovered.  These are
+    /// followed by a `sub_<addr>` symbol for every function start listed in
+    /// `LC_FUNCTION_STARTS` so that addresses in functions without a name
+    /// are not attributed to the function before them.
+    pub fn recovered_symbols(&'a self, arch: &str)
+        -> Result<Vec<(u64, String, SymbolSource)>>
+    {
+        let symbols = self.symbols(arch)?;
+        let commands = match *symbols.ofile {
+            OFile::MachFile { ref commands, .. } => commands,
+            _ => { return Ok(vec![]); }
+        };
+        let mut is_64 = false;
+        let mut segments = vec![];
+        let mut classlist = None;
+        let mut swift_types = None;
+        let mut text_vmaddr = 0;
+        let mut function_starts = None;
+        for &MachCommand(ref load_cmd, _) in commands {
+            if let LoadCommand::FunctionStarts(LinkEditData { off, size }) = *load_cmd {
+                function_starts = Some((off as usize, size as usize));
+            }
+            let (vmaddr, vmsize, fileoff, filesize, sections) = match *load_cmd {
+                LoadCommand::Segment { vmaddr, vmsize, fileoff, filesize, ref sections, .. } => {
+                    (vmaddr, vmsize, fileoff, filesize, sections)
+                }
+                LoadCommand::Segment64 { vmaddr, vmsize, fileoff, filesize, ref sections,
+                                         .. } => {
+                    is_64 = true;
+                    (vmaddr, vmsize, fileoff, filesize, sections)
+                }
+                _ => continue,
+            };
+            if fileoff == 0 && filesize != 0 {
+                text_vmaddr = vmaddr as u64;
+            }
+            segments.push(Segment {
+                vmaddr: vmaddr as u64,
+                vmsize: vmsize as u64,
+                fileoff: fileoff as u64,
+                filesize: filesize as u64,
+            });
+            for sect in sections {
+                if sect.sectname == "__objc_classlist" {
+                    classlist = Some((sect.addr as u64, sect.size as u64));
+                } else if sect.sectname == "__swift5_types" {
+                    swift_types = Some((sect.addr as u64, sect.size as u64));
+                }
+            }
+        }
+        let data = *symbols.cursor.get_ref();
+        let image = MachImage::new(data, is_64, segments);
+        let mut rv: Vec<_> = ObjcImage::new(image.clone(), classlist).symbols()
+            .into_iter()
+            .map(|(addr, sym)| (addr, sym, SymbolSource::Objc))
+            .collect();
+        rv.extend(SwiftImage::new(image, swift_types).symbols().into_iter()
+            .map(|(addr, sym)| (addr, sym, SymbolSource::Swift)));
+        if let Some((off, size)) = function_starts {
+            if let Some(starts) = data.get(off..off.saturating_add(size)) {
+                rv.extend(parse_function_starts(starts, text_vmaddr).into_iter()
+                    .map(|addr| (addr, format!("sub_{:x}", addr), SymbolSource::FunctionStarts)));
+            }
+        }
+        Ok(rv)
+    }
+}
+
+/// Decodes the ULEB128 encoded deltas of `LC_FUNCTION_STARTS` into
+/// addresses.  The first delta is relative to the start of the image and
+/// a zero delta ends the list.
+fn parse_function_starts(data: &[u8], base: u64) -> Vec<u64> {
+    let mut rv = vec![];
+    let mut addr = base;
+    let mut delta = 0u64;
+    let mut shift = 0;
+    for &byte in data {
+        if shift < 64 {
+            delta |= u64::from(byte & 0x7f) << shift;
+        }
+        shift += 7;
+        if byte & 0x80 != 0 {
+            continue;
+        }
+        if delta == 0 {
+            break;
+        }
+        addr = addr.wrapping_add(delta);
+        rv.push(addr);
+        delta = 0;
+        shift = 0;
+    }
+    rv
 }
 
 impl Variant {
@@ -266,3 +444,9 @@ impl Variant {
         self.vmsize
     }
 }
+
+#[test]
+fn test_parse_function_starts() {
+    assert_eq!(parse_function_starts(&[0x80, 0x20, 0x10, 0x84, 0x01, 0x00, 0x08], 0x1000),
+               vec![0x2000, 0x2010, 0x2094]);
+}
@@ -3,6 +3,7 @@ use std::io;
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 
+#[cfg(feature = "server")]
 use super::api::types::ApiError;
 
 use mach_object;
@@ -12,6 +13,8 @@ use serde_yaml;
 use serde_xml;
 use url;
 use hyper;
+#[cfg(feature = "crypto")]
+use openssl;
 
 
 error_chain! {
@@ -33,6 +36,68 @@ error_chain! {
         BadMemDb {
             description("bad memdb file")
         }
+        ChecksumMismatch(expected: String, actual: String) {
+            description("checksum mismatch")
+            display("checksum mismatch: expected {}, got {}", expected, actual)
+        }
+        SdkWithdrawn(sdk_id: String, reason: String) {
+            description("sdk was withdrawn")
+            display("sdk {} was withdrawn: {}", sdk_id, reason)
+        }
+        BadSignature(sdk_id: String, msg: &'static str) {
+            description("bad signature")
+            display("bad signature for sdk {}: {}", sdk_id, msg)
+        }
+        UnknownEncryptionKey(key_id: String) {
+            description("memdb is encrypted with an unknown key")
+            display("memdb is encrypted with the unknown key {}", key_id)
+        }
+        MissingObjectManifest(sdk_id: String) {
+            description("thin sdk without object manifest")
+            display("thin sdk {} has no object manifest", sdk_id)
+        }
+        InvalidOsVersion(os_version: String) {
+            description("invalid os version")
+            display("cannot tell the SDK of os version '{}'", os_version)
+        }
+        AddressBeforeImage(addr: u64, image_addr: u64) {
+            description("address before image")
+            display("address {:#x} lies before the image loaded at {:#x}", addr, image_addr)
+        }
+        MissingSharedObject(uuid: String) {
+            description("missing shared object")
+            display("shared object {} is missing", uuid)
+        }
+        SandboxFailure(filename: String, msg: String) {
+            description("object conversion failed in the sandbox")
+            display("could not convert {} in the sandbox: {}", filename, msg)
+        }
+        MemDbTooLarge(size: u64, max_size: u64) {
+            description("memdb too large")
+            display("memdb of {} bytes does not fit into {} bytes even when trimmed",
+                    size, max_size)
+        }
+        TruncatedMemDb(expected: u64, actual: u64) {
+            description("truncated memdb")
+            display("truncated memdb: expected {} bytes, got {}", expected, actual)
+        }
+        SdkUpdating(sdk_id: String) {
+            description("sdk is being updated")
+            display("sdk {} is being updated", sdk_id)
+        }
+        TooManyOpenMemDbs(limit: usize) {
+            description("too many open memdbs")
+            display("too many open memdbs: all {} memdbs are in use", limit)
+        }
+        ObjectFetchPending(uuid: String) {
+            description("shared object is being fetched")
+            display("shared object {} is being fetched", uuid)
+        }
+        InsufficientSpace(path: String, needed: u64, available: u64) {
+            description("not enough free disk space")
+            display("not enough free disk space in {}: need {} bytes, {} available",
+                    path, needed, available)
+        }
         ConfigError(err: serde_yaml::Error) {
             description("failed to load config file")
             display("failed to load config file: {}", err)
@@ -49,10 +114,48 @@ error_chain! {
             description("bad environment variable")
             display("bad environment variable '{}': {}", path, msg)
         }
+        UnknownTenant(name: String) {
+            description("unknown tenant")
+            display("unknown tenant: '{}'", name)
+        }
+        SyncCancelled(done: usize, remaining: usize) {
+            description("sync cancelled")
+            display("sync cancelled after {} SDKs with {} left to download", done, remaining)
+        }
+        StashLocked(path: String) {
+            description("the stash is locked by another process")
+            display("the stash in {} is locked by another process", path)
+        }
+        ReadOnlyStash {
+            description("the stash is read-only")
+            display("the stash is read-only (stash.read_only is set)")
+        }
+        SyncStateConflict {
+            description("the sync state was modified by another process")
+        }
         S3Unavailable(msg: String) {
             description("S3 is unavailable")
             display("S3 is unavailable: {}", msg)
         }
+        S3ClockSkew {
+            description("S3 rejected the request because the clock is skewed")
+        }
+        QuotaExceeded(msg: String) {
+            description("S3 request quota exceeded")
+            display("S3 request quota exceeded: {}", msg)
+        }
+        DownloadInterrupted(filename: String, received: u64) {
+            description("download interrupted")
+            display("download of {} was interrupted after {} bytes", filename, received)
+        }
+        S3IncompleteListing(msg: String) {
+            description("S3 returned an incomplete listing")
+            display("S3 returned an incomplete listing: {}", msg)
+        }
+        PrimaryUnavailable(msg: String) {
+            description("the primary server is unavailable")
+            display("the primary server is unavailable: {}", msg)
+        }
     }
 
     foreign_links {
@@ -66,6 +169,26 @@ error_chain! {
         XmlError(serde_xml::Error);
         UrlParseError(url::ParseError);
         WebError(hyper::Error);
-        ApiError(ApiError);
+        SslError(openssl::error::ErrorStack) #[cfg(feature = "crypto")];
+        ApiError(ApiError) #[cfg(feature = "server")];
+    }
+}
+
+impl Error {
+    /// Returns `true` if the error means that S3 (or the primary server
+    /// that is followed instead) cannot be used right now.
+    ///
+    /// These errors are temporary: the stash keeps serving what it has and
+    /// the next sync tries again.
+    pub fn is_s3_offline(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::S3Unavailable(_) |
+            ErrorKind::S3ClockSkew |
+            ErrorKind::S3IncompleteListing(_) |
+            ErrorKind::QuotaExceeded(_) |
+            ErrorKind::DownloadInterrupted(..) |
+            ErrorKind::PrimaryUnavailable(_) => true,
+            _ => false,
+        }
     }
 }
@@ -0,0 +1,316 @@
+//! A C API for looking up symbols in a local stash.
+//!
+//! This lets native crash processors resolve frames in-process against
+//! the same stash a symbol server synchronizes.  The matching header is
+//! `include/symbolserver.h`; it is generated with `cbindgen` from this
+//! module so the two must be kept in sync.
+//!
+//! All functions catch panics.  Errors are reported through an optional
+//! `err_out` parameter which receives a string that has to be released
+//! with `symbolserver_err_free`.
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic;
+use std::ptr;
+
+use super::config::Config;
+use super::memdb::stash::MemDbStash;
+use super::utils::parse_uuid;
+use super::{Result, Error, ErrorKind};
+
+/// An opened stash.
+pub struct SymbolServerStash {
+    stash: MemDbStash,
+}
+
+/// A resolved symbol.
+#[repr(C)]
+pub struct SymbolServerSymbol {
+    /// The uuid of the object as raw bytes.
+    pub object_uuid: [u8; 16],
+    /// The name of the object containing the symbol.
+    pub object_name: *mut c_char,
+    /// The name of the symbol.
+    pub symbol: *mut c_char,
+    /// The address of the symbol.
+    pub addr: u64,
+}
+
+unsafe fn cstr_to_str<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(Error::from("unexpected null pointer"));
+    }
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+fn string_to_cstr(s: &str) -> *mut c_char {
+    // symbol names cannot contain nul bytes but better be safe
+    CString::new(s.replace('\0', "")).unwrap().into_raw()
+}
+
+unsafe fn set_error(err_out: *mut *mut c_char, err: &Error) {
+    if !err_out.is_null() {
+        *err_out = string_to_cstr(&err.to_string());
+    }
+}
+
+/// Runs a function, converting errors and panics into a null return.
+unsafe fn landingpad<T, F>(err_out: *mut *mut c_char, f: F) -> *mut T
+    where F: FnOnce() -> Result<*mut T>
+{
+    if !err_out.is_null() {
+        *err_out = ptr::null_mut();
+    }
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(Ok(rv)) => rv,
+        Ok(Err(err)) => {
+            set_error(err_out, &err);
+            ptr::null_mut()
+        }
+        Err(_) => {
+            set_error(err_out, &Error::from("symbolserver panicked"));
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe fn opt_cstr_to_str<'a>(s: *const c_char) -> Result<Option<&'a str>> {
+    if s.is_null() {
+        Ok(None)
+    } else {
+        cstr_to_str(s).map(Some)
+    }
+}
+
+fn lookup(stash: &MemDbStash, sdk_id: &str, cpu_name: Option<&str>, object: &str, addr: u64)
+    -> Result<*mut SymbolServerSymbol>
+{
+    let uuid = parse_uuid(object);
+    let cpu_name = match cpu_name {
+        Some(cpu_name) => cpu_name,
+        // only lookups by name need the cpu
+        None if uuid.is_some() => "",
+        None => return Err(Error::from("cpu_name is required for lookups by name")),
+    };
+    let sdk_infos = stash.fuzzy_match_sdk_id(sdk_id)?;
+    if sdk_infos.is_empty() {
+        return Err(ErrorKind::UnknownSdk.into());
+    }
+
+    for sdk_info in sdk_infos.iter() {
+        let mut memdb = stash.get_memdb(sdk_info)?;
+        let object_uuid = match uuid {
+            Some(uuid) => Some(uuid),
+            None if memdb.is_thin() => memdb.find_uuid(object, cpu_name)?.cloned(),
+            None => None,
+        };
+        if let Some(ref object_uuid) = object_uuid {
+            memdb = stash.get_object_memdb(memdb, object_uuid)?;
+        }
+        let sym = match uuid {
+            Some(ref uuid) => memdb.lookup_by_uuid(uuid, addr),
+            None => memdb.lookup_by_object_name(object, cpu_name, addr),
+        };
+        if let Some(sym) = sym {
+            stash.record_lookup(sdk_info, &sym.object_uuid(), sym.object_name());
+            let mut object_uuid = [0u8; 16];
+            object_uuid.copy_from_slice(sym.object_uuid().as_bytes());
+            return Ok(Box::into_raw(Box::new(SymbolServerSymbol {
+                object_uuid,
+                object_name: string_to_cstr(sym.object_name()),
+                symbol: string_to_cstr(sym.symbol()),
+                addr: sym.addr(),
+            })));
+        }
+    }
+
+    Ok(ptr::null_mut())
+}
+
+/// Opens the stash configured in the given config file.
+///
+/// If `config_path` is null the default config is loaded.  Returns null
+/// on error.
+#[no_mangle]
+pub unsafe extern "C" fn symbolserver_stash_open(config_path: *const c_char,
+                                                 err_out: *mut *mut c_char)
+    -> *mut SymbolServerStash
+{
+    landingpad(err_out, || {
+        let config = if config_path.is_null() {
+            Config::load_default()?
+        } else {
+            Config::load_file(cstr_to_str(config_path)?)?
+        };
+        let stash = MemDbStash::new(&config)?;
+        Ok(Box::into_raw(Box::new(SymbolServerStash { stash })))
+    })
+}
+
+/// Closes a stash opened with `symbolserver_stash_open`.
+#[no_mangle]
+pub unsafe extern "C" fn symbolserver_stash_free(stash: *mut SymbolServerStash) {
+    if !stash.is_null() {
+        drop(Box::from_raw(stash));
+    }
+}
+
+/// Looks up an address in an object of an SDK.
+///
+/// `object` is either the uuid or the name of the object; `cpu_name` is
+/// only used for lookups by name and may be null for lookups by uuid.
+/// Returns null if the symbol was not found or an error occurred, in
+/// which case `err_out` is set.  The result has to be freed with
+/// `symbolserver_symbol_free`.
+#[no_mangle]
+pub unsafe extern "C" fn symbolserver_lookup(stash: *const SymbolServerStash,
+                                             sdk_id: *const c_char,
+                                             cpu_name: *const c_char,
+                                             object: *const c_char,
+                                             addr: u64,
+                                             err_out: *mut *mut c_char)
+    -> *mut SymbolServerSymbol
+{
+    landingpad(err_out, || {
+        if stash.is_null() {
+            return Err(Error::from("unexpected null pointer"));
+        }
+        lookup(&(*stash).stash, cstr_to_str(sdk_id)?, opt_cstr_to_str(cpu_name)?,
+               cstr_to_str(object)?, addr)
+    })
+}
+
+/// Frees a symbol returned by `symbolserver_lookup`.
+#[no_mangle]
+pub unsafe extern "C" fn symbolserver_symbol_free(sym: *mut SymbolServerSymbol) {
+    if !sym.is_null() {
+        let sym = Box::from_raw(sym);
+        drop(CString::from_raw(sym.object_name));
+        drop(CString::from_raw(sym.symbol));
+    }
+}
+
+/// Frees an error message.
+#[no_mangle]
+pub unsafe extern "C" fn symbolserver_err_free(err: *mut c_char) {
+    if !err.is_null() {
+        drop(CString::from_raw(err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::os::raw::c_char;
+    use std::ptr;
+
+    use super::*;
+
+    /// Returns and frees the error message set by a call.
+    unsafe fn take_error(err: *mut c_char) -> Option<String> {
+        if err.is_null() {
+            return None;
+        }
+        let msg = CStr::from_ptr(err).to_string_lossy().into_owned();
+        symbolserver_err_free(err);
+        Some(msg)
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_lookup_round_trip() {
+        use std::fs;
+        use tempdir::TempDir;
+        use super::super::sdk::SdkInfo;
+        use super::super::testing::MockS3;
+
+        let s3 = MockS3::start().unwrap();
+        let info = SdkInfo::new("iOS", 10, 2, 0, Some("14C92"));
+        s3.put_sdk(&info).unwrap();
+        let dir = TempDir::new("symbolserver-test").unwrap();
+        let config = s3.config(dir.path());
+        MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+        let config_path = dir.path().join("config.yml");
+        fs::write(&config_path, format!("aws:\n  bucket_url: {}\nsymbol_dir: {}\n",
+                                        config.get_aws_bucket_url().unwrap(),
+                                        dir.path().display())).unwrap();
+
+        unsafe {
+            let mut err = ptr::null_mut();
+            let path = CString::new(config_path.to_str().unwrap()).unwrap();
+            let stash = symbolserver_stash_open(path.as_ptr(), &mut err);
+            assert_eq!(take_error(err), None);
+            assert!(!stash.is_null());
+
+            let sdk_id = CString::new("iOS_10.2.0_14C92").unwrap();
+            let uuid = CString::new("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b").unwrap();
+            // lookups by uuid do not need the cpu
+            let sym = symbolserver_lookup(stash, sdk_id.as_ptr(), ptr::null(), uuid.as_ptr(),
+                                          0x5b14, &mut err);
+            assert_eq!(take_error(err), None);
+            assert!(!sym.is_null());
+            assert_eq!(CStr::from_ptr((*sym).symbol).to_str().unwrap(),
+                       "___CFFromMacKoreanLen");
+            assert_eq!(&(*sym).object_uuid[..4], &[0xfe, 0x6d, 0x76, 0xd4]);
+            symbolserver_symbol_free(sym);
+
+            let cpu_name = CString::new("arm64").unwrap();
+            let name = CString::new(
+                "/System/Library/CoreServices/Encodings/libKoreanConverter.dylib").unwrap();
+            let sym = symbolserver_lookup(stash, sdk_id.as_ptr(), cpu_name.as_ptr(),
+                                          name.as_ptr(), 0x5b14, &mut err);
+            assert_eq!(take_error(err), None);
+            assert!(!sym.is_null());
+            assert_eq!(CStr::from_ptr((*sym).object_name).to_str().unwrap(),
+                       name.to_str().unwrap());
+            symbolserver_symbol_free(sym);
+
+            // lookups by name do
+            let sym = symbolserver_lookup(stash, sdk_id.as_ptr(), ptr::null(), name.as_ptr(),
+                                          0x5b14, &mut err);
+            assert!(sym.is_null());
+            assert_eq!(take_error(err).unwrap(), "cpu_name is required for lookups by name");
+
+            // a miss is not an error
+            let sym = symbolserver_lookup(stash, sdk_id.as_ptr(), ptr::null(), uuid.as_ptr(),
+                                          0x0, &mut err);
+            assert!(sym.is_null());
+            assert_eq!(take_error(err), None);
+
+            let unknown = CString::new("iOS_1.0.0_1A1").unwrap();
+            let sym = symbolserver_lookup(stash, unknown.as_ptr(), ptr::null(), uuid.as_ptr(),
+                                          0x5b14, &mut err);
+            assert!(sym.is_null());
+            assert!(take_error(err).is_some());
+
+            symbolserver_stash_free(stash);
+        }
+    }
+
+    #[test]
+    fn test_null_arguments() {
+        unsafe {
+            let mut err = ptr::null_mut();
+            let value = CString::new("value").unwrap();
+            let sym = symbolserver_lookup(ptr::null(), value.as_ptr(), value.as_ptr(),
+                                          value.as_ptr(), 0, &mut err);
+            assert!(sym.is_null());
+            assert_eq!(take_error(err).unwrap(), "unexpected null pointer");
+
+            // a null error out parameter is ignored
+            let sym = symbolserver_lookup(ptr::null(), value.as_ptr(), value.as_ptr(),
+                                          value.as_ptr(), 0, ptr::null_mut());
+            assert!(sym.is_null());
+
+            let missing = CString::new("/nonexistent/symbolserver.yml").unwrap();
+            let stash = symbolserver_stash_open(missing.as_ptr(), &mut err);
+            assert!(stash.is_null());
+            assert!(take_error(err).is_some());
+
+            // freeing null is a no-op
+            symbolserver_stash_free(ptr::null_mut());
+            symbolserver_symbol_free(ptr::null_mut());
+            symbolserver_err_free(ptr::null_mut());
+        }
+    }
+}
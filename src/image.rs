@@ -0,0 +1,128 @@
+//! Access to the mapped contents of Mach-O images
+//!
+//! The runtime metadata of Objective-C and Swift is made of structures
+//! that point at each other by virtual address.  This resolves such
+//! addresses to the data of the image on disk.  All reads check their
+//! bounds and return `None` for addresses the image does not contain.
+use std::str::from_utf8;
+
+/// A segment of an image.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub vmaddr: u64,
+    pub vmsize: u64,
+    pub fileoff: u64,
+    pub filesize: u64,
+}
+
+/// The data of a Mach-O image with its segments.
+#[derive(Clone)]
+pub struct MachImage<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    segments: Vec<Segment>,
+}
+
+impl<'a> MachImage<'a> {
+    /// Creates an image from its data (starting at the Mach-O header) and
+    /// its segments.
+    pub fn new(data: &'a [u8], is_64: bool, segments: Vec<Segment>) -> MachImage<'a> {
+        MachImage {
+            data,
+            is_64,
+            segments,
+        }
+    }
+
+    /// Returns `true` for 64 bit images.
+    pub fn is_64(&self) -> bool {
+        self.is_64
+    }
+
+    /// The size of a pointer in bytes.
+    pub fn ptr_size(&self) -> u64 {
+        if self.is_64 { 8 } else { 4 }
+    }
+
+    /// Returns the data of the image from an address to the end of the
+    /// segment that contains it.
+    ///
+    /// Segments whose file offsets overflow or point past the end of the
+    /// data give `None` like addresses outside of all segments.
+    fn get_tail(&self, addr: u64) -> Option<&'a [u8]> {
+        let seg = self.segments.iter().find(|seg| {
+            addr >= seg.vmaddr && addr - seg.vmaddr < seg.filesize.min(seg.vmsize)
+        })?;
+        let start = seg.fileoff.checked_add(addr - seg.vmaddr)?;
+        let end = seg.fileoff.checked_add(seg.filesize.min(seg.vmsize))?;
+        if end > self.data.len() as u64 {
+            return None;
+        }
+        Some(&self.data[start as usize..end as usize])
+    }
+
+    /// Returns how many bytes the segment that contains an address has
+    /// left from there on.
+    pub fn available(&self, addr: u64) -> Option<u64> {
+        self.get_tail(addr).map(|x| x.len() as u64)
+    }
+
+    /// Returns `len` bytes at an address.
+    pub fn get_bytes(&self, addr: u64, len: u64) -> Option<&'a [u8]> {
+        let tail = self.get_tail(addr)?;
+        if len > tail.len() as u64 {
+            return None;
+        }
+        Some(&tail[..len as usize])
+    }
+
+    /// Reads a little endian 32 bit integer.
+    pub fn read_u32(&self, addr: u64) -> Option<u32> {
+        let bytes = self.get_bytes(addr, 4)?;
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(bytes);
+        Some(u32::from_le_bytes(buf))
+    }
+
+    /// Reads a pointer, dropping the bits that chained fixups and pointer
+    /// authentication keep in the upper half.
+    pub fn read_ptr(&self, addr: u64) -> Option<u64> {
+        if !self.is_64 {
+            return self.read_u32(addr).map(u64::from);
+        }
+        let bytes = self.get_bytes(addr, 8)?;
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(bytes);
+        let ptr = u64::from_le_bytes(buf);
+        Some(if ptr >> 48 != 0 { ptr & 0xf_ffff_ffff } else { ptr })
+    }
+
+    /// Reads a 32 bit offset relative to its own address and returns the
+    /// address it points to.
+    pub fn read_rel(&self, addr: u64) -> Option<u64> {
+        let offset = self.read_u32(addr)? as i32;
+        Some((addr as i64).wrapping_add(i64::from(offset)) as u64)
+    }
+
+    /// Reads a relative offset that points to a pointer to the target if
+    /// its lowest bit is set.  Returns `None` for null offsets.
+    pub fn read_rel_indirect(&self, addr: u64) -> Option<u64> {
+        let offset = self.read_u32(addr)? as i32;
+        if offset == 0 {
+            return None;
+        }
+        let target = (addr as i64).wrapping_add(i64::from(offset & !1)) as u64;
+        if offset & 1 != 0 {
+            self.read_ptr(target)
+        } else {
+            Some(target)
+        }
+    }
+
+    /// Reads a zero terminated string.
+    pub fn read_cstr(&self, addr: u64) -> Option<&'a str> {
+        let bytes = self.get_tail(addr)?;
+        let len = bytes.iter().position(|&x| x == 0)?;
+        from_utf8(&bytes[..len]).ok()
+    }
+}
@@ -1,9 +1,15 @@
 //! This crate implements symbol handling for system libraries
+//!
+//! Besides powering the `sentry-symbolserver` binary the crate can be used
+//! as a library to convert SDKs and read memdbs.  The command line interface
+//! and the API server are behind the `cli` and `server` features which are
+//! enabled by default.  Library code never prints; progress of long running
+//! operations is sent to a `report::Reporter` instead.
 #![recursion_limit = "1024"]
 
 #[macro_use] extern crate serde_derive;
 extern crate serde;
-extern crate serde_json;
+#[cfg_attr(any(feature = "server", feature = "testing"), macro_use)] extern crate serde_json;
 extern crate serde_yaml;
 extern crate serde_xml;
 #[macro_use] extern crate error_chain;
@@ -15,8 +21,8 @@ extern crate globset;
 #[macro_use] extern crate lazy_static;
 extern crate mach_object;
 extern crate memmap;
-extern crate clap;
-extern crate console;
+#[cfg(feature = "cli")] extern crate clap;
+#[cfg(feature = "cli")] extern crate console;
 extern crate indicatif;
 extern crate xz2;
 extern crate tempdir;
@@ -30,16 +36,25 @@ extern crate dirs;
 extern crate chrono;
 extern crate hyper;
 extern crate hyper_native_tls;
-extern crate multipart;
-extern crate mime;
+#[cfg(feature = "crypto")] extern crate openssl;
+#[cfg(feature = "cli")] extern crate multipart;
+#[cfg(feature = "cli")] extern crate mime;
 extern crate url;
 extern crate libc;
 extern crate md5;
 extern crate num_cpus;
-extern crate openssl_probe;
+#[cfg(feature = "cli")] extern crate openssl_probe;
 #[macro_use] extern crate log;
 extern crate rustc_serialize;
 #[macro_use] extern crate if_chain;
+#[cfg(feature = "jemalloc")] extern crate jemalloc_ctl;
+#[cfg(feature = "python")] extern crate pyo3;
+#[cfg(feature = "grpc")] extern crate grpcio;
+#[cfg(feature = "grpc")] extern crate protobuf;
+#[cfg(feature = "grpc")] extern crate futures;
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
 
 pub use errors::{Result, Error, ErrorKind, ResultExt};
 
@@ -49,8 +64,28 @@ pub mod memdb;
 pub mod utils;
 pub mod config;
 pub mod s3;
+pub mod manifest;
+pub mod reconvert;
+pub mod mirror;
+pub mod signatures;
+pub mod statsd;
+#[cfg(feature = "cli")]
 pub mod cli;
 pub mod dsym;
+pub mod image;
+pub mod objc;
+pub mod swift;
 pub mod sdk;
+pub mod sandbox;
+pub mod download;
+#[cfg(feature = "server")]
 pub mod api;
 pub mod constants;
+pub mod allocator;
+pub mod report;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "testing")]
+pub mod testing;
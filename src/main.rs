@@ -1,4 +1,14 @@
 extern crate libsymbolserver;
+#[cfg(feature = "jemalloc")] extern crate jemallocator;
+#[cfg(feature = "mimalloc")] extern crate mimalloc;
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static ALLOCATOR: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 fn main() {
     libsymbolserver::cli::main();
@@ -0,0 +1,194 @@
+//! Upstream manifests.
+//!
+//! A manifest is a JSON file next to the compressed memdbs that lists all
+//! of them with their size and hash.  Servers configured with
+//! `sync.manifest` read it instead of listing the bucket, and the
+//! `publish-manifest` command writes it.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_json;
+
+use super::sdk::SdkInfo;
+use super::memdb::stash::{RemoteSdk, WithdrawnSdk};
+use super::s3::{S3Server, UpstreamListing};
+use super::utils::{HashingWriter, write_file_atomic};
+use super::{Result, ResultExt};
+
+/// An upstream maintained list of the SDKs in the bucket
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    revision: Option<u64>,
+    sdks: Vec<ManifestEntry>,
+    #[serde(default)]
+    withdrawn: Vec<TombstoneEntry>,
+}
+
+/// A compressed SDK listed in a manifest
+#[derive(Serialize, Deserialize, Debug)]
+struct ManifestEntry {
+    filename: String,
+    size: u64,
+    hash: String,
+}
+
+/// An SDK withdrawn in a manifest
+#[derive(Serialize, Deserialize, Debug)]
+struct TombstoneEntry {
+    sdk_id: String,
+    reason: String,
+}
+
+/// Returns the SDK a tombstone file (`<sdk id>.withdrawn`) is for.
+pub fn sdk_info_from_tombstone(filename: &str) -> Option<SdkInfo> {
+    if filename.ends_with(".withdrawn") {
+        SdkInfo::from_filename(&filename[..filename.len() - 10])
+    } else {
+        None
+    }
+}
+
+impl Manifest {
+    /// Creates a manifest for the given listing.
+    ///
+    /// The etags of the SDKs are used as their hashes.
+    pub fn new(revision: u64, listing: &UpstreamListing) -> Manifest {
+        let mut sdks: Vec<_> = listing.sdks.iter().map(|sdk| ManifestEntry {
+            filename: sdk.filename().to_string(),
+            size: sdk.size(),
+            hash: sdk.etag().to_string(),
+        }).collect();
+        sdks.sort_by(|a, b| a.filename.cmp(&b.filename));
+        let mut withdrawn: Vec<_> = listing.withdrawn.iter().map(|x| TombstoneEntry {
+            sdk_id: x.info().sdk_id(),
+            reason: x.reason().to_string(),
+        }).collect();
+        withdrawn.sort_by(|a, b| a.sdk_id.cmp(&b.sdk_id));
+        Manifest {
+            revision: Some(revision),
+            sdks,
+            withdrawn,
+        }
+    }
+
+    /// Parses a manifest.
+    pub fn from_slice(data: &[u8]) -> Result<Manifest> {
+        Ok(serde_json::from_slice(data).chain_err(|| "Invalid manifest")?)
+    }
+
+    /// Loads the manifest at a path if it exists.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Option<Manifest>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(Manifest::from_slice(&data)?)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Lists the compressed memdbs and tombstones in a local folder.
+    ///
+    /// The hash of a file is its MD5 checksum which is also what S3 uses
+    /// as etag for files that were not uploaded in parts.
+    pub fn scan_dir<P: AsRef<Path>>(path: P) -> Result<UpstreamListing> {
+        let mut rv = UpstreamListing::default();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let filename = match entry.file_name().into_string() {
+                Ok(filename) => filename,
+                Err(_) => continue,
+            };
+            if let Some(info) = sdk_info_from_tombstone(&filename) {
+                let reason = fs::read_to_string(entry.path())?.trim().to_string();
+                rv.withdrawn.push(WithdrawnSdk::new(info, if reason.is_empty() {
+                    "no reason given".to_string()
+                } else {
+                    reason
+                }));
+                continue;
+            }
+            if !filename.ends_with(".memdbz") {
+                continue;
+            }
+            let info = match SdkInfo::from_filename(&filename) {
+                Some(info) => info,
+                None => continue,
+            };
+            let mut f = fs::File::open(entry.path())?;
+            let mut hasher = HashingWriter::new(io::sink());
+            let size = io::copy(&mut f, &mut hasher)?;
+            rv.sdks.push(RemoteSdk::new(filename, info, hasher.checksum(), size));
+        }
+        Ok(rv)
+    }
+
+    /// The revision of the manifest if known
+    pub fn revision(&self) -> Option<u64> {
+        self.revision
+    }
+
+    /// The number of SDKs in the manifest
+    pub fn sdk_count(&self) -> usize {
+        self.sdks.len()
+    }
+
+    /// Returns the SDKs listed in the manifest.
+    ///
+    /// Entries that are not compressed memdbs are skipped with a warning.
+    pub fn listing(&self) -> UpstreamListing {
+        let mut rv = UpstreamListing::default();
+        for entry in &self.sdks {
+            if_chain! {
+                if entry.filename.ends_with(".memdbz");
+                if let Some(info) = SdkInfo::from_filename(&entry.filename);
+                then {
+                    rv.sdks.push(RemoteSdk::new(entry.filename.clone(), info,
+                                                entry.hash.clone(), entry.size));
+                } else {
+                    warn!("Ignoring {} in manifest", entry.filename);
+                }
+            }
+        }
+        for entry in &self.withdrawn {
+            match SdkInfo::from_filename(&entry.sdk_id) {
+                Some(info) => {
+                    rv.withdrawn.push(WithdrawnSdk::new(info, entry.reason.clone()));
+                }
+                None => {
+                    warn!("Ignoring withdrawn {} in manifest", entry.sdk_id);
+                }
+            }
+        }
+        rv
+    }
+
+    /// Serializes the manifest.
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self).chain_err(|| "Could not serialize manifest")?)
+    }
+
+    /// Writes the manifest to a path, replacing it atomically.
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        write_file_atomic(path.as_ref(), "tempmanifest", &self.to_vec()?)
+    }
+}
+
+/// Lists the bucket and uploads a manifest of it with the next revision.
+pub fn publish_bucket_manifest(s3: &S3Server, key: &str) -> Result<Manifest> {
+    let previous = s3.get_manifest(key)?.unwrap_or_default();
+    let manifest = Manifest::new(previous.revision().unwrap_or(0) + 1,
+                                 &s3.list_bucket_sdks()?);
+    s3.put_manifest(key, &manifest)?;
+    Ok(manifest)
+}
+
+/// Scans a local folder and writes a manifest of it with the next revision
+/// into the folder.
+pub fn publish_dir_manifest<P: AsRef<Path>>(path: P, key: &str) -> Result<Manifest> {
+    let manifest_path = path.as_ref().join(key);
+    let previous = Manifest::from_path(&manifest_path)?.unwrap_or_default();
+    let manifest = Manifest::new(previous.revision().unwrap_or(0) + 1,
+                                 &Manifest::scan_dir(path)?);
+    manifest.write_to_path(&manifest_path)?;
+    Ok(manifest)
+}
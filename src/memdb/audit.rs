@@ -0,0 +1,159 @@
+//! An append-only log of all changes to the stash.
+//!
+//! Every SDK that is added, updated, removed, quarantined or marked for a
+//! re-download is recorded together with who caused the change and the
+//! etag of the file involved.
+//! Audits of random samples additionally record SDKs whose local copy
+//! drifted from the one in S3.
+//! The log is a file with one JSON event per line in the symbol directory
+//! and is never rewritten, which makes it possible to reconstruct why an
+//! SDK vanished from a server after the fact.
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::Utc;
+use serde_json;
+
+use super::super::sdk::SdkInfo;
+use super::super::{Result, ResultExt};
+
+/// What happened to an SDK
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    #[serde(rename = "add")]
+    Add,
+    #[serde(rename = "update")]
+    Update,
+    #[serde(rename = "remove")]
+    Remove,
+    #[serde(rename = "quarantine")]
+    Quarantine,
+    #[serde(rename = "drift")]
+    Drift,
+    #[serde(rename = "redownload")]
+    Redownload,
+}
+
+/// A single entry in the audit log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvent {
+    timestamp: i64,
+    actor: String,
+    action: AuditAction,
+    sdk_id: String,
+    etag: Option<String>,
+    reason: Option<String>,
+}
+
+/// The audit log of a stash
+pub struct AuditLog {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl AuditEvent {
+    /// Creates a new event that happens now.
+    pub fn new(actor: &str, action: AuditAction, info: &SdkInfo) -> AuditEvent {
+        AuditEvent {
+            timestamp: Utc::now().timestamp(),
+            actor: actor.to_string(),
+            action,
+            sdk_id: info.sdk_id(),
+            etag: None,
+            reason: None,
+        }
+    }
+
+    /// Sets the etag of the file involved.
+    pub fn with_etag(mut self, etag: &str) -> AuditEvent {
+        self.etag = Some(etag.to_string());
+        self
+    }
+
+    /// Sets why the change happened.
+    pub fn with_reason(mut self, reason: &str) -> AuditEvent {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    /// Unix timestamp of the event
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Who caused the change (`sync`, `cli` etc.)
+    pub fn actor(&self) -> &str {
+        &self.actor
+    }
+
+    /// What happened
+    pub fn action(&self) -> AuditAction {
+        self.action
+    }
+
+    /// The id of the SDK that changed
+    pub fn sdk_id(&self) -> &str {
+        &self.sdk_id
+    }
+
+    /// The etag of the file involved if known
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_ref().map(|x| x.as_str())
+    }
+
+    /// Why the change happened if known
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_ref().map(|x| x.as_str())
+    }
+}
+
+impl AuditLog {
+    /// Opens the audit log at the given path.
+    pub fn new<P: AsRef<Path>>(path: P) -> AuditLog {
+        AuditLog {
+            path: path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Appends an event to the log.
+    pub fn record(&self, event: &AuditEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)
+            .chain_err(|| "Could not serialize audit event")?;
+        line.push(b'\n');
+        let _guard = self.lock.lock().unwrap();
+        let mut f = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        f.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Returns the most recent events, optionally only for one SDK.
+    ///
+    /// The newest event comes first.  Lines that cannot be parsed are
+    /// skipped.
+    pub fn query(&self, sdk_id: Option<&str>, limit: usize) -> Result<Vec<AuditEvent>> {
+        let _guard = self.lock.lock().unwrap();
+        let f = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(vec![]);
+            }
+            Err(err) => { return Err(err.into()); }
+        };
+        let mut rv = vec![];
+        for line in io::BufReader::new(f).lines() {
+            let event: AuditEvent = match serde_json::from_str(&line?) {
+                Ok(event) => event,
+                Err(_) => continue,
+            };
+            if sdk_id.map_or(true, |x| x == event.sdk_id) {
+                rv.push(event);
+            }
+        }
+        rv.reverse();
+        rv.truncate(limit);
+        Ok(rv)
+    }
+}
@@ -0,0 +1,367 @@
+//! Encryption of memdb files at rest.
+//!
+//! With `stash.encryption_key` set the memdbs in the symbol directory are
+//! encrypted with AES-256-GCM.  An encrypted file starts with a header of
+//! the magic `SYMENC\0\x01`, the id of the key it was encrypted with and a
+//! random nonce, followed by the ciphertext and the 16 byte tag.  The header
+//! is authenticated along with the contents.
+//!
+//! Encrypted memdbs cannot be mapped into the process and are decrypted
+//! into memory when they are opened.  Files without the header are read as
+//! they are, so enabling encryption on an existing stash only encrypts the
+//! memdbs the sync writes from then on.
+//!
+//! Builds without the `crypto` feature refuse to load a key and fail to
+//! open encrypted files.
+#[cfg(feature = "crypto")]
+use std::cmp;
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+#[cfg(feature = "crypto")]
+use openssl::rand::rand_bytes;
+#[cfg(feature = "crypto")]
+use openssl::sha::sha256;
+#[cfg(feature = "crypto")]
+use openssl::symm::{decrypt_aead, Cipher, Crypter, Mode};
+#[cfg(feature = "crypto")]
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::hex::ToHex;
+
+use super::super::config::Config;
+use super::super::{ErrorKind, Result, ResultExt};
+
+const MAGIC: &[u8; 8] = b"SYMENC\x00\x01";
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+#[cfg(feature = "crypto")]
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 8 + KEY_ID_LEN + NONCE_LEN;
+
+/// The key memdbs are encrypted with.
+#[cfg(feature = "crypto")]
+pub struct EncryptionKey {
+    key: Vec<u8>,
+    key_id: [u8; KEY_ID_LEN],
+}
+
+/// Builds without the `crypto` feature cannot load a key, so there is
+/// never a value of this.
+#[cfg(not(feature = "crypto"))]
+pub enum EncryptionKey {}
+
+/// A writer that encrypts everything written through it.
+///
+/// Without a key the data is written as it is.
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    #[cfg(feature = "crypto")]
+    crypter: Option<Crypter>,
+    #[cfg(feature = "crypto")]
+    buf: Vec<u8>,
+}
+
+impl EncryptionKey {
+    /// Loads the encryption key of the config if one is set.
+    pub fn from_config(config: &Config) -> Result<Option<EncryptionKey>> {
+        match config.get_stash_encryption_key() {
+            Some(path) => Ok(Some(EncryptionKey::load(&path)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl EncryptionKey {
+    /// Refuses to load a key as this build cannot encrypt.
+    pub fn load<P: AsRef<Path>>(_path: P) -> Result<EncryptionKey> {
+        Err(ErrorKind::BadConfigKey(
+            "stash.encryption_key", "Encryption needs a build with the crypto feature").into())
+    }
+
+    /// A short fingerprint of the key
+    pub fn key_id(&self) -> String {
+        match *self {}
+    }
+
+    /// Reads a memdb file and decrypts it if it is encrypted.
+    pub fn read_file(&self, _path: &Path) -> Result<Vec<u8>> {
+        match *self {}
+    }
+
+    fn open_reader(&self, _f: fs::File, _header: Vec<u8>) -> Result<Box<Read>> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl EncryptionKey {
+    /// Loads a key from a file holding 32 hex encoded bytes.
+    ///
+    /// Such a key can be made with `openssl rand -hex 32`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<EncryptionKey> {
+        let mut contents = String::new();
+        fs::File::open(path.as_ref())
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .chain_err(|| format!("Could not read encryption key {}",
+                                  path.as_ref().display()))?;
+        let key = contents.trim().from_hex().ok()
+            .and_then(|key| if key.len() == 32 { Some(key) } else { None })
+            .ok_or_else(|| ErrorKind::BadConfigKey(
+                "stash.encryption_key", "Encryption key has to be 32 hex encoded bytes"))?;
+        Ok(EncryptionKey::new(key))
+    }
+
+    fn new(key: Vec<u8>) -> EncryptionKey {
+        let mut key_id = [0; KEY_ID_LEN];
+        key_id.copy_from_slice(&sha256(&key)[..KEY_ID_LEN]);
+        EncryptionKey { key, key_id }
+    }
+
+    /// A short fingerprint of the key
+    pub fn key_id(&self) -> String {
+        self.key_id.to_hex()
+    }
+
+    /// Reads a memdb file and decrypts it if it is encrypted.
+    ///
+    /// Files encrypted with another key fail with `UnknownEncryptionKey`,
+    /// files that do not decrypt with `BadMemDb`.
+    pub fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut contents = vec![];
+        fs::File::open(path)?.read_to_end(&mut contents)?;
+        if !contents.starts_with(MAGIC) {
+            return Ok(contents);
+        }
+        if contents.len() < HEADER_LEN + TAG_LEN {
+            return Err(ErrorKind::BadMemDb.into());
+        }
+        let key_id = &contents[8..8 + KEY_ID_LEN];
+        if key_id != self.key_id {
+            return Err(ErrorKind::UnknownEncryptionKey(key_id.to_hex()).into());
+        }
+        let (header, rest) = contents.split_at(HEADER_LEN);
+        let (data, tag) = rest.split_at(rest.len() - TAG_LEN);
+        decrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(&header[8 + KEY_ID_LEN..]),
+                     header, data, tag)
+            .map_err(|_| ErrorKind::BadMemDb.into())
+    }
+
+    /// Starts decrypting a file whose header was already read.
+    fn open_reader(&self, f: fs::File, header: Vec<u8>) -> Result<Box<Read>> {
+        let key_id = &header[8..8 + KEY_ID_LEN];
+        if key_id != self.key_id {
+            return Err(ErrorKind::UnknownEncryptionKey(key_id.to_hex()).into());
+        }
+        let mut crypter = Crypter::new(Cipher::aes_256_gcm(), Mode::Decrypt, &self.key,
+                                       Some(&header[8 + KEY_ID_LEN..]))?;
+        crypter.aad_update(&header)?;
+        Ok(Box::new(DecryptingReader {
+            inner: f,
+            crypter,
+            pending: vec![],
+            out: vec![],
+            pos: 0,
+            finished: false,
+        }))
+    }
+}
+
+/// Returns the id of the key a file is encrypted with or `None` if the
+/// file is not encrypted.
+pub fn get_encryption_key_id(path: &Path) -> Result<Option<String>> {
+    let mut header = vec![];
+    fs::File::open(path)?.take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    if header.len() == HEADER_LEN && header.starts_with(MAGIC) {
+        Ok(Some(header[8..8 + KEY_ID_LEN].to_hex()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// A reader that decrypts a file as it is read.
+///
+/// The tag is checked once the end of the file is reached, so data read
+/// before that is not authenticated yet.
+#[cfg(feature = "crypto")]
+struct DecryptingReader<R: Read> {
+    inner: R,
+    crypter: Crypter,
+    /// Read but not decrypted yet, the last `TAG_LEN` bytes might be the tag
+    pending: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+/// Opens a memdb file and decrypts it on the fly if it is encrypted.
+///
+/// Unlike `EncryptionKey::read_file` this does not hold the file in memory,
+/// which makes it the right choice for files that are streamed somewhere
+/// else or only read partially.  Encrypted files fail with
+/// `UnknownEncryptionKey` without the right key.
+pub fn open_file(path: &Path, key: Option<&EncryptionKey>) -> Result<Box<Read>> {
+    let mut f = fs::File::open(path)?;
+    let mut header = vec![];
+    (&mut f).take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    if header.len() < HEADER_LEN || !header.starts_with(MAGIC) {
+        return Ok(Box::new(Cursor::new(header).chain(f)));
+    }
+    match key {
+        Some(key) => key.open_reader(f, header),
+        None => Err(ErrorKind::UnknownEncryptionKey(header[8..8 + KEY_ID_LEN].to_hex()).into()),
+    }
+}
+
+#[cfg(feature = "crypto")]
+fn bad_ciphertext() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "memdb does not decrypt")
+}
+
+#[cfg(feature = "crypto")]
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.out.len() {
+                let count = cmp::min(buf.len(), self.out.len() - self.pos);
+                buf[..count].copy_from_slice(&self.out[self.pos..self.pos + count]);
+                self.pos += count;
+                return Ok(count);
+            }
+            if self.finished {
+                return Ok(0);
+            }
+
+            let mut chunk = [0; 8192];
+            let read = self.inner.read(&mut chunk)?;
+            self.pos = 0;
+            if read == 0 {
+                if self.pending.len() != TAG_LEN {
+                    return Err(bad_ciphertext());
+                }
+                self.crypter.set_tag(&self.pending).map_err(|_| bad_ciphertext())?;
+                self.out.resize(32, 0);
+                let count = self.crypter.finalize(&mut self.out).map_err(|_| bad_ciphertext())?;
+                self.out.truncate(count);
+                self.finished = true;
+                continue;
+            }
+            self.pending.extend_from_slice(&chunk[..read]);
+            let ready = self.pending.len().saturating_sub(TAG_LEN);
+            self.out.resize(ready + 16, 0);
+            let count = self.crypter.update(&self.pending[..ready], &mut self.out)
+                .map_err(|_| bad_ciphertext())?;
+            self.out.truncate(count);
+            self.pending.drain(..ready);
+        }
+    }
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Starts an encrypted file with a fresh nonce.
+    #[cfg(feature = "crypto")]
+    pub fn new(mut inner: W, key: Option<&EncryptionKey>) -> Result<EncryptingWriter<W>> {
+        let crypter = match key {
+            Some(key) => {
+                let mut header = MAGIC.to_vec();
+                header.extend_from_slice(&key.key_id);
+                let mut nonce = [0; NONCE_LEN];
+                rand_bytes(&mut nonce)?;
+                header.extend_from_slice(&nonce);
+                let mut crypter = Crypter::new(
+                    Cipher::aes_256_gcm(), Mode::Encrypt, &key.key, Some(&nonce))?;
+                crypter.aad_update(&header)?;
+                inner.write_all(&header)?;
+                Some(crypter)
+            }
+            None => None,
+        };
+        Ok(EncryptingWriter { inner, crypter, buf: vec![] })
+    }
+
+    /// Starts a file that is written as it is.
+    #[cfg(not(feature = "crypto"))]
+    pub fn new(inner: W, key: Option<&EncryptionKey>) -> Result<EncryptingWriter<W>> {
+        if let Some(key) = key {
+            match *key {}
+        }
+        Ok(EncryptingWriter { inner })
+    }
+
+    /// Writes the tag and returns the inner writer.
+    #[cfg(feature = "crypto")]
+    pub fn finish(mut self) -> Result<W> {
+        if let Some(mut crypter) = self.crypter.take() {
+            let mut buf = [0; 32];
+            let rest = crypter.finalize(&mut buf)?;
+            self.inner.write_all(&buf[..rest])?;
+            let mut tag = [0; TAG_LEN];
+            crypter.get_tag(&mut tag)?;
+            self.inner.write_all(&tag)?;
+        }
+        Ok(self.inner)
+    }
+
+    /// Returns the inner writer.
+    #[cfg(not(feature = "crypto"))]
+    pub fn finish(self) -> Result<W> {
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    #[cfg(feature = "crypto")]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.crypter {
+            Some(ref mut crypter) => {
+                self.buf.resize(buf.len() + 16, 0);
+                let count = crypter.update(buf, &mut self.buf)
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                self.inner.write_all(&self.buf[..count])?;
+                Ok(buf.len())
+            }
+            None => self.inner.write(buf),
+        }
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+#[cfg(feature = "crypto")]
+fn test_encrypt() {
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let path = dir.path().join("test.memdb");
+    let key = EncryptionKey::new(vec![42; 32]);
+    let mut w = EncryptingWriter::new(fs::File::create(&path).unwrap(), Some(&key)).unwrap();
+    w.write_all(b"hello ").unwrap();
+    w.write_all(b"world").unwrap();
+    w.finish().unwrap();
+
+    assert_eq!(get_encryption_key_id(&path).unwrap(), Some(key.key_id()));
+    assert_eq!(&key.read_file(&path).unwrap()[..], b"hello world");
+    let mut streamed = vec![];
+    open_file(&path, Some(&key)).unwrap().read_to_end(&mut streamed).unwrap();
+    assert_eq!(&streamed[..], b"hello world");
+    assert!(open_file(&path, None).is_err());
+    let other = EncryptionKey::new(vec![23; 32]);
+    assert!(other.read_file(&path).is_err());
+
+    let mut contents = vec![];
+    fs::File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+    let last = contents.len() - 1;
+    contents[last] ^= 1;
+    fs::File::create(&path).unwrap().write_all(&contents).unwrap();
+    assert!(key.read_file(&path).is_err());
+    assert!(open_file(&path, Some(&key)).unwrap().read_to_end(&mut vec![]).is_err());
+}
@@ -3,3 +3,8 @@ pub mod read;
 pub mod write;
 pub mod types;
 pub mod stash;
+pub mod encryption;
+pub mod objects;
+pub mod usage;
+pub mod audit;
+pub mod summary;
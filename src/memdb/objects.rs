@@ -0,0 +1,120 @@
+//! The shared object store of thin SDKs.
+//!
+//! The symbols of the images of thin SDKs are kept in memdbs of their own
+//! that are stored by UUID under `objects/<first two digits>/<uuid>.memdb`.
+//! Every thin SDK comes with a manifest at `objects/<sdk id>.json` that
+//! lists the objects it references with their size and checksum.  The
+//! layout is the same in the symbol directory and in the bucket, so a sync
+//! only fetches the objects of a new SDK that it does not have yet and the
+//! verification can check objects one by one.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json;
+use uuid::Uuid;
+
+use super::super::sdk::SdkInfo;
+use super::super::utils::HashingWriter;
+use super::super::{Result, ResultExt};
+
+/// Lists the shared objects of a thin SDK
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ObjectManifest {
+    objects: Vec<ManifestObject>,
+}
+
+/// A shared object listed in a manifest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestObject {
+    uuid: Uuid,
+    size: u64,
+    checksum: String,
+}
+
+impl ObjectManifest {
+    /// Parses a manifest.
+    pub fn from_slice(data: &[u8]) -> Result<ObjectManifest> {
+        Ok(serde_json::from_slice(data).chain_err(|| "Invalid object manifest")?)
+    }
+
+    /// Loads the manifest at a path if it exists.
+    pub fn load(path: &Path) -> Result<Option<ObjectManifest>> {
+        match fs::File::open(path) {
+            Ok(f) => Ok(Some(serde_json::from_reader(io::BufReader::new(f))
+                .chain_err(|| "Invalid object manifest")?)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Serializes the manifest.
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self).chain_err(|| "Could not serialize object manifest")?)
+    }
+
+    /// Adds an object unless the manifest already lists it.
+    pub fn add(&mut self, object: ManifestObject) {
+        if !self.objects.iter().any(|x| x.uuid == object.uuid) {
+            self.objects.push(object);
+        }
+    }
+
+    /// The objects the SDK references.
+    pub fn objects(&self) -> &[ManifestObject] {
+        &self.objects
+    }
+}
+
+impl ManifestObject {
+    /// Describes the object stored at a path.
+    pub fn from_path(uuid: &Uuid, path: &Path) -> Result<ManifestObject> {
+        let mut hasher = HashingWriter::new(io::sink());
+        let size = io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        Ok(ManifestObject {
+            uuid: *uuid,
+            size,
+            checksum: hasher.checksum(),
+        })
+    }
+
+    /// The UUID of the image
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+
+    /// The size of the memdb of the object
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The MD5 checksum of the memdb of the object
+    pub fn checksum(&self) -> &str {
+        &self.checksum
+    }
+}
+
+/// Returns the key of a shared object relative to the symbol directory or
+/// the bucket.
+pub fn get_shared_object_key(uuid: &Uuid) -> String {
+    let uuid = uuid.hyphenated().to_string();
+    format!("objects/{}/{}.memdb", &uuid[..2], uuid)
+}
+
+/// Returns the key of the object manifest of a thin SDK relative to the
+/// symbol directory or the bucket.
+pub fn get_object_manifest_key(info: &SdkInfo) -> String {
+    format!("objects/{}.json", info.sdk_id())
+}
+
+/// Returns the path of the memdb of a shared object in an object store.
+pub fn get_shared_object_path(store: &Path, uuid: &Uuid) -> PathBuf {
+    let uuid = uuid.hyphenated().to_string();
+    store.join(&uuid[..2]).join(format!("{}.memdb", uuid))
+}
+
+/// Returns the path of the object manifest of a thin SDK in an object
+/// store.
+pub fn get_object_manifest_path(store: &Path, info: &SdkInfo) -> PathBuf {
+    store.join(format!("{}.json", info.sdk_id()))
+}
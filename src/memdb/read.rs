@@ -4,21 +4,25 @@
 //! in-memory database format which is a flat file on the file system
 //! that gets mmaped into the process.
 use std::str::from_utf8;
+use std::cmp::Ordering;
+use std::io::{self, Read};
 use std::mem;
 use std::slice;
+use std::ptr;
 use std::path::Path;
 use std::borrow::Cow;
-use std::ffi::CStr;
-use std::os::raw::c_char;
 
 use std::fmt;
+use serde_json;
 use uuid::Uuid;
 use memmap::{Mmap, Protection};
 
-use super::types::{IndexItem, StoredSlice, MemDbHeader, IndexedUuid};
-use super::super::{Result, ErrorKind};
-use super::super::sdk::SdkInfo;
-use super::super::utils::binsearch_by_key;
+use super::types::{IndexItem, StoredSlice, MemDbHeader, IndexedUuid, LeafRef, ConversionInfo,
+                   MEMDB_VERSION, MIN_MEMDB_VERSION};
+use super::super::{Result, Error, ErrorKind};
+use super::super::dsym::SymbolSource;
+use super::super::sdk::{DeviceFamily, SdkInfo};
+use super::super::utils::{binsearch_by_key, parse_uuid};
 
 
 enum Backing<'a> {
@@ -29,7 +33,17 @@ enum Backing<'a> {
 /// Provides access to a memdb file
 pub struct MemDb<'a> {
     info: SdkInfo,
-    backing: Backing<'a>
+    backing: Backing<'a>,
+    is_thin: bool,
+}
+
+/// The header and conversion info of a memdb file
+///
+/// Unlike a `MemDb` this can be read from a stream without loading the
+/// file, which is what listings and the sync need.
+pub struct MemDbMetadata {
+    header: MemDbHeader,
+    conversion_info: Option<ConversionInfo>,
 }
 
 /// Represents a symbol from a memdb file.
@@ -39,12 +53,24 @@ pub struct Symbol<'a> {
     object_name: Cow<'a, str>,
     symbol: Cow<'a, str>,
     addr: u64,
+    is_last: bool,
+    is_recovered: bool,
+    source: Option<SymbolSource>,
+}
+
+/// The address index of a variant
+enum AddrIndex<'a> {
+    /// A flat sorted list of index items (memdb version 2)
+    Flat(&'a [IndexItem]),
+    /// A directory of sorted leaves (memdb version 3 and later)
+    Paged(&'a [LeafRef]),
 }
 
 /// Represents a symbol iterator
 pub struct SymbolIter<'a> {
     memdb: &'a MemDb<'a>,
     uuid: &'a Uuid,
+    leaves: &'a [LeafRef],
     index: &'a [IndexItem],
     pos: usize,
 }
@@ -62,6 +88,10 @@ impl<'a> Iterator for SymbolIter<'a> {
                     Ok(None) => { continue; }
                     Err(err) => { return Some(Err(err)); }
                 }
+            } else if let Some((leaf, rest)) = self.leaves.split_first() {
+                self.leaves = rest;
+                self.index = iter_try!(self.memdb.get_leaf(leaf));
+                self.pos = 0;
             } else {
                 return None;
             }
@@ -91,20 +121,27 @@ impl<'a> Backing<'a> {
     }
 
     fn get_slice<T>(&self, offset: usize, count: usize) -> Result<&[T]> {
-        let size = mem::size_of::<T>();
+        let len = count.checked_mul(mem::size_of::<T>()).ok_or(ErrorKind::BadMemDb)?;
+        let data = self.get_data(offset, len)?;
+        if data.as_ptr() as usize % mem::align_of::<T>() != 0 {
+            return Err(ErrorKind::BadMemDb.into());
+        }
         Ok(unsafe {
-            slice::from_raw_parts(
-                mem::transmute(self.get_data(offset, count * size)?.as_ptr()),
-                count
-            )
+            slice::from_raw_parts(data.as_ptr() as *const T, count)
         })
     }
 
+    /// Returns a copy of the header, which works for buffers that are not
+    /// aligned for it.
     #[inline(always)]
-    fn header(&self) -> Result<&MemDbHeader> {
+    fn header(&self) -> Result<MemDbHeader> {
+        let data = self.get_data(0, mem::size_of::<MemDbHeader>())?;
+        let mut header = MemDbHeader::default();
         unsafe {
-            Ok(mem::transmute(self.get_data(0, mem::size_of::<MemDbHeader>())?.as_ptr()))
+            ptr::copy_nonoverlapping(data.as_ptr(), &mut header as *mut MemDbHeader as *mut u8,
+                                     mem::size_of::<MemDbHeader>());
         }
+        Ok(header)
     }
 
     #[inline(always)]
@@ -137,20 +174,158 @@ impl<'a> Symbol<'a> {
     pub fn addr(&self) -> u64 {
         self.addr
     }
+
+    /// Returns `true` if this is the last symbol of the object.
+    ///
+    /// Memdbs do not record where symbols end.  Every other symbol ends
+    /// where the next one starts but any address past the last symbol is
+    /// attributed to it, even if it lies in padding or unknown code.
+    pub fn is_last_in_object(&self) -> bool {
+        self.is_last
+    }
+
+    /// Returns `true` if the symbol was not in the symbol table of the
+    /// object but recovered from Objective-C or Swift metadata.
+    pub fn is_recovered(&self) -> bool {
+        self.is_recovered
+    }
+
+    /// Where the name of the symbol came from if the memdb records it.
+    pub fn source(&self) -> Option<SymbolSource> {
+        self.source
+    }
 }
 
-fn load_memdb<'a>(backing: Backing<'a>) -> Result<MemDb<'a>> {
-    let info = {
-        let header = backing.header()?;
-        if header.version != 2 {
-            return Err(ErrorKind::UnsupportedMemDbVersion.into());
-        }
-        header.sdk_info.to_sdk_info()
+/// Checks that the sections the header points to lie within the file,
+/// behind the header and do not overlap.
+fn check_sections(header: &MemDbHeader, file_size: usize) -> Result<()> {
+    let section = |start: u32, count: u32, item_size: usize| {
+        (start as usize, start as usize + count as usize * item_size)
     };
-    Ok(MemDb {
+    let mut sections = vec![
+        section(header.variants_start, header.variants_count, mem::size_of::<StoredSlice>()),
+        section(header.uuids_start, header.uuids_count, mem::size_of::<IndexedUuid>()),
+        section(header.object_names_start, header.object_names_count,
+                mem::size_of::<StoredSlice>()),
+        section(header.symbols_start, header.symbols_count, mem::size_of::<StoredSlice>()),
+        (header.tagged_object_names_start as usize, header.tagged_object_names_end as usize),
+    ];
+    if header.version >= 7 {
+        sections.push(section(header.conversion_info_start, header.conversion_info_len, 1));
+    }
+    sections.retain(|&(start, end)| start != end);
+    sections.sort();
+    let mut last_end = header.header_size();
+    for &(start, end) in &sections {
+        if start > end || start < last_end || end > file_size {
+            return Err(ErrorKind::BadMemDb.into());
+        }
+        last_end = end;
+    }
+    Ok(())
+}
+
+fn load_memdb<'a>(backing: Backing<'a>) -> Result<MemDb<'a>> {
+    let header = backing.header()?;
+    if header.version < MIN_MEMDB_VERSION || header.version > MEMDB_VERSION {
+        return Err(ErrorKind::UnsupportedMemDbVersion.into());
+    }
+    check_sections(&header, backing.buffer().len())?;
+    let mut info = header.sdk_info.to_sdk_info().ok_or(ErrorKind::BadMemDb)?;
+    if header.version >= 4 {
+        info.set_device_family(DeviceFamily::from_u32(header.device_family));
+    }
+    let is_thin = backing.get_slice::<StoredSlice>(header.variants_start as usize,
+                                                   header.variants_count as usize)?
+        .iter().any(|x| x.is_external());
+    let mut rv = MemDb {
         backing: backing,
         info: info,
-    })
+        is_thin,
+    };
+    if let Some(conversion_info) = rv.conversion_info()? {
+        rv.info.set_product_type(conversion_info.product_type.as_ref().map(|x| x.as_str()));
+    }
+    Ok(rv)
+}
+
+impl MemDbMetadata {
+    /// Reads the metadata from the start of a decompressed memdb.
+    ///
+    /// The conversion info is written before the symbols, so this stops
+    /// reading long before the end of the file.
+    pub fn read<R: Read>(mut src: R) -> Result<MemDbMetadata> {
+        let mut buf = vec![];
+        (&mut src).take(mem::size_of::<MemDbHeader>() as u64).read_to_end(&mut buf)?;
+        let header_size = mem::size_of::<MemDbHeader>();
+        // files before version 7 have a shorter header, the sections that
+        // follow it are at least as long as the missing fields
+        if buf.len() < header_size {
+            return Err(ErrorKind::BadMemDb.into());
+        }
+        let mut header = MemDbHeader::default();
+        unsafe {
+            ptr::copy_nonoverlapping(buf.as_ptr(), &mut header as *mut MemDbHeader as *mut u8,
+                                     header_size);
+        }
+        if header.version < MIN_MEMDB_VERSION || header.version > MEMDB_VERSION {
+            return Err(ErrorKind::UnsupportedMemDbVersion.into());
+        }
+        if header.version < 7 || header.conversion_info_len == 0 {
+            return Ok(MemDbMetadata { header, conversion_info: None });
+        }
+
+        let start = header.conversion_info_start as usize;
+        let end = start + header.conversion_info_len as usize;
+        if start < header.header_size() {
+            return Err(ErrorKind::BadMemDb.into());
+        }
+        let mut data = vec![];
+        if start < buf.len() {
+            data.extend_from_slice(&buf[start..end.min(buf.len())]);
+        } else {
+            io::copy(&mut (&mut src).take((start - buf.len()) as u64), &mut io::sink())?;
+        }
+        let missing = end - start - data.len();
+        (&mut src).take(missing as u64).read_to_end(&mut data)?;
+        if data.len() != end - start {
+            return Err(ErrorKind::BadMemDb.into());
+        }
+        Ok(MemDbMetadata {
+            header,
+            conversion_info: Some(serde_json::from_slice(&data)
+                .map_err(|_| Error::from(ErrorKind::BadMemDb))?),
+        })
+    }
+
+    /// The format version of the memdb
+    pub fn format_version(&self) -> u32 {
+        self.header.version
+    }
+
+    /// The SDK the memdb is for
+    pub fn info(&self) -> Result<SdkInfo> {
+        let mut info = self.header.sdk_info.to_sdk_info().ok_or(ErrorKind::BadMemDb)?;
+        if self.header.version >= 4 {
+            info.set_device_family(DeviceFamily::from_u32(self.header.device_family));
+        }
+        if let Some(ref conversion_info) = self.conversion_info {
+            info.set_product_type(conversion_info.product_type.as_ref().map(|x| x.as_str()));
+        }
+        Ok(info)
+    }
+
+    /// How the memdb was converted, memdbs before version 7 do not record
+    /// this
+    pub fn conversion_info(&self) -> Option<&ConversionInfo> {
+        self.conversion_info.as_ref()
+    }
+
+    /// Whether the memdb was converted as thin SDK that keeps its symbols
+    /// in shared objects
+    pub fn is_thin(&self) -> bool {
+        self.conversion_info.as_ref().map_or(false, |x| x.options.thin)
+    }
 }
 
 impl<'a> MemDb<'a> {
@@ -181,6 +356,84 @@ impl<'a> MemDb<'a> {
         &self.info
     }
 
+    /// Returns `true` if the memdb keeps symbols in the shared object store.
+    pub fn is_thin(&self) -> bool {
+        self.is_thin
+    }
+
+    /// Returns the format version the memdb was written in.
+    pub fn format_version(&self) -> Result<u32> {
+        Ok(self.backing.header()?.version)
+    }
+
+    /// Returns how the memdb was converted.
+    ///
+    /// Memdbs before version 7 do not record this.
+    pub fn conversion_info(&self) -> Result<Option<ConversionInfo>> {
+        let header = self.backing.header()?;
+        if header.version < 7 || header.conversion_info_len == 0 {
+            return Ok(None);
+        }
+        let data = self.backing.get_data(header.conversion_info_start as usize,
+                                         header.conversion_info_len as usize)?;
+        Ok(Some(serde_json::from_slice(data).map_err(|_| Error::from(ErrorKind::BadMemDb))?))
+    }
+
+    /// Returns the size of the memdb in bytes.
+    pub fn size(&self) -> usize {
+        self.backing.buffer().len()
+    }
+
+    /// Checks that all structures in the memdb are within bounds.
+    ///
+    /// This walks the entire file so it's not something that should be
+    /// done on every load.
+    pub fn validate(&self) -> Result<()> {
+        let header = self.backing.header()?;
+        let buffer_len = self.backing.buffer().len();
+        if header.tagged_object_names_start > header.tagged_object_names_end ||
+           header.tagged_object_names_end as usize > buffer_len {
+            return Err(ErrorKind::BadMemDb.into());
+        }
+
+        let object_names = self.object_names()?;
+        for slice in object_names {
+            self.get_string(slice)?;
+        }
+        let symbols = self.symbols()?;
+        for slice in symbols {
+            self.get_string(slice)?;
+        }
+
+        let check_items = |items: &[IndexItem]| -> Result<()> {
+            for item in items {
+                if item.src_id() as usize >= object_names.len() ||
+                   item.sym_id().map_or(false, |x| x as usize >= symbols.len()) {
+                    return Err(ErrorKind::BadMemDb.into());
+                }
+            }
+            Ok(())
+        };
+
+        let variants = self.variants()?;
+        for iuuid in self.uuids()? {
+            if iuuid.idx() >= variants.len() {
+                return Err(ErrorKind::BadMemDb.into());
+            }
+            match self.get_index(iuuid.uuid())? {
+                Some(AddrIndex::Flat(items)) => check_items(items)?,
+                Some(AddrIndex::Paged(directory)) => {
+                    for leaf in directory {
+                        check_items(self.get_leaf(leaf)?)?;
+                    }
+                }
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+
     /// Finds a symbol by UUID and address.
     pub fn lookup_by_uuid(&'a self, uuid: &Uuid, addr: u64) -> Option<Symbol<'a>> {
         self.lookup_impl(uuid, addr).ok().and_then(|x| x)
@@ -197,16 +450,59 @@ impl<'a> MemDb<'a> {
         }
     }
 
+    /// Returns the closest symbols before (or at) and after an address.
+    ///
+    /// This is meant for addresses that do not resolve to a symbol of
+    /// their own, for instance because they lie before the first symbol
+    /// of an image.
+    pub fn nearby_symbols(&'a self, uuid: &Uuid, addr: u64)
+        -> (Option<Symbol<'a>>, Option<Symbol<'a>>)
+    {
+        self.nearby_impl(uuid, addr).unwrap_or((None, None))
+    }
+
+    /// Returns `true` if the memdb has symbols for the image with the UUID.
+    pub fn has_uuid(&self, uuid: &Uuid) -> bool {
+        self.get_index(uuid).ok().map_or(false, |x| x.is_some())
+    }
+
+    /// Returns `true` if the symbols of the image with the UUID are kept in
+    /// the shared object store.
+    ///
+    /// Such images are known to the memdb but lookups in it never find a
+    /// symbol.  The symbols are in the memdb of the object instead.
+    pub fn is_shared_object(&self, uuid: &Uuid) -> bool {
+        if !self.is_thin {
+            return false;
+        }
+        if_chain! {
+            if let Ok(uuids) = self.uuids();
+            if let Some(iuuid) = binsearch_by_key(uuids, *uuid, |item| *item.uuid());
+            if iuuid.uuid() == uuid;
+            if let Ok(variants) = self.variants();
+            if let Some(variant_slice) = variants.get(iuuid.idx());
+            then {
+                variant_slice.is_external()
+            } else {
+                false
+            }
+        }
+    }
+
     /// Given an object namd and architecture this finds the image UUID in the file.
     pub fn find_uuid(&self, object_name: &str, arch: &str) -> Result<Option<&Uuid>> {
         let header = self.backing.header()?;
         let mut offset = header.tagged_object_names_start as usize;
         let refstr = format!("{}:{}", object_name, arch);
         let mut uuid_idx = 0;
-        while offset < header.tagged_object_names_end as usize {
-            let s = self.get_cstr(offset)?;
+        let end = header.tagged_object_names_end as usize;
+        while offset < end {
+            let s = self.get_cstr(offset, end)?;
             if s == &refstr {
-                return Ok(Some(&self.uuids()?[uuid_idx].uuid));
+                return match self.uuids()?.get(uuid_idx) {
+                    Some(iuuid) => Ok(Some(&iuuid.uuid)),
+                    None => Err(ErrorKind::BadMemDb.into()),
+                };
             }
             offset += s.len() + 1;
             uuid_idx += 1;
@@ -215,9 +511,9 @@ impl<'a> MemDb<'a> {
     }
 
     /// Given object name and architecture or UUID as string, this finds the
-    /// UUID in the file.
+    /// UUID in the file.  UUIDs can be in any format `parse_uuid` accepts.
     pub fn find_uuid_fuzzy(&self, name_or_uuid: &str) -> Result<Option<&Uuid>> {
-        if let Ok(parsed_uuid) = name_or_uuid.parse::<Uuid>() {
+        if let Some(parsed_uuid) = parse_uuid(name_or_uuid) {
             let uuids = self.uuids()?;
             if let Some(item) = binsearch_by_key(uuids, parsed_uuid, |item| *item.uuid()) {
                 if item.uuid() == &parsed_uuid {
@@ -241,32 +537,102 @@ impl<'a> MemDb<'a> {
 
     /// Returns the symbols for an Uuid
     pub fn iter_symbols(&'a self, uuid: &'a Uuid) -> Result<SymbolIter<'a>> {
-        let index = self.get_index(uuid)?.unwrap_or(&[][..]);
+        let (leaves, index) = match self.get_index(uuid)? {
+            Some(AddrIndex::Flat(index)) => (&[][..], index),
+            Some(AddrIndex::Paged(leaves)) => (leaves, &[][..]),
+            None => (&[][..], &[][..]),
+        };
         Ok(SymbolIter {
             memdb: self,
-            uuid: uuid,
-            index: index,
+            uuid,
+            leaves,
+            index,
             pos: 0,
         })
     }
 
-    fn get_cstr(&self, offset: usize) -> Result<&str> {
-        unsafe {
-            Ok(from_utf8(CStr::from_ptr(self.backing.buffer().as_ptr().offset(
-                offset as isize) as *const c_char).to_bytes())?)
-        }
+    /// Reads the zero terminated string at `offset`, which has to end
+    /// before `end`.
+    fn get_cstr(&self, offset: usize, end: usize) -> Result<&str> {
+        let data = self.backing.buffer().get(offset..end).ok_or(ErrorKind::BadMemDb)?;
+        let len = data.iter().position(|&x| x == 0).ok_or(ErrorKind::BadMemDb)?;
+        Ok(from_utf8(&data[..len])?)
     }
 
     fn lookup_impl(&'a self, uuid: &Uuid, addr: u64) -> Result<Option<Symbol<'a>>>
     {
-        if let Some(index) = self.get_index(uuid)? {
-            if let Some(item) = binsearch_by_key(index, addr, |item| item.addr()) {
-                return Ok(self.index_item_to_symbol(item, uuid)?);
+        let (index, has_more_leaves) = match self.get_index(uuid)? {
+            Some(AddrIndex::Flat(index)) => (index, false),
+            Some(AddrIndex::Paged(directory)) => {
+                match binsearch_by_key(directory, addr, |leaf| leaf.addr()) {
+                    Some(leaf) => {
+                        let is_last_leaf = ptr::eq(leaf, &directory[directory.len() - 1]);
+                        (self.get_leaf(leaf)?, !is_last_leaf)
+                    }
+                    None => { return Ok(None); }
+                }
             }
+            None => { return Ok(None); }
+        };
+        if let Some(item) = binsearch_by_key(index, addr, |item| item.addr()) {
+            let is_last = !has_more_leaves && ptr::eq(item, &index[index.len() - 1]);
+            return Ok(self.index_item_to_symbol(item, uuid)?.map(|mut sym| {
+                sym.is_last = is_last;
+                sym
+            }));
         }
         Ok(None)
     }
 
+    fn nearby_impl(&'a self, uuid: &Uuid, addr: u64)
+        -> Result<(Option<Symbol<'a>>, Option<Symbol<'a>>)>
+    {
+        let leaves = match self.get_index(uuid)? {
+            Some(AddrIndex::Flat(index)) => vec![index],
+            Some(AddrIndex::Paged(directory)) => {
+                let mut leaves = vec![];
+                for leaf in directory {
+                    leaves.push(self.get_leaf(leaf)?);
+                }
+                leaves
+            }
+            None => { return Ok((None, None)); }
+        };
+
+        // split the items into the ones up to the address and the ones
+        // after it, in reverse order for the former.
+        let split = leaves.iter()
+            .take_while(|items| items.first().map_or(true, |x| x.addr() <= addr))
+            .count();
+        let mut before = leaves[..split].to_vec();
+        let mut after = leaves[split..].to_vec();
+        if let Some(items) = before.pop() {
+            let pos = match items.binary_search_by(|x| {
+                if x.addr() <= addr { Ordering::Less } else { Ordering::Greater }
+            }) {
+                Ok(pos) | Err(pos) => pos,
+            };
+            before.push(&items[..pos]);
+            after.insert(0, &items[pos..]);
+        }
+
+        let mut preceding = None;
+        for item in before.iter().rev().flat_map(|items| items.iter().rev()) {
+            if let Some(sym) = self.index_item_to_symbol(item, uuid)? {
+                preceding = Some(sym);
+                break;
+            }
+        }
+        let mut following = None;
+        for item in after.iter().flat_map(|items| items.iter()) {
+            if let Some(sym) = self.index_item_to_symbol(item, uuid)? {
+                following = Some(sym);
+                break;
+            }
+        }
+        Ok((preceding, following))
+    }
+
     #[inline(always)]
     fn uuids(&self) -> Result<&[IndexedUuid]> {
         let head = self.backing.header()?;
@@ -280,27 +646,32 @@ impl<'a> MemDb<'a> {
     }
 
     #[inline(always)]
-    fn get_index(&self, uuid: &Uuid) -> Result<Option<&[IndexItem]>> {
+    fn get_index(&self, uuid: &Uuid) -> Result<Option<AddrIndex>> {
         let uuids = self.uuids()?;
         if let Some(iuuid) = binsearch_by_key(uuids, *uuid, |item| *item.uuid()) {
             // only consider exact matches
             if iuuid.uuid() != uuid {
                 return Ok(None);
             }
-            let variant_slice = &self.variants()?[iuuid.idx()];
-            unsafe {
-                let data = self.backing.get_data(variant_slice.offset(),
-                                                 variant_slice.len())?;
-                let count = variant_slice.len() / mem::size_of::<IndexItem>();
-                return Ok(Some(slice::from_raw_parts(
-                    mem::transmute(data.as_ptr()),
-                    count
-                )));
+            let variant_slice = self.variants()?.get(iuuid.idx())
+                .ok_or(ErrorKind::BadMemDb)?;
+            if self.backing.header()?.version >= 3 {
+                let count = variant_slice.len() / mem::size_of::<LeafRef>();
+                return Ok(Some(AddrIndex::Paged(
+                    self.backing.get_slice(variant_slice.offset(), count)?)));
             }
+            let count = variant_slice.len() / mem::size_of::<IndexItem>();
+            return Ok(Some(AddrIndex::Flat(
+                self.backing.get_slice(variant_slice.offset(), count)?)));
         }
         Ok(None)
     }
 
+    #[inline(always)]
+    fn get_leaf(&self, leaf: &LeafRef) -> Result<&[IndexItem]> {
+        self.backing.get_slice(leaf.offset(), leaf.count())
+    }
+
     #[inline(always)]
     fn symbols(&self) -> Result<&[StoredSlice]> {
         let head = self.backing.header()?;
@@ -318,19 +689,22 @@ impl<'a> MemDb<'a> {
     fn get_string(&'a self, slice: &StoredSlice) -> Result<Cow<'a, str>> {
         let bytes = self.backing.get_data(slice.offset(), slice.len())?;
         if slice.is_compressed() {
-            panic!("We do not support compression");
+            // never written, compressed strings are not supported
+            Err(ErrorKind::BadMemDb.into())
         } else {
             Ok(Cow::Borrowed(from_utf8(bytes)?))
         }
     }
 
     fn get_object_name(&'a self, src_id: u16) -> Result<Cow<'a, str>> {
-        self.get_string(&self.object_names()?[src_id as usize])
+        self.get_string(self.object_names()?.get(src_id as usize)
+                        .ok_or(ErrorKind::BadMemDb)?)
     }
 
     fn get_symbol(&'a self, sym_id: Option<u32>) -> Result<Option<Cow<'a, str>>> {
         if let Some(sym_id) = sym_id {
-            Ok(Some(self.get_string(&self.symbols()?[sym_id as usize])?))
+            Ok(Some(self.get_string(self.symbols()?.get(sym_id as usize)
+                                    .ok_or(ErrorKind::BadMemDb)?)?))
         } else {
             Ok(None)
         }
@@ -343,6 +717,9 @@ impl<'a> MemDb<'a> {
                 object_name: self.get_object_name(ii.src_id())?,
                 symbol: symbol,
                 addr: ii.addr(),
+                is_last: false,
+                is_recovered: ii.is_recovered(),
+                source: ii.source(),
             }))
         } else {
             Ok(None)
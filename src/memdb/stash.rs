@@ -3,39 +3,212 @@
 //! The `MemDbStash` pulls in remote SDKs from an S3 bucket and provides
 //! access to it.  This is used by the symbol server to manage the local
 //! cache and also to refer to memdb files that are mmap'ed in.
+use std::env;
+use std::fmt;
+use std::hash::Hash;
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
 use std::iter::FromIterator;
+use std::mem;
+use std::ptr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Values as HashMapValuesIter;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant, SystemTime};
 
+use libc;
+use md5;
+use serde::Serialize;
 use serde_json;
-use xz2::write::XzDecoder;
-use chrono::Utc;
-use console::style;
-use indicatif::{ProgressBar, ProgressStyle};
+use uuid::Uuid;
+use xz2::read::XzDecoder as XzStreamDecoder;
+use xz2::write::{XzDecoder, XzEncoder};
+use chrono::{DateTime, Duration, Utc};
+use num_cpus;
+use tempfile::NamedTempFileOptions;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 
-use super::read::MemDb;
+use super::audit::{AuditAction, AuditEvent, AuditLog};
+use super::encryption::{get_encryption_key_id, open_file, EncryptingWriter, EncryptionKey};
+use super::summary::{SyncSummaries, SyncSummary};
+use super::read::{MemDb, MemDbMetadata};
+use super::types::{ConversionInfo, MemDbHeader};
+use super::objects::{get_object_manifest_path, get_shared_object_path, ManifestObject,
+                     ObjectManifest};
+use super::usage::UsageStats;
 use super::super::config::Config;
-use super::super::sdk::SdkInfo;
-use super::super::s3::S3Server as S3;
-use super::super::utils::{copy_with_progress, HumanDuration, IgnorePatterns, Rev};
-use super::super::{Result, ResultExt, ErrorKind};
+use super::super::sdk::{DeviceFamily, SdkInfo};
+use super::super::s3::{S3Server as S3, UpstreamListing};
+use super::super::mirror::{MirrorSdk, MirrorState, PrimaryServer};
+use super::super::signatures::SignaturePolicy;
+use super::super::report::{Reporter, SdkStatus, default_reporter};
+use super::super::utils::{copy_with_progress, ensure_free_space, link_or_copy, move_file,
+                          parallel_map, random_bytes,
+                          is_abandoned_temp_dir,
+                          FileLock, HashingWriter, HumanDuration, IgnorePatterns,
+                          OwnedTempDir, Rev, TeeWriter, write_file_atomic};
+use super::super::{Result, ResultExt, Error, ErrorKind};
+
+/// How much larger than the download a decompressed memdb is assumed to be.
+///
+/// Memdbs compress to about a third of their size, this leaves some room.
+const DECOMPRESSION_RATIO: u64 = 4;
+
+/// How many shared objects stay open at most
+const MAX_SHARED_OBJECTS: usize = 4096;
+
+/// How many shared objects are fetched at once at most
+const MAX_OBJECT_FETCHES: usize = 64;
+
+/// How many failed fetches of shared objects are remembered at most
+const MAX_FAILED_FETCHES: usize = 4096;
+
+/// How long a shared object is not fetched again after the first failure,
+/// doubled with every further failure up to `MAX_FETCH_BACKOFF_SECS`
+const FETCH_BACKOFF_SECS: u64 = 10;
+const MAX_FETCH_BACKOFF_SECS: u64 = 3600;
 
 /// Helper for synching
 pub struct SyncOptions {
-    pub user_facing: bool,
+    pub reporter: Arc<Reporter>,
+    /// Who triggered the sync, as recorded in the audit log
+    pub actor: String,
+    /// Once set the sync stops after the SDK that is being downloaded
+    pub cancel: Arc<AtomicBool>,
+    /// The sync stops like it was cancelled instead of starting a download
+    /// that is not expected to finish by then
+    pub deadline: Option<DateTime<Utc>>,
+}
+
+/// Helper for verifying
+pub struct VerifyOptions {
+    pub reporter: Arc<Reporter>,
+    pub jobs: usize,
+}
+
+/// Limits the results of a fuzzy SDK match
+#[derive(Debug, Clone)]
+pub struct FuzzyMatchOptions {
+    /// The maximum number of SDKs returned
+    pub max_results: usize,
+    /// The worst score an SDK may have to be returned
+    pub max_score: Option<u32>,
+    /// Only return inexact matches of this device family
+    pub device_family: Option<DeviceFamily>,
+    /// Only return inexact matches of this product type (`iPhone12,1`)
+    pub product_type: Option<String>,
+}
+
+/// A memdb that failed verification
+#[derive(Debug)]
+pub struct VerifyFailure {
+    info: SdkInfo,
+    reason: String,
+}
+
+/// An SDK matched for a requested SDK id
+#[derive(Debug, Clone)]
+pub struct SdkMatch {
+    info: SdkInfo,
+    score: u32,
+}
+
+/// A memdb that is currently mapped into the process.
+struct OpenMemDb {
+    memdb: Arc<MemDb<'static>>,
+    last_used: AtomicUsize,
 }
 
 /// The main memdb stash type
 pub struct MemDbStash {
     path: PathBuf,
-    s3: S3,
+    upstream: Arc<Upstream>,
+    signatures: SignaturePolicy,
+    encryption_key: Option<Arc<EncryptionKey>>,
     local_state: RwLock<Option<Arc<SdkSyncState>>>,
-    memdbs: RwLock<HashMap<SdkInfo, Arc<MemDb<'static>>>>,
-    ignore_patterns: IgnorePatterns,
+    local_state_stamp: Mutex<Option<StateStamp>>,
+    memdbs: RwLock<HashMap<SdkInfo, OpenMemDb>>,
+    shared_objects: RwLock<HashMap<Uuid, OpenMemDb>>,
+    object_fetches: Arc<Mutex<HashMap<Uuid, Arc<ObjectFetch>>>>,
+    failed_fetches: Arc<Mutex<HashMap<Uuid, FailedFetch>>>,
+    lazy_objects: bool,
+    /// The SDKs of the running sync that are replaced by a newer version
+    updating: RwLock<HashSet<SdkInfo>>,
+    serve_stale: bool,
+    object_fetch_budget: StdDuration,
+    prefetch_objects: usize,
+    max_open_memdbs: Option<usize>,
+    access_clock: AtomicUsize,
+    usage: Mutex<UsageStats>,
+    usage_dirty: AtomicBool,
+    ignore_patterns: RwLock<IgnorePatterns>,
+    ignore_override: RwLock<Option<IgnorePatterns>>,
+    fuzzy_match_options: FuzzyMatchOptions,
+    sdk_aliases: HashMap<SdkInfo, SdkInfo>,
+    audit_log: AuditLog,
+    sync_summaries: SyncSummaries,
+    read_only: bool,
+    compress_memdbs: bool,
+    decompressed_cache_size: u64,
+    listing_ttl: Duration,
+    remote_listing: Mutex<Option<CachedListing>>,
+    gc_max_age: Duration,
+    gc_stats: Mutex<GcStats>,
+    audit_stats: Mutex<AuditStats>,
+    memdb_cache_hits: AtomicUsize,
+    memdb_cache_misses: AtomicUsize,
+    scratch_path: PathBuf,
+    worker_threads: usize,
+}
+
+/// Where the stash syncs from
+enum Upstream {
+    S3(S3),
+    /// Another server that is followed over the mirror API
+    Primary(PrimaryServer),
+}
+
+/// A download of a shared object that lookups can wait for
+#[derive(Default)]
+struct ObjectFetch {
+    /// Whether the object was stored, once the download ended
+    outcome: Mutex<Option<bool>>,
+    finished: Condvar,
+}
+
+/// A shared object that could not be fetched
+struct FailedFetch {
+    failures: u32,
+    retry_after: Instant,
+}
+
+/// How waiting for a shared object ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FetchOutcome {
+    /// The object is in the object store
+    Stored,
+    /// The download failed, now or not long ago
+    Failed,
+    /// The download did not end in time or could not start yet
+    Pending,
+}
+
+/// The last successful listing of the bucket
+struct CachedListing {
+    fetched: DateTime<Utc>,
+    state: SdkSyncState,
+}
+
+/// Identifies a version of the sync state file on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StateStamp {
+    modified: SystemTime,
+    len: u64,
 }
 
 /// Information about a remotely available SDK
@@ -50,7 +223,32 @@ pub struct RemoteSdk {
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 struct SdkSyncState {
     sdks: HashMap<String, RemoteSdk>,
+    /// Bumped once per change of the served SDKs (a completed sync that
+    /// changed something, a quarantine).
     revision: Option<u64>,
+    /// Bumped on every write of the state.
+    #[serde(default)]
+    generation: u64,
+    #[serde(default)]
+    checksums: HashMap<String, String>,
+    /// SDKs withdrawn upstream, by memdb filename
+    #[serde(default)]
+    withdrawn: HashMap<String, WithdrawnSdk>,
+}
+
+/// An SDK that was recalled upstream because its memdb is known to be bad
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WithdrawnSdk {
+    info: SdkInfo,
+    reason: String,
+}
+
+/// A memdb that was moved out of the stash because it is broken
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuarantinedSdk {
+    sdk_id: String,
+    reason: String,
+    timestamp: i64,
 }
 
 /// Information about the health of the stash sync
@@ -61,6 +259,112 @@ pub struct SyncStatus {
     different: u32,
     revision: u64,
     offline: bool,
+    warning: Option<String>,
+}
+
+/// How an SDK differs between the stash and upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdkDifferenceKind {
+    /// The stash has the SDK but upstream does not
+    LocalOnly,
+    /// Upstream has the SDK but the stash does not
+    RemoteOnly,
+    /// Both have the SDK but the size or etag differs
+    Different,
+}
+
+/// An SDK that differs between the stash and upstream
+#[derive(Debug, Clone)]
+pub struct SdkDifference {
+    info: SdkInfo,
+    kind: SdkDifferenceKind,
+    local: Option<RemoteSdk>,
+    remote: Option<RemoteSdk>,
+}
+
+/// Result of reconciling the sync state with the files on disk
+#[derive(Debug, Default)]
+pub struct ConsistencyReport {
+    checked: usize,
+    missing: Vec<SdkInfo>,
+    unknown: Vec<String>,
+}
+
+/// How the memdb of an SDK was converted
+#[derive(Serialize, Debug, Clone)]
+pub struct SdkConversion {
+    sdk_id: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    format_version: Option<u32>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    device_family: Option<DeviceFamily>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    product_type: Option<String>,
+    /// `None` for memdbs written before this was recorded
+    conversion: Option<ConversionInfo>,
+    /// Why the memdb could not be read
+    #[serde(skip_serializing_if="Option::is_none")]
+    error: Option<String>,
+}
+
+/// What reloading the memdb of an SDK did
+#[derive(Serialize, Debug, Clone)]
+pub struct SdkReload {
+    sdk_id: String,
+    /// If the memdb was open before
+    evicted: bool,
+    /// `reloaded`, `quarantined` (it failed verification) or `redownload`
+    outcome: &'static str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    reason: Option<String>,
+}
+
+impl SdkReload {
+    /// `reloaded`, `quarantined` or `redownload`
+    pub fn outcome(&self) -> &str {
+        self.outcome
+    }
+}
+
+/// Counts what the garbage collection of temporary files removed
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct GcStats {
+    runs: u64,
+    removed_files: u64,
+    removed_bytes: u64,
+    last_run: Option<i64>,
+}
+
+/// Counts what audits of random samples of the stash found
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct AuditStats {
+    runs: u64,
+    checked: u64,
+    failures: u64,
+    drifted: u64,
+    last_run: Option<i64>,
+}
+
+/// Counts how often memdbs were already open when they were needed
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct MemDbCacheStats {
+    hits: u64,
+    misses: u64,
+}
+
+/// A memdb file of the stash opened for sending it to a client
+pub struct MemDbFile {
+    contents: MemDbContents,
+    size: u64,
+    modified: SystemTime,
+    etag: Option<String>,
+}
+
+/// What a memdb file is sent from
+enum MemDbContents {
+    File(fs::File),
+    /// The decrypted contents of an encrypted memdb
+    Data(Vec<u8>),
 }
 
 impl RemoteSdk {
@@ -84,6 +388,11 @@ impl RemoteSdk {
         self.size
     }
 
+    /// The etag of the compressed SDK
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
     /// Returns the SDK info
     pub fn info(&self) -> &SdkInfo {
         &self.info
@@ -104,17 +413,62 @@ impl SdkSyncState {
 
     pub fn remove_sdk(&mut self, info: &SdkInfo) {
         self.sdks.remove(&info.memdb_filename());
+        self.checksums.remove(&info.memdb_filename());
+    }
+
+    pub fn get_checksum(&self, info: &SdkInfo) -> Option<&str> {
+        self.checksums.get(&info.memdb_filename()).map(|x| x.as_str())
+    }
+
+    pub fn set_checksum(&mut self, info: &SdkInfo, checksum: String) {
+        self.checksums.insert(info.memdb_filename(), checksum);
     }
 
     pub fn sdks<'a>(&'a self) -> RemoteSdkIter<'a> {
         self.sdks.values()
     }
 
+    pub fn get_withdrawn(&self, info: &SdkInfo) -> Option<&WithdrawnSdk> {
+        self.withdrawn.get(&info.memdb_filename())
+    }
+
     pub fn sdk_count(&self) -> usize {
         self.sdks.len()
     }
 }
 
+impl fmt::Display for SdkDifferenceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            SdkDifferenceKind::LocalOnly => "local only",
+            SdkDifferenceKind::RemoteOnly => "remote only",
+            SdkDifferenceKind::Different => "different",
+        })
+    }
+}
+
+impl SdkDifference {
+    /// The SDK that differs
+    pub fn info(&self) -> &SdkInfo {
+        &self.info
+    }
+
+    /// How the SDK differs
+    pub fn kind(&self) -> SdkDifferenceKind {
+        self.kind
+    }
+
+    /// The SDK as the stash has it
+    pub fn local(&self) -> Option<&RemoteSdk> {
+        self.local.as_ref()
+    }
+
+    /// The SDK as upstream has it
+    pub fn remote(&self) -> Option<&RemoteSdk> {
+        self.remote.as_ref()
+    }
+}
+
 impl SyncStatus {
 
     /// Indicates that the server is running offline (no S3 access)
@@ -122,6 +476,22 @@ impl SyncStatus {
         self.offline
     }
 
+    /// Explains why S3 is considered offline if it is reachable but
+    /// unusable (clock skew, incomplete listings)
+    pub fn warning(&self) -> Option<&str> {
+        self.warning.as_ref().map(|x| x.as_str())
+    }
+
+    /// Returns the number of upstream SDKs missing locally
+    pub fn missing(&self) -> u32 {
+        self.missing
+    }
+
+    /// Returns the number of local SDKs that differ from upstream
+    pub fn different(&self) -> u32 {
+        self.different
+    }
+
     /// Returns the lag (number of SDKs behind upstream)
     pub fn lag(&self) -> u32 {
         self.missing + self.different
@@ -144,270 +514,2170 @@ impl SyncStatus {
     }
 }
 
-impl Default for SyncOptions {
-    fn default() -> SyncOptions {
-        SyncOptions {
-            user_facing: false,
-        }
+impl WithdrawnSdk {
+    /// Creates a tombstone for an SDK
+    pub fn new(info: SdkInfo, reason: String) -> WithdrawnSdk {
+        WithdrawnSdk { info, reason }
     }
-}
 
-impl MemDbStash {
-    /// Opens a stash for a given config.
-    pub fn new(config: &Config) -> Result<MemDbStash> {
-        Ok(MemDbStash {
-            path: config.get_symbol_dir()?.to_path_buf(),
-            s3: S3::from_config(config)?,
-            local_state: RwLock::new(None),
-            memdbs: RwLock::new(HashMap::new()),
-            ignore_patterns: config.get_ignore_patterns()?.clone(),
-        })
+    /// The withdrawn SDK
+    pub fn info(&self) -> &SdkInfo {
+        &self.info
     }
 
-    fn get_local_sync_state_filename(&self) -> PathBuf {
-        self.path.join("sync.state")
+    /// Why the SDK was withdrawn
+    pub fn reason(&self) -> &str {
+        &self.reason
     }
+}
 
-    fn save_state(&self, new_state: &SdkSyncState, filename: &Path) -> Result<()> {
-        let mut tmp_filename = filename.to_path_buf();
-        tmp_filename.set_extension("tempstate");
-        {
-            let mut f = fs::File::create(&tmp_filename)?;
-            serde_json::to_writer(&mut f, new_state)
-                .chain_err(|| "Could not update sync state")?;
-        }
-        fs::rename(&tmp_filename, &filename)?;
-        Ok(())
+impl SdkConversion {
+    /// The id of the SDK
+    pub fn sdk_id(&self) -> &str {
+        &self.sdk_id
     }
 
-    fn read_local_state(&self) -> Result<SdkSyncState> {
-        let rv: SdkSyncState = match fs::File::open(&self.get_local_sync_state_filename()) {
-            Ok(f) => serde_json::from_reader(io::BufReader::new(f))
-                .chain_err(|| "Parsing error on loading sync state")?,
-            Err(err) => {
-                if err.kind() == io::ErrorKind::NotFound {
-                    Default::default()
-                } else {
-                    return Err(err).chain_err(|| "Error loading sync state");
-                }
-            }
-        };
-        let mut opt = self.local_state.write().unwrap();
-        *opt = Some(Arc::new(rv.clone()));
-        Ok(rv)
+    /// The format version the memdb was written in, `None` if the memdb
+    /// could not be read
+    pub fn format_version(&self) -> Option<u32> {
+        self.format_version
     }
 
-    fn get_local_state(&self) -> Result<Arc<SdkSyncState>> {
-        if let Some(ref arc) = *self.local_state.read().unwrap() {
-            return Ok(arc.clone());
-        }
-        self.read_local_state()?;
-        Ok(self.local_state.read().unwrap().as_ref().unwrap().clone())
+    /// The device family recorded in the memdb if known
+    pub fn device_family(&self) -> Option<DeviceFamily> {
+        self.device_family
     }
 
-    fn save_local_state(&self, new_state: &SdkSyncState) -> Result<()> {
-        self.save_state(new_state, &self.get_local_sync_state_filename())?;
-        let mut opt = self.local_state.write().unwrap();
-        *opt = Some(Arc::new(new_state.clone()));
-        Ok(())
+    /// The product type recorded in the memdb if known
+    pub fn product_type(&self) -> Option<&str> {
+        self.product_type.as_ref().map(|x| x.as_str())
     }
 
-    fn fetch_remote_state(&self) -> Result<SdkSyncState> {
-        let mut sdks = HashMap::new();
-        for remote_sdk in self.s3.list_upstream_sdks()? {
-            sdks.insert(remote_sdk.info().memdb_filename().into(), remote_sdk);
-        }
-        Ok(SdkSyncState { sdks: sdks, revision: None })
+    /// How the memdb was converted, `None` for old memdbs
+    pub fn conversion(&self) -> Option<&ConversionInfo> {
+        self.conversion.as_ref()
     }
 
-    fn update_sdk(&self, sdk: &RemoteSdk, options: &SyncOptions) -> Result<()> {
-        // XXX: the progress bar here can stall out because we currently
-        // need to buffer the download into memory in the s3 code :(
-        let progress = if options.user_facing {
-            ProgressBar::new(sdk.size())
-        } else {
-            info!("updating {}", sdk.info());
-            ProgressBar::hidden()
-        };
-        progress.set_style(ProgressStyle::default_bar()
-            .template("{wide_bar} {bytes}/{total_bytes}"));
-        let started = Utc::now();
-        println!("{} {}", style("Updating").green(), sdk.info());
-        let mut src = self.s3.download_sdk(sdk)?;
-        let dst = fs::File::create(self.path.join(sdk.info().memdb_filename()))?;
-        let mut dst = XzDecoder::new(dst);
-        copy_with_progress(&progress, &mut src, &mut dst)?;
-        progress.finish_and_clear();
+    /// Why the memdb could not be read
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_ref().map(|x| x.as_str())
+    }
+}
 
-        let duration = Utc::now() - started;
-        if !options.user_facing {
-            info!("updated {} in {}", sdk.info(), HumanDuration(duration));
-        }
-        Ok(())
+impl QuarantinedSdk {
+    /// The id of the SDK
+    pub fn sdk_id(&self) -> &str {
+        &self.sdk_id
     }
 
-    fn remove_sdk(&self, sdk: &RemoteSdk, options: &SyncOptions) -> Result<()> {
-        if options.user_facing {
-            info!("{} {}", style("Deleting").red(), sdk.info());
-        } else {
-            info!("removing {}", sdk.info());
-        }
-        if let Err(err) = fs::remove_file(self.path.join(sdk.info().memdb_filename())) {
-            if err.kind() != io::ErrorKind::NotFound {
-                return Err(err.into());
-            }
-        }
-        Ok(())
+    /// Why the memdb was quarantined
+    pub fn reason(&self) -> &str {
+        &self.reason
     }
 
-    /// Returns the current revision
-    pub fn get_revision(&self) -> Result<u64> {
-        Ok(self.read_local_state()?.revision.unwrap_or(0))
+    /// Unix timestamp of when the memdb was quarantined
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
     }
+}
 
-    /// Returns the number of local SDKs
-    pub fn sdk_count(&self) -> Result<usize> {
-        Ok(self.get_local_state()?.sdk_count())
+impl VerifyFailure {
+    /// The SDK that failed verification
+    pub fn info(&self) -> &SdkInfo {
+        &self.info
     }
 
-    /// Returns a list of all synched SDKs
-    pub fn list_sdks(&self) -> Result<Vec<SdkInfo>> {
-        let local_state = self.get_local_state()?;
-        let mut rv : Vec<_> = local_state.sdks().map(|sdk| {
-            sdk.info().clone()
-        }).collect();
-        rv.sort();
-        Ok(rv)
+    /// Why the verification failed
+    pub fn reason(&self) -> &str {
+        &self.reason
     }
+}
 
-    /// Checks the local stash against the server
-    pub fn get_sync_status(&self) -> Result<SyncStatus> {
-        let local_state = self.read_local_state()?;
+impl SdkMatch {
+    /// The matched SDK
+    pub fn info(&self) -> &SdkInfo {
+        &self.info
+    }
 
-        let mut remote_total = 0;
-        let mut missing = 0;
-        let mut different = 0;
-        let mut offline = false;
+    /// How well the SDK matches; lower is better and `0` is exact
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+}
 
-        match self.fetch_remote_state() {
-            Ok(remote_state) => {
-                for sdk in remote_state.sdks() {
-                    if self.sdk_is_ignored(sdk.info()) {
-                        continue;
-                    }
-                    if let Some(local_sdk) = local_state.get_sdk(sdk.info()) {
-                        if local_sdk != sdk {
-                            different += 1;
-                        }
-                    } else {
-                        missing += 1;
-                    }
-                    remote_total += 1;
-                }
-            }
-            Err(err) => {
-                if let &ErrorKind::S3Unavailable(_) = err.kind() {
-                    offline = true;
-                } else {
-                    return Err(err);
-                }
-            }
+impl Default for VerifyOptions {
+    fn default() -> VerifyOptions {
+        VerifyOptions {
+            reporter: default_reporter(),
+            jobs: num_cpus::get(),
         }
+    }
+}
 
-        Ok(SyncStatus {
-            remote_total: remote_total as u32,
-            missing: missing as u32,
-            different: different as u32,
-            revision: local_state.revision.unwrap_or(0),
-            offline: offline,
-        })
+impl Default for SyncOptions {
+    fn default() -> SyncOptions {
+        SyncOptions {
+            reporter: default_reporter(),
+            actor: "sync".into(),
+            cancel: Arc::new(AtomicBool::new(false)),
+            deadline: None,
+        }
     }
+}
 
-    /// Checks if the SDK is ignored by config
-    pub fn sdk_is_ignored(&self, info: &SdkInfo) -> bool {
-        self.ignore_patterns.is_match(&info.sdk_id())
+impl ConsistencyReport {
+    /// The number of SDKs in the sync state that were checked
+    pub fn checked(&self) -> usize {
+        self.checked
     }
 
-    /// Synchronize the local stash with the server
-    pub fn sync(&self, options: SyncOptions) -> Result<()> {
-        let mut local_state = self.read_local_state()?;
-        let remote_state = self.fetch_remote_state()?;
-        let started = Utc::now();
-        let mut changed = false;
-        let mut to_delete : HashSet<_> = HashSet::from_iter(
-            local_state.sdks().map(|x| x.info().clone()));
-        let mut sdks : Vec<_> = remote_state.sdks()
-            .map(|x| x.info().clone()).collect();
-        sdks.sort_by(|a, b| b.cmp(a));
+    /// SDKs whose memdb was missing and that were dropped from the state
+    pub fn missing(&self) -> &[SdkInfo] {
+        &self.missing
+    }
 
-        for sdk_info in sdks.iter() {
-            if !self.sdk_is_ignored(sdk_info) {
-                let mut changed_something = false;
-                let sdk = remote_state.get_sdk(sdk_info).unwrap();
-                if let Some(local_sdk) = local_state.get_sdk(sdk_info) {
-                    if local_sdk != sdk {
-                        self.update_sdk(&sdk, &options)?;
-                        self.memdbs.write().unwrap().remove(sdk_info);
-                        changed_something = true;
-                    } else if options.user_facing {
-                        println!("{} {}", style("Unchanged").cyan(), sdk_info);
-                    } else {
-                        debug!("unchanged sdk {}", sdk_info);
-                    }
-                } else {
-                    self.update_sdk(&sdk, &options)?;
-                    changed_something = true;
-                }
-                if changed_something {
-                    changed = true;
-                    local_state.update_sdk(&sdk);
-                    local_state.revision = Some(local_state.revision.unwrap_or(0) + 1);
-                    self.save_local_state(&local_state)?;
-                }
-            } else {
-                if options.user_facing {
-                    println!("{} {} by config", style("Ignored").yellow(), sdk_info);
-                } else {
-                    debug!("ignored sdk {} by config", sdk_info);
-                }
-            }
+    /// Memdb files in the symbol directory that the state does not know
+    pub fn unknown(&self) -> &[String] {
+        &self.unknown
+    }
 
-            to_delete.remove(sdk_info);
-        }
+    /// Returns `true` if the state and the files on disk agree.
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.unknown.is_empty()
+    }
+}
 
-        for sdk_info in to_delete.iter() {
-            local_state.remove_sdk(sdk_info);
-            if let Some(sdk) = local_state.get_sdk(sdk_info) {
-                self.remove_sdk(sdk, &options)?;
-                self.memdbs.write().unwrap().remove(&sdk.info());
-            }
+impl MemDbFile {
+    /// The opened file unless the memdb is encrypted
+    pub fn file(&self) -> Option<&fs::File> {
+        match self.contents {
+            MemDbContents::File(ref file) => Some(file),
+            MemDbContents::Data(_) => None,
         }
+    }
 
-        let duration = Utc::now() - started;
-        if options.user_facing {
-            println!("Sync done in {}", HumanDuration(duration));
-        } else if changed {
-            info!("finished sync in {}", HumanDuration(duration));
+    /// The decrypted contents if the memdb is encrypted
+    pub fn data(&self) -> Option<&[u8]> {
+        match self.contents {
+            MemDbContents::File(_) => None,
+            MemDbContents::Data(ref data) => Some(data),
         }
+    }
 
-        // save us one last time
-        local_state.revision = Some(local_state.revision.unwrap_or(0) + 1);
-        self.save_local_state(&local_state)?;
+    /// The size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
 
-        Ok(())
+    /// When the file was last written
+    pub fn modified(&self) -> SystemTime {
+        self.modified
     }
 
-    /// Looks up an memdb by an SDK info if it's available.
-    ///
-    /// This returns a memdb wrapped in an arc as internally the system
+    /// The etag of the compressed memdb or the checksum of the
+    /// decompressed one if known
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_ref().map(|x| x.as_str())
+    }
+}
+
+impl GcStats {
+    /// How often the garbage collection ran
+    pub fn runs(&self) -> u64 {
+        self.runs
+    }
+
+    /// The number of files and folders removed
+    pub fn removed_files(&self) -> u64 {
+        self.removed_files
+    }
+
+    /// The number of bytes freed
+    pub fn removed_bytes(&self) -> u64 {
+        self.removed_bytes
+    }
+
+    /// Unix timestamp of the last run
+    pub fn last_run(&self) -> Option<i64> {
+        self.last_run
+    }
+}
+
+impl AuditStats {
+    /// How often the audit ran
+    pub fn runs(&self) -> u64 {
+        self.runs
+    }
+
+    /// The number of SDKs that were checked
+    pub fn checked(&self) -> u64 {
+        self.checked
+    }
+
+    /// The number of memdbs that failed verification and were quarantined
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /// The number of SDKs whose etag differs from the one in S3 or that
+    /// are gone from S3
+    pub fn drifted(&self) -> u64 {
+        self.drifted
+    }
+
+    /// Unix timestamp of the last run
+    pub fn last_run(&self) -> Option<i64> {
+        self.last_run
+    }
+}
+
+impl ObjectFetch {
+    fn finish(&self, stored: bool) {
+        *self.outcome.lock().unwrap() = Some(stored);
+        self.finished.notify_all();
+    }
+
+    /// Waits for the download to end, forever if there is no timeout.
+    fn wait(&self, timeout: Option<StdDuration>) -> FetchOutcome {
+        let deadline = timeout.map(|x| Instant::now() + x);
+        let mut outcome = self.outcome.lock().unwrap();
+        while outcome.is_none() {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    outcome = self.finished.wait_timeout(outcome, deadline - now).unwrap().0;
+                }
+                None => {
+                    outcome = self.finished.wait(outcome).unwrap();
+                }
+            }
+        }
+        match *outcome {
+            Some(true) => FetchOutcome::Stored,
+            Some(false) => FetchOutcome::Failed,
+            None => FetchOutcome::Pending,
+        }
+    }
+}
+
+impl FailedFetch {
+    /// Records another failure and backs off exponentially.
+    fn record(failed: Option<FailedFetch>) -> FailedFetch {
+        let failures = failed.map_or(0, |x| x.failures).saturating_add(1);
+        let backoff = FETCH_BACKOFF_SECS.saturating_mul(1 << (failures - 1).min(16))
+            .min(MAX_FETCH_BACKOFF_SECS);
+        FailedFetch {
+            failures,
+            retry_after: Instant::now() + StdDuration::from_secs(backoff),
+        }
+    }
+}
+
+impl Upstream {
+    fn from_config(config: &Config) -> Result<Upstream> {
+        match config.get_sync_primary()? {
+            Some(url) => Ok(Upstream::Primary(PrimaryServer::new(
+                url, config.get_sync_primary_token())?)),
+            None => Ok(Upstream::S3(S3::from_config(config)?)),
+        }
+    }
+
+    fn list_upstream_sdks(&self) -> Result<UpstreamListing> {
+        match *self {
+            Upstream::S3(ref s3) => s3.list_upstream_sdks(),
+            Upstream::Primary(ref primary) => primary.list_upstream_sdks(),
+        }
+    }
+
+    fn download_sdk(&self, sdk: &RemoteSdk) -> Result<Box<Read>> {
+        match *self {
+            Upstream::S3(ref s3) => s3.download_sdk(sdk),
+            Upstream::Primary(ref primary) => primary.download_sdk(sdk),
+        }
+    }
+
+    fn get_sdk_signature(&self, sdk: &RemoteSdk) -> Result<Option<Vec<u8>>> {
+        match *self {
+            Upstream::S3(ref s3) => s3.get_sdk_signature(sdk),
+            // the primary checked the signatures
+            Upstream::Primary(_) => Ok(None),
+        }
+    }
+
+    fn get_object_manifest(&self, info: &SdkInfo) -> Result<Option<ObjectManifest>> {
+        match *self {
+            Upstream::S3(ref s3) => s3.get_object_manifest(info),
+            Upstream::Primary(ref primary) => primary.get_object_manifest(info),
+        }
+    }
+
+    fn download_shared_object(&self, info: &SdkInfo, uuid: &Uuid) -> Result<Vec<u8>> {
+        match *self {
+            Upstream::S3(ref s3) => s3.download_shared_object(uuid),
+            Upstream::Primary(ref primary) => primary.download_shared_object(info, uuid),
+        }
+    }
+}
+
+impl MemDbStash {
+    /// Opens a stash for a given config.
+    pub fn new(config: &Config) -> Result<MemDbStash> {
+        let path = config.get_symbol_dir()?.to_path_buf();
+        let scratch_path = match config.get_stash_scratch_dir() {
+            Some(scratch_dir) => scratch_dir.to_path_buf(),
+            None => path.join("scratch"),
+        };
+        let usage = read_usage(&path.join("usage.state"));
+        let ignore_override = read_ignore_override(&path.join("ignore.state"));
+        let audit_log = AuditLog::new(path.join("audit.log"));
+        let sync_summaries = SyncSummaries::new(path.join("sync-reports"),
+                                                config.get_sync_keep_reports()?);
+        Ok(MemDbStash {
+            path,
+            upstream: Arc::new(Upstream::from_config(config)?),
+            signatures: SignaturePolicy::from_config(config)?,
+            encryption_key: EncryptionKey::from_config(config)?.map(Arc::new),
+            local_state: RwLock::new(None),
+            local_state_stamp: Mutex::new(None),
+            memdbs: RwLock::new(HashMap::new()),
+            shared_objects: RwLock::new(HashMap::new()),
+            object_fetches: Arc::new(Mutex::new(HashMap::new())),
+            failed_fetches: Arc::new(Mutex::new(HashMap::new())),
+            lazy_objects: config.get_sync_lazy_objects()?,
+            updating: RwLock::new(HashSet::new()),
+            serve_stale: config.get_sync_serve_stale()?,
+            object_fetch_budget: config.get_sync_object_fetch_budget()?.to_std()
+                .unwrap_or_default(),
+            prefetch_objects: config.get_sync_prefetch_objects()?,
+            max_open_memdbs: config.get_stash_max_open_memdbs()?,
+            access_clock: AtomicUsize::new(0),
+            usage: Mutex::new(usage),
+            usage_dirty: AtomicBool::new(false),
+            ignore_patterns: RwLock::new(config.get_ignore_patterns()?.clone()),
+            ignore_override: RwLock::new(ignore_override),
+            fuzzy_match_options: FuzzyMatchOptions {
+                max_results: config.get_stash_fuzzy_max_results()?,
+                max_score: config.get_stash_fuzzy_max_score()?,
+                device_family: None,
+                product_type: None,
+            },
+            sdk_aliases: config.get_stash_sdk_aliases()?,
+            audit_log,
+            sync_summaries,
+            read_only: config.get_stash_read_only()?,
+            compress_memdbs: config.get_stash_compress_memdbs()?,
+            decompressed_cache_size: config.get_stash_decompressed_cache_size()?,
+            listing_ttl: config.get_sync_listing_ttl()?,
+            remote_listing: Mutex::new(None),
+            gc_max_age: config.get_stash_gc_max_age()?,
+            gc_stats: Mutex::new(GcStats::default()),
+            audit_stats: Mutex::new(AuditStats::default()),
+            memdb_cache_hits: AtomicUsize::new(0),
+            memdb_cache_misses: AtomicUsize::new(0),
+            scratch_path,
+            worker_threads: config.get_server_worker_threads()?,
+        })
+    }
+
+    fn get_local_sync_state_filename(&self) -> PathBuf {
+        self.path.join("sync.state")
+    }
+
+    fn get_memdb_path(&self, info: &SdkInfo) -> PathBuf {
+        self.path.join(info.memdb_filename())
+    }
+
+    fn get_compressed_memdb_path(&self, info: &SdkInfo) -> PathBuf {
+        self.path.join(format!("{}z", info.memdb_filename()))
+    }
+
+    /// Returns the file a memdb is stored in, which is the compressed one
+    /// unless there is a decompressed one.
+    fn get_stored_memdb_path(&self, info: &SdkInfo) -> PathBuf {
+        let path = self.get_memdb_path(info);
+        let compressed_path = self.get_compressed_memdb_path(info);
+        if path.is_file() || !compressed_path.is_file() {
+            path
+        } else {
+            compressed_path
+        }
+    }
+
+    /// The folder with the shared objects of thin SDKs.
+    pub fn get_object_store_path(&self) -> PathBuf {
+        self.path.join("objects")
+    }
+
+    fn get_decompressed_cache_path(&self) -> PathBuf {
+        self.path.join("decompressed")
+    }
+
+    /// Returns the path of a temporary file in the scratch folder.
+    ///
+    /// The scratch folder is created if it does not exist yet.
+    fn get_scratch_file_path(&self, filename: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.scratch_path)?;
+        Ok(self.scratch_path.join(filename))
+    }
+
+    /// Returns the folder temporary files are written to.
+    pub fn scratch_path(&self) -> &Path {
+        &self.scratch_path
+    }
+
+    /// Opens a memdb.
+    ///
+    /// Memdbs are always mapped into the process, see
+    /// `get_openable_memdb_path`.  An encrypted memdb fails to open with
+    /// `UnknownEncryptionKey` if no key is configured.
+    fn open_memdb(&self, info: &SdkInfo, open: &HashMap<SdkInfo, OpenMemDb>)
+        -> Result<MemDb<'static>>
+    {
+        if self.encryption_key.is_none() {
+            if let Ok(Some(key_id)) = get_encryption_key_id(&self.get_stored_memdb_path(info)) {
+                return Err(ErrorKind::UnknownEncryptionKey(key_id).into());
+            }
+        }
+        MemDb::from_path(self.get_openable_memdb_path(info, open)?)
+    }
+
+    /// Returns the path a memdb can be mapped from.
+    ///
+    /// Memdbs that are stored compressed or encrypted are decompressed and
+    /// decrypted into the cache first, which only the server can read.
+    /// Making room in the cache never closes the memdbs in `open`.
+    fn get_openable_memdb_path(&self, info: &SdkInfo, open: &HashMap<SdkInfo, OpenMemDb>)
+        -> Result<PathBuf>
+    {
+        let path = self.get_stored_memdb_path(info);
+        let compressed = path == self.get_compressed_memdb_path(info);
+        if !compressed && self.encryption_key.is_none() {
+            return Ok(path);
+        }
+        let cache_dir = self.get_decompressed_cache_path();
+        let cached_path = cache_dir.join(info.memdb_filename());
+        if !cached_path.is_file() {
+            debug!("decompressing {}", info);
+            fs::create_dir_all(&cache_dir)?;
+            let mut needed = fs::metadata(&path)?.len();
+            if compressed {
+                needed *= DECOMPRESSION_RATIO;
+            }
+            ensure_free_space(&cache_dir, needed)?;
+            // requests that open the same memdb at the same time each write
+            // a file of their own, which is removed if anything fails.  The
+            // rename makes others see either no file or a complete one.
+            let mut src = open_file(&path, self.encryption_key.as_ref().map(|x| &**x))?;
+            let mut tmp = NamedTempFileOptions::new().suffix(".tempmemdb").create_in(&cache_dir)?;
+            fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o600))?;
+            if compressed {
+                let mut dst = XzDecoder::new(tmp);
+                io::copy(&mut src, &mut dst)?;
+                tmp = dst.finish()?;
+            } else {
+                io::copy(&mut src, &mut tmp)?;
+            }
+            tmp.persist(&cached_path).map_err(|err| err.error)?;
+            self.trim_decompressed_cache(info, open)?;
+        }
+        Ok(cached_path)
+    }
+
+    /// Removes the least recently used decompressed memdbs until the
+    /// cache fits its size limit.
+    fn trim_decompressed_cache(&self, keep: &SdkInfo, open: &HashMap<SdkInfo, OpenMemDb>)
+        -> Result<()>
+    {
+        let mut total_size = 0;
+        let mut candidates = vec![];
+        {
+            let usage = self.usage.lock().unwrap();
+            for entry in fs::read_dir(self.get_decompressed_cache_path())? {
+                let entry = entry?;
+                // files that are being decompressed can be renamed meanwhile
+                let size = match entry.metadata() {
+                    Ok(metadata) => metadata.len(),
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err.into()),
+                };
+                total_size += size;
+                let info = match entry.file_name().to_str().and_then(SdkInfo::from_filename) {
+                    Some(info) => info,
+                    None => continue,
+                };
+                if &info != keep && !open.contains_key(&info) {
+                    let last_access = usage.get_sdk(&info.sdk_id())
+                        .map_or(0, |x| x.last_access());
+                    candidates.push((last_access, entry.path(), size));
+                }
+            }
+        }
+        candidates.sort_by_key(|&(last_access, _, _)| last_access);
+        for (_, path, size) in candidates {
+            if total_size <= self.decompressed_cache_size {
+                break;
+            }
+            debug!("evicting decompressed memdb {}", path.display());
+            remove_file_if_exists(&path)?;
+            total_size -= size;
+        }
+        Ok(())
+    }
+
+    /// Removes all files of a memdb from the symbol directory.
+    fn remove_memdb_files(&self, info: &SdkInfo) -> Result<()> {
+        remove_file_if_exists(&get_object_manifest_path(&self.get_object_store_path(), info))?;
+        remove_file_if_exists(&self.get_memdb_path(info))?;
+        remove_file_if_exists(&self.get_compressed_memdb_path(info))?;
+        remove_file_if_exists(&self.get_decompressed_cache_path().join(info.memdb_filename()))
+    }
+
+    /// Removes what the memdb at `path` replaces: the file in the other
+    /// format and the decompressed copy of the old version.
+    fn remove_replaced_memdb_files(&self, info: &SdkInfo, path: &Path) -> Result<()> {
+        for other in &[self.get_memdb_path(info), self.get_compressed_memdb_path(info)] {
+            if other != path {
+                remove_file_if_exists(other)?;
+            }
+        }
+        remove_file_if_exists(&self.get_decompressed_cache_path().join(info.memdb_filename()))
+    }
+
+    /// Checks if the memdb of an SDK is in the symbol directory.
+    fn has_memdb_files(&self, info: &SdkInfo) -> bool {
+        self.get_memdb_path(info).is_file() || self.get_compressed_memdb_path(info).is_file()
+    }
+
+    /// Creates a memdb file in the symbol directory, encrypting it if a key
+    /// is configured.
+    fn create_memdb_file(&self, path: &Path) -> Result<EncryptingWriter<fs::File>> {
+        EncryptingWriter::new(fs::File::create(path)?, self.encryption_key.as_ref().map(|x| &**x))
+    }
+
+    fn get_ignore_override_filename(&self) -> PathBuf {
+        self.path.join("ignore.state")
+    }
+
+    fn get_usage_filename(&self) -> PathBuf {
+        self.path.join("usage.state")
+    }
+
+    fn save_state<T: Serialize>(&self, new_state: &T, filename: &Path) -> Result<()> {
+        let contents = serde_json::to_vec(new_state)
+            .chain_err(|| "Could not update sync state")?;
+        write_file_atomic(filename, "tempstate", &contents)
+    }
+
+    fn get_sync_lock_filename(&self) -> PathBuf {
+        self.path.join("sync.lock")
+    }
+
+    fn get_state_lock_filename(&self) -> PathBuf {
+        self.path.join("state.lock")
+    }
+
+    fn get_local_state_stamp(&self) -> Result<Option<StateStamp>> {
+        match fs::metadata(&self.get_local_sync_state_filename()) {
+            Ok(md) => Ok(Some(StateStamp {
+                modified: md.modified()?,
+                len: md.len(),
+            })),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn load_local_state(&self) -> Result<SdkSyncState> {
+        match fs::File::open(&self.get_local_sync_state_filename()) {
+            Ok(f) => Ok(serde_json::from_reader(io::BufReader::new(f))
+                .chain_err(|| "Parsing error on loading sync state")?),
+            Err(err) => {
+                if err.kind() == io::ErrorKind::NotFound {
+                    Ok(Default::default())
+                } else {
+                    Err(err).chain_err(|| "Error loading sync state")
+                }
+            }
+        }
+    }
+
+    fn read_local_state(&self) -> Result<SdkSyncState> {
+        // stamp before loading so a concurrent write is picked up again
+        // by the next check
+        let stamp = self.get_local_state_stamp()?;
+        let rv = self.load_local_state()?;
+        *self.local_state_stamp.lock().unwrap() = stamp;
+        let mut opt = self.local_state.write().unwrap();
+        *opt = Some(Arc::new(rv.clone()));
+        Ok(rv)
+    }
+
+    fn get_local_state(&self) -> Result<Arc<SdkSyncState>> {
+        if let Some(ref arc) = *self.local_state.read().unwrap() {
+            return Ok(arc.clone());
+        }
+        self.read_local_state()?;
+        Ok(self.local_state.read().unwrap().as_ref().unwrap().clone())
+    }
+
+    /// Reloads the local state from disk after another process changed it.
+    ///
+    /// Memdbs that were removed or replaced in the meantime are closed so
+    /// that the next lookup opens the current file.  Returns the SDKs that
+    /// changed.
+    fn reload_local_state(&self) -> Result<Vec<SdkInfo>> {
+        let old_state = self.local_state.read().unwrap().clone();
+        let new_state = self.read_local_state()?;
+        let mut changed = vec![];
+        if let Some(old_state) = old_state {
+            for sdk in old_state.sdks() {
+                if new_state.get_sdk(sdk.info()).map_or(true, |x| x != sdk) {
+                    changed.push(sdk.info().clone());
+                }
+            }
+        }
+        let mut memdbs = self.memdbs.write().unwrap();
+        for info in changed.iter() {
+            memdbs.remove(info);
+        }
+        Ok(changed)
+    }
+
+    /// Reloads the local state if another process changed it on disk.
+    ///
+    /// Returns `true` if the state was reloaded.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        if self.local_state.read().unwrap().is_none() {
+            return Ok(false);
+        }
+        let stamp = self.get_local_state_stamp()?;
+        if *self.local_state_stamp.lock().unwrap() == stamp {
+            return Ok(false);
+        }
+        let changed = self.reload_local_state()?;
+        info!("sync state was changed externally, reloaded ({} SDKs changed)",
+              changed.len());
+        Ok(true)
+    }
+
+    /// Writes the state to disk, optionally bumping the revision.
+    ///
+    /// The write only goes through if the state on disk still has the
+    /// generation the new state was based on.  Otherwise another process
+    /// wrote in the meantime, our view of the state is reloaded and
+    /// `SyncStateConflict` is returned.
+    fn commit_local_state(&self, new_state: &mut SdkSyncState, bump_revision: bool)
+        -> Result<()>
+    {
+        let _lock = FileLock::lock(self.get_state_lock_filename())?;
+        if self.load_local_state()?.generation != new_state.generation {
+            self.reload_local_state()?;
+            return Err(ErrorKind::SyncStateConflict.into());
+        }
+        new_state.generation += 1;
+        if bump_revision {
+            new_state.revision = Some(new_state.revision.unwrap_or(0) + 1);
+        }
+        self.save_state(new_state, &self.get_local_sync_state_filename())?;
+        *self.local_state_stamp.lock().unwrap() = self.get_local_state_stamp()?;
+        let mut opt = self.local_state.write().unwrap();
+        *opt = Some(Arc::new(new_state.clone()));
+        Ok(())
+    }
+
+    /// Lists the bucket and remembers the listing.
+    ///
+    /// A failed listing is forgotten so that the status reflects it.
+    fn list_remote_state(&self) -> Result<SdkSyncState> {
+        let listing = match self.upstream.list_upstream_sdks() {
+            Ok(listing) => listing,
+            Err(err) => {
+                *self.remote_listing.lock().unwrap() = None;
+                return Err(err);
+            }
+        };
+        let mut state = SdkSyncState::default();
+        for withdrawn in listing.withdrawn {
+            state.withdrawn.insert(withdrawn.info().memdb_filename(), withdrawn);
+        }
+        for remote_sdk in listing.sdks {
+            state.sdks.insert(remote_sdk.info().memdb_filename(), remote_sdk);
+        }
+        state.checksums = listing.checksums;
+        *self.remote_listing.lock().unwrap() = Some(CachedListing {
+            fetched: Utc::now(),
+            state: state.clone(),
+        });
+        Ok(state)
+    }
+
+    /// Lists the bucket again unless the last listing is younger than
+    /// `sync.listing_ttl`.
+    fn get_remote_state(&self) -> Result<SdkSyncState> {
+        if let Some(ref cached) = *self.remote_listing.lock().unwrap() {
+            if Utc::now() - cached.fetched < self.listing_ttl {
+                return Ok(cached.state.clone());
+            }
+        }
+        self.list_remote_state()
+    }
+
+    /// Lists the bucket so that the sync status does not have to.
+    ///
+    /// The server calls this in the background twice per listing TTL.
+    pub fn refresh_remote_listing(&self) -> Result<()> {
+        self.list_remote_state().map(|_| ())
+    }
+
+    /// Returns how long the listing is reused for the sync status.
+    pub fn listing_ttl(&self) -> Duration {
+        self.listing_ttl
+    }
+
+    /// Builds the remote state from a fresh listing or, if `cached` is set,
+    /// from a listing that is at most `sync.listing_ttl` old.
+    ///
+    /// An empty listing while SDKs are synced locally is treated as
+    /// incomplete rather than as a request to delete everything.
+    fn fetch_remote_state(&self, local_state: &SdkSyncState, cached: bool)
+        -> Result<SdkSyncState>
+    {
+        let mut state = if cached {
+            self.get_remote_state()?
+        } else {
+            self.list_remote_state()?
+        };
+        if state.sdks.is_empty() && local_state.sdk_count() > 0 {
+            return Err(ErrorKind::S3IncompleteListing(format!(
+                "no SDKs listed but {} are synced locally", local_state.sdk_count())).into());
+        }
+
+        // withdrawn SDKs are not offered even if their files still exist
+        {
+            let withdrawn = &state.withdrawn;
+            state.sdks.retain(|filename, _| !withdrawn.contains_key(filename));
+        }
+        Ok(state)
+    }
+
+    /// Downloads an SDK and returns the checksum of the decompressed memdb.
+    ///
+    /// The progress bar is advanced by the compressed size of the SDK.  If
+    /// memdbs are kept compressed the download is stored as it is and only
+    /// decompressed to compute the checksum.  Downloads that decompress to
+    /// a truncated memdb fail with `TruncatedMemDb` and leave no files
+    /// behind.
+    ///
+    /// The memdb is written to the scratch folder and only replaces the
+    /// files of the old version once it is complete.  If there is not enough free
+    /// space for it this fails with `InsufficientSpace` before anything is
+    /// downloaded.  If the upstream knows the checksum of the memdb a
+    /// download with a different one fails with `ChecksumMismatch`.
+    fn update_sdk(&self, sdk: &RemoteSdk, expected_checksum: Option<&str>,
+                  progress: &ProgressBar) -> Result<String> {
+        let (dst_path, needed) = if self.compress_memdbs {
+            (self.get_compressed_memdb_path(sdk.info()), sdk.size())
+        } else {
+            (self.get_memdb_path(sdk.info()), sdk.size() * DECOMPRESSION_RATIO)
+        };
+        let tmp_path = self.get_scratch_file_path(
+            &format!("{}.download", sdk.info().memdb_filename()))?;
+        ensure_free_space(&self.scratch_path, needed)?;
+        ensure_free_space(&self.path, needed)?;
+
+        // XXX: the progress bar here can stall out because we currently
+        // need to buffer the download into memory in the s3 code :(
+        let started = Utc::now();
+        let signature = if self.signatures.is_enabled() {
+            self.upstream.get_sdk_signature(sdk)?
+        } else {
+            None
+        };
+        let mut src = self.signatures.reader(self.upstream.download_sdk(sdk)?)?;
+        // a primary that keeps its memdbs decompressed sends them as they are
+        let is_compressed = !sdk.filename().ends_with(".memdb");
+        let checksum = match self.write_memdb(&tmp_path, &mut src, is_compressed, progress)
+            .and_then(|checksum| {
+                src.check(sdk.info(), signature.as_ref().map(|x| &x[..]))?;
+                Ok(checksum)
+            })
+            .and_then(|checksum| match expected_checksum {
+                Some(expected) if expected != checksum => {
+                    Err(ErrorKind::ChecksumMismatch(expected.to_string(), checksum).into())
+                }
+                _ => Ok(checksum),
+            })
+        {
+            Ok(checksum) => checksum,
+            Err(err) => {
+                // the memdb that is installed already stays in place
+                remove_file_if_exists(&tmp_path)?;
+                return Err(err);
+            }
+        };
+
+        // the shared objects of a thin SDK are fetched before it replaces
+        // the old version so that a failed fetch keeps the old one
+        let manifest = match self.fetch_shared_objects(sdk.info(), &tmp_path) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                remove_file_if_exists(&tmp_path)?;
+                return Err(err);
+            }
+        };
+
+        // the scratch folder can be on another file system, so the memdb
+        // is moved next to the old one first and then renamed over it.
+        // Lookups find either version that way and never a missing file.
+        let staged_path = dst_path.with_extension("installing");
+        if let Err(err) = move_file(&tmp_path, &staged_path) {
+            remove_file_if_exists(&tmp_path)?;
+            remove_file_if_exists(&staged_path)?;
+            return Err(err);
+        }
+
+        // once the old files are replaced a failure leaves no usable
+        // memdb behind, which the caller has to drop from the state
+        if let Err(err) = fs::rename(&staged_path, &dst_path).map_err(Error::from)
+            .and_then(|_| self.remove_replaced_memdb_files(sdk.info(), &dst_path))
+            .and_then(|_| match manifest {
+                Some(ref manifest) => self.store_object_manifest(sdk.info(), manifest),
+                None => remove_file_if_exists(
+                    &get_object_manifest_path(&self.get_object_store_path(), sdk.info())),
+            })
+        {
+            remove_file_if_exists(&staged_path)?;
+            self.remove_memdb_files(sdk.info())?;
+            return Err(err);
+        }
+
+        let duration = Utc::now() - started;
+        debug!("updated {} in {}", sdk.info(), HumanDuration(duration));
+        Ok(checksum)
+    }
+
+    /// Drops an SDK from the local state if a failed update left none of
+    /// its files behind.
+    ///
+    /// A failed download keeps serving the old version, only if the old
+    /// files were replaced already the SDK is dropped until a later sync
+    /// gets a good copy.
+    fn drop_lost_sdk(&self, local_state: &mut SdkSyncState, sdk: &RemoteSdk, actor: &str,
+                     reason: &str) -> Result<()> {
+        if local_state.get_sdk(sdk.info()).is_none() || self.has_memdb_files(sdk.info()) {
+            return Ok(());
+        }
+        self.memdbs.write().unwrap().remove(sdk.info());
+        self.audit(AuditEvent::new(actor, AuditAction::Remove, sdk.info())
+                   .with_etag(&sdk.etag)
+                   .with_reason(reason));
+        local_state.remove_sdk(sdk.info());
+        self.commit_local_state(local_state, false)
+    }
+
+    /// Finds a local SDK with the same contents as a remote one under
+    /// another name and returns it with its checksum.
+    ///
+    /// Re-uploads and renames upstream show up as SDKs with the etag and
+    /// size of one the stash has already.  If upstream knows the checksum
+    /// of the memdb it has to match as well.
+    fn find_local_duplicate(&self, local_state: &SdkSyncState, remote_state: &SdkSyncState,
+                            sdk: &RemoteSdk) -> Option<(SdkInfo, String)> {
+        let expected = remote_state.get_checksum(sdk.info());
+        local_state.sdks()
+            .filter(|x| x.info() != sdk.info() && x.etag() == sdk.etag() &&
+                    x.size() == sdk.size())
+            .filter_map(|x| {
+                let checksum = local_state.get_checksum(x.info())?;
+                if expected.map_or(true, |expected| expected == checksum) {
+                    Some((x.info().clone(), checksum.to_string()))
+                } else {
+                    None
+                }
+            })
+            .next()
+    }
+
+    /// Makes an SDK use the files of another one with the same contents.
+    ///
+    /// The files are hard linked where the filesystem allows it, so that
+    /// removing one of the SDKs later does not affect the other.
+    fn link_sdk(&self, src: &SdkInfo, dst: &SdkInfo) -> Result<()> {
+        self.remove_memdb_files(dst)?;
+        let store = self.get_object_store_path();
+        let files = [
+            (self.get_memdb_path(src), self.get_memdb_path(dst)),
+            (self.get_compressed_memdb_path(src), self.get_compressed_memdb_path(dst)),
+            (get_object_manifest_path(&store, src), get_object_manifest_path(&store, dst)),
+        ];
+        for &(ref from, ref to) in files.iter() {
+            if !from.is_file() {
+                continue;
+            }
+            if let Err(err) = link_or_copy(from, to) {
+                self.remove_memdb_files(dst)?;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches the shared objects of a downloaded thin SDK that are not in
+    /// the object store yet.
+    ///
+    /// Objects never change so the ones that are already there are kept.
+    /// Returns the object manifest, which has to be stored along with the
+    /// memdb for the verification, or `None` if the SDK is not thin.  With
+    /// `sync.lazy_objects` only the manifest is fetched and the objects are
+    /// fetched when they are first looked up.
+    fn fetch_shared_objects(&self, info: &SdkInfo, memdb_path: &Path)
+        -> Result<Option<ObjectManifest>>
+    {
+        // broken memdbs are left to the verification after the sync
+        match self.read_memdb_metadata(memdb_path, self.compress_memdbs) {
+            Ok(ref metadata) if metadata.is_thin() => {}
+            _ => return Ok(None),
+        }
+        let manifest = self.upstream.get_object_manifest(info)?.ok_or_else(|| {
+            ErrorKind::MissingObjectManifest(info.sdk_id())
+        })?;
+        let store = self.get_object_store_path();
+        let mut fetched = 0;
+        for object in manifest.objects() {
+            let path = get_shared_object_path(&store, object.uuid());
+            if self.lazy_objects || path.is_file() {
+                continue;
+            }
+            let tmp_path = self.get_scratch_file_path(
+                &format!("{}.download", object.uuid()))?;
+            store_shared_object(&self.upstream, info, object, &path, &tmp_path,
+                                self.encryption_key.as_ref().map(|x| &**x))?;
+            fetched += 1;
+        }
+        debug!("fetched {} of {} shared objects of {}", fetched,
+               manifest.objects().len(), info);
+        Ok(Some(manifest))
+    }
+
+    fn store_object_manifest(&self, info: &SdkInfo, manifest: &ObjectManifest) -> Result<()> {
+        let store = self.get_object_store_path();
+        fs::create_dir_all(&store)?;
+        write_file_atomic(&get_object_manifest_path(&store, info), "tempjson",
+                          &manifest.to_vec()?)
+    }
+
+    /// Reads the header and conversion info of a memdb file without
+    /// loading the rest of it.
+    fn read_memdb_metadata(&self, path: &Path, compressed: bool) -> Result<MemDbMetadata> {
+        let src = open_file(path, self.encryption_key.as_ref().map(|x| &**x))?;
+        if compressed {
+            MemDbMetadata::read(XzStreamDecoder::new(src))
+        } else {
+            MemDbMetadata::read(src)
+        }
+    }
+
+    fn write_memdb(&self, path: &Path, src: &mut Read, is_compressed: bool,
+                   progress: &ProgressBar) -> Result<String>
+    {
+        if !is_compressed {
+            return self.write_decompressed_memdb(path, src, progress);
+        }
+        if self.compress_memdbs {
+            let compressed = self.create_memdb_file(path)?;
+            let decoder = XzDecoder::new(
+                SizeCheckingWriter::new(HashingWriter::new(io::sink())));
+            let mut dst = TeeWriter::new(compressed, decoder);
+            copy_with_progress(progress, src, &mut dst)?;
+            let (compressed, mut decoder) = dst.into_inner();
+            compressed.finish()?.sync_all()?;
+            Ok(decoder.finish()?.finish()?.checksum())
+        } else {
+            let dst = self.create_memdb_file(path)?;
+            let mut dst = XzDecoder::new(SizeCheckingWriter::new(HashingWriter::new(dst)));
+            copy_with_progress(progress, src, &mut dst)?;
+            let hasher = dst.finish()?.finish()?;
+            let checksum = hasher.checksum();
+            hasher.into_inner().finish()?.sync_all()?;
+            Ok(checksum)
+        }
+    }
+
+    /// Writes a memdb that is downloaded decompressed, compressing it if
+    /// memdbs are kept compressed.
+    fn write_decompressed_memdb(&self, path: &Path, src: &mut Read, progress: &ProgressBar)
+        -> Result<String>
+    {
+        let checker = SizeCheckingWriter::new(HashingWriter::new(io::sink()));
+        if self.compress_memdbs {
+            let compressed = XzEncoder::new(self.create_memdb_file(path)?, 6);
+            let mut dst = TeeWriter::new(compressed, checker);
+            copy_with_progress(progress, src, &mut dst)?;
+            let (compressed, checker) = dst.into_inner();
+            compressed.finish()?.finish()?.sync_all()?;
+            Ok(checker.finish()?.checksum())
+        } else {
+            let dst = self.create_memdb_file(path)?;
+            let mut dst = SizeCheckingWriter::new(HashingWriter::new(dst));
+            copy_with_progress(progress, src, &mut dst)?;
+            let hasher = dst.finish()?;
+            let checksum = hasher.checksum();
+            hasher.into_inner().finish()?.sync_all()?;
+            Ok(checksum)
+        }
+    }
+
+    /// Adds the metadata recorded in a downloaded memdb to the remote SDK.
+    ///
+    /// The remote listing only knows what is in the filenames.  Broken
+    /// memdbs are left alone here since they are removed after the sync.
+    fn with_local_metadata(&self, sdk: &RemoteSdk) -> RemoteSdk {
+        let mut rv = sdk.clone();
+        if_chain! {
+            if let Ok(memdb) = self.open_memdb(sdk.info(), &*self.memdbs.read().unwrap());
+            then {
+                rv.info.set_device_family(memdb.info().device_family());
+                rv.info.set_product_type(memdb.info().product_type());
+            }
+        }
+        rv
+    }
+
+    fn remove_sdk(&self, sdk: &RemoteSdk, options: &SyncOptions) -> Result<()> {
+        options.reporter.sdk_status(SdkStatus::Deleting, sdk.info());
+        self.remove_memdb_files(sdk.info())
+    }
+
+    /// Describes the SDKs of the stash for servers following it.
+    ///
+    /// The SDKs are offered under the filename they can be downloaded as,
+    /// which depends on whether the memdbs are kept compressed.
+    pub fn mirror_state(&self) -> Result<MirrorState> {
+        let state = self.get_local_state()?;
+        let mut sdks = vec![];
+        for sdk in state.sdks() {
+            let mut sdk = sdk.clone();
+            if self.compress_memdbs {
+                sdk.filename = format!("{}z", sdk.info().memdb_filename());
+            } else {
+                sdk.filename = sdk.info().memdb_filename();
+                sdk.size = fs::metadata(self.get_memdb_path(sdk.info()))
+                    .map(|x| x.len()).unwrap_or(sdk.size);
+            }
+            let checksum = state.get_checksum(sdk.info()).map(|x| x.to_string());
+            sdks.push(MirrorSdk::new(sdk, checksum));
+        }
+        sdks.sort_by(|a, b| a.sdk().filename().cmp(b.sdk().filename()));
+        let mut withdrawn: Vec<_> = state.withdrawn.values().cloned().collect();
+        withdrawn.sort_by_key(|x| x.info().sdk_id());
+        Ok(MirrorState::new(state.revision.unwrap_or(0), sdks, withdrawn))
+    }
+
+    /// Returns the current revision
+    ///
+    /// The revision changes exactly once whenever the SDKs served by the
+    /// stash change: once per sync that added, updated or removed SDKs and
+    /// once per quarantined SDK.
+    pub fn get_revision(&self) -> Result<u64> {
+        Ok(self.get_local_state()?.revision.unwrap_or(0))
+    }
+
+    /// Returns the etag of a local SDK.
+    pub fn get_sdk_etag(&self, info: &SdkInfo) -> Result<Option<String>> {
+        Ok(self.get_local_state()?.get_sdk(info).map(|x| x.etag.clone()))
+    }
+
+    /// Returns the number of local SDKs
+    pub fn sdk_count(&self) -> Result<usize> {
+        Ok(self.get_local_state()?.sdk_count())
+    }
+
+    /// Returns a list of all synched SDKs
+    pub fn list_sdks(&self) -> Result<Vec<SdkInfo>> {
+        let local_state = self.get_local_state()?;
+        let mut rv : Vec<_> = local_state.sdks().map(|sdk| {
+            sdk.info().clone()
+        }).collect();
+        rv.sort();
+        Ok(rv)
+    }
+
+    /// Returns how the memdbs of all synched SDKs were converted.
+    ///
+    /// This opens the memdbs like lookups do.
+    pub fn list_sdk_conversions(&self) -> Result<Vec<SdkConversion>> {
+        self.get_sdk_conversions(&self.list_sdks()?)
+    }
+
+    /// Returns how the memdbs of the given SDKs were converted.
+    ///
+    /// Only the start of each memdb is read and memdbs are not opened.  A
+    /// memdb that cannot be read is reported with its error instead of
+    /// failing the whole list.
+    pub fn get_sdk_conversions(&self, sdks: &[SdkInfo]) -> Result<Vec<SdkConversion>> {
+        let mut rv = vec![];
+        for info in sdks {
+            let path = self.get_stored_memdb_path(info);
+            let compressed = path == self.get_compressed_memdb_path(info);
+            let metadata = self.read_memdb_metadata(&path, compressed)
+                .and_then(|metadata| Ok((metadata.info()?, metadata)));
+            rv.push(match metadata {
+                Ok((memdb_info, metadata)) => SdkConversion {
+                    sdk_id: info.sdk_id(),
+                    format_version: Some(metadata.format_version()),
+                    device_family: memdb_info.device_family(),
+                    product_type: memdb_info.product_type().map(|x| x.to_string()),
+                    conversion: metadata.conversion_info().cloned(),
+                    error: None,
+                },
+                Err(err) => SdkConversion {
+                    sdk_id: info.sdk_id(),
+                    format_version: None,
+                    device_family: None,
+                    product_type: None,
+                    conversion: None,
+                    error: Some(err.to_string()),
+                },
+            });
+        }
+        Ok(rv)
+    }
+
+    /// Checks the local stash against the server
+    pub fn get_sync_status(&self) -> Result<SyncStatus> {
+        let local_state = self.read_local_state()?;
+
+        let mut remote_total = 0;
+        let mut missing = 0;
+        let mut different = 0;
+        let mut offline = false;
+        let mut warning = None;
+
+        match self.fetch_remote_state(&local_state, true) {
+            Ok(remote_state) => {
+                for sdk in remote_state.sdks() {
+                    if self.sdk_is_ignored(sdk.info()) {
+                        continue;
+                    }
+                    if let Some(local_sdk) = local_state.get_sdk(sdk.info()) {
+                        if local_sdk != sdk {
+                            different += 1;
+                        }
+                    } else {
+                        missing += 1;
+                    }
+                    remote_total += 1;
+                }
+            }
+            Err(err) => {
+                if !err.is_s3_offline() {
+                    return Err(err);
+                }
+                offline = true;
+                match *err.kind() {
+                    ErrorKind::S3Unavailable(_) |
+                    ErrorKind::PrimaryUnavailable(_) => {}
+                    _ => { warning = Some(err.to_string()); }
+                }
+            }
+        }
+
+        Ok(SyncStatus {
+            remote_total: remote_total as u32,
+            missing: missing as u32,
+            different: different as u32,
+            revision: local_state.revision.unwrap_or(0),
+            offline: offline,
+            warning,
+        })
+    }
+
+    /// Lists the SDKs that differ between the stash and upstream.
+    ///
+    /// This is what `get_sync_status` counts.  SDKs that are ignored by the
+    /// config are left out on both sides and upstream is listed anew.
+    pub fn compare_with_upstream(&self) -> Result<Vec<SdkDifference>> {
+        let local_state = self.read_local_state()?;
+        let remote_state = self.fetch_remote_state(&local_state, false)?;
+        let mut rv = vec![];
+
+        for sdk in remote_state.sdks() {
+            if self.sdk_is_ignored(sdk.info()) {
+                continue;
+            }
+            let local_sdk = local_state.get_sdk(sdk.info());
+            let kind = match local_sdk {
+                Some(local_sdk) if local_sdk == sdk => continue,
+                Some(_) => SdkDifferenceKind::Different,
+                None => SdkDifferenceKind::RemoteOnly,
+            };
+            rv.push(SdkDifference {
+                info: sdk.info().clone(),
+                kind,
+                local: local_sdk.cloned(),
+                remote: Some(sdk.clone()),
+            });
+        }
+        for sdk in local_state.sdks() {
+            if !self.sdk_is_ignored(sdk.info()) && remote_state.get_sdk(sdk.info()).is_none() {
+                rv.push(SdkDifference {
+                    info: sdk.info().clone(),
+                    kind: SdkDifferenceKind::LocalOnly,
+                    local: Some(sdk.clone()),
+                    remote: None,
+                });
+            }
+        }
+
+        rv.sort_by(|a, b| a.info.cmp(&b.info));
+        Ok(rv)
+    }
+
+    fn get_quarantine_path(&self) -> PathBuf {
+        self.path.join("quarantine")
+    }
+
+    fn move_to_quarantine(&self, info: &SdkInfo, reason: &str) -> Result<()> {
+        let dir = self.get_quarantine_path();
+        fs::create_dir_all(&dir)?;
+        let filename = info.memdb_filename();
+        for name in &[filename.clone(), format!("{}z", filename)] {
+            let path = self.path.join(name);
+            if path.is_file() {
+                move_file(&path, &dir.join(name))?;
+            }
+        }
+        remove_file_if_exists(&self.get_decompressed_cache_path().join(&filename))?;
+        self.save_state(&QuarantinedSdk {
+            sdk_id: info.sdk_id(),
+            reason: reason.to_string(),
+            timestamp: Utc::now().timestamp(),
+        }, &dir.join(format!("{}.reason", filename)))
+    }
+
+    fn release_from_quarantine(&self, info: &SdkInfo) -> Result<()> {
+        let dir = self.get_quarantine_path();
+        let filename = info.memdb_filename();
+        for path in &[dir.join(&filename), dir.join(format!("{}z", filename)),
+                      dir.join(format!("{}.reason", filename))] {
+            remove_file_if_exists(path)?;
+        }
+        Ok(())
+    }
+
+    /// Moves a broken memdb into the quarantine.
+    ///
+    /// The memdb is moved into the `quarantine` folder of the symbol
+    /// directory next to a file with the reason and the SDK is dropped from
+    /// the local state.  This way it is no longer used for lookups and the
+    /// next sync downloads it again.
+    ///
+    /// A read-only stash only closes the memdb and leaves the file alone.
+    pub fn quarantine(&self, info: &SdkInfo, reason: &str, actor: &str) -> Result<()> {
+        if self.read_only {
+            error!("cannot quarantine {} in read-only stash: {}", info, reason);
+            self.memdbs.write().unwrap().remove(info);
+            return Ok(());
+        }
+        error!("quarantining {}: {}", info, reason);
+        let mut attempts = 0;
+        let etag = loop {
+            let mut local_state = self.read_local_state()?;
+            let etag = local_state.get_sdk(info).map(|x| x.etag.clone());
+            local_state.remove_sdk(info);
+            match self.commit_local_state(&mut local_state, true) {
+                Ok(()) => break etag,
+                Err(Error(ErrorKind::SyncStateConflict, _)) if attempts < 3 => {
+                    attempts += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        let mut event = AuditEvent::new(actor, AuditAction::Quarantine, info)
+            .with_reason(reason);
+        if let Some(etag) = etag {
+            event = event.with_etag(&etag);
+        }
+        self.audit(event);
+        self.memdbs.write().unwrap().remove(info);
+        self.move_to_quarantine(info, reason)
+    }
+
+    /// Closes the memdb of an SDK if it is open.
+    ///
+    /// Lookups that still hold the memdb keep using it, the next lookup
+    /// maps the file again.  Returns if the memdb was open.
+    pub fn evict_memdb(&self, info: &SdkInfo) -> Result<bool> {
+        if self.get_local_state()?.get_sdk(info).is_none() {
+            return Err(ErrorKind::UnknownSdk.into());
+        }
+        Ok(self.memdbs.write().unwrap().remove(info).is_some())
+    }
+
+    /// Makes the next sync download an SDK again.
+    ///
+    /// Only the etag and checksum of the SDK are forgotten, so the memdb
+    /// keeps being served until the download replaces it.
+    fn request_redownload(&self, info: &SdkInfo, actor: &str) -> Result<()> {
+        if self.read_only {
+            return Err(ErrorKind::ReadOnlyStash.into());
+        }
+        let mut attempts = 0;
+        let etag = loop {
+            let mut local_state = self.read_local_state()?;
+            let etag = match local_state.sdks.get_mut(&info.memdb_filename()) {
+                Some(sdk) => mem::replace(&mut sdk.etag, String::new()),
+                None => return Err(ErrorKind::UnknownSdk.into()),
+            };
+            local_state.checksums.remove(&info.memdb_filename());
+            match self.commit_local_state(&mut local_state, false) {
+                Ok(()) => break etag,
+                Err(Error(ErrorKind::SyncStateConflict, _)) if attempts < 3 => {
+                    attempts += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        info!("{} requested a re-download of {}", actor, info);
+        self.audit(AuditEvent::new(actor, AuditAction::Redownload, info)
+                   .with_etag(&etag)
+                   .with_reason("re-download requested"));
+        Ok(())
+    }
+
+    /// Closes the memdb of an SDK and checks the file before opening it
+    /// again.
+    ///
+    /// A decompressed copy is decompressed again.  If the memdb fails
+    /// verification it is quarantined so that the next sync downloads it
+    /// again.  With `redownload` the memdb is only closed and keeps being
+    /// served until the next sync downloaded it again.
+    pub fn reload_memdb(&self, info: &SdkInfo, redownload: bool, actor: &str)
+        -> Result<SdkReload>
+    {
+        let evicted = self.evict_memdb(info)?;
+        let mut rv = SdkReload {
+            sdk_id: info.sdk_id(),
+            evicted,
+            outcome: "reloaded",
+            reason: None,
+        };
+        if redownload {
+            self.request_redownload(info, actor)?;
+            rv.outcome = "redownload";
+            return Ok(rv);
+        }
+
+        if self.compress_memdbs && self.encryption_key.is_none() {
+            remove_file_if_exists(&self.get_decompressed_cache_path().join(info.memdb_filename()))?;
+        }
+        let local_state = self.read_local_state()?;
+        let failure = self.verify_sdks(&local_state, vec![info.clone()], &VerifyOptions {
+            jobs: 1,
+            ..Default::default()
+        })?.into_iter().next();
+        match failure {
+            Some(failure) => {
+                self.quarantine(info, failure.reason(), actor)?;
+                rv.outcome = "quarantined";
+                rv.reason = Some(failure.reason);
+            }
+            None => {
+                self.get_memdb(info)?;
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Verifies a random sample of the local SDKs and compares their etags
+    /// with the ones in S3.
+    ///
+    /// Memdbs that fail verification are quarantined so that the next sync
+    /// downloads them again.  SDKs that changed or vanished upstream are
+    /// recorded as drift in the audit log.  If S3 cannot be reached only
+    /// the local files are checked.
+    pub fn audit_sample(&self, sample: usize, actor: &str) -> Result<AuditStats> {
+        let mut rv = AuditStats::default();
+        let local_state = self.read_local_state()?;
+        let mut infos: Vec<_> = local_state.sdks()
+            .map(|x| x.info().clone())
+            .filter(|x| !self.sdk_is_ignored(x))
+            .collect();
+        pick_random_sample(&mut infos, sample)?;
+        rv.checked = infos.len() as u64;
+
+        let failures = self.verify_sdks(&local_state, infos.clone(), &VerifyOptions {
+            jobs: 1,
+            ..Default::default()
+        })?;
+        for failure in failures.iter() {
+            self.quarantine(failure.info(),
+                            &format!("audit: {}", failure.reason()), actor)?;
+            rv.failures += 1;
+        }
+
+        match self.fetch_remote_state(&local_state, true) {
+            Ok(remote_state) => {
+                for info in infos.iter() {
+                    let local_sdk = local_state.get_sdk(info).unwrap();
+                    let reason = match remote_state.get_sdk(info) {
+                        Some(remote_sdk) if remote_sdk == local_sdk => continue,
+                        Some(remote_sdk) => format!(
+                            "etag is {} upstream", remote_sdk.etag()),
+                        None => "missing upstream".to_string(),
+                    };
+                    warn!("{} drifted from S3: {}", info, reason);
+                    self.audit(AuditEvent::new(actor, AuditAction::Drift, info)
+                        .with_etag(local_sdk.etag())
+                        .with_reason(&reason));
+                    rv.drifted += 1;
+                }
+            }
+            Err(ref err) if err.is_s3_offline() => {
+                warn!("skipping upstream comparison of audit: {}", err);
+            }
+            Err(err) => return Err(err),
+        }
+
+        rv.runs = 1;
+        rv.last_run = Some(Utc::now().timestamp());
+        let mut stats = self.audit_stats.lock().unwrap();
+        stats.runs += 1;
+        stats.checked += rv.checked;
+        stats.failures += rv.failures;
+        stats.drifted += rv.drifted;
+        stats.last_run = rv.last_run;
+        Ok(rv)
+    }
+
+    /// Returns what audits found since the stash was opened.
+    pub fn audit_stats(&self) -> AuditStats {
+        self.audit_stats.lock().unwrap().clone()
+    }
+
+    /// Returns the memdbs that are currently quarantined.
+    pub fn list_quarantined(&self) -> Result<Vec<QuarantinedSdk>> {
+        let mut rv = vec![];
+        let iter = match fs::read_dir(self.get_quarantine_path()) {
+            Ok(iter) => iter,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(rv);
+            }
+            Err(err) => { return Err(err.into()); }
+        };
+        for entry in iter {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some("reason") {
+                continue;
+            }
+            let f = fs::File::open(&path)?;
+            if let Ok(item) = serde_json::from_reader(io::BufReader::new(f)) {
+                rv.push(item);
+            }
+        }
+        rv.sort_by(|a: &QuarantinedSdk, b| a.sdk_id.cmp(&b.sdk_id));
+        Ok(rv)
+    }
+
+    /// Removes temporary files that crashed processes left behind.
+    ///
+    /// This covers half written state files, everything in the scratch
+    /// folder, memdbs that were not renamed into place yet, memdbs of
+    /// downloads that never made it into the sync state
+    /// and the temporary folders of `convert-sdk` whose process is gone.
+    /// Only files older than `stash.gc_max_age` are considered.  Partial
+    /// downloads are only removed if no sync is running, and the sync lock
+    /// is only held while removing them.  A read-only stash is left alone.
+    pub fn collect_garbage(&self) -> Result<GcStats> {
+        let mut rv = GcStats::default();
+        if self.read_only {
+            return Ok(rv);
+        }
+        let cutoff = SystemTime::now() - self.gc_max_age.to_std().unwrap();
+        let prefix = format!("{}.", get_temp_dir_prefix(&self.path));
+
+        // nothing a sync could still need
+        let mut candidates = vec![];
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.to_str().map_or(false, |x| x.ends_with(".tempstate")) {
+                candidates.push(path);
+            }
+        }
+        if let Ok(iter) = fs::read_dir(self.get_decompressed_cache_path()) {
+            for entry in iter {
+                let path = entry?.path();
+                if path.extension().and_then(|x| x.to_str()) == Some("tempmemdb") {
+                    candidates.push(path);
+                }
+            }
+        }
+        let temp_dirs = vec![self.scratch_path.clone(), env::temp_dir()];
+        for (idx, dir) in temp_dirs.iter().enumerate() {
+            let iter = match fs::read_dir(dir) {
+                Ok(iter) => iter,
+                Err(_) => continue,
+            };
+            for entry in iter {
+                let path = entry?.path();
+                if path.is_dir() {
+                    let ours = path.file_name().and_then(|x| x.to_str())
+                        .map_or(false, |x| x.starts_with(&prefix));
+                    if ours && is_abandoned_temp_dir(&path)? {
+                        candidates.push(path);
+                    }
+                } else if idx == 0 &&
+                          path.extension().and_then(|x| x.to_str()) != Some("download") {
+                    candidates.push(path);
+                }
+            }
+        }
+        self.remove_garbage(candidates, cutoff, &mut rv);
+
+        // partial downloads and whatever a sync has not committed yet
+        match FileLock::try_lock(self.get_sync_lock_filename())? {
+            Some(_lock) => {
+                let local_state = self.read_local_state()?;
+                let mut candidates = vec![];
+                for entry in fs::read_dir(&self.path)? {
+                    let path = entry?.path();
+                    let filename = match path.file_name().and_then(|x| x.to_str()) {
+                        Some(filename) => filename.to_string(),
+                        None => continue,
+                    };
+                    if filename.ends_with(".installing") {
+                        candidates.push(path);
+                        continue;
+                    }
+                    if !filename.ends_with(".memdb") && !filename.ends_with(".memdbz") {
+                        continue;
+                    }
+                    if let Some(info) = SdkInfo::from_filename(&filename) {
+                        if local_state.get_sdk(&info).is_none() {
+                            candidates.push(path);
+                        }
+                    }
+                }
+                candidates.extend(self.get_unreferenced_objects(&local_state)?);
+                if let Ok(iter) = fs::read_dir(&self.scratch_path) {
+                    for entry in iter {
+                        let path = entry?.path();
+                        if path.extension().and_then(|x| x.to_str()) == Some("download") {
+                            candidates.push(path);
+                        }
+                    }
+                }
+                self.remove_garbage(candidates, cutoff, &mut rv);
+            }
+            None => {
+                let err = Error::from(ErrorKind::StashLocked(self.path.display().to_string()));
+                info!("kept partial downloads: {}", err);
+            }
+        }
+
+        rv.runs = 1;
+        rv.last_run = Some(Utc::now().timestamp());
+        let mut stats = self.gc_stats.lock().unwrap();
+        stats.runs += 1;
+        stats.removed_files += rv.removed_files;
+        stats.removed_bytes += rv.removed_bytes;
+        stats.last_run = rv.last_run;
+        Ok(rv)
+    }
+
+    /// Removes the candidates that are older than the cutoff.
+    fn remove_garbage(&self, candidates: Vec<PathBuf>, cutoff: SystemTime, rv: &mut GcStats) {
+        for path in candidates {
+            let md = match fs::symlink_metadata(&path) {
+                Ok(md) => md,
+                Err(_) => continue,
+            };
+            if md.modified().map(|x| x > cutoff).unwrap_or(true) {
+                continue;
+            }
+            let size = if md.is_dir() { dir_size(&path) } else { md.len() };
+            let removed = if md.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            match removed {
+                Ok(()) => {
+                    info!("removed leftover {}", path.display());
+                    rv.removed_files += 1;
+                    rv.removed_bytes += size;
+                }
+                Err(err) => {
+                    warn!("could not remove leftover {}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+
+    /// Returns the files in the object store that no local SDK references.
+    ///
+    /// These are the manifests of SDKs that are gone and the objects that
+    /// none of the remaining manifests list.
+    fn get_unreferenced_objects(&self, local_state: &SdkSyncState) -> Result<Vec<PathBuf>> {
+        let store = self.get_object_store_path();
+        let mut rv = vec![];
+        let mut referenced = HashSet::new();
+        let iter = match fs::read_dir(&store) {
+            Ok(iter) => iter,
+            Err(_) => return Ok(rv),
+        };
+        for entry in iter {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some("json") {
+                continue;
+            }
+            let info = path.file_stem().and_then(|x| x.to_str())
+                .and_then(SdkInfo::from_filename);
+            match info {
+                Some(ref info) if local_state.get_sdk(info).is_some() => {
+                    if let Some(manifest) = ObjectManifest::load(&path)? {
+                        referenced.extend(manifest.objects().iter().map(|x| *x.uuid()));
+                    }
+                }
+                _ => rv.push(path),
+            }
+        }
+        for entry in fs::read_dir(&store)? {
+            let shard = entry?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&shard)? {
+                let path = entry?.path();
+                let uuid = path.file_stem().and_then(|x| x.to_str())
+                    .and_then(|x| x.parse::<Uuid>().ok());
+                if uuid.map_or(false, |uuid| !referenced.contains(&uuid)) {
+                    rv.push(path);
+                }
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Returns how many bytes the files in the symbol directory take up.
+    pub fn disk_usage(&self) -> u64 {
+        dir_size(&self.path)
+    }
+
+    /// Returns what the garbage collection removed since the stash was
+    /// opened.
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc_stats.lock().unwrap().clone()
+    }
+
+    /// Returns how often `get_memdb` found the memdb open since the stash
+    /// was opened.
+    pub fn memdb_cache_stats(&self) -> MemDbCacheStats {
+        MemDbCacheStats {
+            hits: self.memdb_cache_hits.load(Ordering::Relaxed) as u64,
+            misses: self.memdb_cache_misses.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    /// Reconciles the sync state with the memdbs actually on disk.
+    ///
+    /// SDKs whose memdb file is missing are dropped from the state so the
+    /// next sync downloads them again.  Memdb files the state does not
+    /// know about are only reported.  A read-only stash only reports.
+    ///
+    /// Fails with `StashLocked` instead of dropping SDKs while another
+    /// process syncs the stash.
+    pub fn check_consistency(&self, actor: &str) -> Result<ConsistencyReport> {
+        let mut local_state = self.read_local_state()?;
+        let mut rv = ConsistencyReport {
+            checked: local_state.sdk_count(),
+            ..Default::default()
+        };
+
+        rv.missing = self.find_missing_memdbs(&local_state);
+
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            let filename = match path.file_name().and_then(|x| x.to_str()) {
+                Some(filename) => filename,
+                None => continue,
+            };
+            // compressed memdbs are tracked under the uncompressed name
+            let key = if filename.ends_with(".memdb") {
+                filename
+            } else if filename.ends_with(".memdbz") {
+                &filename[..filename.len() - 1]
+            } else {
+                continue;
+            };
+            if !local_state.sdks.contains_key(key) {
+                rv.unknown.push(filename.to_string());
+            }
+        }
+        rv.unknown.sort();
+
+        if !rv.missing.is_empty() && !self.read_only {
+            // a sync replaces memdbs, which are missing for a moment then
+            let _lock = FileLock::try_lock(self.get_sync_lock_filename())?
+                .ok_or_else(|| ErrorKind::StashLocked(self.path.display().to_string()))?;
+            // a sync that finished before the lock was taken may have put
+            // the memdbs back or removed their SDKs
+            local_state = self.read_local_state()?;
+            rv.checked = local_state.sdk_count();
+            rv.missing = self.find_missing_memdbs(&local_state);
+            if rv.missing.is_empty() {
+                return Ok(rv);
+            }
+            for info in rv.missing.iter() {
+                if let Some(sdk) = local_state.get_sdk(info) {
+                    self.audit(AuditEvent::new(actor, AuditAction::Remove, info)
+                               .with_etag(&sdk.etag)
+                               .with_reason("memdb missing on disk"));
+                }
+            }
+            for info in rv.missing.iter() {
+                local_state.remove_sdk(info);
+            }
+            self.commit_local_state(&mut local_state, true)?;
+        }
+
+        Ok(rv)
+    }
+
+    /// Returns the SDKs of a state whose memdbs are not on disk.
+    fn find_missing_memdbs(&self, state: &SdkSyncState) -> Vec<SdkInfo> {
+        let mut rv: Vec<_> = state.sdks()
+            .filter(|sdk| !self.get_memdb_path(sdk.info()).is_file() &&
+                          !self.get_compressed_memdb_path(sdk.info()).is_file())
+            .map(|sdk| sdk.info().clone())
+            .collect();
+        rv.sort();
+        rv
+    }
+
+    /// Returns the summaries of the most recent syncs.
+    pub fn sync_summaries(&self) -> &SyncSummaries {
+        &self.sync_summaries
+    }
+
+    /// Returns `true` if the stash must not be modified.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns the audit log of the stash.
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    fn audit(&self, event: AuditEvent) {
+        if let Err(err) = self.audit_log.record(&event) {
+            error!("could not write audit log: {}", err);
+        }
+    }
+
+    /// Checks if the SDK is ignored by config
+    ///
+    /// Ignored SDKs are neither synced nor used for lookups.  The override
+    /// set with `set_ignore_override` takes the place of `sync.ignore`.
+    pub fn sdk_is_ignored(&self, info: &SdkInfo) -> bool {
+        if let Some(ref patterns) = *self.ignore_override.read().unwrap() {
+            return patterns.is_match(&info.sdk_id());
+        }
+        self.ignore_patterns.read().unwrap().is_match(&info.sdk_id())
+    }
+
+    /// Returns the ignore patterns from the config.
+    pub fn config_ignore_patterns(&self) -> IgnorePatterns {
+        self.ignore_patterns.read().unwrap().clone()
+    }
+
+    /// Replaces the ignore patterns from the config, for instance after
+    /// the config was reloaded.
+    pub fn set_config_ignore_patterns(&self, patterns: IgnorePatterns) {
+        *self.ignore_patterns.write().unwrap() = patterns;
+    }
+
+    /// Returns the ignore patterns that override the config if any.
+    pub fn ignore_override(&self) -> Option<IgnorePatterns> {
+        self.ignore_override.read().unwrap().clone()
+    }
+
+    /// Overrides the ignore patterns from the config, `None` goes back to
+    /// the config.
+    ///
+    /// The override is kept in the symbol directory so that it survives
+    /// restarts and applies to the `sync` command as well.  Read-only
+    /// stashes only keep it in memory.
+    pub fn set_ignore_override(&self, patterns: Option<IgnorePatterns>) -> Result<()> {
+        if !self.read_only {
+            let filename = self.get_ignore_override_filename();
+            match patterns {
+                Some(ref patterns) => self.save_state(patterns, &filename)?,
+                None => remove_file_if_exists(&filename)?,
+            }
+        }
+        match patterns {
+            Some(ref patterns) => info!("overriding ignore patterns with {:?}",
+                                        patterns.sources()),
+            None => info!("removed ignore pattern override"),
+        }
+        *self.ignore_override.write().unwrap() = patterns;
+        Ok(())
+    }
+
+    /// Synchronize the local stash with the server
+    ///
+    /// Only one process can sync a symbol directory at a time.  If another
+    /// one is already syncing, the local state is reloaded from disk and
+    /// nothing else happens.
+    ///
+    /// Fails with `ReadOnlyStash` if the stash is read-only and with
+    /// `SyncStateConflict` if another process modified the state while
+    /// syncing.
+    pub fn sync(&self, options: SyncOptions) -> Result<()> {
+        if self.read_only {
+            return Err(ErrorKind::ReadOnlyStash.into());
+        }
+        let _lock = match FileLock::try_lock(self.get_sync_lock_filename())? {
+            Some(lock) => lock,
+            None => {
+                info!("another process is syncing {}, reloading state", self.path.display());
+                self.reload_local_state()?;
+                return Ok(());
+            }
+        };
+
+        let before = self.read_local_state()?;
+        let mut summary = SyncSummary::new(&options.actor);
+        let rv = self.sync_locked(&options, &mut summary);
+        self.updating.write().unwrap().clear();
+        if rv.is_ok() && self.lazy_objects {
+            match self.read_local_state()
+                .and_then(|state| self.prefetch_shared_objects(&state, &options.cancel)) {
+                Ok(0) => {}
+                Ok(fetched) => info!("prefetched {} shared objects", fetched),
+                Err(err) => warn!("could not prefetch shared objects: {}", err),
+            }
+        }
+
+        // a sync that failed half way might have changed SDKs already
+        if rv.is_err() {
+            if let Ok(mut state) = self.read_local_state() {
+                if state.revision == before.revision && state.generation != before.generation {
+                    if let Err(err) = self.commit_local_state(&mut state, true) {
+                        error!("could not bump revision after failed sync: {}", err);
+                    }
+                }
+            }
+        }
+        match rv {
+            // S3 being offline is reported by the sync status instead
+            Err(ref err) if !err.is_s3_offline() => {
+                summary.add_failure(None, &err.to_string());
+            }
+            _ => {}
+        }
+        if summary.is_noteworthy() {
+            summary.finish(self.get_local_state().map(|x| x.revision.unwrap_or(0)).unwrap_or(0));
+            if let Err(err) = self.sync_summaries.write(&summary) {
+                error!("could not write sync summary: {}", err);
+            }
+        }
+        rv
+    }
+
+    /// Returns how many bytes the last sync that downloaded something
+    /// downloaded and how long it took.
+    ///
+    /// The time includes listing the bucket, so this errs on the slow side.
+    fn get_previous_download_rate(&self) -> Option<(u64, Duration)> {
+        let summaries = match self.sync_summaries.list() {
+            Ok(summaries) => summaries,
+            Err(err) => {
+                debug!("could not read sync summaries: {}", err);
+                return None;
+            }
+        };
+        summaries.iter().filter_map(|summary| {
+            let done: u64 = summary.added().iter().chain(summary.updated().iter())
+                .map(|x| x.size()).sum();
+            let elapsed = Duration::seconds(summary.finished()? - summary.started());
+            Some((done, elapsed)).filter(|&(done, _)| done > 0)
+        }).next()
+    }
+
+    fn sync_locked(&self, options: &SyncOptions, summary: &mut SyncSummary) -> Result<()> {
+        let mut local_state = self.read_local_state()?;
+        let remote_state = self.fetch_remote_state(&local_state, false)?;
+        let started = Utc::now();
+        let mut updated = vec![];
+        let mut to_delete : HashSet<_> = HashSet::from_iter(
+            local_state.sdks().map(|x| x.info().clone()));
+        let mut sdks : Vec<_> = remote_state.sdks()
+            .map(|x| x.info().clone()).collect();
+        sdks.sort_by(|a, b| b.cmp(a));
+
+        // figure out what needs downloading first so that we can tell
+        // how much there is to do.
+        let mut to_download = vec![];
+        for sdk_info in sdks.iter() {
+            if self.sdk_is_ignored(sdk_info) {
+                options.reporter.sdk_status(SdkStatus::Ignored, sdk_info);
+            } else if let Some(local_sdk) = local_state.get_sdk(sdk_info) {
+                if local_sdk != remote_state.get_sdk(sdk_info).unwrap() {
+                    options.reporter.sdk_status(SdkStatus::Updating, sdk_info);
+                    to_download.push((sdk_info, AuditAction::Update));
+                } else {
+                    options.reporter.sdk_status(SdkStatus::Unchanged, sdk_info);
+                }
+            } else {
+                options.reporter.sdk_status(SdkStatus::Updating, sdk_info);
+                to_download.push((sdk_info, AuditAction::Add));
+            }
+            to_delete.remove(sdk_info);
+        }
+        let changed = !to_download.is_empty();
+        let mut cancelled = None;
+        self.updating.write().unwrap().extend(to_download.iter()
+            .filter(|&&(_, action)| action == AuditAction::Update)
+            .map(|&(info, _)| info.clone()));
+
+        if changed {
+            let total_size = to_download.iter()
+                .map(|&(info, _)| remote_state.get_sdk(info).unwrap().size())
+                .sum();
+            options.reporter.detail(&format!("Downloading {} SDKs ({})",
+                                             to_download.len(), HumanBytes(total_size)));
+            let progress = options.reporter.progress_bar(total_size);
+            progress.set_style(ProgressStyle::default_bar()
+                .template("{prefix} {wide_bar} {bytes}/{total_bytes} ({eta} left) {msg}"));
+            let download_started = Utc::now();
+            let mut downloaded = 0;
+            // until something was downloaded only earlier syncs tell how
+            // fast downloads are
+            let previous_rate = if options.deadline.is_some() {
+                self.get_previous_download_rate()
+            } else {
+                None
+            };
+
+            for (idx, &(sdk_info, action)) in to_download.iter().enumerate() {
+                let sdk = remote_state.get_sdk(sdk_info).unwrap();
+                let eta = estimate_download_time(
+                    downloaded, Utc::now() - download_started, sdk.size())
+                    .or_else(|| previous_rate.and_then(|(done, elapsed)| {
+                        estimate_download_time(done, elapsed, sdk.size())
+                    }));
+                let past_deadline = options.deadline.map_or(false, |deadline| {
+                    Utc::now() + eta.unwrap_or_else(Duration::zero) > deadline
+                });
+                if options.cancel.load(Ordering::SeqCst) || past_deadline {
+                    cancelled = Some(to_download.len() - idx);
+                    break;
+                }
+                progress.set_prefix(&format!("[{}/{}]", idx + 1, to_download.len()));
+                progress.set_message(&match eta {
+                    Some(eta) => format!("{} (~{})", sdk_info, HumanDuration(eta)),
+                    None => sdk_info.to_string(),
+                });
+
+                // an SDK the stash has under another name is not downloaded
+                // again, if linking fails it is downloaded after all
+                let linked = self.find_local_duplicate(&local_state, &remote_state, sdk)
+                    .and_then(|(src, checksum)| match self.link_sdk(&src, sdk_info) {
+                        Ok(()) => Some((src, checksum)),
+                        Err(err) => {
+                            warn!("could not link {} to {}: {}", sdk_info, src, err);
+                            None
+                        }
+                    });
+                let checksum = match linked {
+                    Some((ref src, ref checksum)) => {
+                        options.reporter.detail(&format!(
+                            "{} has the contents of {}, linked it", sdk_info, src));
+                        progress.inc(sdk.size());
+                        checksum.clone()
+                    }
+                    None => match self.update_sdk(
+                        sdk, remote_state.get_checksum(sdk_info), &progress) {
+                        Ok(checksum) => {
+                            downloaded += sdk.size();
+                            checksum
+                        }
+                        Err(err) => {
+                            self.drop_lost_sdk(&mut local_state, sdk, &options.actor,
+                                               &err.to_string())?;
+                            if !is_broken_memdb(&err) {
+                                return Err(err);
+                            }
+                            warn!("could not update {}: {}", sdk_info, err);
+                            downloaded += sdk.size();
+                            summary.add_failure(Some(sdk_info), &err.to_string());
+                            self.updating.write().unwrap().remove(sdk_info);
+                            continue;
+                        }
+                    },
+                };
+                if action == AuditAction::Update {
+                    self.memdbs.write().unwrap().remove(sdk_info);
+                }
+                let mut event = AuditEvent::new(&options.actor, action, sdk_info)
+                    .with_etag(&sdk.etag);
+                if let Some((ref src, _)) = linked {
+                    event = event.with_reason(&format!("same contents as {}", src.sdk_id()));
+                }
+                self.audit(event);
+                updated.push(sdk_info.clone());
+                if action == AuditAction::Update {
+                    summary.add_updated(sdk_info, sdk.size());
+                } else {
+                    summary.add_added(sdk_info, sdk.size());
+                }
+                local_state.update_sdk(&self.with_local_metadata(sdk));
+                local_state.set_checksum(sdk_info, checksum);
+                self.commit_local_state(&mut local_state, false)?;
+                self.updating.write().unwrap().remove(sdk_info);
+            }
+            progress.finish_and_clear();
+        }
+
+        for sdk_info in to_delete.iter() {
+            if let Some(sdk) = local_state.get_sdk(sdk_info) {
+                self.remove_sdk(sdk, options)?;
+                self.memdbs.write().unwrap().remove(&sdk.info());
+                summary.add_removed(sdk_info, sdk.size());
+                let reason = match remote_state.get_withdrawn(sdk_info) {
+                    Some(withdrawn) => format!("withdrawn upstream: {}", withdrawn.reason()),
+                    None => "removed upstream".to_string(),
+                };
+                self.audit(AuditEvent::new(&options.actor, AuditAction::Remove, sdk_info)
+                           .with_etag(&sdk.etag)
+                           .with_reason(&reason));
+            }
+            local_state.remove_sdk(sdk_info);
+        }
+
+        // remember the tombstones so that lookups can tell withdrawn SDKs
+        // from unknown ones.
+        let withdrawn_changed = local_state.withdrawn != remote_state.withdrawn;
+        local_state.withdrawn = remote_state.withdrawn.clone();
+
+        // validate what we downloaded.  Broken SDKs are dropped from the
+        // local state so that the next sync downloads them again.
+        if !updated.is_empty() {
+            let mut broken = HashSet::new();
+            let failures = self.verify_sdks(&local_state, updated.clone(), &VerifyOptions {
+                reporter: options.reporter.clone(),
+                jobs: self.worker_threads,
+            })?;
+            for failure in failures {
+                error!("downloaded SDK {} is broken: {}", failure.info(), failure.reason());
+                let reason = format!("verification failed: {}", failure.reason());
+                let mut event = AuditEvent::new(&options.actor, AuditAction::Quarantine,
+                                                failure.info()).with_reason(&reason);
+                if let Some(sdk) = local_state.get_sdk(failure.info()) {
+                    event = event.with_etag(&sdk.etag);
+                }
+                self.audit(event);
+                local_state.remove_sdk(failure.info());
+                self.memdbs.write().unwrap().remove(failure.info());
+                self.move_to_quarantine(failure.info(), &reason)?;
+                summary.add_failure(Some(failure.info()), &reason);
+                broken.insert(failure.info().clone());
+            }
+            for info in updated.iter().filter(|x| !broken.contains(*x)) {
+                self.release_from_quarantine(info)?;
+            }
+        }
+
+        // the revision only moves once per sync so that it can be used to
+        // invalidate caches.
+        if changed || !to_delete.is_empty() || withdrawn_changed {
+            self.commit_local_state(&mut local_state, true)?;
+            let duration = Utc::now() - started;
+            info!("finished sync in {} (revision {})", HumanDuration(duration),
+                  local_state.revision.unwrap_or(0));
+        }
+
+        // what was downloaded is committed, the next sync picks up the rest
+        if let Some(remaining) = cancelled {
+            return Err(ErrorKind::SyncCancelled(updated.len(), remaining).into());
+        }
+
+        Ok(())
+    }
+
+    /// Verifies the checksums and structure of all local memdbs.
+    ///
+    /// Returns the memdbs that failed verification.
+    pub fn verify(&self, options: &VerifyOptions) -> Result<Vec<VerifyFailure>> {
+        let local_state = self.read_local_state()?;
+        let infos = local_state.sdks().map(|x| x.info().clone()).collect();
+        self.verify_sdks(&local_state, infos, options)
+    }
+
+    fn verify_sdks(&self, local_state: &SdkSyncState, infos: Vec<SdkInfo>,
+                   options: &VerifyOptions)
+        -> Result<Vec<VerifyFailure>>
+    {
+        // compressed and encrypted memdbs are decompressed and decrypted
+        // into the cache up front
+        let items: Vec<_> = {
+            let open = self.memdbs.read().unwrap();
+            infos.into_iter().map(|info| {
+                let path = self.get_openable_memdb_path(&info, &*open)
+                    .unwrap_or_else(|_| self.get_memdb_path(&info));
+                let checksum = local_state.get_checksum(&info).map(|x| x.to_string());
+                (info, path, checksum)
+            }).collect()
+        };
+
+        let total_size = items.iter()
+            .filter_map(|&(_, ref path, _)| fs::metadata(path).ok())
+            .map(|md| md.len())
+            .sum();
+        let progress = options.reporter.progress_bar(total_size);
+        progress.set_style(ProgressStyle::default_bar()
+            .template("{wide_bar} {bytes}/{total_bytes}"));
+
+        let progress = Arc::new(progress);
+        let pb = progress.clone();
+        let key = self.encryption_key.clone();
+        let store = self.get_object_store_path();
+        let lazy_objects = self.lazy_objects;
+        let mut failures: Vec<_> = parallel_map(items, options.jobs, move |(info, path, checksum)| {
+            let key = key.as_ref().map(|x| &**x);
+            verify_memdb(&path, checksum.as_ref().map(|x| x.as_str()), &pb)
+                .and_then(|_| verify_shared_objects(&store, &info, key, lazy_objects))
+                .err()
+                .map(|err| VerifyFailure { info, reason: err.to_string() })
+        }).into_iter().filter_map(|x| x).collect();
+        progress.finish_and_clear();
+
+        failures.sort_by(|a, b| a.info.cmp(&b.info));
+        Ok(failures)
+    }
+
+    /// Looks up an memdb by an SDK info if it's available.
+    ///
+    /// This returns a memdb wrapped in an arc as internally the system
     /// might try to unload the memdb if no longer needed.  If the MemDb
-    /// does not exist, a `UnknownSdk` error is returned.
+    /// does not exist, a `UnknownSdk` error is returned.  If the open
+    /// memdb budget is exhausted and no memdb is idle enough to be closed,
+    /// a `TooManyOpenMemDbs` error is returned.
     pub fn get_memdb(&self, info: &SdkInfo) -> Result<Arc<MemDb<'static>>> {
         // try to fetch it from the local mapping.  The sync method will
         // remove it from here automatically.
-        if let Some(arc) = self.memdbs.read().unwrap().get(info) {
-            return Ok(arc.clone());
+        if let Some(open) = self.memdbs.read().unwrap().get(info) {
+            open.last_used.store(self.tick(), Ordering::Relaxed);
+            self.memdb_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(open.memdb.clone());
         }
+        self.memdb_cache_misses.fetch_add(1, Ordering::Relaxed);
 
         let local_state = self.get_local_state()?;
 
@@ -415,15 +2685,393 @@ impl MemDbStash {
         // if we go directly to the memdbs array or look at the file system
         // we might start to consider things that are not available yet or
         // not available any longer.
-        if local_state.get_sdk(&info).is_some() {
-            let memdb = MemDb::from_path(self.path.join(&info.memdb_filename()))?;
-            self.memdbs.write().unwrap().insert(info.clone(), Arc::new(memdb));
-            if let Some(arc) = self.memdbs.read().unwrap().get(info) {
-                return Ok(arc.clone());
+        if local_state.get_sdk(&info).is_none() {
+            if let Some(withdrawn) = local_state.get_withdrawn(info) {
+                return Err(ErrorKind::SdkWithdrawn(
+                    info.sdk_id(), withdrawn.reason().to_string()).into());
+            }
+            return Err(ErrorKind::UnknownSdk.into());
+        }
+
+        // decompressing and decrypting can take a while, do it before
+        // locking out everybody else.
+        let memdb = self.open_memdb(info, &*self.memdbs.read().unwrap());
+        let mut memdbs = self.memdbs.write().unwrap();
+
+        // someone else might have opened it while we were waiting for
+        // the write lock.
+        if let Some(open) = memdbs.get(info) {
+            open.last_used.store(self.tick(), Ordering::Relaxed);
+            return Ok(open.memdb.clone());
+        }
+
+        if let Some(limit) = self.max_open_memdbs {
+            if memdbs.len() >= limit && !close_idle_memdb(&mut *memdbs) {
+                return Err(ErrorKind::TooManyOpenMemDbs(limit).into());
+            }
+        }
+
+        let memdb = match memdb {
+            Ok(memdb) => Arc::new(memdb),
+            Err(err) => {
+                if_chain! {
+                    if let ErrorKind::Io(ref io_err) = *err.kind();
+                    if io_err.raw_os_error() == Some(libc::EMFILE);
+                    then {
+                        return Err(ErrorKind::TooManyOpenMemDbs(memdbs.len()).into());
+                    }
+                }
+                if is_broken_memdb(&err) {
+                    drop(memdbs);
+                    self.quarantine(info, &err.to_string(), "server")?;
+                }
+                return Err(err);
+            }
+        };
+        memdbs.insert(info.clone(), OpenMemDb {
+            memdb: memdb.clone(),
+            last_used: AtomicUsize::new(self.tick()),
+        });
+        Ok(memdb)
+    }
+
+    /// Returns the memdb with the symbols of an image of an SDK.
+    ///
+    /// Images of thin SDKs are looked up in the memdb of the shared object,
+    /// all others in the memdb of the SDK.  If the object store does not
+    /// have the object the memdb of the SDK is returned, which knows the
+    /// image but has no symbols for it.  An object that is still being
+    /// fetched fails with `ObjectFetchPending`.
+    pub fn get_object_memdb(&self, memdb: Arc<MemDb<'static>>, uuid: &Uuid)
+        -> Result<Arc<MemDb<'static>>>
+    {
+        if !memdb.is_shared_object(uuid) {
+            return Ok(memdb);
+        }
+        Ok(self.get_shared_object(memdb.info(), uuid)?.unwrap_or(memdb))
+    }
+
+    /// Opens the memdb of a shared object of an SDK if the object store
+    /// has it.
+    ///
+    /// Shared objects never change so they stay open once opened, up to
+    /// `MAX_SHARED_OBJECTS` of them.  They are small and do not count
+    /// against `stash.max_open_memdbs`.  With `sync.lazy_objects` a missing
+    /// object is fetched and the lookup waits for it up to
+    /// `sync.object_fetch_budget_ms`.  If that is not enough it fails with
+    /// `ObjectFetchPending`, and an object that could not be fetched is
+    /// treated as missing until the fetch is retried.
+    pub fn get_shared_object(&self, info: &SdkInfo, uuid: &Uuid)
+        -> Result<Option<Arc<MemDb<'static>>>>
+    {
+        if let Some(open) = self.shared_objects.read().unwrap().get(uuid) {
+            open.last_used.store(self.tick(), Ordering::Relaxed);
+            return Ok(Some(open.memdb.clone()));
+        }
+        let path = get_shared_object_path(&self.get_object_store_path(), uuid);
+        if !path.is_file() {
+            let object = if self.lazy_objects {
+                self.get_manifest_object(info, uuid)?
+            } else {
+                None
+            };
+            match object {
+                Some(object) => {
+                    match self.fetch_shared_object(info, &object,
+                                                   Some(self.object_fetch_budget))? {
+                        FetchOutcome::Stored => {}
+                        FetchOutcome::Failed => { return Ok(None); }
+                        FetchOutcome::Pending => {
+                            debug!("shared object {} was not fetched in time", uuid);
+                            return Err(ErrorKind::ObjectFetchPending(uuid.to_string()).into());
+                        }
+                    }
+                }
+                None => {
+                    warn!("shared object {} is missing from the object store", uuid);
+                    return Ok(None);
+                }
+            }
+        }
+        let memdb = Arc::new(match self.encryption_key {
+            Some(ref key) => MemDb::from_vec(key.read_file(&path)?)?,
+            None => MemDb::from_path(&path)?,
+        });
+        let mut shared_objects = self.shared_objects.write().unwrap();
+        if let Some(open) = shared_objects.get(uuid) {
+            return Ok(Some(open.memdb.clone()));
+        }
+        // an object that does not fit is still used, it just is not kept
+        if shared_objects.len() >= MAX_SHARED_OBJECTS &&
+           !close_idle_memdb(&mut *shared_objects) {
+            return Ok(Some(memdb));
+        }
+        shared_objects.insert(*uuid, OpenMemDb {
+            memdb: memdb.clone(),
+            last_used: AtomicUsize::new(self.tick()),
+        });
+        Ok(Some(memdb))
+    }
+
+    /// Looks up a shared object in the local object manifest of an SDK.
+    fn get_manifest_object(&self, info: &SdkInfo, uuid: &Uuid)
+        -> Result<Option<ManifestObject>>
+    {
+        let path = get_object_manifest_path(&self.get_object_store_path(), info);
+        Ok(ObjectManifest::load(&path)?.and_then(|manifest| {
+            manifest.objects().iter().find(|x| x.uuid() == uuid).cloned()
+        }))
+    }
+
+    /// Returns the stored object manifest of a thin SDK.
+    pub fn get_object_manifest(&self, info: &SdkInfo) -> Result<Option<ObjectManifest>> {
+        ObjectManifest::load(&get_object_manifest_path(&self.get_object_store_path(), info))
+    }
+
+    /// Reads the decrypted memdb of a shared object of an SDK, for servers
+    /// that follow this one.
+    ///
+    /// Only objects the manifest of the SDK lists are handed out.  With
+    /// `sync.lazy_objects` a missing object is fetched first, waiting up to
+    /// `sync.object_fetch_budget_ms` for it like lookups do.
+    pub fn read_shared_object(&self, info: &SdkInfo, uuid: &Uuid) -> Result<Option<Vec<u8>>> {
+        let object = match self.get_manifest_object(info, uuid)? {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+        let path = get_shared_object_path(&self.get_object_store_path(), uuid);
+        if !path.is_file() {
+            if !self.lazy_objects {
+                return Ok(None);
+            }
+            match self.fetch_shared_object(info, &object, Some(self.object_fetch_budget))? {
+                FetchOutcome::Stored => {}
+                FetchOutcome::Failed => { return Ok(None); }
+                FetchOutcome::Pending => {
+                    return Err(ErrorKind::ObjectFetchPending(uuid.to_string()).into());
+                }
+            }
+        }
+        let mut data = vec![];
+        open_file(&path, self.encryption_key.as_ref().map(|x| &**x))?.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Fetches a shared object in the background and waits for it.
+    ///
+    /// Lookups that need an object that is being fetched wait for the same
+    /// download.  An object that failed to download is not fetched again
+    /// before its backoff is over, and at most `MAX_OBJECT_FETCHES`
+    /// downloads run at once.
+    fn fetch_shared_object(&self, info: &SdkInfo, object: &ManifestObject,
+                           timeout: Option<StdDuration>)
+        -> Result<FetchOutcome>
+    {
+        let path = get_shared_object_path(&self.get_object_store_path(), object.uuid());
+        let fetch = {
+            let mut fetches = self.object_fetches.lock().unwrap();
+            let pending = fetches.get(object.uuid()).cloned();
+            if let Some(fetch) = pending {
+                fetch
+            } else if path.is_file() {
+                return Ok(FetchOutcome::Stored);
+            } else if self.failed_fetches.lock().unwrap().get(object.uuid())
+                .map_or(false, |x| x.retry_after > Instant::now())
+            {
+                return Ok(FetchOutcome::Failed);
+            } else if fetches.len() >= MAX_OBJECT_FETCHES {
+                return Ok(FetchOutcome::Pending);
+            } else {
+                let fetch = Arc::new(ObjectFetch::default());
+                let tmp_path = self.get_scratch_file_path(
+                    &format!("{}.download", object.uuid()))?;
+                let upstream = self.upstream.clone();
+                let key = self.encryption_key.clone();
+                let fetches_ref = self.object_fetches.clone();
+                let failed_ref = self.failed_fetches.clone();
+                let obj = object.clone();
+                let info = info.clone();
+                let dst = path.clone();
+                let done = fetch.clone();
+                thread::spawn(move || {
+                    let started = Utc::now();
+                    let stored = match store_shared_object(&upstream, &info, &obj, &dst,
+                                                           &tmp_path,
+                                                           key.as_ref().map(|x| &**x)) {
+                        Ok(()) => {
+                            debug!("fetched shared object {} in {}", obj.uuid(),
+                                   HumanDuration(Utc::now() - started));
+                            true
+                        }
+                        Err(err) => {
+                            warn!("could not fetch shared object {}: {}", obj.uuid(), err);
+                            false
+                        }
+                    };
+                    {
+                        let mut failed = failed_ref.lock().unwrap();
+                        let previous = failed.remove(obj.uuid());
+                        if !stored {
+                            if failed.len() >= MAX_FAILED_FETCHES {
+                                let now = Instant::now();
+                                failed.retain(|_, x| x.retry_after > now);
+                            }
+                            if failed.len() < MAX_FAILED_FETCHES {
+                                failed.insert(*obj.uuid(), FailedFetch::record(previous));
+                            }
+                        }
+                    }
+                    fetches_ref.lock().unwrap().remove(obj.uuid());
+                    done.finish(stored);
+                });
+                fetches.insert(*object.uuid(), fetch.clone());
+                fetch
+            }
+        };
+        Ok(fetch.wait(timeout))
+    }
+
+    /// Fetches the most used shared objects that are not in the object
+    /// store.
+    ///
+    /// This keeps the objects that are looked up all the time local when
+    /// objects are fetched lazily.  Returns the number of fetched objects.
+    fn prefetch_shared_objects(&self, local_state: &SdkSyncState, cancel: &AtomicBool)
+        -> Result<usize>
+    {
+        let store = self.get_object_store_path();
+        let infos: HashMap<_, _> = local_state.sdks()
+            .map(|x| (x.info().sdk_id(), x.info().clone())).collect();
+        let stats = self.get_usage_stats();
+        let mut fetched = 0;
+        for (sdk_id, uuid) in stats.most_used_objects().into_iter().take(self.prefetch_objects) {
+            if cancel.load(Ordering::SeqCst) {
+                break;
+            }
+            if get_shared_object_path(&store, uuid).is_file() {
+                continue;
+            }
+            let info = match infos.get(sdk_id) {
+                Some(info) => info,
+                None => continue,
+            };
+            if let Some(object) = self.get_manifest_object(info, uuid)? {
+                match self.fetch_shared_object(info, &object, None)? {
+                    FetchOutcome::Stored => { fetched += 1; }
+                    FetchOutcome::Failed => {}
+                    // lookups keep all downloads busy
+                    FetchOutcome::Pending => { break; }
+                }
+            }
+        }
+        Ok(fetched)
+    }
+
+    /// Returns `true` if the running sync replaces an SDK with a newer
+    /// version.
+    ///
+    /// Until the new version is downloaded lookups use the old one, unless
+    /// `sync.serve_stale` is off, in which case the SDK does not match.
+    pub fn is_updating(&self, info: &SdkInfo) -> bool {
+        self.updating.read().unwrap().contains(info)
+    }
+
+    /// Checks if the memdb of an SDK is currently open.
+    pub fn is_memdb_open(&self, info: &SdkInfo) -> bool {
+        self.memdbs.read().unwrap().contains_key(info)
+    }
+
+    /// Returns the number of memdbs that are currently open.
+    pub fn open_memdb_count(&self) -> usize {
+        self.memdbs.read().unwrap().len()
+    }
+
+    /// Returns the total size in bytes of all open memdbs.
+    pub fn open_memdb_size(&self) -> u64 {
+        self.memdbs.read().unwrap().values()
+            .map(|open| open.memdb.size() as u64)
+            .sum()
+    }
+
+    /// Returns the maximum number of memdbs that can be open at once.
+    pub fn max_open_memdbs(&self) -> Option<usize> {
+        self.max_open_memdbs
+    }
+
+    /// Records a symbol lookup that was answered from an SDK.
+    pub fn record_lookup(&self, info: &SdkInfo, uuid: &Uuid, object_name: &str) {
+        self.usage.lock().unwrap().record(
+            &info.sdk_id(), uuid, object_name, Utc::now().timestamp());
+        self.usage_dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the usage counters.
+    pub fn get_usage_stats(&self) -> UsageStats {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Adds the usage counters exported from another server.
+    ///
+    /// This makes a new server warm up and prefetch what the other one
+    /// needs.  The counters are written with the next `save_usage_stats`.
+    pub fn import_usage_stats(&self, stats: &UsageStats) {
+        self.usage.lock().unwrap().merge(stats);
+        self.usage_dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Fetches the most used shared objects that are not local yet.
+    ///
+    /// Only does something if objects are fetched lazily, a sync does the
+    /// same.  Returns the number of fetched objects.
+    pub fn prefetch_hot_objects(&self) -> Result<usize> {
+        if !self.lazy_objects {
+            return Ok(0);
+        }
+        let local_state = self.read_local_state()?;
+        self.prefetch_shared_objects(&local_state, &AtomicBool::new(false))
+    }
+
+    /// Writes the usage counters to disk if they changed.
+    ///
+    /// In a read-only stash the counters are only kept in memory.
+    pub fn save_usage_stats(&self) -> Result<()> {
+        if !self.usage_dirty.swap(false, Ordering::Relaxed) || self.read_only {
+            return Ok(());
+        }
+        let stats = self.get_usage_stats();
+        if let Err(err) = self.save_state(&stats, &self.get_usage_filename()) {
+            self.usage_dirty.store(true, Ordering::Relaxed);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Returns the most frequently used SDKs to open ahead of time.
+    ///
+    /// These are at most `count` local SDKs and never more than can be
+    /// open at once.
+    pub fn warm_up_candidates(&self, count: usize) -> Result<Vec<SdkInfo>> {
+        let local_state = self.get_local_state()?;
+        let sdk_ids: Vec<String> = self.usage.lock().unwrap()
+            .most_used_sdks().into_iter().map(|x| x.to_string()).collect();
+        let mut rv = vec![];
+        for sdk_id in sdk_ids {
+            if rv.len() >= count || self.max_open_memdbs.map_or(false, |x| rv.len() >= x) {
+                break;
+            }
+            if_chain! {
+                if let Some(info) = SdkInfo::from_filename(&sdk_id);
+                if local_state.get_sdk(&info).is_some();
+                then {
+                    rv.push(info);
+                }
             }
         }
+        Ok(rv)
+    }
 
-        Err(ErrorKind::UnknownSdk.into())
+    fn tick(&self) -> usize {
+        self.access_clock.fetch_add(1, Ordering::Relaxed) + 1
     }
 
     /// Looks up an memdb by an SDK info as string if available.
@@ -435,21 +3083,418 @@ impl MemDbStash {
         }
     }
 
+    /// The configured limits for fuzzy matches.
+    pub fn fuzzy_match_options(&self) -> FuzzyMatchOptions {
+        self.fuzzy_match_options.clone()
+    }
+
+    /// Opens the memdb file of an SDK for sending it to a client.
+    ///
+    /// If `compressed` is set this is the `.memdbz` file as it was
+    /// downloaded, which only exists if memdbs are kept compressed;
+    /// otherwise `None` is returned.  Uncompressed memdbs of a compressed
+    /// stash are decompressed into the cache first.  Encrypted memdbs are
+    /// decrypted into memory.
+    pub fn open_memdb_file(&self, info: &SdkInfo, compressed: bool)
+        -> Result<Option<MemDbFile>>
+    {
+        let local_state = self.get_local_state()?;
+        let sdk = match local_state.get_sdk(info) {
+            Some(sdk) => sdk,
+            None => {
+                if let Some(withdrawn) = local_state.get_withdrawn(info) {
+                    return Err(ErrorKind::SdkWithdrawn(
+                        info.sdk_id(), withdrawn.reason().to_string()).into());
+                }
+                return Err(ErrorKind::UnknownSdk.into());
+            }
+        };
+        if compressed && !self.compress_memdbs {
+            return Ok(None);
+        }
+        let etag = if compressed {
+            Some(sdk.etag().to_string())
+        } else {
+            local_state.get_checksum(info).map(|x| x.to_string())
+        };
+        if let (true, Some(key)) = (compressed, self.encryption_key.as_ref()) {
+            let path = self.get_stored_memdb_path(info);
+            let modified = fs::metadata(&path)?.modified()?;
+            let data = key.read_file(&path)?;
+            return Ok(Some(MemDbFile {
+                size: data.len() as u64,
+                contents: MemDbContents::Data(data),
+                modified,
+                etag,
+            }));
+        }
+        let path = if compressed {
+            self.get_compressed_memdb_path(info)
+        } else {
+            self.get_openable_memdb_path(info, &*self.memdbs.read().unwrap())?
+        };
+        let file = fs::File::open(&path)?;
+        let md = file.metadata()?;
+        Ok(Some(MemDbFile {
+            contents: MemDbContents::File(file),
+            size: md.len(),
+            modified: md.modified()?,
+            etag,
+        }))
+    }
+
+    /// Returns the tombstone of an SDK that was withdrawn upstream.
+    pub fn get_withdrawn(&self, info: &SdkInfo) -> Result<Option<WithdrawnSdk>> {
+        Ok(self.get_local_state()?.get_withdrawn(info).cloned())
+    }
+
     /// Given an SDK info this returns an array of fuzzy matches for it.
     pub fn fuzzy_match_sdk_id(&self, sdk_id: &str) -> Result<Vec<SdkInfo>> {
+        Ok(self.fuzzy_match_sdk_id_with_scores(sdk_id, &self.fuzzy_match_options)?
+           .into_iter().map(|x| x.info).collect())
+    }
+
+    /// Returns the SDK that should be used for a requested SDK.
+    ///
+    /// This is the SDK itself unless the config aliases it to another one.
+    pub fn resolve_sdk_alias(&self, info: &SdkInfo) -> SdkInfo {
+        self.sdk_aliases.get(info).unwrap_or(info).clone()
+    }
+
+    /// Like `fuzzy_match_sdk_id` but also returns the match scores.
+    ///
+    /// Aliases are resolved before matching.  SDKs of a different minor
+    /// version never match, so the result is empty if nothing close to the
+    /// requested SDK is synched.
+    pub fn fuzzy_match_sdk_id_with_scores(&self, sdk_id: &str, opts: &FuzzyMatchOptions)
+        -> Result<Vec<SdkMatch>>
+    {
         let local_state = self.get_local_state()?;
         let mut rv = vec![];
 
         if let Some(sdk_info) = SdkInfo::from_filename(sdk_id) {
+            let sdk_info = self.resolve_sdk_alias(&sdk_info);
             // find all sdks that have a fuzzy match
             for other in local_state.sdks() {
-                let q = other.info().get_fuzzy_match(&sdk_info).unwrap_or(99999);
-                rv.push((q, other.info().clone()));
+                if self.sdk_is_ignored(other.info()) ||
+                   (!self.serve_stale && self.is_updating(other.info())) {
+                    continue;
+                }
+                if let Some(q) = other.info().get_fuzzy_match(&sdk_info) {
+                    if opts.max_score.map_or(true, |max| q <= max) &&
+                       (q == 0 || is_same_device(other.info(), opts)) {
+                        rv.push((q, other.info().clone()));
+                    }
+                }
             }
 
             rv.sort_by_key(|&(q, ref info)| (q, info > &sdk_info, Rev(info.clone())));
         }
 
-        Ok(rv.into_iter().take(10).map(|(_, info)| info).collect())
+        Ok(rv.into_iter().take(opts.max_results).map(|(score, info)| SdkMatch {
+            info,
+            score,
+        }).collect())
+    }
+}
+
+/// Checks if an SDK may be used for the device a lookup asks for.
+///
+/// SDKs converted before the family or product type was recorded are not
+/// excluded.  A product type without a family implies the family.
+fn is_same_device(info: &SdkInfo, opts: &FuzzyMatchOptions) -> bool {
+    let device_family = opts.device_family.or_else(|| {
+        opts.product_type.as_ref().and_then(|x| DeviceFamily::from_product_type(x))
+    });
+    let same_family = match (info.device_family(), device_family) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    };
+    let same_product = match (info.product_type(), opts.product_type.as_ref()) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    };
+    same_family && same_product
+}
+
+/// Estimates how long downloading `size` bytes takes given how fast the
+/// previous downloads went.
+fn estimate_download_time(done: u64, elapsed: Duration, size: u64) -> Option<Duration> {
+    let millis = elapsed.num_milliseconds();
+    if done == 0 || millis <= 0 {
+        return None;
+    }
+    Some(Duration::milliseconds((size as f64 * millis as f64 / done as f64) as i64))
+}
+
+/// Checks if an error means that a memdb file is unusable.
+///
+/// A missing file is not broken: a sync replaces memdbs and a lookup can
+/// race with that, missing files are left to `check_consistency`.
+fn is_broken_memdb(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::BadMemDb |
+        ErrorKind::UnsupportedMemDbVersion |
+        ErrorKind::ChecksumMismatch(..) |
+        ErrorKind::BadSignature(..) |
+        ErrorKind::TruncatedMemDb(..) |
+        ErrorKind::Utf8Error(..) => true,
+        _ => false,
+    }
+}
+
+/// A writer that counts the bytes of a memdb and keeps its header so that
+/// truncated memdbs can be detected once everything is written.
+struct SizeCheckingWriter<W: Write> {
+    inner: W,
+    header: Vec<u8>,
+    written: u64,
+}
+
+impl<W: Write> SizeCheckingWriter<W> {
+    fn new(inner: W) -> SizeCheckingWriter<W> {
+        SizeCheckingWriter {
+            inner,
+            header: vec![],
+            written: 0,
+        }
+    }
+
+    /// Returns the inner writer if the memdb is as large as its header says.
+    ///
+    /// Files too short for a header are not memdbs at all and are left to
+    /// the verification after the sync.
+    fn finish(self) -> Result<W> {
+        if self.header.len() < mem::size_of::<MemDbHeader>() {
+            return Ok(self.inner);
+        }
+        let mut header = MemDbHeader::default();
+        unsafe {
+            ptr::copy_nonoverlapping(self.header.as_ptr(),
+                                     &mut header as *mut MemDbHeader as *mut u8,
+                                     mem::size_of::<MemDbHeader>());
+        }
+        let expected = header.file_size() as u64;
+        if self.written < expected {
+            return Err(ErrorKind::TruncatedMemDb(expected, self.written).into());
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SizeCheckingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let rv = self.inner.write(buf)?;
+        let missing = mem::size_of::<MemDbHeader>() - self.header.len();
+        if missing > 0 {
+            self.header.extend_from_slice(&buf[..rv.min(missing)]);
+        }
+        self.written += rv as u64;
+        Ok(rv)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns the prefix of the temporary folders of the stash at `path`.
+fn get_temp_dir_prefix(path: &Path) -> String {
+    let hash = format!("{:x}", md5::compute(path.as_os_str().as_bytes()));
+    format!("symbolserver-{}", &hash[..12])
+}
+
+/// Creates a temporary folder for the stash of a config.
+///
+/// The garbage collection of that stash removes the folder once the
+/// process that created it is gone.  The folder is created in the scratch
+/// folder if one is configured.
+pub fn create_temp_dir(config: &Config) -> Result<OwnedTempDir> {
+    let parent = match config.get_stash_scratch_dir() {
+        Some(scratch_dir) => scratch_dir.to_path_buf(),
+        None => env::temp_dir(),
+    };
+    fs::create_dir_all(&parent)?;
+    let prefix = match config.get_symbol_dir() {
+        Ok(path) => get_temp_dir_prefix(&path),
+        Err(_) => "symbolserver".to_string(),
+    };
+    OwnedTempDir::new_in(&parent, &prefix)
+}
+
+/// Returns the total size of the files in a folder.
+fn dir_size(path: &Path) -> u64 {
+    let mut rv = 0;
+    if let Ok(iter) = fs::read_dir(path) {
+        for entry in iter.filter_map(|x| x.ok()) {
+            match entry.metadata() {
+                Ok(ref md) if md.is_dir() => { rv += dir_size(&entry.path()); }
+                Ok(md) => { rv += md.len(); }
+                Err(_) => {}
+            }
+        }
+    }
+    rv
+}
+
+/// Shuffles the first `n` items into a random order and drops the rest.
+fn pick_random_sample<T>(items: &mut Vec<T>, n: usize) -> Result<()> {
+    let n = n.min(items.len());
+    let mut buf = [0u8; 8];
+    for i in 0..n {
+        random_bytes(&mut buf)?;
+        let value = buf.iter().fold(0u64, |acc, &x| (acc << 8) | u64::from(x));
+        let j = i + (value % (items.len() - i) as u64) as usize;
+        items.swap(i, j);
+    }
+    items.truncate(n);
+    Ok(())
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    if let Err(err) = fs::remove_file(path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+fn verify_memdb(path: &Path, checksum: Option<&str>, progress: &ProgressBar) -> Result<()> {
+    let mut hasher = HashingWriter::new(io::sink());
+    let mut f = fs::File::open(path)?;
+    copy_with_progress(progress, &mut f, &mut hasher)?;
+    if let Some(expected) = checksum {
+        let actual = hasher.checksum();
+        if expected != actual {
+            return Err(ErrorKind::ChecksumMismatch(expected.to_string(), actual).into());
+        }
+    }
+    MemDb::from_path(path)?.validate()
+}
+
+/// Checks the shared objects of a thin SDK against its object manifest.
+///
+/// Objects that were not fetched yet are fine if objects are fetched lazily.
+fn verify_shared_objects(store: &Path, info: &SdkInfo, key: Option<&EncryptionKey>,
+                         lazy_objects: bool)
+    -> Result<()>
+{
+    let manifest = match ObjectManifest::load(&get_object_manifest_path(store, info))? {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+    for object in manifest.objects() {
+        let path = get_shared_object_path(store, object.uuid());
+        if !path.is_file() {
+            if lazy_objects {
+                continue;
+            }
+            return Err(ErrorKind::MissingSharedObject(object.uuid().to_string()).into());
+        }
+        let data = match key {
+            Some(key) => key.read_file(&path)?,
+            None => {
+                let mut data = vec![];
+                fs::File::open(&path)?.read_to_end(&mut data)?;
+                data
+            }
+        };
+        let mut hasher = HashingWriter::new(io::sink());
+        hasher.write_all(&data)?;
+        if hasher.checksum() != object.checksum() {
+            return Err(Error::from(ErrorKind::ChecksumMismatch(
+                object.checksum().to_string(), hasher.checksum()))).chain_err(
+                || format!("shared object {} is corrupted", object.uuid()));
+        }
+        MemDb::from_slice(&data)?.validate()?;
+    }
+    Ok(())
+}
+
+/// Downloads a shared object, checks it against the manifest and moves it
+/// into the object store.
+fn store_shared_object(upstream: &Upstream, info: &SdkInfo, object: &ManifestObject,
+                       path: &Path, tmp_path: &Path, key: Option<&EncryptionKey>)
+    -> Result<()>
+{
+    let data = upstream.download_shared_object(info, object.uuid())?;
+    let mut hasher = HashingWriter::new(io::sink());
+    hasher.write_all(&data)?;
+    if data.len() as u64 != object.size() || hasher.checksum() != object.checksum() {
+        return Err(ErrorKind::ChecksumMismatch(
+            object.checksum().to_string(), hasher.checksum()).into());
+    }
+    MemDb::from_slice(&data)?.validate()?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    let rv = fs::File::create(tmp_path)
+        .map_err(Error::from)
+        .and_then(|f| EncryptingWriter::new(f, key))
+        .and_then(|mut f| {
+            f.write_all(&data)?;
+            f.finish()?.sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| move_file(tmp_path, path));
+    if let Err(err) = rv {
+        remove_file_if_exists(tmp_path)?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn read_usage(filename: &Path) -> UsageStats {
+    match fs::File::open(filename) {
+        Ok(f) => match serde_json::from_reader(io::BufReader::new(f)) {
+            Ok(stats) => stats,
+            Err(err) => {
+                warn!("could not parse usage stats, starting over: {}", err);
+                Default::default()
+            }
+        },
+        Err(err) => {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!("could not load usage stats, starting over: {}", err);
+            }
+            Default::default()
+        }
+    }
+}
+
+fn read_ignore_override(filename: &Path) -> Option<IgnorePatterns> {
+    match fs::File::open(filename) {
+        Ok(f) => match serde_json::from_reader(io::BufReader::new(f)) {
+            Ok(patterns) => Some(patterns),
+            Err(err) => {
+                warn!("could not parse ignore pattern override, using config: {}", err);
+                None
+            }
+        },
+        Err(err) => {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!("could not load ignore pattern override, using config: {}", err);
+            }
+            None
+        }
+    }
+}
+
+/// Closes the least recently used memdb that is not currently in use.
+///
+/// A memdb is considered idle if the stash holds the only reference to
+/// it.  Returns `false` if all memdbs are in use.
+fn close_idle_memdb<K>(memdbs: &mut HashMap<K, OpenMemDb>) -> bool
+    where K: Clone + Eq + Hash + fmt::Display
+{
+    let victim = memdbs.iter()
+        .filter(|&(_, open)| Arc::strong_count(&open.memdb) == 1)
+        .min_by_key(|&(_, open)| open.last_used.load(Ordering::Relaxed))
+        .map(|(info, _)| info.clone());
+    if let Some(info) = victim {
+        debug!("closing idle memdb {}", info);
+        memdbs.remove(&info);
+        true
+    } else {
+        false
     }
 }
@@ -0,0 +1,230 @@
+//! Summaries of past syncs.
+//!
+//! Every sync that changed the stash or failed leaves a JSON file in the
+//! `sync-reports` folder of the symbol directory that lists what was added,
+//! updated and removed, what went wrong and which revision the stash ended
+//! up at.  Only the most recent reports are kept.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde_json;
+
+use super::super::sdk::SdkInfo;
+use super::super::{Result, ResultExt};
+
+/// An SDK that changed during a sync
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SdkChange {
+    sdk_id: String,
+    size: u64,
+}
+
+/// Something that went wrong during a sync
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncFailure {
+    sdk_id: Option<String>,
+    reason: String,
+}
+
+/// The summary of a single sync
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncSummary {
+    started: i64,
+    finished: Option<i64>,
+    actor: String,
+    added: Vec<SdkChange>,
+    updated: Vec<SdkChange>,
+    removed: Vec<SdkChange>,
+    failures: Vec<SyncFailure>,
+    revision: Option<u64>,
+}
+
+/// The folder the sync summaries are kept in
+pub struct SyncSummaries {
+    path: PathBuf,
+    keep: usize,
+}
+
+impl SdkChange {
+    /// The id of the SDK that changed
+    pub fn sdk_id(&self) -> &str {
+        &self.sdk_id
+    }
+
+    /// The compressed size of the SDK in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl SyncFailure {
+    /// The id of the SDK that failed, if the failure was about one SDK
+    pub fn sdk_id(&self) -> Option<&str> {
+        self.sdk_id.as_ref().map(|x| x.as_str())
+    }
+
+    /// What went wrong
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl SyncSummary {
+    /// Starts the summary of a sync that starts now.
+    pub fn new(actor: &str) -> SyncSummary {
+        SyncSummary {
+            started: Utc::now().timestamp(),
+            finished: None,
+            actor: actor.to_string(),
+            added: vec![],
+            updated: vec![],
+            removed: vec![],
+            failures: vec![],
+            revision: None,
+        }
+    }
+
+    /// Records an SDK that was downloaded for the first time.
+    pub fn add_added(&mut self, info: &SdkInfo, size: u64) {
+        self.added.push(SdkChange { sdk_id: info.sdk_id(), size });
+    }
+
+    /// Records an SDK that was downloaded again.
+    pub fn add_updated(&mut self, info: &SdkInfo, size: u64) {
+        self.updated.push(SdkChange { sdk_id: info.sdk_id(), size });
+    }
+
+    /// Records an SDK that was removed.
+    pub fn add_removed(&mut self, info: &SdkInfo, size: u64) {
+        self.removed.push(SdkChange { sdk_id: info.sdk_id(), size });
+    }
+
+    /// Records a failure, optionally of a single SDK.
+    pub fn add_failure(&mut self, info: Option<&SdkInfo>, reason: &str) {
+        self.failures.push(SyncFailure {
+            sdk_id: info.map(|x| x.sdk_id()),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Marks the sync as finished with the given stash revision.
+    pub fn finish(&mut self, revision: u64) {
+        self.finished = Some(Utc::now().timestamp());
+        self.revision = Some(revision);
+    }
+
+    /// Returns `true` if the sync changed something or failed.
+    pub fn is_noteworthy(&self) -> bool {
+        !self.added.is_empty() || !self.updated.is_empty() ||
+            !self.removed.is_empty() || !self.failures.is_empty()
+    }
+
+    /// Unix timestamp of when the sync started
+    pub fn started(&self) -> i64 {
+        self.started
+    }
+
+    /// Unix timestamp of when the sync finished
+    pub fn finished(&self) -> Option<i64> {
+        self.finished
+    }
+
+    /// Who ran the sync (`sync`, `cli` etc.)
+    pub fn actor(&self) -> &str {
+        &self.actor
+    }
+
+    /// SDKs that were downloaded for the first time
+    pub fn added(&self) -> &[SdkChange] {
+        &self.added
+    }
+
+    /// SDKs that were downloaded again
+    pub fn updated(&self) -> &[SdkChange] {
+        &self.updated
+    }
+
+    /// SDKs that were removed
+    pub fn removed(&self) -> &[SdkChange] {
+        &self.removed
+    }
+
+    /// Everything that went wrong
+    pub fn failures(&self) -> &[SyncFailure] {
+        &self.failures
+    }
+
+    /// The revision of the stash after the sync
+    pub fn revision(&self) -> Option<u64> {
+        self.revision
+    }
+}
+
+impl SyncSummaries {
+    /// Opens the summary folder at the given path keeping `keep` summaries.
+    pub fn new<P: AsRef<Path>>(path: P, keep: usize) -> SyncSummaries {
+        SyncSummaries {
+            path: path.as_ref().to_path_buf(),
+            keep,
+        }
+    }
+
+    fn list_files(&self) -> Result<Vec<PathBuf>> {
+        let iter = match fs::read_dir(&self.path) {
+            Ok(iter) => iter,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(vec![]);
+            }
+            Err(err) => { return Err(err.into()); }
+        };
+        let mut rv = vec![];
+        for entry in iter {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) == Some("json") {
+                rv.push(path);
+            }
+        }
+        rv.sort();
+        Ok(rv)
+    }
+
+    /// Writes a summary and removes the ones that exceed the limit.
+    pub fn write(&self, summary: &SyncSummary) -> Result<()> {
+        fs::create_dir_all(&self.path)?;
+        let mut counter = 0;
+        let mut filename;
+        loop {
+            filename = self.path.join(format!("sync-{:012}-{:03}.json",
+                                              summary.started, counter));
+            if !filename.exists() {
+                break;
+            }
+            counter += 1;
+        }
+        let mut f = fs::File::create(&filename)?;
+        serde_json::to_writer_pretty(&mut f, summary)
+            .chain_err(|| "Could not write sync summary")?;
+
+        let files = self.list_files()?;
+        if files.len() > self.keep {
+            for path in &files[..files.len() - self.keep] {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the kept summaries, newest first.
+    pub fn list(&self) -> Result<Vec<SyncSummary>> {
+        let mut rv = vec![];
+        for path in self.list_files()?.into_iter().rev() {
+            let f = fs::File::open(&path)?;
+            if let Ok(summary) = serde_json::from_reader(io::BufReader::new(f)) {
+                rv.push(summary);
+            }
+        }
+        Ok(rv)
+    }
+}
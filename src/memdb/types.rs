@@ -1,10 +1,34 @@
 //! Exposes types related to memdb files
 use std::str::from_utf8;
 
+use std::mem;
+
 use uuid::Uuid;
 
+use super::super::dsym::SymbolSource;
 use super::super::sdk::SdkInfo;
 
+/// The memdb format version that is written
+pub const MEMDB_VERSION: u32 = 7;
+
+/// The oldest memdb format version that can be read
+pub const MIN_MEMDB_VERSION: u32 = 2;
+
+/// The page size the address index is laid out for
+pub const PAGE_SIZE: usize = 4096;
+
+/// The maximum number of index items in a leaf of the address index
+pub const LEAF_CAPACITY: usize = PAGE_SIZE / mem::size_of::<IndexItem>();
+
+/// Marks the symbol ID of a symbol that was recovered from runtime
+/// metadata instead of a symbol table (version 5 and later)
+const RECOVERED_SYMBOL: u32 = 0x8000_0000;
+
+/// The bits of the symbol ID of a recovered symbol that hold its source
+/// (version 6 and later, zero before)
+const SOURCE_SHIFT: u32 = 29;
+const SOURCE_MASK: u32 = 0x6000_0000;
+
 
 /// The stored memdb file header
 #[repr(C)]
@@ -22,6 +46,58 @@ pub struct MemDbHeader {
     pub object_names_count: u32,
     pub symbols_start: u32,
     pub symbols_count: u32,
+    /// The device family of the SDK (version 4 and later, 0 if unknown)
+    pub device_family: u32,
+    /// Where the JSON encoded `ConversionInfo` is (version 7 and later)
+    pub conversion_info_start: u32,
+    pub conversion_info_len: u32,
+}
+
+/// Describes how a memdb was converted.
+///
+/// This is stored in the memdb so that files written by a converter with
+/// a bug can be found and converted again.  It leaves out anything that
+/// changes between runs (like the time) so that the same SDK still gives
+/// the same memdb.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConversionInfo {
+    /// The version of the converter
+    pub converter_version: String,
+    /// The git commit the converter was built from if known
+    pub converter_commit: Option<String>,
+    /// The memdb format version the file was written in
+    pub format_version: u32,
+    /// A hash of the names and UUIDs of the objects that were converted
+    pub sdk_fingerprint: String,
+    /// The product type of the SDK (`iPhone12,1`) if it was known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_type: Option<String>,
+    /// The options that influence the contents of the memdb
+    pub options: ConversionOptions,
+    /// Objects that were left out because they could not be converted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<SkippedObject>,
+}
+
+/// An object the sandbox failed to convert
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SkippedObject {
+    pub filename: String,
+    pub reason: String,
+}
+
+/// The options of a conversion that influence the contents of the memdb
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConversionOptions {
+    pub compress: bool,
+    pub thin: bool,
+    pub symbol_sources: Vec<SymbolSource>,
+    /// Whether hot objects were laid out first
+    #[serde(default)]
+    pub profile_guided: bool,
+    /// What was dropped to fit the size budget, in the order it was dropped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trimmed: Vec<String>,
 }
 
 /// Packed SDK information
@@ -58,13 +134,52 @@ pub struct IndexItem {
     sym_id: u32,
 }
 
+/// Points to a leaf of the address index
+///
+/// Starting with version 3 the variants of a memdb point to a directory
+/// of leaves instead of a flat list of index items.  A leaf never
+/// straddles a page boundary so that a lookup only needs to touch the
+/// page of the directory and the page of the leaf.
+#[repr(C, packed)]
+pub struct LeafRef {
+    addr_low: u32,
+    addr_high: u16,
+    count: u16,
+    offset: u32,
+}
+
 fn copy_str_to_slice(slice: &mut [u8], s: &str) {
     let bytes = s.as_bytes();
     (&mut slice[..bytes.len()]).copy_from_slice(bytes);
 }
 
-fn str_from_zero_slice(slice: &[u8]) -> &str {
-    from_utf8(slice).unwrap().trim_right_matches('\x00')
+fn str_from_zero_slice(slice: &[u8]) -> Option<&str> {
+    from_utf8(slice).ok().map(|x| x.trim_end_matches('\x00'))
+}
+
+impl MemDbHeader {
+
+    /// Returns how large the header is in the version of the file.
+    ///
+    /// Version 4 added the device family and version 7 the conversion
+    /// info, older files start their sections earlier.
+    pub fn header_size(&self) -> usize {
+        let missing = match self.version {
+            0..=3 => 12,
+            4..=6 => 8,
+            _ => 0,
+        };
+        mem::size_of::<MemDbHeader>() - missing
+    }
+
+    /// Returns the size of the memdb file the header describes.
+    ///
+    /// The symbol table is written last so a file that is shorter than
+    /// this is truncated.
+    pub fn file_size(&self) -> usize {
+        self.symbols_start as usize +
+            self.symbols_count as usize * mem::size_of::<StoredSlice>()
+    }
 }
 
 impl PackedSdkInfo {
@@ -79,15 +194,16 @@ impl PackedSdkInfo {
         }
     }
 
-    pub fn to_sdk_info(&self) -> SdkInfo {
-        let build = str_from_zero_slice(&self.build[..]);
-        SdkInfo::new(
-            str_from_zero_slice(&self.name[..]),
+    /// Returns `None` if the name or build are not UTF-8.
+    pub fn to_sdk_info(&self) -> Option<SdkInfo> {
+        let build = str_from_zero_slice(&self.build[..])?;
+        Some(SdkInfo::new(
+            str_from_zero_slice(&self.name[..])?,
             self.version_major as u32,
             self.version_minor as u32,
             self.version_patchlevel as u32,
             if build.is_empty() { None } else { Some(build) },
-        )
+        ))
     }
 }
 
@@ -122,6 +238,12 @@ impl StoredSlice {
         }
     }
 
+    /// Creates the slice of a variant whose symbols are kept in the shared
+    /// object store instead of the memdb.
+    pub fn new_external() -> StoredSlice {
+        StoredSlice::new(0, 0, true)
+    }
+
     /// Returns the offset of the stored slice as bytes
     pub fn offset(&self) -> usize {
         self.offset as usize
@@ -136,6 +258,13 @@ impl StoredSlice {
     pub fn is_compressed(&self) -> bool {
         self.len >> 31 != 0
     }
+
+    /// Indicates that the variant is kept in the shared object store.
+    ///
+    /// Variants reuse the compression flag of strings for this.
+    pub fn is_external(&self) -> bool {
+        self.is_compressed()
+    }
 }
 
 impl IndexItem {
@@ -159,12 +288,78 @@ impl IndexItem {
         self.src_id
     }
 
+    /// Creates an indexed symbol that was recovered from another source
+    /// than the symbol table
+    pub fn new_recovered(addr: u64, src_id: u16, sym_id: u32, source: SymbolSource)
+        -> IndexItem
+    {
+        let flags = match source {
+            SymbolSource::SymbolTable => 0,
+            _ => RECOVERED_SYMBOL | (source.to_u32() << SOURCE_SHIFT),
+        };
+        IndexItem::new(addr, src_id, Some(sym_id | flags))
+    }
+
     /// The ID of the symbol
     pub fn sym_id(&self) -> Option<u32> {
         if self.sym_id == !0 {
             None
         } else {
-            Some(self.sym_id)
+            Some(self.sym_id & !(RECOVERED_SYMBOL | SOURCE_MASK))
+        }
+    }
+
+    /// Returns `true` if the symbol was recovered from runtime metadata
+    pub fn is_recovered(&self) -> bool {
+        self.sym_id != !0 && self.sym_id & RECOVERED_SYMBOL != 0
+    }
+
+    /// Where the symbol came from.  `None` for the end marker and symbols
+    /// recovered before the source was recorded.
+    pub fn source(&self) -> Option<SymbolSource> {
+        if self.sym_id == !0 {
+            None
+        } else if !self.is_recovered() {
+            Some(SymbolSource::SymbolTable)
+        } else {
+            match (self.sym_id & SOURCE_MASK) >> SOURCE_SHIFT {
+                0 => None,
+                kind => SymbolSource::from_u32(kind),
+            }
         }
     }
+
+    /// Returns the item with its object name and symbol under new IDs.
+    /// Where the symbol came from is kept.
+    pub fn remap(&self, src_id: u16, sym_id: Option<u32>) -> IndexItem {
+        let flags = self.sym_id & (RECOVERED_SYMBOL | SOURCE_MASK);
+        IndexItem::new(self.addr(), src_id, sym_id.map(|x| x | flags))
+    }
+}
+
+impl LeafRef {
+    /// Creates a new reference to a leaf
+    pub fn new(addr: u64, count: usize, offset: usize) -> LeafRef {
+        LeafRef {
+            addr_low: (addr & 0xffffffff) as u32,
+            addr_high: ((addr >> 32) & 0xffff) as u16,
+            count: count as u16,
+            offset: offset as u32,
+        }
+    }
+
+    /// The address of the first symbol in the leaf
+    pub fn addr(&self) -> u64 {
+        (u64::from(self.addr_high) << 32) | u64::from(self.addr_low)
+    }
+
+    /// The number of index items in the leaf
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+
+    /// The offset of the leaf in bytes
+    pub fn offset(&self) -> usize {
+        self.offset as usize
+    }
 }
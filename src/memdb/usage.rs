@@ -0,0 +1,191 @@
+//! Tracks how often SDKs and objects in the stash are used.
+//!
+//! The counters are kept in memory and periodically written to the
+//! symbol directory so that they survive restarts.  They are used to
+//! decide which SDKs are worth keeping mapped in.
+use std::collections::{BTreeMap, HashMap};
+
+use uuid::Uuid;
+
+/// Usage information for a single object in an SDK
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct ObjectUsage {
+    name: String,
+    lookups: u64,
+    last_access: i64,
+}
+
+/// Usage information for an SDK
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct SdkUsage {
+    lookups: u64,
+    last_access: i64,
+    objects: HashMap<Uuid, ObjectUsage>,
+}
+
+/// Usage information for all SDKs in the stash
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct UsageStats {
+    sdks: HashMap<String, SdkUsage>,
+}
+
+impl ObjectUsage {
+    /// The name of the object
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of symbols looked up in this object
+    pub fn lookups(&self) -> u64 {
+        self.lookups
+    }
+
+    /// Unix timestamp of the last lookup
+    pub fn last_access(&self) -> i64 {
+        self.last_access
+    }
+}
+
+impl SdkUsage {
+    /// The number of symbols looked up in this SDK
+    pub fn lookups(&self) -> u64 {
+        self.lookups
+    }
+
+    /// Unix timestamp of the last lookup
+    pub fn last_access(&self) -> i64 {
+        self.last_access
+    }
+
+    /// Returns the usage of a specific object
+    pub fn get_object(&self, uuid: &Uuid) -> Option<&ObjectUsage> {
+        self.objects.get(uuid)
+    }
+}
+
+impl UsageStats {
+    /// Records a symbol lookup in an object of an SDK
+    pub fn record(&mut self, sdk_id: &str, uuid: &Uuid, object_name: &str, now: i64) {
+        let sdk = self.sdks.entry(sdk_id.to_string()).or_insert_with(Default::default);
+        sdk.lookups += 1;
+        sdk.last_access = now;
+        let obj = sdk.objects.entry(*uuid).or_insert_with(Default::default);
+        if obj.name != object_name {
+            obj.name = object_name.to_string();
+        }
+        obj.lookups += 1;
+        obj.last_access = now;
+    }
+
+    /// Returns the usage of an SDK
+    pub fn get_sdk(&self, sdk_id: &str) -> Option<&SdkUsage> {
+        self.sdks.get(sdk_id)
+    }
+
+    /// Returns the number of SDKs with recorded usage
+    pub fn sdk_count(&self) -> usize {
+        self.sdks.len()
+    }
+
+    /// Forgets about an SDK
+    pub fn remove_sdk(&mut self, sdk_id: &str) {
+        self.sdks.remove(sdk_id);
+    }
+
+    /// Adds the counters of another server.
+    ///
+    /// Lookups add up and the later access wins.
+    pub fn merge(&mut self, other: &UsageStats) {
+        for (sdk_id, other_sdk) in other.sdks.iter() {
+            let sdk = self.sdks.entry(sdk_id.clone()).or_insert_with(Default::default);
+            sdk.lookups += other_sdk.lookups;
+            sdk.last_access = sdk.last_access.max(other_sdk.last_access);
+            for (uuid, other_obj) in other_sdk.objects.iter() {
+                let obj = sdk.objects.entry(*uuid).or_insert_with(Default::default);
+                if obj.name.is_empty() {
+                    obj.name = other_obj.name.clone();
+                }
+                obj.lookups += other_obj.lookups;
+                obj.last_access = obj.last_access.max(other_obj.last_access);
+            }
+        }
+    }
+
+    /// Returns the usage of the `count` most used SDKs only.
+    pub fn hot_set(&self, count: usize) -> UsageStats {
+        UsageStats {
+            sdks: self.most_used_sdks().into_iter().take(count).map(|sdk_id| {
+                (sdk_id.to_string(), self.sdks[sdk_id].clone())
+            }).collect(),
+        }
+    }
+
+    /// Returns the usage of up to `limit` SDKs from `offset` on.
+    ///
+    /// The SDKs are ordered by their id so that the pages of a listing do
+    /// not overlap while counters change.
+    pub fn page(&self, offset: usize, limit: usize) -> BTreeMap<&str, &SdkUsage> {
+        let mut sdk_ids: Vec<_> = self.sdks.keys().map(|x| x.as_str()).collect();
+        sdk_ids.sort();
+        sdk_ids.into_iter().skip(offset).take(limit)
+            .map(|sdk_id| (sdk_id, &self.sdks[sdk_id]))
+            .collect()
+    }
+
+    /// Returns the objects ordered by how often they were used along with
+    /// the SDK they were used most in.
+    pub fn most_used_objects(&self) -> Vec<(&str, &Uuid)> {
+        let mut objects: HashMap<&Uuid, (u64, u64, &str)> = HashMap::new();
+        for (sdk_id, sdk) in self.sdks.iter() {
+            for (uuid, obj) in sdk.objects.iter() {
+                let entry = objects.entry(uuid).or_insert((0, 0, sdk_id));
+                if obj.lookups > entry.1 {
+                    entry.1 = obj.lookups;
+                    entry.2 = sdk_id;
+                }
+                entry.0 += obj.lookups;
+            }
+        }
+        let mut rv: Vec<_> = objects.into_iter().collect();
+        rv.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then_with(|| a.0.cmp(b.0)));
+        rv.into_iter().map(|(uuid, (_, _, sdk_id))| (sdk_id, uuid)).collect()
+    }
+
+    /// Returns the SDK ids ordered by how often they were used
+    pub fn most_used_sdks(&self) -> Vec<&str> {
+        let mut rv: Vec<_> = self.sdks.iter().collect();
+        rv.sort_by(|a, b| {
+            (b.1.lookups, b.1.last_access).cmp(&(a.1.lookups, a.1.last_access))
+        });
+        rv.into_iter().map(|(sdk_id, _)| sdk_id.as_str()).collect()
+    }
+}
+
+#[test]
+fn test_most_used_sdks() {
+    let uuid: Uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    let mut stats = UsageStats::default();
+    stats.record("iOS_10.2.0_14C92", &uuid, "libfoo.dylib", 10);
+    stats.record("iOS_10.3.0_14E277", &uuid, "libfoo.dylib", 11);
+    stats.record("iOS_10.3.0_14E277", &uuid, "libfoo.dylib", 12);
+
+    assert_eq!(stats.most_used_sdks(), vec!["iOS_10.3.0_14E277", "iOS_10.2.0_14C92"]);
+    let sdk = stats.get_sdk("iOS_10.3.0_14E277").unwrap();
+    assert_eq!(sdk.lookups(), 2);
+    assert_eq!(sdk.last_access(), 12);
+    assert_eq!(sdk.get_object(&uuid).unwrap().lookups(), 2);
+
+    let json = ::serde_json::to_string(&stats).unwrap();
+    let mut stats: UsageStats = ::serde_json::from_str(&json).unwrap();
+    assert_eq!(stats.get_sdk("iOS_10.2.0_14C92").unwrap().lookups(), 1);
+
+    let page = stats.page(1, 10);
+    assert_eq!(page.keys().collect::<Vec<_>>(), vec![&"iOS_10.3.0_14E277"]);
+    assert!(stats.page(2, 10).is_empty());
+
+    let hot = stats.hot_set(1);
+    assert_eq!(hot.sdk_count(), 1);
+    stats.merge(&hot);
+    assert_eq!(stats.get_sdk("iOS_10.3.0_14E277").unwrap().lookups(), 4);
+    assert_eq!(stats.get_sdk("iOS_10.2.0_14C92").unwrap().lookups(), 1);
+}
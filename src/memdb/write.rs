@@ -3,24 +3,35 @@
 //! A support folder with SDK debug symbols can be processed into a
 //! in-memory database format which is a flat file on the file system
 //! that gets mmaped into the process.
-use std::io::{Write, Seek, SeekFrom};
-use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write, Seek, SeekFrom};
+use std::fs::{self, File, OpenOptions};
 use std::mem;
 use std::slice;
 use std::cell::RefCell;
 use std::collections::{HashSet, HashMap};
+use std::collections::hash_map::Entry;
+use std::path::{Path, PathBuf};
 
+use md5;
+use serde_json;
 use uuid::Uuid;
 use xz2::write::XzEncoder;
-use tempfile::tempfile;
-use console::{style, StyledObject};
-use indicatif::{ProgressBar, ProgressStyle};
+use tempfile::{tempfile, tempfile_in};
+use indicatif::ProgressStyle;
+use mach_object::Error as MachError;
 
-use super::types::{IndexItem, StoredSlice, MemDbHeader, IndexedUuid};
-use super::super::Result;
+use super::types::{IndexItem, StoredSlice, MemDbHeader, IndexedUuid, LeafRef, ConversionInfo,
+                   ConversionOptions, SkippedObject, MEMDB_VERSION, PAGE_SIZE,
+                   LEAF_CAPACITY};
+use super::objects::{get_object_manifest_path, get_shared_object_path, ObjectManifest,
+                     ManifestObject};
+use super::super::{ErrorKind, Result, ResultExt};
+use super::super::constants::{GIT_COMMIT, VERSION};
 use super::super::sdk::{SdkInfo, DumpOptions, Objects};
-use super::super::dsym::{Object, Variant};
-use super::super::utils::{file_size_format, copy_with_progress};
+use super::super::dsym::{Object, Variant, SymbolSource};
+use super::super::sandbox::{Sandbox, enter_seccomp};
+use super::super::utils::{file_size_format, copy_with_progress, write_file_atomic,
+                          IgnorePatterns};
 
 
 struct MemDbBuilder<W> {
@@ -35,17 +46,231 @@ struct MemDbBuilder<W> {
     variant_uuids: Vec<IndexedUuid>,
     variant_uuids_seen: HashSet<Uuid>,
     variants: Vec<Vec<IndexItem>>,
+    /// The variants whose symbols went to the shared object store
+    shared_variants: HashSet<usize>,
+    object_manifest: ObjectManifest,
     symbol_count: usize,
+    /// The names of the trim levels that were applied
+    trimmed: Vec<String>,
+    /// The objects the sandbox failed to convert
+    skipped: Vec<SkippedObject>,
     options: DumpOptions,
 }
 
-fn format_step(step: usize, opts: &DumpOptions) -> StyledObject<String> {
+/// What an object contributes to the memdb.
+///
+/// Objects are recorded in the journal in this form so that an interrupted
+/// conversion does not have to read their symbols again.
+#[derive(Serialize, Deserialize)]
+struct ObjectRecord {
+    filename: String,
+    variants: Vec<VariantRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct VariantRecord {
+    src: String,
+    arch: String,
+    uuid: Uuid,
+    vmsize: u64,
+    /// The symbols by address relative to the image, `None` if a variant
+    /// with the same UUID came before
+    symbols: Option<Vec<(u64, String)>>,
+    /// Symbols from other sources than the symbol table with their source
+    #[serde(default)]
+    recovered: Vec<(u64, String, SymbolSource)>,
+}
+
+/// What the child converting an object in a sandbox writes to stdout, one
+/// message per line.
+#[derive(Serialize, Deserialize)]
+enum SandboxMessage {
+    /// A variant of the object with its symbols
+    Variant(VariantRecord),
+    /// The file is not an object and is skipped
+    NotAnObject,
+    /// The object could not be read
+    Failed(String),
+    /// Every variant was sent
+    Done,
+}
+
+/// A step of trimming a memdb down to `DumpOptions::max_size`.
+///
+/// The steps are applied in order and each keeps the ones before.
+enum Trim {
+    /// Drops the symbols recovered from a source
+    Source(SymbolSource),
+    /// Drops the objects that match a pattern of `cold_objects`
+    ColdObjects(String, IgnorePatterns),
+}
+
+impl Trim {
+    /// The steps for the options from the first to the last resort.
+    ///
+    /// Memdbs carry no line information, so the first thing to go are the
+    /// recovered symbols, starting with the function starts which only
+    /// name addresses, then the Swift and the Objective-C metadata.  Then
+    /// the cold objects are dropped one pattern after another.
+    fn levels(opts: &DumpOptions) -> Result<Vec<Trim>> {
+        let mut rv = vec![];
+        for &source in [SymbolSource::FunctionStarts, SymbolSource::Swift,
+                        SymbolSource::Objc].iter() {
+            if opts.symbol_sources.contains(&source) {
+                rv.push(Trim::Source(source));
+            }
+        }
+        for pattern in opts.cold_objects.iter() {
+            rv.push(Trim::ColdObjects(pattern.clone(), IgnorePatterns::new(&[pattern])?));
+        }
+        Ok(rv)
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            Trim::Source(source) => source.to_string(),
+            Trim::ColdObjects(ref pattern, _) => format!("cold:{}", pattern),
+        }
+    }
+}
+
+/// Identifies what a journal was written for.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct JournalHeader {
+    sdk_id: String,
+    memdb_version: u32,
+}
+
+/// Records the objects a conversion has processed.
+///
+/// The journal is a line of JSON for the header followed by one line per
+/// object.  A line that was not written completely ends the journal.
+struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Opens a journal and returns the objects a previous conversion of
+    /// the same SDK recorded in it.
+    fn open(path: &Path, info: &SdkInfo) -> Result<(Journal, Vec<ObjectRecord>)> {
+        let header = JournalHeader {
+            sdk_id: info.sdk_id(),
+            memdb_version: MEMDB_VERSION,
+        };
+        let mut records = vec![];
+        let mut valid_len = 0;
+        if let Ok(f) = File::open(path) {
+            let mut reader = BufReader::new(f);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let read = reader.read_line(&mut line)?;
+                if read == 0 || !line.ends_with('\n') {
+                    break;
+                }
+                if valid_len == 0 {
+                    match serde_json::from_str::<JournalHeader>(&line) {
+                        Ok(ref other) if *other == header => {}
+                        _ => break,
+                    }
+                } else {
+                    match serde_json::from_str(&line) {
+                        Ok(record) => records.push(record),
+                        Err(_) => break,
+                    }
+                }
+                valid_len += read as u64;
+            }
+        }
+
+        let mut file = OpenOptions::new().create(true).write(true).open(path)?;
+        file.set_len(valid_len)?;
+        file.seek(SeekFrom::End(0))?;
+        let mut journal = Journal { path: path.to_path_buf(), file };
+        if valid_len == 0 {
+            journal.append(&header)?;
+        }
+        Ok((journal, records))
+    }
+
+    fn append<T: ::serde::Serialize>(&mut self, value: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(value).chain_err(|| "Could not serialize journal")?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Removes the journal once the memdb is complete.
+    fn finish(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Reads the symbols of a variant from the given sources.
+///
+/// When several sources name the same address the one listed first wins.
+/// Only the symbol table can have several names for the same address.
+fn read_variant(obj: &Object, var: &Variant, uuid: Uuid, filename: &str,
+                sources: &[SymbolSource], seen: bool) -> Result<VariantRecord> {
+    let mut recovered = vec![];
+    let symbols = if seen {
+        None
+    } else {
+        let mut candidates = vec![];
+        if sources.contains(&SymbolSource::SymbolTable) {
+            let mut symbols = obj.symbols(var.arch())?;
+            candidates.extend(symbols.iter().map(|(addr, sym)| {
+                (addr - var.vmaddr(), sym.to_string(), SymbolSource::SymbolTable)
+            }));
+        }
+        if sources.iter().any(|&x| x != SymbolSource::SymbolTable) {
+            for (addr, sym, source) in obj.recovered_symbols(var.arch())? {
+                if addr >= var.vmaddr() && sources.contains(&source) {
+                    candidates.push((addr - var.vmaddr(), sym, source));
+                }
+            }
+        }
+        candidates.sort_by_key(|x| sources.iter().position(|&source| source == x.2));
+
+        let mut rv = vec![];
+        let mut claimed = HashMap::new();
+        for (addr, sym, source) in candidates {
+            match claimed.entry(addr) {
+                Entry::Vacant(entry) => { entry.insert(source); }
+                Entry::Occupied(entry) => {
+                    if source != SymbolSource::SymbolTable || *entry.get() != source {
+                        continue;
+                    }
+                }
+            }
+            if source == SymbolSource::SymbolTable {
+                rv.push((addr, sym));
+            } else {
+                recovered.push((addr, sym, source));
+            }
+        }
+        Some(rv)
+    };
+    Ok(VariantRecord {
+        src: var.name().unwrap_or(filename).to_string(),
+        arch: var.arch().to_string(),
+        uuid,
+        vmsize: var.vmsize(),
+        symbols,
+        recovered,
+    })
+}
+
+fn report_step(step: usize, opts: &DumpOptions, msg: &str) {
     let steps = if opts.compress {
         6
     } else {
         5
     };
-    style(format!("[{}/{}]", step, steps)).dim()
+    opts.reporter.step(step, steps, msg);
 }
 
 trait WriteSeek : Write + Seek {}
@@ -55,11 +280,22 @@ impl<W: Write + Seek> MemDbBuilder<W> {
 
     pub fn new(writer: W, info: &SdkInfo, opts: DumpOptions)
         -> Result<MemDbBuilder<W>>
+    {
+        MemDbBuilder::create(writer, info, opts, false)
+    }
+
+    /// Creates a builder that assembles the memdb in a temporary file even
+    /// if it is not compressed, so that it never seeks in the writer.
+    fn create(writer: W, info: &SdkInfo, opts: DumpOptions, buffered: bool)
+        -> Result<MemDbBuilder<W>>
     {
         let rv = MemDbBuilder {
             writer: RefCell::new(writer),
-            tempfile: if opts.compress {
-                Some(RefCell::new(tempfile()?))
+            tempfile: if opts.compress || buffered {
+                Some(RefCell::new(match opts.scratch_dir {
+                    Some(ref dir) => tempfile_in(dir)?,
+                    None => tempfile()?,
+                }))
             } else {
                 None
             },
@@ -72,7 +308,11 @@ impl<W: Write + Seek> MemDbBuilder<W> {
             variant_uuids: vec![],
             variant_uuids_seen: HashSet::new(),
             variants: vec![],
+            shared_variants: HashSet::new(),
+            object_manifest: ObjectManifest::default(),
             symbol_count: 0,
+            trimmed: vec![],
+            skipped: vec![],
             options: opts,
         };
         let header = MemDbHeader { ..Default::default() };
@@ -81,10 +321,9 @@ impl<W: Write + Seek> MemDbBuilder<W> {
     }
 
     fn with_file<T, F: FnOnce(&mut WriteSeek) -> T>(&self, f: F) -> T {
-        if self.options.compress {
-            f(&mut *self.tempfile.as_ref().unwrap().borrow_mut() as &mut WriteSeek)
-        } else {
-            f(&mut *self.writer.borrow_mut() as &mut WriteSeek)
+        match self.tempfile {
+            Some(ref tempfile) => f(&mut *tempfile.borrow_mut() as &mut WriteSeek),
+            None => f(&mut *self.writer.borrow_mut() as &mut WriteSeek),
         }
     }
 
@@ -106,6 +345,17 @@ impl<W: Write + Seek> MemDbBuilder<W> {
         }
     }
 
+    /// Pads the file so that a block of `len` bytes written next does not
+    /// straddle a page boundary unless it is larger than a page.
+    fn pad_for_block(&self, len: usize) -> Result<()> {
+        let pos = self.tell()?;
+        let padded = padded_for_block(pos, len);
+        if padded > pos {
+            self.write_bytes(&[0u8; PAGE_SIZE][..padded - pos])?;
+        }
+        Ok(())
+    }
+
     fn seek(&self, new_pos: usize) -> Result<()> {
         self.with_file(|w| {
             w.seek(SeekFrom::Start(new_pos as u64))?;
@@ -139,57 +389,373 @@ impl<W: Write + Seek> MemDbBuilder<W> {
         object_count as u16
     }
 
-    pub fn write_object(&mut self, obj: &Object, filename: Option<&str>) -> Result<()> {
+    /// Reads the symbols of the variants of an object.
+    ///
+    /// Symbols are only read for variants whose UUID was not seen yet.
+    fn record_object(&self, obj: &Object, filename: &str) -> Result<ObjectRecord> {
+        let mut variants: Vec<VariantRecord> = vec![];
         for variant in obj.variants() {
-            let src = variant.name().or(filename).unwrap();
             if let Some(uuid) = variant.uuid() {
-                self.write_object_variant(&obj, &variant, &uuid, src)?;
+                let seen = self.variant_uuids_seen.contains(&uuid) ||
+                    variants.iter().any(|x| x.uuid == uuid);
+                variants.push(read_variant(obj, &variant, uuid, filename,
+                                           &self.options.symbol_sources, seen)?);
             }
         }
+        Ok(ObjectRecord {
+            filename: filename.to_string(),
+            variants,
+        })
+    }
+
+    /// Reads the symbols of an object in a child process.
+    ///
+    /// Returns `None` if the file is not an object.  Like `record_object`
+    /// it drops the symbols of variants whose UUID was seen already.
+    fn record_object_sandboxed(&self, sandbox: &Sandbox, data: Vec<u8>, filename: &str)
+        -> Result<Option<ObjectRecord>>
+    {
+        let sources: Vec<_> = self.options.symbol_sources.iter()
+            .map(|x| x.to_string()).collect();
+        let sources = sources.join(",");
+        let mut args = vec!["convert-object", "--filename", filename,
+                            "--symbol-sources", &sources];
+        if sandbox.seccomp {
+            args.push("--seccomp");
+        }
+
+        let mut variants = vec![];
+        let mut outcome = None;
+        sandbox.run(filename, &args, data, |line| {
+            match serde_json::from_str(line).chain_err(|| "Invalid output of the sandbox")? {
+                SandboxMessage::Variant(var) => { variants.push(var); }
+                SandboxMessage::NotAnObject => { outcome = Some(Ok(false)); }
+                SandboxMessage::Failed(msg) => { outcome = Some(Err(msg)); }
+                SandboxMessage::Done => { outcome = Some(Ok(true)); }
+            }
+            Ok(())
+        })?;
+        match outcome {
+            Some(Ok(true)) => {}
+            Some(Ok(false)) => { return Ok(None); }
+            Some(Err(msg)) => {
+                return Err(ErrorKind::SandboxFailure(filename.to_string(), msg).into());
+            }
+            None => {
+                return Err(ErrorKind::SandboxFailure(
+                    filename.to_string(), "the child stopped early".into()).into());
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for var in variants.iter_mut() {
+            if self.variant_uuids_seen.contains(&var.uuid) || !seen.insert(var.uuid) {
+                var.symbols = None;
+                var.recovered.clear();
+            }
+        }
+        Ok(Some(ObjectRecord {
+            filename: filename.to_string(),
+            variants,
+        }))
+    }
+
+    fn write_object(&mut self, record: &ObjectRecord) -> Result<()> {
+        for variant in record.variants.iter() {
+            self.write_object_variant(variant)?;
+        }
         Ok(())
     }
 
-    fn write_object_variant(&mut self, obj: &Object, var: &Variant,
-                            uuid: &Uuid, src: &str) -> Result<bool> {
+    /// Works out the uncompressed size of the memdb for every number of
+    /// trim levels applied, in one pass over the objects written so far.
+    ///
+    /// Every index item and string is charged to the first level that
+    /// drops it: items to their source or to the cold pattern of their
+    /// object, strings to the last level that still refers to them.  The
+    /// index is then laid out like `flush` does to account for the padding
+    /// of its leaves.
+    fn estimate_sizes(&self, levels: &[Trim]) -> Result<Vec<u64>> {
+        let never = levels.len();
+        let source_level = |source: SymbolSource| levels.iter().position(|trim| match *trim {
+            Trim::Source(x) => x == source,
+            _ => false,
+        }).unwrap_or(never);
+
+        // an object is dropped with all its copies, even those that did not
+        // carry the symbols
+        let mut object_levels: HashMap<Uuid, usize> = HashMap::new();
+        for &(ref name, uuid) in self.object_uuid_mapping.iter() {
+            let level = levels.iter().position(|trim| match *trim {
+                Trim::ColdObjects(_, ref patterns) => patterns.is_match(object_src(name)),
+                _ => false,
+            }).unwrap_or(never);
+            let entry = object_levels.entry(uuid).or_insert(never);
+            *entry = (*entry).min(level);
+        }
+
+        let mut item_counts = vec![vec![0usize; never + 1]; self.variants.len()];
+        let mut symbol_levels = vec![0usize; self.symbols.len()];
+        let mut name_levels = vec![0usize; self.object_names.len()];
+        for (idx, items) in self.variants.iter().enumerate() {
+            let level = object_levels[self.variant_uuids[idx].uuid()];
+            for item in items.iter() {
+                let item_level = match item.source() {
+                    Some(source) if item.is_recovered() => level.min(source_level(source)),
+                    _ => level,
+                };
+                item_counts[idx][item_level] += 1;
+                if let Some(sym_id) = item.sym_id() {
+                    let sym_level = &mut symbol_levels[sym_id as usize];
+                    *sym_level = (*sym_level).max(item_level);
+                }
+                let name_level = &mut name_levels[item.src_id() as usize];
+                *name_level = (*name_level).max(item_level);
+            }
+        }
+        let mut charged = vec![0usize; never + 1];
+        for (sym, &level) in self.symbols.iter().zip(symbol_levels.iter()) {
+            charged[level] += sym.len() + mem::size_of::<StoredSlice>();
+        }
+        for (name, &level) in self.object_names.iter().zip(name_levels.iter()) {
+            charged[level] += name.len() + mem::size_of::<StoredSlice>();
+        }
+        for &(ref name, uuid) in self.object_uuid_mapping.iter() {
+            charged[object_levels[&uuid]] += name.len() + 1;
+        }
+
+        let order = self.variant_order();
+        let mut sizes = vec![];
+        for level in 0..never + 1 {
+            let kept: Vec<usize> = order.iter().cloned()
+                .filter(|&idx| object_levels[self.variant_uuids[idx].uuid()] >= level)
+                .collect();
+            let mut pos = mem::size_of::<MemDbHeader>();
+            let mut leaf_counts = vec![];
+            for &idx in kept.iter() {
+                let mut left: usize = item_counts[idx][level..].iter().sum();
+                leaf_counts.push((left + LEAF_CAPACITY - 1) / LEAF_CAPACITY);
+                while left > 0 {
+                    let len = left.min(LEAF_CAPACITY) * mem::size_of::<IndexItem>();
+                    pos = padded_for_block(pos, len) + len;
+                    left -= left.min(LEAF_CAPACITY);
+                }
+            }
+            for (&idx, &leaves) in kept.iter().zip(leaf_counts.iter()) {
+                if !self.shared_variants.contains(&idx) {
+                    let len = leaves * mem::size_of::<LeafRef>();
+                    pos = padded_for_block(pos, len) + len;
+                }
+            }
+            pos += kept.len() * (mem::size_of::<StoredSlice>() + mem::size_of::<IndexedUuid>());
+
+            let mut conversion_info = self.conversion_info();
+            conversion_info.options.trimmed = levels[..level].iter().map(|x| x.name()).collect();
+            pos += serde_json::to_vec(&conversion_info)
+                .chain_err(|| "Could not serialize the conversion info")?.len();
+            pos += charged[level..].iter().sum::<usize>();
+            sizes.push(pos as u64);
+        }
+        Ok(sizes)
+    }
+
+    /// Returns how many trim levels the memdb needs to fit into `max_size`.
+    fn find_trim_level(&self, levels: &[Trim], max_size: u64) -> Result<usize> {
+        let sizes = self.estimate_sizes(levels)?;
+        for (level, &size) in sizes.iter().enumerate() {
+            if size <= max_size {
+                return Ok(level);
+            }
+            if level < levels.len() {
+                self.options.reporter.detail(&format!("{} exceeds {}, dropping {}",
+                                                      file_size_format(size as usize),
+                                                      file_size_format(max_size as usize),
+                                                      levels[level].name()));
+            }
+        }
+        Err(ErrorKind::MemDbTooLarge(sizes[levels.len()], max_size).into())
+    }
+
+    /// Drops what the trim levels drop from the objects written so far.
+    ///
+    /// Symbols and object names that nothing refers to anymore go as well.
+    fn trim(&mut self, levels: &[Trim]) {
+        // an object is dropped with all its copies, even those that did not
+        // carry the symbols
+        let mut cold = HashSet::new();
+        for trim in levels.iter() {
+            if let Trim::ColdObjects(_, ref patterns) = *trim {
+                cold.extend(self.object_uuid_mapping.iter()
+                    .filter(|&&(ref name, _)| patterns.is_match(object_src(name)))
+                    .map(|&(_, uuid)| uuid));
+            }
+        }
+        let dropped: Vec<_> = levels.iter().filter_map(|trim| match *trim {
+            Trim::Source(source) => Some(source),
+            _ => None,
+        }).collect();
+        self.object_uuid_mapping.retain(|&(_, uuid)| !cold.contains(&uuid));
+
+        let symbols = mem::replace(&mut self.symbols, vec![]);
+        let object_names = mem::replace(&mut self.object_names, vec![]);
+        let variants = mem::replace(&mut self.variants, vec![]);
+        let variant_uuids = mem::replace(&mut self.variant_uuids, vec![]);
+        self.symbols_map.clear();
+        self.object_names_map.clear();
+        self.symbol_count = 0;
+        for (items, indexed_uuid) in variants.into_iter().zip(variant_uuids.into_iter()) {
+            let uuid = *indexed_uuid.uuid();
+            if cold.contains(&uuid) {
+                continue;
+            }
+            let mut index = vec![];
+            for item in items.iter() {
+                if item.is_recovered() && item.source().map_or(false, |x| dropped.contains(&x)) {
+                    continue;
+                }
+                let src_id = self.add_object_name(&object_names[item.src_id() as usize]);
+                let sym_id = item.sym_id().map(|x| self.add_symbol(&symbols[x as usize]));
+                index.push(item.remap(src_id, sym_id));
+            }
+            self.symbol_count += index.len();
+            self.variant_uuids.push(IndexedUuid::new(&uuid, self.variants.len()));
+            self.variants.push(index);
+        }
+        self.trimmed = levels.iter().map(|x| x.name()).collect();
+    }
+
+    fn write_object_variant(&mut self, var: &VariantRecord) -> Result<()> {
         self.object_uuid_mapping.push((
-            format!("{}:{}", src, var.arch()),
-            *uuid
+            format!("{}:{}", var.src, var.arch),
+            var.uuid
         ));
 
-        if self.variant_uuids_seen.contains(uuid) {
-            return Ok(false);
+        let symbols = match var.symbols {
+            Some(ref symbols) if !self.variant_uuids_seen.contains(&var.uuid) => symbols,
+            _ => { return Ok(()); }
+        };
+        self.variant_uuids_seen.insert(var.uuid);
+
+        // for thin SDKs the symbols go into a memdb of their own which
+        // other SDKs with the same image share.
+        if let Some(ref store) = self.options.object_store {
+            let object = write_shared_object(store, &self.info, var)?;
+            self.object_manifest.add(object);
+            self.shared_variants.insert(self.variants.len());
+            self.variant_uuids.push(IndexedUuid::new(&var.uuid, self.variants.len()));
+            self.variants.push(vec![]);
+            return Ok(());
         }
-        self.variant_uuids_seen.insert(*uuid);
 
-        let mut symbols = obj.symbols(var.arch())?;
-        let src_id = self.add_object_name(src);
+        let src_id = self.add_object_name(&var.src);
 
         // build symbol index
         let mut index = vec![];
-        for (addr, sym) in symbols.iter() {
+        for &(addr, ref sym) in symbols.iter() {
+            let sym_id = self.add_symbol(sym);
+            index.push(IndexItem::new(addr, src_id, Some(sym_id)));
+            self.symbol_count += 1;
+        }
+        for &(addr, ref sym, source) in var.recovered.iter() {
             let sym_id = self.add_symbol(sym);
-            index.push(IndexItem::new(addr - var.vmaddr(), src_id, Some(sym_id)));
+            index.push(IndexItem::new_recovered(addr, src_id, sym_id, source));
             self.symbol_count += 1;
         }
 
         // write an end marker if we know the image size
-        if var.vmsize() > 0 {
-            index.push(IndexItem::new(var.vmsize(), src_id, None));
+        if var.vmsize > 0 {
+            index.push(IndexItem::new(var.vmsize, src_id, None));
             self.symbol_count += 1;
         }
 
         index.sort_by_key(|item| item.addr());
 
         // register variant and uuid
-        self.variant_uuids.push(IndexedUuid::new(uuid, self.variants.len()));
+        self.variant_uuids.push(IndexedUuid::new(&var.uuid, self.variants.len()));
         self.variants.push(index);
+        Ok(())
+    }
+
+    fn conversion_info(&self) -> ConversionInfo {
+        let mut objects: Vec<_> = self.object_uuid_mapping.iter()
+            .map(|&(ref name, uuid)| format!("{}\t{}\n", name, uuid))
+            .collect();
+        objects.sort();
+        ConversionInfo {
+            converter_version: VERSION.to_string(),
+            converter_commit: GIT_COMMIT.map(|x| x.to_string()),
+            format_version: MEMDB_VERSION,
+            sdk_fingerprint: format!("{:x}", md5::compute(objects.concat().as_bytes())),
+            options: ConversionOptions {
+                compress: self.options.compress,
+                thin: self.options.object_store.is_some(),
+                symbol_sources: self.options.symbol_sources.clone(),
+                profile_guided: !self.options.hot_objects.is_empty(),
+                trimmed: self.trimmed.clone(),
+            },
+            product_type: self.info.product_type().map(|x| x.to_string()),
+            skipped: self.skipped.clone(),
+        }
+    }
+
+    /// Returns the variants in the order their index is laid out in.
+    ///
+    /// The hot objects of the options come first so that the pages most
+    /// lookups touch are next to each other, the others follow in the order
+    /// they were found in.
+    fn variant_order(&self) -> Vec<usize> {
+        let ranks: HashMap<Uuid, usize> = self.options.hot_objects.iter().enumerate()
+            .map(|(rank, uuid)| (*uuid, rank)).collect();
+        let mut rv: Vec<usize> = (0..self.variants.len()).collect();
+        rv.sort_by_key(|&idx| ranks.get(self.variant_uuids[idx].uuid()).cloned()
+                       .unwrap_or(!0));
+        rv
+    }
+
+    /// Returns the symbols in the order their strings are written in.  The
+    /// symbols of hot objects come first, in the order of the variants.
+    fn symbol_order(&self, variants: &[usize]) -> Vec<usize> {
+        let hot: HashSet<&Uuid> = self.options.hot_objects.iter().collect();
+        let mut seen = vec![false; self.symbols.len()];
+        let mut rv = Vec::with_capacity(self.symbols.len());
+        for &idx in variants.iter() {
+            if !hot.contains(self.variant_uuids[idx].uuid()) {
+                break;
+            }
+            for sym_id in self.variants[idx].iter().filter_map(|x| x.sym_id()) {
+                if !seen[sym_id as usize] {
+                    seen[sym_id as usize] = true;
+                    rv.push(sym_id as usize);
+                }
+            }
+        }
+        rv.extend((0..self.symbols.len()).filter(|&sym_id| !seen[sym_id]));
+        rv
+    }
 
-        Ok(true)
+    /// Writes strings in the given order and returns their slices in the
+    /// order of the strings.
+    fn make_ordered_string_slices(&self, strings: &[String], order: &[usize])
+        -> Result<Vec<StoredSlice>>
+    {
+        let mut slices: Vec<Option<StoredSlice>> = strings.iter().map(|_| None).collect();
+        let pb = self.options.reporter.progress_bar(strings.len() as u64);
+        pb.set_style(ProgressStyle::default_bar()
+            .template("{wide_msg:.dim}\n{wide_bar} {pos:>5}/{len}"));
+        for &idx in order.iter() {
+            let offset = self.tell()?;
+            pb.set_message(&strings[idx]);
+            let len = self.write_bytes(strings[idx].as_bytes())?;
+            slices[idx] = Some(StoredSlice::new(offset, len, false));
+            pb.inc(1);
+        }
+        pb.finish_and_clear();
+        Ok(slices.into_iter().map(|x| x.unwrap()).collect())
     }
 
     fn make_string_slices(&self, strings: &[String], _try_compress: bool) -> Result<Vec<StoredSlice>> {
         let mut slices = vec![];
-        let pb = ProgressBar::new(strings.len() as u64);
+        let pb = self.options.reporter.progress_bar(strings.len() as u64);
         pb.set_style(ProgressStyle::default_bar()
             .template("{wide_msg:.dim}\n{wide_bar} {pos:>5}/{len}"));
         for string in strings.iter() {
@@ -205,7 +771,7 @@ impl<W: Write + Seek> MemDbBuilder<W> {
 
     fn write_slices(&self, slices: &[StoredSlice], start: &mut u32, len: &mut u32) -> Result<()> {
         *start = self.tell()? as u32;
-        let pb = ProgressBar::new(slices.len() as u64);
+        let pb = self.options.reporter.progress_bar(slices.len() as u64);
         for item in slices.iter() {
             self.write(item)?;
             pb.inc(1);
@@ -216,27 +782,58 @@ impl<W: Write + Seek> MemDbBuilder<W> {
     }
 
     pub fn flush(&mut self) -> Result<()> {
-        println!("      Found {} symbols", style(self.symbol_count).cyan());
-        let mut header = MemDbHeader { ..Default::default() };
-        header.version = 2;
+        self.options.reporter.detail(&format!("Found {} symbols", self.symbol_count));
+        // the header is zeroed so that its padding does not end up in the
+        // file as garbage and the same objects always give the same memdb.
+        let mut header: MemDbHeader = unsafe { mem::zeroed() };
+        header.version = MEMDB_VERSION;
         header.sdk_info.set_from_sdk_info(&self.info);
+        header.device_family = self.info.device_family().map_or(0, |x| x.to_u32());
 
-        println!("{} Writing metadata", format_step(2, &self.options));
-        // start by writing out the index of the variants and record the slices.
-        let mut slices = vec![];
-        let pb = ProgressBar::new(self.variants.iter().map(|x| x.iter().count()).sum::<usize>() as u64);
+        report_step(2, &self.options, "Writing metadata");
+        // start by writing out the address index of the variants.  The index
+        // is split into leaves that do not straddle page boundaries and we
+        // record a directory of the leaves for each variant.
+        let order = self.variant_order();
+        let mut directories = vec![];
+        let pb = self.options.reporter.progress_bar(
+            self.variants.iter().map(|x| x.iter().count()).sum::<usize>() as u64);
         pb.set_style(ProgressStyle::default_bar()
             .template("{wide_msg:.dim}\n{wide_bar} {pos:>5}/{len}"));
-        for variant in self.variants.iter() {
-            let offset = self.tell()?;
-            for index_item in variant {
-                pb.set_message(&self.object_uuid_mapping[index_item.src_id() as usize].0);
-                self.write(index_item)?;
-                pb.inc(1);
+        for &idx in order.iter() {
+            let variant = &self.variants[idx];
+            let mut directory = vec![];
+            for leaf in variant.chunks(LEAF_CAPACITY) {
+                self.pad_for_block(leaf.len() * mem::size_of::<IndexItem>())?;
+                let offset = self.tell()?;
+                for index_item in leaf {
+                    pb.set_message(&self.object_uuid_mapping[index_item.src_id() as usize].0);
+                    self.write(index_item)?;
+                    pb.inc(1);
+                }
+                directory.push(LeafRef::new(leaf[0].addr(), leaf.len(), offset));
             }
-            slices.push(StoredSlice::new(offset, self.tell()? - offset, false));
+            directories.push((idx, directory));
         }
         pb.finish_and_clear();
+
+        // the directories are written in one block so that small ones share
+        // pages with each other.
+        let mut slices: Vec<Option<StoredSlice>> = order.iter().map(|_| None).collect();
+        for &(idx, ref directory) in directories.iter() {
+            if self.shared_variants.contains(&idx) {
+                slices[idx] = Some(StoredSlice::new_external());
+                continue;
+            }
+            let len = directory.len() * mem::size_of::<LeafRef>();
+            self.pad_for_block(len)?;
+            let offset = self.tell()?;
+            for leaf_ref in directory.iter() {
+                self.write(leaf_ref)?;
+            }
+            slices[idx] = Some(StoredSlice::new(offset, len, false));
+        }
+        let slices: Vec<_> = slices.into_iter().map(|x| x.unwrap()).collect();
         self.write_slices(&slices[..], &mut header.variants_start, &mut header.variants_count)?;
 
         // next write out the UUIDs.  Since these are fixed length we do not
@@ -261,14 +858,21 @@ impl<W: Write + Seek> MemDbBuilder<W> {
         let slices = self.make_string_slices(&self.object_names[..], true)?;
         self.write_slices(&slices[..], &mut header.object_names_start, &mut header.object_names_count)?;
 
-        println!("{} Writing symbol strings", format_step(3, &self.options));
+        // record how the memdb was converted
+        let conversion_info = serde_json::to_vec(&self.conversion_info())
+            .chain_err(|| "Could not serialize the conversion info")?;
+        header.conversion_info_start = self.tell()? as u32;
+        header.conversion_info_len = self.write_bytes(&conversion_info)? as u32;
+
+        report_step(3, &self.options, "Writing symbol strings");
 
         // now write out all the symbols
-        let slices = self.make_string_slices(&self.symbols[..], true)?;
-        println!("{} Writing symbol index", format_step(4, &self.options));
+        let symbol_order = self.symbol_order(&order);
+        let slices = self.make_ordered_string_slices(&self.symbols[..], &symbol_order)?;
+        report_step(4, &self.options, "Writing symbol index");
         self.write_slices(&slices[..], &mut header.symbols_start, &mut header.symbols_count)?;
 
-        println!("{} Writing headers", format_step(5, &self.options));
+        report_step(5, &self.options, "Writing headers");
 
         let file_size = self.tell()?;
 
@@ -276,13 +880,19 @@ impl<W: Write + Seek> MemDbBuilder<W> {
         self.seek(0)?;
         self.write(&header)?;
 
-        println!("      Indexed {} variants",
-                 style(self.variant_uuids.len()).cyan());
+        self.options.reporter.detail(&format!("Indexed {} variants",
+                                              self.variant_uuids.len()));
+
+        // thin SDKs list their objects in a manifest in the object store
+        if let Some(ref store) = self.options.object_store {
+            write_file_atomic(&get_object_manifest_path(store, &self.info), "tempjson",
+                              &self.object_manifest.to_vec()?)?;
+        }
 
         // compress if necessary
         if self.options.compress {
-            println!("{} Compressing", format_step(6, &self.options));
-            let pb = ProgressBar::new(file_size as u64);
+            report_step(6, &self.options, "Compressing");
+            let pb = self.options.reporter.progress_bar(file_size as u64);
             pb.set_style(ProgressStyle::default_bar()
                 .template("{wide_bar} {bytes}/{total_bytes}"));
             self.seek(0)?;
@@ -291,39 +901,517 @@ impl<W: Write + Seek> MemDbBuilder<W> {
             {
                 let mut zwriter = XzEncoder::new(&mut *writer, 9);
                 copy_with_progress(&pb, &mut *reader, &mut zwriter)?;
+                zwriter.finish()?;
             }
+            writer.flush()?;
             let compressed_file_size = writer.seek(SeekFrom::Current(0))? as usize;
             let pct = (compressed_file_size * 100) / file_size;
             pb.finish_and_clear();
-            println!(
-                "      Compressed from {} to {} ({}% of original size)",
+            self.options.reporter.detail(&format!(
+                "Compressed from {} to {} ({}% of original size)",
                 file_size_format(file_size),
                 file_size_format(compressed_file_size),
-                pct);
+                pct));
+        } else if let Some(ref tempfile) = self.tempfile {
+            self.seek(0)?;
+            let mut writer = self.writer.borrow_mut();
+            io::copy(&mut *tempfile.borrow_mut(), &mut *writer)?;
+            writer.flush()?;
         }
 
         Ok(())
     }
 }
 
+/// Lets a memdb be written to a stream that cannot seek, like stdout.
+///
+/// The builder only asks for the position of the writer once it is done.
+struct StreamWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let rv = self.inner.write(buf)?;
+        self.written += rv as u64;
+        Ok(rv)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for StreamWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.written),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "cannot seek in a stream")),
+        }
+    }
+}
+
+/// Writes the memdb of a shared object unless the store already has it.
+///
+/// Objects are identified by their UUID so an object that is already in
+/// the store has the same symbols.  They only record the name of the SDK
+/// so that every SDK writes the same memdb for the same object.  The memdb
+/// is written to a temporary file first so that a store never holds an
+/// incomplete object.
+fn write_shared_object(store: &Path, info: &SdkInfo, var: &VariantRecord)
+    -> Result<ManifestObject>
+{
+    let path = get_shared_object_path(store, &var.uuid);
+    if !path.is_file() {
+        fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = tempfile::NamedTempFile::new_in(store)?;
+        {
+            let info = SdkInfo::new(info.name(), 0, 0, 0, None);
+            let mut builder = MemDbBuilder::new(&*tmp, &info, DumpOptions::default())?;
+            builder.write_object_variant(var)?;
+            builder.flush()?;
+        }
+        tmp.persist(&path).map_err(|err| err.error)?;
+    }
+    ManifestObject::from_path(&var.uuid, &path)
+}
+
+/// Returns where a block of `len` bytes written at `pos` starts so that it
+/// does not straddle a page boundary unless it is larger than a page.
+fn padded_for_block(pos: usize, len: usize) -> usize {
+    let in_page = pos % PAGE_SIZE;
+    if in_page != 0 && in_page + len > PAGE_SIZE {
+        pos + PAGE_SIZE - in_page
+    } else {
+        pos
+    }
+}
+
+/// The path of an object from its name and architecture.
+fn object_src(tagged_name: &str) -> &str {
+    tagged_name.rfind(':').map_or(tagged_name, |idx| &tagged_name[..idx])
+}
+
 /// Dumps objects into a writer
+///
+/// With a journal in the options objects a previous run recorded in it
+/// are not read again.  The journal is removed once the memdb is written.
+///
+/// With a `max_size` in the options a memdb that would be larger is
+/// trimmed.  First the recovered symbols are dropped by source (function
+/// starts, Swift, Objective-C), then the `cold_objects` pattern by
+/// pattern, until the uncompressed memdb fits.  The objects are read once
+/// and trimmed in the builder.  What was dropped is recorded in
+/// the conversion info.  The journal always records the objects
+/// untrimmed.
 pub fn dump_memdb<W: Write + Seek>(writer: W, info: &SdkInfo,
                                    opts: DumpOptions, objects: Objects)
     -> Result<()>
 {
-    println!("{} Processing {} files", format_step(1, &opts),
-             style(objects.file_count()).cyan());
-    let mut builder = MemDbBuilder::new(writer, info, opts)?;
-    let pb = ProgressBar::new(objects.file_count() as u64);
+    dump(writer, info, opts, objects, false)
+}
+
+/// Dumps objects into a writer that cannot seek, like stdout.
+///
+/// The memdb is assembled in a temporary file in the scratch folder first,
+/// as its header is only known at the end, and then copied (or
+/// compressed) into the writer.
+pub fn dump_memdb_to_stream<W: Write>(writer: W, info: &SdkInfo,
+                                      opts: DumpOptions, objects: Objects)
+    -> Result<()>
+{
+    dump(StreamWriter { inner: writer, written: 0 }, info, opts, objects, true)
+}
+
+fn dump<W: Write + Seek>(writer: W, info: &SdkInfo, opts: DumpOptions,
+                         mut objects: Objects, buffered: bool)
+    -> Result<()>
+{
+    if opts.max_size.is_some() && opts.object_store.is_some() {
+        return Err("Thin SDKs cannot be trimmed to a size".into());
+    }
+    let levels = Trim::levels(&opts)?;
+    let (mut journal, records) = match opts.journal {
+        Some(ref path) => {
+            let (journal, records) = Journal::open(path, info)?;
+            (Some(journal), records)
+        }
+        None => (None, vec![]),
+    };
+    report_step(1, &opts, &format!("Processing {} files", objects.file_count()));
+    if !records.is_empty() {
+        opts.reporter.detail(&format!("Resuming after {} objects from the journal",
+                                      records.len()));
+    }
+    let pb = opts.reporter.progress_bar(objects.file_count() as u64);
+    let mut builder = MemDbBuilder::create(writer, info, opts, buffered)?;
     pb.set_style(ProgressStyle::default_bar()
         .template("{wide_msg:.dim}\n{wide_bar} {pos:>5}/{len}"));
-    for obj_res in objects {
-        let (offset, filename, obj) = obj_res?;
+
+    // replaying the journal in order leaves the builder as it was
+    let mut done = HashMap::new();
+    for record in records.iter() {
+        builder.write_object(record)?;
+        *done.entry(record.filename.clone()).or_insert(0) += 1;
+    }
+    let sandbox = builder.options.sandbox.clone();
+    while let Some(file_res) = objects.next_file() {
+        let (offset, filename, file) = file_res?;
         pb.set_message(&filename);
-        builder.write_object(&obj, Some(&filename))?;
+        let is_done = match done.get_mut(filename.as_str()) {
+            Some(ref mut count) if **count > 0 => {
+                **count -= 1;
+                true
+            }
+            _ => false,
+        };
+        let record = if is_done {
+            None
+        } else if let Some(ref sandbox) = sandbox {
+            // one bad object should not cost the whole SDK
+            match builder.record_object_sandboxed(sandbox, file.into_bytes()?, &filename) {
+                Ok(record) => record,
+                Err(err) => {
+                    let reason = match *err.kind() {
+                        ErrorKind::SandboxFailure(_, ref reason) => Some(reason.clone()),
+                        _ => None,
+                    };
+                    let reason = match reason {
+                        Some(reason) => reason,
+                        None => return Err(err),
+                    };
+                    builder.options.reporter.detail(&format!(
+                        "Skipping {}: {}", filename, reason));
+                    builder.skipped.push(SkippedObject { filename: filename.clone(), reason });
+                    None
+                }
+            }
+        } else {
+            match file.parse() {
+                Ok(obj) => Some(builder.record_object(&obj, &filename)?),
+                Err(err) => {
+                    if let ErrorKind::MachO(MachError::LoadError(_)) = *err.kind() {
+                        None
+                    } else {
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        if let Some(record) = record {
+            if let Some(ref mut journal) = journal {
+                journal.append(&record)?;
+            }
+            builder.write_object(&record)?;
+        }
         pb.inc(offset as u64);
     }
     pb.finish_and_clear();
+    if let Some(max_size) = builder.options.max_size {
+        let level = builder.find_trim_level(&levels, max_size)?;
+        if level > 0 {
+            builder.trim(&levels[..level]);
+        }
+    }
     builder.flush()?;
+    if let Some(journal) = journal {
+        journal.finish()?;
+    }
     Ok(())
 }
+
+/// Converts the object on stdin for a `Sandbox`.
+///
+/// This runs in the child process (`convert-object`) and writes a
+/// `SandboxMessage` per line to stdout.  With `seccomp` the process is
+/// locked down after the object was read.
+pub fn convert_sandboxed_object(filename: &str, symbol_sources: &[SymbolSource],
+                                seccomp: bool) -> Result<()> {
+    let mut buf = vec![];
+    io::stdin().read_to_end(&mut buf)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if seccomp {
+        enter_seccomp()?;
+    }
+
+    let mut send = |msg: &SandboxMessage| -> Result<()> {
+        let mut line = serde_json::to_vec(msg).chain_err(|| "Could not serialize object")?;
+        line.push(b'\n');
+        stdout.write_all(&line)?;
+        Ok(())
+    };
+    let obj = match Object::from_vec(buf) {
+        Ok(obj) => obj,
+        Err(err) => {
+            return send(&match *err.kind() {
+                ErrorKind::MachO(MachError::LoadError(_)) => SandboxMessage::NotAnObject,
+                _ => SandboxMessage::Failed(err.to_string()),
+            });
+        }
+    };
+    for variant in obj.variants() {
+        if let Some(uuid) = variant.uuid() {
+            match read_variant(&obj, variant, uuid, filename, symbol_sources, false) {
+                Ok(record) => send(&SandboxMessage::Variant(record))?,
+                Err(err) => return send(&SandboxMessage::Failed(err.to_string())),
+            }
+        }
+    }
+    send(&SandboxMessage::Done)
+}
+
+#[test]
+fn test_paged_address_index() {
+    use std::io::Cursor;
+    use super::read::MemDb;
+
+    let info = SdkInfo::new("iOS", 10, 2, 0, Some("14C92"));
+    let uuid: Uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    let mut buf = Cursor::new(vec![]);
+    {
+        let mut builder = MemDbBuilder::new(&mut buf, &info, Default::default()).unwrap();
+        let src_id = builder.add_object_name("libfoo.dylib");
+        let mut index = vec![];
+        for i in 0..1000u64 {
+            let sym_id = builder.add_symbol(&format!("sym{}", i));
+            index.push(IndexItem::new(i * 16, src_id, Some(sym_id)));
+        }
+        builder.object_uuid_mapping.push(("libfoo.dylib:arm64".into(), uuid));
+        builder.variant_uuids.push(IndexedUuid::new(&uuid, 0));
+        builder.variants.push(index);
+        builder.flush().unwrap();
+    }
+
+    let memdb = MemDb::from_vec(buf.into_inner()).unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 4).unwrap().symbol(), "sym0");
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 500 * 16 + 4).unwrap().symbol(), "sym500");
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 999 * 16 + 4).unwrap().symbol(), "sym999");
+    assert!(!memdb.lookup_by_uuid(&uuid, 500 * 16 + 4).unwrap().is_last_in_object());
+    assert!(!memdb.lookup_by_uuid(&uuid, 998 * 16 + 4).unwrap().is_last_in_object());
+    assert!(memdb.lookup_by_uuid(&uuid, 999 * 16 + 4).unwrap().is_last_in_object());
+    assert_eq!(memdb.iter_symbols(&uuid).unwrap().count(), 1000);
+}
+
+#[test]
+fn test_resume_from_journal() {
+    use std::io::{Cursor, Read};
+    use tempdir::TempDir;
+    use super::read::MemDb;
+    use super::super::sdk::Sdk;
+
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let sdk_path = tmp.path().join("iOS DeviceSupport/10.2 (14C92)");
+    let lib_path = sdk_path.join("Symbols/usr/lib");
+    fs::create_dir_all(&lib_path).unwrap();
+    let mut data = vec![];
+    File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/res/libKoreanConverter.dylib"))
+        .unwrap().read_to_end(&mut data).unwrap();
+    File::create(lib_path.join("libKoreanConverter.dylib")).unwrap().write_all(&data).unwrap();
+    let sdk = Sdk::new(&sdk_path).unwrap();
+
+    // a previous run recorded the object with a made up variant and was
+    // interrupted while writing the next line
+    let uuid: Uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1c".parse().unwrap();
+    let journal_path = tmp.path().join("iOS_10.2.0_14C92.memdb.journal");
+    {
+        let (mut journal, records) = Journal::open(&journal_path, sdk.info()).unwrap();
+        assert!(records.is_empty());
+        journal.append(&ObjectRecord {
+            filename: "/usr/lib/libKoreanConverter.dylib".into(),
+            variants: vec![VariantRecord {
+                src: "/usr/lib/libKoreanConverter.dylib".into(),
+                arch: "arm64".into(),
+                uuid,
+                vmsize: 0x100,
+                symbols: Some(vec![(0x10, "journaled".into())]),
+                recovered: vec![(0x20, "-[Journal recovered]".into(), SymbolSource::Objc)],
+            }],
+        }).unwrap();
+        journal.file.write_all(b"{\"filename\":").unwrap();
+    }
+
+    let mut buf = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut buf, DumpOptions {
+        journal: Some(journal_path.clone()),
+        ..Default::default()
+    }).unwrap();
+    assert!(!journal_path.exists());
+    let memdb = MemDb::from_vec(buf.into_inner()).unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x14).unwrap().symbol(), "journaled");
+    assert!(!memdb.lookup_by_uuid(&uuid, 0x14).unwrap().is_recovered());
+    let sym = memdb.lookup_by_uuid(&uuid, 0x24).unwrap();
+    assert_eq!(sym.symbol(), "-[Journal recovered]");
+    assert!(sym.is_recovered());
+    assert_eq!(sym.source(), Some(SymbolSource::Objc));
+    // the object itself was not read again
+    let real_uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    assert!(memdb.lookup_by_uuid(&real_uuid, 0x5b10).is_none());
+}
+
+#[test]
+fn test_thin_sdk() {
+    use std::io::{Cursor, Read};
+    use tempdir::TempDir;
+    use super::read::{MemDb, MemDbMetadata};
+    use super::super::sdk::Sdk;
+
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let sdk_path = tmp.path().join("iOS DeviceSupport/10.2 (14C92)");
+    let lib_path = sdk_path.join("Symbols/usr/lib");
+    fs::create_dir_all(&lib_path).unwrap();
+    let mut data = vec![];
+    File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/res/libKoreanConverter.dylib"))
+        .unwrap().read_to_end(&mut data).unwrap();
+    File::create(lib_path.join("libKoreanConverter.dylib")).unwrap().write_all(&data).unwrap();
+    let sdk = Sdk::new(&sdk_path).unwrap();
+
+    let store = tmp.path().join("objects");
+    let mut buf = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut buf, DumpOptions {
+        object_store: Some(store.clone()),
+        ..Default::default()
+    }).unwrap();
+
+    let uuid: Uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    let metadata = MemDbMetadata::read(Cursor::new(buf.get_ref())).unwrap();
+    assert!(metadata.is_thin());
+    assert_eq!(metadata.info().unwrap().sdk_id(), sdk.info().sdk_id());
+    let memdb = MemDb::from_vec(buf.into_inner()).unwrap();
+    assert!(memdb.is_thin());
+    assert!(memdb.is_shared_object(&uuid));
+    let name = "/System/Library/CoreServices/Encodings/libKoreanConverter.dylib";
+    assert_eq!(memdb.find_uuid(name, "arm64").unwrap(), Some(&uuid));
+    assert!(memdb.lookup_by_uuid(&uuid, 0x5b10).is_none());
+
+    let object = MemDb::from_path(get_shared_object_path(&store, &uuid)).unwrap();
+    let manifest = ObjectManifest::load(&get_object_manifest_path(&store, sdk.info()))
+        .unwrap().unwrap();
+    assert!(manifest.objects().iter().any(|x| x.uuid() == &uuid));
+    assert!(!object.is_thin());
+    assert_eq!(object.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+               "___CFFromMacKoreanLen");
+}
+
+#[test]
+fn test_symbol_sources() {
+    use std::io::{Cursor, Read};
+    use tempdir::TempDir;
+    use super::read::MemDb;
+    use super::super::sdk::Sdk;
+
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let sdk_path = tmp.path().join("iOS DeviceSupport/10.2 (14C92)");
+    let lib_path = sdk_path.join("Symbols/usr/lib");
+    fs::create_dir_all(&lib_path).unwrap();
+    let mut data = vec![];
+    File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/res/libKoreanConverter.dylib"))
+        .unwrap().read_to_end(&mut data).unwrap();
+    File::create(lib_path.join("libKoreanConverter.dylib")).unwrap().write_all(&data).unwrap();
+    let sdk = Sdk::new(&sdk_path).unwrap();
+    let uuid: Uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+
+    let dump = |symbol_sources| {
+        let mut buf = Cursor::new(vec![]);
+        sdk.dump_memdb(&mut buf, DumpOptions {
+            symbol_sources,
+            ..Default::default()
+        }).unwrap();
+        MemDb::from_vec(buf.into_inner()).unwrap()
+    };
+
+    // the symbol table names the function so its function start is dropped
+    let memdb = dump(SymbolSource::all());
+    let sym = memdb.lookup_by_uuid(&uuid, 0x5b10).unwrap();
+    assert_eq!(sym.symbol(), "___CFFromMacKoreanLen");
+    assert_eq!(sym.source(), Some(SymbolSource::SymbolTable));
+
+    let memdb = dump(vec![SymbolSource::FunctionStarts, SymbolSource::SymbolTable]);
+    let sym = memdb.lookup_by_uuid(&uuid, 0x5b10).unwrap();
+    assert_eq!(sym.symbol(), "sub_195942b10");
+    assert!(sym.is_recovered());
+    assert_eq!(sym.source(), Some(SymbolSource::FunctionStarts));
+}
+
+#[test]
+fn test_hot_objects_first() {
+    use std::io::Cursor;
+    use super::read::MemDb;
+
+    let info = SdkInfo::new("iOS", 10, 2, 0, Some("14C92"));
+    let cold: Uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    let hot: Uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1c".parse().unwrap();
+    let mut buf = Cursor::new(vec![]);
+    {
+        let mut builder = MemDbBuilder::new(&mut buf, &info, DumpOptions {
+            hot_objects: vec![hot],
+            ..Default::default()
+        }).unwrap();
+        for (idx, &(name, uuid)) in [("cold", cold), ("hot", hot)].iter().enumerate() {
+            let src_id = builder.add_object_name(&format!("lib{}.dylib", name));
+            let sym_id = builder.add_symbol(&format!("{}_function", name));
+            builder.object_uuid_mapping.push((format!("lib{}.dylib:arm64", name), uuid));
+            builder.variant_uuids.push(IndexedUuid::new(&uuid, idx));
+            builder.variants.push(vec![IndexItem::new(0x10, src_id, Some(sym_id))]);
+        }
+        builder.flush().unwrap();
+    }
+
+    let data = buf.into_inner();
+    let find = |needle: &[u8]| data.windows(needle.len()).position(|x| x == needle).unwrap();
+    assert!(find(b"hot_function") < find(b"cold_function"));
+    let memdb = MemDb::from_vec(data.clone()).unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&hot, 0x14).unwrap().symbol(), "hot_function");
+    assert_eq!(memdb.lookup_by_uuid(&cold, 0x14).unwrap().symbol(), "cold_function");
+    assert!(memdb.conversion_info().unwrap().unwrap().options.profile_guided);
+}
+
+#[test]
+fn test_trim_cold_objects() {
+    use std::io::Cursor;
+
+    let info = SdkInfo::new("iOS", 10, 2, 0, Some("14C92"));
+    let record = |name: &str, uuid: &str| ObjectRecord {
+        filename: name.to_string(),
+        variants: vec![VariantRecord {
+            src: name.to_string(),
+            arch: "arm64".into(),
+            uuid: uuid.parse().unwrap(),
+            vmsize: 0x10000,
+            symbols: Some((0..1000u64).map(|i| (i * 16, format!("{}_sym{}", name, i)))
+                .collect()),
+            recovered: vec![(0x8000, "recovered".into(), SymbolSource::FunctionStarts)],
+        }],
+    };
+    let records = vec![
+        record("/usr/lib/libhot.dylib", "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b"),
+        record("/usr/lib/libcold.dylib", "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1c"),
+    ];
+    let opts = DumpOptions {
+        cold_objects: vec!["*/libcold.dylib".into()],
+        ..Default::default()
+    };
+    let levels = Trim::levels(&opts).unwrap();
+    assert_eq!(levels.iter().map(|x| x.name()).collect::<Vec<_>>(),
+               vec!["function_starts", "swift", "objc", "cold:*/libcold.dylib"]);
+
+    let mut builder = MemDbBuilder::new(Cursor::new(vec![]), &info, opts).unwrap();
+    for record in records.iter() {
+        builder.write_object(record).unwrap();
+    }
+    let sizes = builder.estimate_sizes(&levels).unwrap();
+    assert!(sizes.windows(2).all(|x| x[0] >= x[1]));
+    assert_eq!(builder.find_trim_level(&levels, sizes[0]).unwrap(), 0);
+    let level = builder.find_trim_level(&levels, sizes[3] - 1).unwrap();
+    assert_eq!(level, 4);
+    assert!(builder.find_trim_level(&levels, sizes[4] - 1).is_err());
+
+    builder.trim(&levels[..level]);
+    assert_eq!(builder.object_names, vec!["/usr/lib/libhot.dylib"]);
+    assert_eq!(builder.object_uuid_mapping.len(), 1);
+    assert!(builder.symbols.iter().all(|x| x.starts_with("/usr/lib/libhot.dylib_sym")));
+    assert_eq!(builder.symbol_count, 1001);
+    builder.flush().unwrap();
+    assert_eq!(builder.writer.borrow().get_ref().len() as u64, sizes[level]);
+}
@@ -0,0 +1,237 @@
+//! Following the stash of another symbolserver.
+//!
+//! Every server describes its stash at `/mirror/state` and hands out the
+//! memdbs at `/download`.  A server with `sync.primary` set syncs from
+//! such a primary instead of from S3, which keeps standby servers hot
+//! without each of them listing and downloading from the bucket.  The state
+//! is requested with the revision of the last one as etag so that polling
+//! a primary that did not change costs a single `304 Not Modified`, and
+//! the sync only downloads the SDKs whose etag changed.  Thin SDKs bring
+//! their object manifest and shared objects along over
+//! `/mirror/objects`.
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Mutex;
+
+use hyper::client::Client as HyperClient;
+use hyper::header::{Authorization, Bearer, EntityTag, Headers, IfNoneMatch};
+use hyper::status::StatusCode;
+use serde_json;
+use url::Url;
+use uuid::Uuid;
+
+use super::memdb::objects::ObjectManifest;
+use super::memdb::stash::{RemoteSdk, WithdrawnSdk};
+use super::s3::{new_hyper_client, UpstreamListing};
+use super::sdk::SdkInfo;
+use super::{ErrorKind, Result, ResultExt};
+
+/// The SDKs a server offers to the servers following it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MirrorState {
+    revision: u64,
+    sdks: Vec<MirrorSdk>,
+    withdrawn: Vec<WithdrawnSdk>,
+}
+
+/// An SDK offered over the mirror API
+///
+/// The filename of the SDK is the file to request from `/download`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MirrorSdk {
+    sdk: RemoteSdk,
+    /// The checksum of the decompressed memdb if known
+    checksum: Option<String>,
+}
+
+/// A server whose stash is followed
+pub struct PrimaryServer {
+    url: Url,
+    /// Sent as bearer token if the primary requires authentication
+    token: Option<String>,
+    client: HyperClient,
+    last_state: Mutex<Option<MirrorState>>,
+}
+
+impl MirrorState {
+    /// Creates the state of a stash at the given revision.
+    pub fn new(revision: u64, sdks: Vec<MirrorSdk>, withdrawn: Vec<WithdrawnSdk>)
+        -> MirrorState
+    {
+        MirrorState { revision, sdks, withdrawn }
+    }
+
+    /// The revision of the stash
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// The SDKs of the stash
+    pub fn sdks(&self) -> &[MirrorSdk] {
+        &self.sdks
+    }
+
+    /// Only keeps the SDKs and withdrawn SDKs whose id matches a predicate.
+    pub fn retain<F: Fn(&str) -> bool>(&mut self, f: F) {
+        self.sdks.retain(|x| f(&x.sdk.info().sdk_id()));
+        self.withdrawn.retain(|x| f(&x.info().sdk_id()));
+    }
+
+    /// Converts the state into a listing the stash can sync from.
+    pub fn listing(&self) -> UpstreamListing {
+        let mut checksums = HashMap::new();
+        for sdk in &self.sdks {
+            if let Some(ref checksum) = sdk.checksum {
+                checksums.insert(sdk.sdk.info().memdb_filename(), checksum.clone());
+            }
+        }
+        UpstreamListing {
+            sdks: self.sdks.iter().map(|x| x.sdk.clone()).collect(),
+            withdrawn: self.withdrawn.clone(),
+            checksums,
+        }
+    }
+}
+
+impl MirrorSdk {
+    /// Creates an SDK entry with an optional checksum.
+    pub fn new(sdk: RemoteSdk, checksum: Option<String>) -> MirrorSdk {
+        MirrorSdk { sdk, checksum }
+    }
+
+    /// The SDK
+    pub fn sdk(&self) -> &RemoteSdk {
+        &self.sdk
+    }
+
+    /// The checksum of the decompressed memdb if known
+    pub fn checksum(&self) -> Option<&str> {
+        self.checksum.as_ref().map(|x| x.as_str())
+    }
+}
+
+impl PrimaryServer {
+    /// Creates a client for the primary at the given base URL that
+    /// authenticates with `token` if given.
+    pub fn new(mut url: Url, token: Option<String>) -> Result<PrimaryServer> {
+        if !url.path().ends_with('/') {
+            let path = format!("{}/", url.path());
+            url.set_path(&path);
+        }
+        Ok(PrimaryServer {
+            url,
+            token,
+            client: new_hyper_client().chain_err(|| "Could not configure TLS layer")?,
+            last_state: Mutex::new(None),
+        })
+    }
+
+    /// The base URL of the primary
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        if let Some(ref token) = self.token {
+            headers.set(Authorization(Bearer { token: token.clone() }));
+        }
+        headers
+    }
+
+    fn unavailable<E: ToString>(&self, err: E) -> ErrorKind {
+        ErrorKind::PrimaryUnavailable(format!("{}: {}", self.url, err.to_string()))
+    }
+
+    /// Fetches the state of the primary.
+    ///
+    /// If the revision did not change since the last call the state of
+    /// that call is returned.
+    pub fn get_state(&self) -> Result<MirrorState> {
+        let url = self.url.join("mirror/state")?;
+        let mut headers = self.headers();
+        let last_state = self.last_state.lock().unwrap().clone();
+        if let Some(ref state) = last_state {
+            headers.set(IfNoneMatch::Items(vec![
+                EntityTag::new(false, state.revision.to_string())]));
+        }
+        let mut resp = self.client.get(url.as_str()).headers(headers).send()
+            .map_err(|err| self.unavailable(err))?;
+        match resp.status {
+            StatusCode::NotModified if last_state.is_some() => {
+                return Ok(last_state.unwrap());
+            }
+            StatusCode::Ok => {}
+            status => { return Err(self.unavailable(status).into()); }
+        }
+        let mut body = vec![];
+        resp.read_to_end(&mut body).map_err(|err| self.unavailable(err))?;
+        let state: MirrorState = serde_json::from_slice(&body)
+            .chain_err(|| "Could not parse the state of the primary")?;
+        debug!("primary {} is at revision {} with {} SDKs", self.url, state.revision,
+               state.sdks.len());
+        *self.last_state.lock().unwrap() = Some(state.clone());
+        Ok(state)
+    }
+
+    /// Lists the SDKs of the primary.
+    pub fn list_upstream_sdks(&self) -> Result<UpstreamListing> {
+        Ok(self.get_state()?.listing())
+    }
+
+    /// Downloads an SDK from the primary and returns a reader to the bytes
+    /// in the SDK.
+    ///
+    /// Depending on the filename of the SDK the memdb is XZ compressed
+    /// (`.memdbz`) or not (`.memdb`).
+    pub fn download_sdk(&self, sdk: &RemoteSdk) -> Result<Box<Read>> {
+        let url = self.url.join(&format!("download/{}", sdk.filename()))?;
+        let resp = self.client.get(url.as_str()).headers(self.headers()).send()
+            .map_err(|err| self.unavailable(err))?;
+        if resp.status != StatusCode::Ok {
+            return Err(self.unavailable(format!("{} answered {}", sdk.filename(),
+                                                resp.status)).into());
+        }
+        Ok(Box::new(resp))
+    }
+    /// Fetches the object manifest of a thin SDK from the primary.
+    ///
+    /// Returns `None` if the primary has no manifest for the SDK.
+    pub fn get_object_manifest(&self, info: &SdkInfo) -> Result<Option<ObjectManifest>> {
+        let url = self.url.join(&format!("mirror/objects/{}", info.sdk_id()))?;
+        let mut resp = self.client.get(url.as_str()).headers(self.headers()).send()
+            .map_err(|err| self.unavailable(err))?;
+        match resp.status {
+            StatusCode::Ok => {}
+            StatusCode::NotFound => { return Ok(None); }
+            status => {
+                return Err(self.unavailable(format!("manifest of {} answered {}",
+                                                    info.sdk_id(), status)).into());
+            }
+        }
+        let mut body = vec![];
+        resp.read_to_end(&mut body).map_err(|err| self.unavailable(err))?;
+        Ok(Some(ObjectManifest::from_slice(&body)?))
+    }
+
+    /// Downloads the memdb of a shared object of a thin SDK from the
+    /// primary.
+    pub fn download_shared_object(&self, info: &SdkInfo, uuid: &Uuid) -> Result<Vec<u8>> {
+        let url = self.url.join(&format!("mirror/objects/{}/{}", info.sdk_id(), uuid))?;
+        let mut resp = self.client.get(url.as_str()).headers(self.headers()).send()
+            .map_err(|err| self.unavailable(err))?;
+        match resp.status {
+            StatusCode::Ok => {}
+            StatusCode::NotFound => {
+                return Err(ErrorKind::MissingSharedObject(uuid.to_string()).into());
+            }
+            status => {
+                return Err(self.unavailable(format!("shared object {} answered {}",
+                                                    uuid, status)).into());
+            }
+        }
+        let mut body = vec![];
+        resp.read_to_end(&mut body).map_err(|err| self.unavailable(err))?;
+        Ok(body)
+    }
+}
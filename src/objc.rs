@@ -0,0 +1,180 @@
+//! Objective-C Metadata Support
+//!
+//! The system libraries of an SDK often have their symbol tables stripped
+//! but the Objective-C runtime still needs to know every class and the
+//! implementations of its methods.  This module reads that metadata from
+//! the `__objc_classlist` section and turns it into `-[Class selector]`
+//! and `+[Class selector]` symbols for the converter.
+//!
+//! Only what is needed for the names is parsed.  Anything that does not
+//! look right (pointers outside of the image, unterminated strings) is
+//! skipped rather than failing the conversion.
+use super::image::MachImage;
+
+/// The parts of a Mach-O image needed to read its Objective-C metadata.
+pub struct ObjcImage<'a> {
+    image: MachImage<'a>,
+    classlist: Option<(u64, u64)>,
+}
+
+/// Method lists with this flag store 32 bit offsets instead of pointers.
+const RELATIVE_METHOD_LIST: u32 = 0x8000_0000;
+
+impl<'a> ObjcImage<'a> {
+    /// Creates an image from the address and size of its
+    /// `__objc_classlist` section.
+    pub fn new(image: MachImage<'a>, classlist: Option<(u64, u64)>) -> ObjcImage<'a> {
+        ObjcImage {
+            image,
+            classlist,
+        }
+    }
+
+    /// Returns the symbols for the methods of all classes by absolute
+    /// address.
+    pub fn symbols(&self) -> Vec<(u64, String)> {
+        let mut rv = vec![];
+        let (start, size) = match self.classlist {
+            Some(classlist) => classlist,
+            None => { return rv; }
+        };
+        let end = match start.checked_add(size) {
+            Some(end) => end,
+            None => { return rv; }
+        };
+        let mut addr = start;
+        while addr + self.image.ptr_size() <= end {
+            if let Some(class) = self.image.read_ptr(addr) {
+                self.add_class_symbols(class, &mut rv);
+            }
+            addr += self.image.ptr_size();
+        }
+        rv
+    }
+
+    fn add_class_symbols(&self, class: u64, rv: &mut Vec<(u64, String)>) {
+        let name = match self.class_ro(class).and_then(|ro| self.class_name(ro)) {
+            Some(name) => name,
+            None => { return; }
+        };
+        self.add_method_symbols(class, '-', name, rv);
+        if let Some(metaclass) = self.image.read_ptr(class) {
+            self.add_method_symbols(metaclass, '+', name, rv);
+        }
+    }
+
+    /// Returns the address of the read-only data of a class.
+    fn class_ro(&self, class: u64) -> Option<u64> {
+        let data = self.image.read_ptr(class.checked_add(4 * self.image.ptr_size())?)?;
+        // the low bits are flags
+        Some(if self.image.is_64() { data & !7 } else { data & !3 })
+    }
+
+    fn class_name(&self, ro: u64) -> Option<&'a str> {
+        let offset = if self.image.is_64() { 24 } else { 16 };
+        self.image.read_cstr(self.image.read_ptr(ro.checked_add(offset)?)?)
+    }
+
+    /// Adds the symbols of the methods in the method list of a class.
+    ///
+    /// Lists whose entries are smaller than a method are skipped, and only
+    /// as many methods are read as fit into the rest of the segment.
+    fn add_method_symbols(&self, class: u64, kind: char, name: &str,
+                          rv: &mut Vec<(u64, String)>) {
+        if_chain! {
+            if let Some(ro) = self.class_ro(class);
+            if let Some(methods) = ro.checked_add(if self.image.is_64() { 32 } else { 20 });
+            if let Some(list) = self.image.read_ptr(methods);
+            if list != 0;
+            if let Some(flags) = self.image.read_u32(list);
+            if let Some(count) = self.image.read_u32(list + 4);
+            if let Some(available) = self.image.available(list + 8);
+            then {
+                let entsize = u64::from(flags & 0xfffc);
+                let is_relative = flags & RELATIVE_METHOD_LIST != 0;
+                // name, types and implementation
+                let method_size = if is_relative { 12 } else { 3 * self.image.ptr_size() };
+                if entsize < method_size {
+                    return;
+                }
+                let count = u64::from(count).min(available / entsize);
+                for idx in 0..count {
+                    let method = list + 8 + idx * entsize;
+                    let (selector, imp) = if is_relative {
+                        match (self.image.read_rel(method).and_then(|x| self.image.read_ptr(x)),
+                               self.image.read_rel(method + 8)) {
+                            (Some(selector), Some(imp)) => (selector, imp),
+                            _ => continue,
+                        }
+                    } else {
+                        match (self.image.read_ptr(method),
+                               self.image.read_ptr(method + 2 * self.image.ptr_size())) {
+                            (Some(selector), Some(imp)) => (selector, imp),
+                            _ => continue,
+                        }
+                    };
+                    if imp == 0 {
+                        continue;
+                    }
+                    if let Some(selector) = self.image.read_cstr(selector) {
+                        rv.push((imp, format!("{}[{} {}]", kind, name, selector)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_objc_symbols() {
+    use super::image::Segment;
+
+    // a single segment mapped at 0x1000 with a class, its metaclass, the
+    // read-only data of both, a method list each and the strings
+    let mut data = vec![0u8; 0x200];
+    {
+        let mut put = |offset: usize, value: u64, size: usize| {
+            for i in 0..size {
+                data[offset + i] = (value >> (i * 8)) as u8;
+            }
+        };
+        put(0x00, 0x1010, 8);                       // classlist: class
+        put(0x10, 0x1040, 8);                       // class: isa
+        put(0x30, 0x1080, 8);                       // class: data
+        put(0x40 + 0x20, 0x10c0 | 1, 8);            // metaclass: data (with flags)
+        put(0x80 + 0x18, 0x1180, 8);                // class ro: name
+        put(0x80 + 0x20, 0x1100, 8);                // class ro: methods
+        put(0xc0 + 0x18, 0x1180, 8);                // metaclass ro: name
+        put(0xc0 + 0x20, 0x1140, 8);                // metaclass ro: methods
+        put(0x100, 24, 4);                          // instance methods
+        put(0x104, 1, 4);
+        put(0x108, 0x1190, 8);
+        put(0x118, 0x2000, 8);
+        put(0x140, 24, 4);                          // class methods
+        put(0x144, 1, 4);
+        put(0x148, 0x11a0, 8);
+        put(0x158, 0x2100, 8);
+    }
+    data[0x180..0x186].copy_from_slice(b"Thing\0");
+    data[0x190..0x195].copy_from_slice(b"init\0");
+    data[0x1a0..0x1a4].copy_from_slice(b"new\0");
+
+    let segments = vec![Segment {
+        vmaddr: 0x1000,
+        vmsize: 0x200,
+        fileoff: 0,
+        filesize: 0x200,
+    }];
+    let image = ObjcImage::new(MachImage::new(&data, true, segments.clone()), Some((0x1000, 8)));
+    assert_eq!(image.symbols(), vec![
+        (0x2000, "-[Thing init]".to_string()),
+        (0x2100, "+[Thing new]".to_string()),
+    ]);
+
+    // counts past the end of the segment and entries that are too small
+    // to hold a method are not trusted
+    data[0x104..0x108].copy_from_slice(&[0xff; 4]);
+    data[0x140] = 4;
+    let image = ObjcImage::new(MachImage::new(&data, true, segments), Some((0x1000, 8)));
+    assert_eq!(image.symbols(), vec![(0x2000, "-[Thing init]".to_string())]);
+}
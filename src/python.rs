@@ -0,0 +1,114 @@
+//! Python bindings for the stash and memdbs.
+//!
+//! These are built as an extension module named `libsymbolserver` when
+//! the `python` feature is enabled.  They are read-only: a stash can be
+//! opened, SDKs can be matched and the symbols of objects inspected.
+use std::sync::Arc;
+
+use pyo3::prelude::*;
+use pyo3::exceptions::RuntimeError;
+
+use super::Error;
+use super::config::Config;
+use super::memdb::read::MemDb;
+use super::memdb::stash::MemDbStash;
+use super::utils::parse_uuid;
+
+fn convert_error(err: Error) -> PyErr {
+    PyErr::new::<RuntimeError, _>(err.to_string())
+}
+
+/// A local stash of memdbs.
+#[pyclass(name=Stash)]
+pub struct PyStash {
+    stash: MemDbStash,
+}
+
+/// A single memdb of an SDK.
+#[pyclass(name=MemDb)]
+pub struct PyMemDb {
+    memdb: Arc<MemDb<'static>>,
+}
+
+#[pymethods]
+impl PyStash {
+    /// Opens the stash from a config file or the default config.
+    #[new]
+    #[args(config_path = "None")]
+    fn new(config_path: Option<&str>) -> PyResult<Self> {
+        let config = match config_path {
+            Some(path) => Config::load_file(path),
+            None => Config::load_default(),
+        }.map_err(convert_error)?;
+        Ok(PyStash {
+            stash: MemDbStash::new(&config).map_err(convert_error)?,
+        })
+    }
+
+    /// Returns the ids of all synched SDKs.
+    fn list_sdks(&self) -> PyResult<Vec<String>> {
+        let sdks = self.stash.list_sdks().map_err(convert_error)?;
+        Ok(sdks.iter().map(|x| x.sdk_id()).collect())
+    }
+
+    /// Returns the ids of the SDKs that best match an SDK id.
+    fn fuzzy_match_sdk_id(&self, sdk_id: &str) -> PyResult<Vec<String>> {
+        let sdks = self.stash.fuzzy_match_sdk_id(sdk_id).map_err(convert_error)?;
+        Ok(sdks.iter().map(|x| x.sdk_id()).collect())
+    }
+
+    /// Opens the memdb of an SDK.
+    fn get_memdb(&self, sdk_id: &str) -> PyResult<PyMemDb> {
+        Ok(PyMemDb {
+            memdb: self.stash.get_memdb_from_sdk_id(sdk_id).map_err(convert_error)?,
+        })
+    }
+}
+
+#[pymethods]
+impl PyMemDb {
+    /// The id of the SDK.
+    #[getter]
+    fn sdk_id(&self) -> String {
+        self.memdb.info().sdk_id()
+    }
+
+    /// Finds the uuid of an object by name or uuid.
+    fn find_uuid(&self, name_or_uuid: &str) -> PyResult<Option<String>> {
+        let uuid = self.memdb.find_uuid_fuzzy(name_or_uuid).map_err(convert_error)?;
+        Ok(uuid.map(|x| x.hyphenated().to_string()))
+    }
+
+    /// Returns all `(addr, symbol)` pairs of an object.
+    fn iter_symbols(&self, uuid: &str) -> PyResult<Vec<(u64, String)>> {
+        let uuid = parse_uuid(uuid)
+            .ok_or_else(|| PyErr::new::<RuntimeError, _>("invalid uuid"))?;
+        let mut rv = vec![];
+        for item in self.memdb.iter_symbols(&uuid).map_err(convert_error)? {
+            let item = item.map_err(convert_error)?;
+            rv.push((item.addr(), item.symbol().to_string()));
+        }
+        Ok(rv)
+    }
+
+    /// Looks up an address in an object given by uuid or name.
+    ///
+    /// Returns `(object_name, symbol, addr)` or `None`.
+    fn lookup(&self, object: &str, cpu_name: &str, addr: u64)
+        -> Option<(String, String, u64)>
+    {
+        let sym = match parse_uuid(object) {
+            Some(uuid) => self.memdb.lookup_by_uuid(&uuid, addr),
+            None => self.memdb.lookup_by_object_name(object, cpu_name, addr),
+        };
+        sym.map(|sym| (sym.object_name().to_string(), sym.symbol().to_string(), sym.addr()))
+    }
+}
+
+/// The `libsymbolserver` Python module.
+#[pymodule]
+fn libsymbolserver(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyStash>()?;
+    m.add_class::<PyMemDb>()?;
+    Ok(())
+}
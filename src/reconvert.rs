@@ -0,0 +1,229 @@
+//! Re-conversion campaigns.
+//!
+//! When a converter release turns out to write bad memdbs, every memdb it
+//! wrote has to be converted again.  The conversion info in the header of
+//! a memdb says which converter wrote it, so a campaign downloads the
+//! memdbs of the bucket, converts the outdated ones again from the archived
+//! support folders and uploads them over the old ones.
+//!
+//! Several workers can run the same campaign.  A worker claims an SDK
+//! before converting it by writing a claim object next to the manifest
+//! (`<manifest>.claims/<sdk id>.json`) and checks that its claim survived,
+//! as S3 cannot update an object conditionally.  Every SDK has a claim
+//! object of its own, so workers claiming different SDKs never overwrite
+//! each other.  Once the new memdb is uploaded the worker deletes its
+//! claim and publishes the manifest with the next revision.  Claims of
+//! workers that died expire after a while.
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, Utc};
+use serde_json;
+use walkdir::WalkDir;
+use xz2::read::XzDecoder;
+
+use super::{Result, ResultExt};
+use super::manifest::publish_bucket_manifest;
+use super::memdb::read::MemDbMetadata;
+use super::memdb::stash::RemoteSdk;
+use super::s3::S3Server;
+use super::sdk::{DumpOptions, Sdk, SdkInfo};
+
+/// Influences a re-conversion campaign
+#[derive(Clone)]
+pub struct ReconvertOptions {
+    /// Memdbs written by older converters (or before converters recorded
+    /// their version) are converted again
+    pub min_converter_version: String,
+    /// Where the support folders are archived, laid out like the default
+    /// location (`iOS DeviceSupport/10.2 (14C92)`)
+    pub inputs: PathBuf,
+    /// The manifest the workers of a campaign coordinate through
+    pub manifest_key: String,
+    /// Identifies this worker in claims
+    pub worker: String,
+    /// How long a claim holds before other workers take over the SDK
+    pub claim_timeout: Duration,
+    /// Only looks for outdated memdbs without converting them
+    pub dry_run: bool,
+    /// How SDKs are converted.  The memdbs are always compressed.
+    pub dump: DumpOptions,
+}
+
+/// What a campaign found and did
+#[derive(Debug, Default)]
+pub struct ReconvertReport {
+    /// The SDKs whose memdbs were written by older converters
+    pub outdated: Vec<SdkInfo>,
+    /// The SDKs that were converted and uploaded again
+    pub converted: Vec<SdkInfo>,
+    /// The outdated SDKs that were left alone and why
+    pub skipped: Vec<(SdkInfo, String)>,
+}
+
+/// The claim of a worker on an SDK
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Claim {
+    /// The worker that converts the SDK
+    pub worker: String,
+    /// When the worker claimed the SDK (a unix timestamp)
+    pub claimed_at: i64,
+}
+
+/// Returns the key of the claim object of an SDK.
+pub fn get_claim_key(manifest_key: &str, info: &SdkInfo) -> String {
+    format!("{}.claims/{}.json", manifest_key, info.sdk_id())
+}
+
+/// Returns `true` if a dotted version is older than another one.
+///
+/// Anything after the digits of a component (like `-beta`) is ignored.
+fn is_older_version(version: &str, other: &str) -> bool {
+    fn parse(version: &str) -> Vec<u64> {
+        version.trim().split('.').map(|x| {
+            x.chars().take_while(|c| c.is_digit(10)).collect::<String>()
+                .parse().unwrap_or(0)
+        }).collect()
+    }
+    let mut a = parse(version);
+    let mut b = parse(other);
+    let len = a.len().max(b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+    a < b
+}
+
+/// Finds the archived support folders (and zips of them) by SDK.
+fn find_archived_sdks(path: &Path) -> Result<HashMap<SdkInfo, PathBuf>> {
+    let mut rv = HashMap::new();
+    for entry in WalkDir::new(path).min_depth(2).max_depth(2) {
+        let entry = entry?;
+        if let Ok(sdk) = Sdk::new(entry.path()) {
+            rv.insert(sdk.info().clone(), entry.path().to_path_buf());
+        }
+    }
+    Ok(rv)
+}
+
+/// Reads the metadata of a memdb in the bucket.
+///
+/// Only the start of the memdb is downloaded and decompressed.
+fn download_metadata(s3: &S3Server, sdk: &RemoteSdk) -> Result<MemDbMetadata> {
+    MemDbMetadata::read(XzDecoder::new(s3.download_sdk(sdk)?))
+}
+
+fn get_claim(s3: &S3Server, key: &str) -> Result<Option<Claim>> {
+    match s3.get_object(key)? {
+        Some(body) => Ok(Some(serde_json::from_slice(&body)
+            .chain_err(|| format!("Invalid claim {}", key))?)),
+        None => Ok(None),
+    }
+}
+
+/// Claims an SDK.
+///
+/// Returns the other worker that holds the claim if that fails.
+fn claim_sdk(s3: &S3Server, opts: &ReconvertOptions, info: &SdkInfo)
+    -> Result<Option<String>>
+{
+    let key = get_claim_key(&opts.manifest_key, info);
+    let now = Utc::now();
+    let not_before = (now - opts.claim_timeout).timestamp();
+    match get_claim(s3, &key)? {
+        Some(ref claim) if claim.worker != opts.worker && claim.claimed_at >= not_before => {
+            return Ok(Some(claim.worker.clone()));
+        }
+        _ => {}
+    }
+    let claim = Claim { worker: opts.worker.clone(), claimed_at: now.timestamp() };
+    s3.put_json_object(&key, serde_json::to_vec(&claim)
+                       .chain_err(|| "Could not serialize claim")?)?;
+
+    // another worker might have claimed the SDK at the same time
+    Ok(match get_claim(s3, &key)? {
+        Some(ref other) if other.worker == claim.worker &&
+                           other.claimed_at == claim.claimed_at => None,
+        Some(other) => Some(other.worker),
+        None => Some("unknown".to_string()),
+    })
+}
+
+fn release_sdk(s3: &S3Server, opts: &ReconvertOptions, info: &SdkInfo) -> Result<()> {
+    let key = get_claim_key(&opts.manifest_key, info);
+    match get_claim(s3, &key)? {
+        Some(ref claim) if claim.worker == opts.worker => s3.delete_object(&key),
+        _ => Ok(()),
+    }
+}
+
+fn convert_sdk(s3: &S3Server, opts: &ReconvertOptions, remote: &RemoteSdk,
+               old: &MemDbMetadata, path: &Path) -> Result<()> {
+    let mut sdk = Sdk::new(path)?;
+    if sdk.info().device_family().is_none() {
+        sdk.set_device_family(old.info()?.device_family());
+    }
+    let mut rv = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut rv, DumpOptions {
+        compress: true,
+        journal: None,
+        object_store: None,
+        ..opts.dump.clone()
+    })?;
+    s3.upload_sdk(remote.filename(), rv.into_inner())
+}
+
+/// Converts the memdbs in the bucket that older converters wrote again.
+pub fn reconvert(s3: &S3Server, opts: &ReconvertOptions) -> Result<ReconvertReport> {
+    let reporter = &opts.dump.reporter;
+    let inputs = find_archived_sdks(&opts.inputs)?;
+    let mut sdks = s3.list_bucket_sdks()?.sdks;
+    sdks.sort_by(|a, b| a.info().cmp(b.info()));
+
+    let mut rv = ReconvertReport::default();
+    for remote in sdks.iter() {
+        let metadata = download_metadata(s3, remote)?;
+        if let Some(conversion) = metadata.conversion_info() {
+            if !is_older_version(&conversion.converter_version, &opts.min_converter_version) {
+                continue;
+            }
+        }
+        let info = remote.info().clone();
+        rv.outdated.push(info.clone());
+        if opts.dry_run {
+            continue;
+        }
+
+        let reason = if metadata.is_thin() {
+            Some("thin SDKs are not converted again".to_string())
+        } else if s3.get_sdk_signature(remote)?.is_some() {
+            Some("signed SDKs are not converted again".to_string())
+        } else if !inputs.contains_key(&info) {
+            Some("no archived support folder".to_string())
+        } else {
+            claim_sdk(s3, opts, &info)?.map(|worker| format!("claimed by {}", worker))
+        };
+        if let Some(reason) = reason {
+            reporter.detail(&format!("Skipping {}: {}", info, reason));
+            rv.skipped.push((info, reason));
+            continue;
+        }
+
+        reporter.detail(&format!("Converting {} again", info));
+        let result = convert_sdk(s3, opts, remote, &metadata, &inputs[&info]);
+        release_sdk(s3, opts, &info)?;
+        result?;
+        publish_bucket_manifest(s3, &opts.manifest_key)?;
+        rv.converted.push(info);
+    }
+    Ok(rv)
+}
+
+#[test]
+fn test_is_older_version() {
+    assert!(is_older_version("1.9.2", "1.10.0"));
+    assert!(is_older_version("1.10", "1.10.1"));
+    assert!(!is_older_version("1.10.0", "1.10"));
+    assert!(!is_older_version("1.11.0-beta", "1.11.0"));
+    assert!(!is_older_version("2.0.0", "1.11.0"));
+}
@@ -0,0 +1,79 @@
+//! Progress reporting for long running operations.
+//!
+//! The library never writes to stdout itself.  Converting, synching and
+//! verifying SDKs instead tell a `Reporter` what they are doing.  The
+//! default reporter forwards everything to the log; the command line
+//! interface installs one that prints to the terminal.
+use std::fmt;
+use std::sync::Arc;
+
+use indicatif::ProgressBar;
+
+use super::sdk::SdkInfo;
+
+/// What happens to an SDK during a sync or verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdkStatus {
+    Updating,
+    Unchanged,
+    Ignored,
+    Deleting,
+}
+
+/// Receives the progress of long running operations.
+pub trait Reporter: Send + Sync {
+    /// Reports what happens to an SDK.
+    fn sdk_status(&self, status: SdkStatus, info: &SdkInfo);
+
+    /// Reports the start of a step of a multi-step operation.
+    fn step(&self, step: usize, steps: usize, msg: &str);
+
+    /// Reports additional information about the current step.
+    fn detail(&self, msg: &str);
+
+    /// Creates a progress bar for `len` units of work.
+    ///
+    /// The default implementation returns a hidden progress bar.
+    fn progress_bar(&self, len: u64) -> ProgressBar {
+        let _ = len;
+        ProgressBar::hidden()
+    }
+}
+
+/// A reporter that writes to the log and does not show progress.
+pub struct LogReporter;
+
+impl fmt::Display for SdkStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            SdkStatus::Updating => "Updating",
+            SdkStatus::Unchanged => "Unchanged",
+            SdkStatus::Ignored => "Ignored",
+            SdkStatus::Deleting => "Deleting",
+        })
+    }
+}
+
+impl Reporter for LogReporter {
+    fn sdk_status(&self, status: SdkStatus, info: &SdkInfo) {
+        match status {
+            SdkStatus::Updating => info!("updating {}", info),
+            SdkStatus::Unchanged => debug!("unchanged sdk {}", info),
+            SdkStatus::Ignored => debug!("ignored sdk {} by config", info),
+            SdkStatus::Deleting => info!("removing {}", info),
+        }
+    }
+
+    fn step(&self, step: usize, steps: usize, msg: &str) {
+        debug!("[{}/{}] {}", step, steps, msg);
+    }
+
+    fn detail(&self, msg: &str) {
+        debug!("{}", msg);
+    }
+}
+
+/// Returns the default reporter.
+pub fn default_reporter() -> Arc<Reporter> {
+    Arc::new(LogReporter)
+}
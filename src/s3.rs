@@ -1,12 +1,15 @@
 //! This implements S3 sync for the symbolserver.
 
 use std::result::Result as StdResult;
+use std::collections::HashMap;
 use std::env;
 use std::io::{Read, Cursor};
 
 use rusoto_core::Region;
 use rusoto_core::credential::{ProvideAwsCredentials, AwsCredentials, CredentialsError, ChainProvider};
-use rusoto_s3::{S3, S3Client, ListObjectsRequest, GetObjectRequest, Object, ListObjectsError};
+use rusoto_s3::{S3, S3Client, ListObjectsRequest, GetObjectRequest, PutObjectRequest,
+                DeleteObjectRequest, Object, ListObjectsError, GetObjectError, PutObjectError,
+                DeleteObjectError};
 
 use chrono::Utc;
 use time::Duration;
@@ -15,10 +18,13 @@ use hyper::client::RedirectPolicy;
 use hyper::net::{HttpConnector, HttpsConnector};
 use hyper_native_tls::NativeTlsClient;
 use url::Url;
+use uuid::Uuid;
 
 use super::sdk::SdkInfo;
 use super::config::Config;
-use super::memdb::stash::RemoteSdk;
+use super::memdb::objects::{get_object_manifest_key, get_shared_object_key, ObjectManifest};
+use super::memdb::stash::{RemoteSdk, WithdrawnSdk};
+use super::manifest::{Manifest, sdk_info_from_tombstone};
 use super::{ErrorKind, Result, ResultExt};
 
 struct FlexibleCredentialsProvider {
@@ -30,9 +36,21 @@ struct FlexibleCredentialsProvider {
 /// Abstracts over S3 operations
 pub struct S3Server {
     url: Url,
+    manifest: Option<String>,
     client: S3Client<FlexibleCredentialsProvider, HyperClient>,
 }
 
+/// The SDKs offered upstream
+#[derive(Debug, Default)]
+pub struct UpstreamListing {
+    /// The compressed memdbs
+    pub sdks: Vec<RemoteSdk>,
+    /// SDKs that were recalled and must not be served
+    pub withdrawn: Vec<WithdrawnSdk>,
+    /// Checksums of the decompressed memdbs by memdb filename, if known
+    pub checksums: HashMap<String, String>,
+}
+
 impl ProvideAwsCredentials for FlexibleCredentialsProvider {
 
     fn credentials(&self) -> StdResult<AwsCredentials, CredentialsError> {
@@ -64,6 +82,16 @@ fn unquote_etag(quoted_etag: Option<String>) -> Option<String> {
     })
 }
 
+/// S3 answers requests whose signature is too old or too new with this.
+fn is_clock_skew(body: &str) -> bool {
+    body.contains("RequestTimeTooSkewed")
+}
+
+/// S3 answers with this when a bucket gets more requests than it allows.
+fn is_throttled(body: &str) -> bool {
+    body.contains("SlowDown")
+}
+
 pub fn new_hyper_client() -> Result<HyperClient> {
     let ssl = NativeTlsClient::new().chain_err(||
         format!("Couldn't create NativeTlsClient."))?;
@@ -93,6 +121,7 @@ impl S3Server {
         };
         Ok(S3Server {
             url: config.get_aws_bucket_url()?,
+            manifest: config.get_sync_manifest().map(|x| x.into_owned()),
             client: S3Client::new(new_hyper_client().chain_err(
                     || "Could not configure TLS layer")?,
                     FlexibleCredentialsProvider {
@@ -132,8 +161,35 @@ impl S3Server {
         }
     }
 
+    fn object_key(&self, filename: &str) -> String {
+        format!("{}/{}", self.bucket_prefix().trim_end_matches('/'), filename)
+    }
+
     /// Requests the list of all compressed SDKs in the bucket
-    pub fn list_upstream_sdks(&self) -> Result<Vec<RemoteSdk>> {
+    ///
+    /// If a manifest is configured the list is read from it instead of
+    /// listing the bucket.
+    pub fn list_upstream_sdks(&self) -> Result<UpstreamListing> {
+        if let Some(ref key) = self.manifest {
+            // like the listing a manifest that cannot be fetched makes us
+            // go offline rather than treating the bucket as empty.
+            let manifest = self.get_manifest(key)?.ok_or_else(|| {
+                ErrorKind::S3IncompleteListing(format!("manifest {} is missing", key))
+            })?;
+            let rv = manifest.listing();
+            debug!("Manifest {} (revision {}) lists {} SDKs", key,
+                   manifest.revision().map_or("unknown".to_string(), |x| x.to_string()),
+                   rv.sdks.len());
+            return Ok(rv);
+        }
+        self.list_bucket_sdks()
+    }
+
+    /// Lists the compressed SDKs in the bucket, ignoring any manifest.
+    ///
+    /// SDKs are withdrawn by uploading an `<sdk id>.withdrawn` file next to
+    /// them that contains the reason.
+    pub fn list_bucket_sdks(&self) -> Result<UpstreamListing> {
         let mut request = ListObjectsRequest::default();
         request.bucket = self.bucket_name().into();
         request.prefix = Some(self.bucket_prefix());
@@ -143,24 +199,207 @@ impl S3Server {
         // the first one that happens.  This gives us better detection in
         // the health check for raw network errors to better report
         // downtime.
-        let out = match self.client.list_objects(&request) {
+        let mut rv = UpstreamListing::default();
+        let mut tombstones = vec![];
+        loop {
+            let out = match self.client.list_objects(&request) {
+                Ok(out) => out,
+                Err(ListObjectsError::HttpDispatch(err)) => {
+                    return Err(ErrorKind::S3Unavailable(err.to_string()).into());
+                }
+                Err(ListObjectsError::Unknown(ref body)) if is_clock_skew(body) => {
+                    return Err(ErrorKind::S3ClockSkew.into());
+                }
+                Err(ListObjectsError::Unknown(ref body)) if is_throttled(body) => {
+                    return Err(ErrorKind::QuotaExceeded("listing SDKs".into()).into());
+                }
+                Err(err) => {
+                    return Err(err).chain_err(|| "Failed to fetch SDKs from S3")?;
+                }
+            };
+
+            let objects = out.contents.unwrap_or_else(|| vec![]);
+            let marker = out.next_marker
+                .or_else(|| objects.last().and_then(|x| x.key.clone()));
+            for obj in objects {
+                if_chain! {
+                    if let Some(ref key) = obj.key;
+                    if let Some(info) = filename_from_key(key).and_then(sdk_info_from_tombstone);
+                    then {
+                        tombstones.push((key.to_string(), info));
+                        continue;
+                    }
+                }
+                if let Some(remote_sdk) = self.object_to_remote_sdk(obj) {
+                    rv.sdks.push(remote_sdk);
+                }
+            }
+
+            if !out.is_truncated.unwrap_or(false) {
+                break;
+            }
+            match marker {
+                Some(marker) => { request.marker = Some(marker); }
+                None => {
+                    return Err(ErrorKind::S3IncompleteListing(
+                        "listing was truncated without a marker".into()).into());
+                }
+            }
+        }
+
+        for (key, info) in tombstones {
+            let body = self.get_object_body(&key)?.unwrap_or_else(|| vec![]);
+            let reason = String::from_utf8_lossy(&body).trim().to_string();
+            rv.withdrawn.push(WithdrawnSdk::new(info, if reason.is_empty() {
+                "no reason given".to_string()
+            } else {
+                reason
+            }));
+        }
+
+        Ok(rv)
+    }
+
+    /// Fetches the manifest stored under a key relative to the bucket URL.
+    ///
+    /// Returns `None` if there is no such manifest.
+    pub fn get_manifest(&self, key: &str) -> Result<Option<Manifest>> {
+        match self.get_object_body(&self.object_key(key))? {
+            Some(body) => Ok(Some(Manifest::from_slice(&body)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches a small object, returning `None` if it does not exist.
+    fn get_object_body(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let request = GetObjectRequest {
+            bucket: self.bucket_name().into(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        let out = match self.client.get_object(&request) {
             Ok(out) => out,
-            Err(ListObjectsError::HttpDispatch(err)) => {
+            Err(GetObjectError::HttpDispatch(err)) => {
                 return Err(ErrorKind::S3Unavailable(err.to_string()).into());
             }
+            Err(GetObjectError::Unknown(ref body)) if is_clock_skew(body) => {
+                return Err(ErrorKind::S3ClockSkew.into());
+            }
+            Err(GetObjectError::Unknown(ref body)) if is_throttled(body) => {
+                return Err(ErrorKind::QuotaExceeded(key.to_string()).into());
+            }
+            Err(GetObjectError::NoSuchKey(_)) => {
+                return Ok(None);
+            }
+            Err(GetObjectError::Unknown(ref body)) if body.contains("NoSuchKey") => {
+                return Ok(None);
+            }
             Err(err) => {
-                return Err(err).chain_err(|| "Failed to fetch SDKs from S3")?;
+                return Err(err).chain_err(|| format!("Failed to fetch {} from S3", key))?;
             }
         };
 
-        let mut rv = vec![];
-        for obj in out.contents.unwrap_or_else(|| vec![]) {
-            if let Some(remote_sdk) = self.object_to_remote_sdk(obj) {
-                rv.push(remote_sdk);
+        let mut body = Vec::new();
+        if out.body.unwrap().read_to_end(&mut body).is_err() {
+            return Err(ErrorKind::DownloadInterrupted(key.to_string(), body.len() as u64).into());
+        }
+        Ok(Some(body))
+    }
+
+    /// Fetches the detached signature uploaded next to an SDK.
+    ///
+    /// Returns `None` if the SDK is not signed.
+    pub fn get_sdk_signature(&self, sdk: &RemoteSdk) -> Result<Option<Vec<u8>>> {
+        self.get_object_body(&self.object_key(&format!("{}.sig", sdk.filename())))
+    }
+
+    /// Fetches the object manifest uploaded for a thin SDK.
+    ///
+    /// Returns `None` if the SDK has no object manifest.
+    pub fn get_object_manifest(&self, info: &SdkInfo) -> Result<Option<ObjectManifest>> {
+        match self.get_object_body(&self.object_key(&get_object_manifest_key(info)))? {
+            Some(body) => Ok(Some(ObjectManifest::from_slice(&body)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Downloads the memdb of a shared object.
+    pub fn download_shared_object(&self, uuid: &Uuid) -> Result<Vec<u8>> {
+        self.get_object_body(&self.object_key(&get_shared_object_key(uuid)))?
+            .ok_or_else(|| ErrorKind::MissingSharedObject(uuid.to_string()).into())
+    }
+
+    /// Uploads a manifest under a key relative to the bucket URL.
+    ///
+    /// S3 replaces objects atomically so readers see either the old or the
+    /// new manifest.
+    pub fn put_manifest(&self, key: &str, manifest: &Manifest) -> Result<()> {
+        self.put_object_body(key, manifest.to_vec()?, "application/json", "manifest")
+    }
+
+    /// Fetches a small object under a key relative to the bucket URL.
+    ///
+    /// Returns `None` if there is no such object.
+    pub fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get_object_body(&self.object_key(key))
+    }
+
+    /// Uploads a JSON document under a key relative to the bucket URL.
+    pub fn put_json_object(&self, key: &str, body: Vec<u8>) -> Result<()> {
+        self.put_object_body(key, body, "application/json", key)
+    }
+
+    /// Deletes the object under a key relative to the bucket URL.
+    ///
+    /// Deleting an object that does not exist succeeds.
+    pub fn delete_object(&self, key: &str) -> Result<()> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket_name().into(),
+            key: self.object_key(key),
+            ..Default::default()
+        };
+        match self.client.delete_object(&request) {
+            Ok(_) => Ok(()),
+            Err(DeleteObjectError::HttpDispatch(err)) => {
+                Err(ErrorKind::S3Unavailable(err.to_string()).into())
+            }
+            Err(err) => {
+                Err(err).chain_err(|| format!("Failed to delete {} from S3", key))?
             }
         }
+    }
 
-        Ok(rv)
+    /// Uploads a compressed memdb, replacing the one of the same name.
+    pub fn upload_sdk(&self, filename: &str, body: Vec<u8>) -> Result<()> {
+        self.put_object_body(filename, body, "application/octet-stream", filename)
+    }
+
+    fn put_object_body(&self, key: &str, body: Vec<u8>, content_type: &str, what: &str)
+        -> Result<()>
+    {
+        let request = PutObjectRequest {
+            bucket: self.bucket_name().into(),
+            key: self.object_key(key),
+            body: Some(body),
+            content_type: Some(content_type.to_owned()),
+            ..Default::default()
+        };
+        match self.client.put_object(&request) {
+            Ok(_) => Ok(()),
+            Err(PutObjectError::HttpDispatch(err)) => {
+                Err(ErrorKind::S3Unavailable(err.to_string()).into())
+            }
+            Err(PutObjectError::Unknown(ref body)) if is_clock_skew(body) => {
+                Err(ErrorKind::S3ClockSkew.into())
+            }
+            Err(PutObjectError::Unknown(ref body)) if is_throttled(body) => {
+                Err(ErrorKind::QuotaExceeded(what.to_string()).into())
+            }
+            Err(err) => {
+                Err(err).chain_err(|| format!("Failed to upload {} to S3", what))?
+            }
+        }
     }
 
     /// Downloads a given remote SDK and returns a reader to the
@@ -170,14 +409,23 @@ impl S3Server {
     pub fn download_sdk(&self, sdk: &RemoteSdk) -> Result<Box<Read>> {
         let request = GetObjectRequest {
             bucket: self.bucket_name().into(),
-            key: format!("{}/{}", self.bucket_prefix()
-                .trim_right_matches('/'), sdk.filename()),
+            key: self.object_key(sdk.filename()),
             response_content_type: Some("application/octet-stream".to_owned()),
             ..Default::default()
         };
 
-        let out = self.client.get_object(&request)
-            .chain_err(|| "Failed to fetch SDK from S3")?;
+        let out = match self.client.get_object(&request) {
+            Ok(out) => out,
+            Err(GetObjectError::Unknown(ref body)) if is_clock_skew(body) => {
+                return Err(ErrorKind::S3ClockSkew.into());
+            }
+            Err(GetObjectError::Unknown(ref body)) if is_throttled(body) => {
+                return Err(ErrorKind::QuotaExceeded(sdk.filename().to_string()).into());
+            }
+            Err(err) => {
+                return Err(err).chain_err(|| "Failed to fetch SDK from S3")?;
+            }
+        };
 
         // XXX: this really should not read into memory but we are currently
         // restricted by rusoto here. https://github.com/rusoto/rusoto/issues/481
@@ -185,7 +433,10 @@ impl S3Server {
 
         let mut stream = out.body.unwrap();
         let mut body = Vec::new();
-        stream.read_to_end(&mut body).unwrap();
+        if stream.read_to_end(&mut body).is_err() {
+            return Err(ErrorKind::DownloadInterrupted(
+                sdk.filename().to_string(), body.len() as u64).into());
+        }
         Ok(Box::new(Cursor::new(body)))
     }
 }
@@ -0,0 +1,277 @@
+//! Parsing untrusted objects in a child process.
+//!
+//! SDKs that users upload can contain objects that crash the Mach-O and
+//! DWARF parsers or send them into an endless loop.  With a `Sandbox` in
+//! the `DumpOptions` every object is parsed by a child process of its own
+//! which streams the symbols back over a pipe, so a bad object only takes
+//! down that child.  On Linux the child can in addition lock itself down
+//! with seccomp once it has read the object.
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use super::{ErrorKind, Result, ResultExt};
+
+/// How long a child may take for one object by default, in seconds.
+pub const DEFAULT_TIMEOUT: u64 = 300;
+
+/// Runs the conversion of objects in child processes.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    /// The binary that is started for every object.  It has to understand
+    /// the hidden `convert-object` subcommand of `sentry-symbolserver`.
+    pub program: PathBuf,
+    /// Makes the children enter seccomp before they parse the object
+    pub seccomp: bool,
+    /// Children that take longer for an object are killed
+    pub timeout: Duration,
+}
+
+impl Sandbox {
+    /// Creates a sandbox that starts the given binary.
+    pub fn new<P: AsRef<Path>>(program: P) -> Sandbox {
+        Sandbox {
+            program: program.as_ref().to_path_buf(),
+            seccomp: false,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT),
+        }
+    }
+
+    /// Runs the program with `input` on stdin and passes every line it
+    /// writes to stdout to `on_line`.
+    ///
+    /// A child that exits unsuccessfully, is killed by a signal or runs
+    /// into the timeout fails with `SandboxFailure` for `name`.  If
+    /// `on_line` fails the child is killed and that error is returned.
+    pub fn run<F>(&self, name: &str, args: &[&str], input: Vec<u8>, mut on_line: F)
+        -> Result<()>
+        where F: FnMut(&str) -> Result<()>
+    {
+        let started = Instant::now();
+        let mut child = Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .chain_err(|| format!("Could not start {}", self.program.display()))?;
+
+        // the child may die before it read everything, so the writer does
+        // not care about errors and runs on its own to not block the reader
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || {
+            stdin.write_all(&input).ok();
+        });
+
+        // the child is only reaped after the watchdog is gone, so its pid
+        // cannot be reused by another process while the watchdog holds it
+        let pid = child.id() as libc::pid_t;
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let watchdog = {
+            let timed_out = timed_out.clone();
+            let timeout = self.timeout;
+            thread::spawn(move || {
+                if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+                    timed_out.store(true, Ordering::SeqCst);
+                    unsafe { libc::kill(pid, libc::SIGKILL); }
+                }
+            })
+        };
+
+        let mut rv = Ok(());
+        for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+            match line {
+                Ok(line) => {
+                    if let Err(err) = on_line(&line) {
+                        child.kill().ok();
+                        rv = Err(err);
+                        break;
+                    }
+                }
+                Err(err) => {
+                    rv = Err(err.into());
+                    break;
+                }
+            }
+        }
+        drop(done_tx);
+        watchdog.join().ok();
+
+        // a child that closed stdout but keeps running still gets the rest
+        // of its time
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if started.elapsed() > self.timeout {
+                timed_out.store(true, Ordering::SeqCst);
+                child.kill().ok();
+                break child.wait()?;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        writer.join().ok();
+
+        if timed_out.load(Ordering::SeqCst) {
+            return Err(ErrorKind::SandboxFailure(
+                name.to_string(),
+                format!("timed out after {} seconds", self.timeout.as_secs())).into());
+        }
+        rv?;
+        if !status.success() {
+            return Err(ErrorKind::SandboxFailure(
+                name.to_string(), describe_status(status)).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn describe_status(status: ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with status {}", code),
+        (None, Some(signal)) => format!("killed by signal {}", signal),
+        (None, None) => format!("exited with {}", status),
+    }
+}
+
+#[cfg(not(unix))]
+fn describe_status(status: ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exited with status {}", code),
+        None => format!("exited with {}", status),
+    }
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod seccomp {
+    use std::io;
+
+    use libc;
+
+    use super::super::{Result, ResultExt};
+
+    // from linux/audit.h, linux/seccomp.h and linux/filter.h
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    const SECCOMP_RET_KILL: u32 = 0x0000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    const BPF_RET_K: u16 = 0x06;
+    /// Offsets of the fields of `struct seccomp_data`
+    const DATA_NR: u32 = 0;
+    const DATA_ARCH: u32 = 4;
+
+    /// What a child needs once it has read its input: writing to stdout
+    /// and stderr, memory management and exiting.
+    const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_close,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_brk,
+        libc::SYS_madvise,
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        libc::SYS_getrandom,
+        libc::SYS_clock_gettime,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+
+    #[repr(C)]
+    struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: libc::c_ushort,
+        filter: *const SockFilter,
+    }
+
+    fn stmt(code: u16, k: u32) -> SockFilter {
+        SockFilter { code, jt: 0, jf: 0, k }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    /// Allows only the system calls in `ALLOWED_SYSCALLS`.
+    ///
+    /// Other calls fail with `EPERM` instead of killing the process, so
+    /// that a parser that tries something unexpected reports an error.
+    pub fn enter() -> Result<()> {
+        let count = ALLOWED_SYSCALLS.len();
+        let mut filter = vec![
+            stmt(BPF_LD_W_ABS, DATA_ARCH),
+            jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET_K, SECCOMP_RET_KILL),
+            stmt(BPF_LD_W_ABS, DATA_NR),
+        ];
+        for (idx, &nr) in ALLOWED_SYSCALLS.iter().enumerate() {
+            // a match skips the remaining checks and the EPERM return
+            filter.push(jump(BPF_JMP_JEQ_K, nr as u32, (count - idx) as u8, 0));
+        }
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+        let prog = SockFprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_ptr(),
+        };
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as libc::c_ulong, 0 as libc::c_ulong,
+                           0 as libc::c_ulong, 0 as libc::c_ulong) != 0 {
+                return Err(io::Error::last_os_error()).chain_err(|| "Could not enter seccomp");
+            }
+            if libc::prctl(libc::PR_SET_SECCOMP, libc::c_ulong::from(libc::SECCOMP_MODE_FILTER),
+                           &prog as *const SockFprog as libc::c_ulong) != 0 {
+                return Err(io::Error::last_os_error()).chain_err(|| "Could not enter seccomp");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restricts the process to the few system calls a conversion needs.
+///
+/// Everything the process wants to read has to be read before.  Only
+/// supported on Linux on x86_64 and aarch64.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn enter_seccomp() -> Result<()> {
+    seccomp::enter()
+}
+
+/// Restricts the process to the few system calls a conversion needs.
+///
+/// Everything the process wants to read has to be read before.  Only
+/// supported on Linux on x86_64 and aarch64.
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn enter_seccomp() -> Result<()> {
+    Err("seccomp is only supported on Linux".into())
+}
@@ -1,17 +1,32 @@
 //! Provides SDK Information
 use std::fs;
 use std::fmt;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 use std::io::{Read, Write, Seek};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use zip;
 use walkdir;
 use regex::Regex;
 use mach_object::Error as MachError;
+use uuid::Uuid;
 
 use super::{Result, Error, ErrorKind};
-use super::dsym::Object;
-use super::memdb::write::dump_memdb;
+use super::dsym::{Object, SymbolSource};
+use super::memdb::write::{dump_memdb, dump_memdb_to_stream};
+use super::report::{Reporter, default_reporter};
+use super::sandbox::Sandbox;
+
+// Fuzzy match scores.  Builds of different trains are always further apart
+// than builds of the same train, a different patchlevel weighs more than
+// any build difference and betas rank behind every release.
+const SCORE_BUILD_MISMATCH: u32 = 1000;
+const SCORE_PATCHLEVEL: u32 = 2000;
+const MAX_PATCHLEVEL_DIFF: u32 = 10;
+const SCORE_BETA: u32 = 25000;
 
 
 enum ObjectIterSource {
@@ -29,12 +44,47 @@ enum ObjectIterSource {
 #[derive(Clone)]
 pub struct DumpOptions {
     pub compress: bool,
+    pub reporter: Arc<Reporter>,
+    /// Where temporary files go (the system temp folder if not set)
+    pub scratch_dir: Option<PathBuf>,
+    /// Where processed objects are recorded so that an interrupted
+    /// conversion can resume
+    pub journal: Option<PathBuf>,
+    /// Where the symbols of the objects go for a thin SDK.  The memdb of
+    /// the SDK then only references them by UUID.
+    pub object_store: Option<PathBuf>,
+    /// The sources symbols are read from in order of priority.  When
+    /// several sources name the same address the first one wins and
+    /// sources that are not listed are not read.
+    pub symbol_sources: Vec<SymbolSource>,
+    /// Objects in order of how often they are looked up (for instance from
+    /// exported usage counters).  Their index and symbol strings are laid
+    /// out first so that the hot parts of the memdb share pages.
+    pub hot_objects: Vec<Uuid>,
+    /// Parses every object in a child process of its own instead of in
+    /// the converting process
+    pub sandbox: Option<Sandbox>,
+    /// The largest uncompressed memdb in bytes.  Larger memdbs are
+    /// trimmed until they fit, see `dump_memdb`.
+    pub max_size: Option<u64>,
+    /// Glob patterns of objects that can be dropped to fit `max_size`, in
+    /// the order they are dropped in
+    pub cold_objects: Vec<String>,
 }
 
 impl Default for DumpOptions {
     fn default() -> DumpOptions {
         DumpOptions {
             compress: false,
+            reporter: default_reporter(),
+            scratch_dir: None,
+            journal: None,
+            object_store: None,
+            symbol_sources: SymbolSource::all(),
+            hot_objects: vec![],
+            sandbox: None,
+            max_size: None,
+            cold_objects: vec![],
         }
     }
 }
@@ -47,14 +97,109 @@ fn get_sdk_name_from_folder(folder: &str) -> Option<&'static str> {
     }
 }
 
+fn get_sdk_name_from_os_name(os_name: &str) -> Option<&'static str> {
+    let os_name = os_name.replace('_', " ").split_whitespace()
+        .collect::<Vec<_>>().join(" ").to_lowercase();
+    match &os_name[..] {
+        "iphone os" | "iphoneos" | "ios" | "ipados" => Some("iOS"),
+        "tvos" | "apple tvos" | "appletvos" => Some("tvOS"),
+        "watchos" | "watch os" => Some("watchOS"),
+        "mac os x" | "macosx" | "macos" | "os x" => Some("macOS"),
+        _ => None,
+    }
+}
+
+/// Returns the name SDK ids use for a platform, which is the name itself
+/// for platforms we do not know.
+fn get_canonical_sdk_name(name: &str) -> &str {
+    get_sdk_name_from_os_name(name).unwrap_or(name)
+}
+
+/// Returns a build number with the letter in uppercase (`14c92` becomes
+/// `14C92`).
+///
+/// The suffix of beta builds keeps its case.  Memdbs of betas were named
+/// after it before SDK ids were canonicalized, and changing it would
+/// change the ids of SDKs that are already converted and synced.
+fn get_canonical_build(build: &str) -> String {
+    lazy_static! {
+        static ref LOOSE_BUILD_RE: Regex = Regex::new(
+            r"^(\d+)([a-zA-Z])(\d+)([a-zA-Z])?$").unwrap();
+    }
+    match LOOSE_BUILD_RE.captures(build) {
+        Some(caps) => format!("{}{}{}{}",
+            &caps[1],
+            caps[2].to_uppercase(),
+            &caps[3],
+            caps.get(4).map_or("", |x| x.as_str())),
+        None => build.to_string(),
+    }
+}
+
+fn get_device_family_from_model(model: &str) -> Option<DeviceFamily> {
+    // product types like `iPhone12,1` name the model before the numbers
+    let model = model.trim_end_matches(|c: char| c.is_ascii_digit() || c == ',');
+    match model {
+        "iPhone" => Some(DeviceFamily::IPhone),
+        "iPad" => Some(DeviceFamily::IPad),
+        "iPod" => Some(DeviceFamily::IPod),
+        "AppleTV" => Some(DeviceFamily::AppleTV),
+        "Watch" => Some(DeviceFamily::Watch),
+        _ => None,
+    }
+}
+
+/// The class of devices an SDK was extracted from
+#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Deserialize, Serialize, Hash)]
+pub enum DeviceFamily {
+    #[serde(rename = "iphone")]
+    IPhone,
+    #[serde(rename = "ipad")]
+    IPad,
+    #[serde(rename = "ipod")]
+    IPod,
+    #[serde(rename = "appletv")]
+    AppleTV,
+    #[serde(rename = "watch")]
+    Watch,
+}
+
 /// Information of the SDK
-#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Deserialize, Serialize, Hash)]
+///
+/// The device family and product type are extra metadata and not part of
+/// the identity of the SDK, so they are ignored for comparisons.
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SdkInfo {
     name: String,
     version_major: u32,
     version_minor: u32,
     version_patchlevel: u32,
     build: Option<String>,
+    #[serde(default)]
+    device_family: Option<DeviceFamily>,
+    #[serde(default)]
+    product_type: Option<String>,
+}
+
+/// What the `Info.plist` of a support folder says about the device it
+/// was copied from.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct DeviceInfo {
+    device_class: Option<String>,
+    product_type: Option<String>,
+}
+
+/// A parsed Apple build number (`14C92` or `14A5261v`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildNumber {
+    /// The build train, usually tied to the major version.
+    pub train: u32,
+    /// The update letter within the train.
+    pub letter: char,
+    /// The build number.
+    pub number: u32,
+    /// The optional lowercase suffix.
+    pub suffix: Option<char>,
 }
 
 /// Iterates over all objects in an SDK
@@ -62,6 +207,14 @@ pub struct Objects {
     source: ObjectIterSource,
 }
 
+/// A file of an SDK that may be an object
+pub enum ObjectFile {
+    /// A file read from an archive
+    Data(Vec<u8>),
+    /// A file in a folder
+    Path(PathBuf),
+}
+
 /// Helper struct to process an SDK from the FS or a ZIP
 pub struct Sdk {
     path: PathBuf,
@@ -82,6 +235,90 @@ impl<'a> fmt::Display for Version {
     }
 }
 
+impl DeviceFamily {
+    /// Converts the family into the value stored in memdb headers.
+    pub fn to_u32(self) -> u32 {
+        match self {
+            DeviceFamily::IPhone => 1,
+            DeviceFamily::IPad => 2,
+            DeviceFamily::IPod => 3,
+            DeviceFamily::AppleTV => 4,
+            DeviceFamily::Watch => 5,
+        }
+    }
+
+    /// Converts a value stored in memdb headers back into a family.
+    pub fn from_u32(value: u32) -> Option<DeviceFamily> {
+        match value {
+            1 => Some(DeviceFamily::IPhone),
+            2 => Some(DeviceFamily::IPad),
+            3 => Some(DeviceFamily::IPod),
+            4 => Some(DeviceFamily::AppleTV),
+            5 => Some(DeviceFamily::Watch),
+            _ => None,
+        }
+    }
+
+    /// Returns the family of a product type (`iPhone12,1`) or device
+    /// class (`iPhone`).
+    pub fn from_product_type(product_type: &str) -> Option<DeviceFamily> {
+        get_device_family_from_model(product_type)
+    }
+}
+
+impl fmt::Display for DeviceFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            DeviceFamily::IPhone => "iphone",
+            DeviceFamily::IPad => "ipad",
+            DeviceFamily::IPod => "ipod",
+            DeviceFamily::AppleTV => "appletv",
+            DeviceFamily::Watch => "watch",
+        })
+    }
+}
+
+impl FromStr for DeviceFamily {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DeviceFamily> {
+        match s {
+            "iphone" => Ok(DeviceFamily::IPhone),
+            "ipad" => Ok(DeviceFamily::IPad),
+            "ipod" => Ok(DeviceFamily::IPod),
+            "appletv" => Ok(DeviceFamily::AppleTV),
+            "watch" => Ok(DeviceFamily::Watch),
+            _ => Err(Error::from(format!("Unknown device family '{}'", s))),
+        }
+    }
+}
+
+impl PartialEq for SdkInfo {
+    fn eq(&self, other: &SdkInfo) -> bool {
+        self.sort_key() == other.sort_key()
+    }
+}
+
+impl Eq for SdkInfo {}
+
+impl PartialOrd for SdkInfo {
+    fn partial_cmp(&self, other: &SdkInfo) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SdkInfo {
+    fn cmp(&self, other: &SdkInfo) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+impl Hash for SdkInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sort_key().hash(state)
+    }
+}
+
 impl fmt::Display for SdkInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{} {} ({})", self.name, self.version(), self.build().unwrap_or("UNKNOWN"))
@@ -101,14 +338,79 @@ impl SdkInfo {
             version_minor: version_minor,
             version_patchlevel: version_patchlevel,
             build: build.map(|x| x.to_string()),
+            device_family: None,
+            product_type: None,
         }
     }
 
     /// Load an SDK info from a given filename
     ///
-    /// If the parse cannot be parsed for an SDK info `None` is returned.
+    /// Besides memdb filenames this accepts SDK ids in the forms clients
+    /// send (see `from_sdk_id`).  If the parse cannot be parsed for an SDK
+    /// info `None` is returned.
     pub fn from_filename(filename: &str) -> Option<SdkInfo> {
-        SdkInfo::from_path(Path::new(filename))
+        SdkInfo::from_path(Path::new(filename)).or_else(|| SdkInfo::from_sdk_id(filename))
+    }
+
+    /// Load an SDK info from an SDK id as clients send it
+    ///
+    /// Next to canonical ids (`iOS_10.2.0_14C92`) this understands ids with
+    /// the platform spelled like in crash reports or in lowercase, the
+    /// parts separated by spaces or dashes and missing patchlevels or
+    /// builds: `ios_10.2_14c92`, `iPhone OS 10.2 (14C92)`,
+    /// `watchos-3.1-14S471` and `tvOS_10.1` all work.  Only known platforms
+    /// are accepted and the result is canonicalized.
+    pub fn from_sdk_id(sdk_id: &str) -> Option<SdkInfo> {
+        lazy_static! {
+            static ref SDK_ID_RE: Regex = Regex::new(r"(?x)
+                ^
+                    \s*
+                    ([a-zA-Z][a-zA-Z\x20_]*?)
+                    [\s_-]*
+                    (\d+)(?:\.(\d+))?(?:\.(\d+))?
+                    (?:[\s_-]+\(?([a-zA-Z0-9]+)\)?)?
+                    \s*
+                $
+            ").unwrap();
+        }
+
+        let caps = try_opt!(SDK_ID_RE.captures(sdk_id));
+        let build = caps.get(5).map(|x| get_canonical_build(x.as_str()));
+        Some(SdkInfo::new(
+            try_opt!(get_sdk_name_from_os_name(caps.get(1).unwrap().as_str())),
+            try_opt!(caps.get(2).unwrap().as_str().parse().ok()),
+            try_opt!(caps.get(3).map(|x| x.as_str()).unwrap_or("0").parse().ok()),
+            try_opt!(caps.get(4).map(|x| x.as_str()).unwrap_or("0").parse().ok()),
+            build.as_ref().map(|x| x.as_str()),
+        ))
+    }
+
+    /// Load an SDK info from an OS version as it appears in crash reports
+    ///
+    /// This understands strings like `iPhone OS 16.3.1 (20D67)` or
+    /// `tvOS 10.1`.  If the string cannot be parsed `None` is returned.
+    pub fn from_os_version(os_version: &str) -> Option<SdkInfo> {
+        lazy_static! {
+            static ref OS_VERSION_RE: Regex = Regex::new(r"(?x)
+                ^
+                    \s*
+                    ([a-zA-Z][a-zA-Z\x20]*?)
+                    \s+
+                    (\d+)\.(\d+)(?:\.(\d+))?
+                    (?:\s*\(([a-zA-Z0-9]+)\))?
+                    \s*
+                $
+            ").unwrap();
+        }
+
+        let caps = try_opt!(OS_VERSION_RE.captures(os_version));
+        Some(SdkInfo::new(
+            try_opt!(get_sdk_name_from_os_name(caps.get(1).unwrap().as_str())),
+            try_opt!(caps.get(2).unwrap().as_str().parse().ok()),
+            try_opt!(caps.get(3).unwrap().as_str().parse().ok()),
+            try_opt!(caps.get(4).map(|x| x.as_str()).unwrap_or("0").parse().ok()),
+            caps.get(5).map(|x| x.as_str()),
+        ))
     }
 
     /// Load an SDK info from a given path
@@ -118,6 +420,7 @@ impl SdkInfo {
         lazy_static! {
             static ref SDK_FILENAME_RE: Regex = Regex::new(r"(?x)
                 ^
+                    (?:([a-zA-Z]+\d+,\d+)\s+)?
                     (\d+)\.(\d+)(?:\.(\d+))?
                     \s+
                     \(([a-zA-Z0-9]+)\)
@@ -137,24 +440,30 @@ impl SdkInfo {
         let p = path.as_ref();
         let filename = try_opt!(p.file_name().and_then(|x| x.to_str()));
         if let Some(caps) = MEMDB_FILENAME_RE.captures(filename) {
+            let build = caps.get(5).map(|x| get_canonical_build(x.as_str()));
             return Some(SdkInfo::new(
-                caps.get(1).unwrap().as_str(),
+                get_canonical_sdk_name(caps.get(1).unwrap().as_str()),
                 try_opt!(caps.get(2).unwrap().as_str().parse().ok()),
                 try_opt!(caps.get(3).unwrap().as_str().parse().ok()),
                 try_opt!(caps.get(4).map(|x| x.as_str()).unwrap_or("0").parse().ok()),
-                caps.get(5).map(|x| x.as_str()),
+                build.as_ref().map(|x| x.as_str()),
             ));
         }
 
         let folder = try_opt!(p.parent().and_then(|x| x.file_name()).and_then(|x| x.to_str()));
         let caps = try_opt!(SDK_FILENAME_RE.captures(filename));
-        Some(SdkInfo::new(
+        let mut rv = SdkInfo::new(
             try_opt!(get_sdk_name_from_folder(folder)),
-            try_opt!(caps.get(1).unwrap().as_str().parse().ok()),
             try_opt!(caps.get(2).unwrap().as_str().parse().ok()),
-            try_opt!(caps.get(3).map(|x| x.as_str()).unwrap_or("0").parse().ok()),
-            caps.get(4).map(|x| x.as_str()),
-        ))
+            try_opt!(caps.get(3).unwrap().as_str().parse().ok()),
+            try_opt!(caps.get(4).map(|x| x.as_str()).unwrap_or("0").parse().ok()),
+            caps.get(5).map(|x| x.as_str()),
+        );
+        if let Some(product_type) = caps.get(1) {
+            rv.device_family = get_device_family_from_model(product_type.as_str());
+            rv.product_type = Some(product_type.as_str().to_string());
+        }
+        Some(rv)
     }
 
     /// The SDK name (iOS, tvOS etc.)
@@ -187,6 +496,32 @@ impl SdkInfo {
         self.build.as_ref().map(|x| &**x)
     }
 
+    /// The device family the SDK was extracted from if known
+    pub fn device_family(&self) -> Option<DeviceFamily> {
+        self.device_family
+    }
+
+    /// Overrides the device family.
+    pub fn set_device_family(&mut self, device_family: Option<DeviceFamily>) {
+        self.device_family = device_family;
+    }
+
+    /// The product type of the device the SDK was extracted from
+    /// (`iPhone12,1`) if known
+    pub fn product_type(&self) -> Option<&str> {
+        self.product_type.as_ref().map(|x| x.as_str())
+    }
+
+    /// Overrides the product type.
+    pub fn set_product_type(&mut self, product_type: Option<&str>) {
+        self.product_type = product_type.map(|x| x.to_string());
+    }
+
+    fn sort_key(&self) -> (&str, u32, u32, u32, Option<&str>) {
+        (&self.name, self.version_major, self.version_minor,
+         self.version_patchlevel, self.build())
+    }
+
     fn make_id(&self, suffix: &str) -> String {
         if let Some(ref build) = self.build {
             format!("{}_{}.{}.{}_{}{}",
@@ -216,7 +551,52 @@ impl SdkInfo {
         self.make_id(".memdb")
     }
 
+    /// Fills in a filename template like `{name}_{version}_{build}.memdbz`.
+    ///
+    /// The placeholders are `{name}`, `{version}` (`10.2.0`), `{build}`
+    /// (`unknown` if there is none), `{sdk_id}` and `{ext}` (`memdb`, or
+    /// `memdbz` if `compressed` is set).  Returns `None` if the template
+    /// has an unknown placeholder or unbalanced braces.
+    pub fn format_filename(&self, template: &str, compressed: bool) -> Option<String> {
+        let mut rv = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(|c| c == '{' || c == '}') {
+            if rest[start..].starts_with('}') {
+                return None;
+            }
+            rv.push_str(&rest[..start]);
+            let end = start + rest[start..].find('}')?;
+            match &rest[start + 1..end] {
+                "name" => rv.push_str(&self.name),
+                "version" => rv.push_str(&format!("{}.{}.{}", self.version_major,
+                                                  self.version_minor,
+                                                  self.version_patchlevel)),
+                "build" => rv.push_str(self.build().unwrap_or("unknown")),
+                "sdk_id" => rv.push_str(&self.sdk_id()),
+                "ext" => rv.push_str(if compressed { "memdbz" } else { "memdb" }),
+                _ => return None,
+            }
+            rest = &rest[end + 1..];
+        }
+        rv.push_str(rest);
+        Some(rv)
+    }
+
+    /// Returns the parsed Apple build number if there is one.
+    pub fn build_number(&self) -> Option<BuildNumber> {
+        self.build().and_then(BuildNumber::parse)
+    }
+
+    /// Returns `true` if this is a beta build of the SDK.
+    pub fn is_beta(&self) -> bool {
+        self.build_number().map_or(false, |x| x.is_beta())
+    }
+
     /// Checks if this fuzzy matches another sdk.
+    ///
+    /// Returns a score where lower is better and `0` is an exact match.
+    /// The patchlevel weighs more than the distance between the builds and
+    /// betas are ranked behind release builds unless a beta was requested.
     pub fn get_fuzzy_match(&self, other: &SdkInfo) -> Option<u32> {
         // this is the minimum match we require
         if !(self.name == other.name &&
@@ -224,13 +604,72 @@ impl SdkInfo {
              self.version_minor == other.version_minor) {
             return None;
         }
+
+        let mut score = 0;
         if self.version_patchlevel != other.version_patchlevel {
-            return Some(2);
+            let diff = if self.version_patchlevel > other.version_patchlevel {
+                self.version_patchlevel - other.version_patchlevel
+            } else {
+                other.version_patchlevel - self.version_patchlevel
+            };
+            score += SCORE_PATCHLEVEL * diff.min(MAX_PATCHLEVEL_DIFF);
         }
         if self.build != other.build {
-            return Some(1);
+            score += match (self.build_number(), other.build_number()) {
+                (Some(a), Some(b)) => a.distance(&b),
+                _ => SCORE_BUILD_MISMATCH,
+            };
+        }
+        if self.is_beta() && !other.is_beta() {
+            score += SCORE_BETA;
+        }
+        Some(score)
+    }
+}
+
+impl BuildNumber {
+    /// Parses an Apple build number like `14C92` or `14A5261v`.
+    pub fn parse(build: &str) -> Option<BuildNumber> {
+        lazy_static! {
+            static ref BUILD_RE: Regex = Regex::new(
+                r"^(\d+)([A-Z])(\d+)([a-z])?$").unwrap();
+        }
+        let caps = try_opt!(BUILD_RE.captures(build));
+        Some(BuildNumber {
+            train: try_opt!(caps.get(1).unwrap().as_str().parse().ok()),
+            letter: caps.get(2).unwrap().as_str().as_bytes()[0] as char,
+            number: try_opt!(caps.get(3).unwrap().as_str().parse().ok()),
+            suffix: caps.get(4).map(|x| x.as_str().as_bytes()[0] as char),
+        })
+    }
+
+    /// Returns `true` if this looks like a beta build.
+    ///
+    /// Apple numbers betas upwards of 5000 and usually adds a lowercase
+    /// suffix (`14A5261v`).
+    pub fn is_beta(&self) -> bool {
+        self.number >= 5000 && self.suffix.is_some()
+    }
+
+    /// Returns how far apart two builds are.
+    ///
+    /// Builds of the same train and letter are as far apart as their
+    /// numbers, otherwise they are a fixed large distance apart.
+    pub fn distance(&self, other: &BuildNumber) -> u32 {
+        if self.train != other.train || self.letter != other.letter {
+            return SCORE_BUILD_MISMATCH;
+        }
+        let diff = if self.number > other.number {
+            self.number - other.number
+        } else {
+            other.number - self.number
+        };
+        let diff = diff.min(SCORE_BUILD_MISMATCH - 2);
+        if self.suffix != other.suffix {
+            diff + 1
+        } else {
+            diff
         }
-        Some(0)
     }
 }
 
@@ -290,30 +729,13 @@ impl Objects {
     }
 }
 
-impl<'a> Iterator for Objects {
-    type Item = Result<(usize, String, Object<'static>)>;
-
-    fn next(&mut self) -> Option<Result<(usize, String, Object<'static>)>> {
+impl Objects {
+    /// Returns the next file of the SDK without parsing it.
+    ///
+    /// Along with the file comes the number of entries of the source that
+    /// were consumed for it, which drives the progress bar.
+    pub fn next_file(&mut self) -> Option<Result<(usize, String, ObjectFile)>> {
         let mut offset = 0;
-
-        macro_rules! try_return_obj {
-            ($expr:expr, $name:expr) => {
-                match $expr {
-                    Ok(rv) => {
-                        return Some(Ok((offset, format!("/{}", $name), rv)));
-                    }
-                    Err(err) => {
-                        if let &ErrorKind::MachO(ref mach_err) = err.kind() {
-                            if let &MachError::LoadError(_) = mach_err {
-                                continue;
-                            }
-                        }
-                        return Some(Err(err.into()));
-                    }
-                }
-            }
-        }
-
         loop {
             offset += 1;
             match self.source {
@@ -325,8 +747,8 @@ impl<'a> Iterator for Objects {
                     *idx += 1;
                     let mut buf : Vec<u8> = vec![];
                     if iter_try!(f.read_to_end(&mut buf)) > 0 {
-                        try_return_obj!(Object::from_vec(buf),
-                            strip_archive_file_prefix(f.name()));
+                        let name = format!("/{}", strip_archive_file_prefix(f.name()));
+                        return Some(Ok((offset, name, ObjectFile::Data(buf))));
                     }
                 }
                 ObjectIterSource::Dir { ref path, ref mut dir_iter } => {
@@ -337,9 +759,9 @@ impl<'a> Iterator for Objects {
                             let prefix = path.join("Symbols");
                             let rp = dent.path().strip_prefix(&prefix)
                                 .unwrap_or(dent.path());
-                            try_return_obj!(
-                                Object::from_path(dent.path()),
-                                rp.display());
+                            let name = format!("/{}", rp.display());
+                            return Some(Ok((offset, name,
+                                            ObjectFile::Path(dent.path().to_path_buf()))));
                         }
                     } else {
                         break;
@@ -351,13 +773,124 @@ impl<'a> Iterator for Objects {
     }
 }
 
+impl ObjectFile {
+    /// Parses the file as an object.
+    pub fn parse(self) -> Result<Object<'static>> {
+        match self {
+            ObjectFile::Data(buf) => Object::from_vec(buf),
+            ObjectFile::Path(path) => Object::from_path(path),
+        }
+    }
+
+    /// Returns the contents of the file.
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        match self {
+            ObjectFile::Data(buf) => Ok(buf),
+            ObjectFile::Path(path) => {
+                let mut buf = vec![];
+                fs::File::open(path)?.read_to_end(&mut buf)?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Objects {
+    type Item = Result<(usize, String, Object<'static>)>;
+
+    fn next(&mut self) -> Option<Result<(usize, String, Object<'static>)>> {
+        // files that are not objects count towards the next object
+        let mut skipped = 0;
+        loop {
+            let (offset, name, file) = iter_try!(self.next_file()?);
+            match file.parse() {
+                Ok(obj) => {
+                    return Some(Ok((skipped + offset, name, obj)));
+                }
+                Err(err) => {
+                    if let ErrorKind::MachO(MachError::LoadError(_)) = *err.kind() {
+                        skipped += offset;
+                        continue;
+                    }
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Reads the `Info.plist` next to the `Symbols` folder of an SDK.
+///
+/// Only XML property lists are understood, the device class and product
+/// type are the values `ideviceinfo` reports as `DeviceClass` and
+/// `ProductType`.
+fn read_device_info(path: &Path) -> Result<Option<DeviceInfo>> {
+    lazy_static! {
+        static ref PLIST_STRING_RE: Regex = Regex::new(
+            r"<key>\s*([A-Za-z]+)\s*</key>\s*<string>\s*([^<]*?)\s*</string>").unwrap();
+    }
+
+    let mut contents = String::new();
+    if path.is_dir() {
+        match fs::File::open(path.join("Info.plist")) {
+            Ok(mut f) => { f.read_to_string(&mut contents).ok(); }
+            Err(_) => return Ok(None),
+        }
+    } else {
+        // the support folder is either the root of the archive or the
+        // folder named like the archive
+        let mut archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+        let mut names = vec!["Info.plist".to_string()];
+        if let Some(stem) = path.file_stem().and_then(|x| x.to_str()) {
+            names.push(format!("{}/Info.plist", stem));
+        }
+        let mut found = false;
+        for name in names {
+            if let Ok(mut f) = archive.by_name(&name) {
+                f.read_to_string(&mut contents).ok();
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            return Ok(None);
+        }
+    }
+
+    let mut rv = DeviceInfo::default();
+    for caps in PLIST_STRING_RE.captures_iter(&contents) {
+        let value = Some(caps[2].to_string()).filter(|x| !x.is_empty());
+        match &caps[1] {
+            "DeviceClass" => rv.device_class = value,
+            "ProductType" => rv.product_type = value,
+            _ => {}
+        }
+    }
+    Ok(if rv == DeviceInfo::default() { None } else { Some(rv) })
+}
+
 impl Sdk {
     /// Constructs a processor from a file system path
+    ///
+    /// The device family and product type come from the `Info.plist` of
+    /// the support folder if it has one and from the folder name
+    /// otherwise.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Sdk> {
         let p = path.as_ref().to_path_buf();
-        let sdk_info = SdkInfo::from_path(&p).ok_or_else(|| {
+        let mut sdk_info = SdkInfo::from_path(&p).ok_or_else(|| {
             Error::from(ErrorKind::UnknownSdk)
         })?;
+        if let Some(device_info) = read_device_info(&p)? {
+            let family = device_info.device_class.as_ref()
+                .or_else(|| device_info.product_type.as_ref())
+                .and_then(|x| get_device_family_from_model(x));
+            if family.is_some() {
+                sdk_info.set_device_family(family);
+            }
+            if device_info.product_type.is_some() {
+                sdk_info.product_type = device_info.product_type;
+            }
+        }
         Ok(Sdk {
             path: p,
             info: sdk_info,
@@ -369,6 +902,16 @@ impl Sdk {
         &self.info
     }
 
+    /// Overrides the device family derived from the path.
+    pub fn set_device_family(&mut self, device_family: Option<DeviceFamily>) {
+        self.info.set_device_family(device_family);
+    }
+
+    /// Overrides the product type derived from the path.
+    pub fn set_product_type(&mut self, product_type: Option<&str>) {
+        self.info.set_product_type(product_type);
+    }
+
     /// Returns an object iterator
     pub fn objects<'a>(&'a self) -> Result<Objects> {
         Ok(Objects {
@@ -383,4 +926,13 @@ impl Sdk {
         dump_memdb(writer, self.info(), opts, self.objects()?)?;
         Ok(())
     }
+
+    /// Writes a memdb file for the SDK to a writer that cannot seek
+    ///
+    /// The memdb is assembled in the scratch folder and copied to the
+    /// writer at the end.
+    pub fn dump_memdb_to_stream<W: Write>(&self, writer: W, opts: DumpOptions) -> Result<()> {
+        dump_memdb_to_stream(writer, self.info(), opts, self.objects()?)?;
+        Ok(())
+    }
 }
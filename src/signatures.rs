@@ -0,0 +1,186 @@
+//! Checks signatures of the SDKs in the bucket.
+//!
+//! An SDK is signed by uploading the detached signature of its compressed
+//! memdb next to it as `<filename>.sig`, for instance made with
+//! `openssl dgst -sha256 -sign key.pem -out iOS_10.2.0_14C92.memdbz.sig
+//! iOS_10.2.0_14C92.memdbz`.  With `sync.trusted_keys` set the sync checks
+//! signatures against these public keys (RSA or EC) while it downloads and
+//! refuses SDKs whose signature does not match any of them.  With
+//! `sync.require_signatures` SDKs without a signature are refused too.
+//!
+//! Only signatures OpenSSL makes are supported.  minisign and GPG are out
+//! of scope: minisign needs Ed25519, which the OpenSSL bindings the server
+//! builds against do not offer, and GPG would need a keyring of its own.
+//! Trusted keys in the minisign format are refused with an error that
+//! says so instead of failing to parse.
+//!
+//! Checking signatures needs the `crypto` feature.  Builds without it
+//! refuse `sync.trusted_keys`.
+#[cfg(feature = "crypto")]
+use std::fs;
+use std::io::{self, Read};
+
+#[cfg(feature = "crypto")]
+use openssl::hash::MessageDigest;
+#[cfg(feature = "crypto")]
+use openssl::pkey::PKey;
+#[cfg(feature = "crypto")]
+use openssl::sign::Verifier;
+
+use super::config::Config;
+use super::sdk::SdkInfo;
+use super::{ErrorKind, Result};
+#[cfg(feature = "crypto")]
+use super::ResultExt;
+
+/// The first line of minisign keys and signatures
+#[cfg(feature = "crypto")]
+const MINISIGN_COMMENT: &[u8] = b"untrusted comment:";
+
+#[cfg(feature = "crypto")]
+type TrustedKeys = Vec<PKey>;
+#[cfg(not(feature = "crypto"))]
+type TrustedKeys = ();
+
+/// The keys SDKs are checked against.
+#[derive(Default)]
+pub struct SignaturePolicy {
+    keys: TrustedKeys,
+    required: bool,
+}
+
+/// A reader that checks the signature of what passes through it.
+pub struct SignedReader<'a, R: Read> {
+    inner: R,
+    #[cfg(feature = "crypto")]
+    verifiers: Vec<Verifier<'a>>,
+    policy: &'a SignaturePolicy,
+}
+
+#[cfg(feature = "crypto")]
+fn load_trusted_keys(config: &Config) -> Result<TrustedKeys> {
+    let mut keys = vec![];
+    for path in config.get_sync_trusted_keys() {
+        let mut pem = vec![];
+        fs::File::open(&path)
+            .and_then(|mut f| f.read_to_end(&mut pem))
+            .chain_err(|| format!("Could not read trusted key {}", path.display()))?;
+        if pem.starts_with(MINISIGN_COMMENT) {
+            return Err(format!("{} is a minisign key, which is not supported",
+                               path.display()).into());
+        }
+        keys.push(PKey::public_key_from_pem(&pem)
+            .chain_err(|| format!("{} is not a PEM encoded public key", path.display()))?);
+    }
+    Ok(keys)
+}
+
+#[cfg(not(feature = "crypto"))]
+fn load_trusted_keys(config: &Config) -> Result<TrustedKeys> {
+    if !config.get_sync_trusted_keys().is_empty() {
+        return Err(ErrorKind::BadConfigKey(
+            "sync.trusted_keys", "Signatures need a build with the crypto feature").into());
+    }
+    Ok(())
+}
+
+impl SignaturePolicy {
+    /// Loads the trusted keys of the config.
+    ///
+    /// A server that follows a primary trusts it and does not check
+    /// signatures itself, so it cannot require them either.
+    pub fn from_config(config: &Config) -> Result<SignaturePolicy> {
+        if config.get_sync_primary()?.is_some() {
+            if config.get_sync_require_signatures()? {
+                return Err(ErrorKind::BadConfigKey(
+                    "sync.require_signatures",
+                    "A server following a primary leaves signatures to the primary").into());
+            }
+            return Ok(SignaturePolicy::default());
+        }
+        Ok(SignaturePolicy {
+            keys: load_trusted_keys(config)?,
+            required: config.get_sync_require_signatures()?,
+        })
+    }
+
+    /// Returns `true` if signatures are checked at all.
+    #[cfg(feature = "crypto")]
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Returns `true` if signatures are checked at all.
+    #[cfg(not(feature = "crypto"))]
+    pub fn is_enabled(&self) -> bool {
+        false
+    }
+
+    /// Wraps a reader to check the signature of the data read from it.
+    #[cfg(feature = "crypto")]
+    pub fn reader<R: Read>(&self, inner: R) -> Result<SignedReader<R>> {
+        let mut verifiers = vec![];
+        for key in &self.keys {
+            verifiers.push(Verifier::new(MessageDigest::sha256(), key)?);
+        }
+        Ok(SignedReader { inner, verifiers, policy: self })
+    }
+
+    /// Wraps a reader to check the signature of the data read from it.
+    #[cfg(not(feature = "crypto"))]
+    pub fn reader<R: Read>(&self, inner: R) -> Result<SignedReader<R>> {
+        Ok(SignedReader { inner, policy: self })
+    }
+}
+
+impl<'a, R: Read> SignedReader<'a, R> {
+    /// Checks the signature once everything was read.
+    pub fn check(&self, info: &SdkInfo, signature: Option<&[u8]>) -> Result<()> {
+        if !self.policy.is_enabled() {
+            return Ok(());
+        }
+        let signature = match signature {
+            Some(signature) => signature,
+            None if self.policy.required => {
+                return Err(ErrorKind::BadSignature(info.sdk_id(), "not signed").into());
+            }
+            None => {
+                warn!("{} is not signed", info);
+                return Ok(());
+            }
+        };
+        if self.verify(signature) {
+            return Ok(());
+        }
+        Err(ErrorKind::BadSignature(info.sdk_id(), "no trusted key matches").into())
+    }
+
+    #[cfg(feature = "crypto")]
+    fn verify(&self, signature: &[u8]) -> bool {
+        self.verifiers.iter().any(|verifier| verifier.verify(signature).unwrap_or(false))
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn verify(&self, _signature: &[u8]) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl<'a, R: Read> Read for SignedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        for verifier in &mut self.verifiers {
+            verifier.update(&buf[..read])
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl<'a, R: Read> Read for SignedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
@@ -0,0 +1,115 @@
+//! A minimal statsd client.
+//!
+//! Metrics are sent as UDP datagrams in the DogStatsD flavor of the
+//! protocol, which adds tags.  Sending never blocks and errors are only
+//! logged: losing a metric is better than slowing down a request.
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use super::config::Config;
+use super::{Result, ResultExt};
+
+/// Sends metrics to a statsd server
+pub struct StatsdClient {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    prefix: String,
+    tags: Vec<String>,
+}
+
+impl StatsdClient {
+    /// Creates a client that sends to the given address (`host:port`).
+    ///
+    /// Every metric is prefixed with `prefix` and carries `tags`
+    /// (`key:value`).
+    pub fn new(addr: &str, prefix: &str, tags: Vec<String>) -> Result<StatsdClient> {
+        let addr = addr.to_socket_addrs()
+            .chain_err(|| "Invalid statsd address")?
+            .next()
+            .ok_or("Statsd address does not resolve")?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdClient {
+            socket,
+            addr,
+            prefix: prefix.trim_end_matches('.').to_string(),
+            tags,
+        })
+    }
+
+    /// Creates a client from the config if a statsd server is configured.
+    pub fn from_config(config: &Config) -> Result<Option<StatsdClient>> {
+        match config.get_statsd_addr() {
+            Some(addr) => Ok(Some(StatsdClient::new(
+                &addr, &config.get_statsd_prefix(), config.get_statsd_tags())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds to a counter.
+    pub fn count(&self, metric: &str, value: i64, tags: &[(&str, &str)]) {
+        self.send(metric, &value.to_string(), "c", tags);
+    }
+
+    /// Records how long something took.
+    pub fn timing(&self, metric: &str, duration: Duration, tags: &[(&str, &str)]) {
+        let ms = duration.as_secs() * 1000 + u64::from(duration.subsec_nanos()) / 1_000_000;
+        self.send(metric, &ms.to_string(), "ms", tags);
+    }
+
+    /// Sets a gauge.
+    pub fn gauge(&self, metric: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(metric, &value.to_string(), "g", tags);
+    }
+
+    fn format(&self, metric: &str, value: &str, ty: &str, tags: &[(&str, &str)]) -> String {
+        let mut rv = String::new();
+        if !self.prefix.is_empty() {
+            rv.push_str(&self.prefix);
+            rv.push('.');
+        }
+        rv.push_str(&format!("{}:{}|{}", metric, value, ty));
+        let mut all_tags = self.tags.clone();
+        all_tags.extend(tags.iter().map(|&(key, value)| {
+            format!("{}:{}", key, sanitize_tag_value(value))
+        }));
+        if !all_tags.is_empty() {
+            rv.push_str("|#");
+            rv.push_str(&all_tags.join(","));
+        }
+        rv
+    }
+
+    fn send(&self, metric: &str, value: &str, ty: &str, tags: &[(&str, &str)]) {
+        let msg = self.format(metric, value, ty, tags);
+        if let Err(err) = self.socket.send_to(msg.as_bytes(), &self.addr) {
+            debug!("could not send metric {}: {}", metric, err);
+        }
+    }
+}
+
+/// Replaces the characters that delimit tags and metrics in a tag value.
+fn sanitize_tag_value(value: &str) -> String {
+    value.replace(|c| c == ',' || c == '|' || c == '#' || c == ':' || c == '\n', "_")
+}
+
+#[test]
+fn test_statsd_client() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let client = StatsdClient::new(&server.local_addr().unwrap().to_string(),
+                                   "symbolserver", vec!["env:test".into()]).unwrap();
+
+    let mut buf = [0u8; 512];
+    client.count("requests", 1, &[("endpoint", "/lookup"), ("status", "200")]);
+    let len = server.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &b"symbolserver.requests:1|c|#env:test,endpoint:/lookup,status:200"[..]);
+
+    client.timing("request.duration", Duration::from_millis(1500), &[]);
+    let len = server.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &b"symbolserver.request.duration:1500|ms|#env:test"[..]);
+
+    client.gauge("sdk.requests", 2, &[("sdk_id", "iOS_1|c,#x:y")]);
+    let len = server.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &b"symbolserver.sdk.requests:2|g|#env:test,sdk_id:iOS_1_c__x_y"[..]);
+}
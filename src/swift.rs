@@ -0,0 +1,216 @@
+//! Swift Metadata Support
+//!
+//! Like for Objective-C the Swift runtime keeps metadata about the types
+//! of an image that survives stripping the symbol table.  The
+//! `__swift5_types` section lists the type descriptors, which carry the
+//! name of the type, its parents and the metadata accessor function.
+//! Non-generic classes also describe their vtable with the implementation
+//! of every method.
+//!
+//! Method names are not part of the metadata, so vtable entries are named
+//! by the type, the kind of method and the slot, for instance
+//! `Foundation.Widget.getter #2`.
+use super::image::MachImage;
+
+/// The parts of a Mach-O image needed to read its Swift metadata.
+pub struct SwiftImage<'a> {
+    image: MachImage<'a>,
+    types: Option<(u64, u64)>,
+}
+
+const KIND_MODULE: u32 = 0;
+const KIND_PROTOCOL: u32 = 3;
+const KIND_CLASS: u32 = 16;
+const KIND_STRUCT: u32 = 17;
+const KIND_ENUM: u32 = 18;
+
+const FLAG_GENERIC: u32 = 0x80;
+const CLASS_HAS_VTABLE: u32 = 0x8000;
+const CLASS_HAS_RESILIENT_SUPERCLASS: u32 = 0x2000;
+const METHOD_IS_INSTANCE: u32 = 0x10;
+
+/// The size of a class descriptor without its trailing objects
+const CLASS_DESCRIPTOR_SIZE: u64 = 44;
+
+/// How deep types can be nested before the name is given up on
+const MAX_NESTING: usize = 16;
+
+impl<'a> SwiftImage<'a> {
+    /// Creates an image from the address and size of its `__swift5_types`
+    /// section.
+    pub fn new(image: MachImage<'a>, types: Option<(u64, u64)>) -> SwiftImage<'a> {
+        SwiftImage {
+            image,
+            types,
+        }
+    }
+
+    /// Returns the symbols for the metadata accessors and vtable methods of
+    /// all types by absolute address.
+    pub fn symbols(&self) -> Vec<(u64, String)> {
+        let mut rv = vec![];
+        let (start, size) = match self.types {
+            Some(types) => types,
+            None => { return rv; }
+        };
+        let mut addr = start;
+        while addr + 4 <= start + size {
+            if let Some(descriptor) = self.read_type_record(addr) {
+                self.add_type_symbols(descriptor, &mut rv);
+            }
+            addr += 4;
+        }
+        rv
+    }
+
+    /// Reads an entry of the types section, which is a relative offset
+    /// with the kind of reference in the lowest bits.
+    fn read_type_record(&self, addr: u64) -> Option<u64> {
+        let offset = self.image.read_u32(addr)? as i32;
+        let target = (addr as i64).wrapping_add(i64::from(offset & !3)) as u64;
+        match offset & 3 {
+            0 => Some(target),
+            1 => self.image.read_ptr(target),
+            _ => None,
+        }
+    }
+
+    /// Reads a relative pointer to a function, which is never indirect.
+    fn read_function(&self, addr: u64) -> Option<u64> {
+        match self.image.read_u32(addr)? {
+            0 => None,
+            _ => self.image.read_rel(addr),
+        }
+    }
+
+    /// Returns the fully qualified name of a context descriptor.
+    fn full_name(&self, descriptor: u64) -> Option<String> {
+        let mut parts = vec![];
+        let mut context = Some(descriptor);
+        while let Some(addr) = context {
+            if parts.len() >= MAX_NESTING {
+                return None;
+            }
+            let kind = self.image.read_u32(addr)? & 0x1f;
+            match kind {
+                KIND_MODULE | KIND_PROTOCOL | KIND_CLASS | KIND_STRUCT | KIND_ENUM => {
+                    parts.push(self.image.read_cstr(self.image.read_rel(addr + 8)?)?);
+                }
+                // extensions and anonymous contexts do not add to the name
+                _ => {}
+            }
+            context = self.image.read_rel_indirect(addr + 4);
+        }
+        parts.reverse();
+        Some(parts.join("."))
+    }
+
+    fn add_type_symbols(&self, descriptor: u64, rv: &mut Vec<(u64, String)>) {
+        let flags = match self.image.read_u32(descriptor) {
+            Some(flags) => flags,
+            None => { return; }
+        };
+        let kind = flags & 0x1f;
+        if kind != KIND_CLASS && kind != KIND_STRUCT && kind != KIND_ENUM {
+            return;
+        }
+        let name = match self.full_name(descriptor) {
+            Some(name) => name,
+            None => { return; }
+        };
+        if let Some(accessor) = self.read_function(descriptor + 12) {
+            rv.push((accessor, format!("type metadata accessor for {}", name)));
+        }
+        if kind == KIND_CLASS && flags & FLAG_GENERIC == 0 {
+            self.add_vtable_symbols(descriptor, flags >> 16, &name, rv);
+        }
+    }
+
+    fn add_vtable_symbols(&self, descriptor: u64, class_flags: u32, name: &str,
+                          rv: &mut Vec<(u64, String)>) {
+        if class_flags & CLASS_HAS_VTABLE == 0 {
+            return;
+        }
+        let mut header = descriptor + CLASS_DESCRIPTOR_SIZE;
+        if class_flags & CLASS_HAS_RESILIENT_SUPERCLASS != 0 {
+            header += 4;
+        }
+        header += match class_flags & 3 {
+            1 => 12,    // singleton metadata initialization
+            2 => 4,     // foreign metadata initialization
+            _ => 0,
+        };
+        let count = match self.image.read_u32(header + 4) {
+            Some(count) => u64::from(count),
+            None => { return; }
+        };
+        for idx in 0..count {
+            let method = header + 8 + idx * 8;
+            let flags = match self.image.read_u32(method) {
+                Some(flags) => flags,
+                None => { return; }
+            };
+            let imp = match self.read_function(method + 4) {
+                Some(imp) => imp,
+                None => continue,
+            };
+            let method_kind = match flags & 0x0f {
+                0 => "method",
+                1 => "init",
+                2 => "getter",
+                3 => "setter",
+                4 => "modify",
+                5 => "read",
+                _ => continue,
+            };
+            let prefix = if flags & METHOD_IS_INSTANCE == 0 && method_kind != "init" {
+                "static "
+            } else {
+                ""
+            };
+            rv.push((imp, format!("{}{}.{} #{}", prefix, name, method_kind, idx)));
+        }
+    }
+}
+
+#[test]
+fn test_swift_symbols() {
+    use super::image::Segment;
+
+    // a module with a class that has a vtable of two methods, laid out in
+    // a single segment mapped at 0x1000
+    let mut data = vec![0u8; 0x200];
+    {
+        let mut put = |offset: usize, value: i64| {
+            for i in 0..4 {
+                data[offset + i] = (value >> (i * 8)) as u8;
+            }
+        };
+        put(0x00, 0x40);                            // types: class descriptor
+        put(0x10, 0);                               // module: flags
+        put(0x18, 0x180 - 0x18);                    // module: name
+        put(0x40, (0x8000 << 16) | 16);             // class: flags
+        put(0x44, 0x10 - 0x44);                     // class: parent
+        put(0x48, 0x190 - 0x48);                    // class: name
+        put(0x4c, 0x1000 - 0x4c);                   // class: accessor at 0x2000
+        put(0x40 + 44 + 4, 2);                      // vtable: size
+        put(0x40 + 44 + 8, 0x10 | 2);               // getter
+        put(0x40 + 44 + 12, 0x1100 - (0x40 + 44 + 12));
+        put(0x40 + 44 + 16, 0);                     // static method
+        put(0x40 + 44 + 20, 0x1200 - (0x40 + 44 + 20));
+    }
+    data[0x180..0x184].copy_from_slice(b"Kit\0");
+    data[0x190..0x197].copy_from_slice(b"Widget\0");
+
+    let image = SwiftImage::new(MachImage::new(&data, true, vec![Segment {
+        vmaddr: 0x1000,
+        vmsize: 0x200,
+        fileoff: 0,
+        filesize: 0x200,
+    }]), Some((0x1000, 4)));
+    assert_eq!(image.symbols(), vec![
+        (0x2000, "type metadata accessor for Kit.Widget".to_string()),
+        (0x2100, "Kit.Widget.getter #0".to_string()),
+        (0x2200, "static Kit.Widget.method #1".to_string()),
+    ]);
+}
@@ -0,0 +1,412 @@
+//! Helpers for testing the sync against a local S3 replacement.
+//!
+//! This is only compiled with the `testing` feature.  `MockS3` is a small
+//! HTTP server that understands the two S3 calls the stash makes (listing
+//! a bucket and fetching an object) and serves objects from memory.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use hyper::server::{Server, Request, Response, Listening};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use hyper::header::{ByteRangeSpec, ContentLength, ETag, EntityTag, IfRange, Range};
+use md5;
+#[cfg(feature = "crypto")]
+use openssl::hash::MessageDigest;
+#[cfg(feature = "crypto")]
+use openssl::pkey::PKey;
+#[cfg(feature = "crypto")]
+use openssl::sign::Signer;
+use tempdir::TempDir;
+use url::Url;
+use walkdir::WalkDir;
+use xz2::write::{XzDecoder, XzEncoder};
+use zip::{CompressionMethod, ZipWriter};
+
+use super::Result;
+use super::config::Config;
+use super::memdb::stash::MemDbStash;
+use super::sdk::{Sdk, SdkInfo, DumpOptions};
+
+const BUCKET: &str = "symbols";
+const PREFIX: &str = "memdbs";
+
+/// A way in which the mock S3 misbehaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockFault {
+    /// Rejects all requests as if the clock was skewed.
+    ClockSkew,
+    /// Returns listings that claim to be truncated but contain nothing.
+    TruncatedListing,
+    /// Takes half a second before sending an object.
+    SlowDownloads,
+    /// Sends only the first half of every object, like a proxy that cut
+    /// the download off.
+    TruncatedDownloads,
+}
+
+/// An in-memory S3 bucket served over HTTP.
+pub struct MockS3 {
+    addr: SocketAddr,
+    objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    fault: Arc<Mutex<Option<MockFault>>>,
+    listening: Option<Listening>,
+}
+
+fn object_etag(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn list_objects(objects: &BTreeMap<String, Vec<u8>>, prefix: &str) -> String {
+    let mut rv = String::new();
+    rv.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    rv.push_str("<ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">");
+    rv.push_str(&format!("<Name>{}</Name><Prefix>{}</Prefix>", BUCKET, xml_escape(prefix)));
+    rv.push_str("<Marker></Marker><MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>");
+    for (key, data) in objects.iter().filter(|&(key, _)| key.starts_with(prefix)) {
+        rv.push_str(&format!(
+            "<Contents><Key>{}</Key><LastModified>2017-01-01T00:00:00.000Z</LastModified>\
+             <ETag>&quot;{}&quot;</ETag><Size>{}</Size>\
+             <StorageClass>STANDARD</StorageClass></Contents>",
+            xml_escape(key), object_etag(data), data.len()));
+    }
+    rv.push_str("</ListBucketResult>");
+    rv
+}
+
+fn truncated_listing(prefix: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+             <Name>{}</Name><Prefix>{}</Prefix><Marker></Marker><MaxKeys>1000</MaxKeys>\
+             <IsTruncated>true</IsTruncated></ListBucketResult>", BUCKET, xml_escape(prefix))
+}
+
+fn handle(objects: &Mutex<BTreeMap<String, Vec<u8>>>, fault: &Mutex<Option<MockFault>>,
+          mut req: Request, mut resp: Response) {
+    let url = match req.uri {
+        RequestUri::AbsolutePath(ref path) => Url::parse(&format!("http://localhost{}", path)).ok(),
+        _ => None,
+    };
+    let url = match url {
+        Some(url) => url,
+        None => {
+            *resp.status_mut() = StatusCode::BadRequest;
+            return;
+        }
+    };
+
+    let fault = *fault.lock().unwrap();
+    let path = url.path().trim_start_matches('/');
+    if fault == Some(MockFault::SlowDownloads) && req.method == Method::Get && path != BUCKET {
+        thread::sleep(Duration::from_millis(500));
+    }
+    let mut objects = objects.lock().unwrap();
+    let body = if fault == Some(MockFault::ClockSkew) {
+        *resp.status_mut() = StatusCode::Forbidden;
+        b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+          <Error><Code>RequestTimeTooSkewed</Code><Message>The difference between \
+          the request time and the current time is too large.</Message></Error>".to_vec()
+    } else if req.method == Method::Put {
+        let mut data = vec![];
+        if req.read_to_end(&mut data).is_err() {
+            *resp.status_mut() = StatusCode::BadRequest;
+            return;
+        }
+        let key = path.splitn(2, '/').nth(1).unwrap_or("").to_string();
+        resp.headers_mut().set(ETag(EntityTag::strong(object_etag(&data))));
+        objects.insert(key, data);
+        vec![]
+    } else if req.method == Method::Delete {
+        objects.remove(path.splitn(2, '/').nth(1).unwrap_or(""));
+        *resp.status_mut() = StatusCode::NoContent;
+        vec![]
+    } else if path == BUCKET {
+        let prefix = url.query_pairs()
+            .find(|&(ref k, _)| k == "prefix")
+            .map(|(_, v)| v.into_owned())
+            .unwrap_or_else(String::new);
+        if fault == Some(MockFault::TruncatedListing) {
+            truncated_listing(&prefix).into_bytes()
+        } else {
+            list_objects(&objects, &prefix).into_bytes()
+        }
+    } else if let Some(data) = path.splitn(2, '/').nth(1).and_then(|key| objects.get(key)) {
+        let etag = EntityTag::strong(object_etag(data));
+        // ranges of another version of the object get the whole object
+        let unchanged = match req.headers.get::<IfRange>() {
+            Some(&IfRange::EntityTag(ref tag)) => tag.strong_eq(&etag),
+            Some(&IfRange::Date(_)) => false,
+            None => true,
+        };
+        resp.headers_mut().set(ETag(etag));
+        match req.headers.get::<Range>() {
+            Some(&Range::Bytes(ref specs)) if specs.len() == 1 && unchanged => {
+                match specs[0] {
+                    ByteRangeSpec::AllFrom(start) if (start as usize) < data.len() => {
+                        *resp.status_mut() = StatusCode::PartialContent;
+                        data[start as usize..].to_vec()
+                    }
+                    _ => data.clone(),
+                }
+            }
+            _ => data.clone(),
+        }
+    } else {
+        *resp.status_mut() = StatusCode::NotFound;
+        b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+          <Error><Code>NoSuchKey</Code><Message>Not found</Message></Error>".to_vec()
+    };
+    let body = if fault == Some(MockFault::TruncatedDownloads) && req.method == Method::Get &&
+                  path != BUCKET && resp.status().is_success() {
+        &body[..body.len() / 2]
+    } else {
+        &body[..]
+    };
+    resp.headers_mut().set(ContentLength(body.len() as u64));
+    resp.send(body).ok();
+}
+
+impl MockS3 {
+    /// Starts a new empty mock S3 server on a random local port.
+    pub fn start() -> Result<MockS3> {
+        let objects = Arc::new(Mutex::new(BTreeMap::new()));
+        let fault = Arc::new(Mutex::new(None));
+        let handler_objects = objects.clone();
+        let handler_fault = fault.clone();
+        let listening = Server::http("127.0.0.1:0")?.handle_threads(
+            move |req: Request, resp: Response| {
+                handle(&handler_objects, &handler_fault, req, resp)
+            }, 2)?;
+        Ok(MockS3 {
+            addr: listening.socket,
+            objects,
+            fault,
+            listening: Some(listening),
+        })
+    }
+
+    /// Returns a config that syncs from this server into `symbol_dir`.
+    pub fn config<P: AsRef<Path>>(&self, symbol_dir: P) -> Config {
+        make_config(&format!("http://{}", self.addr), symbol_dir.as_ref())
+    }
+
+    /// Returns the same config as `config` as JSON for `--config-json`.
+    pub fn config_json<P: AsRef<Path>>(&self, symbol_dir: P) -> String {
+        json!({
+            "aws": {
+                "endpoint": format!("http://{}", self.addr),
+                "access_key": "access-key",
+                "secret_key": "secret-key",
+                "bucket_url": format!("s3://{}/{}", BUCKET, PREFIX)
+            },
+            "symbol_dir": symbol_dir.as_ref().to_string_lossy().into_owned()
+        }).to_string()
+    }
+
+    /// Returns a config pointing to a port that nothing listens on.
+    ///
+    /// This can be used to test how the stash behaves if S3 is down.
+    pub fn offline_config<P: AsRef<Path>>(&self, symbol_dir: P) -> Result<Config> {
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        Ok(make_config(&format!("http://{}", addr), symbol_dir.as_ref()))
+    }
+
+    /// Returns the URL of an object under a key relative to the bucket
+    /// prefix.  Requests for a range that starts within the object get
+    /// the rest of it.
+    pub fn object_url(&self, key: &str) -> String {
+        format!("http://{}/{}/{}/{}", self.addr, BUCKET, PREFIX, key)
+    }
+
+    /// Stores an object under a key relative to the bucket prefix.
+    pub fn put_object(&self, key: &str, data: Vec<u8>) {
+        self.objects.lock().unwrap().insert(format!("{}/{}", PREFIX, key), data);
+    }
+
+    /// Removes an object.
+    pub fn remove_object(&self, key: &str) {
+        self.objects.lock().unwrap().remove(&format!("{}/{}", PREFIX, key));
+    }
+
+    /// Stores a compressed fixture memdb for an SDK.
+    pub fn put_sdk(&self, info: &SdkInfo) -> Result<()> {
+        self.put_object(&remote_filename(info), fixture_memdb(info)?);
+        Ok(())
+    }
+
+    /// Stores a thin compressed memdb for an SDK along with its shared
+    /// objects and object manifest.
+    pub fn put_thin_sdk(&self, info: &SdkInfo) -> Result<()> {
+        let tmp = TempDir::new("symbolserver-objects")?;
+        let store = tmp.path().join("objects");
+        self.put_object(&remote_filename(info), dump_fixture_sdk(info, Some(&store))?);
+        for entry in WalkDir::new(&store) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let key = entry.path().strip_prefix(tmp.path()).unwrap();
+                let mut data = vec![];
+                fs::File::open(entry.path())?.read_to_end(&mut data)?;
+                self.put_object(&key.to_string_lossy(), data);
+            }
+        }
+        Ok(())
+    }
+
+    /// Signs the stored SDK and stores the signature next to it.
+    #[cfg(feature = "crypto")]
+    pub fn sign_sdk(&self, info: &SdkInfo, key: &PKey) -> Result<()> {
+        let key_path = format!("{}/{}", PREFIX, remote_filename(info));
+        let signature = {
+            let objects = self.objects.lock().unwrap();
+            let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+            signer.update(objects.get(&key_path).ok_or("SDK is not stored")?)?;
+            signer.sign_to_vec()?
+        };
+        self.put_object(&format!("{}.sig", remote_filename(info)), signature);
+        Ok(())
+    }
+
+    /// Stores a compressed file for an SDK that is not a valid memdb.
+    pub fn put_corrupted_sdk(&self, info: &SdkInfo) -> Result<()> {
+        let mut encoder = XzEncoder::new(vec![], 6);
+        encoder.write_all(b"this is not a memdb")?;
+        self.put_object(&remote_filename(info), encoder.finish()?);
+        Ok(())
+    }
+
+    /// Stores a compressed memdb for an SDK that misses its last `missing`
+    /// bytes.
+    pub fn put_truncated_sdk(&self, info: &SdkInfo, missing: usize) -> Result<()> {
+        let mut decoder = XzDecoder::new(vec![]);
+        decoder.write_all(&fixture_memdb(info)?)?;
+        let mut memdb = decoder.finish()?;
+        let len = memdb.len().saturating_sub(missing);
+        memdb.truncate(len);
+        let mut encoder = XzEncoder::new(vec![], 6);
+        encoder.write_all(&memdb)?;
+        self.put_object(&remote_filename(info), encoder.finish()?);
+        Ok(())
+    }
+
+    /// Stores a manifest under a key that lists the compressed memdbs
+    /// currently in the bucket.
+    pub fn put_manifest(&self, key: &str, revision: u64) {
+        let prefix = format!("{}/", PREFIX);
+        let sdks: Vec<_> = self.objects.lock().unwrap().iter()
+            .filter(|&(key, _)| key.starts_with(&prefix) && key.ends_with(".memdbz"))
+            .map(|(key, data)| json!({
+                "filename": &key[prefix.len()..],
+                "size": data.len(),
+                "hash": object_etag(data),
+            }))
+            .collect();
+        let manifest = json!({"revision": revision, "sdks": sdks});
+        self.put_object(key, manifest.to_string().into_bytes());
+    }
+
+    /// Removes the memdb of an SDK.
+    pub fn remove_sdk(&self, info: &SdkInfo) {
+        self.remove_object(&remote_filename(info));
+    }
+
+    /// Makes the server misbehave until the fault is cleared again.
+    pub fn set_fault(&self, fault: Option<MockFault>) {
+        *self.fault.lock().unwrap() = fault;
+    }
+}
+
+impl Drop for MockS3 {
+    fn drop(&mut self) {
+        // hyper cannot shut down a server and dropping the guard would
+        // join the listener threads forever.
+        mem::forget(self.listening.take());
+    }
+}
+
+fn make_config(endpoint: &str, symbol_dir: &Path) -> Config {
+    let mut config = Config::default();
+    config.set_aws_endpoint(endpoint);
+    config.set_aws_credentials("access-key", "secret-key");
+    config.set_aws_bucket_url(&format!("s3://{}/{}", BUCKET, PREFIX));
+    config.set_symbol_dir(symbol_dir);
+    config
+}
+
+fn remote_filename(info: &SdkInfo) -> String {
+    format!("{}z", info.memdb_filename())
+}
+
+/// Starts a mock S3 with fixture memdbs for the SDKs and returns it with
+/// a stash in a temporary folder that synced them.
+pub fn synced_stash(sdks: &[SdkInfo]) -> Result<(MockS3, TempDir, MemDbStash)> {
+    let s3 = MockS3::start()?;
+    for info in sdks.iter() {
+        s3.put_sdk(info)?;
+    }
+    let dir = TempDir::new("symbolserver-test")?;
+    let stash = MemDbStash::new(&s3.config(dir.path()))?;
+    stash.sync(Default::default())?;
+    Ok((s3, dir, stash))
+}
+
+/// Builds a compressed memdb for an SDK from the test fixtures.
+pub fn fixture_memdb(info: &SdkInfo) -> Result<Vec<u8>> {
+    dump_fixture_sdk(info, None)
+}
+
+/// Creates a support folder for an SDK from the test fixtures in a folder
+/// and returns its path.
+pub fn fixture_sdk_folder(path: &Path, info: &SdkInfo) -> Result<PathBuf> {
+    let mut folder = format!("{} DeviceSupport/{}.{}.{}", info.name(), info.version_major(),
+                             info.version_minor(), info.version_patchlevel());
+    if let Some(build) = info.build() {
+        folder.push_str(&format!(" ({})", build));
+    }
+    let sdk_path = path.join(folder);
+    let lib_path = sdk_path.join("Symbols/usr/lib");
+    fs::create_dir_all(&lib_path)?;
+
+    let mut data = vec![];
+    fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/res/libKoreanConverter.dylib"))?
+        .read_to_end(&mut data)?;
+    fs::File::create(lib_path.join("libKoreanConverter.dylib"))?.write_all(&data)?;
+    Ok(sdk_path)
+}
+
+/// Zips a support folder for an SDK from the test fixtures.
+pub fn fixture_sdk_zip() -> Result<Vec<u8>> {
+    let mut data = vec![];
+    fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/res/libKoreanConverter.dylib"))?
+        .read_to_end(&mut data)?;
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    zip.start_file("Symbols/usr/lib/libKoreanConverter.dylib", CompressionMethod::Deflated)?;
+    zip.write_all(&data)?;
+    Ok(zip.finish()?.into_inner())
+}
+
+fn dump_fixture_sdk(info: &SdkInfo, object_store: Option<&Path>) -> Result<Vec<u8>> {
+    let tmp = TempDir::new("symbolserver-sdk")?;
+    let sdk_path = fixture_sdk_folder(tmp.path(), info)?;
+    let mut sdk = Sdk::new(&sdk_path)?;
+    sdk.set_device_family(info.device_family());
+    sdk.set_product_type(info.product_type());
+    let mut rv = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut rv, DumpOptions {
+        compress: true,
+        object_store: object_store.map(|x| x.to_path_buf()),
+        ..Default::default()
+    })?;
+    Ok(rv.into_inner())
+}
@@ -1,18 +1,29 @@
 //! Provides various useful utilities.
 use std::io;
+use std::mem;
 use std::fs;
 use std::fmt;
 use std::env;
 use std::panic;
-use std::os::unix::io::RawFd;
+use std::thread;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::result::Result as StdResult;
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::cmp::Ordering;
 
 use globset;
+use libc;
+use md5;
+use tempdir::TempDir;
 use indicatif::ProgressBar;
 use chrono::Duration;
 use serde::{Serialize, Deserialize, de, ser};
+use uuid::Uuid;
 
 use super::{Result, ResultExt, Error, ErrorKind};
 
@@ -22,6 +33,85 @@ pub const SD_LISTEN_FDS_START: RawFd = 3;
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Addr(pub u64);
 
+/// Helper for deserializing Breakpad style debug identifiers.
+///
+/// Breakpad writes the UUID of a Mach-O image as 32 uppercase hex digits
+/// followed by an age (`FE6D76D48C3A3A9A9F63F4A475501F1B0`) and Sentry
+/// writes it as a UUID with the age appended after a dash.  The age is
+/// always zero for Mach-O images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DebugId(pub Uuid);
+
+impl DebugId {
+    /// Parses a debug identifier in either format.
+    pub fn parse(value: &str) -> Option<DebugId> {
+        parse_uuid(value).map(DebugId)
+    }
+}
+
+/// Parses an object UUID the way clients send it.
+///
+/// Dashes are optional, case does not matter and surrounding braces are
+/// ignored.  Breakpad debug identifiers (the UUID followed by an age of
+/// zero) are accepted as well.
+pub fn parse_uuid(value: &str) -> Option<Uuid> {
+    let value = value.trim();
+    let value = if value.starts_with('{') && value.ends_with('}') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    };
+    if !value.is_ascii() {
+        return None;
+    }
+    let (uuid, age) = if value.len() >= 36 && &value[8..9] == "-" {
+        let age = &value[36..];
+        if !age.is_empty() && !age.starts_with('-') {
+            return None;
+        }
+        (&value[..36], age.trim_start_matches('-'))
+    } else if value.len() >= 32 {
+        (&value[..32], &value[32..])
+    } else {
+        return None;
+    };
+    if !age.is_empty() && u32::from_str_radix(age, 16).ok()? != 0 {
+        return None;
+    }
+    Uuid::parse_str(uuid).ok()
+}
+
+impl Deserialize for DebugId {
+    fn deserialize<D>(deserializer: D) -> StdResult<DebugId, D::Error>
+        where D: de::Deserializer {
+        struct DebugIdVisitor;
+
+        impl de::Visitor for DebugIdVisitor {
+            type Value = DebugId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a Mach-O debug identifier")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> StdResult<DebugId, E> {
+                DebugId::parse(value).ok_or_else(|| {
+                    E::custom(format!("invalid debug identifier '{}'", value))
+                })
+            }
+        }
+
+        deserializer.deserialize_str(DebugIdVisitor)
+    }
+}
+
+/// Deserializes an optional object UUID in any of the formats that
+/// `parse_uuid` accepts.
+pub fn deserialize_loose_uuid<D>(deserializer: D) -> StdResult<Option<Uuid>, D::Error>
+    where D: de::Deserializer
+{
+    Option::<DebugId>::deserialize(deserializer).map(|x| x.map(|x| x.0))
+}
+
 /// Reverse sort helper
 #[derive(PartialEq, Eq)]
 pub struct Rev<T: Ord+PartialOrd+Eq+PartialEq>(pub T);
@@ -104,64 +194,83 @@ impl Deserialize for Addr {
 
 #[derive(Clone, Debug, Default)]
 pub struct IgnorePatterns {
+    sources: Vec<String>,
     patterns: Vec<(bool, globset::GlobMatcher)>,
 }
 
+fn make_pattern(value: &str) -> StdResult<(bool, globset::GlobMatcher), String> {
+    let (negative, pattern) = if &value[..1] == "!" {
+        (true, &value[1..])
+    } else {
+        (false, value)
+    };
+    Ok((negative, globset::Glob::new(pattern).map_err(|err| {
+        format!("invalid pattern '{}': {}", value, err)})?.compile_matcher()))
+}
+
 impl Deserialize for IgnorePatterns {
     fn deserialize<D>(deserializer: D) -> StdResult<IgnorePatterns, D::Error>
         where D: de::Deserializer {
         struct FilterVisitor;
 
-        fn make_pattern<E: de::Error>(value: &str)
-            -> StdResult<(bool, globset::GlobMatcher), E>
-        {
-            let (negative, pattern) = if &value[..1] == "!" {
-                (true, &value[1..])
-            } else {
-                (false, value)
-            };
-            Ok((negative, globset::Glob::new(pattern).map_err(|err| {
-               de::Error::custom(format!(
-                   "invalid pattern '{}': {}", value, err))})?.compile_matcher()))
-        }
-
         impl de::Visitor for FilterVisitor {
-            type Value = Vec<(bool, globset::GlobMatcher)>;
+            type Value = Vec<String>;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
                 formatter.write_str("a regular expression filter")
             }
 
             fn visit_seq<V: de::SeqVisitor>(self, mut visitor: V)
-                -> StdResult<Vec<(bool, globset::GlobMatcher)>, V::Error>
+                -> StdResult<Vec<String>, V::Error>
             {
                 let mut rv = vec![];
                 while let Some(item) = visitor.visit::<String>()? {
-                    rv.push(make_pattern(&item)?);
+                    rv.push(item);
                 }
                 Ok(rv)
             }
 
-            fn visit_unit<E: de::Error>(self)
-                -> StdResult<Vec<(bool, globset::GlobMatcher)>, E>
-            {
+            fn visit_unit<E: de::Error>(self) -> StdResult<Vec<String>, E> {
                 Ok(vec![])
             }
 
-            fn visit_str<E: de::Error>(self, value: &str)
-                -> StdResult<Vec<(bool, globset::GlobMatcher)>, E>
-            {
-                Ok(vec![make_pattern(value)?])
+            fn visit_str<E: de::Error>(self, value: &str) -> StdResult<Vec<String>, E> {
+                Ok(vec![value.to_string()])
             }
         }
 
-        deserializer.deserialize_seq(FilterVisitor).map(|patterns| {
-            IgnorePatterns { patterns: patterns }
-        })
+        let sources = deserializer.deserialize_seq(FilterVisitor)?;
+        IgnorePatterns::new(&sources).map_err(|err| de::Error::custom(err.to_string()))
+    }
+}
+
+impl Serialize for IgnorePatterns {
+    fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        self.sources.serialize(serializer)
     }
 }
 
 impl IgnorePatterns {
+    /// Compiles a list of patterns, later patterns win and patterns
+    /// starting with `!` negate.
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<IgnorePatterns> {
+        let mut rv = vec![];
+        for pattern in patterns {
+            rv.push(make_pattern(pattern.as_ref())?);
+        }
+        Ok(IgnorePatterns {
+            sources: patterns.iter().map(|x| x.as_ref().to_string()).collect(),
+            patterns: rv,
+        })
+    }
+
+    /// The patterns as they were given.
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
     pub fn is_match(&self, value: &str) -> bool {
         let mut rv = false;
         for &(negative, ref pattern) in self.patterns.iter() {
@@ -176,8 +285,9 @@ impl IgnorePatterns {
 /// Helper that runs a function and captures panics.
 ///
 /// The function needs to be reasonably protected against panics.  This
-/// might poison mutexes and similar things.
-pub fn run_isolated<F>(f: F)
+/// might poison mutexes and similar things.  Returns a description of
+/// the failure if the function errored or panicked.
+pub fn run_isolated<F>(f: F) -> Option<String>
     where F: FnOnce() -> Result<()>, F: Send
 {
     let rv = panic::catch_unwind(panic::AssertUnwindSafe(move || {
@@ -192,12 +302,19 @@ pub fn run_isolated<F>(f: F)
             if let Some(backtrace) = err.backtrace() {
                 debug!("  Traceback: {:?}", backtrace);
             }
+            Some(err.to_string())
+        } else {
+            None
         }
     }));
 
-    // the default panic handler will already have printed here
-    if let Err(_) = rv {
-        error!("task panicked!");
+    match rv {
+        Ok(failure) => failure,
+        // the default panic handler will already have printed here
+        Err(_) => {
+            error!("task panicked!");
+            Some("task panicked".into())
+        }
     }
 }
 
@@ -252,6 +369,314 @@ impl<R: Read + Seek> Read for ProgressReader<R> {
     }
 }
 
+/// A writer that computes the MD5 checksum of the data written through it.
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    ctx: md5::Context,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            ctx: md5::Context::new(),
+        }
+    }
+
+    /// Returns the hex encoded checksum of everything written so far.
+    pub fn checksum(&self) -> String {
+        format!("{:x}", self.ctx.compute())
+    }
+
+    /// Returns the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let rv = self.inner.write(buf)?;
+        self.ctx.consume(&buf[..rv]);
+        Ok(rv)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer that writes everything to two writers.
+pub struct TeeWriter<A: Write, B: Write> {
+    a: A,
+    b: B,
+}
+
+impl<A: Write, B: Write> TeeWriter<A, B> {
+    pub fn new(a: A, b: B) -> TeeWriter<A, B> {
+        TeeWriter { a, b }
+    }
+
+    /// Returns the two writers.
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.a.write_all(buf)?;
+        self.b.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.a.flush()?;
+        self.b.flush()
+    }
+}
+
+/// An exclusive advisory lock on a file.
+///
+/// The lock is released when the value is dropped.
+pub struct FileLock {
+    _file: fs::File,
+}
+
+impl FileLock {
+    /// Locks a file, creating it if necessary.
+    ///
+    /// Blocks until the lock can be acquired.
+    pub fn lock<P: AsRef<Path>>(path: P) -> Result<FileLock> {
+        Ok(FileLock::acquire(path.as_ref(), libc::LOCK_EX)?.unwrap())
+    }
+
+    /// Tries to lock a file, creating it if necessary.
+    ///
+    /// Returns `None` if another process holds the lock.
+    pub fn try_lock<P: AsRef<Path>>(path: P) -> Result<Option<FileLock>> {
+        FileLock::acquire(path.as_ref(), libc::LOCK_EX | libc::LOCK_NB)
+    }
+
+    fn acquire(path: &Path, op: libc::c_int) -> Result<Option<FileLock>> {
+        let file = fs::OpenOptions::new().create(true).write(true).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), op) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Ok(None);
+            }
+            return Err(err.into());
+        }
+        Ok(Some(FileLock { _file: file }))
+    }
+}
+
+/// The file in a temporary folder that its owner keeps locked
+const TEMP_DIR_LOCK_FILENAME: &'static str = ".owner.lock";
+
+/// A temporary folder that knows whether its creator is still alive.
+///
+/// The creating process keeps a lock file inside the folder locked until
+/// the folder is dropped, so `is_abandoned_temp_dir` can tell folders of
+/// crashed processes from folders that are still in use.
+pub struct OwnedTempDir {
+    dir: TempDir,
+    _lock: FileLock,
+}
+
+impl OwnedTempDir {
+    /// Creates a new folder with the given prefix in `parent`.
+    pub fn new_in<P: AsRef<Path>>(parent: P, prefix: &str) -> Result<OwnedTempDir> {
+        let dir = TempDir::new_in(parent, prefix)?;
+        let lock = FileLock::lock(dir.path().join(TEMP_DIR_LOCK_FILENAME))?;
+        Ok(OwnedTempDir { dir, _lock: lock })
+    }
+
+    /// The path of the folder.
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+/// Returns whether the process that created a temporary folder is gone.
+///
+/// Only folders created by `OwnedTempDir` can be abandoned, everything
+/// else is assumed to belong to someone else.
+pub fn is_abandoned_temp_dir(path: &Path) -> Result<bool> {
+    let lock_path = path.join(TEMP_DIR_LOCK_FILENAME);
+    if !lock_path.is_file() {
+        return Ok(false);
+    }
+    Ok(FileLock::try_lock(&lock_path)?.is_some())
+}
+
+/// Returns the number of bytes available on the filesystem of a path.
+pub fn get_available_space(path: &Path) -> Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .chain_err(|| "Path contains a null byte")?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Fails with `InsufficientSpace` if fewer than `needed` bytes are
+/// available on the filesystem of a path.
+pub fn ensure_free_space(path: &Path, needed: u64) -> Result<()> {
+    let available = get_available_space(path)?;
+    if available < needed {
+        return Err(ErrorKind::InsufficientSpace(
+            path.display().to_string(), needed, available).into());
+    }
+    Ok(())
+}
+
+/// Makes `dst` a copy-on-write clone of `src`.
+///
+/// The clone shares the blocks of `src` until either is written to, so
+/// it is fast and takes no space.  Fails unless the filesystem supports it
+/// (btrfs, XFS with reflinks, APFS) and both paths are on it.
+#[cfg(target_os = "linux")]
+pub fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    // FICLONE from linux/fs.h
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+    let src_file = fs::File::open(src)?;
+    let dst_file = fs::File::create(dst)?;
+    let rv = unsafe { libc::ioctl(dst_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) };
+    if rv != 0 {
+        let err = io::Error::last_os_error();
+        drop(dst_file);
+        fs::remove_file(dst).ok();
+        return Err(err);
+    }
+    dst_file.set_permissions(src_file.metadata()?.permissions())?;
+    Ok(())
+}
+
+/// Makes `dst` a copy-on-write clone of `src`.
+///
+/// The clone shares the blocks of `src` until either is written to, so
+/// it is fast and takes no space.  Fails unless the filesystem supports it
+/// (btrfs, XFS with reflinks, APFS) and both paths are on it.
+#[cfg(target_os = "macos")]
+pub fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    extern "C" {
+        fn clonefile(src: *const libc::c_char, dst: *const libc::c_char,
+                     flags: u32) -> libc::c_int;
+    }
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dst = CString::new(dst.as_os_str().as_bytes())?;
+    if unsafe { clonefile(src.as_ptr(), dst.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes `dst` a copy-on-write clone of `src`.
+///
+/// Not supported on this platform, so this always fails.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "reflinks are not supported"))
+}
+
+/// Copies a file as a copy-on-write clone if the filesystem supports it
+/// and byte by byte otherwise.
+pub fn reflink_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if reflink(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Hard links a file, falling back to a copy (see `reflink_or_copy`) if
+/// the filesystem does not support hard links.
+pub fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        reflink_or_copy(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Moves a file, copying it if the destination is on another filesystem.
+///
+/// Only the rename is atomic.  Btrfs does not rename across subvolumes,
+/// but it clones across them, so such copies are still cheap.
+pub fn move_file(src: &Path, dst: &Path) -> Result<()> {
+    match fs::rename(src, dst) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.raw_os_error() == Some(libc::EXDEV) => {
+            reflink_or_copy(src, dst)?;
+            fs::remove_file(src)?;
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replaces a file so that a crash leaves either the old or the new
+/// contents behind.
+///
+/// The contents are written to a temporary file next to it (with the
+/// extension `tmp_extension`) which is flushed to disk before it is
+/// renamed over the file.  The folder is flushed too so that the rename
+/// itself survives a crash.
+pub fn write_file_atomic(path: &Path, tmp_extension: &str, contents: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension(tmp_extension);
+    {
+        let mut f = fs::File::create(&tmp_path)?;
+        f.write_all(contents)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Fills a buffer with random bytes from the kernel.
+///
+/// This does not need OpenSSL, so it also works in builds without the
+/// `crypto` feature.
+pub fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    fs::File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/// Runs a function over all items on a bounded number of worker threads.
+///
+/// The results are returned in the order in which they complete.
+pub fn parallel_map<T, R, F>(items: Vec<T>, jobs: usize, f: F) -> Vec<R>
+    where T: Send + 'static, R: Send + 'static, F: Fn(T) -> R + Send + Sync + 'static
+{
+    let queue = Arc::new(Mutex::new(items.into_iter()));
+    let f = Arc::new(f);
+    let (tx, rx) = mpsc::channel();
+    let workers: Vec<_> = (0..jobs.max(1)).map(|_| {
+        let queue = queue.clone();
+        let f = f.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            loop {
+                let item = queue.lock().unwrap().next();
+                match item {
+                    Some(item) => { tx.send(f(item)).ok(); }
+                    None => { break; }
+                }
+            }
+        })
+    }).collect();
+    drop(tx);
+
+    let rv = rx.iter().collect();
+    for worker in workers {
+        worker.join().ok();
+    }
+    rv
+}
+
 /// Formats a file size for human readable display.
 pub fn file_size_format(bytes: usize) -> String {
     use humansize::FileSize;
@@ -275,6 +700,31 @@ pub fn is_docker() -> bool {
     false
 }
 
+/// Returns the resident memory of the process in bytes if known.
+///
+/// This is currently only supported on Linux.
+pub fn get_resident_memory() -> Option<u64> {
+    let mut s = String::new();
+    fs::File::open("/proc/self/statm").ok()?.read_to_string(&mut s).ok()?;
+    let pages: u64 = s.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    Some(pages * page_size as u64)
+}
+
+/// Returns the number of threads of the process if known.
+///
+/// This is currently only supported on Linux.
+pub fn get_thread_count() -> Option<usize> {
+    let mut s = String::new();
+    fs::File::open("/proc/self/status").ok()?.read_to_string(&mut s).ok()?;
+    s.lines()
+        .find(|line| line.starts_with("Threads:"))
+        .and_then(|line| line["Threads:".len()..].trim().parse().ok())
+}
+
 /// Returns a single systemd socket fd if there is one.
 pub fn get_systemd_fd() -> Result<Option<RawFd>> {
     let var = match env::var("LISTEN_FDS") {
@@ -298,6 +748,79 @@ pub fn get_systemd_fd() -> Result<Option<RawFd>> {
     Ok(Some(SD_LISTEN_FDS_START))
 }
 
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_reload_signal(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, AtomicOrdering::SeqCst);
+}
+
+/// Makes `SIGHUP` request a config reload instead of killing the process.
+///
+/// Whoever installs the handler has to poll `take_reload_request`.
+pub fn install_reload_handler() -> Result<()> {
+    let handler = handle_reload_signal as extern "C" fn(libc::c_int);
+    if unsafe { libc::signal(libc::SIGHUP, handler as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Returns `true` if `SIGHUP` was received since the last call.
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, AtomicOrdering::SeqCst)
+}
+
+extern "C" fn handle_shutdown_signal(_: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, AtomicOrdering::SeqCst);
+}
+
+/// Makes `SIGTERM` and `SIGINT` request a shutdown instead of killing the
+/// process.
+///
+/// Whoever installs the handler has to poll `shutdown_requested` and exit.
+pub fn install_shutdown_handler() -> Result<()> {
+    for &sig in &[libc::SIGTERM, libc::SIGINT] {
+        let handler = handle_shutdown_signal as extern "C" fn(libc::c_int);
+        if unsafe { libc::signal(sig, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+/// Restores the default handling of `SIGTERM` and `SIGINT` so that
+/// another signal kills the process.
+pub fn uninstall_shutdown_handler() {
+    for &sig in &[libc::SIGTERM, libc::SIGINT] {
+        unsafe { libc::signal(sig, libc::SIG_DFL); }
+    }
+}
+
+/// Returns `true` once `SIGTERM` or `SIGINT` was received.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(AtomicOrdering::SeqCst)
+}
+
+/// Parses a duration like `90s`, `30m`, `2h` or `1d`.
+///
+/// A number without unit is in seconds.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => (&value[..idx], &value[idx..]),
+        None => (value, "s"),
+    };
+    let number: i64 = number.parse().ok()?;
+    match unit {
+        "s" => Some(Duration::seconds(number)),
+        "m" => Some(Duration::minutes(number)),
+        "h" => Some(Duration::hours(number)),
+        "d" => Some(Duration::days(number)),
+        _ => None,
+    }
+}
+
 /// A quick binary search by key.
 pub fn binsearch_by_key<'a, T, B, F>(slice: &'a [T], item: B, mut f: F) -> Option<&'a T>
     where B: Ord, F: FnMut(&T) -> B
@@ -322,9 +845,81 @@ pub fn binsearch_by_key<'a, T, B, F>(slice: &'a [T], item: B, mut f: F) -> Optio
     }
 }
 
+#[test]
+fn test_parallel_map() {
+    let mut rv = parallel_map((0..100).collect(), 4, |x: u32| x * 2);
+    rv.sort();
+    assert_eq!(rv, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+}
+
 #[test]
 fn test_binsearch() {
     let seq = [0u32, 2, 4, 6, 8, 10];
     let m = binsearch_by_key(&seq[..], 5, |&x| x);
     assert_eq!(*m.unwrap(), 4);
 }
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90"), Some(Duration::seconds(90)));
+    assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+    assert_eq!(parse_duration("2h"), Some(Duration::hours(2)));
+    assert_eq!(parse_duration("m"), None);
+    assert_eq!(parse_duration("5y"), None);
+}
+
+#[test]
+fn test_parse_debug_id() {
+    let uuid = Uuid::parse_str("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b").unwrap();
+    assert_eq!(DebugId::parse("FE6D76D48C3A3A9A9F63F4A475501F1B0"), Some(DebugId(uuid)));
+    assert_eq!(DebugId::parse("FE6D76D48C3A3A9A9F63F4A475501F1B"), Some(DebugId(uuid)));
+    assert_eq!(DebugId::parse("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b-0"), Some(DebugId(uuid)));
+    assert_eq!(DebugId::parse("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b"), Some(DebugId(uuid)));
+    assert_eq!(DebugId::parse("FE6D76D48C3A3A9A9F63F4A475501F1B1"), None);
+    assert_eq!(DebugId::parse("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1bx"), None);
+    assert_eq!(DebugId::parse("FE6D76D4"), None);
+}
+
+#[test]
+fn test_parse_uuid() {
+    let uuid = Uuid::parse_str("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b").unwrap();
+    assert_eq!(parse_uuid("FE6D76D4-8C3A-3A9A-9F63-F4A475501F1B"), Some(uuid));
+    assert_eq!(parse_uuid("fe6d76d48c3a3a9a9f63f4a475501f1b"), Some(uuid));
+    assert_eq!(parse_uuid(" {fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b} "), Some(uuid));
+    assert_eq!(parse_uuid("FE6D76D48C3A3A9A9F63F4A475501F1B0"), Some(uuid));
+    assert_eq!(parse_uuid("/usr/lib/libfoo.dylib:arm64"), None);
+}
+
+#[test]
+fn test_reflink_or_copy() {
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let src = tmp.path().join("src");
+    let dst = tmp.path().join("dst");
+    fs::File::create(&src).unwrap().write_all(&[42u8; 10000]).unwrap();
+    reflink_or_copy(&src, &dst).unwrap();
+    let mut data = vec![];
+    fs::File::open(&dst).unwrap().read_to_end(&mut data).unwrap();
+    assert!(data == vec![42u8; 10000]);
+
+    // the copy is independent of the original
+    fs::File::create(&src).unwrap().write_all(b"changed").unwrap();
+    assert_eq!(fs::metadata(&dst).unwrap().len(), 10000);
+}
+
+#[test]
+fn test_owned_temp_dir() {
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let other = tmp.path().join("other");
+    fs::create_dir(&other).unwrap();
+    assert!(!is_abandoned_temp_dir(&other).unwrap());
+
+    let dir = OwnedTempDir::new_in(tmp.path(), "owned").unwrap();
+    let path = dir.path().to_path_buf();
+    assert!(!is_abandoned_temp_dir(&path).unwrap());
+
+    // a crashed process leaves the folder behind but not the lock
+    let OwnedTempDir { dir, _lock: lock } = dir;
+    drop(lock);
+    let path = dir.into_path();
+    assert!(is_abandoned_temp_dir(&path).unwrap());
+}
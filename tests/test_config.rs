@@ -0,0 +1,24 @@
+extern crate libsymbolserver;
+extern crate chrono;
+
+use std::path::Path;
+
+use libsymbolserver::ErrorKind;
+use libsymbolserver::config::Config;
+
+#[test]
+fn test_load_inline_config() {
+    let json = r#"{"symbol_dir": "/data", "stash": {"gc_max_age": 60}}"#;
+    let config = Config::load_str(json).unwrap();
+    assert_eq!(config.get_symbol_dir().unwrap(), Path::new("/data"));
+    assert_eq!(config.get_stash_gc_max_age().unwrap(), chrono::Duration::seconds(60));
+
+    let yaml = "symbol_dir: /data\nstash:\n  gc_max_age: 60\n";
+    let config = Config::load_reader(yaml.as_bytes()).unwrap();
+    assert_eq!(config.get_symbol_dir().unwrap(), Path::new("/data"));
+
+    match *Config::load_str(r#"{"stash": {"gc_max_age": "soon"}}"#).unwrap_err().kind() {
+        ErrorKind::ConfigError(_) => {}
+        ref other => panic!("unexpected error: {}", other),
+    }
+}
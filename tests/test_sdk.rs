@@ -1,8 +1,10 @@
 extern crate libsymbolserver;
+#[cfg(feature = "testing")]
+extern crate tempdir;
 
 use std::path::Path;
 
-use libsymbolserver::sdk::SdkInfo;
+use libsymbolserver::sdk::{DeviceFamily, SdkInfo};
 
 #[test]
 fn test_sdk_info_parse_ios() {
@@ -73,3 +75,180 @@ fn test_sdk_info_parse_filename_no_build() {
     assert_eq!(info.version_patchlevel(), 3);
     assert_eq!(info.build(), None);
 }
+
+#[test]
+fn test_sdk_info_fuzzy_match_builds() {
+    let wanted = SdkInfo::new("iOS", 13, 0, 0, Some("17A344"));
+    let near = SdkInfo::new("iOS", 13, 0, 0, Some("17A346"));
+    let far = SdkInfo::new("iOS", 13, 0, 0, Some("17A577"));
+    let other_patch = SdkInfo::new("iOS", 13, 0, 1, Some("17A344"));
+    let beta = SdkInfo::new("iOS", 13, 0, 0, Some("17A5344a"));
+    assert_eq!(wanted.get_fuzzy_match(&wanted), Some(0));
+    assert!(near.get_fuzzy_match(&wanted) < far.get_fuzzy_match(&wanted));
+    assert!(far.get_fuzzy_match(&wanted) < other_patch.get_fuzzy_match(&wanted));
+    assert!(other_patch.get_fuzzy_match(&wanted) < beta.get_fuzzy_match(&wanted));
+    assert!(beta.is_beta() && !near.is_beta());
+    assert_eq!(SdkInfo::new("iOS", 12, 0, 0, None).get_fuzzy_match(&wanted), None);
+}
+
+#[test]
+fn test_sdk_info_parse_device_model() {
+    let info = SdkInfo::from_path(Path::new("/Users/mitsuhiko/Library/Developer/Xcode/iOS DeviceSupport/iPad8,1 12.1 (16B101)")).unwrap();
+    assert_eq!(info.version_major(), 12);
+    assert_eq!(info.version_minor(), 1);
+    assert_eq!(info.build(), Some("16B101"));
+    assert_eq!(info.device_family(), Some(DeviceFamily::IPad));
+    assert_eq!(info.product_type(), Some("iPad8,1"));
+    assert_eq!(info, SdkInfo::new("iOS", 12, 1, 0, Some("16B101")));
+}
+
+#[test]
+fn test_sdk_info_parse_os_version() {
+    let info = SdkInfo::from_os_version("iPhone OS 16.3.1 (20D67)").unwrap();
+    assert_eq!(info.sdk_id(), "iOS_16.3.1_20D67");
+    let info = SdkInfo::from_os_version("tvOS 10.1").unwrap();
+    assert_eq!(info.sdk_id(), "tvOS_10.1.0");
+    let info = SdkInfo::from_os_version("watchOS 3.1 (14S471)").unwrap();
+    assert_eq!(info.sdk_id(), "watchOS_3.1.0_14S471");
+    assert!(SdkInfo::from_os_version("Android 7.0").is_none());
+    assert!(SdkInfo::from_os_version("iOS").is_none());
+}
+
+#[test]
+fn test_sdk_info_parse_client_ids() {
+    let canonical = |sdk_id: &str| SdkInfo::from_filename(sdk_id).map(|x| x.sdk_id());
+    assert_eq!(canonical("iOS_10.2.0_14C92"), Some("iOS_10.2.0_14C92".into()));
+    assert_eq!(canonical("ios_10.2.0_14c92"), Some("iOS_10.2.0_14C92".into()));
+    assert_eq!(canonical("iOS_10.2_14C92"), Some("iOS_10.2.0_14C92".into()));
+    assert_eq!(canonical("iPhone OS_16.3.1_20D67"), Some("iOS_16.3.1_20D67".into()));
+    assert_eq!(canonical("iPhone OS 16.3.1 (20D67)"), Some("iOS_16.3.1_20D67".into()));
+    assert_eq!(canonical("iPadOS_17.0_21A329"), Some("iOS_17.0.0_21A329".into()));
+    assert_eq!(canonical("iOS_13.0_17a5344a"), Some("iOS_13.0.0_17A5344a".into()));
+    // the suffix of a beta keeps its case so that existing ids stay
+    assert_eq!(canonical("iOS_13.0.0_17A5344A.memdb"), Some("iOS_13.0.0_17A5344A".into()));
+    assert_eq!(canonical("tvos_10.1"), Some("tvOS_10.1.0".into()));
+    assert_eq!(canonical("Apple TVOS 12.2 (16L226)"), Some("tvOS_12.2.0_16L226".into()));
+    assert_eq!(canonical("appletvos-12.2-16l226"), Some("tvOS_12.2.0_16L226".into()));
+    assert_eq!(canonical("watchos_3.1_14s471"), Some("watchOS_3.1.0_14S471".into()));
+    assert_eq!(canonical("Watch OS 5.2.1 (16U113)"), Some("watchOS_5.2.1_16U113".into()));
+    assert_eq!(canonical("watchOS-6"), Some("watchOS_6.0.0".into()));
+    assert_eq!(canonical("Mac OS X 10.12.3 (16D32)"), Some("macOS_10.12.3_16D32".into()));
+    assert_eq!(canonical("android 7.0"), None);
+    assert_eq!(canonical("iOS"), None);
+}
+
+#[test]
+fn test_sdk_info_format_filename() {
+    let info = SdkInfo::new("iOS", 10, 2, 0, Some("14C92"));
+    assert_eq!(info.format_filename("{name}_{version}_{build}.memdbz", true).unwrap(),
+               "iOS_10.2.0_14C92.memdbz");
+    assert_eq!(info.format_filename("{name}/{sdk_id}.{ext}", false).unwrap(),
+               "iOS/iOS_10.2.0_14C92.memdb");
+    assert_eq!(SdkInfo::new("iOS", 10, 2, 0, None).format_filename("{build}", false).unwrap(),
+               "unknown");
+    assert!(info.format_filename("{nope}.memdb", false).is_none());
+    assert!(info.format_filename("{name.memdb", false).is_none());
+    assert!(info.format_filename("name}.memdb", false).is_none());
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn test_sdk_product_type_from_info_plist() {
+    use std::fs;
+    use std::io::{Cursor, Write};
+    use tempdir::TempDir;
+    use libsymbolserver::memdb::read::MemDb;
+    use libsymbolserver::sdk::Sdk;
+    use libsymbolserver::testing::fixture_sdk_folder;
+
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let info = SdkInfo::new("iOS", 12, 1, 0, Some("16B92"));
+    let sdk_path = fixture_sdk_folder(tmp.path(), &info).unwrap();
+    fs::File::create(sdk_path.join("Info.plist")).unwrap().write_all(b"\
+<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<plist version=\"1.0\">
+<dict>
+    <key>DeviceClass</key>
+    <string>iPhone</string>
+    <key>ProductType</key>
+    <string>iPhone11,2</string>
+</dict>
+</plist>").unwrap();
+    let sdk = Sdk::new(&sdk_path).unwrap();
+    assert_eq!(sdk.info().device_family(), Some(DeviceFamily::IPhone));
+    assert_eq!(sdk.info().product_type(), Some("iPhone11,2"));
+
+    let mut data = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut data, Default::default()).unwrap();
+    let memdb = MemDb::from_vec(data.into_inner()).unwrap();
+    assert_eq!(memdb.info().product_type(), Some("iPhone11,2"));
+    assert_eq!(memdb.conversion_info().unwrap().unwrap().product_type,
+               Some("iPhone11,2".to_string()));
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn test_dump_memdb_to_stream() {
+    use std::io::Cursor;
+    use tempdir::TempDir;
+    use libsymbolserver::sdk::{DumpOptions, Sdk};
+    use libsymbolserver::testing::fixture_sdk_folder;
+
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let info = SdkInfo::new("iOS", 10, 2, 0, Some("14C92"));
+    let sdk = Sdk::new(fixture_sdk_folder(tmp.path(), &info).unwrap()).unwrap();
+    for &compress in &[false, true] {
+        let options = DumpOptions { compress, ..Default::default() };
+        let mut expected = Cursor::new(vec![]);
+        sdk.dump_memdb(&mut expected, options.clone()).unwrap();
+        // a vector cannot seek, like stdout
+        let mut streamed: Vec<u8> = vec![];
+        sdk.dump_memdb_to_stream(&mut streamed, options).unwrap();
+        assert!(streamed == expected.into_inner());
+    }
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn test_trim_memdb_to_size() {
+    use std::io::Cursor;
+    use tempdir::TempDir;
+    use libsymbolserver::ErrorKind;
+    use libsymbolserver::memdb::read::MemDb;
+    use libsymbolserver::sdk::{DumpOptions, Sdk};
+    use libsymbolserver::testing::fixture_sdk_folder;
+
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let info = SdkInfo::new("iOS", 10, 2, 0, Some("14C92"));
+    let sdk = Sdk::new(fixture_sdk_folder(tmp.path(), &info).unwrap()).unwrap();
+    let uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    let dump = |max_size, cold_objects: &[&str]| {
+        let mut buf = Cursor::new(vec![]);
+        sdk.dump_memdb(&mut buf, DumpOptions {
+            max_size,
+            cold_objects: cold_objects.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }).map(|_| buf.into_inner())
+    };
+
+    // a memdb that fits is left alone
+    let full = dump(None, &[]).unwrap();
+    let fitting = dump(Some(full.len() as u64), &[]).unwrap();
+    assert!(fitting == full);
+    let memdb = MemDb::from_vec(fitting).unwrap();
+    assert!(memdb.conversion_info().unwrap().unwrap().options.trimmed.is_empty());
+
+    // the recovered symbols go before the object
+    let memdb = MemDb::from_vec(dump(Some(full.len() as u64 - 1), &["/usr/lib/*"]).unwrap())
+        .unwrap();
+    let trimmed = memdb.conversion_info().unwrap().unwrap().options.trimmed;
+    assert_eq!(trimmed[0], "function_starts");
+    assert!(!trimmed.contains(&"cold:/usr/lib/*".to_string()));
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b14).unwrap().symbol(),
+               "___CFFromMacKoreanLen");
+
+    match *dump(Some(1), &["/usr/lib/*"]).unwrap_err().kind() {
+        ErrorKind::MemDbTooLarge(_, 1) => {}
+        ref other => panic!("unexpected error: {}", other),
+    }
+}
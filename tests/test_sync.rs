@@ -0,0 +1,2335 @@
+#![cfg(feature = "testing")]
+extern crate libsymbolserver;
+extern crate tempdir;
+extern crate hyper;
+#[cfg(feature = "crypto")]
+extern crate openssl;
+extern crate chrono;
+extern crate rustc_serialize;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use tempdir::TempDir;
+use hyper::client::Client;
+use hyper::header::{Authorization, Bearer, ETag, Headers};
+use hyper::status::StatusCode;
+
+use libsymbolserver::ErrorKind;
+use libsymbolserver::api::replay::{replay, ReplayOptions};
+use libsymbolserver::api::server::{ApiServer, BindOptions};
+use libsymbolserver::config::Config;
+use libsymbolserver::constants::VERSION;
+use libsymbolserver::manifest::{publish_bucket_manifest, publish_dir_manifest};
+use libsymbolserver::reconvert::{get_claim_key, reconvert, ReconvertOptions};
+use libsymbolserver::memdb::audit::AuditAction;
+use libsymbolserver::memdb::read::MemDb;
+use libsymbolserver::memdb::summary::SyncSummary;
+use libsymbolserver::memdb::stash::{create_temp_dir, FuzzyMatchOptions, MemDbStash,
+                                    SdkDifferenceKind, SyncOptions};
+use libsymbolserver::s3::S3Server;
+use libsymbolserver::sdk::{DeviceFamily, SdkInfo};
+use libsymbolserver::testing::{fixture_memdb, fixture_sdk_folder, fixture_sdk_zip, synced_stash,
+                               MockFault, MockS3};
+use libsymbolserver::utils::{FileLock, IgnorePatterns};
+
+fn ios_10_2() -> SdkInfo {
+    SdkInfo::new("iOS", 10, 2, 0, Some("14C92"))
+}
+
+fn ios_10_3() -> SdkInfo {
+    SdkInfo::new("iOS", 10, 3, 0, Some("14E277"))
+}
+
+#[test]
+fn test_sync_downloads_sdks() {
+    let (_s3, dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    assert!(dir.path().join("iOS_10.2.0_14C92.memdb").is_file());
+
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    let uuid = *memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b")
+        .unwrap().unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+               "___CFFromMacKoreanLen");
+
+    let status = stash.get_sync_status().unwrap();
+    assert!(!status.is_offline());
+    assert_eq!(status.lag(), 0);
+}
+
+#[test]
+fn test_sync_updates_and_removes_sdks() {
+    let (s3, dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    s3.remove_sdk(&ios_10_2());
+    s3.put_sdk(&ios_10_3()).unwrap();
+    // the status reuses the listing of the last sync until it is refreshed
+    assert_eq!(stash.get_sync_status().unwrap().lag(), 0);
+    stash.refresh_remote_listing().unwrap();
+    assert_eq!(stash.get_sync_status().unwrap().lag(), 1);
+
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_3()]);
+    assert!(!dir.path().join("iOS_10.2.0_14C92.memdb").exists());
+    assert!(dir.path().join("iOS_10.3.0_14E277.memdb").is_file());
+}
+
+#[test]
+fn test_sync_links_renamed_sdks() {
+    use std::os::unix::fs::MetadataExt;
+
+    let (s3, dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    // the same memdb uploaded again under another name
+    let renamed = SdkInfo::new("iOS", 10, 2, 0, Some("14C92a"));
+    s3.put_object("iOS_10.2.0_14C92a.memdbz", fixture_memdb(&ios_10_2()).unwrap());
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), renamed.clone()]);
+    let path = dir.path().join("iOS_10.2.0_14C92a.memdb");
+    assert_eq!(fs::metadata(&path).unwrap().nlink(), 2);
+    let events = stash.audit_log().query(Some("iOS_10.2.0_14C92a"), 1).unwrap();
+    assert_eq!(events[0].reason(), Some("same contents as iOS_10.2.0_14C92"));
+
+    // removing the original keeps the copy
+    s3.remove_sdk(&ios_10_2());
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![renamed.clone()]);
+    let memdb = stash.get_memdb(&renamed).unwrap();
+    let uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+               "___CFFromMacKoreanLen");
+}
+
+#[test]
+fn test_compare_with_upstream() {
+    let (s3, _dir, stash) = synced_stash(&[ios_10_2(), ios_10_3()]).unwrap();
+    assert!(stash.compare_with_upstream().unwrap().is_empty());
+
+    let ios_11 = SdkInfo::new("iOS", 11, 0, 0, Some("15A372"));
+    s3.remove_sdk(&ios_10_2());
+    s3.put_corrupted_sdk(&ios_10_3()).unwrap();
+    s3.put_sdk(&ios_11).unwrap();
+    let differences = stash.compare_with_upstream().unwrap();
+    let kinds: Vec<_> = differences.iter().map(|x| (x.info().clone(), x.kind())).collect();
+    assert_eq!(kinds, vec![
+        (ios_10_2(), SdkDifferenceKind::LocalOnly),
+        (ios_10_3(), SdkDifferenceKind::Different),
+        (ios_11, SdkDifferenceKind::RemoteOnly),
+    ]);
+    assert!(differences[0].remote().is_none());
+    assert_ne!(differences[1].local().unwrap().etag(), differences[1].remote().unwrap().etag());
+}
+
+#[test]
+fn test_ignore_override() {
+    let (s3, dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    // ignored SDKs stay local but are not used for lookups any more
+    let patterns = IgnorePatterns::new(&["iOS_10.2.*"]).unwrap();
+    stash.set_ignore_override(Some(patterns)).unwrap();
+    assert!(stash.fuzzy_match_sdk_id("iOS_10.2.0_14C92").unwrap().is_empty());
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+
+    // the override survives a restart
+    let stash = MemDbStash::new(&s3.config(dir.path())).unwrap();
+    assert!(stash.sdk_is_ignored(&ios_10_2()));
+    stash.set_ignore_override(None).unwrap();
+    assert_eq!(stash.fuzzy_match_sdk_id("iOS_10.2.0_14C92").unwrap(), vec![ios_10_2()]);
+    let stash = MemDbStash::new(&s3.config(dir.path())).unwrap();
+    assert!(!stash.sdk_is_ignored(&ios_10_2()));
+}
+
+#[test]
+fn test_sync_from_manifest() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_manifest("index.json", 1);
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_sync_manifest("index.json");
+    let stash = MemDbStash::new(&config).unwrap();
+
+    // SDKs are only picked up once the manifest lists them
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    assert_eq!(stash.get_sync_status().unwrap().lag(), 0);
+
+    s3.put_manifest("index.json", 2);
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+
+    // a missing manifest does not wipe the stash
+    s3.remove_object("index.json");
+    let err = stash.sync(Default::default()).err().unwrap();
+    assert!(err.is_s3_offline(), "unexpected error: {}", err);
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+}
+
+#[test]
+fn test_publish_manifest() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_sync_manifest("index.json");
+    let server = S3Server::from_config(&config).unwrap();
+
+    let manifest = publish_bucket_manifest(&server, "index.json").unwrap();
+    assert_eq!(manifest.revision(), Some(1));
+    assert_eq!(manifest.sdk_count(), 1);
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let manifest = publish_bucket_manifest(&server, "index.json").unwrap();
+    assert_eq!(manifest.revision(), Some(2));
+    assert_eq!(manifest.sdk_count(), 2);
+
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+
+    // a local folder gets the same manifest the bucket does
+    let out = TempDir::new("symbolserver-test").unwrap();
+    fs::write(out.path().join("iOS_10.2.0_14C92.memdbz"),
+              fixture_memdb(&ios_10_2()).unwrap()).unwrap();
+    fs::write(out.path().join("README"), b"not an SDK").unwrap();
+    publish_dir_manifest(out.path(), "index.json").unwrap();
+    let manifest = publish_dir_manifest(out.path(), "index.json").unwrap();
+    assert_eq!(manifest.revision(), Some(2));
+    let sdks = manifest.listing().sdks;
+    assert_eq!(sdks.len(), 1);
+    assert_eq!(sdks[0].info(), &ios_10_2());
+    assert_eq!(sdks[0].etag(), server.list_bucket_sdks().unwrap().sdks.iter()
+        .find(|x| x.info() == &ios_10_2()).unwrap().etag());
+}
+
+#[test]
+fn test_withdrawn_sdks_are_removed() {
+    let (s3, dir, stash) = synced_stash(&[ios_10_2(), ios_10_3()]).unwrap();
+
+    s3.put_object("iOS_10.2.0_14C92.withdrawn", b"broken symbols\n".to_vec());
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_3()]);
+    assert!(!dir.path().join("iOS_10.2.0_14C92.memdb").exists());
+    assert_eq!(stash.get_sync_status().unwrap().lag(), 0);
+    assert_eq!(stash.get_withdrawn(&ios_10_2()).unwrap().unwrap().reason(), "broken symbols");
+    match *stash.get_memdb(&ios_10_2()).err().unwrap().kind() {
+        ErrorKind::SdkWithdrawn(ref sdk_id, ref reason) => {
+            assert_eq!(sdk_id, "iOS_10.2.0_14C92");
+            assert_eq!(reason, "broken symbols");
+        }
+        ref kind => panic!("unexpected error: {}", kind),
+    }
+    let events = stash.audit_log().query(Some("iOS_10.2.0_14C92"), 1).unwrap();
+    assert_eq!(events[0].reason(), Some("withdrawn upstream: broken symbols"));
+
+    // lifting the tombstone brings the SDK back
+    s3.remove_object("iOS_10.2.0_14C92.withdrawn");
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+    assert!(stash.get_withdrawn(&ios_10_2()).unwrap().is_none());
+}
+
+#[test]
+fn test_sync_cancel() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let stash = MemDbStash::new(&s3.config(dir.path())).unwrap();
+
+    let options = SyncOptions::default();
+    options.cancel.store(true, Ordering::SeqCst);
+    match stash.sync(options) {
+        Err(ref err) => match *err.kind() {
+            ErrorKind::SyncCancelled(0, 2) => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        },
+        Ok(()) => panic!("sync was not cancelled"),
+    }
+    assert_eq!(stash.list_sdks().unwrap(), vec![]);
+    assert!(stash.check_consistency("test").unwrap().is_consistent());
+
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+}
+
+#[test]
+fn test_sync_deadline() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let stash = MemDbStash::new(&s3.config(dir.path())).unwrap();
+
+    let mut options = SyncOptions::default();
+    options.deadline = Some(chrono::Utc::now() - chrono::Duration::seconds(1));
+    match *stash.sync(options).unwrap_err().kind() {
+        ErrorKind::SyncCancelled(0, 1) => {}
+        ref kind => panic!("unexpected error {:?}", kind),
+    }
+
+    let mut options = SyncOptions::default();
+    options.deadline = Some(chrono::Utc::now() + chrono::Duration::hours(1));
+    stash.sync(options).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+
+    // before the first download the last sync tells how fast it goes
+    let mut slow = SyncSummary::new("test");
+    slow.add_added(&ios_10_2(), 1);
+    thread::sleep(Duration::from_millis(1100));
+    slow.finish(0);
+    stash.sync_summaries().write(&slow).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let mut options = SyncOptions::default();
+    options.deadline = Some(chrono::Utc::now() + chrono::Duration::minutes(10));
+    match *stash.sync(options).unwrap_err().kind() {
+        ErrorKind::SyncCancelled(0, 1) => {}
+        ref kind => panic!("unexpected error {:?}", kind),
+    }
+}
+
+#[test]
+fn test_serve_stale_while_updating() {
+    let uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    for &serve_stale in &[true, false] {
+        let s3 = MockS3::start().unwrap();
+        s3.put_sdk(&ios_10_2()).unwrap();
+        let dir = TempDir::new("symbolserver-test").unwrap();
+        let mut config = s3.config(dir.path());
+        config.set_sync_serve_stale(serve_stale);
+        let stash = Arc::new(MemDbStash::new(&config).unwrap());
+        stash.sync(Default::default()).unwrap();
+
+        // the new version is held up while it downloads
+        s3.put_corrupted_sdk(&ios_10_2()).unwrap();
+        s3.set_fault(Some(MockFault::SlowDownloads));
+        let sync = {
+            let stash = stash.clone();
+            thread::spawn(move || stash.sync(Default::default()))
+        };
+        for _ in 0..500 {
+            if stash.is_updating(&ios_10_2()) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(stash.is_updating(&ios_10_2()));
+        let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+        assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+                   "___CFFromMacKoreanLen");
+        let matches = stash.fuzzy_match_sdk_id("iOS_10.2.0_14C92").unwrap();
+        assert_eq!(matches.is_empty(), !serve_stale);
+
+        s3.set_fault(None);
+        sync.join().unwrap().unwrap();
+        assert!(!stash.is_updating(&ios_10_2()));
+    }
+}
+
+#[test]
+fn test_sync_offline() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    MemDbStash::new(&s3.config(dir.path())).unwrap().sync(Default::default()).unwrap();
+
+    let stash = MemDbStash::new(&s3.offline_config(dir.path()).unwrap()).unwrap();
+    match *stash.sync(Default::default()).unwrap_err().kind() {
+        ErrorKind::S3Unavailable(_) => {}
+        ref kind => panic!("unexpected error: {}", kind),
+    }
+    let status = stash.get_sync_status().unwrap();
+    assert!(status.is_offline());
+    assert!(status.is_healthy());
+
+    // the previously synched SDKs are still served
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    assert!(stash.get_memdb(&ios_10_2()).is_ok());
+}
+
+#[test]
+fn test_sync_recovers_from_corruption() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_corrupted_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let stash = MemDbStash::new(&s3.config(dir.path())).unwrap();
+
+    stash.sync(Default::default()).unwrap();
+    assert!(stash.list_sdks().unwrap().is_empty());
+    assert!(!dir.path().join("iOS_10.2.0_14C92.memdb").exists());
+    assert!(dir.path().join("quarantine/iOS_10.2.0_14C92.memdb").is_file());
+    let quarantined = stash.list_quarantined().unwrap();
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].sdk_id(), "iOS_10.2.0_14C92");
+
+    s3.put_sdk(&ios_10_2()).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+    assert!(stash.list_quarantined().unwrap().is_empty());
+}
+
+#[test]
+fn test_sync_rejects_truncated_memdbs() {
+    let (s3, _dir, stash) = synced_stash(&[ios_10_2(), ios_10_3()]).unwrap();
+
+    // the previous version keeps being served
+    s3.put_truncated_sdk(&ios_10_2(), 16).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+    let summary = &stash.sync_summaries().list().unwrap()[0];
+    assert_eq!(summary.failures().len(), 1);
+    assert_eq!(summary.failures()[0].sdk_id(), Some("iOS_10.2.0_14C92"));
+    assert!(summary.failures()[0].reason().starts_with("truncated memdb"));
+
+    s3.put_sdk(&ios_10_2()).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+}
+
+#[test]
+fn test_failed_update_keeps_old_memdb() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    s3.put_truncated_sdk(&ios_10_2(), 16).unwrap();
+    s3.set_fault(Some(MockFault::TruncatedDownloads));
+    assert!(stash.sync(Default::default()).is_err());
+
+    let stash = MemDbStash::new(&config).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    let uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b14).unwrap().symbol(),
+               "___CFFromMacKoreanLen");
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+}
+
+#[test]
+fn test_collect_garbage() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_stash_gc_max_age(0);
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    fs::write(dir.path().join("sync.tempstate"), b"{").unwrap();
+    fs::write(dir.path().join("iOS_9.3.0_13E230.memdb"), b"partial").unwrap();
+    fs::create_dir_all(dir.path().join("decompressed")).unwrap();
+    fs::write(dir.path().join("decompressed/iOS_10.2.0_14C92.tempmemdb"), b"partial").unwrap();
+
+    let stats = stash.collect_garbage().unwrap();
+    assert!(stats.removed_files() >= 3);
+    assert!(!dir.path().join("sync.tempstate").exists());
+    assert!(!dir.path().join("iOS_9.3.0_13E230.memdb").exists());
+    assert!(!dir.path().join("decompressed/iOS_10.2.0_14C92.tempmemdb").exists());
+    assert!(dir.path().join("iOS_10.2.0_14C92.memdb").is_file());
+    assert!(dir.path().join("sync.state").is_file());
+    assert_eq!(stash.gc_stats().runs(), 1);
+
+    // partial downloads are left alone while a sync is running
+    fs::write(dir.path().join("iOS_9.3.0_13E230.memdb"), b"partial").unwrap();
+    {
+        let _lock = FileLock::lock(dir.path().join("sync.lock")).unwrap();
+        stash.collect_garbage().unwrap();
+        assert!(dir.path().join("iOS_9.3.0_13E230.memdb").exists());
+    }
+    stash.collect_garbage().unwrap();
+    assert!(!dir.path().join("iOS_9.3.0_13E230.memdb").exists());
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+
+    // only temporary folders of this stash whose process is gone
+    let scratch = TempDir::new("symbolserver-test").unwrap();
+    config.set_stash_scratch_dir(scratch.path());
+    let stash = MemDbStash::new(&config).unwrap();
+    let alive = create_temp_dir(&config).unwrap();
+    fs::create_dir(scratch.path().join("symbolserver.foreign")).unwrap();
+    // a copy of a living folder looks like one whose process crashed
+    let crashed = alive.path().with_extension("crashed");
+    fs::create_dir(&crashed).unwrap();
+    for entry in fs::read_dir(alive.path()).unwrap() {
+        let path = entry.unwrap().path();
+        fs::copy(&path, crashed.join(path.file_name().unwrap())).unwrap();
+    }
+    stash.collect_garbage().unwrap();
+    assert!(alive.path().is_dir());
+    assert!(scratch.path().join("symbolserver.foreign").is_dir());
+    assert!(!crashed.exists());
+}
+
+#[test]
+fn test_broken_memdb_is_quarantined_on_open() {
+    let (_s3, dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    fs::File::create(dir.path().join("iOS_10.2.0_14C92.memdb")).unwrap()
+        .write_all(b"garbage").unwrap();
+    assert!(stash.get_memdb(&ios_10_2()).is_err());
+    assert!(stash.list_sdks().unwrap().is_empty());
+    assert_eq!(stash.list_quarantined().unwrap().len(), 1);
+    match *stash.get_memdb(&ios_10_2()).err().unwrap().kind() {
+        ErrorKind::UnknownSdk => {}
+        ref kind => panic!("unexpected error: {}", kind),
+    }
+
+    stash.sync(Default::default()).unwrap();
+    assert!(stash.get_memdb(&ios_10_2()).is_ok());
+    assert!(stash.list_quarantined().unwrap().is_empty());
+}
+
+#[test]
+fn test_missing_memdb_is_not_quarantined() {
+    use std::sync::atomic::AtomicBool;
+
+    let (s3, dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    // a memdb that is missing for a moment is not broken
+    let path = dir.path().join("iOS_10.2.0_14C92.memdb");
+    let moved = dir.path().join("iOS_10.2.0_14C92.moved");
+    fs::rename(&path, &moved).unwrap();
+    assert!(stash.get_memdb(&ios_10_2()).is_err());
+    assert!(stash.list_quarantined().unwrap().is_empty());
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    fs::rename(&moved, &path).unwrap();
+    assert!(stash.get_memdb(&ios_10_2()).is_ok());
+
+    // lookups while an update installs neither fail nor get in the way of
+    // the sync
+    s3.put_thin_sdk(&ios_10_2()).unwrap();
+    let stash = Arc::new(stash);
+    let done = Arc::new(AtomicBool::new(false));
+    let sync = {
+        let stash = stash.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let rv = stash.sync(Default::default());
+            done.store(true, Ordering::SeqCst);
+            rv
+        })
+    };
+    // the new memdb is renamed over the old one, so every lookup finds
+    // one of them
+    while !done.load(Ordering::SeqCst) {
+        stash.evict_memdb(&ios_10_2()).unwrap();
+        stash.get_memdb(&ios_10_2()).unwrap();
+    }
+    sync.join().unwrap().unwrap();
+    assert!(stash.list_quarantined().unwrap().is_empty());
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    assert!(stash.get_memdb(&ios_10_2()).unwrap().is_thin());
+}
+
+#[test]
+fn test_admin_evict_and_reload_sdk() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.add_server_admin_key("admin").unwrap();
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let client = Client::new();
+    let admin = || Authorization(Bearer { token: "admin".to_string() });
+    let post = |path: &str| {
+        let mut resp = client.post(&format!("{}{}", url, path)).header(admin()).body("")
+            .send().unwrap();
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        (resp.status, body)
+    };
+    let lookup = r#"{"sdk_id": "iOS_10.2.0_14C92", "cpu_name": "arm64",
+        "symbols": [{"object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", "addr": "0x5b14"}]}"#;
+    assert_eq!(client.post(&format!("{}lookup", url)).header(admin()).body(lookup)
+               .send().unwrap().status, StatusCode::Ok);
+
+    let (status, body) = post("admin/sdks/iOS_10.2.0_14C92/evict");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""evicted":true"#));
+    assert!(post("admin/sdks/iOS_10.2.0_14C92/evict").1.contains(r#""evicted":false"#));
+    assert_eq!(post("admin/sdks/iOS_11.0.0_15A372/evict").0, StatusCode::NotFound);
+    assert_eq!(post("admin/sdks/iOS_10.2.0_14C92/nope").0, StatusCode::NotFound);
+
+    // a broken file is quarantined instead of being mapped again
+    fs::File::create(dir.path().join("iOS_10.2.0_14C92.memdb")).unwrap()
+        .write_all(b"garbage").unwrap();
+    let (status, body) = post("admin/sdks/iOS_10.2.0_14C92/reload");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""outcome":"quarantined""#));
+    assert!(MemDbStash::new(&config).unwrap().list_sdks().unwrap().is_empty());
+
+    // the server only notices a sync of another stash after a while, so
+    // the rest goes through a stash of its own
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+    stash.get_memdb(&ios_10_2()).unwrap();
+    assert_eq!(stash.reload_memdb(&ios_10_2(), false, "admin").unwrap().outcome(), "reloaded");
+    assert!(stash.is_memdb_open(&ios_10_2()));
+    let etag = stash.get_sdk_etag(&ios_10_2()).unwrap();
+    assert_eq!(stash.reload_memdb(&ios_10_2(), true, "admin").unwrap().outcome(), "redownload");
+    // the memdb keeps being served until the next sync replaced it
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    assert!(stash.get_memdb(&ios_10_2()).is_ok());
+    let revision = stash.get_revision().unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    assert_eq!(stash.get_sdk_etag(&ios_10_2()).unwrap(), etag);
+    assert!(stash.get_revision().unwrap() > revision);
+    let events = stash.audit_log().query(Some("iOS_10.2.0_14C92"), 10).unwrap();
+    assert_eq!(events[1].action(), AuditAction::Redownload);
+    assert_eq!(events[0].action(), AuditAction::Update);
+
+    // the server records who asked for it
+    let (status, body) = post("admin/sdks/iOS_10.2.0_14C92/reload?redownload=1");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""outcome":"redownload""#));
+    let events = stash.audit_log().query(Some("iOS_10.2.0_14C92"), 1).unwrap();
+    assert_eq!(events[0].actor(), "admin (key-8c6976e5)");
+}
+
+#[test]
+fn test_audit_sample() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    let stats = stash.audit_sample(10, "test").unwrap();
+    assert_eq!((stats.checked(), stats.failures(), stats.drifted()), (1, 0, 0));
+
+    // bit-rot is quarantined and downloaded again by the next sync
+    fs::File::create(dir.path().join("iOS_10.2.0_14C92.memdb")).unwrap()
+        .write_all(b"garbage").unwrap();
+    let stats = stash.audit_sample(10, "test").unwrap();
+    assert_eq!((stats.failures(), stats.drifted()), (1, 0));
+    assert!(stash.list_sdks().unwrap().is_empty());
+    stash.sync(Default::default()).unwrap();
+
+    // a changed upstream object is reported as drift
+    s3.put_corrupted_sdk(&ios_10_2()).unwrap();
+    let stash = MemDbStash::new(&config).unwrap();
+    let stats = stash.audit_sample(10, "test").unwrap();
+    assert_eq!((stats.failures(), stats.drifted()), (0, 1));
+    assert_eq!(stash.audit_stats().runs(), 1);
+    let events = stash.audit_log().query(Some("iOS_10.2.0_14C92"), 10).unwrap();
+    let drift = events.iter().find(|x| x.action() == AuditAction::Drift).unwrap();
+    assert_eq!(drift.actor(), "test");
+    assert!(drift.reason().unwrap().contains("upstream"));
+}
+
+#[test]
+fn test_fuzzy_match_limits() {
+    let ios_10_2_1 = SdkInfo::new("iOS", 10, 2, 1, Some("14D27"));
+    let (_s3, _dir, stash) = synced_stash(&[ios_10_2(), ios_10_2_1.clone()]).unwrap();
+
+    let all = stash.fuzzy_match_sdk_id("iOS_10.2.0_14C92").unwrap();
+    assert_eq!(all, vec![ios_10_2(), ios_10_2_1]);
+    assert!(stash.fuzzy_match_sdk_id("iOS_11.0.0_15A372").unwrap().is_empty());
+
+    let exact = stash.fuzzy_match_sdk_id_with_scores("iOS_10.2.0_14C92", &FuzzyMatchOptions {
+        max_results: 10,
+        max_score: Some(0),
+        device_family: None,
+        product_type: None,
+    }).unwrap();
+    assert_eq!(exact.len(), 1);
+    assert_eq!(exact[0].info(), &ios_10_2());
+
+    let first = stash.fuzzy_match_sdk_id_with_scores("iOS_10.2.1", &FuzzyMatchOptions {
+        max_results: 1,
+        max_score: None,
+        device_family: None,
+        product_type: None,
+    }).unwrap();
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0].info().version_patchlevel(), 1);
+}
+
+#[test]
+fn test_fuzzy_match_device_family() {
+    let mut iphone = SdkInfo::new("iOS", 12, 1, 0, Some("16B92"));
+    iphone.set_device_family(Some(DeviceFamily::IPhone));
+    let mut ipad = SdkInfo::new("iOS", 12, 1, 0, Some("16B101"));
+    ipad.set_device_family(Some(DeviceFamily::IPad));
+    let (_s3, _dir, stash) = synced_stash(&[iphone.clone(), ipad.clone()]).unwrap();
+
+    let memdb = stash.get_memdb(&ipad).unwrap();
+    assert_eq!(memdb.info().device_family(), Some(DeviceFamily::IPad));
+
+    let mut opts = stash.fuzzy_match_options();
+    opts.device_family = Some(DeviceFamily::IPad);
+    let matches = stash.fuzzy_match_sdk_id_with_scores("iOS_12.1.0_16B94", &opts).unwrap();
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].info(), &ipad);
+
+    // exact matches are never filtered
+    let matches = stash.fuzzy_match_sdk_id_with_scores("iOS_12.1.0_16B92", &opts).unwrap();
+    assert_eq!(matches[0].info(), &iphone);
+    assert_eq!(matches[0].score(), 0);
+}
+
+#[test]
+fn test_fuzzy_match_product_type() {
+    let mut xs = SdkInfo::new("iOS", 12, 1, 0, Some("16B92"));
+    xs.set_product_type(Some("iPhone11,2"));
+    let mut xr = SdkInfo::new("iOS", 12, 1, 0, Some("16B93"));
+    xr.set_product_type(Some("iPhone11,8"));
+    let (_s3, _dir, stash) = synced_stash(&[xs.clone(), xr.clone()]).unwrap();
+
+    let memdb = stash.get_memdb(&xr).unwrap();
+    assert_eq!(memdb.info().product_type(), Some("iPhone11,8"));
+
+    let mut opts = stash.fuzzy_match_options();
+    opts.product_type = Some("iPhone11,8".into());
+    let matches = stash.fuzzy_match_sdk_id_with_scores("iOS_12.1.0_16B94", &opts).unwrap();
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].info(), &xr);
+
+    // the family of a product type excludes SDKs of other families
+    opts.product_type = Some("iPad8,1".into());
+    assert!(stash.fuzzy_match_sdk_id_with_scores("iOS_12.1.0_16B94", &opts).unwrap().is_empty());
+}
+
+#[test]
+fn test_fuzzy_match_sdk_aliases() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&SdkInfo::new("iOS", 10, 2, 1, Some("14D27"))).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.add_stash_sdk_alias("iOS_10.2.0_14C99", "iOS_10.2.1_14D27");
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    let matches = stash.fuzzy_match_sdk_id_with_scores(
+        "iOS_10.2.0_14C99", &stash.fuzzy_match_options()).unwrap();
+    assert_eq!(matches[0].info(), &SdkInfo::new("iOS", 10, 2, 1, Some("14D27")));
+    assert_eq!(matches[0].score(), 0);
+    assert_eq!(stash.fuzzy_match_sdk_id("iOS_10.2.0_14C92").unwrap()[0], ios_10_2());
+
+    config.add_stash_sdk_alias("iOS_10.2.0_14C99", "not an sdk");
+    assert!(MemDbStash::new(&config).is_err());
+}
+
+#[test]
+fn test_sync_audit_log() {
+    let (s3, _dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+    s3.remove_sdk(&ios_10_2());
+    s3.put_corrupted_sdk(&ios_10_3()).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    let events = stash.audit_log().query(None, 100).unwrap();
+    let actions: Vec<_> = events.iter().map(|x| (x.action(), x.sdk_id())).collect();
+    assert_eq!(actions, vec![
+        (AuditAction::Quarantine, "iOS_10.3.0_14E277"),
+        (AuditAction::Remove, "iOS_10.2.0_14C92"),
+        (AuditAction::Add, "iOS_10.3.0_14E277"),
+        (AuditAction::Add, "iOS_10.2.0_14C92"),
+    ]);
+    assert!(events[0].reason().unwrap().starts_with("verification failed"));
+    assert!(events.iter().all(|x| x.actor() == "sync" && x.etag().is_some()));
+
+    let events = stash.audit_log().query(Some("iOS_10.2.0_14C92"), 1).unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].action(), AuditAction::Remove);
+}
+
+#[test]
+fn test_read_only_stash() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+    let state = fs::metadata(dir.path().join("sync.state")).unwrap().modified().unwrap();
+
+    config.set_stash_read_only(true);
+    let stash = MemDbStash::new(&config).unwrap();
+    assert!(stash.is_read_only());
+    match *stash.sync(Default::default()).err().unwrap().kind() {
+        ErrorKind::ReadOnlyStash => {}
+        ref kind => panic!("unexpected error: {}", kind),
+    }
+
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    let uuid = *memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b").unwrap().unwrap();
+    stash.record_lookup(&ios_10_2(), &uuid, "libKoreanConverter.dylib");
+    stash.save_usage_stats().unwrap();
+    stash.quarantine(&ios_10_2(), "test", "test").unwrap();
+
+    assert!(!dir.path().join("usage.state").exists());
+    assert!(!dir.path().join("quarantine").exists());
+    assert!(dir.path().join("iOS_10.2.0_14C92.memdb").is_file());
+    assert_eq!(fs::metadata(dir.path().join("sync.state")).unwrap().modified().unwrap(), state);
+}
+
+#[test]
+fn test_sync_is_exclusive() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    let stash = MemDbStash::new(&config).unwrap();
+    let other = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(other.list_sdks().unwrap(), vec![ios_10_2()]);
+
+    // while someone else holds the lock nothing is synched
+    s3.put_sdk(&ios_10_3()).unwrap();
+    {
+        let _lock = FileLock::try_lock(dir.path().join("sync.lock")).unwrap().unwrap();
+        stash.sync(Default::default()).unwrap();
+        assert!(!dir.path().join("iOS_10.3.0_14E277.memdb").exists());
+    }
+
+    // the other stash picks up the new state instead of synching itself
+    stash.sync(Default::default()).unwrap();
+    let _lock = FileLock::try_lock(dir.path().join("sync.lock")).unwrap().unwrap();
+    other.sync(Default::default()).unwrap();
+    assert_eq!(other.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+}
+
+#[test]
+fn test_external_state_change_is_detected() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+    stash.get_memdb(&ios_10_2()).unwrap();
+    assert!(!stash.reload_if_changed().unwrap());
+
+    s3.remove_sdk(&ios_10_2());
+    s3.put_sdk(&ios_10_3()).unwrap();
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    assert!(stash.reload_if_changed().unwrap());
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_3()]);
+    assert!(stash.get_memdb(&ios_10_2()).is_err());
+    assert!(!stash.reload_if_changed().unwrap());
+}
+
+#[test]
+fn test_consistency_check() {
+    let (_s3, dir, stash) = synced_stash(&[ios_10_2(), ios_10_3()]).unwrap();
+    assert!(stash.check_consistency("test").unwrap().is_consistent());
+
+    fs::remove_file(dir.path().join("iOS_10.2.0_14C92.memdb")).unwrap();
+    fs::File::create(dir.path().join("iOS_9.3.0_13E230.memdb")).unwrap();
+    let report = stash.check_consistency("test").unwrap();
+    assert_eq!(report.checked(), 2);
+    assert_eq!(report.missing(), &[ios_10_2()][..]);
+    assert_eq!(report.unknown(), &["iOS_9.3.0_13E230.memdb".to_string()][..]);
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_3()]);
+    assert_eq!(stash.get_sync_status().unwrap().lag(), 1);
+}
+
+#[test]
+fn test_consistency_check_while_syncing() {
+    let (_s3, dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    fs::remove_file(dir.path().join("iOS_10.2.0_14C92.memdb")).unwrap();
+    let lock = FileLock::try_lock(dir.path().join("sync.lock")).unwrap().unwrap();
+    match *stash.check_consistency("test").unwrap_err().kind() {
+        ErrorKind::StashLocked(_) => {}
+        ref kind => panic!("unexpected error: {}", kind),
+    }
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    drop(lock);
+    assert_eq!(stash.check_consistency("test").unwrap().missing(), &[ios_10_2()][..]);
+}
+
+#[test]
+fn test_sync_unusable_s3_is_offline() {
+    let (s3, _dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    for &fault in &[MockFault::ClockSkew, MockFault::TruncatedListing] {
+        s3.set_fault(Some(fault));
+        let err = stash.sync(Default::default()).err().unwrap();
+        assert!(err.is_s3_offline(), "unexpected error: {}", err);
+        let status = stash.get_sync_status().unwrap();
+        assert!(status.is_offline());
+        assert!(status.is_healthy());
+        assert!(status.warning().is_some());
+    }
+    match *stash.sync(Default::default()).err().unwrap().kind() {
+        ErrorKind::S3IncompleteListing(_) => {}
+        ref kind => panic!("unexpected error: {}", kind),
+    }
+
+    // an empty bucket does not wipe the stash
+    s3.set_fault(None);
+    s3.remove_sdk(&ios_10_2());
+    match *stash.sync(Default::default()).err().unwrap().kind() {
+        ErrorKind::S3IncompleteListing(_) => {}
+        ref kind => panic!("unexpected error: {}", kind),
+    }
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+}
+
+#[test]
+fn test_sync_summaries() {
+    let (s3, _dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    // nothing changed, nothing to report
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.sync_summaries().list().unwrap().len(), 1);
+
+    s3.remove_sdk(&ios_10_2());
+    s3.put_corrupted_sdk(&ios_10_3()).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    let summaries = stash.sync_summaries().list().unwrap();
+    assert_eq!(summaries.len(), 2);
+    let summary = &summaries[0];
+    assert_eq!(summary.actor(), "sync");
+    assert!(summary.finished().unwrap() >= summary.started());
+    assert_eq!(summary.revision(), Some(stash.get_revision().unwrap()));
+    assert_eq!(summary.added()[0].sdk_id(), "iOS_10.3.0_14E277");
+    assert!(summary.updated().is_empty());
+    assert_eq!(summary.removed()[0].sdk_id(), "iOS_10.2.0_14C92");
+    assert!(summary.removed()[0].size() > 0);
+    assert_eq!(summary.failures()[0].sdk_id(), Some("iOS_10.3.0_14E277"));
+    assert_eq!(summaries[1].added()[0].sdk_id(), "iOS_10.2.0_14C92");
+}
+
+#[test]
+fn test_revision_moves_once_per_sync() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let stash = MemDbStash::new(&s3.config(dir.path())).unwrap();
+    assert_eq!(stash.get_revision().unwrap(), 0);
+
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.get_revision().unwrap(), 1);
+
+    // nothing changed
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.get_revision().unwrap(), 1);
+
+    s3.remove_sdk(&ios_10_2());
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.get_revision().unwrap(), 2);
+    assert_eq!(stash.get_sync_status().unwrap().revision(), 2);
+
+    stash.quarantine(&ios_10_3(), "test", "test").unwrap();
+    assert_eq!(stash.get_revision().unwrap(), 3);
+}
+
+#[test]
+fn test_compressed_memdbs() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_stash_compress_memdbs(true);
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    assert!(dir.path().join("iOS_10.2.0_14C92.memdbz").is_file());
+    assert!(!dir.path().join("iOS_10.2.0_14C92.memdb").exists());
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+    assert!(stash.check_consistency("test").unwrap().is_consistent());
+
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    let uuid = *memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b").unwrap().unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(), "___CFFromMacKoreanLen");
+    assert!(dir.path().join("decompressed/iOS_10.2.0_14C92.memdb").is_file());
+
+    s3.remove_sdk(&ios_10_2());
+    stash.sync(Default::default()).unwrap();
+    assert!(!dir.path().join("iOS_10.2.0_14C92.memdbz").exists());
+    assert!(!dir.path().join("decompressed/iOS_10.2.0_14C92.memdb").exists());
+}
+
+#[test]
+fn test_scratch_dir() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let scratch = TempDir::new("symbolserver-scratch").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_stash_compress_memdbs(true);
+    config.set_stash_scratch_dir(scratch.path());
+    config.set_stash_gc_max_age(0);
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    assert!(dir.path().join("iOS_10.2.0_14C92.memdbz").is_file());
+    assert!(stash.get_memdb(&ios_10_2()).is_ok());
+    assert!(dir.path().join("decompressed/iOS_10.2.0_14C92.memdb").is_file());
+    assert_eq!(fs::read_dir(scratch.path()).unwrap().count(), 0);
+    assert!(!dir.path().join("scratch").exists());
+
+    fs::write(scratch.path().join("iOS_10.3.0_14E277.memdb.download"), b"partial").unwrap();
+    fs::write(scratch.path().join("iOS_10.3.0_14E277.memdb.tempmemdb"), b"partial").unwrap();
+    assert_eq!(stash.collect_garbage().unwrap().removed_files(), 2);
+    assert_eq!(fs::read_dir(scratch.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_open_memdb_file() {
+    let (s3, dir, stash) = synced_stash(&[ios_10_2()]).unwrap();
+
+    let memdb_file = stash.open_memdb_file(&ios_10_2(), false).unwrap().unwrap();
+    assert_eq!(memdb_file.size(),
+               fs::metadata(dir.path().join("iOS_10.2.0_14C92.memdb")).unwrap().len());
+    assert!(memdb_file.etag().is_some());
+    assert!(stash.open_memdb_file(&ios_10_2(), true).unwrap().is_none());
+    assert!(stash.open_memdb_file(&ios_10_3(), false).is_err());
+
+    let compressed_dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(compressed_dir.path());
+    config.set_stash_compress_memdbs(true);
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+    let memdb_file = stash.open_memdb_file(&ios_10_2(), true).unwrap().unwrap();
+    assert_eq!(memdb_file.size(),
+               fs::metadata(compressed_dir.path().join("iOS_10.2.0_14C92.memdbz"))
+                   .unwrap().len());
+    assert!(stash.open_memdb_file(&ios_10_2(), false).unwrap().is_some());
+}
+
+/// Reads everything a lookup could touch in a memdb that may be broken.
+fn exercise_memdb(data: &[u8]) {
+    let memdb = match MemDb::from_slice(data) {
+        Ok(memdb) => memdb,
+        Err(_) => return,
+    };
+    memdb.validate().ok();
+    memdb.conversion_info().ok();
+    if let Ok(Some(uuid)) = memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b") {
+        memdb.lookup_by_uuid(uuid, 0x5b14);
+        memdb.nearby_symbols(uuid, 0x5b14);
+        memdb.is_shared_object(uuid);
+        if let Ok(iter) = memdb.iter_symbols(uuid) {
+            for sym in iter.take(10000) {
+                sym.ok();
+            }
+        }
+    }
+    memdb.find_uuid_fuzzy("/usr/lib/libKoreanConverter.dylib:arm64").ok();
+}
+
+#[test]
+fn test_malformed_memdbs_do_not_panic() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    MemDbStash::new(&s3.config(dir.path())).unwrap().sync(Default::default()).unwrap();
+    let good = fs::read(dir.path().join("iOS_10.2.0_14C92.memdb")).unwrap();
+    exercise_memdb(&good);
+    let values = [0, 1, 0x7fff_ffff, 0xffff_ffff, good.len() as u32, good.len() as u32 - 1];
+
+    // every header field with out of range offsets and absurd counts
+    for field in 0..24 {
+        for &value in values.iter() {
+            let mut data = good.clone();
+            let pos = field * 4;
+            data[pos] = value as u8;
+            data[pos + 1] = (value >> 8) as u8;
+            data[pos + 2] = (value >> 16) as u8;
+            data[pos + 3] = (value >> 24) as u8;
+            exercise_memdb(&data);
+        }
+    }
+    for len in (0..good.len()).step_by(good.len() / 64 + 1) {
+        exercise_memdb(&good[..len]);
+    }
+    for pos in (0..good.len()).step_by(good.len() / 512 + 1) {
+        let mut data = good.clone();
+        data[pos] ^= 0xff;
+        exercise_memdb(&data);
+    }
+    // a buffer that is not aligned for the header
+    let mut unaligned = vec![0];
+    unaligned.extend_from_slice(&good);
+    assert!(MemDb::from_slice(&unaligned[1..]).unwrap().validate().is_ok());
+}
+
+/// Runs an API server on a stash in the background and returns its URL.
+fn start_server(config: &Config) -> String {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config = config.clone();
+    thread::spawn(move || {
+        let server = ApiServer::new(&config, false).unwrap();
+        server.run(2, BindOptions::BindToAddr(&addr.to_string())).unwrap();
+    });
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    format!("http://{}/", addr)
+}
+
+#[test]
+fn test_max_requests_per_connection() {
+    let s3 = MockS3::start().unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_server_max_requests_per_connection(2);
+    let url = start_server(&config);
+
+    // three pipelined requests only get two answers before the server
+    // closes the connection, every new connection starts over
+    for _ in 0..2 {
+        let addr = url.trim_start_matches("http://").trim_end_matches('/');
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        for _ in 0..3 {
+            stream.write_all(b"GET /version HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        }
+        let mut data = String::new();
+        stream.read_to_string(&mut data).unwrap();
+        assert_eq!(data.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(data.matches("Connection: close").count(), 1);
+    }
+}
+
+#[test]
+fn test_follow_primary() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let primary_dir = TempDir::new("symbolserver-test").unwrap();
+    let primary_config = s3.config(primary_dir.path());
+    MemDbStash::new(&primary_config).unwrap().sync(Default::default()).unwrap();
+    let primary_url = start_server(&primary_config);
+
+    for &compress_memdbs in &[false, true] {
+        let dir = TempDir::new("symbolserver-test").unwrap();
+        let mut config = Config::default();
+        config.set_symbol_dir(dir.path());
+        config.set_sync_primary(&primary_url);
+        config.set_stash_compress_memdbs(compress_memdbs);
+        let stash = MemDbStash::new(&config).unwrap();
+        stash.sync(Default::default()).unwrap();
+
+        assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+        assert!(stash.verify(&Default::default()).unwrap().is_empty());
+        let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+        let uuid = *memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b")
+            .unwrap().unwrap();
+        assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+                   "___CFFromMacKoreanLen");
+
+        // nothing changed on the primary so nothing is downloaded again
+        let revision = stash.get_revision().unwrap();
+        stash.sync(Default::default()).unwrap();
+        assert_eq!(stash.get_revision().unwrap(), revision);
+        assert_eq!(stash.get_sync_status().unwrap().lag(), 0);
+    }
+
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = Config::default();
+    config.set_symbol_dir(dir.path());
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    config.set_sync_primary(&format!("http://{}/", addr));
+    let stash = MemDbStash::new(&config).unwrap();
+    assert!(stash.sync(Default::default()).unwrap_err().is_s3_offline());
+}
+
+#[test]
+fn test_drain() {
+    let s3 = MockS3::start().unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.add_server_admin_key("admin").unwrap();
+    let url = start_server(&config);
+    let client = Client::new();
+    let ready_url = format!("{}ready", url);
+
+    assert_eq!(client.get(&ready_url).send().unwrap().status, StatusCode::Ok);
+    assert_eq!(client.get(&format!("{}admin/drain", url)).send().unwrap().status,
+               StatusCode::Unauthorized);
+    assert_eq!(client.get(&ready_url).send().unwrap().status, StatusCode::Ok);
+    assert_eq!(client.get(&format!("{}admin/drain", url))
+               .header(Authorization(Bearer { token: "admin".to_string() }))
+               .send().unwrap().status, StatusCode::Ok);
+    assert_eq!(client.get(&ready_url).send().unwrap().status,
+               StatusCode::ServiceUnavailable);
+    // draining does not make the server unhealthy
+    assert_eq!(client.get(&format!("{}health", url)).send().unwrap().status, StatusCode::Ok);
+}
+
+#[test]
+fn test_shutdown_returns_from_run() {
+    let s3 = MockS3::start().unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let server = ApiServer::new(&config, false).unwrap();
+    let shutdown = server.shutdown_handle();
+    let runner = thread::spawn(move || {
+        server.run(2, BindOptions::BindToAddr(&addr.to_string())).unwrap();
+    });
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let url = format!("http://{}/", addr);
+    assert_eq!(Client::new().get(&format!("{}health", url)).send().unwrap().status,
+               StatusCode::Ok);
+    shutdown.shutdown();
+    runner.join().unwrap();
+}
+
+#[test]
+fn test_version() {
+    let s3 = MockS3::start().unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    let url = start_server(&config);
+
+    let mut body = String::new();
+    Client::new().get(&format!("{}version", url)).send().unwrap()
+        .read_to_string(&mut body).unwrap();
+    assert!(body.contains("\"supported\":[2,3,4,5,6,7],\"written\":7"));
+    assert!(body.contains(&format!("\"config_fingerprint\":\"{}\"", config.fingerprint())));
+
+    let mut other_config = config.clone();
+    other_config.set_stash_compress_memdbs(true);
+    assert_eq!(config.fingerprint(), config.clone().fingerprint());
+    assert!(config.fingerprint() != other_config.fingerprint());
+}
+
+#[test]
+fn test_tenants() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let tenant_dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.add_tenant("firmware", "s3://symbols/memdbs", tenant_dir.path());
+    assert!(config.get_tenant_config("nope").is_err());
+    let tenant_config = config.get_tenant_config("firmware").unwrap();
+    MemDbStash::new(&tenant_config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let client = Client::new();
+    let get = |path: &str| {
+        let mut resp = client.get(&format!("{}{}", url, path)).send().unwrap();
+        let mut body = vec![];
+        resp.read_to_end(&mut body).unwrap();
+        (resp.status, String::from_utf8_lossy(&body).into_owned())
+    };
+    let (status, body) = get("tenants/firmware/sdks");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(&ios_10_2().sdk_id()));
+    let (status, body) = get("sdks");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(!body.contains(&ios_10_2().sdk_id()));
+    assert_eq!(get("tenants/firmware/download/iOS_10.2.0_14C92.memdb").0, StatusCode::Ok);
+    assert_eq!(get("tenants/nope/sdks").0, StatusCode::NotFound);
+}
+
+#[test]
+fn test_api_keys() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.add_server_api_key("partner", &["iOS_*", "!iOS_10.3*"]).unwrap();
+    config.add_server_api_key("internal", &["*"]).unwrap();
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let client = Client::new();
+    let lookup = |key: Option<&str>, path: &str, body: &str| {
+        let mut headers = Headers::new();
+        if let Some(key) = key {
+            headers.set(Authorization(Bearer { token: key.to_string() }));
+        }
+        let mut resp = client.post(&format!("{}{}", url, path))
+            .headers(headers).body(body).send().unwrap();
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        (resp.status, body)
+    };
+    let single = |info: &SdkInfo| {
+        format!(r#"{{"sdk_id": "{}", "cpu_name": "arm64", "symbols": []}}"#, info.sdk_id())
+    };
+
+    assert_eq!(lookup(None, "lookup", &single(&ios_10_2())).0, StatusCode::Unauthorized);
+    assert_eq!(lookup(Some("nope"), "lookup", &single(&ios_10_2())).0,
+               StatusCode::Unauthorized);
+    // the body is only looked at once the client is known
+    assert_eq!(lookup(None, "lookup", "not json").0, StatusCode::Unauthorized);
+    assert_eq!(lookup(None, "lookup/batch", "not json").0, StatusCode::Unauthorized);
+    assert_eq!(lookup(Some("partner"), "lookup", "not json").0, StatusCode::BadRequest);
+    assert_eq!(lookup(Some("partner"), "lookup", &single(&ios_10_2())).0, StatusCode::Ok);
+    let (status, body) = lookup(Some("partner"), "lookup", &single(&ios_10_3()));
+    assert_eq!(status, StatusCode::Forbidden);
+    assert!(body.contains("sdk_forbidden"));
+    assert_eq!(lookup(Some("internal"), "lookup", &single(&ios_10_3())).0, StatusCode::Ok);
+
+    let batch = format!(r#"{{"cpu_name": "arm64", "frames": [
+        {{"sdk_id": "{}", "object_name": "x", "addr": "0x1"}},
+        {{"sdk_id": "{}", "object_name": "x", "addr": "0x1"}}]}}"#,
+        ios_10_2().sdk_id(), ios_10_3().sdk_id());
+    let (status, body) = lookup(Some("partner"), "lookup/batch", &batch);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains("sdk_forbidden"));
+}
+
+#[test]
+fn test_api_keys_restrict_listings_and_downloads() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.add_server_api_key("partner", &["iOS_*", "!iOS_10.3*"]).unwrap();
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let client = Client::new();
+    let get = |key: Option<&str>, path: &str| {
+        let mut headers = Headers::new();
+        if let Some(key) = key {
+            headers.set(Authorization(Bearer { token: key.to_string() }));
+        }
+        let mut resp = client.get(&format!("{}{}", url, path)).headers(headers).send().unwrap();
+        let mut body = vec![];
+        resp.read_to_end(&mut body).unwrap();
+        (resp.status, String::from_utf8_lossy(&body).into_owned())
+    };
+
+    for path in &["sdks", "mirror/state", "download/iOS_10.2.0_14C92.memdb"] {
+        assert_eq!(get(None, path).0, StatusCode::Unauthorized);
+    }
+    assert_eq!(get(Some("partner"), "download/iOS_10.2.0_14C92.memdb").0, StatusCode::Ok);
+    let (status, body) = get(Some("partner"), "download/iOS_10.3.0_14E277.memdb");
+    assert_eq!(status, StatusCode::Forbidden);
+    assert!(body.contains("sdk_forbidden"));
+
+    let (status, body) = get(Some("partner"), "sdks");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(&ios_10_2().sdk_id()));
+    assert!(!body.contains(&ios_10_3().sdk_id()));
+    assert!(body.contains("\"total\":1"));
+    let (status, body) = get(Some("partner"), "mirror/state");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(&ios_10_2().sdk_id()));
+    assert!(!body.contains(&ios_10_3().sdk_id()));
+
+    // a follower only gets what its key may use
+    let follower_dir = TempDir::new("symbolserver-test").unwrap();
+    let mut follower_config = Config::default();
+    follower_config.set_symbol_dir(follower_dir.path());
+    follower_config.set_sync_primary(&url);
+    follower_config.set_sync_primary_token("partner");
+    let stash = MemDbStash::new(&follower_config).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+}
+
+#[test]
+fn test_admin_endpoints_need_an_admin() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+    let open_url = start_server(&config);
+    config.add_server_api_key("partner", &["*"]).unwrap();
+    config.add_server_admin_key("admin").unwrap();
+    let url = start_server(&config);
+
+    let client = Client::new();
+    let request = |url: &str, method: &str, path: &str, key: Option<&str>| {
+        let mut headers = Headers::new();
+        if let Some(key) = key {
+            headers.set(Authorization(Bearer { token: key.to_string() }));
+        }
+        let url = format!("{}{}", url, path);
+        let req = match method {
+            "GET" => client.get(&url),
+            "PUT" => client.put(&url).body(r#"{"patterns": ["*"]}"#),
+            "DELETE" => client.delete(&url),
+            _ => client.post(&url).body(""),
+        };
+        req.headers(headers).send().unwrap().status
+    };
+    let endpoints = [
+        ("GET", "admin/usage"),
+        ("GET", "admin/memory"),
+        ("GET", "admin/audit"),
+        ("GET", "admin/ignore"),
+        ("PUT", "admin/ignore"),
+        ("DELETE", "admin/ignore"),
+        ("POST", "admin/sdks/iOS_10.2.0_14C92/evict"),
+        ("POST", "admin/sdks/iOS_10.2.0_14C92/reload"),
+        ("POST", "admin/drain"),
+    ];
+    for &(method, path) in endpoints.iter() {
+        // without keys lookups are open, but the admin endpoints are not
+        assert_eq!(request(&open_url, method, path, None), StatusCode::Unauthorized,
+                   "{} {}", method, path);
+        assert_eq!(request(&url, method, path, None), StatusCode::Unauthorized,
+                   "{} {}", method, path);
+        assert_eq!(request(&url, method, path, Some("partner")), StatusCode::Forbidden,
+                   "{} {}", method, path);
+    }
+    // nobody managed to ignore everything
+    assert!(!MemDbStash::new(&config).unwrap().sdk_is_ignored(&ios_10_2()));
+
+    for &(method, path) in endpoints.iter() {
+        assert_eq!(request(&url, method, path, Some("admin")), StatusCode::Ok,
+                   "{} {}", method, path);
+    }
+}
+
+#[test]
+#[cfg(feature = "crypto")]
+fn test_jwt_auth() {
+    use libsymbolserver::config::AuthMode;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::sign::Signer;
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_server_auth_mode(AuthMode::Jwt);
+    config.set_server_jwt_secret("s3cr3t");
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let token = |claims: &str, secret: &[u8]| {
+        let signed = format!("{}.{}", br#"{"alg":"HS256","typ":"JWT"}"#.to_base64(URL_SAFE),
+                             claims.as_bytes().to_base64(URL_SAFE));
+        let key = PKey::hmac(secret).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        format!("{}.{}", signed, signer.sign_to_vec().unwrap().to_base64(URL_SAFE))
+    };
+    let url = start_server(&config);
+    let client = Client::new();
+    let lookup = |token: &str, info: &SdkInfo| {
+        client.post(&format!("{}lookup", url))
+            .header(Authorization(Bearer { token: token.to_string() }))
+            .body(&format!(r#"{{"sdk_id": "{}", "cpu_name": "arm64", "symbols": []}}"#,
+                           info.sdk_id())[..])
+            .send().unwrap().status
+    };
+
+    // 2100-01-01
+    let partner = token(r#"{"sub":"partner","exp":4102444800,"sdks":["iOS_*","!iOS_10.3*"]}"#,
+                        b"s3cr3t");
+    assert_eq!(lookup(&partner, &ios_10_2()), StatusCode::Ok);
+    assert_eq!(lookup(&partner, &ios_10_3()), StatusCode::Forbidden);
+    assert_eq!(lookup(&token(r#"{"sub":"internal","exp":4102444800}"#, b"s3cr3t"),
+                      &ios_10_3()),
+               StatusCode::Ok);
+    assert_eq!(lookup(&token(r#"{"sub":"internal","exp":4102444800}"#, b"guessed"),
+                      &ios_10_2()),
+               StatusCode::Unauthorized);
+    assert_eq!(lookup(&token(r#"{"sub":"internal","exp":1000}"#, b"s3cr3t"), &ios_10_2()),
+               StatusCode::Unauthorized);
+    assert_eq!(lookup(&token(r#"{"sub":"internal"}"#, b"s3cr3t"), &ios_10_2()),
+               StatusCode::Unauthorized);
+    assert_eq!(lookup("partner", &ios_10_2()), StatusCode::Unauthorized);
+}
+
+#[test]
+#[cfg(feature = "crypto")]
+fn test_client_cert_auth() {
+    use libsymbolserver::config::AuthMode;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnectorBuilder, SslMethod, SSL_VERIFY_NONE};
+    use openssl::x509::{X509, X509Name};
+
+    fn make_cert(common_name: &str, issuer: Option<(&X509, &PKey)>) -> (X509, PKey) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        match issuer {
+            Some((cert, issuer_key)) => {
+                builder.set_issuer_name(cert.subject_name()).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let (ca_cert, ca_key) = make_cert("clients", None);
+    let (server_cert, server_key) = make_cert("localhost", None);
+    let write_pem = |name: &str, pem: Vec<u8>| {
+        let path = dir.path().join(name);
+        fs::write(&path, pem).unwrap();
+        path
+    };
+    let mut config = s3.config(dir.path());
+    config.set_server_auth_mode(AuthMode::ClientCert);
+    config.set_server_tls(write_pem("cert.pem", server_cert.to_pem().unwrap()),
+                          write_pem("key.pem", server_key.private_key_to_pem().unwrap()),
+                          Some(write_pem("clients.pem", ca_cert.to_pem().unwrap())));
+    config.add_server_tls_admin_client("ops");
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+    let url = start_server(&config);
+    let addr = url.trim_start_matches("http://").trim_end_matches('/').to_string();
+
+    // returns `None` if the server refused the connection
+    let request = |cert: Option<&(X509, PKey)>, path: &str| -> Option<String> {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+        builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+        if let Some(&(ref cert, ref key)) = cert {
+            builder.builder_mut().set_certificate(cert).unwrap();
+            builder.builder_mut().set_private_key(key).unwrap();
+        }
+        let stream = TcpStream::connect(&addr[..]).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut stream = builder.build()
+            .danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+            .ok()?;
+        write!(stream, "GET /{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+               path).ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        response.lines().next().map(|x| x.to_string())
+    };
+
+    let client = make_cert("crash-processor", Some((&ca_cert, &ca_key)));
+    let admin = make_cert("ops", Some((&ca_cert, &ca_key)));
+    let impostor = make_cert("ops", None);
+    assert_eq!(request(Some(&client), "sdks").unwrap(), "HTTP/1.1 200 OK");
+    assert_eq!(request(Some(&client), "admin/usage").unwrap(), "HTTP/1.1 403 Forbidden");
+    assert_eq!(request(Some(&admin), "admin/usage").unwrap(), "HTTP/1.1 200 OK");
+    assert_eq!(request(Some(&impostor), "sdks"), None);
+    assert_eq!(request(None, "sdks"), None);
+}
+
+#[test]
+fn test_lookup_absolute_addr() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let client = Client::new();
+    let lookup = |symbol: &str| {
+        let body = format!(r#"{{"sdk_id": "iOS_10.2.0_14C92", "cpu_name": "arm64",
+            "symbols": [{{"object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", {}}}]}}"#,
+            symbol);
+        let mut resp = client.post(&format!("{}lookup", url)).body(&body).send().unwrap();
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        (resp.status, body)
+    };
+
+    let (status, body) = lookup(r#""addr": "0x180005b14", "image_addr": "0x180000000""#);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""symbol":"___CFFromMacKoreanLen","addr":"0x180005b10""#));
+    let (status, body) = lookup(
+        r#""addr": "0x190005b14", "image_vmaddr": "0x180000000", "slide": "0x10000000""#);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""addr":"0x190005b10""#));
+    let (status, body) = lookup(r#""addr": "0x5b14", "image_addr": "0x180000000""#);
+    assert_eq!(status, StatusCode::BadRequest);
+    assert!(body.contains("address_before_image"));
+    assert_eq!(lookup(r#""addr": "0x5b14", "slide": "0x10""#).0, StatusCode::BadRequest);
+}
+
+#[test]
+fn test_lookup_msgpack_response() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let client = Client::new();
+    let lookup = |path: &str, body: &str, accept: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("Accept", vec![accept.as_bytes().to_vec()]);
+        let mut resp = client.post(&format!("{}{}", url, path))
+            .headers(headers).body(body).send().unwrap();
+        let mut body = vec![];
+        resp.read_to_end(&mut body).unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        (resp.headers.get_raw("Content-Type").unwrap()[0].clone(), body)
+    };
+    let contains = |haystack: &[u8], needle: &[u8]| {
+        haystack.windows(needle.len()).any(|x| x == needle)
+    };
+
+    let body = r#"{"sdk_id": "iOS_10.2.0_14C92", "cpu_name": "arm64",
+        "symbols": [{"object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", "addr": "0x5b14"}]}"#;
+    let (content_type, data) = lookup("lookup", body, "application/msgpack");
+    assert_eq!(content_type, b"application/msgpack".to_vec());
+    assert_eq!(data[..9].to_vec(), b"\x82\xa7symbols".to_vec());
+    assert!(contains(&data, b"\xa6symbol\xb5___CFFromMacKoreanLen\xa4addr\xa60x5b10"));
+
+    let batch = r#"{"cpu_name": "arm64", "frames": [{"sdk_id": "iOS_10.2.0_14C92",
+        "object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", "addr": "0x5b14"}]}"#;
+    let (content_type, data) = lookup("lookup/batch", batch, "application/x-msgpack");
+    assert_eq!(content_type, b"application/msgpack".to_vec());
+    assert!(contains(&data, b"\xa6frames\x81\xa10"));
+
+    // JSON wins if the client prefers it
+    let (content_type, data) = lookup(
+        "lookup/batch", batch, "application/json, application/msgpack;q=0.5");
+    assert_eq!(content_type, b"application/json".to_vec());
+    assert!(contains(&data, br#""symbol":"___CFFromMacKoreanLen""#));
+}
+
+/// Sends a request on its own connection and reads until the server hung
+/// up, which it only does once it finished with the request.
+fn send_to_completion(url: &str, request: &str) -> String {
+    let mut stream = TcpStream::connect(url.trim_start_matches("http://").trim_end_matches('/'))
+        .unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut data = String::new();
+    stream.read_to_string(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn test_metrics_count_requests() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let body = r#"{"sdk_id": "iOS_10.2.0_14C92", "cpu_name": "arm64",
+        "symbols": [{"object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b",
+                     "addr": "0x5b14"}]}"#;
+    let request = format!("POST /lookup HTTP/1.1\r\nHost: localhost\r\n\
+                           Connection: close\r\nContent-Length: {}\r\n\r\n{}",
+                          body.len(), body);
+    for _ in 0..2 {
+        let data = send_to_completion(&url, &request);
+        assert!(data.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    // the lookups are done, only the metrics request itself is in flight
+    let mut resp = Client::new().get(&format!("{}metrics", url)).send().unwrap();
+    let mut body = String::new();
+    resp.read_to_string(&mut body).unwrap();
+    assert!(body.contains(r#""requests":3,"requests_in_flight":1,"#));
+    assert!(body.contains(r#""memdb_cache":{"hits":1,"misses":1}"#));
+}
+
+#[test]
+fn test_record_and_replay() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let record_path = dir.path().join("replay.jsonl");
+    let mut config = s3.config(dir.path());
+    config.set_server_record_path(&record_path);
+    config.set_server_record_sample_rate(1.0);
+    config.add_server_api_key("internal", &["*"]).unwrap();
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let client = Client::new();
+    let lookup = |key: Option<&str>, path: &str, body: &str| {
+        let mut headers = Headers::new();
+        if let Some(key) = key {
+            headers.set(Authorization(Bearer { token: key.to_string() }));
+        }
+        client.post(&format!("{}{}", url, path)).headers(headers).body(body).send().unwrap()
+            .status
+    };
+    let body = r#"{"sdk_id": "iOS_10.2.0_14C92", "cpu_name": "arm64",
+        "symbols": [{"object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", "addr": "0x5b14"},
+                    {"object_name": "/private/var/containers/Bundle/Application/Foo.app/Foo",
+                     "addr": "0x1000"}]}"#;
+    assert_eq!(lookup(Some("internal"), "lookup", body), StatusCode::Ok);
+    // requests that fail authentication are not recorded
+    assert_eq!(lookup(None, "lookup", body), StatusCode::Unauthorized);
+    let body = r#"{"cpu_name": "arm64", "frames": [{"id": "secret", "sdk_id": "iOS_10.2.0_14C92",
+        "object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", "addr": "0x5b14"}]}"#;
+    assert_eq!(lookup(Some("internal"), "lookup/batch", body), StatusCode::Ok);
+
+    let recorded = fs::read_to_string(&record_path).unwrap();
+    assert_eq!(recorded.lines().count(), 2);
+    assert!(recorded.contains(r#""path":"/lookup/batch""#));
+    assert!(recorded.contains("/anonymized/"));
+    assert!(!recorded.contains("Foo.app"));
+    assert!(!recorded.contains("secret"));
+    // the objects are not named, so they could be apps of the clients
+    assert!(!recorded.contains("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b"));
+
+    let report = replay(&record_path, &ReplayOptions {
+        url: url.clone(),
+        api_key: Some("internal".to_string()),
+        speed: 0.0,
+        concurrency: 2,
+    }).unwrap();
+    assert_eq!(report.sent, 2);
+    assert_eq!(report.statuses.get(&200), Some(&2));
+}
+
+#[test]
+fn test_lookup_breakpad_frames() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let client = Client::new();
+    let lookup = |symbol: &str| {
+        let body = format!(r#"{{"sdk_id": "iOS_10.2.0_14C92", "cpu_name": "arm64",
+            "symbols": [{{{}}}]}}"#, symbol);
+        let mut resp = client.post(&format!("{}lookup", url)).body(&body).send().unwrap();
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        (resp.status, body)
+    };
+
+    for debug_id in &["FE6D76D48C3A3A9A9F63F4A475501F1B0",
+                      "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b-0"] {
+        let (status, body) = lookup(&format!(
+            r#""debug_id": "{}", "module_offset": "0x5b14""#, debug_id));
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.contains(r#""symbol":"___CFFromMacKoreanLen","addr":"0x5b10""#));
+    }
+    for object_uuid in &["FE6D76D4-8C3A-3A9A-9F63-F4A475501F1B",
+                         "fe6d76d48c3a3a9a9f63f4a475501f1b",
+                         "FE6D76D48C3A3A9A9F63F4A475501F1B0"] {
+        let (status, body) = lookup(&format!(
+            r#""object_uuid": "{}", "addr": "0x5b14""#, object_uuid));
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.contains(r#""symbol":"___CFFromMacKoreanLen","addr":"0x5b10""#));
+    }
+    assert_eq!(lookup(r#""debug_id": "FE6D76D48C3A3A9A9F63F4A475501F1B1",
+        "module_offset": "0x5b14""#).0, StatusCode::BadRequest);
+    assert_eq!(lookup(r#""debug_id": "FE6D76D48C3A3A9A9F63F4A475501F1B0",
+        "module_offset": "0x5b14", "image_addr": "0x180000000""#).0, StatusCode::BadRequest);
+    assert_eq!(lookup(r#""debug_id": "FE6D76D48C3A3A9A9F63F4A475501F1B0""#).0,
+               StatusCode::BadRequest);
+}
+
+#[test]
+fn test_lookup_near_misses() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let body = r#"{"sdk_id": "iOS_10.2.0_14C92", "cpu_name": "arm64", "symbols": [
+        {"object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", "addr": "0x5b14"},
+        {"object_uuid": "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b", "addr": "0x1"}]}"#;
+    let mut resp = Client::new().post(&format!("{}lookup?near_misses=1", url))
+        .body(body).send().unwrap();
+    let mut body = String::new();
+    resp.read_to_string(&mut body).unwrap();
+    assert_eq!(resp.status, StatusCode::Ok);
+    assert!(body.contains(r#""near_misses":[{"index":1,"preceding":null,"following":{"#));
+    assert!(body.contains(r#""addr":"0x1370","distance":4975}"#));
+}
+
+#[test]
+#[cfg(feature = "crypto")]
+fn test_sync_checks_signatures() {
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid;
+    use openssl::pkey::PKey;
+
+    let group = EcGroup::from_curve_name(nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let other_key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.sign_sdk(&ios_10_2(), &other_key).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let key_path = dir.path().join("trusted.pem");
+    fs::File::create(&key_path).unwrap().write_all(&key.public_key_to_pem().unwrap()).unwrap();
+    fs::create_dir(dir.path().join("lenient")).unwrap();
+    fs::create_dir(dir.path().join("strict")).unwrap();
+    let mut config = s3.config(dir.path().join("lenient"));
+    config.add_sync_trusted_key(&key_path);
+
+    // unsigned SDKs are accepted unless signatures are required, badly
+    // signed ones never
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_3()]);
+
+    config.set_symbol_dir(dir.path().join("strict"));
+    config.set_sync_require_signatures(true);
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![]);
+
+    s3.sign_sdk(&ios_10_2(), &key).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+
+    // followers do not check signatures, so they cannot require them
+    config.set_sync_primary("http://127.0.0.1:1/");
+    assert!(MemDbStash::new(&config).is_err());
+
+    // minisign keys are refused up front
+    let minisign_path = dir.path().join("minisign.pub");
+    fs::write(&minisign_path, "untrusted comment: minisign public key 1\n\
+                               RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3\n").unwrap();
+    let mut config = s3.config(dir.path().join("strict"));
+    config.add_sync_trusted_key(&minisign_path);
+    assert!(MemDbStash::new(&config).is_err());
+}
+
+#[test]
+#[cfg(feature = "crypto")]
+fn test_encrypted_memdbs() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let key_path = dir.path().join("memdb.key");
+    fs::File::create(&key_path).unwrap().write_all(&[b'a'; 64]).unwrap();
+
+    for &compress in &[false, true] {
+        let symbol_dir = dir.path().join(format!("compressed-{}", compress));
+        fs::create_dir(&symbol_dir).unwrap();
+        let mut config = s3.config(&symbol_dir);
+        config.set_stash_compress_memdbs(compress);
+        config.set_stash_encryption_key(&key_path);
+        let stash = MemDbStash::new(&config).unwrap();
+        stash.sync(Default::default()).unwrap();
+
+        let filename = if compress { "iOS_10.2.0_14C92.memdbz" } else { "iOS_10.2.0_14C92.memdb" };
+        let mut contents = vec![];
+        fs::File::open(symbol_dir.join(filename)).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents.starts_with(b"SYMENC"));
+        assert!(stash.verify(&Default::default()).unwrap().is_empty());
+
+        let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+        let uuid = *memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b")
+            .unwrap().unwrap();
+        assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+                   "___CFFromMacKoreanLen");
+        let cached = symbol_dir.join("decompressed/iOS_10.2.0_14C92.memdb");
+        assert_eq!(fs::metadata(&cached).unwrap().permissions().mode() & 0o777, 0o600);
+        let memdb_file = stash.open_memdb_file(&ios_10_2(), false).unwrap().unwrap();
+        assert_eq!(memdb_file.size(), memdb.size() as u64);
+
+        // without the key the memdb cannot be opened but is not quarantined
+        let stash = MemDbStash::new(&s3.config(&symbol_dir)).unwrap();
+        match stash.get_memdb(&ios_10_2()) {
+            Err(ref err) => match *err.kind() {
+                ErrorKind::UnknownEncryptionKey(_) => {}
+                ref kind => panic!("unexpected error {:?}", kind),
+            },
+            Ok(_) => panic!("opened an encrypted memdb without the key"),
+        }
+        assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2()]);
+    }
+}
+
+#[test]
+fn test_sync_thin_sdks() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_thin_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let stash = MemDbStash::new(&s3.config(dir.path())).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    let object_key = "objects/fe/fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b.memdb";
+    assert!(dir.path().join(object_key).is_file());
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    assert!(memdb.is_thin());
+    let uuid = *memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b")
+        .unwrap().unwrap();
+    let object = stash.get_object_memdb(memdb, &uuid).unwrap();
+    assert_eq!(object.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+               "___CFFromMacKoreanLen");
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+
+    // the objects of the next SDK are already there and are not fetched
+    s3.put_thin_sdk(&ios_10_3()).unwrap();
+    s3.remove_object(object_key);
+    stash.sync(Default::default()).unwrap();
+    assert_eq!(stash.list_sdks().unwrap(), vec![ios_10_2(), ios_10_3()]);
+
+    fs::File::create(dir.path().join(object_key)).unwrap().write_all(b"broken").unwrap();
+    assert_eq!(stash.verify(&Default::default()).unwrap().len(), 2);
+}
+
+#[test]
+fn test_follow_primary_thin_sdks() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_thin_sdk(&ios_10_2()).unwrap();
+    let primary_dir = TempDir::new("symbolserver-test").unwrap();
+    let mut primary_config = s3.config(primary_dir.path());
+    primary_config.add_server_api_key("follower", &["*"]).unwrap();
+    MemDbStash::new(&primary_config).unwrap().sync(Default::default()).unwrap();
+    let primary_url = start_server(&primary_config);
+
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = Config::default();
+    config.set_symbol_dir(dir.path());
+    config.set_sync_primary(&primary_url);
+    config.set_sync_primary_token("follower");
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    let object_key = "objects/fe/fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b.memdb";
+    assert!(dir.path().join(object_key).is_file());
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    let uuid = *memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b")
+        .unwrap().unwrap();
+    let object = stash.get_object_memdb(memdb, &uuid).unwrap();
+    assert_eq!(object.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+               "___CFFromMacKoreanLen");
+
+    // objects are only handed out to clients that may use the SDK
+    let client = Client::new();
+    let object_url = format!("{}mirror/objects/{}/{}", primary_url, ios_10_2().sdk_id(), uuid);
+    assert_eq!(client.get(&object_url).send().unwrap().status, StatusCode::Unauthorized);
+    assert_eq!(client.get(&object_url)
+               .header(Authorization(Bearer { token: "follower".to_string() }))
+               .send().unwrap().status, StatusCode::Ok);
+}
+
+#[test]
+fn test_sync_lazy_objects() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_thin_sdk(&ios_10_2()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_sync_lazy_objects(true);
+    config.set_sync_object_fetch_budget_ms(10000);
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    // only the manifest is synced
+    let object_key = "objects/fe/fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b.memdb";
+    assert!(!dir.path().join(object_key).is_file());
+    assert!(stash.verify(&Default::default()).unwrap().is_empty());
+
+    // the first lookup fetches the object
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    let uuid = *memdb.find_uuid_fuzzy("fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b")
+        .unwrap().unwrap();
+    let object = stash.get_object_memdb(memdb, &uuid).unwrap();
+    assert_eq!(object.lookup_by_uuid(&uuid, 0x5b10).unwrap().symbol(),
+               "___CFFromMacKoreanLen");
+    assert!(dir.path().join(object_key).is_file());
+
+    // objects that are used are prefetched by the next sync
+    stash.record_lookup(&ios_10_2(), &uuid, "/usr/lib/libKoreanConverter.dylib");
+    fs::remove_file(dir.path().join(object_key)).unwrap();
+    stash.sync(Default::default()).unwrap();
+    assert!(dir.path().join(object_key).is_file());
+
+    // an object that could not be fetched is not fetched again right away
+    let data = fs::read(dir.path().join(object_key)).unwrap();
+    fs::remove_file(dir.path().join(object_key)).unwrap();
+    s3.remove_object(object_key);
+    let stash = MemDbStash::new(&config).unwrap();
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    assert!(Arc::ptr_eq(&stash.get_object_memdb(memdb.clone(), &uuid).unwrap(), &memdb));
+    s3.put_object(object_key, data);
+    assert!(Arc::ptr_eq(&stash.get_object_memdb(memdb.clone(), &uuid).unwrap(), &memdb));
+    assert!(!dir.path().join(object_key).is_file());
+
+    // lookups that cannot wait for the object are asked to come back
+    config.set_sync_object_fetch_budget_ms(0);
+    let stash = MemDbStash::new(&config).unwrap();
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    match stash.get_object_memdb(memdb, &uuid) {
+        Err(ref err) => match *err.kind() {
+            ErrorKind::ObjectFetchPending(_) => {}
+            ref kind => panic!("unexpected error: {}", kind),
+        },
+        Ok(_) => panic!("the object was fetched without waiting"),
+    }
+    for _ in 0..100 {
+        if dir.path().join(object_key).is_file() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(dir.path().join(object_key).is_file());
+}
+
+#[test]
+fn test_sdk_conversions() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config = s3.config(dir.path());
+    let stash = MemDbStash::new(&config).unwrap();
+    stash.sync(Default::default()).unwrap();
+
+    let memdb = stash.get_memdb(&ios_10_2()).unwrap();
+    assert_eq!(memdb.format_version().unwrap(), 7);
+    let conversion = memdb.conversion_info().unwrap().unwrap();
+    assert_eq!(conversion.converter_version, VERSION);
+    assert_eq!(conversion.format_version, 7);
+    assert!(conversion.options.compress);
+    assert!(!conversion.options.thin);
+    // the fingerprint only depends on the objects
+    let other = stash.get_memdb(&ios_10_3()).unwrap();
+    assert_eq!(other.conversion_info().unwrap().unwrap().sdk_fingerprint,
+               conversion.sdk_fingerprint);
+
+    let url = start_server(&config);
+    let mut resp = Client::new().get(&format!("{}sdks?conversions=1", url)).send().unwrap();
+    let mut body = String::new();
+    resp.read_to_string(&mut body).unwrap();
+    assert_eq!(resp.status, StatusCode::Ok);
+    assert!(body.contains("\"sdk_id\":\"iOS_10.2.0_14C92\",\"format_version\":7"));
+    assert!(body.contains(&format!("\"sdk_fingerprint\":\"{}\"", conversion.sdk_fingerprint)));
+
+    // a broken memdb does not fail the whole list
+    fs::remove_file(dir.path().join("iOS_10.3.0_14E277.memdb")).unwrap();
+    fs::write(dir.path().join("iOS_10.3.0_14E277.memdb"), b"broken").unwrap();
+    let conversions = stash.list_sdk_conversions().unwrap();
+    assert_eq!(conversions.len(), 2);
+    assert_eq!(conversions[0].format_version(), Some(7));
+    assert!(conversions[0].error().is_none());
+    assert_eq!(conversions[1].sdk_id(), "iOS_10.3.0_14E277");
+    assert!(conversions[1].format_version().is_none());
+    assert!(conversions[1].error().is_some());
+}
+
+#[test]
+fn test_list_sdks_paginated() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let mut config = s3.config(dir.path());
+    config.set_server_max_page_size(1);
+    config.add_server_admin_key("admin").unwrap();
+    MemDbStash::new(&config).unwrap().sync(Default::default()).unwrap();
+
+    let url = start_server(&config);
+    let get_from = |url: &str, path: &str| {
+        let mut resp = Client::new().get(&format!("{}{}", url, path))
+            .header(Authorization(Bearer { token: "admin".to_string() })).send().unwrap();
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        (resp.status, body)
+    };
+    let get = |path: &str| get_from(&url, path);
+    let (status, body) = get("sdks");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""sdks":["iOS_10.2.0_14C92"],"total":2,"next_offset":1"#));
+    let (status, body) = get("sdks?limit=10");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""sdks":["iOS_10.2.0_14C92"],"total":2,"next_offset":1"#));
+    let (status, body) = get("sdks?offset=1&limit=10");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""sdks":["iOS_10.3.0_14E277"],"total":2,"next_offset":null"#));
+    let (status, body) = get("admin/audit?limit=1");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""next_offset":1"#));
+    let (status, body) = get(&format!("admin/audit?offset={}", ::std::usize::MAX));
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""events":[]"#));
+    assert_eq!(get("sdks?limit=0").0, StatusCode::BadRequest);
+    let (status, body) = get("admin/usage");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""total":0,"next_offset":null"#));
+    assert_eq!(get("admin/usage?limit=0").0, StatusCode::BadRequest);
+
+    // the old unpaged listing is still there if asked for
+    config.set_server_unpaged_sdk_list(true);
+    let unpaged_url = start_server(&config);
+    let (status, body) = get_from(&unpaged_url, "sdks");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(
+        r#""sdks":["iOS_10.2.0_14C92","iOS_10.3.0_14E277"],"total":2,"next_offset":null"#));
+    let (status, body) = get_from(&unpaged_url, "sdks?limit=10");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""sdks":["iOS_10.2.0_14C92"],"total":2,"next_offset":1"#));
+}
+
+#[test]
+fn test_reconvert() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    s3.put_manifest("index.json", 1);
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let inputs = TempDir::new("symbolserver-test").unwrap();
+    fixture_sdk_folder(inputs.path(), &ios_10_2()).unwrap();
+    let config = s3.config(dir.path());
+    let server = S3Server::from_config(&config).unwrap();
+
+    // another worker is converting iOS 10.2
+    let claim_key = get_claim_key("index.json", &ios_10_2());
+    assert_eq!(claim_key, "index.json.claims/iOS_10.2.0_14C92.json");
+    server.put_json_object(&claim_key, format!(
+        r#"{{"worker":"other","claimed_at":{}}}"#,
+        chrono::Utc::now().timestamp() - 60).into_bytes()).unwrap();
+
+    let mut opts = ReconvertOptions {
+        min_converter_version: VERSION.to_string(),
+        inputs: inputs.path().to_path_buf(),
+        manifest_key: "index.json".into(),
+        worker: "test".into(),
+        claim_timeout: chrono::Duration::hours(1),
+        dry_run: false,
+        dump: Default::default(),
+    };
+    assert!(reconvert(&server, &opts).unwrap().outdated.is_empty());
+
+    opts.min_converter_version = "999.0".into();
+    let report = reconvert(&server, &opts).unwrap();
+    assert_eq!(report.outdated, vec![ios_10_2(), ios_10_3()]);
+    assert!(report.converted.is_empty());
+    assert_eq!(report.skipped, vec![
+        (ios_10_2(), "claimed by other".to_string()),
+        (ios_10_3(), "no archived support folder".to_string()),
+    ]);
+
+    // the claim of the other worker expired
+    opts.claim_timeout = chrono::Duration::seconds(10);
+    let report = reconvert(&server, &opts).unwrap();
+    assert_eq!(report.converted, vec![ios_10_2()]);
+    let manifest = server.get_manifest("index.json").unwrap().unwrap();
+    assert_eq!(manifest.revision(), Some(2));
+    assert_eq!(manifest.sdk_count(), 2);
+    assert!(server.get_object(&claim_key).unwrap().is_none());
+}
+
+#[test]
+fn test_sandboxed_conversion() {
+    use std::env;
+    use std::io::Cursor;
+    use libsymbolserver::sandbox::Sandbox;
+    use libsymbolserver::sdk::{DumpOptions, Sdk};
+
+    // integration tests live in target/<profile>/deps next to the binary
+    let binary = env::current_exe().unwrap().parent().unwrap().parent().unwrap()
+        .join("sentry-symbolserver");
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let sdk_path = fixture_sdk_folder(tmp.path(), &ios_10_2()).unwrap();
+    let lib_path = sdk_path.join("Symbols/usr/lib");
+    fs::File::create(lib_path.join("README")).unwrap().write_all(b"not an object").unwrap();
+    let sdk = Sdk::new(&sdk_path).unwrap();
+
+    let mut expected = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut expected, Default::default()).unwrap();
+    let mut buf = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut buf, DumpOptions {
+        sandbox: Some(Sandbox::new(&binary)),
+        ..Default::default()
+    }).unwrap();
+    assert!(buf.get_ref() == expected.get_ref());
+    let memdb = MemDb::from_vec(buf.into_inner()).unwrap();
+    let uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b14).unwrap().symbol(), "___CFFromMacKoreanLen");
+
+    // the parser panics on a truncated object, which only takes down the child
+    let mut data = vec![];
+    fs::File::open(lib_path.join("libKoreanConverter.dylib")).unwrap()
+        .read_to_end(&mut data).unwrap();
+    fs::File::create(lib_path.join("truncated.dylib")).unwrap().write_all(&data[..4096]).unwrap();
+    let mut buf = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut buf, DumpOptions {
+        sandbox: Some(Sandbox::new(&binary)),
+        ..Default::default()
+    }).unwrap();
+    let memdb = MemDb::from_vec(buf.into_inner()).unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b14).unwrap().symbol(), "___CFFromMacKoreanLen");
+    let skipped = memdb.conversion_info().unwrap().unwrap().skipped;
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].filename, "/usr/lib/truncated.dylib");
+}
+
+#[test]
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn test_seccomp_conversion() {
+    use std::env;
+    use std::io::Cursor;
+    use libsymbolserver::sandbox::Sandbox;
+    use libsymbolserver::sdk::{DumpOptions, Sdk};
+
+    let binary = env::current_exe().unwrap().parent().unwrap().parent().unwrap()
+        .join("sentry-symbolserver");
+    let tmp = TempDir::new("symbolserver-test").unwrap();
+    let sdk_path = fixture_sdk_folder(tmp.path(), &ios_10_2()).unwrap();
+    let sdk = Sdk::new(&sdk_path).unwrap();
+
+    // the children only get to parse the objects after entering seccomp
+    let mut expected = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut expected, Default::default()).unwrap();
+    let mut sandbox = Sandbox::new(&binary);
+    sandbox.seccomp = true;
+    let mut buf = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut buf, DumpOptions {
+        sandbox: Some(sandbox),
+        ..Default::default()
+    }).unwrap();
+    assert!(buf.get_ref() == expected.get_ref());
+    let memdb = MemDb::from_vec(buf.into_inner()).unwrap();
+    assert!(memdb.conversion_info().unwrap().unwrap().skipped.is_empty());
+}
+
+#[test]
+fn test_convert_sdk_from_url() {
+    use std::io::Cursor;
+    use libsymbolserver::download::{get_download_dir, parse_sdk_url, DownloadedSdk};
+    use libsymbolserver::report::default_reporter;
+    use libsymbolserver::sdk::Sdk;
+
+    let s3 = MockS3::start().unwrap();
+    let zip = fixture_sdk_zip().unwrap();
+    s3.put_object("iOS%20DeviceSupport/10.2%20(14C92).zip", zip.clone());
+    let scratch = TempDir::new("symbolserver-test").unwrap();
+    let reporter = default_reporter();
+
+    let url = parse_sdk_url(&s3.object_url("ios.zip")).unwrap();
+    assert!(DownloadedSdk::fetch(&url, scratch.path(), &*reporter).is_err());
+    assert!(parse_sdk_url("iOS DeviceSupport/10.2 (14C92).zip").is_none());
+    let url = parse_sdk_url(&s3.object_url("iOS%20DeviceSupport/..%2F..%2F10.2%20(14C92).zip"))
+        .unwrap();
+    assert!(DownloadedSdk::fetch(&url, scratch.path(), &*reporter).is_err());
+
+    // an earlier attempt left a part of another version behind
+    let url = parse_sdk_url(&s3.object_url("iOS%20DeviceSupport/10.2%20(14C92).zip")).unwrap();
+    let dir = get_download_dir(scratch.path(), &url).join("iOS DeviceSupport");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("10.2 (14C92).zip.part"), &[0u8; 100][..]).unwrap();
+    fs::write(dir.join("10.2 (14C92).zip.part.validator"), "etag \"outdated\"").unwrap();
+    let download = DownloadedSdk::fetch(&url, scratch.path(), &*reporter).unwrap();
+    assert!(fs::read(download.path()).unwrap() == zip);
+    download.remove().unwrap();
+
+    // an earlier attempt left a part of this version behind
+    let resp = Client::new().get(url.as_str()).send().unwrap();
+    let etag = resp.headers.get::<ETag>().unwrap().0.clone();
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("10.2 (14C92).zip.part"), &zip[..100]).unwrap();
+    fs::write(dir.join("10.2 (14C92).zip.part.validator"), format!("etag {}", etag)).unwrap();
+
+    let download = DownloadedSdk::fetch(&url, scratch.path(), &*reporter).unwrap();
+    let mut data = vec![];
+    fs::File::open(download.path()).unwrap().read_to_end(&mut data).unwrap();
+    assert!(data == zip);
+    let sdk = Sdk::new(download.path()).unwrap();
+    assert_eq!(sdk.info(), &ios_10_2());
+    let mut buf = Cursor::new(vec![]);
+    sdk.dump_memdb(&mut buf, Default::default()).unwrap();
+    let memdb = MemDb::from_vec(buf.into_inner()).unwrap();
+    let uuid = "fe6d76d4-8c3a-3a9a-9f63-f4a475501f1b".parse().unwrap();
+    assert_eq!(memdb.lookup_by_uuid(&uuid, 0x5b14).unwrap().symbol(), "___CFFromMacKoreanLen");
+
+    download.remove().unwrap();
+    assert!(!get_download_dir(scratch.path(), &url).exists());
+}
+
+/// Runs the command line tool with `--porcelain` and returns its stdout.
+fn run_porcelain(config_json: &str, args: &[&str]) -> String {
+    use std::env;
+    use std::process::Command;
+
+    let binary = env::current_exe().unwrap().parent().unwrap().parent().unwrap()
+        .join("sentry-symbolserver");
+    let output = Command::new(binary)
+        .arg("--config-json").arg(config_json).arg("--porcelain").args(args)
+        .output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_porcelain_output() {
+    let s3 = MockS3::start().unwrap();
+    s3.put_sdk(&ios_10_2()).unwrap();
+    s3.put_sdk(&ios_10_3()).unwrap();
+    let dir = TempDir::new("symbolserver-test").unwrap();
+    let config_json = s3.config_json(dir.path());
+
+    let output = run_porcelain(&config_json, &["sync"]);
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "sdk\tupdating\tiOS_10.3.0_14E277");
+    assert_eq!(lines[1], "sdk\tupdating\tiOS_10.2.0_14C92");
+    let fields: Vec<_> = lines[2].split('\t').collect();
+    assert_eq!(&fields[..3], &["result", "done", "0"]);
+    assert!(fields[3].parse::<u64>().is_ok());
+    let output = run_porcelain(&config_json, &["sync"]);
+    assert!(output.starts_with("sdk\tunchanged\tiOS_10.3.0_14E277\n\
+                                sdk\tunchanged\tiOS_10.2.0_14C92\n\
+                                result\tdone\t0\t"));
+
+    let stash = MemDbStash::new(&s3.config(dir.path())).unwrap();
+    let output = run_porcelain(&config_json, &["sync-status"]);
+    let lines: Vec<Vec<_>> = output.lines().map(|x| x.split('\t').collect()).collect();
+    let keys: Vec<_> = lines.iter().map(|x| x[0]).collect();
+    assert_eq!(keys, ["revision", "missing", "different", "lag", "offline", "healthy",
+                      "warning"]);
+    assert!(lines.iter().all(|x| x.len() == 2));
+    let revision = stash.get_sync_status().unwrap().revision().to_string();
+    assert_eq!(lines[0][1], revision.as_str());
+    assert_eq!(lines[1][1], "0");
+    assert_eq!(lines[2][1], "0");
+    assert!(lines[3][1].parse::<u64>().is_ok());
+    assert_eq!(lines[4][1], "false");
+    assert_eq!(lines[5][1], "true");
+    assert_eq!(lines[6][1], "-");
+
+    let output = run_porcelain(&config_json, &["list-sdks"]);
+    let mut conversions = stash.list_sdk_conversions().unwrap();
+    conversions.sort_by(|a, b| a.sdk_id().cmp(b.sdk_id()));
+    let expected: String = conversions.iter().map(|x| {
+        let conversion = x.conversion().unwrap();
+        format!("sdk\t{}\t7\t-\t-\t{}\t{}\n", x.sdk_id(), conversion.converter_version,
+                conversion.sdk_fingerprint)
+    }).collect();
+    assert_eq!(output, expected);
+}